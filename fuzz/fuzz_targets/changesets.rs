@@ -0,0 +1,14 @@
+//! The changeset readers never panic
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = osmio::changesets::ChangesetReader::new(data);
+    while let Ok(Some(_)) = reader.next_changeset() {}
+
+    for tags in osmio::changesets::ChangesetTagReader::new(data) {
+        if tags.is_err() {
+            break;
+        }
+    }
+});
@@ -0,0 +1,9 @@
+//! The osmChange reader never panics
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use osmio::OSMReader;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = osmio::osc::OSCReader::new(data);
+    while let Ok(Some(_)) = reader.try_next() {}
+});
@@ -0,0 +1,18 @@
+//! Both PBF readers, and the other ways of reading PBF files, never panic
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use osmio::OSMReader;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = osmio::pbf::PBFReader::new(data);
+    while let Ok(Some(_)) = reader.try_next() {}
+
+    let mut reader = osmio::pbf::PBFReader::new(data);
+    while let Ok(Some(_)) = reader.next_dense_nodes() {}
+
+    let mut reader = osmio::stringpbf::PBFReader::new(data);
+    while let Ok(Some(_)) = reader.try_next() {}
+
+    let mut reader = osmio::stringpbf::PBFNodePositionReader::from_reader(data);
+    while let Ok(Some(_)) = reader.try_next() {}
+});
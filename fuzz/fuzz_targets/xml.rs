@@ -0,0 +1,16 @@
+//! The XML reader (and format detection) never panics
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use osmio::OSMReader;
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = osmio::xml::XMLReader::new(data);
+    while let Ok(Some(_)) = reader.try_next() {}
+
+    if let Ok(mut reader) =
+        osmio::open::AnyReader::from_reader(Box::new(Cursor::new(data.to_vec())))
+    {
+        while let Ok(Some(_)) = reader.try_next() {}
+    }
+});
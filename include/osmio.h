@@ -0,0 +1,70 @@
+/*
+ * C API for osmio, for reading OpenStreetMap data files (PBF, and optionally compressed XML).
+ *
+ * Build the library with:
+ *
+ *     cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * See the `osmio::ffi` module documentation for an example.
+ */
+#ifndef OSMIO_H
+#define OSMIO_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* `object_type` of a node */
+#define OSMIO_NODE ((uint8_t)'n')
+/* `object_type` of a way */
+#define OSMIO_WAY ((uint8_t)'w')
+/* `object_type` of a relation */
+#define OSMIO_RELATION ((uint8_t)'r')
+
+/* A reader, which owns the current object & its data */
+typedef struct OsmioReader OsmioReader;
+
+/* An OSM object. It (and all its strings & arrays) is owned by the reader, and is only valid
+ * until the next call with that reader. */
+typedef struct OsmioObject {
+    /* OSMIO_NODE, OSMIO_WAY or OSMIO_RELATION */
+    uint8_t object_type;
+    int64_t id;
+    /* True iff `lat` & `lon` are set (only for nodes) */
+    bool has_location;
+    double lat;
+    double lon;
+    size_t num_tags;
+    /* `num_tags` NUL terminated strings */
+    const char *const *tag_keys;
+    /* `num_tags` NUL terminated strings, in the same order as `tag_keys` */
+    const char *const *tag_values;
+    /* Number of node ids in `nodes` (only for ways) */
+    size_t num_nodes;
+    const int64_t *nodes;
+} OsmioObject;
+
+/* Open a PBF or XML file (or stdin, if `path` is "-"). Returns NULL on error, see
+ * osmio_last_error. */
+OsmioReader *osmio_reader_open(const char *path);
+
+/* The next object, or NULL at the end of the file, or if there's an error (then
+ * osmio_last_error isn't NULL). */
+const OsmioObject *osmio_reader_next(OsmioReader *reader);
+
+/* Close the file, and free the reader. Does nothing if `reader` is NULL. */
+void osmio_reader_free(OsmioReader *reader);
+
+/* The error from the last call (on this thread), or NULL if it succeeded. Valid until the next
+ * call. */
+const char *osmio_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* OSMIO_H */
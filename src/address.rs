@@ -0,0 +1,518 @@
+//! Addresses from `addr:*` tags, and expanding address interpolation ways.
+//!
+//! [`Address::from_obj`] collects the `addr:*` tags of an object, and
+//! [`Address::validate`] finds common mistakes. An `addr:interpolation` way has addresses on some
+//! of its nodes (usually the ends), and implies the addresses in between, e.g. `2`, `4`, `6` & `8`
+//! along a way from `2` to `8` with `addr:interpolation=even`. [`interpolate`] generates those
+//! as point addresses.
+//!
+//! ```no_run
+//! use osmio::address::{interpolate, Address};
+//! use osmio::node_locations::{NodeLocations, SparseNodeLocations};
+//! use osmio::{Node, OSMObj, OSMObjBase, OSMReader};
+//! use std::collections::HashMap;
+//!
+//! let mut reader = osmio::read_pbf("input.osm.pbf")?;
+//! let mut locations = SparseNodeLocations::new();
+//! let mut addresses = HashMap::new();
+//! for obj in reader.objects() {
+//!     if let Some(node) = obj.as_node() {
+//!         locations.add_node(node);
+//!         if let Some(address) = Address::from_obj(node) {
+//!             addresses.insert(node.id(), address);
+//!         }
+//!     } else if let Some(way) = obj.as_way() {
+//!         if way.has_tag("addr:interpolation") {
+//!             for point in interpolate(way, &locations, &addresses)? {
+//!                 println!("{:?}", point);
+//!             }
+//!         }
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use node_locations::NodeLocations;
+use routing::distance;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use tag_values::split_values;
+use {Lat, Lon, OSMObjBase, ObjId, Way};
+
+/// The most addresses which are generated between 2 housenumbers of an interpolation way, so that
+/// mistakes like `2` to `20000` don't create huge numbers of addresses.
+pub const MAX_INTERPOLATED: u32 = 1000;
+
+/// An address, from the `addr:*` tags of an object
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Address {
+    /// `addr:housenumber`. This can be a list (`12;14`) or a range (`12-16`), see
+    /// [`Address::housenumbers`].
+    pub housenumber: Option<String>,
+    pub street: Option<String>,
+    /// `addr:place`, used instead of `addr:street` for addresses which aren't on a street
+    pub place: Option<String>,
+    pub postcode: Option<String>,
+    pub city: Option<String>,
+    pub country: Option<String>,
+    pub unit: Option<String>,
+    /// `addr:interpolation`, for interpolation ways
+    pub interpolation: Option<String>,
+    /// Every other `addr:*` tag, without the `addr:` prefix
+    pub other: BTreeMap<String, String>,
+}
+
+/// Something wrong with an [`Address`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddressProblem {
+    /// There's a housenumber, but neither a street nor a place
+    NoStreetOrPlace,
+    /// There's a street & a place
+    StreetAndPlace,
+    /// This housenumber range doesn't go from a lower to a higher number
+    InvalidRange(String),
+    /// This `addr:interpolation` value isn't valid
+    InvalidInterpolation(String),
+}
+
+impl Address {
+    /// The address of this object, or `None` if it has no `addr:*` tags
+    pub fn from_obj(obj: &impl OSMObjBase) -> Option<Self> {
+        Self::from_tags(obj.tags())
+    }
+
+    /// The address from these tags, or `None` if there are no `addr:*` tags
+    pub fn from_tags<'a>(tags: impl IntoIterator<Item = (&'a str, &'a str)>) -> Option<Self> {
+        let mut address = Address::default();
+        let mut any = false;
+        for (k, v) in tags {
+            let k = match k.strip_prefix("addr:") {
+                Some(k) => k,
+                None => continue,
+            };
+            any = true;
+            let field = match k {
+                "housenumber" => &mut address.housenumber,
+                "street" => &mut address.street,
+                "place" => &mut address.place,
+                "postcode" => &mut address.postcode,
+                "city" => &mut address.city,
+                "country" => &mut address.country,
+                "unit" => &mut address.unit,
+                "interpolation" => &mut address.interpolation,
+                _ => {
+                    address.other.insert(k.to_string(), v.to_string());
+                    continue;
+                }
+            };
+            *field = Some(v.to_string());
+        }
+        if any {
+            Some(address)
+        } else {
+            None
+        }
+    }
+
+    /// The housenumbers, from a list like `12;14` (or `12,14`). Ranges like `12-16` are one
+    /// housenumber.
+    pub fn housenumbers(&self) -> Vec<&str> {
+        self.housenumber
+            .as_deref()
+            .map_or_else(Vec::new, |h| h.split(',').flat_map(split_values).collect())
+    }
+
+    /// Find any problems with this address
+    pub fn validate(&self) -> Vec<AddressProblem> {
+        let mut problems = Vec::new();
+        if self.housenumber.is_some() && self.street.is_none() && self.place.is_none() {
+            problems.push(AddressProblem::NoStreetOrPlace);
+        }
+        if self.street.is_some() && self.place.is_some() {
+            problems.push(AddressProblem::StreetAndPlace);
+        }
+        for housenumber in self.housenumbers() {
+            if let Some((from, to)) = housenumber.split_once('-') {
+                let valid = match (from.trim().parse::<u32>(), to.trim().parse::<u32>()) {
+                    (Ok(from), Ok(to)) => from < to,
+                    // Not a numeric range, e.g. `A-12`
+                    _ => true,
+                };
+                if !valid {
+                    problems.push(AddressProblem::InvalidRange(housenumber.to_string()));
+                }
+            }
+        }
+        if let Some(interpolation) = &self.interpolation {
+            if interpolation.parse::<Interpolation>().is_err() {
+                problems.push(AddressProblem::InvalidInterpolation(
+                    interpolation.to_string(),
+                ));
+            }
+        }
+        problems
+    }
+}
+
+/// The `addr:interpolation` scheme of an interpolation way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Every number
+    All,
+    Odd,
+    Even,
+    /// Letters after the same number, e.g. `12a`, `12b`, `12c`
+    Alphabetic,
+    /// Every nth number
+    Step(u32),
+}
+
+impl FromStr for Interpolation {
+    type Err = InterpolationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Interpolation::All),
+            "odd" => Ok(Interpolation::Odd),
+            "even" => Ok(Interpolation::Even),
+            "alphabetic" => Ok(Interpolation::Alphabetic),
+            _ => match s.parse() {
+                Ok(n) if n > 0 => Ok(Interpolation::Step(n)),
+                _ => Err(InterpolationError::UnknownInterpolation(s.to_string())),
+            },
+        }
+    }
+}
+
+/// Why an interpolation way couldn't be expanded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterpolationError {
+    /// The way has no `addr:interpolation` tag
+    NotInterpolation,
+    /// This isn't a valid `addr:interpolation` value
+    UnknownInterpolation(String),
+    /// The location of this node isn't available
+    MissingLocation(ObjId),
+    /// Fewer than 2 nodes of the way have housenumbers
+    TooFewHousenumbers,
+    /// Addresses can't be interpolated between these housenumbers, e.g. they aren't numbers, or
+    /// are odd for an `even` way
+    InvalidHousenumbers { from: String, to: String },
+    /// More than [`MAX_INTERPOLATED`] addresses would be generated between these housenumbers
+    TooManyAddresses { from: String, to: String },
+}
+
+impl std::fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InterpolationError::NotInterpolation => write!(f, "Not an interpolation way"),
+            InterpolationError::UnknownInterpolation(s) => {
+                write!(f, "Unknown addr:interpolation {:?}", s)
+            }
+            InterpolationError::MissingLocation(nid) => {
+                write!(f, "Location of node {} is missing", nid)
+            }
+            InterpolationError::TooFewHousenumbers => {
+                write!(f, "Fewer than 2 nodes have housenumbers")
+            }
+            InterpolationError::InvalidHousenumbers { from, to } => {
+                write!(f, "Can't interpolate between {:?} and {:?}", from, to)
+            }
+            InterpolationError::TooManyAddresses { from, to } => write!(
+                f,
+                "More than {} addresses between {:?} and {:?}",
+                MAX_INTERPOLATED, from, to
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InterpolationError {}
+
+/// An address generated from an interpolation way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterpolatedAddress {
+    pub address: Address,
+    pub location: (Lat, Lon),
+}
+
+/// Split a housenumber like `12a` into `(12, "a")`
+fn split_housenumber(housenumber: &str) -> Option<(u32, &str)> {
+    let housenumber = housenumber.trim();
+    let digits = housenumber
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(housenumber.len());
+    let number = housenumber[..digits].parse().ok()?;
+    Some((number, housenumber[digits..].trim()))
+}
+
+/// The housenumbers strictly between `from` & `to`, and how far from `from` to `to` (0–1) each
+/// one is
+fn between(
+    interpolation: Interpolation,
+    from: &str,
+    to: &str,
+) -> Result<Vec<(String, f64)>, InterpolationError> {
+    let invalid = || InterpolationError::InvalidHousenumbers {
+        from: from.to_string(),
+        to: to.to_string(),
+    };
+    let (from_number, from_suffix) = split_housenumber(from).ok_or_else(invalid)?;
+    let (to_number, to_suffix) = split_housenumber(to).ok_or_else(invalid)?;
+
+    if interpolation == Interpolation::Alphabetic {
+        let letter = |suffix: &str| match suffix.as_bytes() {
+            [] => Some(b'a' - 1),
+            [c] if c.is_ascii_alphabetic() => Some(c.to_ascii_lowercase()),
+            _ => None,
+        };
+        let (a, b) = match (letter(from_suffix), letter(to_suffix)) {
+            (Some(a), Some(b)) if from_number == to_number => (a, b),
+            _ => return Err(invalid()),
+        };
+        let (low, high) = (a.min(b), a.max(b));
+        let span = f64::from(high - low);
+        let letters = (low + 1..high).map(|c| {
+            let fraction = f64::from(c - low) / span;
+            let fraction = if a < b { fraction } else { 1. - fraction };
+            (format!("{}{}", from_number, c as char), fraction)
+        });
+        let mut letters: Vec<_> = letters.collect();
+        if a > b {
+            letters.reverse();
+        }
+        return Ok(letters);
+    }
+
+    if !(from_suffix.is_empty() && to_suffix.is_empty()) {
+        return Err(invalid());
+    }
+    let step = match interpolation {
+        Interpolation::All => 1,
+        Interpolation::Odd | Interpolation::Even => {
+            let parity = if interpolation == Interpolation::Odd {
+                1
+            } else {
+                0
+            };
+            if from_number % 2 != parity || to_number % 2 != parity {
+                return Err(invalid());
+            }
+            2
+        }
+        Interpolation::Step(n) => n,
+        Interpolation::Alphabetic => unreachable!(),
+    };
+    let (low, high) = (from_number.min(to_number), from_number.max(to_number));
+    if (high - low) / step > MAX_INTERPOLATED {
+        return Err(InterpolationError::TooManyAddresses {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+    let span = f64::from(high - low);
+    let numbers = (1..)
+        .map(|i| i * step)
+        .take_while(|offset| *offset < high - low)
+        .map(|offset| {
+            let number = if from_number < to_number {
+                from_number + offset
+            } else {
+                from_number - offset
+            };
+            (number.to_string(), f64::from(offset) / span)
+        });
+    Ok(numbers.collect())
+}
+
+/// The point `fraction` (0–1) of the length along this line
+fn along(line: &[(Lat, Lon)], fraction: f64) -> (Lat, Lon) {
+    let degrees: Vec<(f64, f64)> = line
+        .iter()
+        .map(|(lat, lon)| (lat.degrees(), lon.degrees()))
+        .collect();
+    let lengths: Vec<f64> = degrees.windows(2).map(|w| distance(w[0], w[1])).collect();
+    let mut remaining = fraction * lengths.iter().sum::<f64>();
+    for (i, length) in lengths.iter().enumerate() {
+        if remaining <= *length && *length > 0. {
+            let f = remaining / length;
+            let (a, b) = (degrees[i], degrees[i + 1]);
+            let lat = a.0 + (b.0 - a.0) * f;
+            let lon = a.1 + (b.1 - a.1) * f;
+            return (
+                Lat::try_from(lat).unwrap_or(line[i].0),
+                Lon::try_from(lon).unwrap_or(line[i].1),
+            );
+        }
+        remaining -= length;
+    }
+    *line.last().unwrap()
+}
+
+/// Generate the addresses implied by this `addr:interpolation` way.
+///
+/// `addresses` has the addresses of (at least) the nodes of the way. Addresses are generated
+/// between each pair of consecutive nodes with a housenumber, spaced along the way by number.
+/// The addresses of those nodes aren't included. The street, postcode etc. are from the way's
+/// `addr:*` tags, or else the first node's address.
+pub fn interpolate(
+    way: &impl Way,
+    locations: &impl NodeLocations,
+    addresses: &HashMap<ObjId, Address>,
+) -> Result<Vec<InterpolatedAddress>, InterpolationError> {
+    let interpolation: Interpolation = way
+        .tag("addr:interpolation")
+        .ok_or(InterpolationError::NotInterpolation)?
+        .parse()?;
+    let nodes = way.nodes();
+    let line = nodes
+        .iter()
+        .map(|nid| {
+            locations
+                .get(*nid)
+                .ok_or(InterpolationError::MissingLocation(*nid))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let numbered: Vec<(usize, &Address, &str)> = nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, nid)| {
+            let address = addresses.get(nid)?;
+            Some((i, address, address.housenumber.as_deref()?))
+        })
+        .collect();
+    if numbered.len() < 2 {
+        return Err(InterpolationError::TooFewHousenumbers);
+    }
+
+    let mut base = Address::from_obj(way).unwrap_or_default();
+    if base.street.is_none() && base.place.is_none() {
+        base = numbered[0].1.clone();
+    }
+    base.interpolation = None;
+
+    let mut result = Vec::new();
+    for pair in numbered.windows(2) {
+        let ((i, _, from), (j, _, to)) = (pair[0], pair[1]);
+        for (housenumber, fraction) in between(interpolation, from, to)? {
+            let mut address = base.clone();
+            address.housenumber = Some(housenumber);
+            result.push(InterpolatedAddress {
+                address,
+                location: along(&line[i..=j], fraction),
+            });
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use obj_types::StringWayBuilder;
+
+    fn tags(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn address(tags: &[(&'static str, &'static str)]) -> Address {
+        Address::from_tags(tags.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(Address::from_tags(vec![("building", "yes")]), None);
+        let a = address(&[
+            ("addr:housenumber", "12;14, 16-20"),
+            ("addr:street", "High Street"),
+            ("addr:flats", "1-4"),
+        ]);
+        assert_eq!(a.street.as_deref(), Some("High Street"));
+        assert_eq!(a.other["flats"], "1-4");
+        assert_eq!(a.housenumbers(), vec!["12", "14", "16-20"]);
+        assert!(a.validate().is_empty());
+
+        let a = address(&[
+            ("addr:housenumber", "20-16"),
+            ("addr:interpolation", "some"),
+        ]);
+        assert_eq!(
+            a.validate(),
+            vec![
+                AddressProblem::NoStreetOrPlace,
+                AddressProblem::InvalidRange("20-16".to_string()),
+                AddressProblem::InvalidInterpolation("some".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut locations = SparseNodeLocations::new();
+        for (nid, lon) in [(1, 0.), (2, 0.0006), (3, 0.001)].iter() {
+            locations.set(
+                *nid,
+                (Lat::try_from(0.).unwrap(), Lon::try_from(*lon).unwrap()),
+            );
+        }
+        let mut addresses = HashMap::new();
+        addresses.insert(
+            1,
+            address(&[("addr:housenumber", "2"), ("addr:street", "A")]),
+        );
+        addresses.insert(
+            3,
+            address(&[("addr:housenumber", "12"), ("addr:street", "A")]),
+        );
+        let way = |interpolation: &str| {
+            StringWayBuilder::default()
+                ._id(1)
+                ._nodes(vec![1, 2, 3])
+                ._tags(tags(&[("addr:interpolation", interpolation)]))
+                .build()
+                .unwrap()
+        };
+
+        let points = interpolate(&way("even"), &locations, &addresses).unwrap();
+        let numbers: Vec<_> = points
+            .iter()
+            .map(|p| p.address.housenumber.as_deref().unwrap())
+            .collect();
+        assert_eq!(numbers, vec!["4", "6", "8", "10"]);
+        assert_eq!(points[0].address.street.as_deref(), Some("A"));
+        assert!((points[0].location.1.degrees() - 0.0002).abs() < 1e-7);
+        assert!((points[3].location.1.degrees() - 0.0008).abs() < 1e-7);
+
+        assert_eq!(
+            interpolate(&way("odd"), &locations, &addresses),
+            Err(InterpolationError::InvalidHousenumbers {
+                from: "2".to_string(),
+                to: "12".to_string()
+            })
+        );
+        assert_eq!(
+            interpolate(&way("5"), &locations, &addresses)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        addresses.insert(3, address(&[("addr:housenumber", "2d")]));
+        let points = interpolate(&way("alphabetic"), &locations, &addresses).unwrap();
+        let numbers: Vec<_> = points
+            .iter()
+            .map(|p| p.address.housenumber.as_deref().unwrap())
+            .collect();
+        assert_eq!(numbers, vec!["2a", "2b", "2c"]);
+        assert!((points[0].location.1.degrees() - 0.00025).abs() < 1e-7);
+
+        addresses.remove(&3);
+        assert_eq!(
+            interpolate(&way("all"), &locations, &addresses),
+            Err(InterpolationError::TooFewHousenumbers)
+        );
+    }
+}
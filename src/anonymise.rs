@@ -0,0 +1,443 @@
+//! Removing personal metadata (user id, user name & changeset id) from objects.
+//!
+//! Metadata can either be removed, or replaced with pseudonyms. Pseudonyms are a permutation of
+//! the ids (a Feistel network, with salted SHA-256 hashes as the round function), so the same user
+//! (or changeset) always gets the same pseudonym for the same salt, 2 different users never get
+//! the same pseudonym, and the real value can't be recovered without knowing the salt. Timestamps
+//! & versions are kept.
+//!
+//! To make sure nothing leaks, wrap any writer in an [`AnonymisingWriter`]:
+//!
+//! ```no_run
+//! use osmio::anonymise::{Anonymiser, AnonymisingWriter};
+//! use osmio::prelude::*;
+//! use osmio::xml::XMLWriter;
+//! use osmio::OSMWriter;
+//!
+//! let mut reader = osmio::read_pbf("input.osm.pbf")?;
+//! let writer = XMLWriter::new(std::fs::File::create("output.osm")?);
+//! let mut writer = AnonymisingWriter::new_with(writer, Anonymiser::pseudonymise("secret salt"));
+//! for obj in reader.objects() {
+//!     writer.write_obj(&obj)?;
+//! }
+//! writer.close()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! To show at the type level that no personal data gets past the reader, read [`Anonymous`]
+//! objects, which have no user, uid or changeset accessors at all:
+//!
+//! ```no_run
+//! use osmio::anonymise::{Anonymous, AnonymousOSMObj};
+//! use osmio::OSMReader;
+//!
+//! fn count_shops(objs: impl Iterator<Item = impl AnonymousOSMObj>) -> usize {
+//!     objs.filter(|o| o.has_tag("shop")).count()
+//! }
+//!
+//! let mut reader = osmio::read_pbf("input.osm.pbf")?;
+//! println!("{} shops", count_shops(reader.anonymous_objects()));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use header::FileInfo;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::marker::PhantomData;
+use {Lat, Lon, Node, OSMObj, OSMObjBase, OSMObjectType, OSMWriteError, OSMWriter, ObjId};
+use {Relation, TimestampFormat, Way};
+
+/// What to do with the metadata
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mode {
+    Strip,
+    Pseudonymise { salt: Vec<u8> },
+}
+
+/// Removes or replaces the user & changeset of objects
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anonymiser {
+    mode: Mode,
+    keep_changesets: bool,
+}
+
+impl Default for Anonymiser {
+    fn default() -> Self {
+        Self::strip()
+    }
+}
+
+impl Anonymiser {
+    /// Remove the uid, user & changeset id
+    pub fn strip() -> Self {
+        Anonymiser {
+            mode: Mode::Strip,
+            keep_changesets: false,
+        }
+    }
+
+    /// Replace the uid, user & changeset id with pseudonyms derived from them & `salt`.
+    ///
+    /// The user name is replaced with `user_` and the pseudonymous uid.
+    pub fn pseudonymise(salt: impl AsRef<[u8]>) -> Self {
+        Anonymiser {
+            mode: Mode::Pseudonymise {
+                salt: salt.as_ref().to_vec(),
+            },
+            keep_changesets: false,
+        }
+    }
+
+    /// Keep the changeset ids unchanged (default: `false`).
+    pub fn keep_changesets(mut self, keep_changesets: bool) -> Self {
+        self.keep_changesets = keep_changesets;
+        self
+    }
+
+    /// The pseudonym for this value. `kind` keeps uids & changesets with the same number apart.
+    ///
+    /// The lower 31 bits are permuted with a 4 round Feistel network (alternately 16 & 15 bits
+    /// wide), so there are no collisions. The top bit is kept, so positive ids stay positive for
+    /// tools which treat them as signed.
+    fn pseudonym(salt: &[u8], kind: &[u8], value: u32) -> u32 {
+        let (mut left, mut right) = ((value >> 15) & 0xFFFF, value & 0x7FFF);
+        let (mut left_bits, mut right_bits) = (16, 15);
+        for round in 0..4u8 {
+            let mut hasher = Sha256::new();
+            hasher.update(salt);
+            hasher.update(kind);
+            hasher.update([round]);
+            hasher.update(right.to_be_bytes());
+            let hash = hasher.finalize();
+            let f =
+                u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) & ((1 << left_bits) - 1);
+            (left, right) = (right, left ^ f);
+            std::mem::swap(&mut left_bits, &mut right_bits);
+        }
+        (value & 0x8000_0000) | (left << right_bits) | right
+    }
+
+    /// Anonymise this object
+    pub fn apply(&self, obj: &mut impl OSMObjBase) {
+        match &self.mode {
+            Mode::Strip => {
+                obj.set_uid(None);
+                obj.set_user(None);
+                if !self.keep_changesets {
+                    obj.set_changeset_id(None);
+                }
+            }
+            Mode::Pseudonymise { salt } => {
+                let uid = obj.uid().map(|uid| Self::pseudonym(salt, b"uid", uid));
+                obj.set_uid(uid);
+                let user = uid.map(|uid| format!("user_{}", uid));
+                obj.set_user(user.as_deref());
+                if !self.keep_changesets {
+                    let changeset_id = obj
+                        .changeset_id()
+                        .map(|c| Self::pseudonym(salt, b"changeset", c));
+                    obj.set_changeset_id(changeset_id);
+                }
+            }
+        }
+    }
+
+    /// Anonymise all the objects from this iterator (e.g. `reader.objects()`)
+    pub fn anonymise_iter<'a, O: OSMObjBase + 'a>(
+        &'a self,
+        objs: impl Iterator<Item = O> + 'a,
+    ) -> impl Iterator<Item = O> + 'a {
+        objs.map(move |mut obj| {
+            self.apply(&mut obj);
+            obj
+        })
+    }
+}
+
+/// Wraps another writer, and anonymises every object before it's written.
+pub struct AnonymisingWriter<W: Write, Wr: OSMWriter<W>> {
+    inner: Wr,
+    anonymiser: Anonymiser,
+    _writer: PhantomData<W>,
+}
+
+impl<W: Write, Wr: OSMWriter<W>> AnonymisingWriter<W, Wr> {
+    /// Wrap this writer
+    pub fn new_with(inner: Wr, anonymiser: Anonymiser) -> Self {
+        AnonymisingWriter {
+            inner,
+            anonymiser,
+            _writer: PhantomData,
+        }
+    }
+
+    /// The wrapped writer
+    pub fn inner_writer(&self) -> &Wr {
+        &self.inner
+    }
+}
+
+/// `new` creates the inner writer, and strips all metadata.
+impl<W: Write, Wr: OSMWriter<W>> OSMWriter<W> for AnonymisingWriter<W, Wr> {
+    fn new(writer: W) -> Self {
+        Self::new_with(Wr::new(writer), Anonymiser::strip())
+    }
+
+    fn close(&mut self) -> Result<(), OSMWriteError> {
+        self.inner.close()
+    }
+
+    fn is_open(&self) -> bool {
+        self.inner.is_open()
+    }
+
+    fn write_obj(&mut self, obj: &impl OSMObj) -> Result<(), OSMWriteError> {
+        let mut obj = obj.clone();
+        self.anonymiser.apply(&mut obj);
+        self.inner.write_obj(&obj)
+    }
+
+    fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+
+    fn set_header(&mut self, key_value: (&str, &str)) -> Result<(), OSMWriteError> {
+        self.inner.set_header(key_value)
+    }
+
+    fn set_file_info(&mut self, file_info: &FileInfo) -> Result<(), OSMWriteError> {
+        self.inner.set_file_info(file_info)
+    }
+}
+
+/// The fields of an object which aren't personal data: everything but the uid, user & changeset
+/// id.
+pub trait AnonymousOSMObj {
+    fn id(&self) -> ObjId;
+    fn version(&self) -> Option<u32>;
+    fn deleted(&self) -> bool;
+    fn timestamp(&self) -> &Option<TimestampFormat>;
+    fn object_type(&self) -> OSMObjectType;
+    fn tags<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = (&'a str, &'a str)> + 'a>;
+    fn tag(&self, key: impl AsRef<str>) -> Option<&str>;
+    fn has_tag(&self, key: impl AsRef<str>) -> bool {
+        self.tag(key).is_some()
+    }
+}
+
+/// An object whose uid, user & changeset id have been removed.
+///
+/// The metadata is stripped when it's created, and it can't be set again, since there's no
+/// mutable access to the object. The user, uid & changeset accessors aren't available, so code
+/// which only takes `Anonymous` objects (or [`AnonymousOSMObj`]) can't use personal data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anonymous<O>(O);
+
+impl<O: OSMObjBase> Anonymous<O> {
+    /// Strip the metadata from this object
+    pub fn new(mut obj: O) -> Self {
+        obj.strip_metadata();
+        Anonymous(obj)
+    }
+
+    /// The underlying object (which has no metadata), e.g. to write it
+    pub fn inner(&self) -> &O {
+        &self.0
+    }
+
+    /// The underlying object (which has no metadata)
+    pub fn into_inner(self) -> O {
+        self.0
+    }
+}
+
+impl<O: OSMObjBase> From<O> for Anonymous<O> {
+    fn from(obj: O) -> Self {
+        Anonymous::new(obj)
+    }
+}
+
+impl<O: OSMObjBase> AnonymousOSMObj for Anonymous<O> {
+    fn id(&self) -> ObjId {
+        self.0.id()
+    }
+    fn version(&self) -> Option<u32> {
+        self.0.version()
+    }
+    fn deleted(&self) -> bool {
+        self.0.deleted()
+    }
+    fn timestamp(&self) -> &Option<TimestampFormat> {
+        self.0.timestamp()
+    }
+    fn object_type(&self) -> OSMObjectType {
+        self.0.object_type()
+    }
+    fn tags<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = (&'a str, &'a str)> + 'a> {
+        self.0.tags()
+    }
+    fn tag(&self, key: impl AsRef<str>) -> Option<&str> {
+        self.0.tag(key)
+    }
+}
+
+impl<O: Node> Anonymous<O> {
+    pub fn lat_lon(&self) -> Option<(Lat, Lon)> {
+        self.0.lat_lon()
+    }
+}
+
+impl<O: Way> Anonymous<O> {
+    pub fn nodes(&self) -> &[ObjId] {
+        self.0.nodes()
+    }
+}
+
+impl<O: Relation> Anonymous<O> {
+    pub fn members<'a>(
+        &'a self,
+    ) -> Box<dyn ExactSizeIterator<Item = (OSMObjectType, ObjId, &'a str)> + 'a> {
+        self.0.members()
+    }
+}
+
+impl<O: OSMObj> Anonymous<O> {
+    pub fn into_node(self) -> Option<Anonymous<O::Node>> {
+        self.0.into_node().map(Anonymous)
+    }
+    pub fn into_way(self) -> Option<Anonymous<O::Way>> {
+        self.0.into_way().map(Anonymous)
+    }
+    pub fn into_relation(self) -> Option<Anonymous<O::Relation>> {
+        self.0.into_relation().map(Anonymous)
+    }
+}
+
+/// Iterator of [`Anonymous`] objects, from an iterator of objects.
+///
+/// Created by [`OSMReader::anonymous_objects`](crate::OSMReader::anonymous_objects).
+pub struct AnonymousObjects<I>(I);
+
+impl<I> AnonymousObjects<I> {
+    pub fn new(objs: I) -> Self {
+        AnonymousObjects(objs)
+    }
+}
+
+impl<I> Iterator for AnonymousObjects<I>
+where
+    I: Iterator,
+    I::Item: OSMObjBase,
+{
+    type Item = Anonymous<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(Anonymous::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringNodeBuilder, StringOSMObj};
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+    use xml::{XMLReader, XMLWriter};
+    use {Lat, Lon, OSMReader};
+
+    fn node() -> StringOSMObj {
+        StringNodeBuilder::default()
+            ._id(1)
+            ._version(3)
+            ._uid(1234)
+            ._user("alice".to_string())
+            ._changeset_id(99)
+            ._lat_lon((Lat::try_from(1.).unwrap(), Lon::try_from(2.).unwrap()))
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn strip() {
+        let mut obj = node();
+        Anonymiser::strip().apply(&mut obj);
+        assert_eq!(obj.uid(), None);
+        assert_eq!(obj.user(), None);
+        assert_eq!(obj.changeset_id(), None);
+        assert_eq!(obj.version(), Some(3));
+
+        let mut obj = node();
+        Anonymiser::strip().keep_changesets(true).apply(&mut obj);
+        assert_eq!(obj.changeset_id(), Some(99));
+    }
+
+    #[test]
+    fn pseudonymise() {
+        let anonymiser = Anonymiser::pseudonymise("salt");
+        let mut obj1 = node();
+        anonymiser.apply(&mut obj1);
+        let mut obj2 = node();
+        anonymiser.apply(&mut obj2);
+        assert_eq!(obj1, obj2);
+        assert_ne!(obj1.uid(), Some(1234));
+        assert_eq!(
+            obj1.user(),
+            Some(format!("user_{}", obj1.uid().unwrap()).as_str())
+        );
+        assert_ne!(obj1.changeset_id(), Some(99));
+
+        let mut obj3 = node();
+        Anonymiser::pseudonymise("other salt").apply(&mut obj3);
+        assert_ne!(obj1.uid(), obj3.uid());
+    }
+
+    #[test]
+    fn pseudonyms_are_unique() {
+        let mut seen = std::collections::HashSet::new();
+        for value in (0..200_000).chain(u32::MAX - 1000..=u32::MAX) {
+            let pseudonym = Anonymiser::pseudonym(b"salt", b"uid", value);
+            assert!(seen.insert(pseudonym), "{} collides", value);
+            assert_eq!(pseudonym >> 31, value >> 31);
+        }
+    }
+
+    #[test]
+    fn writer() {
+        let mut output = Vec::new();
+        {
+            let mut writer: AnonymisingWriter<_, XMLWriter<_>> =
+                AnonymisingWriter::new(&mut output);
+            writer.write_obj(&node()).unwrap();
+        }
+        let output = String::from_utf8(output).unwrap();
+        assert!(!output.contains("alice"), "{}", output);
+
+        let mut reader = XMLReader::new(Cursor::new(output));
+        let obj = reader.next().unwrap();
+        assert_eq!(obj.user(), None);
+        assert_eq!(obj.version(), Some(3));
+    }
+
+    #[test]
+    fn anonymous() {
+        let obj = Anonymous::new(node());
+        assert_eq!(obj.id(), 1);
+        assert_eq!(obj.version(), Some(3));
+        assert_eq!(obj.inner().uid(), None);
+        assert_eq!(obj.inner().user(), None);
+        assert_eq!(obj.inner().changeset_id(), None);
+        let node = obj.into_node().unwrap();
+        assert_eq!(
+            node.lat_lon(),
+            Some((Lat::try_from(1.).unwrap(), Lon::try_from(2.).unwrap()))
+        );
+
+        let xml = r#"<osm><node id="1" version="2" uid="5" user="bob" changeset="7" lat="1" lon="2"><tag k="shop" v="bakery"/></node></osm>"#;
+        let mut reader = XMLReader::new(xml.as_bytes());
+        let objs = reader.anonymous_objects().collect::<Vec<_>>();
+        assert_eq!(objs.len(), 1);
+        assert!(objs[0].has_tag("shop"));
+        assert_eq!(objs[0].inner().user(), None);
+        assert_eq!(objs[0].inner().uid(), None);
+    }
+}
@@ -0,0 +1,683 @@
+//! Reading objects from the OSM API (v0.6), e.g. `api.openstreetmap.org`.
+//!
+//! ```no_run
+//! use osmio::api::Api;
+//! use osmio::{OSMObjBase, OSMObjectType};
+//!
+//! let api = Api::new();
+//! if let Some(node) = api.node(1)? {
+//!     println!("node 1 is version {:?}", node.version());
+//! }
+//! // The current version of many objects at once
+//! let ways = api.objects(OSMObjectType::Way, &[2, 3, 5])?;
+//! // A way & all its nodes
+//! let way_and_nodes = api.way_full(2)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Responses are OSM XML, parsed with the [`xml`](crate::xml) reader. Objects which have been
+//! deleted (HTTP 410) or never existed (HTTP 404) are `None`. Requests are made by a
+//! [`HttpGet`], by default the `curl` program (see [`download::Curl`]), which sends the
+//! `User-Agent` [`osmio/<version>`](crate::download::DEFAULT_USER_AGENT) unless it's changed with
+//! [`Api::user_agent`].
+//!
+//! # Uploading
+//!
+//! With an OAuth2 access token (with the `write_api` scope), changes can be uploaded:
+//!
+//! ```no_run
+//! use osmio::api::Api;
+//! use osmio::osc::ChangeAction;
+//! use osmio::obj_types::StringNodeBuilder;
+//! use osmio::{Lat, Lon};
+//! use std::convert::TryFrom;
+//!
+//! let api = Api::new()
+//!     .user_agent("my-bot/1.0")
+//!     .token(std::env::var("OSM_TOKEN")?);
+//! let changeset_id = api.create_changeset(&[("comment", "Add a bench"), ("created_by", "my-bot")])?;
+//! let bench = StringNodeBuilder::default()
+//!     ._id(-1)
+//!     ._lat_lon((Lat::try_from(53.3)?, Lon::try_from(-6.2)?))
+//!     ._tags(vec![("amenity".to_string(), "bench".to_string())])
+//!     .build()?;
+//! let results = api.upload_changes(changeset_id, &[(ChangeAction::Create, bench.into())])?;
+//! println!("The bench is node {:?}", results[0].new_id);
+//! api.close_changeset(changeset_id)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use download::Curl;
+use obj_types::{StringNode, StringOSMObj, StringRelation, StringWay};
+use osc::{ChangeAction, OSCWriter};
+use quick_xml::events::Event;
+use std::io::Write;
+use std::process::Stdio;
+use xml::{write_xml_escaped, XMLReader};
+use {BBox, OSMObj, OSMObjBase, OSMObjectType, OSMReader, OSMWriter, ObjId};
+
+use anyhow::{bail, Context, Result};
+
+/// The main OpenStreetMap API
+pub const OSM_API_URL: &str = "https://api.openstreetmap.org/api/0.6";
+
+/// The status code & body of a HTTP response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Something which can make HTTP `GET` requests
+pub trait HttpGet {
+    /// Fetch `url`. Only errors if no response was received, any HTTP status is returned.
+    fn get(&self, url: &str) -> Result<Response>;
+}
+
+impl HttpGet for Curl {
+    fn get(&self, url: &str) -> Result<Response> {
+        // The status code is written after the body, on its own line
+        let output = self
+            .command()
+            .args(["--write-out", "\n%{http_code}"])
+            .arg("--")
+            .arg(url)
+            .output()
+            .context("Unable to run curl")?;
+        response_from_curl(url, output)
+    }
+}
+
+/// A HTTP request which changes something, e.g. a `PUT` or `POST`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub url: &'a str,
+    /// Header names & values
+    pub headers: Vec<(&'a str, String)>,
+    pub body: &'a [u8],
+}
+
+/// Something which can also make other HTTP requests, needed for uploading
+pub trait HttpSend: HttpGet {
+    /// Send this request. Only errors if no response was received, any HTTP status is returned.
+    fn send(&self, request: &Request) -> Result<Response>;
+}
+
+impl HttpSend for Curl {
+    fn send(&self, request: &Request) -> Result<Response> {
+        // The body is in a file, and the headers (which can include the access token) are given
+        // on stdin, so neither appear in the process list
+        let mut body_file = tempfile::NamedTempFile::new()?;
+        body_file.write_all(request.body)?;
+        body_file.flush()?;
+        let mut child = self
+            .command()
+            .arg("--request")
+            .arg(request.method)
+            .arg("--data-binary")
+            .arg(format!("@{}", body_file.path().display()))
+            .args(["--config", "-"])
+            .args(["--write-out", "\n%{http_code}"])
+            .arg("--")
+            .arg(request.url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Unable to run curl")?;
+        {
+            let mut stdin = child.stdin.take().context("No stdin for curl")?;
+            for (name, value) in request.headers.iter() {
+                let header = format!("{}: {}", name, value);
+                writeln!(
+                    stdin,
+                    "header = \"{}\"",
+                    header.replace('\\', "\\\\").replace('"', "\\\"")
+                )?;
+            }
+        }
+        let output = child.wait_with_output()?;
+        response_from_curl(request.url, output)
+    }
+}
+
+/// The response from `curl --write-out '\n%{http_code}'`
+fn response_from_curl(url: &str, output: std::process::Output) -> Result<Response> {
+    if !output.status.success() {
+        bail!(
+            "Unable to fetch {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let mut body = output.stdout;
+    let newline = body
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .context("No status code from curl")?;
+    let status = std::str::from_utf8(&body[newline + 1..])?
+        .trim()
+        .parse()
+        .context("Invalid status code from curl")?;
+    body.truncate(newline);
+    Ok(Response { status, body })
+}
+
+/// What happened to one uploaded object, from the API's `diffResult`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffResult {
+    pub object_type: OSMObjectType,
+    /// The id in the upload (negative for new objects)
+    pub old_id: ObjId,
+    /// The id now, `None` if it was deleted
+    pub new_id: Option<ObjId>,
+    /// The version now, `None` if it was deleted
+    pub new_version: Option<u32>,
+}
+
+/// A client for the OSM API. See the [module documentation](self).
+#[derive(Clone)]
+pub struct Api<T> {
+    base_url: String,
+    transport: T,
+    token: Option<String>,
+}
+
+impl<T: std::fmt::Debug> std::fmt::Debug for Api<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Not the token
+        f.debug_struct("Api")
+            .field("base_url", &self.base_url)
+            .field("transport", &self.transport)
+            .field("token", &self.token.as_ref().map(|_| "..."))
+            .finish()
+    }
+}
+
+impl Api<Curl> {
+    /// The main OSM API ([`OSM_API_URL`]), using `curl`
+    pub fn new() -> Self {
+        Api::with_base_url(OSM_API_URL)
+    }
+
+    /// Another OSM API, e.g. `https://master.apis.dev.openstreetmap.org/api/0.6`
+    pub fn with_base_url(base_url: impl Into<String>) -> Self {
+        Api {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            transport: Curl::new(),
+            token: None,
+        }
+    }
+
+    /// Send this `User-Agent` instead of the
+    /// [`DEFAULT_USER_AGENT`](crate::download::DEFAULT_USER_AGENT). The OSM API asks programs
+    /// to identify themselves with it.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.transport = self.transport.user_agent(user_agent);
+        self
+    }
+}
+
+impl Default for Api<Curl> {
+    fn default() -> Self {
+        Api::new()
+    }
+}
+
+impl<T: HttpGet> Api<T> {
+    /// Make requests with this transport instead
+    pub fn transport<T2: HttpGet>(self, transport: T2) -> Api<T2> {
+        Api {
+            base_url: self.base_url,
+            transport,
+            token: self.token,
+        }
+    }
+
+    /// Use this OAuth2 access token, which is needed for uploading
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// The base URL, without a trailing `/`
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// The objects in the response to `GET {base_url}/{path}`, or `None` if it's 404 or 410
+    fn get_objects(&self, path: &str) -> Result<Option<Vec<StringOSMObj>>> {
+        let url = format!("{}/{}", self.base_url, path);
+        let response = self.transport.get(&url)?;
+        match response.status {
+            200 => {}
+            404 | 410 => return Ok(None),
+            status => bail!(
+                "{} returned HTTP {}: {}",
+                url,
+                status,
+                String::from_utf8_lossy(&response.body).trim()
+            ),
+        }
+        let mut reader = XMLReader::new(response.body.as_slice());
+        let objects = reader
+            .try_objects()
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid response from {}", url))?;
+        Ok(Some(objects))
+    }
+
+    /// The one object at `path`, which must be of the right type
+    fn get_object(&self, path: &str) -> Result<Option<StringOSMObj>> {
+        match self.get_objects(path)? {
+            None => Ok(None),
+            Some(mut objects) if objects.len() == 1 => Ok(objects.pop()),
+            Some(objects) => bail!("Expected 1 object from {}, got {}", path, objects.len()),
+        }
+    }
+
+    /// The current version of this node, or `None` if it's deleted or doesn't exist
+    pub fn node(&self, id: ObjId) -> Result<Option<StringNode>> {
+        let obj = self.get_object(&format!("node/{}", id))?;
+        obj.map(|o| o.into_node().context("Expected a node"))
+            .transpose()
+    }
+
+    /// The current version of this way, or `None` if it's deleted or doesn't exist
+    pub fn way(&self, id: ObjId) -> Result<Option<StringWay>> {
+        let obj = self.get_object(&format!("way/{}", id))?;
+        obj.map(|o| o.into_way().context("Expected a way"))
+            .transpose()
+    }
+
+    /// The current version of this relation, or `None` if it's deleted or doesn't exist
+    pub fn relation(&self, id: ObjId) -> Result<Option<StringRelation>> {
+        let obj = self.get_object(&format!("relation/{}", id))?;
+        obj.map(|o| o.into_relation().context("Expected a relation"))
+            .transpose()
+    }
+
+    /// The current version of all these objects, in one request. Objects which are deleted are
+    /// included, with `visible=false`. The API returns an error if any don't exist.
+    pub fn objects(&self, object_type: OSMObjectType, ids: &[ObjId]) -> Result<Vec<StringOSMObj>> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids = ids
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let path = format!("{0}s?{0}s={1}", object_type.name_long(), ids);
+        self.get_objects(&path)?
+            .with_context(|| format!("Some {}s don't exist: {}", object_type, ids))
+    }
+
+    /// This way & all its nodes, or an empty `Vec` if it's deleted or doesn't exist
+    pub fn way_full(&self, id: ObjId) -> Result<Vec<StringOSMObj>> {
+        Ok(self
+            .get_objects(&format!("way/{}/full", id))?
+            .unwrap_or_default())
+    }
+
+    /// This relation, all its members, and the nodes of the member ways (but not the members of
+    /// member relations), or an empty `Vec` if it's deleted or doesn't exist
+    pub fn relation_full(&self, id: ObjId) -> Result<Vec<StringOSMObj>> {
+        Ok(self
+            .get_objects(&format!("relation/{}/full", id))?
+            .unwrap_or_default())
+    }
+
+    /// Every version of this object, oldest first, or an empty `Vec` if it never existed
+    pub fn history(&self, object_type: OSMObjectType, id: ObjId) -> Result<Vec<StringOSMObj>> {
+        Ok(self
+            .get_objects(&format!("{}/{}/history", object_type.name_long(), id))?
+            .unwrap_or_default())
+    }
+
+    /// Everything in this bbox: the nodes, the ways using them (& all their nodes), and the
+    /// relations with any of them as members. The API limits the size of the bbox, & number of
+    /// nodes.
+    pub fn map(&self, bbox: &BBox) -> Result<Vec<StringOSMObj>> {
+        let path = format!(
+            "map?bbox={},{},{},{}",
+            bbox.min_lon, bbox.min_lat, bbox.max_lon, bbox.max_lat
+        );
+        self.get_objects(&path)?
+            .with_context(|| format!("No map data for {}", path))
+    }
+}
+
+impl<T: HttpSend> Api<T> {
+    /// Send an authenticated request, and return the body, erroring unless it's HTTP 200
+    fn send(&self, method: &str, path: &str, body: &[u8]) -> Result<Vec<u8>> {
+        let token = self
+            .token
+            .as_ref()
+            .context("An access token is needed to change anything")?;
+        let url = format!("{}/{}", self.base_url, path);
+        let response = self.transport.send(&Request {
+            method,
+            url: &url,
+            headers: vec![
+                ("Authorization", format!("Bearer {}", token)),
+                ("Content-Type", "text/xml".to_string()),
+            ],
+            body,
+        })?;
+        if response.status != 200 {
+            bail!(
+                "{} {} returned HTTP {}: {}",
+                method,
+                url,
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            );
+        }
+        Ok(response.body)
+    }
+
+    /// Open a new changeset with these tags (e.g. `comment` & `created_by`), returning its id
+    pub fn create_changeset(&self, tags: &[(impl AsRef<str>, impl AsRef<str>)]) -> Result<u32> {
+        let mut body = Vec::new();
+        write!(body, "<osm><changeset>")?;
+        for (k, v) in tags {
+            write!(body, "<tag k=\"")?;
+            write_xml_escaped(&mut body, k.as_ref())?;
+            write!(body, "\" v=\"")?;
+            write_xml_escaped(&mut body, v.as_ref())?;
+            write!(body, "\"/>")?;
+        }
+        write!(body, "</changeset></osm>")?;
+        let response = self.send("PUT", "changeset/create", &body)?;
+        String::from_utf8_lossy(&response)
+            .trim()
+            .parse()
+            .context("Invalid changeset id from the API")
+    }
+
+    /// Upload an osmChange file (e.g. from [`OSCWriter`]) to this open changeset. Every object
+    /// in it must have this changeset id.
+    pub fn upload_diff(&self, changeset_id: u32, osc: &[u8]) -> Result<Vec<DiffResult>> {
+        let response = self.send("POST", &format!("changeset/{}/upload", changeset_id), osc)?;
+        parse_diff_result(&response)
+    }
+
+    /// Upload these changes to this open changeset. The objects' changeset ids are set, and they
+    /// are written in order. New objects should have negative ids, and modified or deleted
+    /// objects the version they're based on.
+    pub fn upload_changes(
+        &self,
+        changeset_id: u32,
+        changes: &[(ChangeAction, StringOSMObj)],
+    ) -> Result<Vec<DiffResult>> {
+        let mut writer = OSCWriter::new(Vec::new());
+        for (action, obj) in changes {
+            let mut obj = obj.clone();
+            obj.set_changeset_id(changeset_id);
+            writer.write_change(*action, &obj)?;
+        }
+        let osc = writer.finish()?;
+        self.upload_diff(changeset_id, &osc)
+    }
+
+    /// Close this changeset
+    pub fn close_changeset(&self, changeset_id: u32) -> Result<()> {
+        self.send("PUT", &format!("changeset/{}/close", changeset_id), &[])?;
+        Ok(())
+    }
+}
+
+/// Parse the `<diffResult>` the API returns after an upload
+fn parse_diff_result(xml: &[u8]) -> Result<Vec<DiffResult>> {
+    let mut reader = quick_xml::Reader::from_reader(xml);
+    let mut buf = Vec::new();
+    let mut results = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Eof => break,
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let object_type = match e.name().local_name().as_ref() {
+                    b"node" => OSMObjectType::Node,
+                    b"way" => OSMObjectType::Way,
+                    b"relation" => OSMObjectType::Relation,
+                    _ => continue,
+                };
+                let (mut old_id, mut new_id, mut new_version) = (None, None, None);
+                for attr in e.attributes() {
+                    let attr = attr?;
+                    let value = attr.decode_and_unescape_value(&reader)?;
+                    match attr.key.local_name().as_ref() {
+                        b"old_id" => old_id = Some(value.parse()?),
+                        b"new_id" => new_id = Some(value.parse()?),
+                        b"new_version" => new_version = Some(value.parse()?),
+                        _ => {}
+                    }
+                }
+                results.push(DiffResult {
+                    object_type,
+                    old_id: old_id.context("No old_id in diffResult")?,
+                    new_id,
+                    new_version,
+                });
+            }
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use OSMObjBase;
+
+    /// Canned responses, and the URLs which were requested. Responses to other requests are
+    /// keyed by `"{method} {url}"`
+    #[derive(Default)]
+    struct Fake {
+        responses: HashMap<String, Response>,
+        requested: RefCell<Vec<String>>,
+        sent: RefCell<Vec<Sent>>,
+    }
+
+    /// A request other than `GET`
+    struct Sent {
+        method: String,
+        url: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Fake {
+        fn with(mut self, url: &str, status: u16, body: &str) -> Self {
+            self.responses.insert(
+                url.to_string(),
+                Response {
+                    status,
+                    body: body.as_bytes().to_vec(),
+                },
+            );
+            self
+        }
+    }
+
+    impl HttpGet for Fake {
+        fn get(&self, url: &str) -> Result<Response> {
+            self.requested.borrow_mut().push(url.to_string());
+            Ok(self.responses.get(url).cloned().unwrap_or(Response {
+                status: 404,
+                body: Vec::new(),
+            }))
+        }
+    }
+
+    impl HttpSend for Fake {
+        fn send(&self, request: &Request) -> Result<Response> {
+            self.sent.borrow_mut().push(Sent {
+                method: request.method.to_string(),
+                url: request.url.to_string(),
+                headers: request
+                    .headers
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.clone()))
+                    .collect(),
+                body: String::from_utf8(request.body.to_vec()).unwrap(),
+            });
+            self.get(&format!("{} {}", request.method, request.url))
+        }
+    }
+
+    const NODE: &str = r#"<osm version="0.6"><node id="1" visible="true" version="3" changeset="10" timestamp="2024-01-01T00:00:00Z" user="a" uid="1" lat="1.5" lon="2.5"><tag k="name" v="A"/></node></osm>"#;
+    const WAY_FULL: &str = r#"<osm version="0.6">
+        <node id="1" version="1" lat="1.0" lon="1.0"/>
+        <node id="2" version="1" lat="2.0" lon="2.0"/>
+        <way id="5" version="2"><nd ref="1"/><nd ref="2"/><tag k="highway" v="road"/></way>
+    </osm>"#;
+
+    fn api(fake: Fake) -> Api<Fake> {
+        Api::with_base_url("https://example.com/api/0.6/").transport(fake)
+    }
+
+    #[test]
+    fn single_objects() {
+        let api = api(Fake::default()
+            .with("https://example.com/api/0.6/node/1", 200, NODE)
+            .with("https://example.com/api/0.6/node/2", 410, "")
+            .with("https://example.com/api/0.6/relation/1", 200, NODE));
+        let node = api.node(1).unwrap().unwrap();
+        assert_eq!(node.id(), 1);
+        assert_eq!(node.version(), Some(3));
+        assert_eq!(node.tag("name"), Some("A"));
+        assert_eq!(api.node(2).unwrap(), None);
+        assert_eq!(api.way(3).unwrap(), None);
+        // A node isn't a relation
+        assert!(api.relation(1).is_err());
+    }
+
+    #[test]
+    fn full_and_many() {
+        let api = api(Fake::default()
+            .with("https://example.com/api/0.6/way/5/full", 200, WAY_FULL)
+            .with("https://example.com/api/0.6/nodes?nodes=1,2", 200, WAY_FULL)
+            .with(
+                "https://example.com/api/0.6/map?bbox=0,0,3,3",
+                200,
+                WAY_FULL,
+            )
+            .with("https://example.com/api/0.6/node/1/history", 200, NODE)
+            .with("https://example.com/api/0.6/way/6/full", 500, "Oops"));
+        let objs = api.way_full(5).unwrap();
+        assert_eq!(
+            objs.iter()
+                .map(|o| (o.object_type(), o.id()))
+                .collect::<Vec<_>>(),
+            vec![
+                (OSMObjectType::Node, 1),
+                (OSMObjectType::Node, 2),
+                (OSMObjectType::Way, 5)
+            ]
+        );
+        assert_eq!(api.objects(OSMObjectType::Node, &[1, 2]).unwrap().len(), 3);
+        assert!(api.objects(OSMObjectType::Node, &[]).unwrap().is_empty());
+        assert!(api.objects(OSMObjectType::Node, &[7]).is_err());
+        let bbox: BBox = "0,0,3,3".parse().unwrap();
+        assert_eq!(api.map(&bbox).unwrap().len(), 3);
+        assert_eq!(api.history(OSMObjectType::Node, 1).unwrap().len(), 1);
+        assert!(api.relation_full(9).unwrap().is_empty());
+        let err = api.way_full(6).unwrap_err().to_string();
+        assert!(err.contains("HTTP 500"), "{}", err);
+        assert_eq!(
+            api.transport.requested.borrow().last().unwrap(),
+            "https://example.com/api/0.6/way/6/full"
+        );
+    }
+
+    #[test]
+    fn upload() {
+        let fake = Fake::default()
+            .with("PUT https://example.com/api/0.6/changeset/create", 200, "42")
+            .with(
+                "POST https://example.com/api/0.6/changeset/42/upload",
+                200,
+                r#"<diffResult version="0.6"><node old_id="-1" new_id="100" new_version="1"/><way old_id="5" new_id="5" new_version="3"/><node old_id="2"/></diffResult>"#,
+            )
+            .with("PUT https://example.com/api/0.6/changeset/42/close", 200, "");
+
+        // A token is needed
+        assert!(api(Fake::default()).close_changeset(1).is_err());
+
+        let api = api(fake).token("secret");
+        let changeset_id = api
+            .create_changeset(&[("comment", "Fix <things> & \"stuff\"")])
+            .unwrap();
+        assert_eq!(changeset_id, 42);
+
+        let mut reader = XMLReader::new(WAY_FULL.as_bytes());
+        let objs = reader.objects().collect::<Vec<_>>();
+        let mut new_node = objs[0].clone();
+        new_node.set_id(-1);
+        let results = api
+            .upload_changes(
+                changeset_id,
+                &[
+                    (ChangeAction::Create, new_node),
+                    (ChangeAction::Modify, objs[2].clone()),
+                    (ChangeAction::Delete, objs[1].clone()),
+                ],
+            )
+            .unwrap();
+        assert_eq!(
+            results,
+            vec![
+                DiffResult {
+                    object_type: OSMObjectType::Node,
+                    old_id: -1,
+                    new_id: Some(100),
+                    new_version: Some(1)
+                },
+                DiffResult {
+                    object_type: OSMObjectType::Way,
+                    old_id: 5,
+                    new_id: Some(5),
+                    new_version: Some(3)
+                },
+                DiffResult {
+                    object_type: OSMObjectType::Node,
+                    old_id: 2,
+                    new_id: None,
+                    new_version: None
+                },
+            ]
+        );
+        api.close_changeset(changeset_id).unwrap();
+        // Errors include the response
+        let err = api.close_changeset(7).unwrap_err().to_string();
+        assert!(err.contains("HTTP 404"), "{}", err);
+
+        let sent = api.transport.sent.borrow();
+        assert_eq!(sent[0].method, "PUT");
+        assert!(sent[0]
+            .headers
+            .contains(&("Authorization".to_string(), "Bearer secret".to_string())));
+        assert_eq!(
+            sent[0].body,
+            r#"<osm><changeset><tag k="comment" v="Fix &lt;things&gt; &amp; &quot;stuff&quot;"/></changeset></osm>"#
+        );
+        let osc = &sent[1].body;
+        assert_eq!(
+            (sent[1].method.as_str(), sent[1].url.as_str()),
+            ("POST", "https://example.com/api/0.6/changeset/42/upload")
+        );
+        let changes = ::osc::OSCReader::new(osc.as_bytes())
+            .objects()
+            .map(|o| (o.id(), o.changeset_id()))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, vec![(-1, Some(42)), (5, Some(42)), (2, Some(42))]);
+        assert!(osc.contains("<create>") && osc.contains("<delete>"));
+        assert!(!format!("{:?}", Api::new().token("secret")).contains("secret"));
+    }
+}
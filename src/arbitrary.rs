@@ -0,0 +1,641 @@
+//! [`quickcheck::Arbitrary`] & [`proptest::arbitrary::Arbitrary`] implementations, for property
+//! based testing.
+//!
+//! Enabled with the `quickcheck` and/or `proptest` feature. Both generate the same data, which
+//! looks like real OSM data: ids, versions, changeset ids & uids are positive, locations are
+//! valid, timestamps are from the lifetime of OSM, tag keys are unique & not empty, and deleted
+//! objects have no tags, location or members.
+//!
+//! Every object type, [`Changeset`], and [`Tags`] can be generated on its own. [`Objects`] is a
+//! whole file's worth: sorted by type then id, and every way & relation only refers to objects
+//! in it. This makes a round trip test for a reader/writer pair a one-liner:
+//!
+//! ```rust,ignore
+//! quickcheck::quickcheck(|objs: osmio::arbitrary::Objects| write_then_read(&objs.0) == objs.0);
+//!
+//! proptest::proptest!(|(objs: osmio::arbitrary::Objects)| {
+//!     proptest::prop_assert_eq!(write_then_read(&objs.0), objs.0);
+//! });
+//! ```
+use super::*;
+use changesets::Changeset;
+use obj_types::{StringNode, StringOSMObj, StringRelation, StringWay};
+use std::collections::HashSet;
+
+/// Random numbers, from quickcheck or proptest
+trait Source {
+    fn next_u64(&mut self) -> u64;
+
+    /// How big generated values should be, e.g. the maximum length of lists
+    fn size(&self) -> usize;
+}
+
+#[cfg(feature = "quickcheck")]
+impl Source for quickcheck::Gen {
+    fn next_u64(&mut self) -> u64 {
+        quickcheck::Arbitrary::arbitrary(self)
+    }
+
+    fn size(&self) -> usize {
+        quickcheck::Gen::size(self)
+    }
+}
+
+/// proptest's random numbers, with quickcheck's default size
+#[cfg(feature = "proptest")]
+struct ProptestSource<'a>(&'a mut proptest::test_runner::TestRunner);
+
+#[cfg(feature = "proptest")]
+impl<'a> Source for ProptestSource<'a> {
+    fn next_u64(&mut self) -> u64 {
+        use proptest::strategy::{Strategy, ValueTree};
+        proptest::num::u64::ANY
+            .new_tree(self.0)
+            .expect("any u64 can be generated")
+            .current()
+    }
+
+    fn size(&self) -> usize {
+        100
+    }
+}
+
+/// A type which can be generated, used to implement both `Arbitrary` traits
+trait Generate: Sized {
+    fn generate(g: &mut dyn Source) -> Self;
+
+    /// Simpler values, to try when a test fails
+    fn shrinks(&self) -> Vec<Self> {
+        Vec::new()
+    }
+}
+
+macro_rules! impl_arbitrary {
+    ($($type:ty),*) => {$(
+        #[cfg(feature = "quickcheck")]
+        impl quickcheck::Arbitrary for $type {
+            fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+                Generate::generate(g)
+            }
+
+            fn shrink(&self) -> Box<dyn Iterator<Item = Self>> {
+                Box::new(self.shrinks().into_iter())
+            }
+        }
+
+        #[cfg(feature = "proptest")]
+        impl proptest::arbitrary::Arbitrary for $type {
+            type Parameters = ();
+            type Strategy = ArbitraryStrategy<$type>;
+
+            fn arbitrary_with(_: ()) -> Self::Strategy {
+                ArbitraryStrategy {
+                    generate: <$type as Generate>::generate,
+                    shrinks: <$type as Generate>::shrinks,
+                }
+            }
+        }
+    )*};
+}
+
+impl_arbitrary!(
+    Lat,
+    Lon,
+    Tags,
+    StringNode,
+    StringWay,
+    StringRelation,
+    StringOSMObj,
+    Objects,
+    Changeset
+);
+
+/// The proptest strategy for the types in this module, e.g. `any::<Objects>()`
+#[cfg(feature = "proptest")]
+#[derive(Clone, Copy)]
+pub struct ArbitraryStrategy<T> {
+    generate: fn(&mut dyn Source) -> T,
+    shrinks: fn(&T) -> Vec<T>,
+}
+
+#[cfg(feature = "proptest")]
+impl<T> std::fmt::Debug for ArbitraryStrategy<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ArbitraryStrategy").finish()
+    }
+}
+
+#[cfg(feature = "proptest")]
+impl<T: Clone + std::fmt::Debug> proptest::strategy::Strategy for ArbitraryStrategy<T> {
+    type Tree = ArbitraryValueTree<T>;
+    type Value = T;
+
+    fn new_tree(
+        &self,
+        runner: &mut proptest::test_runner::TestRunner,
+    ) -> proptest::strategy::NewTree<Self> {
+        let current = (self.generate)(&mut ProptestSource(runner));
+        Ok(ArbitraryValueTree {
+            failing: current.clone(),
+            current,
+            candidates: Vec::new(),
+            shrinks: self.shrinks,
+        })
+    }
+}
+
+/// A generated value, and the simpler values to try instead
+#[cfg(feature = "proptest")]
+pub struct ArbitraryValueTree<T> {
+    current: T,
+    /// The simplest value which made the test fail
+    failing: T,
+    /// Simpler versions of `failing` which haven't been tried yet, in reverse order
+    candidates: Vec<T>,
+    shrinks: fn(&T) -> Vec<T>,
+}
+
+#[cfg(feature = "proptest")]
+impl<T: Clone + std::fmt::Debug> proptest::strategy::ValueTree for ArbitraryValueTree<T> {
+    type Value = T;
+
+    fn current(&self) -> T {
+        self.current.clone()
+    }
+
+    /// The current value fails, so try its simpler versions
+    fn simplify(&mut self) -> bool {
+        self.failing = self.current.clone();
+        self.candidates = (self.shrinks)(&self.failing);
+        self.candidates.reverse();
+        self.complicate()
+    }
+
+    /// The current value passes, so try the next simpler version of the failing value, or go
+    /// back to it
+    fn complicate(&mut self) -> bool {
+        match self.candidates.pop() {
+            Some(candidate) => {
+                self.current = candidate;
+                true
+            }
+            None => {
+                self.current = self.failing.clone();
+                false
+            }
+        }
+    }
+}
+
+/// A random item from this slice, which mustn't be empty
+fn choose<'a, T>(g: &mut dyn Source, items: &'a [T]) -> &'a T {
+    &items[(g.next_u64() % items.len() as u64) as usize]
+}
+
+/// 2005-01-01
+const FIRST_TIMESTAMP: i64 = 1_104_537_600;
+/// 2030-01-01
+const LAST_TIMESTAMP: i64 = 1_893_456_000;
+
+/// Characters for tag values, user names & roles, including ones which need escaping in XML
+const VALUE_CHARS: &[char] = &[
+    'a', 'b', 'c', 'e', 'm', 'r', 's', 't', 'A', 'B', 'S', '0', '1', '7', ' ', '-', '.', ':', ';',
+    '&', '<', '>', '"', '\'', 'é', 'ß', 'ø', 'Ж', 'ש', '東', '京', '—', '🚲',
+];
+const KEY_CHARS: &[char] = &['a', 'd', 'e', 'h', 'i', 'n', 'o', 'r', 's', 'y', ':', '_'];
+const ROLES: &[&str] = &[
+    "", "", "outer", "inner", "stop", "platform", "from", "to", "via",
+];
+
+/// A random number in `min..=max`
+fn between(g: &mut dyn Source, min: i64, max: i64) -> i64 {
+    debug_assert!(min <= max);
+    let range = (max - min) as u64 + 1;
+    min + (g.next_u64() % range) as i64
+}
+
+/// A random number in `1..=max`, usually (but not always) small, like most counts in OSM
+fn small(g: &mut dyn Source, max: usize) -> usize {
+    let max = max.clamp(1, g.size().max(1));
+    between(g, 1, max as i64) as usize
+}
+
+/// True with probability `1/n`
+fn one_in(g: &mut dyn Source, n: u64) -> bool {
+    g.next_u64().is_multiple_of(n)
+}
+
+fn string(g: &mut dyn Source, chars: &[char], len: usize) -> String {
+    (0..len).map(|_| *choose(g, chars)).collect()
+}
+
+fn timestamp(g: &mut dyn Source) -> TimestampFormat {
+    TimestampFormat::EpochNunber(between(g, FIRST_TIMESTAMP, LAST_TIMESTAMP))
+}
+
+fn location(g: &mut dyn Source) -> (Lat, Lon) {
+    (Lat::generate(g), Lon::generate(g))
+}
+
+/// The metadata every object has: version, changeset id, timestamp, uid & user
+struct Meta {
+    version: u32,
+    changeset_id: u32,
+    timestamp: TimestampFormat,
+    uid: u32,
+    user: String,
+}
+
+impl Meta {
+    fn arbitrary(g: &mut dyn Source) -> Self {
+        let uid = between(g, 1, 25_000_000) as u32;
+        let user_len = small(g, 20);
+        Meta {
+            version: small(g, 50) as u32,
+            changeset_id: between(g, 1, 160_000_000) as u32,
+            timestamp: timestamp(g),
+            uid,
+            user: string(g, VALUE_CHARS, user_len),
+        }
+    }
+}
+
+impl Generate for Lat {
+    fn generate(g: &mut dyn Source) -> Self {
+        Lat::from_inner(between(g, -900_000_000, 900_000_000) as i32)
+    }
+}
+
+impl Generate for Lon {
+    fn generate(g: &mut dyn Source) -> Self {
+        Lon::from_inner(between(g, -1_800_000_000, 1_800_000_000) as i32)
+    }
+}
+
+/// A list of tags, with unique, non-empty keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tags(pub Vec<(String, String)>);
+
+impl Generate for Tags {
+    fn generate(g: &mut dyn Source) -> Self {
+        let num = small(g, 10) - 1;
+        let mut keys = HashSet::new();
+        let mut tags = Vec::with_capacity(num);
+        while tags.len() < num {
+            let key_len = small(g, 12);
+            let key = string(g, KEY_CHARS, key_len);
+            if keys.insert(key.clone()) {
+                let value_len = small(g, 30);
+                tags.push((key, string(g, VALUE_CHARS, value_len)));
+            }
+        }
+        Tags(tags)
+    }
+
+    /// Removes one tag at a time
+    fn shrinks(&self) -> Vec<Self> {
+        (0..self.0.len())
+            .rev()
+            .map(|i| {
+                let mut tags = self.0.clone();
+                tags.remove(i);
+                Tags(tags)
+            })
+            .collect()
+    }
+}
+
+fn node(g: &mut dyn Source, id: ObjId, deleted: bool) -> StringNode {
+    let meta = Meta::arbitrary(g);
+    let tags = if deleted { vec![] } else { Tags::generate(g).0 };
+    StringNode {
+        _id: id,
+        _version: Some(meta.version),
+        _deleted: deleted,
+        _changeset_id: Some(meta.changeset_id),
+        _timestamp: Some(meta.timestamp),
+        _uid: Some(meta.uid),
+        _user: Some(meta.user),
+        // Readers don't distinguish "no tags" from "0 tags"
+        _tags: if tags.is_empty() { None } else { Some(tags) },
+        _lat_lon: if deleted { None } else { Some(location(g)) },
+    }
+}
+
+fn way(g: &mut dyn Source, id: ObjId, deleted: bool, nodes: &[ObjId]) -> StringWay {
+    let meta = Meta::arbitrary(g);
+    let mut way_nodes = Vec::new();
+    if !deleted && !nodes.is_empty() {
+        // Consecutive nodes are never the same, and some ways are closed
+        let num = small(g, 20).max(2);
+        while way_nodes.len() < num {
+            let nid = *choose(g, nodes);
+            if way_nodes.last() != Some(&nid) || nodes.len() == 1 {
+                way_nodes.push(nid);
+            }
+        }
+        if num > 2 && one_in(g, 3) {
+            let first = way_nodes[0];
+            *way_nodes.last_mut().unwrap() = first;
+        }
+    }
+    StringWay {
+        _id: id,
+        _version: Some(meta.version),
+        _deleted: deleted,
+        _changeset_id: Some(meta.changeset_id),
+        _timestamp: Some(meta.timestamp),
+        _uid: Some(meta.uid),
+        _user: Some(meta.user),
+        _tags: if deleted { vec![] } else { Tags::generate(g).0 },
+        _nodes: way_nodes,
+    }
+}
+
+fn relation(
+    g: &mut dyn Source,
+    id: ObjId,
+    deleted: bool,
+    members: &[(OSMObjectType, ObjId)],
+) -> StringRelation {
+    let meta = Meta::arbitrary(g);
+    let mut rel_members = Vec::new();
+    if !deleted && !members.is_empty() {
+        for _ in 0..small(g, 20) {
+            let (object_type, mid) = *choose(g, members);
+            let role = choose(g, ROLES).to_string();
+            rel_members.push((object_type, mid, role));
+        }
+    }
+    StringRelation {
+        _id: id,
+        _version: Some(meta.version),
+        _deleted: deleted,
+        _changeset_id: Some(meta.changeset_id),
+        _timestamp: Some(meta.timestamp),
+        _uid: Some(meta.uid),
+        _user: Some(meta.user),
+        _tags: if deleted { vec![] } else { Tags::generate(g).0 },
+        _members: rel_members,
+    }
+}
+
+/// A random, positive, id, up to about the size of the current OSM ids
+fn id(g: &mut dyn Source) -> ObjId {
+    between(g, 1, 12_000_000_000)
+}
+
+/// A random list of ids, for the other end of references
+fn ref_ids(g: &mut dyn Source) -> Vec<ObjId> {
+    (0..small(g, 10)).map(|_| id(g)).collect()
+}
+
+impl Generate for StringNode {
+    fn generate(g: &mut dyn Source) -> Self {
+        let (deleted, id) = (one_in(g, 10), id(g));
+        node(g, id, deleted)
+    }
+}
+
+impl Generate for StringWay {
+    fn generate(g: &mut dyn Source) -> Self {
+        let (deleted, id, nodes) = (one_in(g, 10), id(g), ref_ids(g));
+        way(g, id, deleted, &nodes)
+    }
+}
+
+impl Generate for StringRelation {
+    fn generate(g: &mut dyn Source) -> Self {
+        let (deleted, id) = (one_in(g, 10), id(g));
+        let members = ref_ids(g)
+            .into_iter()
+            .map(|mid| (*choose(g, &OBJECT_TYPES), mid))
+            .collect::<Vec<_>>();
+        relation(g, id, deleted, &members)
+    }
+}
+
+const OBJECT_TYPES: [OSMObjectType; 3] = [
+    OSMObjectType::Node,
+    OSMObjectType::Way,
+    OSMObjectType::Relation,
+];
+
+impl Generate for StringOSMObj {
+    fn generate(g: &mut dyn Source) -> Self {
+        match choose(g, &OBJECT_TYPES) {
+            OSMObjectType::Node => StringNode::generate(g).into(),
+            OSMObjectType::Way => StringWay::generate(g).into(),
+            OSMObjectType::Relation => StringRelation::generate(g).into(),
+        }
+    }
+}
+
+/// The objects of a file, without deleted objects
+///
+/// They're sorted by type then id (with no duplicates), and every way node & relation member is
+/// in it. Relations only have earlier relations as members.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Objects(pub Vec<StringOSMObj>);
+
+impl Generate for Objects {
+    fn generate(g: &mut dyn Source) -> Self {
+        let ids = |g: &mut dyn Source, num: usize| {
+            let mut id = 0;
+            (0..num)
+                .map(|_| {
+                    id += between(g, 1, 1000);
+                    id
+                })
+                .collect::<Vec<ObjId>>()
+        };
+        let (num_nodes, num_ways, num_relations) =
+            (small(g, 50) - 1, small(g, 20) - 1, small(g, 10) - 1);
+        let node_ids = ids(g, num_nodes);
+        let way_ids = if node_ids.is_empty() {
+            vec![]
+        } else {
+            ids(g, num_ways)
+        };
+        let relation_ids = ids(g, num_relations);
+
+        let mut objs = Vec::with_capacity(node_ids.len() + way_ids.len() + relation_ids.len());
+        objs.extend(node_ids.iter().map(|&id| node(g, id, false).into()));
+        objs.extend(
+            way_ids
+                .iter()
+                .map(|&id| way(g, id, false, &node_ids).into()),
+        );
+        let mut members = node_ids
+            .iter()
+            .map(|&id| (OSMObjectType::Node, id))
+            .chain(way_ids.iter().map(|&id| (OSMObjectType::Way, id)))
+            .collect::<Vec<_>>();
+        for &id in relation_ids.iter() {
+            objs.push(relation(g, id, false, &members).into());
+            members.push((OSMObjectType::Relation, id));
+        }
+        Objects(objs)
+    }
+
+    /// Shrinks to shorter prefixes, which are still complete
+    fn shrinks(&self) -> Vec<Self> {
+        if self.0.is_empty() {
+            return vec![];
+        }
+        let mut lens = vec![0, self.0.len() / 2, self.0.len() - 1];
+        lens.dedup();
+        lens.into_iter()
+            .map(|len| Objects(self.0[..len].to_vec()))
+            .collect()
+    }
+}
+
+impl Generate for Changeset {
+    fn generate(g: &mut dyn Source) -> Self {
+        let created = timestamp(g);
+        let open = one_in(g, 10);
+        let closed = if open {
+            None
+        } else {
+            // Changesets are closed at most a day after they're opened
+            let created = created.to_epoch_number();
+            Some(TimestampFormat::EpochNunber(between(
+                g,
+                created,
+                created + 86_400,
+            )))
+        };
+        let anonymous = one_in(g, 20);
+        let num_changes = if one_in(g, 10) {
+            0
+        } else {
+            small(g, 10_000) as u64
+        };
+        let bbox = if num_changes == 0 {
+            None
+        } else {
+            let (lat1, lon1) = location(g);
+            let (lat2, lon2) = location(g);
+            Some(BBox::new(
+                lat1.min(lat2),
+                lon1.min(lon2),
+                lat1.max(lat2),
+                lon1.max(lon2),
+            ))
+        };
+        let user_len = small(g, 20);
+        Changeset {
+            id: between(g, 1, 160_000_000) as u32,
+            created,
+            closed,
+            open,
+            uid: if anonymous {
+                None
+            } else {
+                Some(between(g, 1, 25_000_000))
+            },
+            user: if anonymous {
+                None
+            } else {
+                Some(string(g, VALUE_CHARS, user_len))
+            },
+            tags: Tags::generate(g).0.into_iter().collect(),
+            num_changes,
+            comments_count: small(g, 5) as u64 - 1,
+            bbox,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "quickcheck")]
+    use quickcheck::quickcheck;
+    #[cfg(feature = "quickcheck")]
+    use std::collections::BTreeSet;
+    use xml::{XMLReader, XMLWriter};
+
+    fn xml_round_trip(objs: &[StringOSMObj]) -> Vec<StringOSMObj> {
+        let mut xmlwr = XMLWriter::new(Vec::new());
+        for obj in objs {
+            xmlwr.write_obj(obj).unwrap();
+        }
+        let bytes = xmlwr.finish().unwrap();
+        XMLReader::new(bytes.as_slice()).objects().collect()
+    }
+
+    #[cfg(feature = "quickcheck")]
+    quickcheck! {
+        fn xml_objects(objs: Objects) -> bool {
+            xml_round_trip(&objs.0) == objs.0
+        }
+
+        fn xml_obj(obj: StringOSMObj) -> bool {
+            xml_round_trip(std::slice::from_ref(&obj)) == vec![obj]
+        }
+
+        fn objects_are_complete(objs: Objects) -> bool {
+            let mut seen = BTreeSet::new();
+            objs.0.windows(2).all(|w| (w[0].object_type(), w[0].id()) < (w[1].object_type(), w[1].id()))
+                && objs.0.iter().all(|o| {
+                    let refs_seen = match o {
+                        StringOSMObj::Node(_) => true,
+                        StringOSMObj::Way(w) => w.nodes().iter().all(|n| seen.contains(&(OSMObjectType::Node, *n))),
+                        StringOSMObj::Relation(r) => r.members().all(|(t, id, _)| seen.contains(&(t, id))),
+                    };
+                    seen.insert((o.object_type(), o.id()));
+                    refs_seen
+                })
+        }
+
+        fn tags_are_valid(tags: Tags) -> bool {
+            let keys = tags.0.iter().map(|(k, _)| k).collect::<HashSet<_>>();
+            keys.len() == tags.0.len() && keys.iter().all(|k| !k.is_empty())
+        }
+
+        fn changesets_are_valid(c: Changeset) -> bool {
+            c.open == c.closed.is_none()
+                && c.closed.iter().all(|closed| *closed >= c.created)
+                && c.uid.is_some() == c.user.is_some()
+                && c.bbox.is_some() == (c.num_changes > 0)
+        }
+    }
+
+    #[cfg(feature = "proptest")]
+    proptest::proptest! {
+        #[test]
+        fn proptest_xml_objects(objs: Objects) {
+            proptest::prop_assert_eq!(xml_round_trip(&objs.0), objs.0);
+        }
+
+        #[test]
+        fn proptest_changesets_are_valid(c: Changeset) {
+            proptest::prop_assert_eq!(c.open, c.closed.is_none());
+            proptest::prop_assert_eq!(c.bbox.is_some(), c.num_changes > 0);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "proptest")]
+    fn proptest_shrinks() {
+        use proptest::strategy::{Strategy, ValueTree};
+        use proptest::test_runner::TestRunner;
+
+        let mut runner = TestRunner::default();
+        let mut tree = proptest::prelude::any::<Tags>()
+            .new_tree(&mut runner)
+            .unwrap();
+        while tree.current().0.is_empty() {
+            tree = proptest::prelude::any::<Tags>()
+                .new_tree(&mut runner)
+                .unwrap();
+        }
+        let len = tree.current().0.len();
+        assert!(tree.simplify());
+        assert_eq!(tree.current().0.len(), len - 1);
+        while tree.complicate() {}
+        assert_eq!(tree.current().0.len(), len);
+    }
+}
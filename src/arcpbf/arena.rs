@@ -0,0 +1,623 @@
+//! Decoding whole blocks into a reusable arena, without allocating for each object.
+//!
+//! [`PBFReader::next_block_into`] decodes all the objects of the next block into a
+//! [`BlockArena`]. The strings, tags, way nodes & relation members of every object in the block
+//! are stored in a few flat buffers, and objects are [`ArenaObj`] views into them. Decoding the
+//! next block into the same arena reuses those buffers, so once they've grown to the size of the
+//! largest block, there's (almost) no allocation per block or per object. The borrow checker
+//! makes sure no `ArenaObj` outlives its block.
+//!
+//! The tag filter, parse mode & duplicate tags policy are applied.
+use super::{dense_arrays, osmformat, Anomalies, PBFReader};
+use obj_types::{StrRef, StringTable};
+use parse_mode::AnomalyKind;
+use pbf::dense::DenseNodes;
+use std::io::Read;
+use std::ops::Range;
+use tagfilter::TagFilter;
+use utils::pbf_lat_lon;
+use {Error, Lat, Lon, OSMObjectType, ObjId, TimestampFormat};
+
+/// One object in a [`BlockArena`]
+#[derive(Debug, Clone)]
+struct Entry {
+    object_type: OSMObjectType,
+    id: ObjId,
+    version: Option<u32>,
+    deleted: bool,
+    changeset_id: Option<u32>,
+    timestamp: Option<TimestampFormat>,
+    uid: Option<u32>,
+    /// String index of the user name
+    user: Option<u32>,
+    lat_lon: Option<(Lat, Lon)>,
+    /// Range of `BlockArena::tags`
+    tags: Range<u32>,
+    /// Range of `BlockArena::refs` for ways, or `BlockArena::members` for relations
+    refs: Range<u32>,
+}
+
+/// The string table index of this string, if there is one
+fn table_idx(s: Option<StrRef>) -> Option<u32> {
+    match s {
+        Some(StrRef::Table(idx)) => Some(idx),
+        _ => None,
+    }
+}
+
+/// All the objects of one PBF block. See the [module docs](self).
+#[derive(Debug, Default)]
+pub struct BlockArena {
+    strings: StringTable,
+    entries: Vec<Entry>,
+    /// Key & value string indexes
+    tags: Vec<(u32, u32)>,
+    /// Way nodes
+    refs: Vec<ObjId>,
+    /// Relation members, with the string index of the role
+    members: Vec<(OSMObjectType, ObjId, u32)>,
+}
+
+impl BlockArena {
+    /// An empty arena. Buffers are allocated as blocks are decoded into it.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of objects
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True iff there are no objects
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Remove all the objects, keeping the allocated buffers
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.tags.clear();
+        self.refs.clear();
+        self.members.clear();
+    }
+
+    /// The object at this position
+    pub fn get(&self, idx: usize) -> Option<ArenaObj<'_>> {
+        self.entries
+            .get(idx)
+            .map(|entry| ArenaObj { arena: self, entry })
+    }
+
+    /// All the objects, in the order they are in the block
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = ArenaObj<'_>> + '_ {
+        self.entries
+            .iter()
+            .map(move |entry| ArenaObj { arena: self, entry })
+    }
+
+    fn push_tags(
+        &mut self,
+        anomalies: &Anomalies,
+        keys_vals: impl Iterator<Item = (i64, i64)>,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Range<u32>, Error> {
+        let start = self.tags.len() as u32;
+        for (k, v) in keys_vals {
+            let k = table_idx(anomalies.string(&self.strings, k, object_type, id)?);
+            let v = table_idx(anomalies.string(&self.strings, v, object_type, id)?);
+            if let (Some(k), Some(v)) = (k, v) {
+                self.tags.push((k, v));
+            }
+        }
+        let strings = &self.strings;
+        let keys = self.tags[start as usize..]
+            .iter()
+            .map(|&(k, _)| strings.get(k).unwrap_or(""));
+        if let Some(duplicates) = anomalies.duplicates(keys, object_type, id)? {
+            let mut tags = self.tags.split_off(start as usize);
+            duplicates.remove(&mut tags);
+            self.tags.extend(tags);
+        }
+        Ok(start..self.tags.len() as u32)
+    }
+
+    /// Keep this object iff it matches the tag filter, otherwise remove its tags & refs
+    fn push_entry(&mut self, entry: Entry, tag_filter: Option<&TagFilter>) {
+        if let Some(tag_filter) = tag_filter {
+            let strings = &self.strings;
+            let tags = self.tags[entry.tags.start as usize..entry.tags.end as usize]
+                .iter()
+                .map(|&(k, v)| (strings.get(k).unwrap_or(""), strings.get(v).unwrap_or("")));
+            if !tag_filter.matches_tags(entry.object_type, tags) {
+                self.tags.truncate(entry.tags.start as usize);
+                match entry.object_type {
+                    OSMObjectType::Node => {}
+                    OSMObjectType::Way => self.refs.truncate(entry.refs.start as usize),
+                    OSMObjectType::Relation => self.members.truncate(entry.refs.start as usize),
+                }
+                return;
+            }
+        }
+        self.entries.push(entry);
+    }
+
+    /// An entry with the metadata from this `Info`
+    fn entry(
+        &self,
+        anomalies: &Anomalies,
+        info: Option<&osmformat::Info>,
+        date_granularity: i32,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Entry, Error> {
+        let metadata =
+            anomalies.metadata(info, date_granularity, &self.strings, object_type, id)?;
+        Ok(Entry {
+            object_type,
+            id,
+            version: metadata.version,
+            deleted: metadata.deleted,
+            changeset_id: metadata.changeset_id,
+            timestamp: metadata.timestamp,
+            uid: metadata.uid,
+            user: table_idx(metadata.user),
+            lat_lon: None,
+            tags: 0..0,
+            refs: 0..0,
+        })
+    }
+
+    /// Decode this block into the arena, replacing what was there
+    pub(super) fn fill(
+        &mut self,
+        block: &osmformat::PrimitiveBlock,
+        tag_filter: Option<&TagFilter>,
+        lossy_utf8: bool,
+        anomalies: &Anomalies,
+    ) -> Result<(), Error> {
+        self.clear();
+        self.strings
+            .refill(block.get_stringtable().get_s(), lossy_utf8);
+        let granularity = block.get_granularity();
+        let offsets = (block.get_lat_offset(), block.get_lon_offset());
+        let date_granularity = block.get_date_granularity();
+        let wanted = |object_type| tag_filter.map_or(true, |f| f.matches_type(object_type));
+
+        for group in block.get_primitivegroup() {
+            if !group.get_nodes().is_empty() {
+                if !wanted(OSMObjectType::Node) {
+                    continue;
+                }
+                for node in group.get_nodes() {
+                    let id = node.get_id();
+                    anomalies.ids(OSMObjectType::Node, id, std::iter::empty())?;
+                    let info = node.has_info().then(|| node.get_info());
+                    let mut entry =
+                        self.entry(anomalies, info, date_granularity, OSMObjectType::Node, id)?;
+                    let raw = (node.get_lat(), node.get_lon());
+                    entry.lat_lon = Some(pbf_lat_lon(raw, granularity, offsets, id)?);
+                    let keys_vals = node.get_keys().iter().zip(node.get_vals());
+                    let keys_vals = keys_vals.map(|(&k, &v)| (k.into(), v.into()));
+                    entry.tags = self.push_tags(anomalies, keys_vals, OSMObjectType::Node, id)?;
+                    self.push_entry(entry, tag_filter);
+                }
+            } else if group.has_dense() {
+                if !wanted(OSMObjectType::Node) {
+                    continue;
+                }
+                self.push_dense(
+                    group.get_dense(),
+                    granularity,
+                    offsets,
+                    date_granularity,
+                    tag_filter,
+                    anomalies,
+                )?;
+            } else if !group.get_ways().is_empty() {
+                if !wanted(OSMObjectType::Way) {
+                    continue;
+                }
+                for way in group.get_ways() {
+                    let id = way.get_id();
+                    let info = way.has_info().then(|| way.get_info());
+                    let mut entry =
+                        self.entry(anomalies, info, date_granularity, OSMObjectType::Way, id)?;
+                    let keys_vals = way.get_keys().iter().zip(way.get_vals());
+                    let keys_vals = keys_vals.map(|(&k, &v)| (k.into(), v.into()));
+                    entry.tags = self.push_tags(anomalies, keys_vals, OSMObjectType::Way, id)?;
+
+                    let start = self.refs.len();
+                    let mut node_id: ObjId = 0;
+                    for delta in way.get_refs() {
+                        node_id = node_id.wrapping_add(*delta);
+                        self.refs.push(node_id);
+                    }
+                    anomalies.ids(OSMObjectType::Way, id, self.refs[start..].iter().copied())?;
+                    entry.refs = start as u32..self.refs.len() as u32;
+                    self.push_entry(entry, tag_filter);
+                }
+            } else if !group.get_relations().is_empty() {
+                if !wanted(OSMObjectType::Relation) {
+                    continue;
+                }
+                for relation in group.get_relations() {
+                    let id = relation.get_id();
+                    let info = relation.has_info().then(|| relation.get_info());
+                    let mut entry = self.entry(
+                        anomalies,
+                        info,
+                        date_granularity,
+                        OSMObjectType::Relation,
+                        id,
+                    )?;
+                    let keys_vals = relation.get_keys().iter().zip(relation.get_vals());
+                    let keys_vals = keys_vals.map(|(&k, &v)| (k.into(), v.into()));
+                    entry.tags =
+                        self.push_tags(anomalies, keys_vals, OSMObjectType::Relation, id)?;
+
+                    let start = self.members.len();
+                    let mut member_id: ObjId = 0;
+                    let members = relation
+                        .get_memids()
+                        .iter()
+                        .zip(relation.get_types())
+                        .zip(relation.get_roles_sid());
+                    for ((delta, member_type), role) in members {
+                        member_id = member_id.wrapping_add(*delta);
+                        let member_type = match *member_type {
+                            osmformat::Relation_MemberType::NODE => OSMObjectType::Node,
+                            osmformat::Relation_MemberType::WAY => OSMObjectType::Way,
+                            osmformat::Relation_MemberType::RELATION => OSMObjectType::Relation,
+                        };
+                        let role = anomalies.string(
+                            &self.strings,
+                            (*role).into(),
+                            OSMObjectType::Relation,
+                            id,
+                        )?;
+                        if let Some(role) = table_idx(role) {
+                            self.members.push((member_type, member_id, role));
+                        }
+                    }
+                    let member_ids = self.members[start..].iter().map(|m| m.1);
+                    anomalies.ids(OSMObjectType::Relation, id, member_ids)?;
+                    entry.refs = start as u32..self.members.len() as u32;
+                    self.push_entry(entry, tag_filter);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn push_dense(
+        &mut self,
+        dense: &osmformat::DenseNodes,
+        granularity: i32,
+        offsets: (i64, i64),
+        date_granularity: i32,
+        tag_filter: Option<&TagFilter>,
+        anomalies: &Anomalies,
+    ) -> Result<(), Error> {
+        let nodes = DenseNodes::new(
+            dense_arrays(dense),
+            granularity,
+            offsets,
+            date_granularity,
+            anomalies.offset,
+        )?;
+        if let Some(id) = nodes
+            .first_id()
+            .filter(|_| !nodes.has_info() && !anomalies.skip_metadata)
+        {
+            let detail = format!("no metadata for {} nodes", nodes.len());
+            anomalies.report(AnomalyKind::MissingInfo, OSMObjectType::Node, id, detail)?;
+        }
+        for node in nodes {
+            let node = node?;
+            let id = node.id;
+            anomalies.ids(OSMObjectType::Node, id, std::iter::empty())?;
+            let mut entry = Entry {
+                object_type: OSMObjectType::Node,
+                id,
+                version: None,
+                deleted: node.deleted,
+                changeset_id: None,
+                timestamp: None,
+                uid: None,
+                user: None,
+                lat_lon: Some(node.lat_lon),
+                tags: 0..0,
+                refs: 0..0,
+            };
+            if let Some(tags) = node.tags() {
+                entry.tags = self.push_tags(anomalies, tags, OSMObjectType::Node, id)?;
+            }
+            if let Some(info) = node.info.filter(|_| !anomalies.skip_metadata) {
+                entry.version = Some(info.version);
+                entry.timestamp = anomalies.timestamp(info.timestamp, OSMObjectType::Node, id)?;
+                entry.changeset_id = Some(info.changeset_id);
+                entry.uid = Some(info.uid);
+                entry.user = table_idx(anomalies.string(
+                    &self.strings,
+                    info.user_sid,
+                    OSMObjectType::Node,
+                    id,
+                )?);
+            }
+            self.push_entry(entry, tag_filter);
+        }
+        Ok(())
+    }
+}
+
+/// One object in a [`BlockArena`]
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaObj<'a> {
+    arena: &'a BlockArena,
+    entry: &'a Entry,
+}
+
+impl<'a> ArenaObj<'a> {
+    pub fn object_type(&self) -> OSMObjectType {
+        self.entry.object_type
+    }
+    pub fn id(&self) -> ObjId {
+        self.entry.id
+    }
+    pub fn version(&self) -> Option<u32> {
+        self.entry.version
+    }
+    pub fn deleted(&self) -> bool {
+        self.entry.deleted
+    }
+    pub fn changeset_id(&self) -> Option<u32> {
+        self.entry.changeset_id
+    }
+    pub fn timestamp(&self) -> Option<&'a TimestampFormat> {
+        self.entry.timestamp.as_ref()
+    }
+    pub fn uid(&self) -> Option<u32> {
+        self.entry.uid
+    }
+    pub fn user(&self) -> Option<&'a str> {
+        self.arena.strings.get(self.entry.user?)
+    }
+
+    pub fn tags(&self) -> impl ExactSizeIterator<Item = (&'a str, &'a str)> + 'a {
+        let strings = &self.arena.strings;
+        let range = self.entry.tags.start as usize..self.entry.tags.end as usize;
+        self.arena.tags[range]
+            .iter()
+            .map(move |&(k, v)| (strings.get(k).unwrap_or(""), strings.get(v).unwrap_or("")))
+    }
+    pub fn tag(&self, key: &str) -> Option<&'a str> {
+        self.tags().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    /// The location, for nodes
+    pub fn lat_lon(&self) -> Option<(Lat, Lon)> {
+        self.entry.lat_lon
+    }
+
+    /// The nodes of a way (empty for nodes & relations)
+    pub fn nodes(&self) -> &'a [ObjId] {
+        match self.entry.object_type {
+            OSMObjectType::Way => {
+                &self.arena.refs[self.entry.refs.start as usize..self.entry.refs.end as usize]
+            }
+            _ => &[],
+        }
+    }
+
+    /// The members of a relation (empty for nodes & ways)
+    pub fn members(&self) -> impl ExactSizeIterator<Item = (OSMObjectType, ObjId, &'a str)> + 'a {
+        let members = match self.entry.object_type {
+            OSMObjectType::Relation => {
+                &self.arena.members[self.entry.refs.start as usize..self.entry.refs.end as usize]
+            }
+            _ => &[],
+        };
+        let strings = &self.arena.strings;
+        members
+            .iter()
+            .map(move |&(t, id, role)| (t, id, strings.get(role).unwrap_or("")))
+    }
+}
+
+impl<R: Read> PBFReader<R> {
+    /// Decode the next block (which has any matching objects) into `arena`, replacing the previous
+    /// block. Returns false (with an empty arena) at the end of the file. See the
+    /// [module docs](super::arena).
+    ///
+    /// Don't mix this with [`OSMReader::next`](crate::OSMReader::next) on the same reader.
+    ///
+    /// ```no_run
+    /// use osmio::pbf::{BlockArena, PBFReader};
+    ///
+    /// let mut reader = PBFReader::from_filename("input.osm.pbf")?;
+    /// let mut arena = BlockArena::new();
+    /// let mut num_shops = 0;
+    /// while reader.next_block_into(&mut arena)? {
+    ///     num_shops += arena.iter().filter(|o| o.tag("shop").is_some()).count();
+    /// }
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn next_block_into(&mut self, arena: &mut BlockArena) -> Result<bool, Error> {
+        loop {
+            let (offset, block) = match self.try_next_block()? {
+                None => {
+                    arena.clear();
+                    return Ok(false);
+                }
+                Some(block) => block,
+            };
+            let anomalies = self.anomalies(offset);
+            arena.fill(
+                &block,
+                self.tag_filter.as_ref(),
+                self.lossy_utf8,
+                &anomalies,
+            )?;
+            if !arena.is_empty() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::file;
+    use super::*;
+    use parse_mode::DuplicateTags;
+    use {Node, OSMObj, OSMObjBase, OSMReader, Relation, Way};
+
+    /// A PBF file with 2 dense nodes, 2 ways & a relation
+    fn pbf() -> Vec<u8> {
+        let mut block = osmformat::PrimitiveBlock::new();
+        for s in ["", "highway", "path", "name", "Lane", "alice", "stop"] {
+            block.mut_stringtable().mut_s().push(s.as_bytes().to_vec());
+        }
+        let mut dense = osmformat::DenseNodes::new();
+        dense.set_id(vec![1, 1]);
+        dense.set_lat(vec![515_000_000, -100]);
+        dense.set_lon(vec![-1_000_000, 50]);
+        dense.set_keys_vals(vec![3, 4, 0, 0]);
+        let info = dense.mut_denseinfo();
+        info.set_version(vec![1, 2]);
+        info.set_timestamp(vec![1000, 1]);
+        info.set_changeset(vec![10, 1]);
+        info.set_uid(vec![5, 0]);
+        info.set_user_sid(vec![5, 0]);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.set_dense(dense);
+        block.mut_primitivegroup().push(group);
+
+        let mut group = osmformat::PrimitiveGroup::new();
+        for (id, refs, tagged) in [(7, vec![1, 1], true), (8, vec![2, -1], false)] {
+            let mut way = osmformat::Way::new();
+            way.set_id(id);
+            if tagged {
+                way.set_keys(vec![1]);
+                way.set_vals(vec![2]);
+            }
+            way.set_refs(refs);
+            way.mut_info().set_version(3);
+            way.mut_info().set_user_sid(5);
+            group.mut_ways().push(way);
+        }
+        block.mut_primitivegroup().push(group);
+
+        let mut relation = osmformat::Relation::new();
+        relation.set_id(9);
+        relation.set_memids(vec![7, -6]);
+        relation.set_types(vec![
+            osmformat::Relation_MemberType::WAY,
+            osmformat::Relation_MemberType::NODE,
+        ]);
+        relation.set_roles_sid(vec![0, 6]);
+        relation.mut_info().set_version(1);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.mut_relations().push(relation);
+        block.mut_primitivegroup().push(group);
+        file(&block)
+    }
+
+    #[test]
+    fn same_as_objects() {
+        let file = pbf();
+        let objs: Vec<_> = PBFReader::new(&file[..]).objects().collect();
+        let mut arena = BlockArena::new();
+        let mut reader = PBFReader::new(&file[..]);
+        assert!(reader.next_block_into(&mut arena).unwrap());
+        assert_eq!(arena.len(), objs.len());
+        for (arena_obj, obj) in arena.iter().zip(objs.iter()) {
+            assert_eq!(arena_obj.object_type(), obj.object_type());
+            assert_eq!(arena_obj.id(), obj.id());
+            assert_eq!(arena_obj.version(), obj.version());
+            assert_eq!(arena_obj.timestamp(), obj.timestamp().as_ref());
+            assert_eq!(arena_obj.changeset_id(), obj.changeset_id());
+            assert_eq!(arena_obj.uid(), obj.uid());
+            assert_eq!(arena_obj.user(), obj.user());
+            assert!(arena_obj.tags().eq(obj.tags()));
+            if let Some(node) = obj.as_node() {
+                assert_eq!(arena_obj.lat_lon(), node.lat_lon());
+            }
+            if let Some(way) = obj.as_way() {
+                assert_eq!(arena_obj.nodes(), way.nodes());
+            }
+            if let Some(relation) = obj.as_relation() {
+                assert!(arena_obj.members().eq(relation.members()));
+            }
+        }
+        assert_eq!(arena.get(0).unwrap().user(), Some("alice"));
+        assert_eq!(arena.get(0).unwrap().tag("name"), Some("Lane"));
+        assert_eq!(arena.get(3).unwrap().nodes(), &[2, 1]);
+        assert_eq!(
+            arena.get(4).unwrap().members().collect::<Vec<_>>(),
+            vec![
+                (OSMObjectType::Way, 7, ""),
+                (OSMObjectType::Node, 1, "stop")
+            ]
+        );
+
+        assert!(!reader.next_block_into(&mut arena).unwrap());
+        assert!(arena.is_empty());
+    }
+
+    #[test]
+    fn reuse_and_filter() {
+        let mut file = pbf();
+        file.extend(pbf());
+        let mut reader = PBFReader::builder()
+            .tag_filter("highway".parse().unwrap())
+            .build(&file[..]);
+        let mut arena = BlockArena::new();
+        let mut ids = Vec::new();
+        while reader.next_block_into(&mut arena).unwrap() {
+            ids.push(arena.iter().map(|o| o.id()).collect::<Vec<_>>());
+            assert_eq!(arena.get(0).unwrap().nodes(), &[1, 2]);
+        }
+        assert_eq!(ids, vec![vec![7], vec![7]]);
+    }
+
+    #[test]
+    fn duplicate_tags() {
+        let mut block = osmformat::PrimitiveBlock::new();
+        for s in ["", "name", "Lane", "Road"] {
+            block.mut_stringtable().mut_s().push(s.as_bytes().to_vec());
+        }
+        let mut dense = osmformat::DenseNodes::new();
+        dense.set_id(vec![1]);
+        dense.set_lat(vec![0]);
+        dense.set_lon(vec![0]);
+        dense.set_keys_vals(vec![1, 2, 1, 3, 0]);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.set_dense(dense);
+        block.mut_primitivegroup().push(group);
+        let file = file(&block);
+
+        let tags = |duplicate_tags| {
+            let mut reader = PBFReader::builder()
+                .duplicate_tags(duplicate_tags)
+                .build(&file[..]);
+            let mut arena = BlockArena::new();
+            reader.next_block_into(&mut arena).map(|_| {
+                let node = arena.get(0).unwrap();
+                node.tags()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<Vec<_>>()
+            })
+        };
+        let tag = |v: &str| ("name".to_string(), v.to_string());
+        assert_eq!(
+            tags(DuplicateTags::KeepAll).unwrap(),
+            vec![tag("Lane"), tag("Road")]
+        );
+        assert_eq!(tags(DuplicateTags::KeepFirst).unwrap(), vec![tag("Lane")]);
+        assert_eq!(tags(DuplicateTags::KeepLast).unwrap(), vec![tag("Road")]);
+        assert!(matches!(tags(DuplicateTags::Error), Err(Error::Anomaly(_))));
+    }
+}
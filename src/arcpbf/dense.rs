@@ -0,0 +1,212 @@
+//! Reading dense nodes as columns, without building an object per node.
+use super::{dense_arrays, osmformat, PBFReader};
+use obj_types::StringTable;
+use pbf::dense::DenseNodes;
+use std::io::Read;
+use std::ops::Range;
+use std::sync::Arc;
+use {Error, Lat, Lon, ObjId};
+
+/// The dense nodes of one PBF block, as parallel columns (node `i` is `ids[i]` at
+/// `(lats[i], lons[i])`).
+///
+/// Metadata (version, timestamp, user etc.) isn't decoded.
+#[derive(Debug, Clone, Default)]
+pub struct DenseNodeColumns {
+    pub ids: Vec<ObjId>,
+    pub lats: Vec<Lat>,
+    pub lons: Vec<Lon>,
+    /// The tags of node `i` are `tags[tag_ranges[i]]`
+    pub tag_ranges: Vec<Range<usize>>,
+    /// Key & value string indexes, see [`DenseNodeColumns::string`]
+    pub tags: Vec<(u32, u32)>,
+    strings: Arc<StringTable>,
+}
+
+impl DenseNodeColumns {
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// True iff there are no nodes
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The string with this index in the block's string table (`None` if there isn't one, or
+    /// it's not valid UTF-8 and the reader isn't lossy)
+    pub fn string(&self, idx: u32) -> Option<&str> {
+        self.strings.get(idx)
+    }
+
+    /// The tags of node `i`. Tags with an invalid string are skipped.
+    pub fn node_tags(&self, i: usize) -> impl Iterator<Item = (&str, &str)> + '_ {
+        self.tags[self.tag_ranges[i].clone()]
+            .iter()
+            .filter_map(move |&(k, v)| Some((self.string(k)?, self.string(v)?)))
+    }
+
+    fn push_group(
+        &mut self,
+        dense: &osmformat::DenseNodes,
+        granularity: i32,
+        lat_lon_offsets: (i64, i64),
+        offset: u64,
+    ) -> Result<(), Error> {
+        // Metadata isn't used, so the date granularity doesn't matter
+        let nodes = DenseNodes::new(
+            dense_arrays(dense),
+            granularity,
+            lat_lon_offsets,
+            1000,
+            offset,
+        )?;
+        self.ids.reserve(nodes.len());
+        self.lats.reserve(nodes.len());
+        self.lons.reserve(nodes.len());
+        self.tag_ranges.reserve(nodes.len());
+
+        for node in nodes {
+            let node = node?;
+            self.ids.push(node.id);
+            self.lats.push(node.lat_lon.0);
+            self.lons.push(node.lat_lon.1);
+            let start = self.tags.len();
+            for (k, v) in node.tags().into_iter().flatten() {
+                self.tags.push((k as u32, v as u32));
+            }
+            self.tag_ranges.push(start..self.tags.len());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> PBFReader<R> {
+    /// The dense nodes of the next block which has any, as columns. This is much faster than
+    /// reading nodes one at a time when only the ids, locations or tags are needed.
+    ///
+    /// Ways & relations are skipped. With the sorted assumption (see
+    /// [`OSMReader::set_sorted_assumption`](crate::OSMReader::set_sorted_assumption)), this
+    /// returns `None` at the first block of ways or relations. The tag filter isn't applied.
+    /// Don't mix this with [`OSMReader::next`](crate::OSMReader::next) on the same reader.
+    /// An error if the block's arrays don't have one entry per node.
+    ///
+    /// ```no_run
+    /// use osmio::pbf::PBFReader;
+    ///
+    /// let mut reader = PBFReader::from_filename("input.osm.pbf")?;
+    /// let mut lats = Vec::new();
+    /// while let Some(block) = reader.next_dense_nodes()? {
+    ///     lats.extend(block.lats.iter().map(|&lat| f64::from(lat)));
+    /// }
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn next_dense_nodes(&mut self) -> Result<Option<DenseNodeColumns>, Error> {
+        loop {
+            let (offset, block) = match self.try_next_block()? {
+                None => return Ok(None),
+                Some(block) => block,
+            };
+            let groups = block.get_primitivegroup();
+            if groups.iter().all(|g| !g.has_dense()) {
+                let only_nodes = groups.iter().all(|g| !g.get_nodes().is_empty());
+                if self._sorted_assumption && !only_nodes {
+                    return Ok(None);
+                }
+                continue;
+            }
+
+            let mut columns = DenseNodeColumns {
+                strings: Arc::new(StringTable::new(
+                    block.get_stringtable().get_s(),
+                    self.lossy_utf8,
+                )),
+                ..Default::default()
+            };
+            for group in groups.iter().filter(|g| g.has_dense()) {
+                columns.push_group(
+                    group.get_dense(),
+                    block.get_granularity(),
+                    (block.get_lat_offset(), block.get_lon_offset()),
+                    offset,
+                )?;
+            }
+            return Ok(Some(columns));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::file;
+    use super::*;
+    use {Node, OSMObj, OSMObjBase, OSMReader};
+
+    /// A PBF file with one block of 3 dense nodes, the 2nd one tagged
+    fn pbf() -> Vec<u8> {
+        let mut block = osmformat::PrimitiveBlock::new();
+        for s in ["", "amenity", "pub", "name", "Bar"] {
+            block.mut_stringtable().mut_s().push(s.as_bytes().to_vec());
+        }
+        let mut dense = osmformat::DenseNodes::new();
+        dense.set_id(vec![10, 1, 5]);
+        dense.set_lat(vec![515_000_000, -100, 200]);
+        dense.set_lon(vec![-1_000_000, 50, -50]);
+        dense.set_keys_vals(vec![0, 1, 2, 3, 4, 0, 0]);
+        let info = dense.mut_denseinfo();
+        info.set_version(vec![1, 1, 1]);
+        info.set_timestamp(vec![0, 0, 0]);
+        info.set_changeset(vec![0, 0, 0]);
+        info.set_uid(vec![0, 0, 0]);
+        info.set_user_sid(vec![0, 0, 0]);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.set_dense(dense);
+        block.mut_primitivegroup().push(group);
+        file(&block)
+    }
+
+    #[test]
+    fn same_as_objects() {
+        let file = pbf();
+        let nodes: Vec<_> = PBFReader::new(&file[..])
+            .objects()
+            .filter_map(|o| o.into_node())
+            .collect();
+        assert_eq!(nodes.len(), 3);
+
+        let mut reader = PBFReader::new(&file[..]);
+        let block = reader.next_dense_nodes().unwrap().unwrap();
+        assert_eq!(block.ids, vec![10, 11, 16]);
+        for (i, node) in nodes.iter().enumerate() {
+            assert_eq!(block.ids[i], node.id());
+            assert_eq!(Some((block.lats[i], block.lons[i])), node.lat_lon());
+            assert!(block.node_tags(i).eq(node.tags()));
+        }
+        assert_eq!(
+            block.node_tags(1).collect::<Vec<_>>(),
+            vec![("amenity", "pub"), ("name", "Bar")]
+        );
+        assert!(reader.next_dense_nodes().unwrap().is_none());
+    }
+
+    #[test]
+    fn length_mismatch() {
+        let mut block = osmformat::PrimitiveBlock::new();
+        block.mut_stringtable().mut_s().push(Vec::new());
+        let mut dense = osmformat::DenseNodes::new();
+        dense.set_id(vec![1, 1, 1]);
+        dense.set_lat(vec![0, 0, 0]);
+        dense.set_lon(vec![0, 0]);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.set_dense(dense);
+        block.mut_primitivegroup().push(group);
+        let file = file(&block);
+        match PBFReader::new(&file[..]).next_dense_nodes() {
+            Err(Error::Format { reason, .. }) => {
+                assert_eq!(reason, "3 dense node ids, but 3 latitudes and 2 longitudes")
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -4,253 +4,662 @@
 use super::OSMReader;
 use super::ObjId;
 use super::TimestampFormat;
-use byteorder;
-use byteorder::ReadBytesExt;
-use std::io::{Cursor, Read};
+use std::io::{BufReader, Cursor, Read};
 use std::iter::Iterator;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 
 use super::*;
-use crate::COORD_PRECISION_NANOS;
+use utils::pbf_lat_lon;
 
 use flate2::read::ZlibDecoder;
 
-use obj_types::{ArcNode, ArcOSMObj, ArcRelation, ArcWay};
+use cancel::{self, CancellationToken};
+use memory::{MemoryBudget, Reservation};
+use obj_types::{ArcNode, ArcOSMObj, ArcRelation, ArcWay, StrRef, StringTable};
+use parse_mode::{Anomaly, AnomalyKind, DuplicateTags, Duplicates, NonPositiveIds, ParseMode};
+use pbf::dense::{DenseArrays, DenseInfoArrays, DenseNodes};
+use tagfilter::TagFilter;
 
 use protobuf;
+mod arena;
+mod dense;
 mod fileformat;
 mod osmformat;
+mod writer;
+
+pub use self::arena::{ArenaObj, BlockArena};
+pub use self::dense::DenseNodeColumns;
+pub use self::writer::{BlockLayout, GroupLayout, PBFWriter};
 
 struct FileReader<R: Read> {
-    reader: R,
+    reader: BufReader<R>,
+    /// Blobs larger than this (compressed or not) are an error
+    max_blob_size: u64,
+    /// Number of bytes read so far, i.e. the offset of the next blob
+    offset: u64,
+    /// The `OSMHeader` blob, once it's been read
+    header: Option<header::FileInfo>,
+}
+
+/// The largest `BlobHeader` the PBF format allows
+pub(crate) const MAX_BLOB_HEADER_SIZE: u64 = 64 * 1024;
+
+/// The largest blob the PBF format allows, compressed or uncompressed
+pub(crate) const MAX_BLOB_SIZE: u64 = 32 * 1024 * 1024;
+
+/// The size of the blob header at `offset`, from its 4 byte length prefix, or `None` at the end
+/// of the file. A file which ends in the middle of the prefix is an error.
+pub(crate) fn read_blob_header_size(
+    reader: &mut impl Read,
+    offset: u64,
+) -> Result<Option<u32>, Error> {
+    let mut prefix = [0; 4];
+    let mut len = 0;
+    while len < prefix.len() {
+        match reader.read(&mut prefix[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    match len {
+        0 => Ok(None),
+        4 => Ok(Some(u32::from_be_bytes(prefix))),
+        _ => Err(Error::Format {
+            offset: Some(offset),
+            reason: format!(
+                "File ends in the middle of the blob header size ({} of 4 bytes)",
+                len
+            ),
+        }),
+    }
+}
+
+/// `size`, if it's a valid size for a blob header or blob (no more than `max`), or an error. This
+/// is checked before allocating a buffer, so an invalid file can't allocate gigabytes.
+pub(crate) fn check_blob_size(
+    size: i64,
+    max: u64,
+    offset: u64,
+    what: &str,
+) -> Result<usize, Error> {
+    match u64::try_from(size) {
+        Ok(size) if size <= max => Ok(size as usize),
+        _ => Err(Error::Format {
+            offset: Some(offset),
+            reason: format!("The {} size is {}, but the maximum is {}", what, size, max),
+        }),
+    }
 }
 
-fn blob_raw_data(blob: &mut fileformat::Blob) -> Option<Vec<u8>> {
+/// The uncompressed data of the blob at `offset`, which can't be more than `max_size` bytes
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", name = "decompress_blob", skip(blob))
+)]
+fn blob_raw_data(
+    blob: &mut fileformat::Blob,
+    offset: u64,
+    max_size: u64,
+) -> Result<Vec<u8>, Error> {
     // TODO Shame this can't return a Option<&[u8]>, then I don't need blob to be mut. However I
     // get lifetime errors with bytes not living long enough.
     if blob.has_raw() {
-        Some(blob.take_raw())
+        Ok(blob.take_raw())
     } else if blob.has_zlib_data() {
         let zlib_data = blob.get_zlib_data();
         let cursor = Cursor::new(zlib_data);
-        let mut bytes = Vec::with_capacity(blob.get_raw_size() as usize);
-        ZlibDecoder::new(cursor).read_to_end(&mut bytes).ok()?;
-
-        Some(bytes)
+        let raw_size = i64::from(blob.get_raw_size());
+        let mut bytes = Vec::with_capacity(check_blob_size(raw_size, max_size, offset, "raw")?);
+        ZlibDecoder::new(cursor)
+            .take(max_size + 1)
+            .read_to_end(&mut bytes)
+            .map_err(|source| Error::Decompression { offset, source })?;
+        check_blob_size(bytes.len() as i64, max_size, offset, "uncompressed blob")?;
+
+        Ok(bytes)
     } else {
-        None
+        Err(Error::Format {
+            offset: Some(offset),
+            reason: "Blob uses an unsupported compression".to_string(),
+        })
+    }
+}
+
+/// The metadata of a way or relation
+struct Metadata {
+    deleted: bool,
+    changeset_id: Option<u32>,
+    uid: Option<u32>,
+    user: Option<StrRef>,
+    version: Option<u32>,
+    timestamp: Option<TimestampFormat>,
+}
+
+impl Metadata {
+    /// Only whether the object is deleted
+    fn without_info(deleted: bool) -> Self {
+        Metadata {
+            deleted,
+            changeset_id: None,
+            uid: None,
+            user: None,
+            version: None,
+            timestamp: None,
+        }
+    }
+}
+
+/// Whether the object with this `Info` is visible. Without the flag, objects are visible.
+fn info_visible(info: &osmformat::Info) -> bool {
+    !info.has_visible() || info.get_visible()
+}
+
+/// Reports the anomalies in one block
+struct Anomalies<'a> {
+    mode: &'a ParseMode,
+    /// Offset of the blob
+    offset: u64,
+    duplicate_tags: DuplicateTags,
+    non_positive_ids: NonPositiveIds,
+    /// Don't decode the version, timestamp, changeset, uid & user
+    skip_metadata: bool,
+}
+
+impl Anomalies<'_> {
+    /// Check the ids of this object, and the objects it refers to, are allowed
+    fn ids(
+        &self,
+        object_type: OSMObjectType,
+        id: ObjId,
+        refs: impl Iterator<Item = ObjId>,
+    ) -> Result<(), Error> {
+        self.non_positive_ids
+            .check(object_type, id, refs, Some(self.offset))
+    }
+
+    fn report(
+        &self,
+        kind: AnomalyKind,
+        object_type: OSMObjectType,
+        id: ObjId,
+        detail: String,
+    ) -> Result<(), Error> {
+        self.mode.report(Anomaly {
+            kind,
+            offset: Some(self.offset),
+            object_type,
+            id,
+            detail,
+        })
+    }
+
+    /// The string at `idx`, or `None` (after reporting it) if there isn't a valid one
+    fn string(
+        &self,
+        strings: &StringTable,
+        idx: i64,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Option<StrRef>, Error> {
+        let (idx, anomaly) = strings.check(idx);
+        if let Some((kind, detail)) = anomaly {
+            self.report(kind, object_type, id, detail)?;
+        }
+        Ok(idx.and_then(|idx| StrRef::table(strings, idx)))
+    }
+
+    /// This timestamp, or `None` (after reporting it) if it's out of range
+    fn timestamp(
+        &self,
+        timestamp: Result<TimestampFormat, ParseTimestampError>,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Option<TimestampFormat>, Error> {
+        match timestamp {
+            Ok(timestamp) => Ok(Some(timestamp)),
+            Err(e) => {
+                self.report(
+                    AnomalyKind::InvalidTimestamp,
+                    object_type,
+                    id,
+                    e.to_string(),
+                )?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// The metadata from this `Info`, or none (after reporting it) if there isn't one. Only
+    /// `deleted` is decoded when skipping metadata.
+    fn metadata(
+        &self,
+        info: Option<&osmformat::Info>,
+        date_granularity: i32,
+        strings: &StringTable,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Metadata, Error> {
+        let info = match info {
+            Some(info) if !self.skip_metadata => info,
+            Some(info) => return Ok(Metadata::without_info(!info_visible(info))),
+            None => {
+                if !self.skip_metadata {
+                    self.report(
+                        AnomalyKind::MissingInfo,
+                        object_type,
+                        id,
+                        "no Info".to_string(),
+                    )?;
+                }
+                return Ok(Metadata::without_info(false));
+            }
+        };
+        Ok(Metadata {
+            deleted: !info_visible(info),
+            changeset_id: Some(info.get_changeset() as u32),
+            uid: Some(info.get_uid() as u32),
+            user: self.string(strings, info.get_user_sid().into(), object_type, id)?,
+            version: Some(info.get_version() as u32),
+            timestamp: self.timestamp(
+                TimestampFormat::from_date_granularity(info.get_timestamp(), date_granularity),
+                object_type,
+                id,
+            )?,
+        })
+    }
+
+    /// The tags with these key & value indexes. Invalid ones are left out, and the duplicate
+    /// tags policy is applied.
+    fn tags(
+        &self,
+        strings: &StringTable,
+        keys_vals: impl Iterator<Item = (i64, i64)>,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Vec<(StrRef, StrRef)>, Error> {
+        let mut tags = Vec::new();
+        for (k, v) in keys_vals {
+            let k = self.string(strings, k, object_type, id)?;
+            let v = self.string(strings, v, object_type, id)?;
+            if let (Some(k), Some(v)) = (k, v) {
+                tags.push((k, v));
+            }
+        }
+        let keys = tags.iter().map(|(k, _)| k.resolve(strings));
+        if let Some(duplicates) = self.duplicates(keys, object_type, id)? {
+            duplicates.remove(&mut tags);
+        }
+        Ok(tags)
+    }
+
+    /// The duplicate tags among these keys (after reporting them), if the duplicate tags
+    /// policy removes any
+    fn duplicates<'s>(
+        &self,
+        keys: impl Iterator<Item = &'s str>,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Option<Duplicates>, Error> {
+        let duplicates = match self.duplicate_tags.find(keys) {
+            Some(duplicates) => duplicates,
+            None => return Ok(None),
+        };
+        let anomaly = Anomaly {
+            kind: AnomalyKind::DuplicateTag,
+            offset: Some(self.offset),
+            object_type,
+            id,
+            detail: format!("key {:?}", duplicates.key),
+        };
+        self.duplicate_tags.report(anomaly, self.mode)?;
+        Ok(Some(duplicates))
     }
 }
 
 impl<R: Read> FileReader<R> {
+    /// Reads from `reader` directly, without buffering
     pub fn new(reader: R) -> Self {
-        FileReader { reader }
+        Self::with_capacity(0, reader)
+    }
+
+    /// Reads from `reader` with a buffer of this many bytes
+    pub fn with_capacity(capacity: usize, reader: R) -> Self {
+        FileReader {
+            reader: BufReader::with_capacity(capacity, reader),
+            max_blob_size: MAX_BLOB_SIZE,
+            offset: 0,
+            header: None,
+        }
     }
 
     pub fn inner(&self) -> &R {
-        &self.reader
+        self.reader.get_ref()
     }
 
     pub fn into_inner(self) -> R {
-        self.reader
+        self.reader.into_inner()
     }
 
     fn get_next_osmdata_blob(&mut self) -> Option<fileformat::Blob> {
+        self.try_next_osmdata_blob().unwrap()
+    }
+
+    /// The next `OSMData` blob, `None` at the end of the file, or an error if the file is invalid
+    fn try_next_osmdata_blob(&mut self) -> Result<Option<fileformat::Blob>, Error> {
         loop {
-            // FIXME is there a way we can ask self.reader if it's at EOF? Rather than waiting for
-            // the failure and catching that?
-            let size = self.reader.read_u32::<byteorder::BigEndian>().ok()?;
-            let mut header_bytes_vec = vec![0; size as usize];
+            let offset = self.offset;
+            match self.try_next_blob()? {
+                None => break,
+                Some((blob_type, blob)) if blob_type == "OSMData" => return Ok(Some(blob)),
+                Some((blob_type, mut blob))
+                    if blob_type == "OSMHeader" && self.header.is_none() =>
+                {
+                    self.header = Some(parse_header(&mut blob, offset)?);
+                }
+                // keep going to the next blob
+                Some(_) => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// The type (e.g. `OSMHeader`) & contents of the next blob, `None` at the end of the file
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", name = "read_blob", skip_all, fields(offset = self.offset))
+    )]
+    fn try_next_blob(&mut self) -> Result<Option<(String, fileformat::Blob)>, Error> {
+        let offset = self.offset;
+        let size = match read_blob_header_size(&mut self.reader, offset)? {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        let size = check_blob_size(size.into(), MAX_BLOB_HEADER_SIZE, offset, "blob header")?;
+        let mut header_bytes_vec = vec![0; size];
 
-            self.reader
-                .read_exact(header_bytes_vec.as_mut_slice())
-                .unwrap();
+        self.reader
+            .read_exact(header_bytes_vec.as_mut_slice())
+            .map_err(|e| Error::reading(e, offset, "blob header"))?;
 
-            let blob_header: fileformat::BlobHeader =
-                protobuf::parse_from_bytes(&header_bytes_vec).unwrap();
+        let mut blob_header: fileformat::BlobHeader = protobuf::parse_from_bytes(&header_bytes_vec)
+            .map_err(|e| Error::protobuf(offset, e))?;
 
-            let mut blob_bytes = vec![0; blob_header.get_datasize() as usize];
-            self.reader.read_exact(blob_bytes.as_mut_slice()).unwrap();
+        let datasize = blob_header.get_datasize().into();
+        let datasize = check_blob_size(datasize, self.max_blob_size, offset, "blob")?;
+        let mut blob_bytes = vec![0; datasize];
+        self.reader
+            .read_exact(blob_bytes.as_mut_slice())
+            .map_err(|e| Error::reading(e, offset, "blob"))?;
+        self.offset += 4 + header_bytes_vec.len() as u64 + blob_bytes.len() as u64;
 
-            if blob_header.get_field_type() != "OSMData" {
-                // keep going to the next blob
-                continue;
-            }
+        let blob: fileformat::Blob =
+            protobuf::parse_from_bytes(&blob_bytes).map_err(|e| Error::protobuf(offset, e))?;
+
+        Ok(Some((blob_header.take_field_type(), blob)))
+    }
+}
+
+/// The [`FileInfo`](header::FileInfo) from the `OSMHeader` blob at the start of this PBF file
+pub(crate) fn read_file_info(reader: impl Read) -> Result<header::FileInfo, Error> {
+    let mut reader = FileReader::new(reader);
+    let (blob_type, mut blob) = reader.try_next_blob()?.ok_or_else(|| Error::Format {
+        offset: Some(0),
+        reason: "The file is empty".to_string(),
+    })?;
+    if blob_type != "OSMHeader" {
+        return Err(Error::Format {
+            offset: Some(0),
+            reason: format!("The first blob is {:?}, not OSMHeader", blob_type),
+        });
+    }
+    parse_header(&mut blob, 0)
+}
 
-            let blob: fileformat::Blob = protobuf::parse_from_bytes(&blob_bytes).unwrap();
+/// The [`FileInfo`](header::FileInfo) in this encoded `OSMHeader` blob, which is at `offset`
+pub(crate) fn parse_header_blob(bytes: &[u8], offset: u64) -> Result<header::FileInfo, Error> {
+    let mut blob: fileformat::Blob =
+        protobuf::parse_from_bytes(bytes).map_err(|e| Error::protobuf(offset, e))?;
+    parse_header(&mut blob, offset)
+}
 
-            return Some(blob);
+/// The [`FileInfo`](header::FileInfo) in this `OSMHeader` blob, which is at `offset`
+fn parse_header(blob: &mut fileformat::Blob, offset: u64) -> Result<header::FileInfo, Error> {
+    let bytes = blob_raw_data(blob, offset, MAX_BLOB_SIZE)?;
+    let mut header: osmformat::HeaderBlock =
+        protobuf::parse_from_bytes(&bytes).map_err(|e| Error::protobuf(offset, e))?;
+
+    let bbox = if header.has_bbox() {
+        // In nanodegrees
+        let bbox = header.get_bbox();
+        let coord = |nanos: i64| i32::try_from(nanos / i64::from(COORD_PRECISION_NANOS)).ok();
+        match (
+            coord(bbox.get_bottom()),
+            coord(bbox.get_left()),
+            coord(bbox.get_top()),
+            coord(bbox.get_right()),
+        ) {
+            (Some(bottom), Some(left), Some(top), Some(right)) => Some(BBox::new(
+                Lat::from_inner(bottom),
+                Lon::from_inner(left),
+                Lat::from_inner(top),
+                Lon::from_inner(right),
+            )),
+            _ => None,
         }
+    } else {
+        None
+    };
+    let mut other = std::collections::BTreeMap::new();
+    if header.has_source() {
+        other.insert("source".to_string(), header.take_source());
     }
+    Ok(header::FileInfo {
+        generator: if header.has_writingprogram() {
+            Some(header.take_writingprogram())
+        } else {
+            None
+        },
+        bbox,
+        replication_timestamp: if header.has_osmosis_replication_timestamp() {
+            Some(TimestampFormat::EpochNunber(
+                header.get_osmosis_replication_timestamp(),
+            ))
+        } else {
+            None
+        },
+        replication_sequence: if header.has_osmosis_replication_sequence_number() {
+            Some(header.get_osmosis_replication_sequence_number())
+        } else {
+            None
+        },
+        replication_base_url: if header.has_osmosis_replication_base_url() {
+            Some(header.take_osmosis_replication_base_url())
+        } else {
+            None
+        },
+        required_features: header.take_required_features().into_vec(),
+        optional_features: header.take_optional_features().into_vec(),
+        other,
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decode_nodes(
-    _primitive_group: &osmformat::PrimitiveGroup,
-    _granularity: i32,
-    _lat_offset: i64,
-    _lon_offset: i64,
-    _date_granularity: i32,
-    _stringtable: &[Option<Arc<str>>],
-    _results: &mut [ArcOSMObj],
-) {
-    unimplemented!("Dense node");
+    primitive_group: &osmformat::PrimitiveGroup,
+    granularity: i32,
+    lat_offset: i64,
+    lon_offset: i64,
+    date_granularity: i32,
+    stringtable: &Arc<StringTable>,
+    tag_filter: Option<&TagFilter>,
+    anomalies: &Anomalies,
+    results: &mut Vec<ArcOSMObj>,
+) -> Result<(), Error> {
+    let nodes = primitive_group.get_nodes();
+    results.reserve(nodes.len());
+    for node in nodes {
+        let id = node.get_id() as ObjId;
+        anomalies.ids(OSMObjectType::Node, id, std::iter::empty())?;
+        let keys_vals = node.get_keys().iter().zip(node.get_vals());
+        let keys_vals = keys_vals.map(|(&k, &v)| (k.into(), v.into()));
+        let tags = anomalies.tags(stringtable, keys_vals, OSMObjectType::Node, id)?;
+        if tag_filter.is_some_and(|f| {
+            !f.matches_tags(
+                OSMObjectType::Node,
+                tags.iter()
+                    .map(|(k, v)| (k.resolve(stringtable), v.resolve(stringtable))),
+            )
+        }) {
+            continue;
+        }
+
+        let raw = (node.get_lat(), node.get_lon());
+        let lat_lon = pbf_lat_lon(raw, granularity, (lat_offset, lon_offset), id)?;
+
+        let info = node.has_info().then(|| node.get_info());
+        let metadata =
+            anomalies.metadata(info, date_granularity, stringtable, OSMObjectType::Node, id)?;
+
+        results.push(ArcOSMObj::Node(ArcNode {
+            _id: id,
+            _tags: Some(tags),
+            _lat_lon: Some(lat_lon),
+            _deleted: metadata.deleted,
+            _changeset_id: metadata.changeset_id,
+            _uid: metadata.uid,
+            _strings: Arc::clone(stringtable),
+            _user: metadata.user,
+            _version: metadata.version,
+            _timestamp: metadata.timestamp,
+        }));
+    }
+
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decode_dense_nodes(
     primitive_group: &osmformat::PrimitiveGroup,
     granularity: i32,
     lat_offset: i64,
     lon_offset: i64,
     date_granularity: i32,
-    stringtable: &[Option<Arc<str>>],
+    stringtable: &Arc<StringTable>,
+    tag_filter: Option<&TagFilter>,
+    anomalies: &Anomalies,
     results: &mut Vec<ArcOSMObj>,
-) {
-    let dense = primitive_group.get_dense();
-    let ids = dense.get_id();
-    let lats = dense.get_lat();
-    let lons = dense.get_lon();
-    let denseinfo = dense.get_denseinfo();
-
-    let uids = denseinfo.get_uid();
-    let changesets = denseinfo.get_changeset();
-    let user_sids = denseinfo.get_user_sid();
-    let timestamps = denseinfo.get_timestamp();
-
-    let num_nodes = ids.len();
-    results.reserve(num_nodes);
-    // TODO assert that the id, denseinfo, lat, lon and optionally keys_vals has the same
-    // length
-
-    let keys_vals = dense.get_keys_vals();
-    let has_tags = !keys_vals.is_empty();
-
-    let mut keys_vals_index = 0;
-
-    // NB it's important that these start at zero, makes the code easier later
-    let mut last_id: i64 = 0;
-    let mut last_raw_lat: i32 = 0;
-    let mut last_raw_lon: i32 = 0;
-    let mut last_timestamp = 0;
-    let mut last_changset = 0;
-    let mut last_uid = 0;
-    let mut last_user_sid = 0;
-
-    for index in 0..num_nodes {
-        // last_* start off 0
-        let id = ids[index] + last_id;
-        last_id = id;
-
-        let raw_lat = i32::try_from(lats[index] + last_raw_lat as i64)
-            .expect("raw_lat was larger than the OSM precision allows");
-        last_raw_lat = raw_lat;
-
-        let raw_lon = i32::try_from(lons[index] + last_raw_lon as i64)
-            .expect("raw_lon was larger than OSM precision allows");
-        last_raw_lon = raw_lon;
-
-        // granularity is in nanodegrees
-        let scale_factor = granularity / COORD_PRECISION_NANOS;
-        let mut internal_lat = raw_lat * scale_factor;
-        let mut internal_lon = raw_lon * scale_factor;
-
-        // Offsets from pbf are in nanodegrees
-        let internal_lat_offset = lat_offset / COORD_PRECISION_NANOS as i64;
-        let internal_lon_offset = lon_offset / COORD_PRECISION_NANOS as i64;
-        internal_lat += internal_lat_offset as i32;
-        internal_lon += internal_lon_offset as i32;
-
-        let tags = if !has_tags {
-            None
-        } else {
-            let mut tags = Vec::new();
-            loop {
-                //assert!(keys_vals_index <= keys_vals.len());
-                let next = keys_vals[keys_vals_index];
-                keys_vals_index += 1;
-                if next == 0 {
-                    break;
-                } else {
-                    let key = next;
-                    let val = keys_vals[keys_vals_index];
-                    keys_vals_index += 1;
-                    tags.push((key, val));
-                }
-                // FIXME infinite loop detection maybe?
-            }
+) -> Result<(), Error> {
+    let nodes = DenseNodes::new(
+        dense_arrays(primitive_group.get_dense()),
+        granularity,
+        (lat_offset, lon_offset),
+        date_granularity,
+        anomalies.offset,
+    )?;
+    results.reserve(nodes.len());
+    if let Some(id) = nodes
+        .first_id()
+        .filter(|_| !nodes.has_info() && !anomalies.skip_metadata)
+    {
+        let detail = format!("no metadata for {} nodes", nodes.len());
+        anomalies.report(AnomalyKind::MissingInfo, OSMObjectType::Node, id, detail)?;
+    }
 
-            Some(
-                tags.iter()
-                    .map(|&(kidx, vidx)| {
-                        (
-                            stringtable[kidx as usize].clone(),
-                            stringtable[vidx as usize].clone(),
-                        )
-                    })
-                    .filter_map(|(k, v)| match (k, v) {
-                        (Some(k), Some(v)) => Some((k, v)),
-                        _ => None,
-                    })
-                    .collect(),
-            )
+    for node in nodes {
+        let node = node?;
+        let id = node.id;
+        anomalies.ids(OSMObjectType::Node, id, std::iter::empty())?;
+        let tags = match node.tags() {
+            Some(tags) => Some(anomalies.tags(stringtable, tags, OSMObjectType::Node, id)?),
+            None => None,
         };
+        if let Some(tag_filter) = tag_filter {
+            let tags = tags
+                .iter()
+                .flatten()
+                .map(|(k, v)| (k.resolve(stringtable), v.resolve(stringtable)));
+            if !tag_filter.matches_tags(OSMObjectType::Node, tags) {
+                continue;
+            }
+        }
 
-        let changeset_id = changesets[index] + last_changset;
-        last_changset = changeset_id;
-        let uid_id = uids[index] + last_uid;
-        last_uid = uid_id;
-        let user_sid = user_sids[index] + last_user_sid;
-        last_user_sid = user_sid;
-        let timestamp = timestamps[index] as i32 + last_timestamp;
-        let timestamp = timestamp * date_granularity;
-        last_timestamp = timestamp;
-        let timestamp = TimestampFormat::EpochNunber(timestamp as i64);
-        assert!(uid_id < std::i32::MAX);
-
+        let (changeset_id, uid, user, version, timestamp) = match node.info {
+            Some(info) if !anomalies.skip_metadata => (
+                Some(info.changeset_id),
+                Some(info.uid),
+                anomalies.string(stringtable, info.user_sid, OSMObjectType::Node, id)?,
+                Some(info.version),
+                anomalies.timestamp(info.timestamp, OSMObjectType::Node, id)?,
+            ),
+            _ => (None, None, None, None, None),
+        };
         results.push(ArcOSMObj::Node(ArcNode {
-            _id: id as ObjId,
+            _id: id,
             _tags: tags,
-            _lat_lon: Some((Lat(internal_lat), Lon(internal_lon))),
-            _deleted: !denseinfo.get_visible().get(index).unwrap_or(&true),
-            _changeset_id: Some(changeset_id as u32),
-            _uid: Some(uid_id as u32),
-            _user: Some(stringtable[user_sid as usize].clone().unwrap()),
-            _version: Some(denseinfo.get_version()[index] as u32),
-            _timestamp: Some(timestamp),
+            _lat_lon: Some(node.lat_lon),
+            _deleted: node.deleted,
+            _changeset_id: changeset_id,
+            _uid: uid,
+            _strings: Arc::clone(stringtable),
+            _user: user,
+            _version: version,
+            _timestamp: timestamp,
         }));
     }
 
-    // convert the keys_vals to
+    Ok(())
 }
 
+/// The arrays of this `DenseNodes` message, for [`DenseNodes`]
+fn dense_arrays(dense: &osmformat::DenseNodes) -> DenseArrays<'_> {
+    let info = dense.get_denseinfo();
+    DenseArrays {
+        ids: dense.get_id(),
+        lats: dense.get_lat(),
+        lons: dense.get_lon(),
+        keys_vals: dense.get_keys_vals(),
+        info: dense.has_denseinfo().then(|| DenseInfoArrays {
+            versions: info.get_version(),
+            timestamps: info.get_timestamp(),
+            changesets: info.get_changeset(),
+            uids: info.get_uid(),
+            user_sids: info.get_user_sid(),
+            visible: info.get_visible(),
+        }),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn decode_ways(
     primitive_group: &osmformat::PrimitiveGroup,
     _granularity: i32,
     _lat_offset: i64,
     _lon_offset: i64,
-    _date_granularity: i32,
-    stringtable: &[Option<Arc<str>>],
+    date_granularity: i32,
+    stringtable: &Arc<StringTable>,
+    tag_filter: Option<&TagFilter>,
+    anomalies: &Anomalies,
     results: &mut Vec<ArcOSMObj>,
-) {
+) -> Result<(), Error> {
     let ways = primitive_group.get_ways();
     results.reserve(ways.len());
     for way in ways {
         let id = way.get_id() as ObjId;
-        // TODO check for +itive keys/vals
-        let keys = way
-            .get_keys()
-            .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let vals = way
-            .get_vals()
-            .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let tags = keys.zip(vals);
-        let tags: Vec<_> = tags
-            .filter_map(|(k, v)| match (k, v) {
-                (Some(k), Some(v)) => Some((k, v)),
-                _ => None,
-            })
-            .collect();
+        let keys_vals = way.get_keys().iter().zip(way.get_vals());
+        let keys_vals = keys_vals.map(|(&k, &v)| (k.into(), v.into()));
+        let tags = anomalies.tags(stringtable, keys_vals, OSMObjectType::Way, id)?;
+        if tag_filter.is_some_and(|f| {
+            !f.matches_tags(
+                OSMObjectType::Way,
+                tags.iter()
+                    .map(|(k, v)| (k.resolve(stringtable), v.resolve(stringtable))),
+            )
+        }) {
+            continue;
+        }
 
         let refs = way.get_refs();
         let mut nodes = Vec::with_capacity(refs.len());
@@ -259,74 +668,66 @@ fn decode_ways(
             let mut last_id = refs[0];
             nodes.push(last_id as ObjId);
             for nid in &refs[1..] {
-                last_id += nid;
+                last_id = last_id.wrapping_add(*nid);
                 nodes.push(last_id as ObjId);
             }
         }
+        anomalies.ids(OSMObjectType::Way, id, nodes.iter().copied())?;
 
-        // TODO assert all node ids are positive
-
-        // TODO could there be *no* info? What should be done there
-
-        //println!("from pbf {} last_timestamp {}", way.get_info().get_timestamp(), last_timestamp);
-        //let timestamp = way.get_info().get_timestamp() as i32 + last_timestamp;
-        //let timestamp = timestamp * date_granularity;
-        //last_timestamp = timestamp;
-        //let timestamp = epoch_to_iso(timestamp);
-        let timestamp = TimestampFormat::EpochNunber(way.get_info().get_timestamp());
+        let info = way.has_info().then(|| way.get_info());
+        let metadata =
+            anomalies.metadata(info, date_granularity, stringtable, OSMObjectType::Way, id)?;
 
         results.push(ArcOSMObj::Way(ArcWay {
             _id: id,
             _tags: tags,
             _nodes: nodes,
-            _deleted: !way.get_info().get_visible(),
-            _changeset_id: Some(way.get_info().get_changeset() as u32),
-            _uid: Some(way.get_info().get_uid() as u32),
-            _user: Some(
-                stringtable[way.get_info().get_user_sid() as usize]
-                    .clone()
-                    .unwrap()
-                    .clone(),
-            ),
-            _version: Some(way.get_info().get_version() as u32),
-            _timestamp: Some(timestamp),
+            _deleted: metadata.deleted,
+            _changeset_id: metadata.changeset_id,
+            _uid: metadata.uid,
+            _strings: Arc::clone(stringtable),
+            _user: metadata.user,
+            _version: metadata.version,
+            _timestamp: metadata.timestamp,
         }));
     }
+
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decode_relations(
     primitive_group: &osmformat::PrimitiveGroup,
     _granularity: i32,
     _lat_offset: i64,
     _lon_offset: i64,
-    _date_granularity: i32,
-    stringtable: &[Option<Arc<str>>],
+    date_granularity: i32,
+    stringtable: &Arc<StringTable>,
+    tag_filter: Option<&TagFilter>,
+    anomalies: &Anomalies,
     results: &mut Vec<ArcOSMObj>,
-) {
+) -> Result<(), Error> {
     let _last_timestamp = 0;
     for relation in primitive_group.get_relations() {
         let id = relation.get_id() as ObjId;
-        // TODO check for +itive keys/vals
-        let keys = relation
-            .get_keys()
-            .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let vals = relation
-            .get_vals()
-            .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let tags = keys.zip(vals);
-        let tags: Vec<_> = tags
-            .filter_map(|(k, v)| match (k, v) {
-                (Some(k), Some(v)) => Some((k, v)),
-                _ => None,
-            })
-            .collect();
+        let keys_vals = relation.get_keys().iter().zip(relation.get_vals());
+        let keys_vals = keys_vals.map(|(&k, &v)| (k.into(), v.into()));
+        let tags = anomalies.tags(stringtable, keys_vals, OSMObjectType::Relation, id)?;
+        if tag_filter.is_some_and(|f| {
+            !f.matches_tags(
+                OSMObjectType::Relation,
+                tags.iter()
+                    .map(|(k, v)| (k.resolve(stringtable), v.resolve(stringtable))),
+            )
+        }) {
+            continue;
+        }
 
         let roles = relation
             .get_roles_sid()
             .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
+            .map(|&idx| anomalies.string(stringtable, idx.into(), OSMObjectType::Relation, id))
+            .collect::<Result<Vec<_>, _>>()?;
 
         let refs = relation.get_memids();
         let mut member_ids = Vec::with_capacity(refs.len());
@@ -335,10 +736,11 @@ fn decode_relations(
             let mut last_id = refs[0];
             member_ids.push(last_id as ObjId);
             for nid in &refs[1..] {
-                last_id += nid;
+                last_id = last_id.wrapping_add(*nid);
                 member_ids.push(last_id as ObjId);
             }
         }
+        anomalies.ids(OSMObjectType::Relation, id, member_ids.iter().copied())?;
         let _num_members = member_ids.len();
         let member_ids = member_ids.iter();
 
@@ -354,42 +756,49 @@ fn decode_relations(
             .filter_map(|((t, &id), r_opt)| r_opt.map(|r| (t, id, r)))
             .collect();
 
-        // TODO could there be *no* info? What should be done there
-        //let timestamp = relation.get_info().get_timestamp() as i32 + last_timestamp;
-        //let timestamp = timestamp * date_granularity;
-        //last_timestamp = timestamp;
-        //let timestamp = epoch_to_iso(timestamp);
-        let timestamp = TimestampFormat::EpochNunber(relation.get_info().get_timestamp());
+        let info = relation.has_info().then(|| relation.get_info());
+        let metadata = anomalies.metadata(
+            info,
+            date_granularity,
+            stringtable,
+            OSMObjectType::Relation,
+            id,
+        )?;
 
         results.push(ArcOSMObj::Relation(ArcRelation {
             _id: id,
             _tags: tags,
             _members: members,
-            _deleted: !relation.get_info().get_visible(),
-            _changeset_id: Some(relation.get_info().get_changeset() as u32),
-            _uid: Some(relation.get_info().get_uid() as u32),
-            _user: Some(
-                stringtable[relation.get_info().get_user_sid() as usize]
-                    .clone()
-                    .unwrap(),
-            ),
-            _version: Some(relation.get_info().get_version() as u32),
-            _timestamp: Some(timestamp),
+            _deleted: metadata.deleted,
+            _changeset_id: metadata.changeset_id,
+            _uid: metadata.uid,
+            _strings: Arc::clone(stringtable),
+            _user: metadata.user,
+            _version: metadata.version,
+            _timestamp: metadata.timestamp,
         }));
     }
+
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decode_primitive_group_to_objs(
     primitive_group: &osmformat::PrimitiveGroup,
     granularity: i32,
     lat_offset: i64,
     lon_offset: i64,
     date_granularity: i32,
-    stringtable: &[Option<Arc<str>>],
+    stringtable: &Arc<StringTable>,
+    tag_filter: Option<&TagFilter>,
+    anomalies: &Anomalies,
     results: &mut Vec<ArcOSMObj>,
-) {
-    let date_granularity = date_granularity / 1000;
+) -> Result<(), Error> {
+    let wanted = |object_type| tag_filter.map_or(true, |f| f.matches_type(object_type));
     if !primitive_group.get_nodes().is_empty() {
+        if !wanted(OSMObjectType::Node) {
+            return Ok(());
+        }
         decode_nodes(
             primitive_group,
             granularity,
@@ -397,9 +806,14 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            tag_filter,
+            anomalies,
             results,
-        );
+        )?;
     } else if primitive_group.has_dense() {
+        if !wanted(OSMObjectType::Node) {
+            return Ok(());
+        }
         decode_dense_nodes(
             primitive_group,
             granularity,
@@ -407,9 +821,14 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            tag_filter,
+            anomalies,
             results,
-        );
+        )?;
     } else if !primitive_group.get_ways().is_empty() {
+        if !wanted(OSMObjectType::Way) {
+            return Ok(());
+        }
         decode_ways(
             primitive_group,
             granularity,
@@ -417,9 +836,14 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            tag_filter,
+            anomalies,
             results,
-        );
+        )?;
     } else if !primitive_group.get_relations().is_empty() {
+        if !wanted(OSMObjectType::Relation) {
+            return Ok(());
+        }
         decode_relations(
             primitive_group,
             granularity,
@@ -427,20 +851,31 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            tag_filter,
+            anomalies,
             results,
-        );
+        )?;
     } else {
-        unreachable!();
+        // e.g. a group of changesets, which aren't read
     }
+    Ok(())
 }
 
-fn decode_block_to_objs(mut block: osmformat::PrimitiveBlock) -> Vec<ArcOSMObj> {
-    let stringtable: Vec<Option<Arc<str>>> = block
-        .take_stringtable()
-        .take_s()
-        .into_iter()
-        .map(|chars| std::str::from_utf8(&chars).ok().map(Arc::from))
-        .collect();
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", name = "decode_block", skip_all, fields(offset = anomalies.offset))
+)]
+fn decode_block_to_objs(
+    block: osmformat::PrimitiveBlock,
+    tag_filter: Option<&TagFilter>,
+    lossy_utf8: bool,
+    anomalies: &Anomalies,
+) -> Result<Vec<ArcOSMObj>, Error> {
+    // All the objects share this, so decoding a tag doesn't need any allocations or refcounting
+    let stringtable = Arc::new(StringTable::new(
+        block.get_stringtable().get_s(),
+        lossy_utf8,
+    ));
 
     let granularity = block.get_granularity();
     let lat_offset = block.get_lat_offset();
@@ -457,11 +892,13 @@ fn decode_block_to_objs(mut block: osmformat::PrimitiveBlock) -> Vec<ArcOSMObj>
             lon_offset,
             date_granularity,
             &stringtable,
+            tag_filter,
+            anomalies,
             &mut results,
-        );
+        )?;
     }
 
-    results
+    Ok(results)
 }
 
 impl<R: Read> Iterator for FileReader<R> {
@@ -477,6 +914,15 @@ pub struct PBFReader<R: Read> {
     filereader: FileReader<R>,
     _buffer: Vec<ArcOSMObj>,
     _sorted_assumption: bool,
+    tag_filter: Option<TagFilter>,
+    cancellation: Option<CancellationToken>,
+    parse_mode: ParseMode,
+    lossy_utf8: bool,
+    duplicate_tags: DuplicateTags,
+    non_positive_ids: NonPositiveIds,
+    memory_budget: Option<MemoryBudget>,
+    threads: usize,
+    skip_metadata: bool,
 }
 
 impl PBFReader<BufReader<File>> {
@@ -485,6 +931,652 @@ impl PBFReader<BufReader<File>> {
         let filename: &Path = filename.as_ref();
         Ok(Self::new(BufReader::new(File::open(filename)?)))
     }
+
+    /// A [`PBFReaderBuilder`] to configure a reader
+    pub fn builder() -> PBFReaderBuilder {
+        PBFReaderBuilder::new()
+    }
+}
+
+impl<R: Read> PBFReader<R> {
+    /// A reader with the default settings, which reads blobs from `filereader`
+    fn with_filereader(filereader: FileReader<R>) -> Self {
+        PBFReader {
+            filereader,
+            _buffer: Vec::new(),
+            _sorted_assumption: false,
+            tag_filter: None,
+            cancellation: None,
+            parse_mode: ParseMode::default(),
+            lossy_utf8: false,
+            duplicate_tags: DuplicateTags::default(),
+            non_positive_ids: NonPositiveIds::default(),
+            memory_budget: None,
+            threads: 1,
+            skip_metadata: false,
+        }
+    }
+
+    /// Only return objects which match this filter (or all objects if `None`).
+    ///
+    /// The filter is applied while decoding each block, so objects which don't match are never
+    /// built, and blocks of object types which can't match are skipped entirely.
+    pub fn set_tag_filter(&mut self, tag_filter: impl Into<Option<TagFilter>>) {
+        self.tag_filter = tag_filter.into();
+    }
+
+    /// The current tag filter, if any
+    pub fn tag_filter(&self) -> Option<&TagFilter> {
+        self.tag_filter.as_ref()
+    }
+
+    /// Stop reading when this token is cancelled. It's checked before every block is read, and
+    /// then `try_next` returns an [`Error::Cancelled`] (and `next`
+    /// returns `None`).
+    pub fn set_cancellation(&mut self, cancellation: impl Into<Option<CancellationToken>>) {
+        self.cancellation = cancellation.into();
+    }
+
+    /// Count the blocks which [`par_for_each_block`](Self::par_for_each_block) has read, but not
+    /// finished with, against this budget (or don't limit them if `None`). When it's used up,
+    /// reading waits until a block has been decoded & passed to `f`.
+    pub fn set_memory_budget(&mut self, memory_budget: impl Into<Option<MemoryBudget>>) {
+        self.memory_budget = memory_budget.into();
+    }
+
+    /// Decompress & decode blocks on this many threads in
+    /// [`for_each_block`](Self::for_each_block), which still passes them in order (default: 1,
+    /// i.e. on the calling thread).
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Don't decode the version, timestamp, changeset, uid & user of objects, which are then
+    /// `None` (default: `false`). Objects without metadata aren't an anomaly then.
+    pub fn set_skip_metadata(&mut self, skip_metadata: bool) {
+        self.skip_metadata = skip_metadata;
+    }
+
+    /// Blobs larger than this, compressed or uncompressed, are an error (default, and maximum:
+    /// the 32 MiB which the PBF format allows).
+    pub fn set_max_blob_size(&mut self, max_blob_size: u64) {
+        self.filereader.max_blob_size = max_blob_size.min(MAX_BLOB_SIZE);
+    }
+
+    /// Reports the anomalies in the block at `offset`
+    fn anomalies(&self, offset: u64) -> Anomalies<'_> {
+        Anomalies {
+            mode: &self.parse_mode,
+            offset,
+            duplicate_tags: self.duplicate_tags,
+            non_positive_ids: self.non_positive_ids,
+            skip_metadata: self.skip_metadata,
+        }
+    }
+}
+
+/// Configures a [`PBFReader`], instead of calling setters after creating it.
+///
+/// ```no_run
+/// use osmio::pbf::PBFReader;
+/// use osmio::OSMReader;
+///
+/// let mut reader = PBFReader::builder()
+///     .sorted_assumption(true)
+///     .tag_filter("n/amenity=pub".parse()?)
+///     .buffer_size(1 << 20)
+///     .threads(4)
+///     .skip_metadata(true)
+///     .open("input.osm.pbf")?;
+/// let num_pubs = reader.objects().count();
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PBFReaderBuilder {
+    sorted_assumption: bool,
+    tag_filter: Option<TagFilter>,
+    buffer_size: Option<usize>,
+    cancellation: Option<CancellationToken>,
+    parse_mode: ParseMode,
+    lossy_utf8: bool,
+    duplicate_tags: DuplicateTags,
+    non_positive_ids: NonPositiveIds,
+    memory_budget: Option<MemoryBudget>,
+    threads: Option<usize>,
+    skip_metadata: bool,
+    max_blob_size: Option<u64>,
+}
+
+impl PBFReaderBuilder {
+    /// The same settings as [`PBFReader::new`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Assume the file is sorted (default: `false`). See [`OSMReader::set_sorted_assumption`].
+    pub fn sorted_assumption(mut self, sorted_assumption: bool) -> Self {
+        self.sorted_assumption = sorted_assumption;
+        self
+    }
+
+    /// Only return objects which match this filter (default: all objects). See
+    /// [`PBFReader::set_tag_filter`].
+    pub fn tag_filter(mut self, tag_filter: TagFilter) -> Self {
+        self.tag_filter = Some(tag_filter);
+        self
+    }
+
+    /// Stop reading when this token is cancelled. See [`PBFReader::set_cancellation`].
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// What to do with invalid data (default: lenient). See [`OSMReader::set_parse_mode`].
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    /// Decode strings which aren't valid UTF-8 lossily (default: `false`). See
+    /// [`OSMReader::set_lossy_utf8`].
+    pub fn lossy_utf8(mut self, lossy_utf8: bool) -> Self {
+        self.lossy_utf8 = lossy_utf8;
+        self
+    }
+
+    /// What to do with duplicate tags (default: keep them all). See
+    /// [`OSMReader::set_duplicate_tags`].
+    pub fn duplicate_tags(mut self, duplicate_tags: DuplicateTags) -> Self {
+        self.duplicate_tags = duplicate_tags;
+        self
+    }
+
+    /// What to do with negative or zero ids (default: allow them). See
+    /// [`OSMReader::set_non_positive_ids`].
+    pub fn non_positive_ids(mut self, non_positive_ids: NonPositiveIds) -> Self {
+        self.non_positive_ids = non_positive_ids;
+        self
+    }
+
+    /// Limit the memory used by blocks which are being decoded in parallel (default: no limit).
+    /// See [`PBFReader::set_memory_budget`].
+    pub fn memory_budget(mut self, memory_budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(memory_budget);
+        self
+    }
+
+    /// Decompress & decode blocks on this many threads (default: 1). See
+    /// [`PBFReader::set_threads`].
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads);
+        self
+    }
+
+    /// Don't decode the version, timestamp, changeset, uid & user (default: `false`). See
+    /// [`PBFReader::set_skip_metadata`].
+    pub fn skip_metadata(mut self, skip_metadata: bool) -> Self {
+        self.skip_metadata = skip_metadata;
+        self
+    }
+
+    /// Blobs larger than this are an error (default: 32 MiB). See
+    /// [`PBFReader::set_max_blob_size`].
+    pub fn max_blob_size(mut self, max_blob_size: u64) -> Self {
+        self.max_blob_size = Some(max_blob_size);
+        self
+    }
+
+    /// Size of the read buffer (default: the `BufReader` default for
+    /// [`open`](Self::open), and no buffer for [`build`](Self::build)). With a buffer,
+    /// [`OSMReader::into_inner`] loses the data which has been read into it.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = Some(buffer_size);
+        self
+    }
+
+    /// Create a reader which reads from `reader`
+    pub fn build<R: Read>(self, reader: R) -> PBFReader<R> {
+        let filereader = match self.buffer_size {
+            Some(buffer_size) => FileReader::with_capacity(buffer_size, reader),
+            None => FileReader::new(reader),
+        };
+        let mut pbf_reader = PBFReader::with_filereader(filereader);
+        pbf_reader.set_sorted_assumption(self.sorted_assumption);
+        pbf_reader.set_tag_filter(self.tag_filter);
+        pbf_reader.set_cancellation(self.cancellation);
+        pbf_reader.set_parse_mode(self.parse_mode);
+        pbf_reader.set_lossy_utf8(self.lossy_utf8);
+        pbf_reader.set_duplicate_tags(self.duplicate_tags);
+        pbf_reader.set_non_positive_ids(self.non_positive_ids);
+        pbf_reader.set_memory_budget(self.memory_budget);
+        pbf_reader.set_threads(self.threads.unwrap_or(1));
+        pbf_reader.set_skip_metadata(self.skip_metadata);
+        pbf_reader.set_max_blob_size(self.max_blob_size.unwrap_or(MAX_BLOB_SIZE));
+        pbf_reader
+    }
+
+    /// Open this file
+    pub fn open(mut self, filename: impl AsRef<Path>) -> Result<PBFReader<BufReader<File>>> {
+        let file = File::open(filename.as_ref())?;
+        // Buffered here, rather than twice
+        let file = match self.buffer_size.take() {
+            Some(buffer_size) => BufReader::with_capacity(buffer_size, file),
+            None => BufReader::new(file),
+        };
+        Ok(self.build(file))
+    }
+}
+
+impl<R: Read> PBFReader<R> {
+    /// Read & decompress the next block (and the offset of its blob), checking for cancellation
+    /// first
+    fn try_next_block(&mut self) -> Result<Option<(u64, osmformat::PrimitiveBlock)>, Error> {
+        cancel::check(&self.cancellation)?;
+        let offset = self.filereader.offset;
+        let mut blob = match self.filereader.try_next_osmdata_blob()? {
+            None => return Ok(None),
+            Some(blob) => blob,
+        };
+
+        let blob_data = blob_raw_data(&mut blob, offset, self.filereader.max_blob_size)?;
+        let block =
+            protobuf::parse_from_bytes(&blob_data).map_err(|e| Error::protobuf(offset, e))?;
+        Ok(Some((offset, block)))
+    }
+
+    /// The objects in the next block (which may be empty), or `None` at the end of the file
+    fn try_next_block_objs(&mut self) -> Result<Option<Vec<ArcOSMObj>>, Error> {
+        let (offset, block) = match self.try_next_block()? {
+            None => return Ok(None),
+            Some(block) => block,
+        };
+        let anomalies = self.anomalies(offset);
+        decode_block_to_objs(block, self.tag_filter.as_ref(), self.lossy_utf8, &anomalies).map(Some)
+    }
+
+    /// Call `f` with the objects of each block, in order, until the end of the file.
+    ///
+    /// This avoids the overhead of returning objects one at a time, which helps when aggregating
+    /// the whole file. Blocks with no (matching) objects are skipped. Any objects already read
+    /// into the buffer (by `next`) are passed first. Returns an error if the file is invalid, or
+    /// reading is cancelled.
+    ///
+    /// With [`set_threads`](Self::set_threads), blocks are decompressed & decoded on other
+    /// threads, but `f` is still called on this thread, in order. Blocks before an error are
+    /// passed to `f` before it's returned.
+    ///
+    /// ```no_run
+    /// use osmio::pbf::PBFReader;
+    /// use osmio::OSMObj;
+    ///
+    /// let mut reader = PBFReader::from_filename("input.osm.pbf")?;
+    /// let mut num_ways = 0;
+    /// reader.for_each_block(|objs| num_ways += objs.iter().filter(|o| o.is_way()).count())?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn for_each_block(&mut self, mut f: impl FnMut(Vec<ArcOSMObj>)) -> Result<(), Error> {
+        if !self._buffer.is_empty() {
+            let mut objs = std::mem::take(&mut self._buffer);
+            objs.reverse();
+            f(objs);
+        }
+        if self.threads > 1 {
+            return self.for_each_block_threaded(f);
+        }
+        while let Some(objs) = self.try_next_block_objs()? {
+            if !objs.is_empty() {
+                f(objs);
+            }
+        }
+        Ok(())
+    }
+
+    /// [`for_each_block`](Self::for_each_block) with blocks decoded on `self.threads` threads.
+    /// Up to twice as many blocks are read ahead, and put back in order for `f`.
+    fn for_each_block_threaded(&mut self, mut f: impl FnMut(Vec<ArcOSMObj>)) -> Result<(), Error> {
+        let threads = self.threads;
+        let max_blob_size = self.filereader.max_blob_size;
+        let PBFReader {
+            filereader,
+            tag_filter,
+            cancellation,
+            parse_mode,
+            lossy_utf8,
+            duplicate_tags,
+            non_positive_ids,
+            skip_metadata,
+            ..
+        } = self;
+        let decode = |offset: u64, blob: fileformat::Blob| {
+            let anomalies = Anomalies {
+                mode: parse_mode,
+                offset,
+                duplicate_tags: *duplicate_tags,
+                non_positive_ids: *non_positive_ids,
+                skip_metadata: *skip_metadata,
+            };
+            decode_blob(
+                blob,
+                max_blob_size,
+                tag_filter.as_ref(),
+                *lossy_utf8,
+                &anomalies,
+            )
+        };
+        // Blobs to decode, and the decoded blocks (or the panic), by sequence number
+        let (jobs_tx, jobs_rx) = std::sync::mpsc::channel::<(usize, u64, fileformat::Blob)>();
+        let jobs_rx = std::sync::Mutex::new(jobs_rx);
+        let (results_tx, results_rx) = std::sync::mpsc::channel();
+        let stop = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                let (decode, jobs_rx, stop) = (&decode, &jobs_rx, &stop);
+                let results_tx = results_tx.clone();
+                scope.spawn(move || loop {
+                    let job = jobs_rx.lock().unwrap().recv();
+                    let (seq, offset, blob) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let objs = if stop.load(std::sync::atomic::Ordering::Relaxed) {
+                        Ok(Ok(Vec::new()))
+                    } else {
+                        std::panic::catch_unwind(AssertUnwindSafe(|| decode(offset, blob)))
+                    };
+                    if results_tx.send((seq, objs)).is_err() {
+                        break;
+                    }
+                });
+            }
+            // Owned by this closure, so the workers stop when it returns (or panics)
+            let jobs_tx = jobs_tx;
+            let mut pending = std::collections::BTreeMap::new();
+            let (mut num_read, mut num_passed) = (0, 0);
+            let mut reading = true;
+            let mut read_error = None;
+            loop {
+                while reading && num_read - num_passed < threads * 2 {
+                    let offset = filereader.offset;
+                    let blob = cancel::check(cancellation)
+                        .map_err(Error::from)
+                        .and_then(|()| filereader.try_next_osmdata_blob());
+                    match blob {
+                        Ok(Some(blob)) => {
+                            jobs_tx.send((num_read, offset, blob)).unwrap();
+                            num_read += 1;
+                        }
+                        Ok(None) => reading = false,
+                        Err(e) => {
+                            read_error = Some(e);
+                            reading = false;
+                        }
+                    }
+                }
+                if num_passed == num_read {
+                    break;
+                }
+                while !pending.contains_key(&num_passed) {
+                    let (seq, objs) = results_rx.recv().unwrap();
+                    pending.insert(seq, objs);
+                }
+                match pending.remove(&num_passed).unwrap() {
+                    Ok(Ok(objs)) if objs.is_empty() => {}
+                    Ok(Ok(objs)) => f(objs),
+                    Ok(Err(e)) => {
+                        stop.store(true, std::sync::atomic::Ordering::Relaxed);
+                        return Err(e);
+                    }
+                    Err(payload) => std::panic::resume_unwind(payload),
+                }
+                num_passed += 1;
+            }
+            match read_error {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Like [`for_each_block`](Self::for_each_block), but blocks are decompressed & decoded on
+    /// `threads` threads, and `f` is called on those threads, so blocks are passed in no
+    /// particular order.
+    ///
+    /// If there's an error, reading stops, and the first error is returned (some blocks after it
+    /// may have been passed to `f` already). If `f` panics, reading stops, and the panic is
+    /// resumed on this thread once the other threads have finished.
+    ///
+    /// ```no_run
+    /// use osmio::pbf::PBFReader;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let mut reader = PBFReader::from_filename("input.osm.pbf")?;
+    /// let num_objs = AtomicUsize::new(0);
+    /// reader.par_for_each_block(4, |objs| {
+    ///     num_objs.fetch_add(objs.len(), Ordering::Relaxed);
+    /// })?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn par_for_each_block(
+        &mut self,
+        threads: usize,
+        f: impl Fn(Vec<ArcOSMObj>) + Sync,
+    ) -> Result<(), Error> {
+        let threads = threads.max(1);
+        self.par_for_each_block_with(threads, f, |worker, read| {
+            std::thread::scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(worker);
+                }
+                read();
+            })
+        })
+    }
+
+    /// Like [`par_for_each_block`](Self::par_for_each_block), but blocks are decompressed &
+    /// decoded (and `f` is called) on the threads of this rayon pool, rather than new threads,
+    /// so an application which already has a pool doesn't end up with more threads than cores.
+    /// Every thread in the pool is used until the file has been read. The file is read on this
+    /// thread, which shouldn't be one of the pool's threads.
+    ///
+    /// ```no_run
+    /// use osmio::pbf::PBFReader;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build()?;
+    /// let mut reader = PBFReader::from_filename("input.osm.pbf")?;
+    /// let num_objs = AtomicUsize::new(0);
+    /// reader.par_for_each_block_in(&pool, |objs| {
+    ///     num_objs.fetch_add(objs.len(), Ordering::Relaxed);
+    /// })?;
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    #[cfg(feature = "rayon")]
+    pub fn par_for_each_block_in(
+        &mut self,
+        pool: &rayon::ThreadPool,
+        f: impl Fn(Vec<ArcOSMObj>) + Sync,
+    ) -> Result<(), Error> {
+        let threads = pool.current_num_threads().max(1);
+        self.par_for_each_block_with(threads, f, |worker, read| {
+            pool.in_place_scope(|scope| {
+                for _ in 0..threads {
+                    scope.spawn(move |_| worker());
+                }
+                read();
+            })
+        })
+    }
+
+    /// Decode blocks with `threads` workers. `run` must run the worker on `threads` other
+    /// threads, and the reading function on this thread, and return once they've all finished.
+    fn par_for_each_block_with(
+        &mut self,
+        threads: usize,
+        f: impl Fn(Vec<ArcOSMObj>) + Sync,
+        run: impl FnOnce(&(dyn Fn() + Sync), Box<dyn FnOnce() + '_>),
+    ) -> Result<(), Error> {
+        if !self._buffer.is_empty() {
+            let mut objs = std::mem::take(&mut self._buffer);
+            objs.reverse();
+            f(objs);
+        }
+        let PBFReader {
+            filereader,
+            tag_filter,
+            cancellation,
+            parse_mode,
+            lossy_utf8,
+            duplicate_tags,
+            non_positive_ids,
+            memory_budget,
+            skip_metadata,
+            ..
+        } = self;
+        let max_blob_size = filereader.max_blob_size;
+        let (tx, rx) = std::sync::mpsc::sync_channel::<(u64, fileformat::Blob, Option<Reservation>)>(
+            threads * 2,
+        );
+        let rx = std::sync::Mutex::new(rx);
+        // Number of blocks which have been read, but not finished with, and a signal when one is
+        let in_flight = (std::sync::Mutex::new(0_usize), std::sync::Condvar::new());
+        let first_error: std::sync::Mutex<Option<Error>> = std::sync::Mutex::new(None);
+        let failed = std::sync::atomic::AtomicBool::new(false);
+        let fail = |e: Error| {
+            failed.store(true, std::sync::atomic::Ordering::Relaxed);
+            first_error.lock().unwrap().get_or_insert(e);
+        };
+        let first_panic: std::sync::Mutex<Option<Box<dyn std::any::Any + Send>>> =
+            std::sync::Mutex::new(None);
+
+        let decode = |offset: u64, blob: fileformat::Blob| {
+            let anomalies = Anomalies {
+                mode: parse_mode,
+                offset,
+                duplicate_tags: *duplicate_tags,
+                non_positive_ids: *non_positive_ids,
+                skip_metadata: *skip_metadata,
+            };
+            let objs = decode_blob(
+                blob,
+                max_blob_size,
+                tag_filter.as_ref(),
+                *lossy_utf8,
+                &anomalies,
+            );
+            match objs {
+                Ok(objs) if objs.is_empty() => {}
+                Ok(objs) => {
+                    // Without this, the reading thread would block forever once every
+                    // thread has panicked
+                    let res = std::panic::catch_unwind(AssertUnwindSafe(|| f(objs)));
+                    if let Err(payload) = res {
+                        failed.store(true, std::sync::atomic::Ordering::Relaxed);
+                        first_panic.lock().unwrap().get_or_insert(payload);
+                    }
+                }
+                Err(e) => fail(e),
+            }
+        };
+        let worker = || loop {
+            let received = rx.lock().unwrap().recv();
+            let (offset, blob, reservation) = match received {
+                Ok(received) => received,
+                Err(_) => break,
+            };
+            // Keep receiving after an error, so the reading thread never blocks
+            if !failed.load(std::sync::atomic::Ordering::Relaxed) {
+                decode(offset, blob);
+            }
+            drop(reservation);
+            *in_flight.0.lock().unwrap() -= 1;
+            in_flight.1.notify_one();
+        };
+        // Wait for blocks to be finished with, rather than go over the budget. If there are none,
+        // the memory is used elsewhere, so go over it, to make progress.
+        let reserve = |budget: &MemoryBudget, bytes: usize| -> Result<Reservation, Error> {
+            let mut reservation = budget.reservation();
+            let mut queued = in_flight.0.lock().unwrap();
+            while !reservation.try_grow(bytes) {
+                if *queued == 0 || failed.load(std::sync::atomic::Ordering::Relaxed) {
+                    reservation.grow(bytes);
+                    break;
+                }
+                cancel::check(cancellation)?;
+                // Other components can give back memory too, without a signal
+                queued = in_flight
+                    .1
+                    .wait_timeout(queued, std::time::Duration::from_millis(100))
+                    .unwrap()
+                    .0;
+            }
+            Ok(reservation)
+        };
+        let read = || {
+            while !failed.load(std::sync::atomic::Ordering::Relaxed) {
+                if let Err(e) = cancel::check(cancellation) {
+                    fail(e.into());
+                    break;
+                }
+                let offset = filereader.offset;
+                match filereader.try_next_osmdata_blob() {
+                    Ok(Some(blob)) => {
+                        let reservation = match memory_budget {
+                            Some(budget) => match reserve(budget, blob_memory(&blob)) {
+                                Ok(reservation) => Some(reservation),
+                                Err(e) => {
+                                    fail(e);
+                                    break;
+                                }
+                            },
+                            None => None,
+                        };
+                        *in_flight.0.lock().unwrap() += 1;
+                        if tx.send((offset, blob, reservation)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        fail(e);
+                        break;
+                    }
+                }
+            }
+            drop(tx);
+        };
+        run(&worker, Box::new(read));
+
+        if let Some(payload) = first_panic.into_inner().unwrap() {
+            std::panic::resume_unwind(payload);
+        }
+        match first_error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Decompress & decode the `OSMData` blob, which `anomalies` is for
+fn decode_blob(
+    mut blob: fileformat::Blob,
+    max_size: u64,
+    tag_filter: Option<&TagFilter>,
+    lossy_utf8: bool,
+    anomalies: &Anomalies,
+) -> Result<Vec<ArcOSMObj>, Error> {
+    let offset = anomalies.offset;
+    let data = blob_raw_data(&mut blob, offset, max_size)?;
+    let block = protobuf::parse_from_bytes(&data).map_err(|e| Error::protobuf(offset, e))?;
+    decode_block_to_objs(block, tag_filter, lossy_utf8, anomalies)
+}
+
+/// Roughly how much memory a blob uses until its objects have been passed on: the blob, its
+/// decompressed data, and the decoded block.
+fn blob_memory(blob: &fileformat::Blob) -> usize {
+    let raw_size = blob.get_raw_size().max(0) as usize;
+    blob.get_raw().len() + blob.get_zlib_data().len() + blob.get_lzma_data().len() + 2 * raw_size
 }
 
 impl<R: Read> OSMReader for PBFReader<R> {
@@ -492,11 +1584,7 @@ impl<R: Read> OSMReader for PBFReader<R> {
     type Obj = ArcOSMObj;
 
     fn new(reader: R) -> PBFReader<R> {
-        PBFReader {
-            filereader: FileReader::new(reader),
-            _buffer: Vec::new(),
-            _sorted_assumption: false,
-        }
+        PBFReader::with_filereader(FileReader::new(reader))
     }
 
     fn set_sorted_assumption(&mut self, sorted_assumption: bool) {
@@ -506,27 +1594,51 @@ impl<R: Read> OSMReader for PBFReader<R> {
         self._sorted_assumption
     }
 
+    fn set_parse_mode(&mut self, parse_mode: ParseMode) {
+        self.parse_mode = parse_mode;
+    }
+
+    fn set_lossy_utf8(&mut self, lossy_utf8: bool) {
+        self.lossy_utf8 = lossy_utf8;
+    }
+
+    fn set_duplicate_tags(&mut self, duplicate_tags: DuplicateTags) {
+        self.duplicate_tags = duplicate_tags;
+    }
+
+    fn set_non_positive_ids(&mut self, non_positive_ids: NonPositiveIds) {
+        self.non_positive_ids = non_positive_ids;
+    }
+
     fn inner(&self) -> &R {
         self.filereader.inner()
     }
 
+    /// The `OSMHeader` blob, which is read with the first block
+    fn file_info(&self) -> Option<&header::FileInfo> {
+        self.filereader.header.as_ref()
+    }
+
     fn into_inner(self) -> R {
         self.filereader.into_inner()
     }
 
+    /// Panics if the file is invalid, use `try_next` to get an error instead. Returns `None` if
+    /// cancelled.
     fn next(&mut self) -> Option<ArcOSMObj> {
+        match self.try_next() {
+            Err(Error::Cancelled(_)) => None,
+            res => res.unwrap(),
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<ArcOSMObj>, Error> {
         while self._buffer.is_empty() {
             // get the next file block and fill up our buffer
-            // FIXME make this parallel
-
-            // get the next block
-            let mut blob = self.filereader.next()?;
-
-            let blob_data = blob_raw_data(&mut blob).unwrap();
-            let block: osmformat::PrimitiveBlock = protobuf::parse_from_bytes(&blob_data).unwrap();
-
-            // Turn a block into OSM objects
-            let mut objs = decode_block_to_objs(block);
+            let mut objs = match self.try_next_block_objs()? {
+                None => return Ok(None),
+                Some(objs) => objs,
+            };
 
             // we reverse the Vec so that we can .pop from the buffer, rather than .remove(0)
             // IME pop'ing is faster, since it means less memory moving
@@ -535,6 +1647,517 @@ impl<R: Read> OSMReader for PBFReader<R> {
             self._buffer = objs;
         }
 
-        self._buffer.pop()
+        Ok(self._buffer.pop())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse_mode::{Anomaly, AnomalyKind};
+    use protobuf::Message;
+    use std::sync::Mutex;
+    use stringpbf;
+
+    /// A PBF file with just this block
+    pub(super) fn file(block: &osmformat::PrimitiveBlock) -> Vec<u8> {
+        blob("OSMData", block.write_to_bytes().unwrap())
+    }
+
+    /// A blob of this type (with its header), with this raw (uncompressed) data
+    fn blob(blob_type: &str, data: Vec<u8>) -> Vec<u8> {
+        let mut blob = fileformat::Blob::new();
+        blob.set_raw(data);
+        let blob = blob.write_to_bytes().unwrap();
+        let mut header = fileformat::BlobHeader::new();
+        header.set_field_type(blob_type.to_string());
+        header.set_datasize(blob.len() as i32);
+        let header = header.write_to_bytes().unwrap();
+
+        let mut file = (header.len() as u32).to_be_bytes().to_vec();
+        file.extend(header);
+        file.extend(blob);
+        file
+    }
+
+    #[test]
+    fn file_info() {
+        let mut header = osmformat::HeaderBlock::new();
+        header.set_writingprogram("osmium/1.16".to_string());
+        header
+            .mut_required_features()
+            .push("OsmSchema-V0.6".to_string());
+        header
+            .mut_required_features()
+            .push("DenseNodes".to_string());
+        header.set_osmosis_replication_timestamp(1_700_000_000);
+        header.set_osmosis_replication_sequence_number(5_000);
+        let bbox = header.mut_bbox();
+        bbox.set_left(-10_000_000_000);
+        bbox.set_right(2_500_000_000);
+        bbox.set_bottom(49_000_000_000);
+        bbox.set_top(61_000_000_000);
+        let file = blob("OSMHeader", header.write_to_bytes().unwrap());
+
+        let info = read_file_info(file.as_slice()).unwrap();
+        assert_eq!(info.generator.as_deref(), Some("osmium/1.16"));
+        assert_eq!(info.required_features, ["OsmSchema-V0.6", "DenseNodes"]);
+        assert!(info.optional_features.is_empty());
+        assert_eq!(info.replication_timestamp, Some(1_700_000_000.into()));
+        assert_eq!(info.replication_sequence, Some(5_000));
+        assert_eq!(info.replication_base_url, None);
+        assert_eq!(info.bbox, Some("-10,49,2.5,61".parse().unwrap()));
+
+        // No header
+        assert!(read_file_info(dense_block(vec![]).as_slice()).is_err());
+        assert!(read_file_info(&b""[..]).is_err());
+    }
+
+    /// Way 7, without metadata, and with a tag whose key is past the end of the string table,
+    /// and one whose value isn't valid UTF-8
+    fn invalid_way() -> Vec<u8> {
+        let mut block = osmformat::PrimitiveBlock::new();
+        for s in [&b""[..], b"highway", b"path", b"name", b"caf\xe9"] {
+            block.mut_stringtable().mut_s().push(s.to_vec());
+        }
+        let mut way = osmformat::Way::new();
+        way.set_id(7);
+        way.set_keys(vec![1, 9, 3]);
+        way.set_vals(vec![2, 2, 4]);
+        way.set_refs(vec![1, 1]);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.mut_ways().push(way);
+        block.mut_primitivegroup().push(group);
+        file(&block)
+    }
+
+    #[test]
+    fn parse_modes() {
+        let file = invalid_way();
+        let mut reader = PBFReader::new(&file[..]);
+        reader.set_parse_mode(ParseMode::Strict);
+        match reader.try_next() {
+            Err(Error::Anomaly(anomaly)) => {
+                assert_eq!(anomaly.kind, AnomalyKind::StringIndexOutOfRange);
+                assert_eq!((anomaly.object_type, anomaly.id), (OSMObjectType::Way, 7));
+                assert_eq!(anomaly.offset, Some(0));
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let kinds = Arc::new(Mutex::new(Vec::new()));
+        let kinds2 = Arc::clone(&kinds);
+        let mut reader = PBFReader::builder()
+            .parse_mode(ParseMode::warn(move |a| {
+                kinds2.lock().unwrap().push(a.kind)
+            }))
+            .build(&file[..]);
+        let way = reader.try_next().unwrap().unwrap().into_way().unwrap();
+        assert_eq!(way.tags().collect::<Vec<_>>(), vec![("highway", "path")]);
+        assert_eq!(way.nodes(), &[1, 2]);
+        assert_eq!(way.version(), None);
+        assert_eq!(
+            *kinds.lock().unwrap(),
+            vec![
+                AnomalyKind::StringIndexOutOfRange,
+                AnomalyKind::InvalidUtf8,
+                AnomalyKind::MissingInfo
+            ]
+        );
+
+        // The other PBF reader behaves the same
+        let mut reader = stringpbf::PBFReader::new(&file[..]);
+        let way = reader.try_next().unwrap().unwrap().into_way().unwrap();
+        assert_eq!(way.tags().collect::<Vec<_>>(), vec![("highway", "path")]);
+        reader = stringpbf::PBFReader::new(&file[..]);
+        reader.set_parse_mode(ParseMode::Strict);
+        assert!(matches!(reader.try_next(), Err(Error::Anomaly(_))));
+    }
+
+    #[test]
+    fn timestamp_out_of_range() {
+        let mut block = osmformat::PrimitiveBlock::new();
+        block.mut_stringtable().mut_s().push(Vec::new());
+        let mut way = osmformat::Way::new();
+        way.set_id(7);
+        way.mut_info().set_version(1);
+        way.mut_info().set_timestamp(i64::MAX / 10);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.mut_ways().push(way);
+        block.mut_primitivegroup().push(group);
+        let file = file(&block);
+
+        let way = PBFReader::new(&file[..]).next().unwrap();
+        assert_eq!(way.version(), Some(1));
+        assert!(way.timestamp().is_none());
+        let way = stringpbf::PBFReader::new(&file[..]).next().unwrap();
+        assert_eq!(way.version(), Some(1));
+        assert!(way.timestamp().is_none());
+
+        let mut reader = PBFReader::new(&file[..]);
+        reader.set_parse_mode(ParseMode::Strict);
+        match reader.try_next() {
+            Err(Error::Anomaly(anomaly)) => {
+                assert_eq!(anomaly.kind, AnomalyKind::InvalidTimestamp)
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn negative_string_index() {
+        let mut block = osmformat::PrimitiveBlock::new();
+        for s in ["", "outer"] {
+            block.mut_stringtable().mut_s().push(s.as_bytes().to_vec());
+        }
+        let mut relation = osmformat::Relation::new();
+        relation.set_id(3);
+        relation.set_roles_sid(vec![1, -1]);
+        relation.set_memids(vec![5, 1]);
+        relation.set_types(vec![osmformat::Relation_MemberType::WAY; 2]);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.mut_relations().push(relation);
+        block.mut_primitivegroup().push(group);
+        let file = file(&block);
+
+        let mut reader = PBFReader::new(&file[..]);
+        reader.set_parse_mode(ParseMode::Strict);
+        match reader.try_next() {
+            Err(Error::Anomaly(anomaly)) => {
+                assert_eq!(anomaly.kind, AnomalyKind::StringIndexOutOfRange);
+                assert_eq!(
+                    (anomaly.object_type, anomaly.id),
+                    (OSMObjectType::Relation, 3)
+                );
+                assert_eq!(anomaly.detail, "index -1, but there are 2 strings");
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let mut reader = stringpbf::PBFReader::new(&file[..]);
+        reader.set_parse_mode(ParseMode::Strict);
+        assert!(matches!(
+            reader.try_next(),
+            Err(Error::Anomaly(Anomaly {
+                kind: AnomalyKind::StringIndexOutOfRange,
+                ..
+            }))
+        ));
+    }
+
+    /// A block of dense nodes 1 & 2, with these keys_vals
+    fn dense_block(keys_vals: Vec<i32>) -> Vec<u8> {
+        let mut block = osmformat::PrimitiveBlock::new();
+        for s in ["", "amenity", "pub"] {
+            block.mut_stringtable().mut_s().push(s.as_bytes().to_vec());
+        }
+        let mut dense = osmformat::DenseNodes::new();
+        dense.set_id(vec![1, 1]);
+        dense.set_lat(vec![0, 0]);
+        dense.set_lon(vec![0, 0]);
+        dense.set_keys_vals(keys_vals);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.set_dense(dense);
+        block.mut_primitivegroup().push(group);
+        file(&block)
+    }
+
+    #[test]
+    fn for_each_block() {
+        let mut file = dense_block(vec![1, 2, 0, 0]);
+        file.extend(dense_block(vec![0, 0]));
+        file.extend(dense_block(vec![0, 1, 2, 0]));
+
+        let mut reader = PBFReader::new(&file[..]);
+        let first = reader.next().unwrap();
+        let mut blocks = Vec::new();
+        reader
+            .for_each_block(|objs| blocks.push(objs.iter().map(|o| o.id()).collect::<Vec<_>>()))
+            .unwrap();
+        assert_eq!(first.id(), 1);
+        assert_eq!(blocks, vec![vec![2], vec![1, 2], vec![1, 2]]);
+
+        let mut reader = PBFReader::builder()
+            .tag_filter("n/amenity".parse().unwrap())
+            .build(&file[..]);
+        let mut num_blocks = 0;
+        reader.for_each_block(|_| num_blocks += 1).unwrap();
+        assert_eq!(num_blocks, 2);
+
+        // The blocks before the invalid one are still passed, in order
+        file.extend(dense_block(vec![0, 1, 2]));
+        file.extend(dense_block(vec![0, 0]));
+        let mut reader = PBFReader::builder().threads(2).build(&file[..]);
+        let mut blocks = Vec::new();
+        let res = reader
+            .for_each_block(|objs| blocks.push(objs.iter().map(|o| o.id()).collect::<Vec<_>>()));
+        assert!(matches!(res, Err(Error::Format { .. })), "{:?}", res);
+        assert_eq!(blocks, vec![vec![1, 2], vec![1, 2], vec![1, 2]]);
+    }
+
+    #[test]
+    fn par_for_each_block() {
+        let mut file = Vec::new();
+        for _ in 0..20 {
+            file.extend(dense_block(vec![1, 2, 0, 0]));
+        }
+        let num_objs = std::sync::atomic::AtomicUsize::new(0);
+        let num_tagged = std::sync::atomic::AtomicUsize::new(0);
+        PBFReader::new(&file[..])
+            .par_for_each_block(3, |objs| {
+                num_objs.fetch_add(objs.len(), std::sync::atomic::Ordering::Relaxed);
+                let tagged = objs.iter().filter(|o| o.tagged()).count();
+                num_tagged.fetch_add(tagged, std::sync::atomic::Ordering::Relaxed);
+            })
+            .unwrap();
+        assert_eq!(num_objs.into_inner(), 40);
+        assert_eq!(num_tagged.into_inner(), 20);
+
+        // The dense tags are truncated
+        file.extend(dense_block(vec![0, 1, 2]));
+        match PBFReader::new(&file[..]).par_for_each_block(2, |_| {}) {
+            Err(Error::Format { offset, .. }) => assert!(offset.unwrap() > 0),
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn par_for_each_block_memory_budget() {
+        let mut file = Vec::new();
+        for _ in 0..20 {
+            file.extend(dense_block(vec![1, 2, 0, 0]));
+        }
+        // Room for about one block at a time
+        let budget = MemoryBudget::new(100);
+        let num_objs = std::sync::atomic::AtomicUsize::new(0);
+        PBFReader::builder()
+            .memory_budget(budget.clone())
+            .build(&file[..])
+            .par_for_each_block(3, |objs| {
+                assert!(budget.used() > 0);
+                num_objs.fetch_add(objs.len(), std::sync::atomic::Ordering::Relaxed);
+            })
+            .unwrap();
+        assert_eq!(num_objs.into_inner(), 40);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "rayon")]
+    fn par_for_each_block_in() {
+        let mut file = Vec::new();
+        for _ in 0..20 {
+            file.extend(dense_block(vec![1, 2, 0, 0]));
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        let num_objs = std::sync::atomic::AtomicUsize::new(0);
+        PBFReader::new(&file[..])
+            .par_for_each_block_in(&pool, |objs| {
+                assert!(rayon::current_thread_index().is_some());
+                num_objs.fetch_add(objs.len(), std::sync::atomic::Ordering::Relaxed);
+            })
+            .unwrap();
+        assert_eq!(num_objs.into_inner(), 40);
+    }
+
+    #[test]
+    fn par_for_each_block_panic() {
+        let mut file = Vec::new();
+        for _ in 0..20 {
+            file.extend(dense_block(vec![1, 2, 0, 0]));
+        }
+        // Every thread panics, with more blocks left than fit in the queue
+        let res = std::panic::catch_unwind(|| {
+            PBFReader::new(&file[..]).par_for_each_block(2, |_| panic!("in f"))
+        });
+        let payload = res.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"in f"));
+    }
+
+    #[test]
+    #[cfg(feature = "tracing")]
+    fn tracing_spans() {
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Records the names of the spans which are created
+        #[derive(Default)]
+        struct SpanNames(Mutex<Vec<&'static str>>);
+
+        impl Subscriber for &'static SpanNames {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut names = self.0.lock().unwrap();
+                names.push(span.metadata().name());
+                Id::from_u64(names.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, _: &Event<'_>) {}
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let names: &'static SpanNames = Box::leak(Box::default());
+        let file = dense_block(vec![1, 2, 0, 0]);
+        tracing::subscriber::with_default(names, || {
+            assert_eq!(PBFReader::new(&file[..]).objects().count(), 2);
+        });
+        let names = names.0.lock().unwrap();
+        assert!(names.contains(&"read_blob"));
+        assert!(names.contains(&"decompress_blob"));
+        assert!(names.contains(&"decode_block"));
+    }
+
+    #[test]
+    fn truncated_dense_tags() {
+        // node 2 has no 0 at the end of its tags, and the next block is fine
+        let mut file = dense_block(vec![0, 1, 2]);
+        file.extend(dense_block(vec![1, 2, 0, 0]));
+
+        let mut reader = PBFReader::new(&file[..]);
+        match reader.try_next() {
+            Err(Error::Format { offset, reason }) => {
+                assert_eq!(offset, Some(0));
+                assert_eq!(reason, "The dense tags of node 2 are truncated");
+            }
+            res => panic!("{:?}", res),
+        }
+        let node = reader.try_next().unwrap().unwrap().into_node().unwrap();
+        assert_eq!((node.id(), node.tag("amenity")), (1, Some("pub")));
+
+        let mut reader = PBFReader::new(&file[..]);
+        assert!(matches!(
+            reader.next_dense_nodes(),
+            Err(Error::Format {
+                offset: Some(0),
+                ..
+            })
+        ));
+        assert_eq!(reader.next_dense_nodes().unwrap().unwrap().ids, vec![1, 2]);
+
+        let mut reader = stringpbf::PBFReader::new(&file[..]);
+        assert!(matches!(
+            reader.try_next(),
+            Err(Error::Format {
+                offset: Some(0),
+                ..
+            })
+        ));
+        let node = reader.try_next().unwrap().unwrap().into_node().unwrap();
+        assert_eq!(node.id(), 1);
+    }
+
+    #[test]
+    fn non_positive_ids() {
+        let mut block = osmformat::PrimitiveBlock::new();
+        block.mut_stringtable().mut_s().push(Vec::new());
+        let mut way = osmformat::Way::new();
+        way.set_id(7);
+        way.set_refs(vec![3, -4]);
+        let mut group = osmformat::PrimitiveGroup::new();
+        group.mut_ways().push(way);
+        block.mut_primitivegroup().push(group);
+        let file = file(&block);
+
+        let way = PBFReader::new(&file[..])
+            .next()
+            .unwrap()
+            .into_way()
+            .unwrap();
+        assert_eq!(way.nodes(), &[3, -1]);
+
+        let mut reader = PBFReader::builder()
+            .non_positive_ids(NonPositiveIds::Error)
+            .build(&file[..]);
+        match reader.try_next() {
+            Err(Error::Anomaly(anomaly)) => {
+                assert_eq!(anomaly.kind, AnomalyKind::NonPositiveId);
+                assert_eq!(anomaly.detail, "reference to -1");
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let mut reader = stringpbf::PBFReader::new(&file[..]);
+        reader.set_non_positive_ids(NonPositiveIds::Error);
+        assert!(matches!(reader.try_next(), Err(Error::Anomaly(_))));
+    }
+
+    #[test]
+    fn malformed_input_doesnt_panic() {
+        let mut dense = dense_block(vec![1, 2, 0, 0]);
+        dense.extend(invalid_way());
+        for file in [invalid_way(), dense] {
+            for i in 0..file.len() {
+                for (len, xor) in [
+                    (i, 0),
+                    (file.len(), 0x80),
+                    (file.len(), 0xff),
+                    (file.len(), 1),
+                ] {
+                    let mut file = file[..len].to_vec();
+                    if let Some(b) = file.get_mut(i) {
+                        *b ^= xor;
+                    }
+                    let mut reader = PBFReader::new(&file[..]);
+                    while let Ok(Some(_)) = reader.try_next() {}
+                    let mut reader = PBFReader::new(&file[..]);
+                    while let Ok(Some(_)) = reader.next_dense_nodes() {}
+                    let mut reader = stringpbf::PBFReader::new(&file[..]);
+                    while let Ok(Some(_)) = reader.try_next() {}
+                    let mut reader = stringpbf::PBFNodePositionReader::from_reader(&file[..]);
+                    while let Ok(Some(_)) = reader.try_next() {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_blob_header_size() {
+        let mut file = dense_block(vec![0, 0]);
+        let len = file.len();
+        file.extend([0, 0]);
+        let mut reader = PBFReader::new(&file[..]);
+        assert_eq!(reader.try_next().unwrap().unwrap().id(), 1);
+        assert_eq!(reader.try_next().unwrap().unwrap().id(), 2);
+        match reader.try_next() {
+            Err(Error::Format { offset, reason }) => {
+                assert_eq!(offset, Some(len as u64));
+                assert!(reason.contains("2 of 4 bytes"), "{}", reason);
+            }
+            res => panic!("{:?}", res),
+        }
+
+        let mut reader = stringpbf::PBFReader::new(&file[..]);
+        assert_eq!(reader.try_next().unwrap().unwrap().id(), 1);
+        assert_eq!(reader.try_next().unwrap().unwrap().id(), 2);
+        assert!(matches!(reader.try_next(), Err(Error::Format { .. })));
+
+        // Nothing after the last blob is fine
+        let file = dense_block(vec![0, 0]);
+        assert_eq!(PBFReader::new(&file[..]).objects().count(), 2);
+    }
+
+    #[test]
+    fn lossy_utf8() {
+        let file = invalid_way();
+        let way = PBFReader::builder()
+            .lossy_utf8(true)
+            .build(&file[..])
+            .next()
+            .unwrap()
+            .into_way()
+            .unwrap();
+        assert_eq!(way.tag("name"), Some("caf\u{FFFD}"));
+
+        let mut reader = stringpbf::PBFReader::new(&file[..]);
+        reader.set_lossy_utf8(true);
+        let way = reader.next().unwrap().into_way().unwrap();
+        assert_eq!(way.tag("name"), Some("caf\u{FFFD}"));
     }
 }
@@ -0,0 +1,1230 @@
+//! Writing PBF files.
+//!
+//! [`PBFWriter`] writes objects in blocks of (by default) 8,000, with dense nodes, and zlib
+//! compression. Blocks of large objects are written before they get near the maximum size of a
+//! PBF blob (32 MiB).
+//!
+//! It can also copy a PBF file faithfully: [`PBFReader::next_block_with_layout`] returns the
+//! [`BlockLayout`] of each block (its granularity, string table & groups) along with the objects,
+//! and [`PBFWriter::write_block`] writes the objects with that same layout. Unchanged objects are
+//! written as they were, so `read → write` gives (almost) the same file, which is useful to check
+//! the toolchain, and keeps diffs small when extracts are kept in version control.
+//!
+//! ```no_run
+//! use osmio::pbf::{PBFReader, PBFWriter};
+//! use osmio::OSMWriter;
+//!
+//! let mut reader = PBFReader::from_filename("input.osm.pbf")?;
+//! let file_info = osmio::open::read_file_info("input.osm.pbf")?.2;
+//! let mut writer = PBFWriter::new(std::fs::File::create("copy.osm.pbf")?).file_info(file_info);
+//! while let Some((layout, objs)) = reader.next_block_with_layout()? {
+//!     writer.write_block(&layout, &objs)?;
+//! }
+//! writer.finish()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use super::{fileformat, osmformat, PBFReader, MAX_BLOB_SIZE};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use header::FileInfo;
+use memory::{MemoryBudget, Reservation};
+use obj_types::ArcOSMObj;
+use protobuf::Message;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use {Error, Node, OSMObj, OSMObjectType, OSMWriteError, OSMWriter, ObjId, Relation, Way};
+use {Lat, Lon, TimestampFormat, COORD_PRECISION_NANOS};
+
+/// Default maximum number of objects in a block
+const MAX_BLOCK_OBJECTS: usize = 8_000;
+
+/// A block is written once its estimated size reaches this, half of [`MAX_BLOB_SIZE`], so one
+/// more object would have to be huge to make it too big to read
+const MAX_BLOCK_BYTES: usize = 16 * 1024 * 1024;
+
+/// The required feature of files which have deleted objects, or more than one version of an
+/// object
+const HISTORICAL_INFORMATION: &str = "HistoricalInformation";
+
+/// One group of objects in a block, see [`BlockLayout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupLayout {
+    pub object_type: OSMObjectType,
+    /// For nodes, true iff they're dense nodes
+    pub dense: bool,
+    /// Number of objects
+    pub len: usize,
+}
+
+/// How a block of a PBF file is laid out: everything needed to write its objects the same way
+/// again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockLayout {
+    /// Size of a coordinate unit, in nanodegrees
+    pub granularity: i32,
+    /// In nanodegrees
+    pub lat_offset: i64,
+    /// In nanodegrees
+    pub lon_offset: i64,
+    /// Size of a timestamp unit, in milliseconds
+    pub date_granularity: i32,
+    /// The string table, in order. Strings which aren't in it are added at the end.
+    pub strings: Vec<Vec<u8>>,
+    /// The groups, in order
+    pub groups: Vec<GroupLayout>,
+}
+
+/// The PBF defaults (100 nanodegrees, 1 second), with no strings or groups
+impl Default for BlockLayout {
+    fn default() -> Self {
+        BlockLayout {
+            granularity: 100,
+            lat_offset: 0,
+            lon_offset: 0,
+            date_granularity: 1000,
+            strings: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+impl BlockLayout {
+    pub(super) fn of_block(block: &osmformat::PrimitiveBlock) -> Self {
+        let groups = block
+            .get_primitivegroup()
+            .iter()
+            .filter_map(|group| {
+                let (object_type, dense, len) = if !group.get_nodes().is_empty() {
+                    (OSMObjectType::Node, false, group.get_nodes().len())
+                } else if group.has_dense() {
+                    (OSMObjectType::Node, true, group.get_dense().get_id().len())
+                } else if !group.get_ways().is_empty() {
+                    (OSMObjectType::Way, false, group.get_ways().len())
+                } else if !group.get_relations().is_empty() {
+                    (OSMObjectType::Relation, false, group.get_relations().len())
+                } else {
+                    return None;
+                };
+                Some(GroupLayout {
+                    object_type,
+                    dense,
+                    len,
+                })
+            })
+            .collect();
+        BlockLayout {
+            granularity: block.get_granularity(),
+            lat_offset: block.get_lat_offset(),
+            lon_offset: block.get_lon_offset(),
+            date_granularity: block.get_date_granularity(),
+            strings: block.get_stringtable().get_s().to_vec(),
+            groups,
+        }
+    }
+}
+
+/// Delta coding
+fn deltas(values: &[i64]) -> Vec<i64> {
+    let mut last = 0i64;
+    values
+        .iter()
+        .map(|&v| {
+            let delta = v.wrapping_sub(last);
+            last = v;
+            delta
+        })
+        .collect()
+}
+
+/// Dense nodes, before delta coding
+#[derive(Default)]
+struct DenseGroup {
+    ids: Vec<i64>,
+    lats: Vec<i64>,
+    lons: Vec<i64>,
+    keys_vals: Vec<i32>,
+    versions: Vec<i32>,
+    timestamps: Vec<i64>,
+    changesets: Vec<i64>,
+    uids: Vec<i64>,
+    user_sids: Vec<i64>,
+    visible: Vec<bool>,
+    has_info: bool,
+}
+
+impl DenseGroup {
+    fn finish(self) -> osmformat::DenseNodes {
+        let mut dense = osmformat::DenseNodes::new();
+        dense.set_id(deltas(&self.ids));
+        dense.set_lat(deltas(&self.lats));
+        dense.set_lon(deltas(&self.lons));
+        if self.keys_vals.iter().any(|&kv| kv != 0) {
+            dense.set_keys_vals(self.keys_vals);
+        }
+        if self.has_info {
+            let info = dense.mut_denseinfo();
+            info.set_version(self.versions);
+            info.set_timestamp(deltas(&self.timestamps));
+            info.set_changeset(deltas(&self.changesets));
+            info.set_uid(deltas(&self.uids).into_iter().map(|u| u as i32).collect());
+            info.set_user_sid(
+                deltas(&self.user_sids)
+                    .into_iter()
+                    .map(|u| u as i32)
+                    .collect(),
+            );
+            if self.visible.iter().any(|v| !v) {
+                info.set_visible(self.visible);
+            }
+        }
+        dense
+    }
+}
+
+enum Group {
+    Nodes(Vec<osmformat::Node>),
+    Dense(Box<DenseGroup>),
+    Ways(Vec<osmformat::Way>),
+    Relations(Vec<osmformat::Relation>),
+}
+
+impl Group {
+    fn object_type(&self) -> OSMObjectType {
+        match self {
+            Group::Nodes(_) | Group::Dense(_) => OSMObjectType::Node,
+            Group::Ways(_) => OSMObjectType::Way,
+            Group::Relations(_) => OSMObjectType::Relation,
+        }
+    }
+
+    /// Change every string index in this group, from `i` to `new_idx[i]`
+    fn remap_strings(&mut self, new_idx: &[u32]) {
+        fn remap(idxs: &mut [u32], new_idx: &[u32]) {
+            for idx in idxs.iter_mut() {
+                *idx = new_idx[*idx as usize];
+            }
+        }
+        fn remap_info(info: &mut osmformat::Info, new_idx: &[u32]) {
+            if info.has_user_sid() {
+                info.set_user_sid(new_idx[info.get_user_sid() as usize]);
+            }
+        }
+        match self {
+            Group::Nodes(nodes) => {
+                for node in nodes.iter_mut() {
+                    remap(node.mut_keys(), new_idx);
+                    remap(node.mut_vals(), new_idx);
+                    if node.has_info() {
+                        remap_info(node.mut_info(), new_idx);
+                    }
+                }
+            }
+            Group::Dense(dense) => {
+                for kv in dense.keys_vals.iter_mut() {
+                    *kv = new_idx[*kv as usize] as i32;
+                }
+                for user_sid in dense.user_sids.iter_mut() {
+                    *user_sid = i64::from(new_idx[*user_sid as usize]);
+                }
+            }
+            Group::Ways(ways) => {
+                for way in ways.iter_mut() {
+                    remap(way.mut_keys(), new_idx);
+                    remap(way.mut_vals(), new_idx);
+                    if way.has_info() {
+                        remap_info(way.mut_info(), new_idx);
+                    }
+                }
+            }
+            Group::Relations(relations) => {
+                for relation in relations.iter_mut() {
+                    remap(relation.mut_keys(), new_idx);
+                    remap(relation.mut_vals(), new_idx);
+                    for role in relation.mut_roles_sid().iter_mut() {
+                        *role = new_idx[*role as usize] as i32;
+                    }
+                    if relation.has_info() {
+                        remap_info(relation.mut_info(), new_idx);
+                    }
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> osmformat::PrimitiveGroup {
+        let mut group = osmformat::PrimitiveGroup::new();
+        match self {
+            Group::Nodes(nodes) => group.set_nodes(nodes.into()),
+            Group::Dense(dense) => group.set_dense(dense.finish()),
+            Group::Ways(ways) => group.set_ways(ways.into()),
+            Group::Relations(relations) => group.set_relations(relations.into()),
+        }
+        group
+    }
+}
+
+/// Builds one `PrimitiveBlock`
+struct BlockBuilder {
+    granularity: i32,
+    lat_offset: i64,
+    lon_offset: i64,
+    date_granularity: i32,
+    strings: Vec<Vec<u8>>,
+    string_idx: HashMap<Vec<u8>, u32>,
+    /// How often each string is used
+    string_counts: Vec<u64>,
+    /// Whether to order the string table by frequency at the end. Not done when the layout gave
+    /// a string table, since that order is kept.
+    sort_strings: bool,
+    groups: Vec<Group>,
+    group: Option<Group>,
+    num_objs: usize,
+    /// An upper bound on the encoded size, in bytes
+    size_estimate: usize,
+    /// True iff any object is deleted (i.e. `visible=false`)
+    has_deleted: bool,
+}
+
+impl BlockBuilder {
+    fn new(layout: &BlockLayout) -> Self {
+        let mut strings = layout.strings.clone();
+        let sort_strings = strings.is_empty();
+        if strings.is_empty() {
+            // Index 0 can't be used, since it ends the tags of dense nodes
+            strings.push(Vec::new());
+        }
+        let mut string_idx = HashMap::with_capacity(strings.len());
+        for (i, s) in strings.iter().enumerate().skip(1) {
+            string_idx.entry(s.clone()).or_insert(i as u32);
+        }
+        BlockBuilder {
+            granularity: layout.granularity,
+            lat_offset: layout.lat_offset,
+            lon_offset: layout.lon_offset,
+            date_granularity: layout.date_granularity,
+            string_counts: vec![0; strings.len()],
+            strings,
+            string_idx,
+            sort_strings,
+            groups: Vec::new(),
+            group: None,
+            num_objs: 0,
+            size_estimate: 0,
+            has_deleted: false,
+        }
+    }
+
+    /// The index of this string, or 0 for an empty string. Like other writers, this is used for
+    /// roles & user names, where 0 means empty (it can't be used for tags).
+    fn string_or_zero(&mut self, s: &str) -> u32 {
+        if s.is_empty() {
+            0
+        } else {
+            self.string(s)
+        }
+    }
+
+    fn string(&mut self, s: &str) -> u32 {
+        if let Some(&idx) = self.string_idx.get(s.as_bytes()) {
+            self.string_counts[idx as usize] += 1;
+            return idx;
+        }
+        let idx = self.strings.len() as u32;
+        self.size_estimate += s.len() + 6;
+        self.strings.push(s.as_bytes().to_vec());
+        self.string_counts.push(1);
+        self.string_idx.insert(s.as_bytes().to_vec(), idx);
+        idx
+    }
+
+    fn start_group(&mut self, object_type: OSMObjectType, dense: bool) {
+        if let Some(group) = self.group.take() {
+            self.groups.push(group);
+        }
+        self.group = Some(match object_type {
+            OSMObjectType::Node if dense => Group::Dense(Box::default()),
+            OSMObjectType::Node => Group::Nodes(Vec::new()),
+            OSMObjectType::Way => Group::Ways(Vec::new()),
+            OSMObjectType::Relation => Group::Relations(Vec::new()),
+        });
+    }
+
+    fn raw_lat_lon(&self, (lat, lon): (Lat, Lon)) -> (i64, i64) {
+        let scale = i64::from(self.granularity / COORD_PRECISION_NANOS).max(1);
+        let precision = i64::from(COORD_PRECISION_NANOS);
+        (
+            (i64::from(lat.inner()) - self.lat_offset / precision) / scale,
+            (i64::from(lon.inner()) - self.lon_offset / precision) / scale,
+        )
+    }
+
+    fn timestamp(&self, timestamp: &Option<TimestampFormat>) -> i64 {
+        timestamp.as_ref().map_or(0, |t| {
+            t.to_epoch_millis() / i64::from(self.date_granularity.max(1))
+        })
+    }
+
+    fn tags(&mut self, obj: &impl OSMObj) -> (Vec<u32>, Vec<u32>) {
+        obj.tags()
+            .map(|(k, v)| (self.string(k), self.string(v)))
+            .unzip()
+    }
+
+    /// The `Info` of this object, if it has any metadata. Fields with the default value aren't
+    /// set.
+    fn info(&mut self, obj: &impl OSMObj) -> Option<osmformat::Info> {
+        if obj.version().is_none() && obj.timestamp().is_none() && obj.uid().is_none() {
+            return None;
+        }
+        let mut info = osmformat::Info::new();
+        if let Some(version) = obj.version() {
+            info.set_version(version as i32);
+        }
+        let timestamp = self.timestamp(obj.timestamp());
+        if timestamp != 0 {
+            info.set_timestamp(timestamp);
+        }
+        if let Some(changeset_id) = obj.changeset_id().filter(|&c| c != 0) {
+            info.set_changeset(i64::from(changeset_id));
+        }
+        if let Some(uid) = obj.uid().filter(|&u| u != 0) {
+            info.set_uid(uid as i32);
+        }
+        let user_sid = self.string_or_zero(obj.user().unwrap_or(""));
+        if user_sid != 0 {
+            info.set_user_sid(user_sid);
+        }
+        if obj.deleted() {
+            info.set_visible(false);
+        }
+        Some(info)
+    }
+
+    /// Add this object, to the current group if it's the same type, otherwise to a new group
+    fn add(&mut self, obj: &impl OSMObj) {
+        let object_type = obj.object_type();
+        if self.group.as_ref().map(|g| g.object_type()) != Some(object_type) {
+            self.start_group(object_type, true);
+        }
+        self.num_objs += 1;
+        self.has_deleted |= obj.deleted();
+        let (keys, vals) = self.tags(obj);
+        // 10 bytes for each varint (the id, location & metadata, way nodes & member ids), and 5
+        // for each string index
+        self.size_estimate += 100
+            + 10 * keys.len()
+            + obj.as_way().map_or(0, |w| 10 * w.num_nodes())
+            + obj.as_relation().map_or(0, |r| 16 * r.members().count());
+        let info = self.info(obj);
+        match self.group.take().unwrap() {
+            Group::Dense(mut dense) => {
+                let node = obj.as_node().unwrap();
+                let (lat, lon) = node.lat_lon().map_or((0, 0), |ll| self.raw_lat_lon(ll));
+                dense.ids.push(obj.id());
+                dense.lats.push(lat);
+                dense.lons.push(lon);
+                for (k, v) in keys.into_iter().zip(vals) {
+                    dense.keys_vals.push(k as i32);
+                    dense.keys_vals.push(v as i32);
+                }
+                dense.keys_vals.push(0);
+                if let Some(info) = info {
+                    dense.has_info = true;
+                    dense.versions.push(info.get_version());
+                    dense.timestamps.push(info.get_timestamp());
+                    dense.changesets.push(info.get_changeset());
+                    dense.uids.push(info.get_uid().into());
+                    dense.user_sids.push(info.get_user_sid().into());
+                    dense.visible.push(!obj.deleted());
+                } else {
+                    dense.versions.push(-1);
+                    dense.timestamps.push(0);
+                    dense.changesets.push(0);
+                    dense.uids.push(-1);
+                    dense.user_sids.push(0);
+                    dense.visible.push(true);
+                }
+                self.group = Some(Group::Dense(dense));
+            }
+            Group::Nodes(mut nodes) => {
+                let (lat, lon) = obj
+                    .as_node()
+                    .unwrap()
+                    .lat_lon()
+                    .map_or((0, 0), |ll| self.raw_lat_lon(ll));
+                let mut node = osmformat::Node::new();
+                node.set_id(obj.id());
+                node.set_keys(keys);
+                node.set_vals(vals);
+                node.set_lat(lat);
+                node.set_lon(lon);
+                if let Some(info) = info {
+                    node.set_info(info);
+                }
+                nodes.push(node);
+                self.group = Some(Group::Nodes(nodes));
+            }
+            Group::Ways(mut ways) => {
+                let mut way = osmformat::Way::new();
+                way.set_id(obj.id());
+                way.set_keys(keys);
+                way.set_vals(vals);
+                way.set_refs(deltas(obj.as_way().unwrap().nodes()));
+                if let Some(info) = info {
+                    way.set_info(info);
+                }
+                ways.push(way);
+                self.group = Some(Group::Ways(ways));
+            }
+            Group::Relations(mut relations) => {
+                let mut relation = osmformat::Relation::new();
+                relation.set_id(obj.id());
+                relation.set_keys(keys);
+                relation.set_vals(vals);
+                let mut memids: Vec<ObjId> = Vec::new();
+                for (member_type, id, role) in obj.as_relation().unwrap().members() {
+                    memids.push(id);
+                    let role = self.string_or_zero(role) as i32;
+                    relation.mut_roles_sid().push(role);
+                    relation.mut_types().push(match member_type {
+                        OSMObjectType::Node => osmformat::Relation_MemberType::NODE,
+                        OSMObjectType::Way => osmformat::Relation_MemberType::WAY,
+                        OSMObjectType::Relation => osmformat::Relation_MemberType::RELATION,
+                    });
+                }
+                relation.set_memids(deltas(&memids));
+                if let Some(info) = info {
+                    relation.set_info(info);
+                }
+                relations.push(relation);
+                self.group = Some(Group::Relations(relations));
+            }
+        }
+    }
+
+    /// Order the string table by descending frequency (ties in the order they were first used),
+    /// so the most common strings get the smallest indexes, and shortest varints. Index 0 stays
+    /// the empty string.
+    fn sort_strings(&mut self) {
+        let mut order: Vec<usize> = (1..self.strings.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.string_counts[i]));
+        let mut new_idx = vec![0; self.strings.len()];
+        for (pos, &old) in order.iter().enumerate() {
+            new_idx[old] = pos as u32 + 1;
+        }
+        let mut strings = Vec::with_capacity(self.strings.len());
+        strings.push(std::mem::take(&mut self.strings[0]));
+        for &old in order.iter() {
+            strings.push(std::mem::take(&mut self.strings[old]));
+        }
+        self.strings = strings;
+        for group in self.groups.iter_mut() {
+            group.remap_strings(&new_idx);
+        }
+    }
+
+    fn finish(mut self) -> osmformat::PrimitiveBlock {
+        if let Some(group) = self.group.take() {
+            self.groups.push(group);
+        }
+        if self.sort_strings {
+            self.sort_strings();
+        }
+        let mut block = osmformat::PrimitiveBlock::new();
+        block.mut_stringtable().set_s(self.strings.into());
+        block.set_primitivegroup(self.groups.into_iter().map(Group::finish).collect());
+        // Only set what isn't the default, like most writers
+        if self.granularity != 100 {
+            block.set_granularity(self.granularity);
+        }
+        if self.lat_offset != 0 {
+            block.set_lat_offset(self.lat_offset);
+        }
+        if self.lon_offset != 0 {
+            block.set_lon_offset(self.lon_offset);
+        }
+        if self.date_granularity != 1000 {
+            block.set_date_granularity(self.date_granularity);
+        }
+        block
+    }
+}
+
+fn pbf_error(err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> OSMWriteError {
+    OSMWriteError::PBFWrite(std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Initial,
+    WritingObjects,
+    Closed,
+}
+
+/// Writes PBF files. See the [module docs](self).
+pub struct PBFWriter<W: Write> {
+    /// `None` once [`OSMWriter::into_inner`] has been called
+    writer: Option<W>,
+    file_info: FileInfo,
+    state: State,
+    block: Option<BlockBuilder>,
+    max_block_objects: usize,
+    compress: bool,
+    /// Whether the header has `HistoricalInformation`. Only a request until it's written.
+    historical: bool,
+    header_written: bool,
+    /// The memory used by `block`, if there's a budget
+    memory: Option<Reservation>,
+}
+
+impl<W: Write> std::fmt::Debug for PBFWriter<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PBFWriter")
+            .field("file_info", &self.file_info)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+impl<W: Write> PBFWriter<W> {
+    /// Write this in the file header (the generator defaults to osmio, and the required features
+    /// to `OsmSchema-V0.6` & `DenseNodes`)
+    pub fn file_info(mut self, file_info: FileInfo) -> Self {
+        self.file_info = file_info;
+        self
+    }
+
+    /// Compress blocks with zlib (the default), or store them uncompressed
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Put at most this many objects in each block (default 8,000)
+    pub fn max_block_objects(mut self, max_block_objects: usize) -> Self {
+        self.max_block_objects = max_block_objects.max(1);
+        self
+    }
+
+    /// Add `HistoricalInformation` to the required features, for a file with deleted objects,
+    /// or several versions of objects. It's added anyway if the first block has deleted objects,
+    /// but deleted objects in later blocks are an error without it.
+    pub fn historical(mut self, historical: bool) -> Self {
+        self.historical = historical;
+        self
+    }
+
+    /// Also write a block early when the objects in it would use more than the available memory
+    /// in this budget. Blocks passed to [`write_block`](Self::write_block) aren't counted, since
+    /// they're written straight away.
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory = Some(budget.reservation());
+        self
+    }
+
+    fn writer_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+
+    fn write_blob(&mut self, blob_type: &str, data: Vec<u8>) -> Result<(), OSMWriteError> {
+        let too_big = |size: usize| {
+            pbf_error(format!(
+                "{} blob of {} bytes is bigger than the maximum of {} bytes",
+                blob_type, size, MAX_BLOB_SIZE
+            ))
+        };
+        if data.len() as u64 > MAX_BLOB_SIZE {
+            return Err(too_big(data.len()));
+        }
+        let mut blob = fileformat::Blob::new();
+        if self.compress {
+            blob.set_raw_size(data.len() as i32);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data).map_err(OSMWriteError::PBFWrite)?;
+            blob.set_zlib_data(encoder.finish().map_err(OSMWriteError::PBFWrite)?);
+        } else {
+            blob.set_raw(data);
+        }
+        let blob = blob.write_to_bytes().map_err(pbf_error)?;
+        if blob.len() as u64 > MAX_BLOB_SIZE {
+            return Err(too_big(blob.len()));
+        }
+        let mut header = fileformat::BlobHeader::new();
+        header.set_field_type(blob_type.to_string());
+        header.set_datasize(blob.len() as i32);
+        let header = header.write_to_bytes().map_err(pbf_error)?;
+
+        let writer = self.writer_mut();
+        writer
+            .write_all(&(header.len() as u32).to_be_bytes())
+            .and_then(|()| writer.write_all(&header))
+            .and_then(|()| writer.write_all(&blob))
+            .map_err(OSMWriteError::PBFWrite)
+    }
+
+    /// Start writing objects, after which the header can't be changed
+    fn start(&mut self) -> Result<(), OSMWriteError> {
+        match self.state {
+            State::Initial => {
+                self.state = State::WritingObjects;
+                Ok(())
+            }
+            State::WritingObjects => Ok(()),
+            State::Closed => Err(OSMWriteError::AlreadyClosed),
+        }
+    }
+
+    /// Write the header, if it hasn't been written yet. It's written just before the first block,
+    /// so `HistoricalInformation` can be added if that block has deleted objects.
+    fn write_header(&mut self, has_deleted: bool) -> Result<(), OSMWriteError> {
+        if self.header_written {
+            return Ok(());
+        }
+        let info = &self.file_info;
+        let mut header = osmformat::HeaderBlock::new();
+        header.set_writingprogram(
+            info.generator
+                .clone()
+                .unwrap_or_else(|| format!("osmio/{}", ::version())),
+        );
+        let mut required_features = if info.required_features.is_empty() {
+            vec!["OsmSchema-V0.6".to_string(), "DenseNodes".to_string()]
+        } else {
+            info.required_features.clone()
+        };
+        let historical = required_features
+            .iter()
+            .any(|f| f == HISTORICAL_INFORMATION);
+        if (self.historical || has_deleted) && !historical {
+            required_features.push(HISTORICAL_INFORMATION.to_string());
+        }
+        self.historical = self.historical || has_deleted || historical;
+        header.set_required_features(required_features.into());
+        header.set_optional_features(info.optional_features.clone().into());
+        if let Some(bbox) = &info.bbox {
+            let nanos = |inner: i32| i64::from(inner) * i64::from(COORD_PRECISION_NANOS);
+            let pbf_bbox = header.mut_bbox();
+            pbf_bbox.set_left(nanos(bbox.min_lon.inner()));
+            pbf_bbox.set_right(nanos(bbox.max_lon.inner()));
+            pbf_bbox.set_bottom(nanos(bbox.min_lat.inner()));
+            pbf_bbox.set_top(nanos(bbox.max_lat.inner()));
+        }
+        if let Some(timestamp) = &info.replication_timestamp {
+            header.set_osmosis_replication_timestamp(timestamp.to_epoch_number());
+        }
+        if let Some(sequence) = info.replication_sequence {
+            header.set_osmosis_replication_sequence_number(sequence);
+        }
+        if let Some(url) = &info.replication_base_url {
+            header.set_osmosis_replication_base_url(url.clone());
+        }
+        if let Some(source) = info.other.get("source") {
+            header.set_source(source.clone());
+        }
+        let header = header.write_to_bytes().map_err(pbf_error)?;
+        self.write_blob("OSMHeader", header)?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", name = "write_block", skip_all, fields(num_objs = block.num_objs))
+    )]
+    fn write_block_builder(&mut self, block: BlockBuilder) -> Result<(), OSMWriteError> {
+        self.write_header(block.has_deleted)?;
+        if block.has_deleted && !self.historical {
+            return Err(pbf_error(
+                "Deleted objects need HistoricalInformation in the header, which has already been \
+                 written without it. Use PBFWriter::historical",
+            ));
+        }
+        let block = block.finish().write_to_bytes().map_err(pbf_error)?;
+        self.write_blob("OSMData", block)
+    }
+
+    /// Write the objects which have been written with `write_obj`, but aren't in a block yet
+    fn flush_block(&mut self) -> Result<(), OSMWriteError> {
+        let result = match self.block.take() {
+            Some(block) if block.num_objs > 0 => self.write_block_builder(block),
+            _ => Ok(()),
+        };
+        if let Some(memory) = &mut self.memory {
+            memory.clear();
+        }
+        result
+    }
+
+    /// Write these objects as one block, with this layout (e.g. from
+    /// [`PBFReader::next_block_with_layout`]). Objects which were already written with
+    /// `write_obj` are written in a block before it.
+    ///
+    /// Objects are put in groups as the layout says. If the objects don't match the layout (e.g.
+    /// some were added or removed), the remaining objects are put in new groups by type.
+    pub fn write_block(
+        &mut self,
+        layout: &BlockLayout,
+        objs: &[impl OSMObj],
+    ) -> Result<(), OSMWriteError> {
+        self.start()?;
+        self.flush_block()?;
+        let mut block = BlockBuilder::new(layout);
+        let mut objs = objs.iter();
+        for group in layout.groups.iter() {
+            block.start_group(group.object_type, group.dense);
+            for obj in objs.by_ref().take(group.len) {
+                block.add(obj);
+            }
+        }
+        for obj in objs {
+            block.add(obj);
+        }
+        self.write_block_builder(block)
+    }
+}
+
+impl<W: Write> OSMWriter<W> for PBFWriter<W> {
+    fn new(writer: W) -> Self {
+        PBFWriter {
+            writer: Some(writer),
+            file_info: FileInfo::default(),
+            state: State::Initial,
+            block: None,
+            max_block_objects: MAX_BLOCK_OBJECTS,
+            compress: true,
+            historical: false,
+            header_written: false,
+            memory: None,
+        }
+    }
+
+    /// Sets a header value: `generator`, `source`, `osmosis_replication_timestamp`,
+    /// `osmosis_replication_sequence_number` or `osmosis_replication_base_url`. Others are
+    /// ignored, since the PBF header has nowhere to put them.
+    fn set_header(&mut self, (key, value): (&str, &str)) -> Result<(), OSMWriteError> {
+        match self.state {
+            State::Initial => {}
+            State::WritingObjects => return Err(OSMWriteError::AlreadyStarted),
+            State::Closed => return Err(OSMWriteError::AlreadyClosed),
+        }
+        let info = &mut self.file_info;
+        match key {
+            "generator" => info.generator = Some(value.to_string()),
+            "osmosis_replication_timestamp" => {
+                info.replication_timestamp = Some(value.parse().map_err(pbf_error)?)
+            }
+            "osmosis_replication_sequence_number" => {
+                info.replication_sequence = Some(value.parse().map_err(pbf_error)?)
+            }
+            "osmosis_replication_base_url" => info.replication_base_url = Some(value.to_string()),
+            _ => {
+                info.other.insert(key.to_string(), value.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole header, like [`PBFWriter::file_info`]
+    fn set_file_info(&mut self, file_info: &FileInfo) -> Result<(), OSMWriteError> {
+        match self.state {
+            State::Initial => {}
+            State::WritingObjects => return Err(OSMWriteError::AlreadyStarted),
+            State::Closed => return Err(OSMWriteError::AlreadyClosed),
+        }
+        self.file_info = file_info.clone();
+        Ok(())
+    }
+
+    fn is_open(&self) -> bool {
+        self.state != State::Closed
+    }
+
+    fn close(&mut self) -> Result<(), OSMWriteError> {
+        if self.state == State::Closed {
+            return Ok(());
+        }
+        self.start()?;
+        self.flush_block()?;
+        self.write_header(false)?;
+        self.writer_mut().flush().map_err(OSMWriteError::PBFWrite)?;
+        self.state = State::Closed;
+        Ok(())
+    }
+
+    fn write_obj(&mut self, obj: &impl OSMObj) -> Result<(), OSMWriteError> {
+        self.start()?;
+        if self.block.as_ref().is_some_and(|b| {
+            b.num_objs >= self.max_block_objects || b.size_estimate >= MAX_BLOCK_BYTES
+        }) {
+            self.flush_block()?;
+        }
+        let block = self
+            .block
+            .get_or_insert_with(|| BlockBuilder::new(&BlockLayout::default()));
+        block.add(obj);
+        let size = block.size_estimate;
+        if let Some(memory) = &mut self.memory {
+            // Write the block now, rather than keep it over the budget
+            if !memory.try_grow(size.saturating_sub(memory.bytes())) {
+                self.flush_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn into_inner(mut self) -> W {
+        self.writer.take().unwrap()
+    }
+}
+
+/// Closes the file if it's still open, and flushes it. Errors are ignored, so call
+/// [`OSMWriter::close`] or [`OSMWriter::finish`] to see them.
+impl<W: Write> Drop for PBFWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.close();
+            let _ = self.writer_mut().flush();
+        }
+    }
+}
+
+impl<R: Read> PBFReader<R> {
+    /// The layout & objects of the next block, to write it again with
+    /// [`PBFWriter::write_block`]. `None` at the end of the file.
+    ///
+    /// The layout is only faithful if every object is returned, i.e. there's no tag filter, and
+    /// the parse mode doesn't drop anything. Don't mix this with
+    /// [`OSMReader::next`](crate::OSMReader::next) on the same reader.
+    pub fn next_block_with_layout(
+        &mut self,
+    ) -> Result<Option<(BlockLayout, Vec<ArcOSMObj>)>, Error> {
+        let (offset, block) = match self.try_next_block()? {
+            None => return Ok(None),
+            Some(block) => block,
+        };
+        let layout = BlockLayout::of_block(&block);
+        let anomalies = self.anomalies(offset);
+        let objs = super::decode_block_to_objs(
+            block,
+            self.tag_filter.as_ref(),
+            self.lossy_utf8,
+            &anomalies,
+        )?;
+        Ok(Some((layout, objs)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::file;
+    use super::*;
+    use obj_types::{StringNodeBuilder, StringOSMObj, StringRelationBuilder, StringWayBuilder};
+    use std::convert::TryFrom;
+    use {OSMObjBase, OSMReader};
+
+    /// Everything about an object, to compare them
+    fn summary(obj: &impl OSMObj) -> String {
+        let mut s = format!(
+            "{:?} {} v{:?} {:?} cs{:?} {:?}/{:?} deleted={} {:?}",
+            obj.object_type(),
+            obj.id(),
+            obj.version(),
+            obj.timestamp().as_ref().map(|t| t.to_epoch_millis()),
+            obj.changeset_id(),
+            obj.uid(),
+            obj.user(),
+            obj.deleted(),
+            obj.tags().collect::<Vec<_>>(),
+        );
+        if let Some(node) = obj.as_node() {
+            s += &format!(" {:?}", node.lat_lon_f64());
+        } else if let Some(way) = obj.as_way() {
+            s += &format!(" {:?}", way.nodes());
+        } else if let Some(relation) = obj.as_relation() {
+            s += &format!(" {:?}", relation.members().collect::<Vec<_>>());
+        }
+        s
+    }
+
+    fn tags(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn objects() -> Vec<StringOSMObj> {
+        let loc = |lat: f64, lon: f64| (Lat::try_from(lat).unwrap(), Lon::try_from(lon).unwrap());
+        let mut objs: Vec<StringOSMObj> = vec![
+            StringNodeBuilder::default()
+                ._id(1)
+                ._lat_lon(loc(51.5, -0.1))
+                ._tags(tags(&[("amenity", "bench"), ("name", "x")]))
+                .build()
+                .unwrap()
+                .into(),
+            StringNodeBuilder::default()
+                ._id(2)
+                ._lat_lon(loc(-33.9, 151.2))
+                .build()
+                .unwrap()
+                .into(),
+            StringNodeBuilder::default()
+                ._id(5)
+                ._lat_lon(loc(0., 0.))
+                .build()
+                .unwrap()
+                .into(),
+            StringWayBuilder::default()
+                ._id(10)
+                ._nodes(vec![2, 1, 5])
+                ._tags(tags(&[("highway", "path")]))
+                .build()
+                .unwrap()
+                .into(),
+            StringRelationBuilder::default()
+                ._id(20)
+                ._members(vec![
+                    (OSMObjectType::Way, 10, "".to_string()),
+                    (OSMObjectType::Node, 1, "stop".to_string()),
+                ])
+                ._tags(tags(&[("type", "route")]))
+                .build()
+                .unwrap()
+                .into(),
+        ];
+        for (i, obj) in objs.iter_mut().enumerate() {
+            obj.set_version(Some(i as u32 + 1));
+            obj.set_timestamp(TimestampFormat::EpochNunber(1_600_000_000 + i as i64));
+            obj.set_changeset_id(Some(100 + i as u32));
+            obj.set_uid(Some(7));
+            obj.set_user(Some("alice"));
+        }
+        objs
+    }
+
+    #[test]
+    fn round_trip() {
+        let objs = objects();
+        let mut writer = PBFWriter::new(Vec::new()).max_block_objects(2);
+        for obj in objs.iter() {
+            writer.write_obj(obj).unwrap();
+        }
+        let output = writer.finish().unwrap();
+
+        let info = super::super::read_file_info(&output[..]).unwrap();
+        assert_eq!(info.required_features, ["OsmSchema-V0.6", "DenseNodes"]);
+        assert!(info.generator.unwrap().starts_with("osmio/"));
+
+        let mut reader = PBFReader::new(&output[..]);
+        let mut blocks = Vec::new();
+        while let Some((layout, block)) = reader.next_block_with_layout().unwrap() {
+            blocks.push(layout.groups);
+            for obj in block {
+                assert_eq!(summary(&obj), summary(&objs[obj_index(&objs, &obj)]));
+            }
+        }
+        let node = |len| GroupLayout {
+            object_type: OSMObjectType::Node,
+            dense: true,
+            len,
+        };
+        let group = |object_type| GroupLayout {
+            object_type,
+            dense: false,
+            len: 1,
+        };
+        assert_eq!(
+            blocks,
+            vec![
+                vec![node(2)],
+                vec![node(1), group(OSMObjectType::Way)],
+                vec![group(OSMObjectType::Relation)],
+            ]
+        );
+    }
+
+    #[test]
+    fn block_size() {
+        // 3,000 nodes with 10 kB of tags each, 30 MB, which is near the maximum size of a blob
+        let value = "x".repeat(10_000);
+        let mut writer = PBFWriter::new(Vec::new()).compress(false);
+        for id in 1..=3_000 {
+            let node: StringOSMObj = StringNodeBuilder::default()
+                ._id(id)
+                ._tags(tags(&[("note", &format!("{}{}", id, value))]))
+                .build()
+                .unwrap()
+                .into();
+            writer.write_obj(&node).unwrap();
+        }
+        let output = writer.finish().unwrap();
+        let mut reader = PBFReader::new(&output[..]);
+        let mut block_lens = Vec::new();
+        while let Some((_, objs)) = reader.next_block_with_layout().unwrap() {
+            block_lens.push(objs.len());
+        }
+        assert!(block_lens.len() >= 2, "{:?}", block_lens);
+        assert_eq!(block_lens.iter().sum::<usize>(), 3_000);
+
+        // One object which is too big to read
+        let node: StringOSMObj = StringNodeBuilder::default()
+            ._id(1)
+            ._tags(tags(&[("note", &"x".repeat(33 * 1024 * 1024))]))
+            .build()
+            .unwrap()
+            .into();
+        let mut writer = PBFWriter::new(Vec::new()).compress(false);
+        writer.write_obj(&node).unwrap();
+        assert!(writer.close().is_err());
+    }
+
+    #[test]
+    fn memory_budget() {
+        // Each node is about 1 kB
+        let budget = MemoryBudget::new(10_000);
+        let mut writer = PBFWriter::new(Vec::new()).memory_budget(budget.clone());
+        for id in 1..=100 {
+            let node: StringOSMObj = StringNodeBuilder::default()
+                ._id(id)
+                ._tags(tags(&[("note", &format!("{}{}", id, "x".repeat(1_000)))]))
+                .build()
+                .unwrap()
+                .into();
+            writer.write_obj(&node).unwrap();
+            assert!(budget.used() <= budget.limit());
+        }
+        let output = writer.finish().unwrap();
+        assert_eq!(budget.used(), 0);
+        let mut reader = PBFReader::new(&output[..]);
+        let mut block_lens = Vec::new();
+        while let Some((_, objs)) = reader.next_block_with_layout().unwrap() {
+            block_lens.push(objs.len());
+        }
+        assert!(block_lens.len() >= 10, "{:?}", block_lens);
+        assert_eq!(block_lens.iter().sum::<usize>(), 100);
+    }
+
+    #[test]
+    fn historical() {
+        let required_features = |historical: bool, deleted: usize| {
+            let mut objs = objects();
+            objs[deleted].set_deleted(true);
+            let mut writer = PBFWriter::new(Vec::new())
+                .max_block_objects(2)
+                .historical(historical);
+            for obj in objs.iter() {
+                writer.write_obj(obj)?;
+            }
+            let output = writer.finish()?;
+            Ok::<_, OSMWriteError>(
+                super::super::read_file_info(&output[..])
+                    .unwrap()
+                    .required_features,
+            )
+        };
+        let features = ["OsmSchema-V0.6", "DenseNodes", HISTORICAL_INFORMATION];
+        // In the first block
+        assert_eq!(required_features(false, 1).unwrap(), features);
+        assert_eq!(required_features(true, 4).unwrap(), features);
+        // The header has been written by the time the 3rd block is
+        assert!(required_features(false, 4).is_err());
+    }
+
+    #[test]
+    fn strings_by_frequency() {
+        let objs = objects();
+        let mut writer = PBFWriter::new(Vec::new());
+        for obj in objs.iter() {
+            writer.write_obj(obj).unwrap();
+        }
+        let output = writer.finish().unwrap();
+
+        let mut reader = PBFReader::new(&output[..]);
+        let (layout, block) = reader.next_block_with_layout().unwrap().unwrap();
+        assert!(reader.next_block_with_layout().unwrap().is_none());
+        // "alice" is the user of every object, the rest are used once, in this order
+        let strings: Vec<_> = layout
+            .strings
+            .iter()
+            .map(|s| std::str::from_utf8(s).unwrap())
+            .collect();
+        assert_eq!(
+            strings,
+            [
+                "", "alice", "amenity", "bench", "name", "x", "highway", "path", "type", "route",
+                "stop"
+            ]
+        );
+        assert_eq!(block.len(), objs.len());
+        for obj in block {
+            assert_eq!(summary(&obj), summary(&objs[obj_index(&objs, &obj)]));
+        }
+    }
+
+    fn obj_index(objs: &[StringOSMObj], obj: &impl OSMObj) -> usize {
+        objs.iter()
+            .position(|o| o.object_type() == obj.object_type() && o.id() == obj.id())
+            .unwrap()
+    }
+
+    #[test]
+    fn faithful_copy() {
+        let mut writer = PBFWriter::new(Vec::new()).max_block_objects(3);
+        for obj in objects() {
+            writer.write_obj(&obj).unwrap();
+        }
+        let original = writer.finish().unwrap();
+
+        let info = super::super::read_file_info(&original[..]).unwrap();
+        let mut writer = PBFWriter::new(Vec::new()).file_info(info);
+        let mut reader = PBFReader::new(&original[..]);
+        while let Some((layout, objs)) = reader.next_block_with_layout().unwrap() {
+            writer.write_block(&layout, &objs).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), original);
+    }
+
+    #[test]
+    fn faithful_layout() {
+        // Not what PBFWriter would write: non-dense nodes, another granularity, and strings in
+        // another order
+        let mut block = osmformat::PrimitiveBlock::new();
+        for s in ["", "unused", "name", "amenity", "bench", "x"] {
+            block.mut_stringtable().mut_s().push(s.as_bytes().to_vec());
+        }
+        block.set_granularity(1000);
+        block.set_lat_offset(500);
+        block.set_date_granularity(500);
+        let mut group = osmformat::PrimitiveGroup::new();
+        for id in [3, 4] {
+            let mut node = osmformat::Node::new();
+            node.set_id(id);
+            node.set_lat(12345);
+            node.set_lon(-6789);
+            node.set_keys(vec![3, 2]);
+            node.set_vals(vec![4, 5]);
+            node.mut_info().set_version(2);
+            node.mut_info().set_timestamp(3_000_000_001);
+            group.mut_nodes().push(node);
+        }
+        block.mut_primitivegroup().push(group);
+        let input = file(&block);
+
+        let mut reader = PBFReader::new(&input[..]);
+        let (layout, objs) = reader.next_block_with_layout().unwrap().unwrap();
+        assert_eq!(layout.strings.len(), 6);
+        assert_eq!(layout.groups.len(), 1);
+        assert!(!layout.groups[0].dense);
+
+        let mut writer = PBFWriter::new(Vec::new()).compress(false);
+        writer.write_block(&layout, &objs).unwrap();
+        let output = writer.finish().unwrap();
+        let mut reader = PBFReader::new(&output[..]);
+        let (new_layout, new_objs) = reader.next_block_with_layout().unwrap().unwrap();
+        assert_eq!(new_layout, layout);
+        let summaries = |objs: &[ArcOSMObj]| objs.iter().map(summary).collect::<Vec<_>>();
+        assert_eq!(summaries(&new_objs), summaries(&objs));
+        // The same bytes as the original block (which has no header)
+        assert!(output.ends_with(&input));
+    }
+}
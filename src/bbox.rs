@@ -0,0 +1,261 @@
+//! Bounding boxes.
+use node_locations::NodeLocations;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use {Lat, Lon, OSMObjectType, ObjId, ParseLatLonError, Relation, Way};
+
+/// A bounding box, the area between 2 latitudes & 2 longitudes.
+///
+/// If `min_lon` is greater than `max_lon`, the box crosses the antimeridian (180°). Boxes built
+/// with [`BBox::expand`] never cross it.
+///
+/// ```rust
+/// use osmio::{BBox, Lat, Lon};
+///
+/// let bbox: BBox = "0,0,6,2".parse()?;
+/// assert!(bbox.contains((Lat::from_inner(10_000_000), Lon::from_inner(50_000_000))));
+/// let other: BBox = "5,1,10,10".parse()?;
+/// assert!(bbox.intersects(&other));
+/// assert_eq!(bbox.union(&other), "0,0,10,10".parse()?);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BBox {
+    pub min_lat: Lat,
+    pub min_lon: Lon,
+    pub max_lat: Lat,
+    pub max_lon: Lon,
+}
+
+impl BBox {
+    pub fn new(min_lat: Lat, min_lon: Lon, max_lat: Lat, max_lon: Lon) -> Self {
+        BBox {
+            min_lat,
+            min_lon,
+            max_lat,
+            max_lon,
+        }
+    }
+
+    /// A bbox containing just this location
+    pub fn from_point((lat, lon): (Lat, Lon)) -> Self {
+        BBox::new(lat, lon, lat, lon)
+    }
+
+    /// The smallest bbox containing all these locations, or `None` if there are none
+    pub fn from_locations(locs: impl IntoIterator<Item = (Lat, Lon)>) -> Option<Self> {
+        let mut locs = locs.into_iter();
+        let mut bbox = BBox::from_point(locs.next()?);
+        for loc in locs {
+            bbox.expand(loc);
+        }
+        Some(bbox)
+    }
+
+    /// True iff the box crosses the antimeridian
+    pub fn crosses_antimeridian(&self) -> bool {
+        self.min_lon > self.max_lon
+    }
+
+    /// True iff this location is inside (or on the edge of) the box
+    pub fn contains(&self, (lat, lon): (Lat, Lon)) -> bool {
+        let lon_inside = if self.crosses_antimeridian() {
+            self.min_lon <= lon || lon <= self.max_lon
+        } else {
+            self.min_lon <= lon && lon <= self.max_lon
+        };
+        self.min_lat <= lat && lat <= self.max_lat && lon_inside
+    }
+
+    /// The longitude ranges of the box, 2 if it crosses the antimeridian
+    fn lon_ranges(&self) -> Vec<(Lon, Lon)> {
+        if self.crosses_antimeridian() {
+            vec![
+                (self.min_lon, Lon::from_inner(1_800_000_000)),
+                (Lon::from_inner(-1_800_000_000), self.max_lon),
+            ]
+        } else {
+            vec![(self.min_lon, self.max_lon)]
+        }
+    }
+
+    /// True iff the boxes overlap (or touch)
+    pub fn intersects(&self, other: &BBox) -> bool {
+        if self.max_lat < other.min_lat || other.max_lat < self.min_lat {
+            return false;
+        }
+        let other_ranges = other.lon_ranges();
+        self.lon_ranges().iter().any(|(min1, max1)| {
+            other_ranges
+                .iter()
+                .any(|(min2, max2)| min1 <= max2 && min2 <= max1)
+        })
+    }
+
+    /// Make the box bigger, if needed, to contain this location. (The box is treated as not
+    /// crossing the antimeridian.)
+    pub fn expand(&mut self, (lat, lon): (Lat, Lon)) {
+        self.min_lat = self.min_lat.min(lat);
+        self.min_lon = self.min_lon.min(lon);
+        self.max_lat = self.max_lat.max(lat);
+        self.max_lon = self.max_lon.max(lon);
+    }
+
+    /// The smallest box containing both boxes. (The boxes are treated as not crossing the
+    /// antimeridian.)
+    pub fn union(&self, other: &BBox) -> BBox {
+        let mut bbox = *self;
+        bbox.expand((other.min_lat, other.min_lon));
+        bbox.expand((other.max_lat, other.max_lon));
+        bbox
+    }
+
+    /// The bbox of this way, from the locations of its nodes. Nodes without a location are
+    /// ignored. `None` if none of the nodes have a location.
+    pub fn of_way(way: &impl Way, locations: &impl NodeLocations) -> Option<BBox> {
+        BBox::from_locations(way.nodes().iter().filter_map(|nid| locations.get(*nid)))
+    }
+
+    /// The bbox of this relation. Node members are looked up in `locations`, and the bboxes of
+    /// way & relation members are looked up with `member_bbox` (e.g. from a `HashMap` of bboxes
+    /// calculated with [`BBox::of_way`]). Members without a bbox are ignored.
+    pub fn of_relation(
+        relation: &impl Relation,
+        locations: &impl NodeLocations,
+        mut member_bbox: impl FnMut(OSMObjectType, ObjId) -> Option<BBox>,
+    ) -> Option<BBox> {
+        let mut bbox: Option<BBox> = None;
+        for (member_type, member_id, _role) in relation.members() {
+            let member = match member_type {
+                OSMObjectType::Node => locations.get(member_id).map(BBox::from_point),
+                _ => member_bbox(member_type, member_id),
+            };
+            if let Some(member) = member {
+                bbox = Some(bbox.map_or(member, |b| b.union(&member)));
+            }
+        }
+        bbox
+    }
+}
+
+/// An error while trying to parse a string into a [`BBox`]
+#[derive(Debug)]
+pub enum ParseBBoxError {
+    /// There weren't 4 comma separated numbers, there were this many
+    WrongNumberOfParts(usize),
+    /// One of the numbers isn't a valid Lat/Lon
+    LatLon(ParseLatLonError),
+}
+
+impl std::fmt::Display for ParseBBoxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseBBoxError::WrongNumberOfParts(n) => {
+                write!(f, "Expected 4 comma separated numbers for bbox, got {}", n)
+            }
+            ParseBBoxError::LatLon(e) => write!(f, "Invalid bbox: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ParseBBoxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseBBoxError::LatLon(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseLatLonError> for ParseBBoxError {
+    fn from(e: ParseLatLonError) -> Self {
+        ParseBBoxError::LatLon(e)
+    }
+}
+
+/// Parse a bounding box from `left,bottom,right,top` (i.e. `min_lon,min_lat,max_lon,max_lat`),
+/// the format used by `osmium extract --bbox`.
+impl FromStr for BBox {
+    type Err = ParseBBoxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(|p| p.trim()).collect();
+        if parts.len() != 4 {
+            return Err(ParseBBoxError::WrongNumberOfParts(parts.len()));
+        }
+        Ok(BBox {
+            min_lon: parts[0].parse()?,
+            min_lat: parts[1].parse()?,
+            max_lon: parts[2].parse()?,
+            max_lat: parts[3].parse()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use obj_types::{StringRelationBuilder, StringWayBuilder};
+    use std::collections::HashMap;
+
+    fn loc(lat: &str, lon: &str) -> (Lat, Lon) {
+        (lat.parse().unwrap(), lon.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_contains() {
+        let bbox: BBox = "0,0,6,2".parse().unwrap();
+        assert_eq!(bbox.min_lon, Lon::from_str("0").unwrap());
+        assert_eq!(bbox.max_lat, Lat::from_str("2").unwrap());
+        assert!(bbox.contains(loc("1", "5")));
+        assert!(!bbox.contains(loc("1", "9")));
+        assert!("1,2,3".parse::<BBox>().is_err());
+        assert!("1,2,3,x".parse::<BBox>().is_err());
+
+        let antimeridian: BBox = "170,-10,-170,10".parse().unwrap();
+        assert!(antimeridian.crosses_antimeridian());
+        assert!(antimeridian.contains(loc("0", "179")));
+        assert!(!antimeridian.contains(loc("0", "0")));
+    }
+
+    #[test]
+    fn intersects() {
+        let bbox: BBox = "0,0,6,2".parse().unwrap();
+        assert!(bbox.intersects(&"6,2,7,3".parse().unwrap()));
+        assert!(!bbox.intersects(&"7,0,8,2".parse().unwrap()));
+        assert!(!bbox.intersects(&"0,3,6,4".parse().unwrap()));
+        let antimeridian: BBox = "170,-10,-170,10".parse().unwrap();
+        assert!(antimeridian.intersects(&"-175,0,-160,1".parse().unwrap()));
+        assert!(!antimeridian.intersects(&bbox));
+    }
+
+    #[test]
+    fn object_bboxes() {
+        let mut locations = SparseNodeLocations::new();
+        locations.set(1, loc("1", "1"));
+        locations.set(2, loc("2", "-3"));
+        locations.set(3, loc("5", "5"));
+
+        let way = StringWayBuilder::default()
+            ._id(10)
+            ._nodes(vec![1, 2, 99])
+            .build()
+            .unwrap();
+        let way_bbox = BBox::of_way(&way, &locations).unwrap();
+        assert_eq!(way_bbox, "-3,1,1,2".parse().unwrap());
+
+        let relation = StringRelationBuilder::default()
+            ._id(20)
+            ._members(vec![
+                (OSMObjectType::Way, 10, "".to_string()),
+                (OSMObjectType::Node, 3, "".to_string()),
+            ])
+            .build()
+            .unwrap();
+        let way_bboxes: HashMap<ObjId, BBox> = vec![(10, way_bbox)].into_iter().collect();
+        let relation_bbox =
+            BBox::of_relation(&relation, &locations, |_, id| way_bboxes.get(&id).copied());
+        assert_eq!(relation_bbox, Some("-3,1,5,5".parse().unwrap()));
+    }
+}
@@ -0,0 +1,71 @@
+//! Command line handling shared by the programs: options, usage errors & the final summary.
+// Not every program uses every helper
+#![allow(dead_code)]
+
+use anyhow::{anyhow, Context, Error, Result};
+use osmio::open::{Compression, STDIO_FILENAME};
+use std::fmt::Display;
+use std::time::Instant;
+
+/// The arguments of a program, which has this usage message
+pub struct Args {
+    usage: &'static str,
+    args: std::iter::Skip<std::env::Args>,
+}
+
+impl Args {
+    pub fn new(usage: &'static str) -> Self {
+        Args {
+            usage,
+            args: std::env::args().skip(1),
+        }
+    }
+
+    /// The next argument. `-h` or `--help` prints the usage message & exits.
+    pub fn next(&mut self) -> Option<String> {
+        let arg = self.args.next()?;
+        if arg == "-h" || arg == "--help" {
+            println!("{}", self.usage);
+            std::process::exit(0);
+        }
+        Some(arg)
+    }
+
+    /// The value given after option `name`
+    pub fn value(&mut self, name: &str) -> Result<String> {
+        let usage = self.usage;
+        self.args
+            .next()
+            .with_context(|| format!("{} needs a value\n\n{}", name, usage))
+    }
+
+    /// The value of a `--compression` option
+    pub fn compression(&mut self, name: &str) -> Result<Compression> {
+        match self.value(name)?.as_str() {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "bzip2" => Ok(Compression::Bzip2),
+            other => Err(self.error(format!("Unknown compression {:?}", other))),
+        }
+    }
+
+    /// An error, followed by the usage message
+    pub fn error(&self, message: impl Display) -> Error {
+        anyhow!("{}\n\n{}", message, self.usage)
+    }
+
+    /// The error for an option which the program doesn't have
+    pub fn unknown_option(&self, arg: &str) -> Error {
+        self.error(format!("Unknown option {}", arg))
+    }
+}
+
+/// True iff `arg` is an option, rather than a filename (`-` is stdin or stdout)
+pub fn is_option(arg: &str) -> bool {
+    arg.starts_with('-') && arg != STDIO_FILENAME
+}
+
+/// Print what the program did to stderr, and how long it took since `started`
+pub fn report(started: Instant, summary: impl Display) {
+    eprintln!("{} in {:.1}s", summary, started.elapsed().as_secs_f64());
+}
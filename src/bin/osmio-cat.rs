@@ -0,0 +1,138 @@
+//! Concatenate OSM files, optionally keeping only objects which match tag filter expressions or
+//! are in a list of ids.
+//!
+//!     osmio-cat [OPTIONS] INPUT... [--output OUTPUT]
+//!
+//! Like `osmium cat`, `osmium tags-filter` & `osmium getid`. The expression syntax is described in
+//! [`osmio::tagfilter`], and id files in [`osmio::idset::IdSet::from_reader`].
+extern crate anyhow;
+extern crate osmio;
+
+mod common;
+
+use anyhow::{bail, Context, Result};
+use common::{is_option, report};
+use osmio::getid::add_referenced;
+use osmio::idset::IdSet;
+use osmio::open::{AnyReader, AnyWriter, Compression, STDIO_FILENAME};
+use osmio::tagfilter::TagFilter;
+use osmio::{OSMReader, OSMWriter};
+use std::time::Instant;
+
+const USAGE: &str = "Usage: osmio-cat [OPTIONS] INPUT...
+
+Options:
+    -e, --expression EXPR    Keep objects matching this osmium tags-filter style expression (can
+                             be given more than once)
+    -i, --id-file FILE       Keep objects whose ids are in this file (one per line, e.g. w123)
+        --id ID              Keep this object (e.g. n123, can be given more than once)
+    -r, --add-referenced     Also keep the nodes, ways & relations the kept objects refer to
+    -o, --output FILE        Write to this file, instead of stdout. The format & compression are
+                             detected from the filename
+        --compression C      Compress the output with C (none, gzip or bzip2)
+    -h, --help               Show this message
+
+With no expressions or ids, every object is kept. Otherwise objects matching any expression or
+id are kept.";
+
+struct Args {
+    inputs: Vec<String>,
+    output: String,
+    compression: Option<Compression>,
+    tag_filter: TagFilter,
+    ids: IdSet,
+    add_referenced: bool,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut parsed = Args {
+        inputs: Vec::new(),
+        output: STDIO_FILENAME.to_string(),
+        compression: None,
+        tag_filter: TagFilter::default(),
+        ids: IdSet::new(),
+        add_referenced: false,
+    };
+    let mut args = common::Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-e" | "--expression" => {
+                let expression = args.value(&arg)?;
+                parsed
+                    .tag_filter
+                    .add_expression(&expression)
+                    .map_err(|e| anyhow::anyhow!("Invalid expression {:?}: {}", expression, e))?
+            }
+            "-i" | "--id-file" => {
+                let filename = args.value(&arg)?;
+                let ids = IdSet::from_filename(&filename, None)
+                    .with_context(|| format!("Can't read id file {}", filename))?;
+                parsed.ids.union_with(&ids);
+            }
+            "--id" => {
+                let ids: IdSet = args.value(&arg)?.parse()?;
+                parsed.ids.union_with(&ids);
+            }
+            "-r" | "--add-referenced" => parsed.add_referenced = true,
+            "-o" | "--output" => parsed.output = args.value(&arg)?,
+            "--compression" => parsed.compression = Some(args.compression(&arg)?),
+            _ if is_option(&arg) => return Err(args.unknown_option(&arg)),
+            _ => parsed.inputs.push(arg),
+        }
+    }
+    if parsed.inputs.is_empty() {
+        return Err(args.error("Expected at least one input file"));
+    }
+    Ok(parsed)
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+    let filtering = !args.tag_filter.is_empty() || !args.ids.is_empty();
+    if args.add_referenced && args.inputs.iter().any(|i| i == STDIO_FILENAME) {
+        bail!("The inputs are read more than once with --add-referenced, so they can't be stdin");
+    }
+    let started = Instant::now();
+
+    let mut writer = AnyWriter::from_filename(&args.output, args.compression)
+        .with_context(|| format!("Can't write {}", args.output))?;
+    let (mut num_read, mut num_written) = (0, 0);
+    for input in args.inputs.iter() {
+        let open = || AnyReader::from_filename(input);
+        // With --add-referenced, the ids of everything to keep are found first
+        let ids = if args.add_referenced && filtering {
+            let mut ids = args.ids.clone();
+            for obj in open()?.try_objects() {
+                let obj = obj.with_context(|| format!("Can't read {}", input))?;
+                if args.tag_filter.matches(&obj) {
+                    ids.insert_obj(&obj);
+                }
+            }
+            add_referenced(open, &mut ids)?;
+            Some(ids)
+        } else {
+            None
+        };
+
+        let mut reader = open().with_context(|| format!("Can't read {}", input))?;
+        for obj in reader.try_objects() {
+            let obj = obj.with_context(|| format!("Can't read {}", input))?;
+            num_read += 1;
+            let keep = match &ids {
+                Some(ids) => ids.contains_obj(&obj),
+                None => !filtering || args.tag_filter.matches(&obj) || args.ids.contains_obj(&obj),
+            };
+            if keep {
+                writer.write_obj(&obj)?;
+                num_written += 1;
+            }
+        }
+    }
+    writer.finish()?;
+
+    report(
+        started,
+        format!("Wrote {} of {} objects", num_written, num_read),
+    );
+    Ok(())
+}
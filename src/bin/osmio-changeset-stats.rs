@@ -0,0 +1,134 @@
+//! Count changesets, changes & comments per editor, user & day, from a changeset dump.
+//!
+//!     osmio-changeset-stats [OPTIONS] changesets-latest.osm.bz2
+//!
+//! The statistics are described in [`osmio::changeset_stats`].
+extern crate anyhow;
+extern crate osmio;
+extern crate serde_json;
+
+mod common;
+
+use anyhow::{Context, Result};
+use common::{is_option, report};
+use osmio::changeset_stats::{ChangesetFilter, ChangesetStats, Counts};
+use osmio::changesets::ChangesetReader;
+use std::time::Instant;
+
+const USAGE: &str = "Usage: osmio-changeset-stats [OPTIONS] FILE
+
+FILE is a bzip2 compressed changeset dump, e.g. changesets-latest.osm.bz2
+
+Options:
+    -b, --bbox L,B,R,T       Only changesets whose bbox intersects this (left, bottom, right, top)
+        --since TIME         Only changesets created at or after this (e.g. 2024-01-01T00:00:00Z)
+        --until TIME         Only changesets created before this
+    -n, --top N              Show the top N editors & users (default 20)
+        --json               Print all the statistics as JSON, instead of text
+    -h, --help               Show this message";
+
+struct Args {
+    input: String,
+    filter: ChangesetFilter,
+    top: usize,
+    json: bool,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut input = None;
+    let mut filter = ChangesetFilter::default();
+    let mut top = 20;
+    let mut json = false;
+    let mut args = common::Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-b" | "--bbox" => filter.bbox = Some(args.value(&arg)?.parse()?),
+            "--since" => {
+                let since = args.value(&arg)?;
+                filter.since = Some(
+                    since
+                        .parse()
+                        .with_context(|| format!("Invalid time {:?}", since))?,
+                );
+            }
+            "--until" => {
+                let until = args.value(&arg)?;
+                filter.until = Some(
+                    until
+                        .parse()
+                        .with_context(|| format!("Invalid time {:?}", until))?,
+                );
+            }
+            "-n" | "--top" => top = args.value(&arg)?.parse().context("Invalid --top")?,
+            "--json" => json = true,
+            _ if is_option(&arg) => return Err(args.unknown_option(&arg)),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(args.error("Expected one input file")),
+        }
+    }
+    let input = input.ok_or_else(|| args.error("Expected an input file"))?;
+    Ok(Args {
+        input,
+        filter,
+        top,
+        json,
+    })
+}
+
+fn print_top(title: &str, top: &[(&str, &Counts)]) {
+    println!("{}:", title);
+    for (name, counts) in top {
+        println!(
+            "  {:>10} changesets {:>12} changes {:>8} comments  {}",
+            counts.changesets, counts.changes, counts.comments, name
+        );
+    }
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+    let started = Instant::now();
+
+    let reader = ChangesetReader::from_filename(&args.input)
+        .with_context(|| format!("Can't read {}", args.input))?;
+    let mut stats = ChangesetStats::default();
+    let mut num_read = 0;
+    for changeset in reader {
+        let changeset = changeset.with_context(|| format!("Can't read {}", args.input))?;
+        num_read += 1;
+        if args.filter.matches(&changeset) {
+            stats.add(&changeset);
+        }
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        println!(
+            "Total: {} changesets, {} changes, {} comments, {} users, {} editors",
+            stats.total.changesets,
+            stats.total.changes,
+            stats.total.comments,
+            stats.users.len(),
+            stats.editors.len()
+        );
+        print_top("Editors", &stats.top_editors(args.top));
+        print_top("Users", &stats.top_users(args.top));
+        println!("Days:");
+        for (day, counts) in stats.days.iter() {
+            println!(
+                "  {}  {:>8} changesets {:>10} changes {:>6} comments",
+                day, counts.changesets, counts.changes, counts.comments
+            );
+        }
+    }
+
+    report(
+        started,
+        format!(
+            "Counted {} of {} changesets",
+            stats.total.changesets, num_read
+        ),
+    );
+    Ok(())
+}
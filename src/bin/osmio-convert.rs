@@ -0,0 +1,83 @@
+//! Convert an OSM file to another format and/or compression.
+//!
+//!     osmio-convert [--strip-metadata] [--compression none|gzip|bzip2] INPUT OUTPUT
+//!
+//! The input format (PBF or OSM XML, optionally compressed) is detected from its contents, the
+//! output format & compression from the output filename (e.g. `out.osm.bz2`). `-` is stdin or
+//! stdout (uncompressed OSM XML, unless `--compression` is given).
+extern crate anyhow;
+extern crate osmio;
+
+mod common;
+
+use anyhow::{Context, Result};
+use common::{is_option, report};
+use osmio::anonymise::{Anonymiser, AnonymisingWriter};
+use osmio::open::{copy, AnyReader, AnyWriter, Compression};
+use std::time::Instant;
+
+const USAGE: &str = "Usage: osmio-convert [OPTIONS] INPUT OUTPUT
+
+Options:
+    --strip-metadata     Remove user names, user ids & changeset ids
+    --compression C      Compress the output with C (none, gzip or bzip2), instead of detecting
+                         it from the output filename
+    -h, --help           Show this message";
+
+struct Args {
+    input: String,
+    output: String,
+    strip_metadata: bool,
+    compression: Option<Compression>,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut strip_metadata = false;
+    let mut compression = None;
+    let mut filenames = Vec::new();
+    let mut args = common::Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--strip-metadata" => strip_metadata = true,
+            "--compression" => compression = Some(args.compression(&arg)?),
+            _ if is_option(&arg) => return Err(args.unknown_option(&arg)),
+            _ => filenames.push(arg),
+        }
+    }
+    if filenames.len() != 2 {
+        return Err(args.error("Expected an input & output filename"));
+    }
+    let output = filenames.pop().unwrap();
+    let input = filenames.pop().unwrap();
+    Ok(Args {
+        input,
+        output,
+        strip_metadata,
+        compression,
+    })
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+    let started = Instant::now();
+
+    let mut reader = AnyReader::from_filename(&args.input)
+        .with_context(|| format!("Can't read {}", args.input))?;
+    let mut writer = AnyWriter::from_filename(&args.output, args.compression)
+        .with_context(|| format!("Can't write {}", args.output))?;
+    let num = if args.strip_metadata {
+        let mut writer = AnonymisingWriter::new_with(writer, Anonymiser::strip());
+        copy(&mut reader, &mut writer)?
+    } else {
+        copy(&mut reader, &mut writer)?
+    };
+
+    report(
+        started,
+        format!(
+            "Converted {} objects from {} to {}",
+            num, args.input, args.output
+        ),
+    );
+    Ok(())
+}
@@ -0,0 +1,144 @@
+//! Extract the part of a file inside a bbox or polygon, or many regions at once.
+//!
+//!     osmio-extract [OPTIONS] --bbox LEFT,BOTTOM,RIGHT,TOP --output OUTPUT INPUT
+//!     osmio-extract [OPTIONS] --polygon REGION.poly --output OUTPUT INPUT
+//!     osmio-extract [OPTIONS] --config CONFIG.json INPUT
+//!
+//! Like `osmium extract`. The config file format is described in [`osmio::extract::config`]. The
+//! input is read 2 or 3 times, so it must be a file, and it must be sorted.
+extern crate anyhow;
+extern crate osmio;
+
+mod common;
+
+use anyhow::{bail, Context, Result};
+use common::{is_option, report};
+use osmio::extract::config::ExtractConfig;
+use osmio::extract::poly::Poly;
+use osmio::extract::{extract_many, BBox, ExtractStrategy, Region};
+use osmio::open::{AnyReader, AnyWriter, STDIO_FILENAME};
+use osmio::OSMWriter;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const USAGE: &str = "Usage: osmio-extract [OPTIONS] INPUT
+
+Options:
+    -b, --bbox L,B,R,T       Extract this bbox (left, bottom, right, top)
+    -p, --polygon FILE       Extract the area in this .poly file
+    -o, --output FILE        Where to write the --bbox or --polygon extract
+    -c, --config FILE        Create all the extracts in this (osmium extract style) config file
+    -d, --directory DIR      Output directory for the extracts in the config file
+    -s, --strategy S         simple, complete_ways (the default) or smart
+    -h, --help               Show this message";
+
+#[derive(Default)]
+struct Args {
+    input: Option<String>,
+    bbox: Option<BBox>,
+    polygon: Option<PathBuf>,
+    output: Option<PathBuf>,
+    config: Option<PathBuf>,
+    directory: Option<PathBuf>,
+    strategy: ExtractStrategy,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut parsed = Args::default();
+    let mut args = common::Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-b" | "--bbox" => parsed.bbox = Some(args.value(&arg)?.parse()?),
+            "-p" | "--polygon" => parsed.polygon = Some(args.value(&arg)?.into()),
+            "-o" | "--output" => parsed.output = Some(args.value(&arg)?.into()),
+            "-c" | "--config" => parsed.config = Some(args.value(&arg)?.into()),
+            "-d" | "--directory" => parsed.directory = Some(args.value(&arg)?.into()),
+            "-s" | "--strategy" => {
+                parsed.strategy = match args.value(&arg)?.as_str() {
+                    "simple" => ExtractStrategy::Simple,
+                    "complete_ways" => ExtractStrategy::CompleteWays,
+                    "smart" => ExtractStrategy::Smart,
+                    other => return Err(args.error(format!("Unknown strategy {:?}", other))),
+                }
+            }
+            _ if is_option(&arg) => return Err(args.unknown_option(&arg)),
+            _ if parsed.input.is_none() => parsed.input = Some(arg),
+            _ => return Err(args.error("Expected one input file")),
+        }
+    }
+    Ok(parsed)
+}
+
+/// The regions & output filenames of all the extracts
+fn extracts(args: &Args) -> Result<Vec<(Box<dyn Region>, PathBuf)>> {
+    match (&args.bbox, &args.polygon, &args.config) {
+        (Some(bbox), None, None) => {
+            let output = args.output.clone().context("--bbox needs an --output")?;
+            Ok(vec![(Box::new(*bbox), output)])
+        }
+        (None, Some(polygon), None) => {
+            let output = args.output.clone().context("--polygon needs an --output")?;
+            let poly = Poly::from_filename(polygon)
+                .with_context(|| format!("Can't read {}", polygon.display()))?;
+            Ok(vec![(Box::new(poly), output)])
+        }
+        (None, None, Some(config_filename)) => {
+            if args.output.is_some() {
+                bail!("--output can't be used with --config, the outputs are in the config");
+            }
+            let mut config = ExtractConfig::from_filename(config_filename)?;
+            if args.directory.is_some() {
+                config.directory = args.directory.clone();
+            }
+            let base_dir = config_filename.parent().unwrap_or(Path::new("."));
+            config
+                .extracts
+                .iter()
+                .map(|entry| Ok((entry.region(base_dir)?, config.output(entry))))
+                .collect()
+        }
+        _ => bail!("Expected one of --bbox, --polygon or --config\n\n{}", USAGE),
+    }
+}
+
+fn main() -> Result<()> {
+    let args = parse_args()?;
+    let input = match &args.input {
+        Some(input) if input != STDIO_FILENAME => input.clone(),
+        Some(_) => bail!("The input is read more than once, so it can't be stdin"),
+        None => bail!("Expected an input file\n\n{}", USAGE),
+    };
+    let extracts = extracts(&args)?;
+    let started = Instant::now();
+
+    let mut writers = extracts
+        .iter()
+        .map(|(_, output)| {
+            AnyWriter::from_filename(output, None)
+                .with_context(|| format!("Can't write {}", output.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    {
+        let mut targets = extracts
+            .iter()
+            .zip(writers.iter_mut())
+            .map(|((region, _), writer)| (region.as_ref(), writer))
+            .collect::<Vec<_>>();
+        extract_many(
+            || AnyReader::from_filename(&input),
+            args.strategy,
+            &mut targets,
+        )?;
+    }
+    for (writer, (_, output)) in writers.into_iter().zip(extracts.iter()) {
+        writer
+            .finish()
+            .with_context(|| format!("Can't write {}", output.display()))?;
+    }
+
+    report(
+        started,
+        format!("Created {} extract(s) from {}", extracts.len(), input),
+    );
+    Ok(())
+}
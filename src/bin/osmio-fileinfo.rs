@@ -0,0 +1,156 @@
+//! Show the header & statistics (counts, ids, bbox, timestamps, sortedness) of an OSM file.
+//!
+//!     osmio-fileinfo [--json] [--header-only] FILE
+//!
+//! Like `osmium fileinfo --extended`. `FILE` can be PBF or OSM XML (optionally bzip2 or gzip
+//! compressed), or `-` for stdin.
+extern crate anyhow;
+extern crate osmio;
+extern crate serde_json;
+
+mod common;
+
+use anyhow::{bail, Context, Result};
+use common::is_option;
+use osmio::open::{read_file_info, AnyReader, STDIO_FILENAME};
+use osmio::stats::{file_stats, TypeStats};
+use osmio::BBox;
+
+const USAGE: &str = "Usage: osmio-fileinfo [OPTIONS] FILE
+
+Options:
+    --json           Print JSON, instead of text
+    --header-only    Only show the header, don't read the objects
+    -h, --help       Show this message";
+
+fn format_bbox(bbox: &BBox) -> String {
+    // The same order as `--bbox` arguments: left, bottom, right, top
+    format!(
+        "{},{},{},{}",
+        bbox.min_lon, bbox.min_lat, bbox.max_lon, bbox.max_lat
+    )
+}
+
+fn format_ids(stats: &TypeStats) -> String {
+    match (stats.min_id, stats.max_id) {
+        (Some(min), Some(max)) => format!("{}–{}", min, max),
+        _ => "-".to_string(),
+    }
+}
+
+fn main() -> Result<()> {
+    let mut json = false;
+    let mut header_only = false;
+    let mut filename = None;
+    let mut args = common::Args::new(USAGE);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--header-only" => header_only = true,
+            _ if is_option(&arg) => return Err(args.unknown_option(&arg)),
+            _ if filename.is_none() => filename = Some(arg),
+            _ => return Err(args.error("Expected one filename")),
+        }
+    }
+    let filename = filename.ok_or_else(|| args.error("Expected a filename"))?;
+    if filename == STDIO_FILENAME && !header_only {
+        // The header & the objects are read separately
+        bail!("Reading stdin is only supported with --header-only");
+    }
+
+    let (format, compression, header) =
+        read_file_info(&filename).with_context(|| format!("Can't read {}", filename))?;
+    let stats = if header_only {
+        None
+    } else {
+        let mut reader = AnyReader::from_filename(&filename)?;
+        Some(file_stats(&mut reader).with_context(|| format!("Can't read {}", filename))?)
+    };
+
+    if json {
+        let output = serde_json::json!({
+            "file": {
+                "name": filename,
+                "format": format,
+                "compression": compression,
+            },
+            "header": header,
+            "data": stats,
+        });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("File:");
+    println!("  Name: {}", filename);
+    println!("  Format: {:?}", format);
+    println!("  Compression: {:?}", compression);
+    println!("Header:");
+    println!(
+        "  Generator: {}",
+        header.generator.as_deref().unwrap_or("-")
+    );
+    println!(
+        "  Bounding box: {}",
+        header.bbox.as_ref().map_or("-".to_string(), format_bbox)
+    );
+    if let Some(timestamp) = &header.replication_timestamp {
+        println!("  Replication timestamp: {}", timestamp);
+    }
+    if let Some(sequence) = header.replication_sequence {
+        println!("  Replication sequence number: {}", sequence);
+    }
+    if let Some(url) = &header.replication_base_url {
+        println!("  Replication base URL: {}", url);
+    }
+    if !header.required_features.is_empty() {
+        println!(
+            "  Required features: {}",
+            header.required_features.join(", ")
+        );
+    }
+    if !header.optional_features.is_empty() {
+        println!(
+            "  Optional features: {}",
+            header.optional_features.join(", ")
+        );
+    }
+    for (key, value) in header.other.iter() {
+        println!("  {}: {}", key, value);
+    }
+
+    let stats = match stats {
+        Some(stats) => stats,
+        None => return Ok(()),
+    };
+    println!("Data:");
+    println!(
+        "  Bounding box: {}",
+        stats.bbox.as_ref().map_or("-".to_string(), format_bbox)
+    );
+    match (&stats.min_timestamp, &stats.max_timestamp) {
+        (Some(min), Some(max)) => println!("  Timestamps: {} – {}", min, max),
+        _ => println!("  Timestamps: -"),
+    }
+    println!(
+        "  Objects ordered (by type and id): {}",
+        if stats.sorted { "yes" } else { "no" }
+    );
+    println!("  Users: {}", stats.num_users);
+    println!("  Changesets: {}", stats.num_changesets);
+    for (name, type_stats) in [
+        ("Nodes", &stats.nodes),
+        ("Ways", &stats.ways),
+        ("Relations", &stats.relations),
+    ] {
+        println!(
+            "  {}: {} ({} deleted), ids {}, {} tags",
+            name,
+            type_stats.count,
+            type_stats.deleted,
+            format_ids(type_stats),
+            type_stats.num_tags
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+//! Stopping long running reads & sorts from another thread.
+//!
+//! A [`CancellationToken`] is shared (it's cheap to clone) between the code doing the work and
+//! the code which may want to stop it. The PBF & XML readers, the
+//! [`ExternalSorter`](crate::sort::ExternalSorter) (while adding objects & merging them) and the
+//! [`Replication`](crate::extract::update::Replication) client check it regularly, and return an
+//! error once it's cancelled ([`Error::Cancelled`](crate::Error) from readers, and [`Cancelled`]
+//! from the others).
+//!
+//! ```no_run
+//! use osmio::cancel::CancellationToken;
+//! use osmio::pbf::PBFReader;
+//! use osmio::OSMReader;
+//!
+//! let token = CancellationToken::new();
+//! let mut reader = PBFReader::builder()
+//!     .cancellation(token.clone())
+//!     .open("planet.osm.pbf")?;
+//! std::thread::spawn(move || {
+//!     std::thread::sleep(std::time::Duration::from_secs(60));
+//!     token.cancel();
+//! });
+//! loop {
+//!     match reader.try_next() {
+//!         Ok(Some(_obj)) => {}
+//!         Ok(None) => break,
+//!         Err(e) if e.is_cancelled() => {
+//!             println!("Took too long");
+//!             break;
+//!         }
+//!         Err(e) => return Err(e.into()),
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag which can be set (from any thread) to stop some work. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// A new token, which isn't cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel, everything using this token (or a clone of it) will stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// True iff [`CancellationToken::cancel`] has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Return [`Cancelled`] iff this has been cancelled
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The error returned when work is stopped with a [`CancellationToken`].
+///
+/// Functions returning `anyhow::Result` return this as the root error, so check with
+/// `err.is::<Cancelled>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Check an optional token
+pub(crate) fn check(token: &Option<CancellationToken>) -> Result<(), Cancelled> {
+    match token {
+        Some(token) => token.check(),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,209 @@
+//! Flattening changesets into CSV, one row per changeset, for loading into pandas, DuckDB etc.
+//!
+//! ```rust,no_run
+//! use osmio::changeset_export::ChangesetCsvWriter;
+//! use osmio::changesets::ChangesetReader;
+//! # fn main() -> anyhow::Result<()> {
+//! let reader = ChangesetReader::from_filename("changesets-latest.osm.bz2")?;
+//! let output = std::io::BufWriter::new(std::fs::File::create("changesets.csv")?);
+//! let mut writer = ChangesetCsvWriter::new(output).tag_columns(&["created_by", "comment", "source"]);
+//! for changeset in reader {
+//!     writer.write(&changeset?)?;
+//! }
+//! writer.finish()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Only CSV is written. For Parquet, load the CSV with DuckDB (`COPY (SELECT * FROM
+//! 'changesets.csv') TO 'changesets.parquet'`).
+use changesets::Changeset;
+use geometry::projection::{CoordTransform, Wgs84};
+use std::io::{self, Write};
+
+/// The columns which every row has, before the tag columns
+pub const COLUMNS: [&str; 12] = [
+    "id",
+    "created",
+    "closed",
+    "open",
+    "uid",
+    "user",
+    "num_changes",
+    "comments_count",
+    "min_lat",
+    "min_lon",
+    "max_lat",
+    "max_lon",
+];
+
+/// Writes changesets as CSV (RFC 4180), with a header row.
+///
+/// Missing values (e.g. `closed` for open changesets, or the bbox of empty changesets) are empty.
+/// Timestamps are ISO 8601, and the bbox is in degrees (unless
+/// [`ChangesetCsvWriter::transform`] is used). Selected tags are extra columns, named after the
+/// key (default: `created_by` & `comment`).
+pub struct ChangesetCsvWriter<W: Write> {
+    writer: W,
+    tag_columns: Vec<String>,
+    transform: Box<dyn CoordTransform>,
+    header_written: bool,
+    num_rows: u64,
+}
+
+/// Write this CSV field, quoted if needed
+fn write_field(writer: &mut impl Write, field: &str) -> io::Result<()> {
+    if field.contains([',', '"', '\n', '\r']) {
+        writer.write_all(b"\"")?;
+        writer.write_all(field.replace('"', "\"\"").as_bytes())?;
+        writer.write_all(b"\"")
+    } else {
+        writer.write_all(field.as_bytes())
+    }
+}
+
+fn write_row<'a>(writer: &mut impl Write, fields: impl Iterator<Item = &'a str>) -> io::Result<()> {
+    for (i, field) in fields.enumerate() {
+        if i > 0 {
+            writer.write_all(b",")?;
+        }
+        write_field(writer, field)?;
+    }
+    writer.write_all(b"\r\n")
+}
+
+impl<W: Write> ChangesetCsvWriter<W> {
+    pub fn new(writer: W) -> Self {
+        ChangesetCsvWriter {
+            writer,
+            tag_columns: vec!["created_by".to_string(), "comment".to_string()],
+            transform: Box::new(Wgs84),
+            header_written: false,
+            num_rows: 0,
+        }
+    }
+
+    /// Add a column for each of these tags, replacing the default ones. Must be set before
+    /// anything is written.
+    pub fn tag_columns(mut self, keys: &[impl AsRef<str>]) -> Self {
+        self.tag_columns = keys.iter().map(|k| k.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Transform the corners of the bbox (default: [`Wgs84`], unchanged). The `*_lon` columns
+    /// have the x coordinates, and the `*_lat` columns the y coordinates.
+    pub fn transform(mut self, transform: impl CoordTransform + 'static) -> Self {
+        self.transform = Box::new(transform);
+        self
+    }
+
+    /// The names of all the columns
+    pub fn columns(&self) -> Vec<&str> {
+        COLUMNS
+            .iter()
+            .copied()
+            .chain(self.tag_columns.iter().map(|k| k.as_str()))
+            .collect()
+    }
+
+    /// Number of changesets written so far
+    pub fn num_rows(&self) -> u64 {
+        self.num_rows
+    }
+
+    fn ensure_header(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            let columns: Vec<String> = self.columns().into_iter().map(String::from).collect();
+            write_row(&mut self.writer, columns.iter().map(|c| c.as_str()))?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Write this changeset as a row
+    pub fn write(&mut self, changeset: &Changeset) -> io::Result<()> {
+        self.ensure_header()?;
+        let opt = |v: Option<String>| v.unwrap_or_default();
+        let corners = changeset.bbox.as_ref().map(|b| {
+            (
+                self.transform.transform((b.min_lat, b.min_lon)),
+                self.transform.transform((b.max_lat, b.max_lon)),
+            )
+        });
+        let mut fields = vec![
+            changeset.id.to_string(),
+            changeset.created.to_string(),
+            opt(changeset.closed.as_ref().map(|t| t.to_string())),
+            changeset.open.to_string(),
+            opt(changeset.uid.map(|u| u.to_string())),
+            opt(changeset.user.clone()),
+            changeset.num_changes.to_string(),
+            changeset.comments_count.to_string(),
+            opt(corners.map(|((_, y), _)| y.to_string())),
+            opt(corners.map(|((x, _), _)| x.to_string())),
+            opt(corners.map(|(_, (_, y))| y.to_string())),
+            opt(corners.map(|(_, (x, _))| x.to_string())),
+        ];
+        for key in self.tag_columns.iter() {
+            fields.push(changeset.tag(key).unwrap_or("").to_string());
+        }
+        write_row(&mut self.writer, fields.iter().map(|f| f.as_str()))?;
+        self.num_rows += 1;
+        Ok(())
+    }
+
+    /// Write the header (if nothing has been written), flush, and return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.ensure_header()?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use changesets::ChangesetReader;
+    use geometry::projection;
+
+    #[test]
+    fn csv() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+ <changeset id="1" created_at="2020-01-01T00:00:00Z" closed_at="2020-01-01T01:00:00Z" open="false" user="A, B" uid="7" num_changes="3" comments_count="1" min_lat="1.5" min_lon="2" max_lat="3" max_lon="4">
+  <tag k="comment" v="said &quot;hi&quot;&#10;twice"/>
+  <tag k="created_by" v="JOSM"/>
+ </changeset>
+ <changeset id="2" created_at="2020-01-02T00:00:00Z" open="true"/>
+</osm>"#;
+        let mut writer = ChangesetCsvWriter::new(Vec::new());
+        for changeset in ChangesetReader::new(xml.as_bytes()) {
+            writer.write(&changeset.unwrap()).unwrap();
+        }
+        assert_eq!(writer.num_rows(), 2);
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "id,created,closed,open,uid,user,num_changes,comments_count,min_lat,min_lon,max_lat,max_lon,created_by,comment\r\n\
+             1,2020-01-01T00:00:00Z,2020-01-01T01:00:00Z,false,7,\"A, B\",3,1,1.5,2,3,4,JOSM,\"said \"\"hi\"\"\ntwice\"\r\n\
+             2,2020-01-02T00:00:00Z,,true,,,0,0,,,,,,\r\n"
+        );
+
+        let writer = ChangesetCsvWriter::new(Vec::new()).tag_columns(&["source"]);
+        assert_eq!(writer.columns().last(), Some(&"source"));
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert!(output.ends_with(",max_lon,source\r\n"));
+
+        let mut writer = ChangesetCsvWriter::new(Vec::new())
+            .tag_columns(&[] as &[&str])
+            .transform(projection::WebMercator);
+        for changeset in ChangesetReader::new(xml.as_bytes()) {
+            writer.write(&changeset.unwrap()).unwrap();
+        }
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        // The user has a comma, so count from the end: the bbox is the last 4 columns
+        let row: Vec<&str> = output.lines().nth(1).unwrap().rsplit(',').collect();
+        let min_x: f64 = row[2].parse().unwrap();
+        assert!((min_x - 222_638.98).abs() < 0.01, "{}", min_x);
+    }
+}
@@ -0,0 +1,577 @@
+//! Statistics about changesets (e.g. from a changeset dump), per editor, user & day.
+//!
+//! ```no_run
+//! use osmio::changeset_stats::{ChangesetFilter, ChangesetStats};
+//! use osmio::changesets::ChangesetReader;
+//!
+//! let filter = ChangesetFilter {
+//!     since: Some("2024-01-01T00:00:00Z".parse()?),
+//!     ..Default::default()
+//! };
+//! let mut stats = ChangesetStats::default();
+//! for changeset in ChangesetReader::from_filename("changesets-latest.osm.bz2")? {
+//!     let changeset = changeset?;
+//!     if filter.matches(&changeset) {
+//!         stats.add(&changeset);
+//!     }
+//! }
+//! for (editor, counts) in stats.top_editors(10) {
+//!     println!("{}: {} changesets", editor, counts.changesets);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`EditorObjectStats`] counts objects (rather than changesets) per editor, by joining them to
+//! their changeset's `created_by` with a [`ChangesetTagIndex`]:
+//!
+//! ```no_run
+//! use osmio::changeset_stats::{EditorObjectStats, Period};
+//! use osmio::changesets::{ChangesetTagIndex, ChangesetTagReader};
+//! use osmio::OSMReader;
+//!
+//! let index = ChangesetTagIndex::with_keys(["created_by"])
+//!     .load(ChangesetTagReader::from_filename("changesets-latest.osm.bz2")?)?;
+//! let mut stats = EditorObjectStats::new(Period::Year);
+//! stats.add_all(index.annotate_iter(osmio::read_pbf("history.osh.pbf")?.objects()));
+//! for (year, editors) in stats.periods.iter() {
+//!     println!("{}: {} editors", year, editors.len());
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`UserActivityStats`] finds each user's first & last changeset, e.g. for mapper retention.
+//!
+//! ```no_run
+//! use osmio::changeset_stats::UserActivityStats;
+//! use osmio::changesets::ChangesetReader;
+//!
+//! let mut stats = UserActivityStats::default();
+//! stats.add_all(ChangesetReader::from_filename("changesets-latest.osm.bz2")?)?;
+//! for (uid, activity) in stats.sorted() {
+//!     println!("{},{},{}", uid, activity.first, activity.last);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use anyhow::Result;
+use changesets::{Changeset, ChangesetTagIndex, ChangesetTags};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use tag_stats::TypeCounts;
+use utils::epoch_to_iso;
+use {BBox, OSMObjBase, TimestampFormat};
+
+/// The key for changesets without a `created_by` tag
+pub const UNKNOWN_EDITOR: &str = "(unknown)";
+/// The key for changesets without a user name
+pub const ANONYMOUS_USER: &str = "(anonymous)";
+
+/// Totals for a group of changesets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Counts {
+    pub changesets: u64,
+    /// Total number of changes (objects created, modified or deleted)
+    pub changes: u64,
+    /// Total number of discussion comments
+    pub comments: u64,
+}
+
+impl Counts {
+    fn add(&mut self, changeset: &Changeset) {
+        self.changesets += 1;
+        self.changes += changeset.num_changes;
+        self.comments += changeset.comments_count;
+    }
+}
+
+/// Which changesets to include
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChangesetFilter {
+    /// Only changesets whose bbox intersects this (changesets without changes have no bbox,
+    /// and are excluded)
+    pub bbox: Option<BBox>,
+    /// Only changesets created at or after this
+    pub since: Option<TimestampFormat>,
+    /// Only changesets created before this
+    pub until: Option<TimestampFormat>,
+}
+
+impl ChangesetFilter {
+    /// True iff this changeset is included
+    pub fn matches(&self, changeset: &Changeset) -> bool {
+        if let Some(bbox) = &self.bbox {
+            if !changeset.bbox.is_some_and(|b| b.intersects(bbox)) {
+                return false;
+            }
+        }
+        if self.since.as_ref().is_some_and(|t| changeset.created < *t) {
+            return false;
+        }
+        if self.until.as_ref().is_some_and(|t| changeset.created >= *t) {
+            return false;
+        }
+        true
+    }
+}
+
+/// The name of the editor from a `created_by` tag, without the version, e.g. `JOSM` for
+/// `JOSM/1.5 (18678 en)`, or `iD` for `iD 2.27.3`
+pub fn editor_name(created_by: Option<&str>) -> &str {
+    match created_by.and_then(|c| c.split([' ', '/']).find(|s| !s.is_empty())) {
+        Some(name) => name,
+        None => UNKNOWN_EDITOR,
+    }
+}
+
+/// Changeset statistics, in total, and per editor, user & day (`YYYY-MM-DD`, when the changeset
+/// was created)
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangesetStats {
+    pub total: Counts,
+    pub editors: BTreeMap<String, Counts>,
+    pub users: BTreeMap<String, Counts>,
+    pub days: BTreeMap<String, Counts>,
+}
+
+impl ChangesetStats {
+    pub fn add(&mut self, changeset: &Changeset) {
+        self.total.add(changeset);
+        let editor = editor_name(changeset.tag("created_by"));
+        // Only allocate a key the first time
+        match self.editors.get_mut(editor) {
+            Some(counts) => counts.add(changeset),
+            None => self
+                .editors
+                .entry(editor.to_string())
+                .or_default()
+                .add(changeset),
+        }
+        let user = changeset.user.as_deref().unwrap_or(ANONYMOUS_USER);
+        match self.users.get_mut(user) {
+            Some(counts) => counts.add(changeset),
+            None => self
+                .users
+                .entry(user.to_string())
+                .or_default()
+                .add(changeset),
+        }
+        let day = match changeset.created.try_to_epoch_number() {
+            Ok(t) => epoch_to_iso(t)[..10].to_string(),
+            Err(_) => changeset.created.to_iso_string(),
+        };
+        self.days.entry(day).or_default().add(changeset);
+    }
+
+    /// The editors with the most changesets, most first
+    pub fn top_editors(&self, n: usize) -> Vec<(&str, &Counts)> {
+        top(&self.editors, n)
+    }
+
+    /// The users with the most changesets, most first
+    pub fn top_users(&self, n: usize) -> Vec<(&str, &Counts)> {
+        top(&self.users, n)
+    }
+}
+
+/// The `n` keys with the most changesets (then by name)
+fn top(counts: &BTreeMap<String, Counts>, n: usize) -> Vec<(&str, &Counts)> {
+    let mut top = counts
+        .iter()
+        .map(|(k, c)| (k.as_str(), c))
+        .collect::<Vec<_>>();
+    top.sort_by(|a, b| b.1.changesets.cmp(&a.1.changesets).then(a.0.cmp(b.0)));
+    top.truncate(n);
+    top
+}
+
+/// The length of the time periods which [`EditorObjectStats`] are grouped by
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    /// `YYYY-MM-DD`
+    Day,
+    /// `YYYY-MM`
+    #[default]
+    Month,
+    /// `YYYY`
+    Year,
+}
+
+impl Period {
+    /// The key of the period with this timestamp, e.g. `2024-01` for months
+    pub fn key(&self, timestamp: &TimestampFormat) -> String {
+        let mut iso = match timestamp.try_to_epoch_number() {
+            Ok(t) => epoch_to_iso(t),
+            Err(_) => timestamp.to_iso_string(),
+        };
+        iso.truncate(match self {
+            Period::Day => 10,
+            Period::Month => 7,
+            Period::Year => 4,
+        });
+        iso
+    }
+}
+
+/// Number of objects per editor (the `created_by` of the object's changeset, see
+/// [`editor_name`]), in total & per time period (of the object's timestamp).
+///
+/// Objects whose changeset isn't in the [`ChangesetTagIndex`] count as [`UNKNOWN_EDITOR`].
+/// Objects without a timestamp are only counted in the total.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EditorObjectStats {
+    pub period: Period,
+    pub editors: BTreeMap<String, TypeCounts>,
+    /// Period key → editor → counts
+    pub periods: BTreeMap<String, BTreeMap<String, TypeCounts>>,
+}
+
+impl EditorObjectStats {
+    pub fn new(period: Period) -> Self {
+        EditorObjectStats {
+            period,
+            ..Default::default()
+        }
+    }
+
+    /// Count this object, with the tags of its changeset
+    pub fn add(&mut self, obj: &impl OSMObjBase, changeset_tags: ChangesetTags) {
+        let editor = editor_name(changeset_tags.get("created_by"));
+        add_type(&mut self.editors, editor, obj);
+        if let Some(timestamp) = obj.timestamp() {
+            let period = self.period.key(timestamp);
+            add_type(self.periods.entry(period).or_default(), editor, obj);
+        }
+    }
+
+    /// Count all these objects, e.g. from [`ChangesetTagIndex::annotate_iter`]
+    pub fn add_all<'a, O: OSMObjBase>(
+        &mut self,
+        objs: impl IntoIterator<Item = (O, ChangesetTags<'a>)>,
+    ) {
+        for (obj, tags) in objs {
+            self.add(&obj, tags);
+        }
+    }
+
+    /// Count all these objects, looking up their changesets in `index`
+    pub fn add_with_index<O: OSMObjBase>(
+        &mut self,
+        index: &ChangesetTagIndex,
+        objs: impl IntoIterator<Item = O>,
+    ) {
+        for obj in objs {
+            self.add(&obj, index.get_for(&obj));
+        }
+    }
+
+    /// The editors with the most objects, most first
+    pub fn top_editors(&self, n: usize) -> Vec<(&str, &TypeCounts)> {
+        let mut top = self
+            .editors
+            .iter()
+            .map(|(k, c)| (k.as_str(), c))
+            .collect::<Vec<_>>();
+        top.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.0.cmp(b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+fn add_type(counts: &mut BTreeMap<String, TypeCounts>, editor: &str, obj: &impl OSMObjBase) {
+    // Only allocate a key the first time
+    match counts.get_mut(editor) {
+        Some(c) => c.add(obj.object_type()),
+        None => counts
+            .entry(editor.to_string())
+            .or_default()
+            .add(obj.object_type()),
+    }
+}
+
+/// The activity of one user. This is small & fixed size, so it can be kept for every user in the
+/// changeset dump.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserActivity {
+    /// When their first changeset was created (seconds since the epoch)
+    pub first: i64,
+    /// When their last changeset was closed (or created, if it's still open), in seconds since
+    /// the epoch
+    pub last: i64,
+    pub changesets: u64,
+    /// Total number of changes (objects created, modified or deleted)
+    pub changes: u64,
+}
+
+impl UserActivity {
+    /// When their first changeset was created
+    pub fn first_timestamp(&self) -> TimestampFormat {
+        TimestampFormat::EpochNunber(self.first)
+    }
+
+    /// When their last changeset was closed
+    pub fn last_timestamp(&self) -> TimestampFormat {
+        TimestampFormat::EpochNunber(self.last)
+    }
+}
+
+/// The first & last changeset of every user (by uid), streamed from a changeset dump.
+///
+/// Only a [`UserActivity`] is kept per user, not the changesets, or user names (which can
+/// change).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserActivityStats {
+    pub users: HashMap<i64, UserActivity>,
+    /// Number of changesets without a uid
+    pub anonymous: u64,
+    /// Number of changesets whose timestamps couldn't be read, which aren't counted
+    pub skipped: u64,
+}
+
+impl UserActivityStats {
+    pub fn add(&mut self, changeset: &Changeset) {
+        let uid = match changeset.uid {
+            Some(uid) => uid,
+            None => {
+                self.anonymous += 1;
+                return;
+            }
+        };
+        let created = match changeset.created.try_to_epoch_number() {
+            Ok(t) => t,
+            Err(_) => {
+                self.skipped += 1;
+                return;
+            }
+        };
+        let last = changeset
+            .closed
+            .as_ref()
+            .and_then(|c| c.try_to_epoch_number().ok())
+            .map_or(created, |closed| closed.max(created));
+        let activity = self.users.entry(uid).or_insert(UserActivity {
+            first: created,
+            last,
+            changesets: 0,
+            changes: 0,
+        });
+        activity.first = activity.first.min(created);
+        activity.last = activity.last.max(last);
+        activity.changesets += 1;
+        activity.changes += changeset.num_changes;
+    }
+
+    /// Add all these changesets, e.g. from a [`ChangesetReader`](crate::changesets::ChangesetReader)
+    pub fn add_all(
+        &mut self,
+        changesets: impl IntoIterator<Item = Result<Changeset>>,
+    ) -> Result<()> {
+        for changeset in changesets {
+            self.add(&changeset?);
+        }
+        Ok(())
+    }
+
+    /// The activity of this user
+    pub fn get(&self, uid: i64) -> Option<&UserActivity> {
+        self.users.get(&uid)
+    }
+
+    /// Every user, by uid
+    pub fn sorted(&self) -> Vec<(i64, &UserActivity)> {
+        let mut users = self
+            .users
+            .iter()
+            .map(|(uid, a)| (*uid, a))
+            .collect::<Vec<_>>();
+        users.sort_unstable_by_key(|(uid, _)| *uid);
+        users
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use changesets::{ChangesetReader, ChangesetTagReader};
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+ <changeset id="1" created_at="2024-01-01T10:00:00Z" closed_at="2024-01-01T10:05:00Z" open="false" user="alice" uid="1" min_lat="51.5" min_lon="-0.1" max_lat="51.6" max_lon="0.0" comments_count="2" num_changes="10">
+  <tag k="created_by" v="JOSM/1.5 (18678 en)"/>
+ </changeset>
+ <changeset id="2" created_at="2024-01-01T23:00:00Z" closed_at="2024-01-01T23:05:00Z" open="false" user="bob" uid="2" min_lat="48.8" min_lon="2.3" max_lat="48.9" max_lon="2.4" comments_count="0" num_changes="5">
+  <tag k="created_by" v="iD 2.27.3"/>
+ </changeset>
+ <changeset id="3" created_at="2024-01-02T08:00:00Z" closed_at="2024-01-02T08:01:00Z" open="false" user="alice" uid="1" min_lat="51.5" min_lon="-0.1" max_lat="51.5" max_lon="-0.1" comments_count="0" num_changes="1">
+  <tag k="created_by" v="JOSM/1.5 (19000 en)"/>
+ </changeset>
+ <changeset id="4" created_at="2024-01-02T09:00:00Z" closed_at="2024-01-02T09:00:00Z" open="false" comments_count="1" num_changes="0"/>
+</osm>"#;
+
+    fn stats(filter: &ChangesetFilter) -> ChangesetStats {
+        let mut stats = ChangesetStats::default();
+        for changeset in ChangesetReader::new(INPUT.as_bytes()) {
+            let changeset = changeset.unwrap();
+            if filter.matches(&changeset) {
+                stats.add(&changeset);
+            }
+        }
+        stats
+    }
+
+    #[test]
+    fn editor_names() {
+        assert_eq!(editor_name(Some("JOSM/1.5 (18678 en)")), "JOSM");
+        assert_eq!(editor_name(Some("iD 2.27.3")), "iD");
+        assert_eq!(editor_name(Some("StreetComplete")), "StreetComplete");
+        assert_eq!(editor_name(Some(" ")), UNKNOWN_EDITOR);
+        assert_eq!(editor_name(None), UNKNOWN_EDITOR);
+    }
+
+    #[test]
+    fn all() {
+        let stats = stats(&ChangesetFilter::default());
+        assert_eq!(
+            stats.total,
+            Counts {
+                changesets: 4,
+                changes: 16,
+                comments: 3
+            }
+        );
+        assert_eq!(
+            stats.top_editors(2),
+            vec![
+                (
+                    "JOSM",
+                    &Counts {
+                        changesets: 2,
+                        changes: 11,
+                        comments: 2
+                    }
+                ),
+                (
+                    UNKNOWN_EDITOR,
+                    &Counts {
+                        changesets: 1,
+                        changes: 0,
+                        comments: 1
+                    }
+                ),
+            ]
+        );
+        assert_eq!(stats.users.len(), 3);
+        assert_eq!(stats.users[ANONYMOUS_USER].changesets, 1);
+        assert_eq!(stats.top_users(1)[0].0, "alice");
+        assert_eq!(
+            stats.days.keys().collect::<Vec<_>>(),
+            vec!["2024-01-01", "2024-01-02"]
+        );
+        assert_eq!(stats.days["2024-01-02"].changesets, 2);
+    }
+
+    #[test]
+    fn filters() {
+        let london = ChangesetFilter {
+            bbox: Some("-0.2,51.4,0.1,51.7".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(stats(&london).total.changesets, 2);
+
+        let jan_2 = ChangesetFilter {
+            since: Some("2024-01-02T00:00:00Z".parse().unwrap()),
+            until: Some("2024-01-02T09:00:00Z".parse().unwrap()),
+            ..Default::default()
+        };
+        let stats = stats(&jan_2);
+        assert_eq!(stats.total.changesets, 1);
+        assert_eq!(stats.users.keys().collect::<Vec<_>>(), vec!["alice"]);
+    }
+
+    #[test]
+    fn editor_objects() {
+        use obj_types::{StringNodeBuilder, StringOSMObj, StringWayBuilder};
+        let index = ChangesetTagIndex::new()
+            .load(ChangesetTagReader::new(INPUT.as_bytes()))
+            .unwrap();
+        let node = |id: i64, changeset_id: u32, timestamp: &str| -> StringOSMObj {
+            StringNodeBuilder::default()
+                ._id(id)
+                ._changeset_id(changeset_id)
+                ._timestamp(timestamp.parse::<TimestampFormat>().unwrap())
+                .build()
+                .unwrap()
+                .into()
+        };
+        let objs = vec![
+            node(1, 1, "2024-01-01T10:00:00Z"),
+            node(2, 2, "2024-01-01T23:00:00Z"),
+            node(3, 3, "2024-02-02T08:00:00Z"),
+            StringWayBuilder::default()
+                ._id(1)
+                ._changeset_id(3)
+                ._timestamp("2024-02-02T08:00:00Z".parse::<TimestampFormat>().unwrap())
+                .build()
+                .unwrap()
+                .into(),
+            node(4, 99, "2024-02-03T08:00:00Z"),
+        ];
+        let mut stats = EditorObjectStats::new(Period::Month);
+        stats.add_all(index.annotate_iter(objs.into_iter()));
+
+        let top = stats.top_editors(3);
+        assert_eq!(
+            top[0],
+            (
+                "JOSM",
+                &TypeCounts {
+                    nodes: 2,
+                    ways: 1,
+                    relations: 0
+                }
+            )
+        );
+        assert_eq!(top[1].0, UNKNOWN_EDITOR);
+        assert_eq!(top[2].0, "iD");
+        assert_eq!(
+            stats.periods.keys().collect::<Vec<_>>(),
+            vec!["2024-01", "2024-02"]
+        );
+        assert_eq!(stats.periods["2024-01"]["JOSM"].total(), 1);
+        assert_eq!(stats.periods["2024-02"]["JOSM"].total(), 2);
+        assert_eq!(stats.periods["2024-02"][UNKNOWN_EDITOR].nodes, 1);
+
+        assert_eq!(
+            Period::Day.key(&"2024-02-02T08:00:00Z".parse().unwrap()),
+            "2024-02-02"
+        );
+        assert_eq!(Period::Year.key(&TimestampFormat::EpochNunber(0)), "1970");
+    }
+
+    #[test]
+    fn user_activity() {
+        let mut stats = UserActivityStats::default();
+        stats
+            .add_all(ChangesetReader::new(INPUT.as_bytes()))
+            .unwrap();
+        assert_eq!(stats.anonymous, 1);
+        assert_eq!(stats.skipped, 0);
+        let alice = stats.get(1).unwrap();
+        assert_eq!(
+            alice.first_timestamp(),
+            "2024-01-01T10:00:00Z".parse().unwrap()
+        );
+        assert_eq!(
+            alice.last_timestamp(),
+            "2024-01-02T08:01:00Z".parse().unwrap()
+        );
+        assert_eq!((alice.changesets, alice.changes), (2, 11));
+        assert_eq!(
+            stats
+                .sorted()
+                .iter()
+                .map(|(uid, a)| (*uid, a.changesets))
+                .collect::<Vec<_>>(),
+            vec![(1, 2), (2, 1)]
+        );
+    }
+}
@@ -25,6 +25,7 @@
 //! return the tags
 use super::*;
 use anyhow::{bail, ensure};
+#[cfg(feature = "bzip2")]
 use bzip2::read::MultiBzDecoder;
 use quick_xml::events::Event;
 use std::io::{BufReader, Read};
@@ -32,20 +33,30 @@ use std::io::{BufReader, Read};
 /// A single OSM changeset entry
 ///
 /// fields match the XML attributes
-#[derive(Debug, Builder)]
+#[derive(Debug, Clone, Builder)]
 pub struct Changeset {
     pub id: u32,
     pub created: TimestampFormat,
     #[builder(setter(strip_option), default)]
     pub closed: Option<TimestampFormat>,
+    /// True iff the changeset is still open (then `closed` is `None`)
+    #[builder(default)]
     pub open: bool,
     #[builder(setter(strip_option), default)]
     pub uid: Option<i64>,
     #[builder(setter(strip_option), default)]
     pub user: Option<String>,
+    #[builder(default)]
     pub tags: HashMap<String, String>,
+    /// Number of changes (0 if unknown)
+    #[builder(default)]
     pub num_changes: u64,
+    /// Number of discussion comments (0 if unknown)
+    #[builder(default)]
     pub comments_count: u64,
+    /// The area of the changes (not present for changesets without changes)
+    #[builder(setter(strip_option), default)]
+    pub bbox: Option<BBox>,
 }
 
 impl Changeset {
@@ -75,6 +86,31 @@ impl Changeset {
     pub fn into_tags(self) -> HashMap<String, String> {
         self.tags
     }
+
+    /// True iff the changeset is still open, and can have more changes
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// Check `open`, `closed` & `created` agree: open changesets have no closing time, closed ones
+    /// have one, and it's not before the creation time.
+    pub fn validate(&self) -> Result<()> {
+        match (self.open, &self.closed) {
+            (true, Some(closed)) => {
+                bail!("Changeset {} is open, but closed at {}", self.id, closed)
+            }
+            (false, None) => bail!("Changeset {} is closed, but has no closed_at", self.id),
+            (false, Some(closed)) => ensure!(
+                *closed >= self.created,
+                "Changeset {} is closed at {}, before it was created at {}",
+                self.id,
+                closed,
+                self.created
+            ),
+            (true, None) => {}
+        }
+        Ok(())
+    }
 }
 
 /// Reads the `changesets-latest.osm.bz2` file and produces `Changesets`
@@ -105,9 +141,8 @@ impl<R: Read> ChangesetReader<R> {
 
     pub fn next_changeset(&mut self) -> Result<Option<Changeset>> {
         // move forward until we are at a changeset tag (happens at the start)
-        self.buf.clear();
-        let changeset;
         loop {
+            self.buf.clear();
             match self.reader.read_event_into(&mut self.buf)? {
                 Event::Eof => {
                     return Ok(None);
@@ -116,60 +151,14 @@ impl<R: Read> ChangesetReader<R> {
                     if e.name().local_name().as_ref() != b"changeset" {
                         continue;
                     }
-
-                    let mut changeset_builder = ChangesetBuilder::default();
-                    for attr in e.attributes() {
-                        let attr = attr?;
-                        match attr.key.local_name().as_ref() {
-                            b"id" => {
-                                changeset_builder
-                                    .id(attr.decode_and_unescape_value(&self.reader)?.parse()?);
-                            }
-                            b"created_at" => {
-                                changeset_builder.created(TimestampFormat::ISOString(
-                                    attr.decode_and_unescape_value(&self.reader)?.to_string(),
-                                ));
-                            }
-                            b"closed_at" => {
-                                changeset_builder.closed(TimestampFormat::ISOString(
-                                    attr.decode_and_unescape_value(&self.reader)?.to_string(),
-                                ));
-                            }
-                            b"open" => {
-                                changeset_builder.open(match attr.value.as_ref() {
-                                    b"true" => true,
-                                    b"false" => false,
-                                    _ => bail!("unknown value"),
-                                });
-                            }
-                            b"user" => {
-                                changeset_builder.user(
-                                    attr.decode_and_unescape_value(&self.reader)?.to_string(),
-                                );
-                            }
-                            b"uid" => {
-                                changeset_builder
-                                    .uid(attr.decode_and_unescape_value(&self.reader)?.parse()?);
-                            }
-                            b"num_changes" => {
-                                changeset_builder.num_changes(
-                                    attr.decode_and_unescape_value(&self.reader)?.parse()?,
-                                );
-                            }
-                            b"comments_count" => {
-                                changeset_builder.comments_count(
-                                    attr.decode_and_unescape_value(&self.reader)?.parse()?,
-                                );
-                            }
-                            _ => {}
-                        }
-                    }
+                    let mut changeset_builder = changeset_builder(e, &self.reader)?;
 
                     // go for tags
                     let mut tags = HashMap::new();
                     let mut buf = Vec::new();
                     loop {
                         match self.reader.read_event_into(&mut buf)? {
+                            Event::Eof => bail!("File ends in the middle of a changeset"),
                             Event::End(ref e) => {
                                 if e.name().local_name().as_ref() == "changeset".as_bytes() {
                                     break;
@@ -208,78 +197,95 @@ impl<R: Read> ChangesetReader<R> {
                     }
 
                     changeset_builder.tags(tags);
-
-                    changeset = Some(changeset_builder.build()?);
-                    break;
+                    return Ok(Some(changeset_builder.build()?));
                 }
 
-                // TODO find way to merge Start & Empty
                 Event::Empty(ref e) => {
                     if e.name().local_name().as_ref() != "changeset".as_bytes() {
                         continue;
                     }
-
-                    let mut changeset_builder = ChangesetBuilder::default();
-                    for attr in e.attributes() {
-                        let attr = attr?;
-                        match attr.key.local_name().as_ref() {
-                            b"id" => {
-                                changeset_builder
-                                    .id(attr.decode_and_unescape_value(&self.reader)?.parse()?);
-                            }
-                            b"created_at" => {
-                                changeset_builder.created(TimestampFormat::ISOString(
-                                    attr.decode_and_unescape_value(&self.reader)?.to_string(),
-                                ));
-                            }
-                            b"closed_at" => {
-                                changeset_builder.closed(TimestampFormat::ISOString(
-                                    attr.decode_and_unescape_value(&self.reader)?.to_string(),
-                                ));
-                            }
-                            b"open" => {
-                                changeset_builder.open(match attr.value.as_ref() {
-                                    b"true" => true,
-                                    b"false" => false,
-                                    _ => bail!("unknown value"),
-                                });
-                            }
-                            b"user" => {
-                                changeset_builder.user(
-                                    attr.decode_and_unescape_value(&self.reader)?.to_string(),
-                                );
-                            }
-                            b"uid" => {
-                                changeset_builder
-                                    .uid(attr.decode_and_unescape_value(&self.reader)?.parse()?);
-                            }
-                            b"num_changes" => {
-                                changeset_builder.num_changes(
-                                    attr.decode_and_unescape_value(&self.reader)?.parse()?,
-                                );
-                            }
-                            b"comments_count" => {
-                                changeset_builder.comments_count(
-                                    attr.decode_and_unescape_value(&self.reader)?.parse()?,
-                                );
-                            }
-                            _ => {}
-                        }
-                    }
+                    let mut changeset_builder = changeset_builder(e, &self.reader)?;
 
                     // no tags here
                     changeset_builder.tags(HashMap::new());
-
-                    changeset = Some(changeset_builder.build()?);
-                    break;
+                    return Ok(Some(changeset_builder.build()?));
                 }
                 _ => continue,
             }
         }
+    }
+}
 
-        ensure!(changeset.is_some(), "No changeset created?!");
-        Ok(Some(changeset.unwrap()))
+/// A builder with the attributes of this `<changeset>` element (i.e. everything but the tags).
+///
+/// Changesets from the API can be open (with no `closed_at`), and may not have `num_changes` (the
+/// API calls it `changes_count`) or `comments_count`. If there's no `open` attribute, the
+/// changeset is open iff there's no `closed_at`.
+fn changeset_builder<B>(
+    e: &quick_xml::events::BytesStart,
+    reader: &quick_xml::Reader<B>,
+) -> Result<ChangesetBuilder> {
+    let mut changeset_builder = ChangesetBuilder::default();
+    let (mut open, mut has_closed) = (None, false);
+    let (mut min_lat, mut min_lon, mut max_lat, mut max_lon) = (None, None, None, None);
+    for attr in e.attributes() {
+        let attr = attr?;
+        match attr.key.local_name().as_ref() {
+            b"id" => {
+                changeset_builder.id(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            b"created_at" => {
+                changeset_builder.created(TimestampFormat::ISOString(
+                    attr.decode_and_unescape_value(reader)?.to_string(),
+                ));
+            }
+            b"closed_at" => {
+                has_closed = true;
+                changeset_builder.closed(TimestampFormat::ISOString(
+                    attr.decode_and_unescape_value(reader)?.to_string(),
+                ));
+            }
+            b"open" => {
+                open = Some(match attr.value.as_ref() {
+                    b"true" => true,
+                    b"false" => false,
+                    _ => bail!("unknown value"),
+                });
+            }
+            b"user" => {
+                changeset_builder.user(attr.decode_and_unescape_value(reader)?.to_string());
+            }
+            b"uid" => {
+                changeset_builder.uid(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            b"num_changes" | b"changes_count" => {
+                changeset_builder.num_changes(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            b"comments_count" => {
+                changeset_builder.comments_count(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            b"min_lat" => {
+                min_lat = Some(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            b"min_lon" => {
+                min_lon = Some(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            b"max_lat" => {
+                max_lat = Some(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            b"max_lon" => {
+                max_lon = Some(attr.decode_and_unescape_value(reader)?.parse()?);
+            }
+            _ => {}
+        }
+    }
+    changeset_builder.open(open.unwrap_or(!has_closed));
+    if let (Some(min_lat), Some(min_lon), Some(max_lat), Some(max_lon)) =
+        (min_lat, min_lon, max_lat, max_lon)
+    {
+        changeset_builder.bbox(BBox::new(min_lat, min_lon, max_lat, max_lon));
     }
+    Ok(changeset_builder)
 }
 
 impl<R: Read> Iterator for ChangesetReader<R> {
@@ -290,6 +296,7 @@ impl<R: Read> Iterator for ChangesetReader<R> {
     }
 }
 
+#[cfg(feature = "bzip2")]
 impl ChangesetReader<bzip2::read::MultiBzDecoder<std::fs::File>> {
     pub fn from_filename(filename: &str) -> Result<Self> {
         let f = File::open(filename)?;
@@ -300,6 +307,7 @@ impl ChangesetReader<bzip2::read::MultiBzDecoder<std::fs::File>> {
 }
 
 /// A `Reader` which “returns” BZ2 compressed data
+#[cfg(feature = "bzip2")]
 impl<R: Read> ChangesetReader<bzip2::read::MultiBzDecoder<R>> {
     pub fn from_bz2_reader(rdr: R) -> Self {
         let dec = MultiBzDecoder::new(rdr);
@@ -318,6 +326,7 @@ pub struct ChangesetTagReader<R: Read> {
     tags: Vec<(String, String)>,
 }
 
+#[cfg(feature = "bzip2")]
 impl ChangesetTagReader<bzip2::read::MultiBzDecoder<std::fs::File>> {
     /// Read bz2 zipped filename.
     pub fn from_filename(filename: &str) -> Result<Self> {
@@ -329,7 +338,8 @@ impl ChangesetTagReader<bzip2::read::MultiBzDecoder<std::fs::File>> {
 }
 
 impl<R: Read> ChangesetTagReader<R> {
-    fn new(reader: R) -> Self {
+    /// Read an (uncompressed) changeset XML file from this reader
+    pub fn new(reader: R) -> Self {
         ChangesetTagReader {
             reader: quick_xml::Reader::from_reader(BufReader::new(reader)),
             curr_id: None,
@@ -401,11 +411,143 @@ impl<R: Read> Iterator for ChangesetTagReader<R> {
     }
 }
 
+/// The default changeset tags kept by a [`ChangesetTagIndex`]
+pub const DEFAULT_INDEX_KEYS: &[&str] = &["comment", "created_by", "source"];
+
+/// The tags of every changeset, in memory, to look up the changeset tags of objects.
+///
+/// Only some tags are kept (by default [`DEFAULT_INDEX_KEYS`]), since there are many changesets.
+///
+/// ```rust,no_run
+/// use osmio::changesets::{ChangesetTagIndex, ChangesetTagReader};
+/// use osmio::OSMReader;
+/// # fn main() -> anyhow::Result<()> {
+/// let index = ChangesetTagIndex::new()
+///     .load(ChangesetTagReader::from_filename("changesets-latest.osm.bz2")?)?;
+/// let mut reader = osmio::read_pbf("input.osm.pbf")?;
+/// let num_mapsme = index
+///     .annotate_iter(reader.objects())
+///     .filter(|(_obj, tags)| tags.get("created_by").is_some_and(|v| v.starts_with("MAPS.ME")))
+///     .count();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChangesetTagIndex {
+    keys: Vec<String>,
+    tags: HashMap<u64, Vec<(String, String)>>,
+}
+
+impl Default for ChangesetTagIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChangesetTagIndex {
+    /// An empty index, which keeps the [`DEFAULT_INDEX_KEYS`]
+    pub fn new() -> Self {
+        Self::with_keys(DEFAULT_INDEX_KEYS.iter().copied())
+    }
+
+    /// An empty index, which keeps only these keys
+    pub fn with_keys(keys: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        ChangesetTagIndex {
+            keys: keys.into_iter().map(Into::into).collect(),
+            tags: HashMap::new(),
+        }
+    }
+
+    /// Add all the changesets from this [`ChangesetTagReader`] (or any iterator of id & tags).
+    pub fn load(
+        mut self,
+        changesets: impl IntoIterator<Item = Result<(u64, Vec<(String, String)>)>>,
+    ) -> Result<Self> {
+        for changeset in changesets {
+            let (id, tags) = changeset?;
+            self.insert(id, tags);
+        }
+        Ok(self)
+    }
+
+    /// Add (or replace) the tags for this changeset. Tags with other keys are dropped, and
+    /// changesets without any of the keys aren't stored.
+    pub fn insert(&mut self, id: u64, mut tags: Vec<(String, String)>) {
+        tags.retain(|(k, _)| self.keys.iter().any(|key| key == k));
+        if tags.is_empty() {
+            self.tags.remove(&id);
+        } else {
+            tags.shrink_to_fit();
+            self.tags.insert(id, tags);
+        }
+    }
+
+    /// Number of changesets with tags
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    /// True iff there are no changesets with tags
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// The (kept) tags of this changeset
+    pub fn get(&self, id: u64) -> ChangesetTags<'_> {
+        ChangesetTags(self.tags.get(&id).map_or(&[], |t| t.as_slice()))
+    }
+
+    /// The (kept) tags of the changeset of this object. Empty if it has no changeset id.
+    pub fn get_for(&self, obj: &impl OSMObjBase) -> ChangesetTags<'_> {
+        match obj.changeset_id() {
+            Some(id) => self.get(id.into()),
+            None => ChangesetTags(&[]),
+        }
+    }
+
+    /// Pair every object from this iterator (e.g. `reader.objects()`) with its changeset's tags
+    pub fn annotate_iter<'a, O: OSMObjBase + 'a>(
+        &'a self,
+        objs: impl Iterator<Item = O> + 'a,
+    ) -> impl Iterator<Item = (O, ChangesetTags<'a>)> + 'a {
+        objs.map(move |obj| {
+            let tags = self.get_for(&obj);
+            (obj, tags)
+        })
+    }
+}
+
+/// The tags of one changeset, from a [`ChangesetTagIndex`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChangesetTags<'a>(&'a [(String, String)]);
+
+impl<'a> ChangesetTags<'a> {
+    /// The value of this tag
+    pub fn get(&self, key: impl AsRef<str>) -> Option<&'a str> {
+        let key = key.as_ref();
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All the tags
+    pub fn iter(&self) -> impl Iterator<Item = (&'a str, &'a str)> + 'a {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// True iff there are no (kept) tags, or the changeset isn't in the index
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
+    #[cfg(feature = "bzip2")]
     fn changeset_files() {
         let mut osc = ChangesetTagReader::from_filename(
             "/home/amanda/code/rust/osmio/changeset-examples.osm.bz2",
@@ -413,4 +555,98 @@ mod tests {
         .unwrap();
         dbg!(osc.next_tag().unwrap());
     }
+
+    #[test]
+    fn changeset_bbox() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+ <changeset id="1" created_at="2020-01-01T00:00:00Z" open="false" num_changes="1" comments_count="0" min_lat="1.0" min_lon="2.0" max_lat="3.0" max_lon="4.0"/>
+ <changeset id="2" created_at="2020-01-01T00:00:00Z" open="false" num_changes="0" comments_count="0">
+  <tag k="comment" v="empty"/>
+ </changeset>
+</osm>"#;
+        let changesets: Vec<Changeset> = ChangesetReader::new(xml.as_bytes())
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(changesets[0].bbox, Some("2,1,4,3".parse().unwrap()));
+        assert_eq!(changesets[1].bbox, None);
+    }
+
+    #[test]
+    fn open_changesets() {
+        // From the planet dump, and the API (which has no `num_changes`, but `changes_count`)
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="replicate_changesets.rb">
+ <changeset id="1" created_at="2005-04-09T19:54:13Z" closed_at="2005-04-09T20:54:39Z" open="false" user="Steve" uid="1" min_lat="51.5288506" min_lon="-0.1465242" max_lat="51.5288620" max_lon="-0.1464925" num_changes="2" comments_count="11"/>
+ <changeset id="151347887" created_at="2024-05-20T08:41:21Z" open="true" user="mapper" uid="123" num_changes="0" comments_count="0">
+  <tag k="created_by" v="iD 2.29.0"/>
+ </changeset>
+ <changeset id="151347890" created_at="2024-05-20T08:41:30Z" user="mapper" uid="123" comments_count="0" changes_count="3"/>
+ <changeset id="151347891" created_at="2024-05-20T08:42:00Z" closed_at="2024-05-20T08:42:01Z" user="mapper" uid="123"/>
+</osm>"#;
+        let changesets: Vec<Changeset> = ChangesetReader::new(xml.as_bytes())
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(changesets.len(), 4);
+        let open: Vec<_> = changesets.iter().map(|c| c.is_open()).collect();
+        assert_eq!(open, vec![false, true, true, false]);
+        assert_eq!(changesets[0].comments_count, 11);
+        assert_eq!(changesets[1].tag("created_by"), Some("iD 2.29.0"));
+        assert_eq!(changesets[2].num_changes, 3);
+        assert_eq!(changesets[3].num_changes, 0);
+        for c in changesets.iter() {
+            c.validate().unwrap();
+        }
+
+        let mut c = changesets[1].clone();
+        c.closed = Some(TimestampFormat::ISOString(
+            "2024-05-20T09:41:21Z".to_string(),
+        ));
+        assert!(c.validate().is_err());
+        c.open = false;
+        c.validate().unwrap();
+        c.closed = Some(TimestampFormat::ISOString(
+            "2024-05-20T07:41:21Z".to_string(),
+        ));
+        assert!(c.validate().is_err());
+        c.closed = None;
+        assert!(c.validate().is_err());
+    }
+
+    #[test]
+    fn tag_index() {
+        use obj_types::{StringNodeBuilder, StringOSMObj};
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6">
+ <changeset id="1" created_at="2020-01-01T00:00:00Z" open="false" num_changes="1" comments_count="0">
+  <tag k="created_by" v="MAPS.ME android 9.0"/>
+  <tag k="locale" v="en"/>
+ </changeset>
+ <changeset id="2" created_at="2020-01-01T00:00:00Z" open="false" num_changes="1" comments_count="0">
+  <tag k="locale" v="en"/>
+ </changeset>
+</osm>"#;
+        let index = ChangesetTagIndex::new()
+            .load(ChangesetTagReader::new(xml.as_bytes()))
+            .unwrap();
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.get(1).get("created_by"), Some("MAPS.ME android 9.0"));
+        assert_eq!(index.get(1).get("locale"), None);
+        assert!(index.get(2).is_empty());
+
+        let nodes: Vec<StringOSMObj> = [Some(1), Some(3), None]
+            .iter()
+            .map(|cs| {
+                let mut node: StringOSMObj =
+                    StringNodeBuilder::default()._id(1).build().unwrap().into();
+                node.set_changeset_id(*cs);
+                node
+            })
+            .collect();
+        let created_by: Vec<_> = index
+            .annotate_iter(nodes.into_iter())
+            .map(|(_, tags)| tags.get("created_by"))
+            .collect();
+        assert_eq!(created_by, vec![Some("MAPS.ME android 9.0"), None, None]);
+    }
 }
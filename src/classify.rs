@@ -0,0 +1,335 @@
+//! Sorting objects into categories (e.g. `road`, `building`, `poi:food`) with a list of rules.
+//!
+//! A [`Classifier`] is a list of rules, each a category name and a [`TagFilter`] (with the
+//! `osmium tags-filter` [expression syntax](crate::tagfilter)). An object's category is the
+//! category of the first rule which matches it. Objects which match no rule get the default
+//! category, if there is one.
+//!
+//! Rules are usually loaded from a JSON file:
+//!
+//! ```json
+//! {
+//!   "default": "other",
+//!   "rules": [
+//!     {"category": "road", "filter": "w/highway=motorway|trunk|primary|secondary"},
+//!     {"category": "building", "filter": "wr/building"},
+//!     {"category": "poi:food", "filter": "nw/amenity=restaurant|cafe|fast_food"}
+//!   ]
+//! }
+//! ```
+//!
+//! The category can be added to each object as a tag (with [`Pipeline::classify`]), or
+//! [`Classifier::split`] can write each category to a different writer.
+//!
+//! ```rust
+//! use osmio::classify::Classifier;
+//! use osmio::obj_types::StringNodeBuilder;
+//!
+//! let classifier = Classifier::from_json(
+//!     r#"{"rules": [{"category": "poi:food", "filter": "n/amenity=cafe|restaurant"}]}"#,
+//! )?;
+//! let node = StringNodeBuilder::default()
+//!     ._id(1)
+//!     ._tags(vec![("amenity".to_string(), "cafe".to_string())])
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(classifier.classify(&node), Some("poi:food"));
+//! # Ok::<(), osmio::classify::ClassifierError>(())
+//! ```
+//!
+//! [`Pipeline::classify`]: crate::pipeline::Pipeline::classify
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use tagfilter::{ParseTagFilterError, TagFilter};
+use {OSMObjBase, OSMObjectType, OSMReader, OSMWriter};
+
+use anyhow::Result;
+
+/// The JSON rule file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClassifierConfig {
+    #[serde(default)]
+    default: Option<String>,
+    rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    category: String,
+    /// Tag filter expressions, separated by whitespace
+    filter: String,
+}
+
+/// An ordered list of rules. See the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Classifier {
+    rules: Vec<(String, TagFilter)>,
+    default: Option<String>,
+}
+
+impl Classifier {
+    /// A classifier with no rules, which puts nothing in a category
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the rules from JSON (see the [module documentation](self) for the format)
+    pub fn from_json(json: &str) -> Result<Self, ClassifierError> {
+        Self::from_config(serde_json::from_str(json).map_err(ClassifierError::Json)?)
+    }
+
+    /// Load the rules from a JSON file
+    pub fn from_reader(reader: impl Read) -> Result<Self, ClassifierError> {
+        Self::from_config(serde_json::from_reader(reader).map_err(ClassifierError::Json)?)
+    }
+
+    fn from_config(config: ClassifierConfig) -> Result<Self, ClassifierError> {
+        let mut classifier = Classifier::new();
+        for rule in config.rules {
+            let filter = rule
+                .filter
+                .parse()
+                .map_err(|error| ClassifierError::InvalidFilter {
+                    category: rule.category.clone(),
+                    error,
+                })?;
+            classifier = classifier.rule(rule.category, filter);
+        }
+        classifier.default = config.default;
+        Ok(classifier)
+    }
+
+    /// Add a rule, which is checked after the existing ones
+    pub fn rule(mut self, category: impl Into<String>, filter: TagFilter) -> Self {
+        self.rules.push((category.into(), filter));
+        self
+    }
+
+    /// The category for objects which don't match any rule. Without one, they aren't in any
+    /// category.
+    pub fn default_category(mut self, category: impl Into<String>) -> Self {
+        self.default = Some(category.into());
+        self
+    }
+
+    /// Every category, in the order of the rules (then the default category), without duplicates
+    pub fn categories(&self) -> Vec<&str> {
+        let mut categories: Vec<&str> = Vec::new();
+        for category in self
+            .rules
+            .iter()
+            .map(|(c, _)| c.as_str())
+            .chain(self.default.as_deref())
+        {
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+        categories
+    }
+
+    /// The category of an object of this type, with these tags
+    pub fn classify_tags<'a>(
+        &self,
+        object_type: OSMObjectType,
+        tags: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+    ) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|(_, filter)| filter.matches_tags(object_type, tags.clone()))
+            .map(|(category, _)| category.as_str())
+            .or(self.default.as_deref())
+    }
+
+    /// The category of this object
+    pub fn classify(&self, obj: &impl OSMObjBase) -> Option<&str> {
+        self.rules
+            .iter()
+            .find(|(_, filter)| filter.matches(obj))
+            .map(|(category, _)| category.as_str())
+            .or(self.default.as_deref())
+    }
+
+    /// Set the tag `key` to the category of this object. If it isn't in a category, the tag is
+    /// removed.
+    pub fn annotate(&self, obj: &mut impl OSMObjBase, key: &str) {
+        match self.classify(&*obj).map(str::to_string) {
+            Some(category) => obj.set_tag(key, category),
+            None => obj.unset_tag(key),
+        }
+    }
+
+    /// Read every object from `reader`, and write it to the writer for its category. Objects
+    /// which aren't in a category are skipped.
+    ///
+    /// `make_writer` is called with the category to create its writer, the first time an object
+    /// in that category is found. Returns the writer for each category which had objects. They
+    /// haven't been closed.
+    pub fn split<R, W, Wr, F>(
+        &self,
+        reader: &mut R,
+        mut make_writer: F,
+    ) -> Result<HashMap<String, Wr>>
+    where
+        R: OSMReader,
+        W: Write,
+        Wr: OSMWriter<W>,
+        F: FnMut(&str) -> Result<Wr>,
+    {
+        let mut writers: HashMap<String, Wr> = HashMap::new();
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            let category = match self.classify(&obj) {
+                Some(category) => category,
+                None => continue,
+            };
+            if !writers.contains_key(category) {
+                writers.insert(category.to_string(), make_writer(category)?);
+            }
+            writers.get_mut(category).unwrap().write_obj(&obj)?;
+        }
+        Ok(writers)
+    }
+}
+
+/// An error while loading classifier rules
+#[derive(Debug)]
+pub enum ClassifierError {
+    /// The rules aren't valid JSON, or are missing fields
+    Json(serde_json::Error),
+    /// The filter for this category isn't a valid tag filter
+    InvalidFilter {
+        category: String,
+        error: ParseTagFilterError,
+    },
+}
+
+impl std::fmt::Display for ClassifierError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClassifierError::Json(e) => write!(f, "Invalid classifier rules: {}", e),
+            ClassifierError::InvalidFilter { category, error } => {
+                write!(f, "Invalid filter for category {:?}: {}", category, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClassifierError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClassifierError::Json(e) => Some(e),
+            ClassifierError::InvalidFilter { error, .. } => Some(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Cursor;
+    use xml::{XMLReader, XMLWriter};
+
+    const RULES: &str = r#"{
+        "default": "other",
+        "rules": [
+            {"category": "road", "filter": "w/highway=primary|secondary"},
+            {"category": "building", "filter": "wr/building"},
+            {"category": "poi:food", "filter": "nw/amenity=cafe|restaurant"},
+            {"category": "building", "filter": "w/building:part"}
+        ]
+    }"#;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"><tag k="amenity" v="cafe"/></node>
+<node id="2" version="1" lat="1" lon="1"/>
+<way id="1" version="1"><nd ref="1"/><nd ref="2"/><tag k="highway" v="primary"/></way>
+<way id="2" version="1"><nd ref="1"/><nd ref="2"/><tag k="building" v="yes"/><tag k="amenity" v="cafe"/></way>
+<way id="3" version="1"><nd ref="1"/><nd ref="2"/><tag k="highway" v="path"/></way>
+</osm>"#;
+
+    #[test]
+    fn classify() {
+        let classifier = Classifier::from_json(RULES).unwrap();
+        assert_eq!(
+            classifier.categories(),
+            vec!["road", "building", "poi:food", "other"]
+        );
+        let categories: Vec<_> = XMLReader::new(Cursor::new(INPUT))
+            .objects()
+            .map(|o| classifier.classify(&o).unwrap().to_string())
+            .collect();
+        // The first matching rule wins
+        assert_eq!(
+            categories,
+            vec!["poi:food", "other", "road", "building", "other"]
+        );
+        assert_eq!(
+            classifier.classify_tags(
+                OSMObjectType::Relation,
+                vec![("building", "yes")].into_iter()
+            ),
+            Some("building")
+        );
+        assert_eq!(
+            Classifier::new()
+                .rule("road", "w/highway".parse().unwrap())
+                .classify_tags(
+                    OSMObjectType::Node,
+                    vec![("highway", "bus_stop")].into_iter()
+                ),
+            None
+        );
+
+        let mut obj = XMLReader::new(Cursor::new(INPUT)).objects().next().unwrap();
+        classifier.annotate(&mut obj, "category");
+        assert_eq!(obj.tag("category"), Some("poi:food"));
+        Classifier::new().annotate(&mut obj, "category");
+        assert_eq!(obj.tag("category"), None);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            Classifier::from_json(r#"{"rules": [{"category": "x", "filter": "q/highway"}]}"#),
+            Err(ClassifierError::InvalidFilter { category, .. }) if category == "x"
+        ));
+        assert!(matches!(
+            Classifier::from_json(r#"{"rules": [{"category": "x"}]}"#),
+            Err(ClassifierError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn split() {
+        let classifier =
+            Classifier::from_json(r#"{"rules": [{"category": "road", "filter": "w/highway"}]}"#)
+                .unwrap()
+                .rule("building", "w/building".parse().unwrap());
+        let dir = tempfile::tempdir().unwrap();
+        let path = |category: &str| dir.path().join(format!("{}.osm", category));
+        let writers = classifier
+            .split(&mut XMLReader::new(Cursor::new(INPUT)), |category| {
+                Ok(XMLWriter::new(File::create(path(category))?))
+            })
+            .unwrap();
+        let mut categories: Vec<_> = writers.keys().cloned().collect();
+        categories.sort();
+        assert_eq!(categories, vec!["building", "road"]);
+        drop(writers);
+
+        let ids = |category: &str| -> Vec<_> {
+            XMLReader::new(File::open(path(category)).unwrap())
+                .objects()
+                .map(|o| o.id())
+                .collect()
+        };
+        assert_eq!(ids("road"), vec![1, 3]);
+        assert_eq!(ids("building"), vec![2]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Reading files from cloud object stores, i.e. `s3://` & `gs://` URLs (`cloud` feature).
+//!
+//! [`ObjectReader`] reads an object sequentially with range requests, fetching the next chunk
+//! in the background while the current one is being read, so (e.g.) a planet file can be
+//! processed without copying it to local disk first. [`open_input`] (and so
+//! [`AnyReader::from_filename`] & the command line programs) open these URLs with it.
+//!
+//! Credentials & the region come from the usual environment variables (e.g.
+//! `AWS_ACCESS_KEY_ID`, `AWS_REGION`, `GOOGLE_SERVICE_ACCOUNT`), see
+//! [`AmazonS3Builder::from_env`] & [`GoogleCloudStorageBuilder::from_env`].
+//!
+//! ```no_run
+//! use osmio::changesets::ChangesetReader;
+//! use osmio::cloud::ObjectReader;
+//! use osmio::OSMReader;
+//!
+//! let mut reader = osmio::arcpbf::PBFReader::new(ObjectReader::from_url(
+//!     "s3://example-bucket/planet/planet-latest.osm.pbf",
+//! )?);
+//! println!("{} objects", reader.objects().count());
+//!
+//! let changesets = ChangesetReader::from_bz2_reader(ObjectReader::from_url(
+//!     "gs://example-bucket/changesets-latest.osm.bz2",
+//! )?);
+//! println!("{} changesets", changesets.count());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Each reader runs its requests on its own (tokio) runtime, so it can be used from normal
+//! synchronous code, but not from inside an async runtime.
+//!
+//! [`open_input`]: crate::open::open_input
+//! [`AnyReader::from_filename`]: crate::open::AnyReader::from_filename
+use object_store::aws::AmazonS3Builder;
+use object_store::gcp::GoogleCloudStorageBuilder;
+use object_store::path::Path;
+use object_store::ObjectStore;
+use std::io::{self, Read};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+use anyhow::{bail, Result};
+
+/// Bytes fetched with each range request, unless changed with [`ObjectReader::chunk_size`]
+pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// True iff this is a URL which [`ObjectReader::from_url`] can read
+pub fn is_url(s: &str) -> bool {
+    split_url(s).is_some()
+}
+
+/// The scheme (`s3` or `gs`), bucket & key of an object URL
+fn split_url(url: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    if scheme != "s3" && scheme != "gs" {
+        return None;
+    }
+    let (bucket, key) = rest.split_once('/')?;
+    if bucket.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some((scheme, bucket, key))
+}
+
+/// Reads one object from an object store. See the [module documentation](self).
+pub struct ObjectReader {
+    store: Arc<dyn ObjectStore>,
+    path: Path,
+    size: usize,
+    chunk_size: usize,
+    /// Moved to the fetching thread on the first read
+    runtime: Option<Runtime>,
+    /// Chunks from the fetching thread, in order
+    chunks: Option<Receiver<object_store::Result<bytes::Bytes>>>,
+    /// The unread part of the current chunk
+    buf: bytes::Bytes,
+}
+
+impl ObjectReader {
+    /// Read the object at this `s3://bucket/key` or `gs://bucket/key` URL
+    pub fn from_url(url: &str) -> Result<Self> {
+        let (scheme, bucket, key) = match split_url(url) {
+            Some(parts) => parts,
+            None => bail!("Not an s3:// or gs:// object URL: {:?}", url),
+        };
+        let store: Arc<dyn ObjectStore> = if scheme == "s3" {
+            Arc::new(
+                AmazonS3Builder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()?,
+            )
+        } else {
+            Arc::new(
+                GoogleCloudStorageBuilder::from_env()
+                    .with_bucket_name(bucket)
+                    .build()?,
+            )
+        };
+        Self::new(store, Path::from_url_path(key)?)
+    }
+
+    /// Read the object at `path` in this store
+    pub fn new(store: Arc<dyn ObjectStore>, path: Path) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let size = runtime.block_on(store.head(&path))?.size;
+        Ok(ObjectReader {
+            store,
+            path,
+            size,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            runtime: Some(runtime),
+            chunks: None,
+            buf: bytes::Bytes::new(),
+        })
+    }
+
+    /// Fetch this many bytes with each request. It has no effect after the first read.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Size of the object, in bytes
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Start the thread which fetches each chunk in turn. It stays one chunk ahead of the
+    /// reader, and stops when the reader is dropped.
+    fn start(&mut self) -> Receiver<object_store::Result<bytes::Bytes>> {
+        let (sender, receiver) = sync_channel(1);
+        let runtime = self
+            .runtime
+            .take()
+            .expect("fetching thread already started");
+        let store = Arc::clone(&self.store);
+        let path = self.path.clone();
+        let (size, chunk_size) = (self.size, self.chunk_size);
+        std::thread::spawn(move || {
+            let mut offset = 0;
+            while offset < size {
+                let end = size.min(offset + chunk_size);
+                let chunk = runtime.block_on(store.get_range(&path, offset..end));
+                let failed = chunk.is_err();
+                if sender.send(chunk).is_err() || failed {
+                    break;
+                }
+                offset = end;
+            }
+        });
+        receiver
+    }
+}
+
+impl Read for ObjectReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            if self.chunks.is_none() {
+                self.chunks = Some(self.start());
+            }
+            match self.chunks.as_ref().unwrap().recv() {
+                Ok(chunk) => self.buf = chunk.map_err(io::Error::other)?,
+                // The thread has finished: everything has been read
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = out.len().min(self.buf.len());
+        out[..len].copy_from_slice(&self.buf.split_to(len));
+        Ok(len)
+    }
+}
+
+impl std::fmt::Debug for ObjectReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ObjectReader")
+            .field("store", &self.store.to_string())
+            .field("path", &self.path)
+            .field("size", &self.size)
+            .field("chunk_size", &self.chunk_size)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::memory::InMemory;
+    use open::AnyReader;
+    use {OSMObjBase, OSMReader};
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            split_url("s3://bucket/dir/planet.osm.pbf"),
+            Some(("s3", "bucket", "dir/planet.osm.pbf"))
+        );
+        assert!(is_url("gs://bucket/changesets.osm.bz2"));
+        assert!(!is_url("https://bucket/planet.osm.pbf"));
+        assert!(!is_url("s3://bucket/"));
+        assert!(!is_url("planet.osm.pbf"));
+    }
+
+    #[test]
+    fn read() {
+        let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"><tag k="amenity" v="cafe"/></node>
+<node id="2" version="1" lat="1" lon="1"/>
+</osm>"#;
+        let output = input.as_bytes().to_vec();
+
+        let store = Arc::new(InMemory::new());
+        let path = Path::from("extracts/test.osm");
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(store.put(&path, output.clone().into()))
+            .unwrap();
+
+        let mut bytes = Vec::new();
+        let mut reader = ObjectReader::new(store.clone(), path.clone())
+            .unwrap()
+            .chunk_size(7);
+        assert_eq!(reader.size(), output.len());
+        reader.read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, output);
+
+        let reader = ObjectReader::new(store.clone(), path)
+            .unwrap()
+            .chunk_size(10);
+        let mut reader = AnyReader::from_reader(Box::new(reader)).unwrap();
+        let ids: Vec<_> = reader.objects().map(|o| o.id()).collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        assert!(ObjectReader::new(store, Path::from("missing.osm")).is_err());
+    }
+}
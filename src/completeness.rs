@@ -0,0 +1,214 @@
+//! Checking which relations in an extract are complete.
+//!
+//! Extracts cut relations at the boundary, so a boundary or route relation may be missing some
+//! of its members, or have member ways which are missing some of their nodes. Then its geometry
+//! can't be assembled. [`check_completeness`] reports, for every relation, what's missing.
+//!
+//! ```no_run
+//! use osmio::completeness::check_completeness;
+//!
+//! let mut reader = osmio::read_pbf("extract.osm.pbf")?;
+//! let report = check_completeness(&mut reader)?;
+//! for relation in report.incomplete() {
+//!     println!("r{} is missing {} members", relation.relation_id, relation.missing_members.len());
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The missing objects can be downloaded (see [`CompletenessReport::missing_ids`]), or replaced
+//! with stubs (see [`CompletenessReport::stubs`]), so that tools which need every member to exist
+//! can read the file.
+use idset::IdSet;
+use obj_types::{StringNodeBuilder, StringOSMObj, StringRelationBuilder, StringWayBuilder};
+use serde::{Deserialize, Serialize};
+use {Error, OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, Relation, Way};
+
+/// What one relation is missing
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelationCompleteness {
+    pub relation_id: ObjId,
+    pub num_members: usize,
+    /// Members which aren't in the file
+    pub missing_members: Vec<(OSMObjectType, ObjId)>,
+    /// Member ways which are in the file, but are missing some of their nodes
+    pub incomplete_ways: Vec<ObjId>,
+}
+
+impl RelationCompleteness {
+    /// True iff every member (and every node of every member way) is in the file
+    pub fn is_complete(&self) -> bool {
+        self.missing_members.is_empty() && self.incomplete_ways.is_empty()
+    }
+}
+
+/// The completeness of every relation in a file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompletenessReport {
+    /// Every relation, in the order they are in the file
+    pub relations: Vec<RelationCompleteness>,
+}
+
+impl CompletenessReport {
+    /// The relations which aren't complete
+    pub fn incomplete(&self) -> impl Iterator<Item = &RelationCompleteness> {
+        self.relations.iter().filter(|r| !r.is_complete())
+    }
+
+    /// Number of relations which are complete
+    pub fn num_complete(&self) -> usize {
+        self.relations.iter().filter(|r| r.is_complete()).count()
+    }
+
+    /// The members which are missing from any relation, e.g. to download them
+    pub fn missing_ids(&self) -> IdSet {
+        self.relations
+            .iter()
+            .flat_map(|r| r.missing_members.iter().copied())
+            .collect()
+    }
+
+    /// An empty object for every missing member, sorted: nodes without a location, ways without
+    /// nodes, and relations without members. They have no version or tags.
+    pub fn stubs(&self) -> Vec<StringOSMObj> {
+        let ids = self.missing_ids();
+        let nodes = ids
+            .ids(OSMObjectType::Node)
+            .map(|id| StringNodeBuilder::default()._id(id).build().unwrap().into());
+        let ways = ids
+            .ids(OSMObjectType::Way)
+            .map(|id| StringWayBuilder::default()._id(id).build().unwrap().into());
+        let relations = ids.ids(OSMObjectType::Relation).map(|id| {
+            StringRelationBuilder::default()
+                ._id(id)
+                .build()
+                .unwrap()
+                .into()
+        });
+        nodes.chain(ways).chain(relations).collect()
+    }
+}
+
+/// Checks objects one at a time. The objects must be sorted (nodes, then ways, then relations).
+///
+/// The ids of the objects, and of the ways with missing nodes, are kept in memory. Since
+/// relations can have later relations as members, those are only checked in
+/// [`CompletenessChecker::finish`].
+#[derive(Debug, Default)]
+pub struct CompletenessChecker {
+    seen: IdSet,
+    ways_missing_nodes: IdSet,
+    report: CompletenessReport,
+    /// `(index in report, member_id)` for relation members which hadn't been seen yet
+    pending_relation_members: Vec<(usize, ObjId)>,
+}
+
+impl CompletenessChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the next object
+    pub fn check(&mut self, obj: &impl OSMObj) {
+        self.seen.insert_obj(obj);
+        if let Some(way) = obj.as_way() {
+            if way
+                .nodes()
+                .iter()
+                .any(|nid| !self.seen.contains(OSMObjectType::Node, *nid))
+            {
+                self.ways_missing_nodes.insert(OSMObjectType::Way, way.id());
+            }
+        } else if let Some(relation) = obj.as_relation() {
+            let idx = self.report.relations.len();
+            let mut result = RelationCompleteness {
+                relation_id: relation.id(),
+                num_members: relation.members().len(),
+                ..Default::default()
+            };
+            for (member_type, member_id, _role) in relation.members() {
+                if member_type == OSMObjectType::Relation {
+                    if !self.seen.contains(member_type, member_id) {
+                        self.pending_relation_members.push((idx, member_id));
+                    }
+                } else if !self.seen.contains(member_type, member_id) {
+                    result.missing_members.push((member_type, member_id));
+                } else if member_type == OSMObjectType::Way
+                    && self.ways_missing_nodes.contains(member_type, member_id)
+                    && !result.incomplete_ways.contains(&member_id)
+                {
+                    result.incomplete_ways.push(member_id);
+                }
+            }
+            self.report.relations.push(result);
+        }
+    }
+
+    /// Finish checking, and return the report
+    pub fn finish(mut self) -> CompletenessReport {
+        for (idx, member_id) in self.pending_relation_members {
+            if !self.seen.contains(OSMObjectType::Relation, member_id) {
+                self.report.relations[idx]
+                    .missing_members
+                    .push((OSMObjectType::Relation, member_id));
+            }
+        }
+        self.report
+    }
+}
+
+/// Check every relation in this reader. Returns an error if the file is invalid.
+pub fn check_completeness(reader: &mut impl OSMReader) -> Result<CompletenessReport, Error> {
+    let mut checker = CompletenessChecker::new();
+    for obj in reader.try_objects() {
+        checker.check(&obj?);
+    }
+    Ok(checker.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xml::XMLReader;
+
+    #[test]
+    fn completeness() {
+        let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="5"/>
+<way id="10"><nd ref="1"/><nd ref="2"/></way>
+<way id="11"><nd ref="2"/><nd ref="3"/></way>
+<relation id="20"><member type="way" ref="10" role="outer"/></relation>
+<relation id="21"><member type="way" ref="11" role="outer"/><member type="way" ref="12" role="outer"/><member type="node" ref="4" role="label"/><member type="relation" ref="22" role=""/><member type="relation" ref="23" role=""/></relation>
+<relation id="22"><member type="relation" ref="20" role=""/></relation>
+</osm>"#;
+        let report = check_completeness(&mut XMLReader::new(input.as_bytes())).unwrap();
+        assert_eq!(report.relations.len(), 3);
+        assert_eq!(report.num_complete(), 2);
+        let incomplete: Vec<_> = report.incomplete().collect();
+        assert_eq!(
+            incomplete,
+            vec![&RelationCompleteness {
+                relation_id: 21,
+                num_members: 5,
+                missing_members: vec![
+                    (OSMObjectType::Way, 12),
+                    (OSMObjectType::Node, 4),
+                    (OSMObjectType::Relation, 23)
+                ],
+                incomplete_ways: vec![11],
+            }]
+        );
+
+        let stubs: Vec<_> = report
+            .stubs()
+            .iter()
+            .map(|o| format!("{}{}", o.object_type().name_short(), o.id()))
+            .collect();
+        assert_eq!(stubs, vec!["n4", "w12", "r23"]);
+        assert!(report.stubs()[1].as_way().unwrap().nodes().is_empty());
+
+        let input = r#"<osm version="0.6"><node id="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        assert!(check_completeness(&mut XMLReader::new(input.as_bytes())).is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Merging duplicate nodes, which are at exactly the same location.
+//!
+//! Imports often create several nodes at the same place. This takes 2 passes: first every node is
+//! [added](NodeDeduplicator::add_node), to find the duplicates, then every object is
+//! [deduplicated](NodeDeduplicator::dedup): duplicate nodes are dropped, and way nodes & relation
+//! members which refer to them are changed to refer to the node that's kept (the first one seen at
+//! that location).
+//!
+//! Nodes are only merged if they have the same tags, so tagged nodes (e.g. POIs) aren't merged
+//! into untagged way nodes, or into each other, and no tags are lost.
+//!
+//! ```rust
+//! use osmio::dedup::NodeDeduplicator;
+//! use osmio::obj_types::{StringNodeBuilder, StringOSMObj, StringWayBuilder};
+//! use osmio::{Lat, Lon, OSMObj, Way};
+//! use std::convert::TryFrom;
+//!
+//! let loc = (Lat::try_from(1.).unwrap(), Lon::try_from(2.).unwrap());
+//! let objs: Vec<StringOSMObj> = vec![
+//!     StringNodeBuilder::default()._id(1)._lat_lon(loc).build().unwrap().into(),
+//!     StringNodeBuilder::default()._id(2)._lat_lon(loc).build().unwrap().into(),
+//!     StringWayBuilder::default()._id(1)._nodes(vec![2, 3]).build().unwrap().into(),
+//! ];
+//!
+//! let mut dedup = NodeDeduplicator::new();
+//! dedup.extend(objs.iter().cloned());
+//! assert_eq!(dedup.num_duplicates(), 1);
+//!
+//! let objs: Vec<_> = dedup.dedup_iter(objs.into_iter()).collect();
+//! assert_eq!(objs.len(), 2);
+//! assert_eq!(objs[1].as_way().unwrap().nodes(), &[1, 3]);
+//! ```
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use {Error, Node, OSMObj, OSMObjectType, OSMReader, ObjId, Relation, Way};
+
+/// Finds nodes at the same location, and replaces references to them.
+#[derive(Debug, Clone, Default)]
+pub struct NodeDeduplicator {
+    /// (inner lat, inner lon) → (id of the node kept there, hash of its tags)
+    locations: HashMap<(i32, i32), (ObjId, u64)>,
+    /// duplicate node id → node id which replaces it
+    replacements: HashMap<ObjId, ObjId>,
+}
+
+/// Hash of the tags, independent of their order
+fn tags_hash(node: &impl Node) -> u64 {
+    let mut tags: Vec<_> = node.tags().collect();
+    tags.sort_unstable();
+    let mut hasher = DefaultHasher::new();
+    tags.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl NodeDeduplicator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Find the duplicates in this reader (the first pass). Read the file again to
+    /// [`dedup`](Self::dedup) it. Returns an error if the file is invalid.
+    pub fn from_reader(reader: &mut impl OSMReader) -> Result<Self, Error> {
+        let mut dedup = Self::new();
+        for obj in reader.try_objects() {
+            if let Some(node) = obj?.as_node() {
+                dedup.add_node(node);
+            }
+        }
+        Ok(dedup)
+    }
+
+    /// Record the location of this node. Nodes without a location (e.g. deleted nodes) are never
+    /// duplicates.
+    pub fn add_node(&mut self, node: &impl Node) {
+        let (lat, lon) = match node.lat_lon() {
+            Some(ll) => ll,
+            None => return,
+        };
+        let tags = tags_hash(node);
+        let (kept, kept_tags) = *self
+            .locations
+            .entry((lat.inner(), lon.inner()))
+            .or_insert((node.id(), tags));
+        if kept != node.id() && kept_tags == tags {
+            self.replacements.insert(node.id(), kept);
+        }
+    }
+
+    /// Record the locations of all the nodes from this iterator
+    pub fn extend(&mut self, objs: impl IntoIterator<Item = impl OSMObj>) {
+        for obj in objs {
+            if let Some(node) = obj.as_node() {
+                self.add_node(node);
+            }
+        }
+    }
+
+    /// The node which replaces this node, if it's a duplicate
+    pub fn replacement(&self, node_id: ObjId) -> Option<ObjId> {
+        self.replacements.get(&node_id).copied()
+    }
+
+    /// Number of nodes which will be removed
+    pub fn num_duplicates(&self) -> usize {
+        self.replacements.len()
+    }
+
+    fn map_id(&self, node_id: ObjId) -> ObjId {
+        self.replacement(node_id).unwrap_or(node_id)
+    }
+
+    /// Point the nodes of this way at the kept nodes. If that makes the same node appear twice in
+    /// a row, only one is kept. Returns true iff the way was changed.
+    pub fn dedup_way(&self, way: &mut impl Way) -> bool {
+        if !way
+            .nodes()
+            .iter()
+            .any(|nid| self.replacements.contains_key(nid))
+        {
+            return false;
+        }
+        let mut nodes: Vec<ObjId> = way.nodes().iter().map(|nid| self.map_id(*nid)).collect();
+        nodes.dedup();
+        way.set_nodes(nodes);
+        true
+    }
+
+    /// Point the node members of this relation at the kept nodes. Returns true iff the relation
+    /// was changed.
+    pub fn dedup_relation(&self, relation: &mut impl Relation) -> bool {
+        if !relation
+            .members()
+            .any(|(t, id, _)| t == OSMObjectType::Node && self.replacements.contains_key(&id))
+        {
+            return false;
+        }
+        let members: Vec<(OSMObjectType, ObjId, String)> = relation
+            .members()
+            .map(|(t, id, role)| match t {
+                OSMObjectType::Node => (t, self.map_id(id), role.to_string()),
+                _ => (t, id, role.to_string()),
+            })
+            .collect();
+        relation.set_members(members);
+        true
+    }
+
+    /// Deduplicate this object (the second pass). Returns false if this is a duplicate node,
+    /// which should be dropped.
+    pub fn dedup<O: OSMObj>(&self, obj: &mut O) -> bool {
+        if let Some(way) = obj.as_way_mut() {
+            self.dedup_way(way);
+        } else if let Some(relation) = obj.as_relation_mut() {
+            self.dedup_relation(relation);
+        } else if obj.is_node() {
+            return !self.replacements.contains_key(&obj.id());
+        }
+        true
+    }
+
+    /// Deduplicate all the objects from this iterator (e.g. `reader.objects()`), dropping the
+    /// duplicate nodes
+    pub fn dedup_iter<'a, O: OSMObj + 'a>(
+        &'a self,
+        objs: impl Iterator<Item = O> + 'a,
+    ) -> impl Iterator<Item = O> + 'a {
+        objs.filter_map(move |mut obj| {
+            if self.dedup(&mut obj) {
+                Some(obj)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringNodeBuilder, StringOSMObj, StringRelationBuilder, StringWayBuilder};
+    use std::convert::TryFrom;
+    use xml::XMLReader;
+    use {Lat, Lon, OSMObjBase};
+
+    fn node(id: ObjId, lat: f64, tags: &[(&str, &str)]) -> StringOSMObj {
+        StringNodeBuilder::default()
+            ._id(id)
+            ._lat_lon((Lat::try_from(lat).unwrap(), Lon::try_from(0.).unwrap()))
+            ._tags(
+                tags.iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn dedup() {
+        let objs = vec![
+            node(1, 1., &[]),
+            node(2, 1., &[]),
+            node(3, 1., &[("shop", "bakery")]),
+            node(4, 2., &[("shop", "bakery")]),
+            node(5, 2., &[("shop", "bakery")]),
+            node(6, 3., &[]),
+            StringWayBuilder::default()
+                ._id(1)
+                ._nodes(vec![1, 2, 6, 5, 3])
+                .build()
+                .unwrap()
+                .into(),
+            StringRelationBuilder::default()
+                ._id(1)
+                ._members(vec![
+                    (OSMObjectType::Node, 2, "stop".to_string()),
+                    (OSMObjectType::Way, 2, "".to_string()),
+                ])
+                .build()
+                .unwrap()
+                .into(),
+        ];
+        let mut dedup = NodeDeduplicator::new();
+        dedup.extend(objs.iter().cloned());
+        assert_eq!(dedup.num_duplicates(), 2);
+        assert_eq!(dedup.replacement(2), Some(1));
+        assert_eq!(dedup.replacement(3), None);
+        assert_eq!(dedup.replacement(5), Some(4));
+
+        let objs: Vec<_> = dedup.dedup_iter(objs.into_iter()).collect();
+        let ids: Vec<_> = objs.iter().map(|o| (o.object_type(), o.id())).collect();
+        assert_eq!(
+            ids,
+            vec![
+                (OSMObjectType::Node, 1),
+                (OSMObjectType::Node, 3),
+                (OSMObjectType::Node, 4),
+                (OSMObjectType::Node, 6),
+                (OSMObjectType::Way, 1),
+                (OSMObjectType::Relation, 1),
+            ]
+        );
+        assert_eq!(objs[4].as_way().unwrap().nodes(), &[1, 6, 4, 3]);
+        let members: Vec<_> = objs[5].as_relation().unwrap().members().collect();
+        assert_eq!(
+            members,
+            vec![
+                (OSMObjectType::Node, 1, "stop"),
+                (OSMObjectType::Way, 2, "")
+            ]
+        );
+    }
+
+    #[test]
+    fn from_reader() {
+        let xml = r#"<osm><node id="1" lat="1" lon="1"/><node id="2" lat="1" lon="1"/><node id="3" lat="1" lon="2"/></osm>"#;
+        let dedup = NodeDeduplicator::from_reader(&mut XMLReader::new(xml.as_bytes())).unwrap();
+        assert_eq!(dedup.num_duplicates(), 1);
+        assert_eq!(dedup.replacement(2), Some(1));
+
+        let xml = r#"<osm><node id="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        assert!(NodeDeduplicator::from_reader(&mut XMLReader::new(xml.as_bytes())).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! Comparing two versions of a file.
+//!
+//! [`derive_changes`] works out the changes between an old & new file, and writes them as an
+//! osmChange (`.osc`) file, like `osmium derive-changes`.
+//!
+//! ```no_run
+//! use osmio::diff::derive_changes;
+//! use osmio::osc::OSCWriter;
+//! use osmio::OSMWriter;
+//!
+//! let old = osmio::read_pbf("yesterday.osm.pbf")?;
+//! let new = osmio::read_pbf("today.osm.pbf")?;
+//! let mut writer = OSCWriter::new(std::fs::File::create("changes.osc")?);
+//! let counts = derive_changes(old, new, &mut writer)?;
+//! println!("{} created, {} modified, {} deleted", counts.created, counts.modified, counts.deleted);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`MergeJoin`] is the lower level part, which pairs up the objects in 2 sorted files, for other
+//! comparisons.
+use osc::{ChangeAction, OSCWriter};
+use std::cmp::Ordering;
+use std::io::Write;
+use std::iter::Peekable;
+use {Node, OSMObj, OSMObjBase, OSMReader, Relation, Way};
+
+use anyhow::Result;
+
+/// Number of objects in each part of a change file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangeCounts {
+    pub created: u64,
+    pub modified: u64,
+    pub deleted: u64,
+}
+
+/// True iff these objects have the same data (version, deleted, tags, location, nodes &
+/// members). User, changeset & timestamp are ignored.
+pub fn same_data(a: &impl OSMObj, b: &impl OSMObj) -> bool {
+    if a.object_type() != b.object_type()
+        || a.id() != b.id()
+        || a.version() != b.version()
+        || a.deleted() != b.deleted()
+    {
+        return false;
+    }
+    let mut a_tags: Vec<_> = a.tags().collect();
+    let mut b_tags: Vec<_> = b.tags().collect();
+    a_tags.sort_unstable();
+    b_tags.sort_unstable();
+    if a_tags != b_tags {
+        return false;
+    }
+    if let (Some(a), Some(b)) = (a.as_node(), b.as_node()) {
+        a.lat_lon() == b.lat_lon()
+    } else if let (Some(a), Some(b)) = (a.as_way(), b.as_way()) {
+        a.nodes() == b.nodes()
+    } else if let (Some(a), Some(b)) = (a.as_relation(), b.as_relation()) {
+        a.members().eq(b.members())
+    } else {
+        false
+    }
+}
+
+/// One item from a [`MergeJoin`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum JoinItem<L, R> {
+    /// This object is only in the left (old) stream
+    OnlyLeft(L),
+    /// This object is only in the right (new) stream
+    OnlyRight(R),
+    /// This object (type & id) is in both streams
+    Both(L, R),
+}
+
+/// Walks 2 sorted object streams in lockstep, pairing up objects with the same type & id.
+///
+/// Both streams must be sorted (nodes, then ways, then relations, each by id), with only one
+/// version of each object. The streams can have different object types (e.g. a PBF & an XML
+/// file).
+///
+/// ```rust
+/// use osmio::diff::{JoinItem, MergeJoin};
+/// use osmio::obj_types::StringNodeBuilder;
+/// use osmio::OSMObjBase;
+///
+/// let node = |id| StringNodeBuilder::default()._id(id).build().unwrap();
+/// let old = vec![node(1), node(2)];
+/// let new = vec![node(2), node(3)];
+/// let items: Vec<_> = MergeJoin::new(old.into_iter(), new.into_iter())
+///     .map(|item| match item {
+///         JoinItem::OnlyLeft(o) => format!("-{}", o.id()),
+///         JoinItem::OnlyRight(n) => format!("+{}", n.id()),
+///         JoinItem::Both(o, _) => format!("={}", o.id()),
+///     })
+///     .collect();
+/// assert_eq!(items, vec!["-1", "=2", "+3"]);
+/// ```
+pub struct MergeJoin<IL: Iterator, IR: Iterator> {
+    left: Peekable<IL>,
+    right: Peekable<IR>,
+}
+
+impl<IL, IR> MergeJoin<IL, IR>
+where
+    IL: Iterator,
+    IR: Iterator,
+    IL::Item: OSMObjBase,
+    IR::Item: OSMObjBase,
+{
+    /// Join these 2 streams (e.g. `reader.objects()`)
+    pub fn new(left: IL, right: IR) -> Self {
+        MergeJoin {
+            left: left.peekable(),
+            right: right.peekable(),
+        }
+    }
+}
+
+impl<IL, IR> Iterator for MergeJoin<IL, IR>
+where
+    IL: Iterator,
+    IR: Iterator,
+    IL::Item: OSMObjBase,
+    IR::Item: OSMObjBase,
+{
+    type Item = JoinItem<IL::Item, IR::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ordering = match (self.left.peek(), self.right.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(l), Some(r)) => (l.object_type(), l.id()).cmp(&(r.object_type(), r.id())),
+        };
+        Some(match ordering {
+            Ordering::Less => JoinItem::OnlyLeft(self.left.next().unwrap()),
+            Ordering::Greater => JoinItem::OnlyRight(self.right.next().unwrap()),
+            Ordering::Equal => {
+                JoinItem::Both(self.left.next().unwrap(), self.right.next().unwrap())
+            }
+        })
+    }
+}
+
+/// A [`MergeJoin`] of 2 fallible streams (e.g. `reader.try_objects()`). An error from either
+/// stream is returned as it's reached.
+///
+/// ```rust
+/// use osmio::diff::{JoinItem, TryMergeJoin};
+/// use osmio::xml::XMLReader;
+/// use osmio::OSMReader;
+///
+/// let mut old = XMLReader::new(r#"<osm><node id="1" lat="0" lon="0"/></osm>"#.as_bytes());
+/// let mut new = XMLReader::new(r#"<osm><node id="x"/></osm>"#.as_bytes());
+/// let mut items = TryMergeJoin::new(old.try_objects(), new.try_objects());
+/// assert!(items.next().unwrap().is_err());
+/// ```
+pub struct TryMergeJoin<IL: Iterator, IR: Iterator> {
+    left: Peekable<IL>,
+    right: Peekable<IR>,
+}
+
+impl<IL, IR, L, R, E> TryMergeJoin<IL, IR>
+where
+    IL: Iterator<Item = Result<L, E>>,
+    IR: Iterator<Item = Result<R, E>>,
+    L: OSMObjBase,
+    R: OSMObjBase,
+{
+    /// Join these 2 streams
+    pub fn new(left: IL, right: IR) -> Self {
+        TryMergeJoin {
+            left: left.peekable(),
+            right: right.peekable(),
+        }
+    }
+}
+
+impl<IL, IR, L, R, E> Iterator for TryMergeJoin<IL, IR>
+where
+    IL: Iterator<Item = Result<L, E>>,
+    IR: Iterator<Item = Result<R, E>>,
+    L: OSMObjBase,
+    R: OSMObjBase,
+{
+    type Item = Result<JoinItem<L, R>, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ordering = match (self.left.peek(), self.right.peek()) {
+            (None, None) => return None,
+            (Some(Err(_)), _) | (Some(_), None) => Ordering::Less,
+            (_, Some(Err(_))) | (None, Some(_)) => Ordering::Greater,
+            (Some(Ok(l)), Some(Ok(r))) => (l.object_type(), l.id()).cmp(&(r.object_type(), r.id())),
+        };
+        Some(match ordering {
+            Ordering::Less => self.left.next()?.map(JoinItem::OnlyLeft),
+            Ordering::Greater => self.right.next()?.map(JoinItem::OnlyRight),
+            Ordering::Equal => match (self.left.next()?, self.right.next()?) {
+                (Ok(l), Ok(r)) => Ok(JoinItem::Both(l, r)),
+                (Err(e), _) | (_, Err(e)) => Err(e),
+            },
+        })
+    }
+}
+
+/// Write the changes needed to turn `old` into `new` to `writer` (which isn't closed).
+///
+/// Both files must be sorted. Objects only in `new` are created, objects only in `old` are
+/// deleted, and objects in both whose data differs (see [`same_data`]) are modified. Deleted
+/// objects are written as they were in `old`. Since objects in a block are written in the order
+/// they are found, the file will have many blocks. Returns an error if either file is invalid.
+pub fn derive_changes<RO, RN, W>(
+    mut old: RO,
+    mut new: RN,
+    writer: &mut OSCWriter<W>,
+) -> Result<ChangeCounts>
+where
+    RO: OSMReader,
+    RN: OSMReader,
+    W: Write,
+{
+    let mut counts = ChangeCounts::default();
+    for item in TryMergeJoin::new(old.try_objects(), new.try_objects()) {
+        match item? {
+            JoinItem::OnlyLeft(mut obj) => {
+                obj.set_deleted(true);
+                writer.write_change(ChangeAction::Delete, &obj)?;
+                counts.deleted += 1;
+            }
+            JoinItem::OnlyRight(obj) => {
+                writer.write_change(ChangeAction::Create, &obj)?;
+                counts.created += 1;
+            }
+            JoinItem::Both(old_obj, new_obj) => {
+                if !same_data(&old_obj, &new_obj) {
+                    writer.write_change(ChangeAction::Modify, &new_obj)?;
+                    counts.modified += 1;
+                }
+            }
+        }
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Cursor;
+    use xml::XMLReader;
+    use OSMWriter;
+
+    fn reader(objs: &str) -> XMLReader<Cursor<String>> {
+        XMLReader::new(Cursor::new(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<osm version=\"0.6\">\n{}\n</osm>",
+            objs
+        )))
+    }
+
+    #[test]
+    fn changes() {
+        let old = reader(
+            r#"<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="1"/>
+<node id="3" version="1" lat="1" lon="1"><tag k="a" v="b"/></node>
+<way id="10" version="1"><nd ref="1"/><nd ref="2"/></way>"#,
+        );
+        let new = reader(
+            r#"<node id="1" version="1" lat="1" lon="1"/>
+<node id="3" version="1" lat="1" lon="1"><tag k="a" v="c"/></node>
+<node id="4" version="1" lat="1" lon="1"/>
+<way id="10" version="2"><nd ref="1"/><nd ref="4"/></way>"#,
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("changes.osc");
+        let counts = {
+            let mut writer = OSCWriter::new(File::create(&path).unwrap());
+            derive_changes(old, new, &mut writer).unwrap()
+        };
+        assert_eq!(
+            counts,
+            ChangeCounts {
+                created: 1,
+                modified: 2,
+                deleted: 1
+            }
+        );
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        let blocks: Vec<&str> = output
+            .lines()
+            .filter(|l| ["<create>", "<modify>", "<delete>"].contains(l))
+            .collect();
+        assert_eq!(blocks, vec!["<delete>", "<modify>", "<create>", "<modify>"]);
+        assert!(
+            output.contains(r#"<node id="2" visible="false""#),
+            "{}",
+            output
+        );
+        assert!(output.ends_with("</modify>\n</osmChange>"));
+
+        let old = reader(r#"<node id="1" version="1" lat="1" lon="1"/>"#);
+        let new = reader(r#"<node id="1" version="1" lat="1" lon="1"/><node id="x"/>"#);
+        let mut writer = OSCWriter::new(Vec::new());
+        assert!(derive_changes(old, new, &mut writer).is_err());
+    }
+}
@@ -0,0 +1,439 @@
+//! Downloading extracts & replication files, with a local cache.
+//!
+//! Files are saved in a cache directory, along with their `ETag` & `Last-Modified` headers.
+//! When a file is fetched again, a conditional request is made, and the cached copy is used if
+//! the server says it hasn't changed.
+//!
+//! ```no_run
+//! use osmio::OSMReader;
+//!
+//! let mut reader = osmio::download::fetch("https://download.geofabrik.de/europe/monaco-latest.osm.pbf")?;
+//! println!("{} objects", reader.objects().count());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The download itself is done by a [`Transport`]. The default, [`Curl`], runs the `curl`
+//! program, so `https` URLs work without osmio depending on a TLS library.
+use open::AnyReader;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result};
+
+/// The `ETag` & `Last-Modified` headers of a downloaded file, used to validate the cached copy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// The result of a (conditional) download
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Fetched {
+    /// The file has been saved, and these are its new validators
+    Downloaded(Validators),
+    /// The cached copy is still current (HTTP 304), nothing was saved
+    NotModified,
+}
+
+/// Something which can download a URL to a file
+pub trait Transport {
+    /// Download `url` to `dest`. If `validators` is given, the download should be conditional
+    /// (`If-None-Match`/`If-Modified-Since`), returning [`Fetched::NotModified`] if the file
+    /// hasn't changed.
+    fn download(&self, url: &str, validators: Option<&Validators>, dest: &Path) -> Result<Fetched>;
+}
+
+/// The `User-Agent` sent by [`Curl`], unless it's changed with [`Curl::user_agent`]
+pub const DEFAULT_USER_AGENT: &str = concat!("osmio/", env!("CARGO_PKG_VERSION"));
+
+/// Downloads with the `curl` command line program, which must be installed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Curl {
+    user_agent: String,
+}
+
+impl Curl {
+    /// Run `curl`, sending the [`DEFAULT_USER_AGENT`]
+    pub fn new() -> Self {
+        Curl {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+        }
+    }
+
+    /// Send this `User-Agent` header instead. Some servers (e.g. the OSM API) ask programs to
+    /// identify themselves with it.
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// `curl`, with the options used for every request
+    pub(crate) fn command(&self) -> Command {
+        let mut cmd = Command::new("curl");
+        cmd.args(["--silent", "--show-error", "--location"])
+            .arg("--user-agent")
+            .arg(&self.user_agent);
+        cmd
+    }
+}
+
+impl Default for Curl {
+    fn default() -> Self {
+        Curl::new()
+    }
+}
+
+impl Transport for Curl {
+    fn download(&self, url: &str, validators: Option<&Validators>, dest: &Path) -> Result<Fetched> {
+        let headers_file = dest.with_extension("headers");
+        let mut cmd = self.command();
+        cmd.arg("--fail")
+            .arg("--output")
+            .arg(dest)
+            .arg("--dump-header")
+            .arg(&headers_file)
+            .args(["--write-out", "%{http_code}"]);
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                cmd.arg("--header").arg(format!("If-None-Match: {}", etag));
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                cmd.arg("--header")
+                    .arg(format!("If-Modified-Since: {}", last_modified));
+            }
+        }
+        // `--`, so a URL starting with `-` can't be read as an option
+        let output = cmd
+            .arg("--")
+            .arg(url)
+            .output()
+            .context("Unable to run curl")?;
+        let headers = fs::read_to_string(&headers_file).unwrap_or_default();
+        let _ = fs::remove_file(&headers_file);
+        if !output.status.success() {
+            anyhow::bail!(
+                "Unable to download {}: {}",
+                url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        match String::from_utf8_lossy(&output.stdout).trim() {
+            "304" => Ok(Fetched::NotModified),
+            _ => Ok(Fetched::Downloaded(parse_validators(&headers))),
+        }
+    }
+}
+
+/// The validators from raw HTTP response headers. If there were redirects, there are several
+/// responses, and only the last one is used.
+fn parse_validators(headers: &str) -> Validators {
+    let mut validators = Validators::default();
+    for line in headers.lines() {
+        if line.starts_with("HTTP/") {
+            validators = Validators::default();
+        } else if let Some((name, value)) = line.split_once(':') {
+            let value = Some(value.trim().to_string());
+            if name.eq_ignore_ascii_case("etag") {
+                validators.etag = value;
+            } else if name.eq_ignore_ascii_case("last-modified") {
+                validators.last_modified = value;
+            }
+        }
+    }
+    validators
+}
+
+/// The cache directory used by [`fetch`]: `$OSMIO_CACHE_DIR`, or `osmio` in `$XDG_CACHE_HOME`
+/// (or `~/.cache`), or the temporary directory.
+pub fn default_cache_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("OSMIO_CACHE_DIR") {
+        return dir.into();
+    }
+    std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("osmio")
+}
+
+/// Downloads files into a cache directory. See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Downloader<T> {
+    cache_dir: PathBuf,
+    transport: T,
+}
+
+impl Downloader<Curl> {
+    /// Cache files in `cache_dir` (which is created if needed), downloading with `curl`
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        Downloader {
+            cache_dir: cache_dir.into(),
+            transport: Curl::new(),
+        }
+    }
+}
+
+impl<T: Transport> Downloader<T> {
+    /// Download with this transport instead
+    pub fn transport<T2: Transport>(self, transport: T2) -> Downloader<T2> {
+        Downloader {
+            cache_dir: self.cache_dir,
+            transport,
+        }
+    }
+
+    /// The cache directory
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// Where `url` is (or would be) cached. The filename is the SHA-256 of the URL (in hex), so
+    /// different URLs never share a file, then `-` & the last part of the URL's path, with
+    /// anything other than letters, digits, `.` & `-` replaced by `_`.
+    pub fn cached_path(&self, url: &str) -> PathBuf {
+        let hash: String = Sha256::digest(url.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let path = url.split(['?', '#']).next().unwrap_or("");
+        let name: String = path
+            .rsplit('/')
+            .next()
+            .unwrap_or("")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        if name.is_empty() {
+            self.cache_dir.join(hash)
+        } else {
+            self.cache_dir.join(format!("{}-{}", hash, name))
+        }
+    }
+
+    fn validators_path(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap().to_os_string();
+        name.push(".validators");
+        path.with_file_name(name)
+    }
+
+    fn read_validators(path: &Path) -> Option<Validators> {
+        let contents = fs::read_to_string(Self::validators_path(path)).ok()?;
+        let mut validators = Validators::default();
+        for line in contents.lines() {
+            match line.split_once(": ") {
+                Some(("etag", v)) => validators.etag = Some(v.to_string()),
+                Some(("last-modified", v)) => validators.last_modified = Some(v.to_string()),
+                _ => {}
+            }
+        }
+        Some(validators)
+    }
+
+    fn write_validators(path: &Path, validators: &Validators) -> Result<()> {
+        let mut contents = String::new();
+        if let Some(etag) = &validators.etag {
+            contents.push_str(&format!("etag: {}\n", etag));
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            contents.push_str(&format!("last-modified: {}\n", last_modified));
+        }
+        // Written to a temporary file & renamed, so the validators are never partly written
+        let validators_path = Self::validators_path(path);
+        let mut part = validators_path.clone().into_os_string();
+        part.push(".part");
+        fs::write(&part, contents)?;
+        fs::rename(&part, &validators_path)?;
+        Ok(())
+    }
+
+    /// Download `url` (unless the cached copy is still current), and return the path of the
+    /// cached file.
+    ///
+    /// Files are downloaded to a temporary file, and then renamed, so a failed download never
+    /// replaces a good cached copy. A cached file without any validators is downloaded again,
+    /// and the old validators are removed before the file is replaced, so if the new ones are
+    /// never written (e.g. the process is killed), the file isn't used with the wrong ones.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip(self)))]
+    pub fn fetch_path(&self, url: &str) -> Result<PathBuf> {
+        fs::create_dir_all(&self.cache_dir)
+            .with_context(|| format!("Unable to create cache dir {}", self.cache_dir.display()))?;
+        let path = self.cached_path(url);
+        let validators = if path.exists() {
+            Self::read_validators(&path).filter(|v| v.etag.is_some() || v.last_modified.is_some())
+        } else {
+            None
+        };
+
+        let mut part = path.clone().into_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        let fetched = self.transport.download(url, validators.as_ref(), &part);
+        let fetched = match fetched {
+            Ok(fetched) => fetched,
+            Err(e) => {
+                let _ = fs::remove_file(&part);
+                return Err(e);
+            }
+        };
+        match fetched {
+            Fetched::NotModified => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!("Cached copy is current");
+                let _ = fs::remove_file(&part);
+            }
+            Fetched::Downloaded(validators) => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!("Downloaded");
+                match fs::remove_file(Self::validators_path(&path)) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+                fs::rename(&part, &path)?;
+                Self::write_validators(&path, &validators)?;
+            }
+        }
+        Ok(path)
+    }
+
+    /// Download `url` (if needed), and open it with [`AnyReader`]
+    pub fn fetch(&self, url: &str) -> Result<AnyReader> {
+        AnyReader::from_filename(self.fetch_path(url)?)
+    }
+}
+
+/// Download `url` into the [`default_cache_dir`] (or reuse the cached copy if it's still
+/// current), and open it with [`AnyReader`].
+///
+/// Use [`Downloader::fetch_path`] for files which `AnyReader` can't read, like replication diffs.
+pub fn fetch(url: &str) -> Result<AnyReader> {
+    Downloader::new(default_cache_dir()).fetch(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use OSMReader;
+
+    /// Serves `body`, with etag `"v1"`, and records the validators it was given
+    struct Fake {
+        body: &'static str,
+        requests: RefCell<Vec<Option<Validators>>>,
+    }
+
+    impl Transport for &Fake {
+        fn download(
+            &self,
+            _url: &str,
+            validators: Option<&Validators>,
+            dest: &Path,
+        ) -> Result<Fetched> {
+            self.requests.borrow_mut().push(validators.cloned());
+            if validators.and_then(|v| v.etag.as_deref()) == Some("\"v1\"") {
+                return Ok(Fetched::NotModified);
+            }
+            fs::write(dest, self.body)?;
+            Ok(Fetched::Downloaded(Validators {
+                etag: Some("\"v1\"".to_string()),
+                last_modified: None,
+            }))
+        }
+    }
+
+    #[test]
+    fn cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let fake = Fake {
+            body: r#"<osm version="0.6"><node id="1" lat="1" lon="1"/></osm>"#,
+            requests: RefCell::new(Vec::new()),
+        };
+        let downloader = Downloader::new(dir.path()).transport(&fake);
+        let url = "https://example.com/a.osm";
+
+        let path = downloader.fetch_path(url).unwrap();
+        assert_eq!(path, downloader.cached_path(url));
+        assert_eq!(downloader.fetch(url).unwrap().objects().count(), 1);
+        assert_eq!(
+            fs::read_to_string(dir.path().join(format!(
+                "{}.validators",
+                path.file_name().unwrap().to_str().unwrap()
+            )))
+            .unwrap(),
+            "etag: \"v1\"\n"
+        );
+
+        let v1 = Validators {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        };
+        assert_eq!(*fake.requests.borrow(), vec![None, Some(v1)]);
+        let files: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|f| f.unwrap().file_name())
+            .collect();
+        assert_eq!(files.len(), 2);
+        assert!(files
+            .iter()
+            .all(|f| !f.to_str().unwrap().ends_with(".part")));
+    }
+
+    #[test]
+    fn cached_paths() {
+        let downloader = Downloader::new("cache");
+        let path = |url| {
+            let path = downloader.cached_path(url);
+            assert_eq!(path.parent(), Some(Path::new("cache")));
+            path.file_name().unwrap().to_str().unwrap().to_string()
+        };
+        let name = path("https://download.geofabrik.de/europe/monaco-latest.osm.pbf?v=1");
+        assert_eq!(name.len(), 64 + "-monaco-latest.osm.pbf".len());
+        assert!(name.ends_with("-monaco-latest.osm.pbf"));
+        assert!(name[..64].chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(path("https://example.com/").len(), 64);
+        // These were the same file when the whole URL was the name
+        assert_ne!(
+            path("https://example.com/a_b"),
+            path("https://example.com/a/b")
+        );
+        assert_ne!(
+            path("https://example.com/x/a.osm"),
+            path("https://example.com/y/a.osm")
+        );
+    }
+
+    #[test]
+    fn user_agent() {
+        let args = |curl: Curl| -> Vec<String> {
+            let cmd = curl.command();
+            cmd.get_args()
+                .map(|a| a.to_str().unwrap().to_string())
+                .collect()
+        };
+        let default = args(Curl::new());
+        let i = default.iter().position(|a| a == "--user-agent").unwrap();
+        assert!(default[i + 1].starts_with("osmio/"));
+        assert_eq!(default[i + 1], DEFAULT_USER_AGENT);
+        assert!(args(Curl::new().user_agent("my-bot/1.0")).contains(&"my-bot/1.0".to_string()));
+    }
+
+    #[test]
+    fn headers() {
+        let headers = "HTTP/1.1 302 Found\r\nETag: \"old\"\r\n\r\nHTTP/2 200\r\netag: \"abc\"\r\nLast-Modified: Wed, 21 Oct 2015 07:28:00 GMT\r\n\r\n";
+        assert_eq!(
+            parse_validators(headers),
+            Validators {
+                etag: Some("\"abc\"".to_string()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            }
+        );
+    }
+}
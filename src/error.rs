@@ -0,0 +1,103 @@
+//! The error type for reading OSM data.
+use cancel::Cancelled;
+use parse_mode::Anomaly;
+use std::io;
+use {OSMObjectType, ObjId};
+
+/// An error while reading OSM data, returned by [`OSMReader::try_next`](crate::OSMReader::try_next)
+/// & [`OSMReader::try_objects`](crate::OSMReader::try_objects).
+///
+/// Unlike an `anyhow::Error`, this can be matched on, e.g. to skip invalid objects but stop on IO
+/// errors. Offsets are the byte offset (in the underlying reader) of the PBF blob which caused
+/// the error.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading from (or writing to) the underlying reader failed
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    /// A PBF blob or block isn't a valid protobuf message
+    #[error("Invalid protobuf message at byte {offset}: {source}")]
+    Protobuf {
+        offset: u64,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// The XML is invalid
+    #[error("Invalid XML: {0}")]
+    Xml(#[from] quick_xml::Error),
+
+    /// A PBF blob couldn't be decompressed
+    #[error("Unable to decompress blob at byte {offset}: {source}")]
+    Decompression {
+        offset: u64,
+        #[source]
+        source: io::Error,
+    },
+
+    /// The data is well formed, but not valid OSM data (e.g. the file ends in the middle of a blob)
+    #[error(
+        "Invalid file{}: {reason}",
+        offset.map(|o| format!(" at byte {}", o)).unwrap_or_default()
+    )]
+    Format { offset: Option<u64>, reason: String },
+
+    /// An object is invalid
+    #[error("Invalid {object_type} {id}: {reason}")]
+    InvalidObject {
+        object_type: OSMObjectType,
+        id: ObjId,
+        reason: String,
+    },
+
+    /// Invalid data, which is only an error with [`ParseMode::Strict`](crate::parse_mode::ParseMode::Strict)
+    #[error("{0}")]
+    Anomaly(Anomaly),
+
+    /// An object couldn't be built (e.g. with a
+    /// [`StringNodeBuilder`](crate::obj_types::StringNodeBuilder)), because a required field is
+    /// missing
+    #[error("Unable to build: {0}")]
+    Builder(String),
+
+    /// Reading was stopped with a [`CancellationToken`](crate::cancel::CancellationToken)
+    #[error("Cancelled")]
+    Cancelled(#[from] Cancelled),
+}
+
+impl Error {
+    /// An error from reading part of a PBF blob. If the reader ended, the file is truncated.
+    pub(crate) fn reading(err: io::Error, offset: u64, what: &str) -> Error {
+        if err.kind() == io::ErrorKind::UnexpectedEof {
+            Error::Format {
+                offset: Some(offset),
+                reason: format!("File ends in the middle of the {}", what),
+            }
+        } else {
+            Error::Io(err)
+        }
+    }
+
+    pub(crate) fn protobuf(
+        offset: u64,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Error {
+        Error::Protobuf {
+            offset,
+            source: Box::new(source),
+        }
+    }
+
+    /// True iff this is because reading was cancelled
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, Error::Cancelled(_))
+    }
+}
+
+impl From<derive_builder::UninitializedFieldError> for Error {
+    fn from(err: derive_builder::UninitializedFieldError) -> Self {
+        Error::Builder(format!("{} is required", err.field_name()))
+    }
+}
@@ -0,0 +1,253 @@
+//! `osmium extract` style config files, to create many extracts in one pass.
+//!
+//! The config is JSON, with an optional output `directory`, and a list of `extracts`. Each extract
+//! has an `output` filename and a region: a `bbox` (`[left, bottom, right, top]`, or an object
+//! with those keys), or a `polygon`/`multipolygon`. Polygons are either a `.poly` file
+//! (`{"file_name": "region.poly", "file_type": "poly"}`), or GeoJSON style `[lon, lat]`
+//! coordinates, where the first ring of a polygon is the outer ring, and any others are holes.
+//!
+//! ```json
+//! {
+//!     "directory": "extracts",
+//!     "extracts": [
+//!         {"output": "dublin.osm.gz", "bbox": [-6.4, 53.2, -6.0, 53.5]},
+//!         {"output": "ireland.osm", "polygon": {"file_name": "ireland.poly", "file_type": "poly"}},
+//!         {"output": "square.osm", "polygon": [[[0, 0], [0, 1], [1, 1], [1, 0], [0, 0]]]}
+//!     ]
+//! }
+//! ```
+use super::poly::Poly;
+use super::{BBox, Region};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use {Lat, Lon};
+
+use anyhow::{bail, Context, Result};
+
+/// A config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ExtractConfig {
+    /// Output filenames are relative to this directory (or the current directory)
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    pub extracts: Vec<ExtractConfigEntry>,
+}
+
+/// One extract in a config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ExtractConfigEntry {
+    pub output: PathBuf,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub bbox: Option<BBoxConfig>,
+    #[serde(default)]
+    pub polygon: Option<PolygonConfig>,
+    #[serde(default)]
+    pub multipolygon: Option<PolygonConfig>,
+}
+
+/// A bbox, as `[left, bottom, right, top]`, or an object
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum BBoxConfig {
+    Array([f64; 4]),
+    Object {
+        left: f64,
+        bottom: f64,
+        right: f64,
+        top: f64,
+    },
+}
+
+/// A polygon or multipolygon, from a file or with `[lon, lat]` coordinates
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum PolygonConfig {
+    File {
+        file_name: PathBuf,
+        #[serde(default)]
+        file_type: Option<String>,
+    },
+    /// One polygon: an outer ring, then any holes
+    Polygon(Vec<Vec<[f64; 2]>>),
+    /// Several polygons
+    MultiPolygon(Vec<Vec<Vec<[f64; 2]>>>),
+}
+
+impl ExtractConfig {
+    /// Read a config file
+    pub fn from_filename(filename: impl AsRef<Path>) -> Result<Self> {
+        let filename = filename.as_ref();
+        let file = File::open(filename)
+            .with_context(|| format!("Can't open config file {}", filename.display()))?;
+        serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Invalid config file {}", filename.display()))
+    }
+
+    /// Where the output of this extract is written
+    pub fn output(&self, entry: &ExtractConfigEntry) -> PathBuf {
+        match &self.directory {
+            Some(directory) => directory.join(&entry.output),
+            None => entry.output.clone(),
+        }
+    }
+}
+
+impl ExtractConfigEntry {
+    /// The region of this extract. Polygon files are relative to `base_dir`, which should be
+    /// the directory of the config file.
+    pub fn region(&self, base_dir: &Path) -> Result<Box<dyn Region>> {
+        let region: Box<dyn Region> = match (&self.bbox, &self.polygon, &self.multipolygon) {
+            (Some(bbox), None, None) => Box::new(bbox.to_bbox()?),
+            (None, Some(polygon), None) | (None, None, Some(polygon)) => {
+                Box::new(polygon.to_poly(base_dir)?)
+            }
+            (None, None, None) => bail!(
+                "Extract {} has no bbox, polygon or multipolygon",
+                self.output.display()
+            ),
+            _ => bail!(
+                "Extract {} has more than one bbox, polygon or multipolygon",
+                self.output.display()
+            ),
+        };
+        Ok(region)
+    }
+}
+
+impl BBoxConfig {
+    pub fn to_bbox(&self) -> Result<BBox> {
+        let (left, bottom, right, top) = match *self {
+            BBoxConfig::Array([left, bottom, right, top]) => (left, bottom, right, top),
+            BBoxConfig::Object {
+                left,
+                bottom,
+                right,
+                top,
+            } => (left, bottom, right, top),
+        };
+        Ok(BBox::new(
+            Lat::try_from(bottom)?,
+            Lon::try_from(left)?,
+            Lat::try_from(top)?,
+            Lon::try_from(right)?,
+        ))
+    }
+}
+
+/// A closed `(lat, lon)` ring, from `[lon, lat]` coordinates
+fn ring(coords: &[[f64; 2]]) -> Vec<(f64, f64)> {
+    let mut ring: Vec<(f64, f64)> = coords.iter().map(|&[lon, lat]| (lat, lon)).collect();
+    if ring.first() != ring.last() {
+        ring.push(ring[0]);
+    }
+    ring
+}
+
+impl PolygonConfig {
+    pub fn to_poly(&self, base_dir: &Path) -> Result<Poly> {
+        let polygons = match self {
+            PolygonConfig::File {
+                file_name,
+                file_type,
+            } => {
+                let is_poly = match file_type {
+                    Some(file_type) => file_type == "poly",
+                    None => file_name.extension().is_some_and(|e| e == "poly"),
+                };
+                if !is_poly {
+                    bail!(
+                        "Only .poly files are supported, not {}",
+                        file_name.display()
+                    );
+                }
+                let path = base_dir.join(file_name);
+                return Poly::from_filename(&path)
+                    .with_context(|| format!("Can't read {}", path.display()));
+            }
+            PolygonConfig::Polygon(polygon) => std::slice::from_ref(polygon),
+            PolygonConfig::MultiPolygon(polygons) => polygons.as_slice(),
+        };
+        let mut poly = Poly {
+            name: String::new(),
+            outers: Vec::new(),
+            holes: Vec::new(),
+        };
+        for polygon in polygons {
+            for (i, coords) in polygon.iter().enumerate() {
+                if coords.len() < 3 {
+                    bail!("A polygon ring has {} points, it needs 3", coords.len());
+                }
+                if i == 0 {
+                    poly.outers.push(ring(coords));
+                } else {
+                    poly.holes.push(ring(coords));
+                }
+            }
+        }
+        if poly.outers.is_empty() {
+            bail!("The polygon is empty");
+        }
+        Ok(poly)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(lon: f64, lat: f64) -> (Lat, Lon) {
+        (Lat::try_from(lat).unwrap(), Lon::try_from(lon).unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        let config: ExtractConfig = serde_json::from_str(
+            r#"{
+                "directory": "out",
+                "extracts": [
+                    {"output": "a.osm", "bbox": [0, 0, 1, 1]},
+                    {"output": "b.osm", "bbox": {"left": 0, "bottom": 0, "right": 1, "top": 1}},
+                    {"output": "c.osm", "polygon": [[[0, 0], [0, 10], [10, 10], [10, 0]], [[4, 4], [4, 6], [6, 6], [6, 4]]]},
+                    {"output": "d.osm", "multipolygon": [[[[0, 0], [0, 1], [1, 1]]], [[[5, 5], [5, 6], [6, 6]]]]},
+                    {"output": "e.osm", "polygon": {"file_name": "e.geojson", "file_type": "geojson"}},
+                    {"output": "f.osm"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.output(&config.extracts[0]), Path::new("out/a.osm"));
+        let regions = config
+            .extracts
+            .iter()
+            .map(|e| e.region(Path::new(".")))
+            .collect::<Vec<_>>();
+
+        let a = regions[0].as_ref().unwrap();
+        assert!(a.contains(loc(0.5, 0.5)));
+        assert!(!a.contains(loc(1.5, 0.5)));
+        assert_eq!(
+            config.extracts[0].bbox,
+            Some(BBoxConfig::Array([0., 0., 1., 1.]))
+        );
+        let b = regions[1].as_ref().unwrap();
+        assert!(b.contains(loc(0.5, 0.5)));
+
+        let c = regions[2].as_ref().unwrap();
+        assert!(c.contains(loc(1., 1.)));
+        assert!(!c.contains(loc(5., 5.)));
+        assert!(!c.contains(loc(11., 5.)));
+
+        let d = regions[3].as_ref().unwrap();
+        assert!(d.contains(loc(0.2, 0.5)));
+        assert!(d.contains(loc(5.2, 5.5)));
+        assert!(!d.contains(loc(3., 3.)));
+
+        assert!(regions[4].is_err());
+        assert!(regions[5].is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! Extracting the part of a file inside a region.
+//!
+//! Just keeping the nodes inside the region would produce ways with missing nodes, so the
+//! [`ExtractStrategy`] controls which other objects are included, as with `osmium extract`.
+//!
+//! ```no_run
+//! use osmio::extract::{extract, poly::Poly, ExtractStrategy};
+//! use osmio::prelude::*;
+//! use osmio::xml::XMLWriter;
+//! use osmio::OSMWriter;
+//!
+//! let poly = Poly::from_filename("region.poly")?;
+//! let mut writer = XMLWriter::new(std::fs::File::create("region.osm")?);
+//! extract(
+//!     || osmio::read_pbf("planet.osm.pbf"),
+//!     &poly,
+//!     ExtractStrategy::CompleteWays,
+//!     &mut writer,
+//! )?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use {
+    Lat, Lon, Node, OSMObj, OSMObjBase, OSMObjectType, OSMReader, OSMWriter, ObjId, Relation, Way,
+};
+
+pub use bbox::{BBox, ParseBBoxError};
+
+use anyhow::Result;
+
+pub mod config;
+pub mod poly;
+pub mod split;
+pub mod update;
+
+/// An area on the earth
+pub trait Region {
+    /// True iff this location is inside the region
+    fn contains(&self, loc: (Lat, Lon)) -> bool;
+}
+
+impl Region for BBox {
+    fn contains(&self, loc: (Lat, Lon)) -> bool {
+        BBox::contains(self, loc)
+    }
+}
+
+/// Which objects are included in an extract
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExtractStrategy {
+    /// Nodes inside the region, ways with at least one node inside, and relations with at least
+    /// one included member. Ways can refer to nodes which aren't included.
+    Simple,
+    /// Like `Simple`, but every node of an included way is also included, so all ways are
+    /// complete.
+    #[default]
+    CompleteWays,
+    /// Like `CompleteWays`, but multipolygon relations with at least one included member have all
+    /// their member ways (and those ways' nodes) included, so they can be assembled into areas.
+    Smart,
+}
+
+/// Write all objects from a file which are inside `region` to `writer`.
+///
+/// The file needs to be read more than once, so `open` is a function which opens a new reader
+/// for the file each time it's called. The file must be sorted (all nodes, then all ways, then
+/// all relations), as most files are.
+///
+/// Parent relations of included relations are included (only one level). The `writer` isn't
+/// closed.
+pub fn extract<F, R, W>(
+    open: F,
+    region: &impl Region,
+    strategy: ExtractStrategy,
+    writer: &mut impl OSMWriter<W>,
+) -> Result<()>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+    W: Write,
+{
+    extract_many(open, strategy, &mut [(region as &dyn Region, writer)])
+}
+
+/// Which objects are in one extract, calculated while reading the file
+#[derive(Default)]
+struct Included {
+    nodes_inside: HashSet<ObjId>,
+    /// Nodes outside the region which are needed for complete ways
+    extra_nodes: HashSet<ObjId>,
+    ways: HashSet<ObjId>,
+    relations: HashSet<ObjId>,
+    /// Relations with relation members, which might need to be included once all relations are
+    /// known.
+    possible_parents: HashMap<ObjId, Vec<ObjId>>,
+    /// Ways which are needed (Smart strategy) but weren't included in the first pass
+    extra_ways: HashSet<ObjId>,
+}
+
+impl Included {
+    fn add(&mut self, obj: &impl OSMObj, region: &dyn Region, strategy: ExtractStrategy) {
+        if let Some(node) = obj.as_node() {
+            if node.lat_lon().is_some_and(|loc| region.contains(loc)) {
+                self.nodes_inside.insert(node.id());
+            }
+        } else if let Some(way) = obj.as_way() {
+            if way
+                .nodes()
+                .iter()
+                .any(|nid| self.nodes_inside.contains(nid))
+            {
+                self.ways.insert(way.id());
+                if strategy != ExtractStrategy::Simple {
+                    self.extra_nodes.extend(way.nodes().iter().copied());
+                }
+            }
+        } else if let Some(relation) = obj.as_relation() {
+            let mut child_relations = Vec::new();
+            let mut included = false;
+            for (member_type, member_id, _role) in relation.members() {
+                included |= match member_type {
+                    OSMObjectType::Node => self.nodes_inside.contains(&member_id),
+                    OSMObjectType::Way => self.ways.contains(&member_id),
+                    OSMObjectType::Relation => {
+                        child_relations.push(member_id);
+                        self.relations.contains(&member_id)
+                    }
+                };
+            }
+            if included {
+                self.relations.insert(relation.id());
+                if strategy == ExtractStrategy::Smart
+                    && relation.tag("type") == Some("multipolygon")
+                {
+                    let ways = &self.ways;
+                    self.extra_ways.extend(
+                        relation
+                            .members()
+                            .filter(|(t, id, _)| *t == OSMObjectType::Way && !ways.contains(id))
+                            .map(|(_, id, _)| id),
+                    );
+                }
+            } else if !child_relations.is_empty() {
+                self.possible_parents.insert(relation.id(), child_relations);
+            }
+        }
+    }
+
+    fn add_parents(&mut self) {
+        for (relation_id, children) in self.possible_parents.iter() {
+            if children.iter().any(|c| self.relations.contains(c)) {
+                self.relations.insert(*relation_id);
+            }
+        }
+    }
+
+    fn add_extra_way(&mut self, way: &impl Way) {
+        if self.extra_ways.contains(&way.id()) {
+            self.ways.insert(way.id());
+            self.extra_nodes.extend(way.nodes().iter().copied());
+        }
+    }
+
+    fn contains(&self, obj: &impl OSMObj) -> bool {
+        match obj.object_type() {
+            OSMObjectType::Node => {
+                self.nodes_inside.contains(&obj.id()) || self.extra_nodes.contains(&obj.id())
+            }
+            OSMObjectType::Way => self.ways.contains(&obj.id()),
+            OSMObjectType::Relation => self.relations.contains(&obj.id()),
+        }
+    }
+}
+
+/// Extract several regions from a file at once, writing each to its own writer.
+///
+/// This is like calling [`extract`] for each region, but the file is only read as many times as
+/// for one region. The writers aren't closed.
+pub fn extract_many<F, R, W, Wr>(
+    mut open: F,
+    strategy: ExtractStrategy,
+    extracts: &mut [(&dyn Region, &mut Wr)],
+) -> Result<()>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+    W: Write,
+    Wr: OSMWriter<W>,
+{
+    let mut included = extracts
+        .iter()
+        .map(|_| Included::default())
+        .collect::<Vec<_>>();
+
+    for obj in open()?.try_objects() {
+        let obj = obj?;
+        for (included, (region, _)) in included.iter_mut().zip(extracts.iter()) {
+            included.add(&obj, *region, strategy);
+        }
+    }
+
+    for included in included.iter_mut() {
+        included.add_parents();
+    }
+
+    if included.iter().any(|i| !i.extra_ways.is_empty()) {
+        let mut reader = open()?;
+        let sorted = reader.get_sorted_assumption();
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            if let Some(way) = obj.as_way() {
+                for included in included.iter_mut() {
+                    included.add_extra_way(way);
+                }
+            } else if sorted && obj.object_type() == OSMObjectType::Relation {
+                break;
+            }
+        }
+    }
+
+    for obj in open()?.try_objects() {
+        let obj = obj?;
+        for (included, (_, writer)) in included.iter().zip(extracts.iter_mut()) {
+            if included.contains(&obj) {
+                writer.write_obj(&obj)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write all objects from a file which are inside `bbox` to `writer`.
+///
+/// See [`extract`] for the details, this is the same with a [`BBox`] as the region.
+pub fn extract_bbox<F, R, W>(
+    open_reader: F,
+    bbox: &BBox,
+    strategy: ExtractStrategy,
+    writer: &mut impl OSMWriter<W>,
+) -> Result<()>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+    W: Write,
+{
+    extract(open_reader, bbox, strategy, writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use xml::{XMLReader, XMLWriter};
+
+    /// Nodes 1 & 2 are inside. Way 11 is partly inside, and way 12 is outside, but both are in a
+    /// multipolygon, which has a parent relation.
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="5"/>
+<node id="3" version="1" lat="1" lon="9"/>
+<node id="4" version="1" lat="9" lon="9"/>
+<way id="10" version="1"><nd ref="1"/><nd ref="2"/></way>
+<way id="11" version="1"><nd ref="2"/><nd ref="3"/></way>
+<way id="12" version="1"><nd ref="3"/><nd ref="4"/></way>
+<relation id="20" version="1"><member type="way" ref="11" role="outer"/><member type="way" ref="12" role="outer"/><tag k="type" v="multipolygon"/></relation>
+<relation id="21" version="1"><member type="node" ref="4" role=""/></relation>
+<relation id="22" version="1"><member type="relation" ref="20" role=""/></relation>
+</osm>"#;
+
+    /// Everything with a longitude less than 6
+    struct West;
+    impl Region for West {
+        fn contains(&self, loc: (Lat, Lon)) -> bool {
+            f64::from(loc.1) < 6.
+        }
+    }
+
+    fn run(strategy: ExtractStrategy) -> Vec<String> {
+        run_region(&West, strategy)
+    }
+
+    fn run_region(region: &impl Region, strategy: ExtractStrategy) -> Vec<String> {
+        let mut output = Vec::new();
+        {
+            let mut writer = XMLWriter::new(&mut output);
+            extract(
+                || Ok(XMLReader::new(Cursor::new(INPUT))),
+                region,
+                strategy,
+                &mut writer,
+            )
+            .unwrap();
+        }
+        XMLReader::new(Cursor::new(output))
+            .objects()
+            .map(|o| format!("{}{}", o.object_type().name_short(), o.id()))
+            .collect()
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(
+            run(ExtractStrategy::Simple),
+            vec!["n1", "n2", "w10", "w11", "r20", "r22"]
+        );
+    }
+
+    #[test]
+    fn complete_ways() {
+        assert_eq!(
+            run(ExtractStrategy::CompleteWays),
+            vec!["n1", "n2", "n3", "w10", "w11", "r20", "r22"]
+        );
+    }
+
+    #[test]
+    fn smart() {
+        assert_eq!(
+            run(ExtractStrategy::Smart),
+            vec!["n1", "n2", "n3", "n4", "w10", "w11", "w12", "r20", "r22"]
+        );
+    }
+
+    #[test]
+    fn bbox() {
+        let bbox: BBox = "0,0,6,2".parse().unwrap();
+        assert_eq!(
+            run_region(&bbox, ExtractStrategy::CompleteWays),
+            run(ExtractStrategy::CompleteWays)
+        );
+    }
+
+    #[test]
+    fn many() {
+        let north: BBox = "0,8,10,10".parse().unwrap();
+        let (mut west_output, mut north_output) = (Vec::new(), Vec::new());
+        {
+            let mut west_writer = XMLWriter::new(&mut west_output);
+            let mut north_writer = XMLWriter::new(&mut north_output);
+            extract_many(
+                || Ok(XMLReader::new(Cursor::new(INPUT))),
+                ExtractStrategy::Smart,
+                &mut [(&West, &mut west_writer), (&north, &mut north_writer)],
+            )
+            .unwrap();
+        }
+        let ids = |output: Vec<u8>| {
+            XMLReader::new(Cursor::new(output))
+                .objects()
+                .map(|o| format!("{}{}", o.object_type().name_short(), o.id()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(west_output), run(ExtractStrategy::Smart));
+        assert_eq!(
+            ids(north_output),
+            run_region(&north, ExtractStrategy::Smart)
+        );
+        assert_eq!(
+            run_region(&north, ExtractStrategy::Smart),
+            vec!["n2", "n3", "n4", "w11", "w12", "r20", "r21", "r22"]
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let input = r#"<osm version="0.6"><node id="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        let mut output = Vec::new();
+        let mut writer = XMLWriter::new(&mut output);
+        assert!(extract(
+            || Ok(XMLReader::new(input.as_bytes())),
+            &West,
+            ExtractStrategy::Smart,
+            &mut writer,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! The osmosis `.poly` polygon file format.
+//!
+//! See <https://wiki.openstreetmap.org/wiki/Osmosis/Polygon_Filter_File_Format>. A file has a
+//! name on the first line, then one or more sections, each with a name, a list of `lon lat`
+//! coordinates, and `END`. Sections whose name starts with `!` are holes. The file ends with
+//! `END`.
+//!
+//! ```rust
+//! use osmio::extract::{poly::Poly, Region};
+//! use osmio::{Lat, Lon};
+//! use std::convert::TryFrom;
+//!
+//! let poly: Poly = "square\n1\n 0 0\n 0 10\n 10 10\n 10 0\nEND\nEND\n".parse()?;
+//! assert!(poly.contains((Lat::try_from(5.)?, Lon::try_from(5.)?)));
+//! assert!(!poly.contains((Lat::try_from(15.)?, Lon::try_from(5.)?)));
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use super::Region;
+use geometry::point_in_ring;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use {Lat, Lon};
+
+/// A polygon read from a `.poly` file.
+///
+/// A location is inside if it's in any of the `outers`, and not in any of the `holes`. Rings are
+/// `(lat, lon)` pairs, and are always closed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Poly {
+    pub name: String,
+    pub outers: Vec<Vec<(f64, f64)>>,
+    pub holes: Vec<Vec<(f64, f64)>>,
+}
+
+/// An error while parsing a `.poly` file
+#[derive(Debug)]
+pub enum ParsePolyError {
+    /// Error reading the file
+    IO(io::Error),
+    /// This line (1-based line number) could not be parsed
+    InvalidLine(usize, String),
+    /// The file ended before the final `END`
+    UnexpectedEnd,
+}
+
+impl std::fmt::Display for ParsePolyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParsePolyError::IO(e) => write!(f, "Error reading poly file: {}", e),
+            ParsePolyError::InvalidLine(line_no, line) => {
+                write!(f, "Invalid line {} in poly file: {:?}", line_no, line)
+            }
+            ParsePolyError::UnexpectedEnd => write!(f, "Poly file ended before final END"),
+        }
+    }
+}
+
+impl std::error::Error for ParsePolyError {}
+
+impl From<io::Error> for ParsePolyError {
+    fn from(e: io::Error) -> Self {
+        ParsePolyError::IO(e)
+    }
+}
+
+impl Poly {
+    /// Read a `.poly` file
+    pub fn from_filename(filename: impl AsRef<Path>) -> Result<Self, ParsePolyError> {
+        Self::from_reader(File::open(filename)?)
+    }
+
+    /// Read a `.poly` file from this reader
+    pub fn from_reader(reader: impl Read) -> Result<Self, ParsePolyError> {
+        let mut lines = BufReader::new(reader)
+            .lines()
+            .enumerate()
+            .map(|(i, line)| line.map(|l| (i + 1, l)));
+        let mut next_line = || -> Result<(usize, String), ParsePolyError> {
+            loop {
+                match lines.next() {
+                    None => return Err(ParsePolyError::UnexpectedEnd),
+                    Some(line) => {
+                        let (line_no, line) = line?;
+                        if !line.trim().is_empty() {
+                            return Ok((line_no, line.trim().to_string()));
+                        }
+                    }
+                }
+            }
+        };
+
+        let mut poly = Poly {
+            name: next_line()?.1,
+            outers: Vec::new(),
+            holes: Vec::new(),
+        };
+        loop {
+            let (_, section_name) = next_line()?;
+            if section_name == "END" {
+                break;
+            }
+            let mut ring = Vec::new();
+            loop {
+                let (line_no, line) = next_line()?;
+                if line == "END" {
+                    break;
+                }
+                let invalid = || ParsePolyError::InvalidLine(line_no, line.clone());
+                let mut parts = line.split_whitespace().map(f64::from_str);
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(Ok(lon)), Some(Ok(lat)), None) => ring.push((lat, lon)),
+                    _ => return Err(invalid()),
+                }
+            }
+            if ring.first() != ring.last() {
+                ring.push(ring[0]);
+            }
+            if section_name.starts_with('!') {
+                poly.holes.push(ring);
+            } else {
+                poly.outers.push(ring);
+            }
+        }
+
+        Ok(poly)
+    }
+
+    /// Write this polygon in the `.poly` format
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "{}", self.name)?;
+        let sections = self
+            .outers
+            .iter()
+            .map(|r| (false, r))
+            .chain(self.holes.iter().map(|r| (true, r)));
+        for (i, (is_hole, ring)) in sections.enumerate() {
+            writeln!(writer, "{}{}", if is_hole { "!" } else { "" }, i + 1)?;
+            for (lat, lon) in ring {
+                writeln!(writer, "\t{:E}\t{:E}", lon, lat)?;
+            }
+            writeln!(writer, "END")?;
+        }
+        writeln!(writer, "END")?;
+        Ok(())
+    }
+}
+
+impl FromStr for Poly {
+    type Err = ParsePolyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_reader(s.as_bytes())
+    }
+}
+
+impl Region for Poly {
+    fn contains(&self, loc: (Lat, Lon)) -> bool {
+        let point = (loc.0.into(), loc.1.into());
+        self.outers.iter().any(|r| point_in_ring(point, r))
+            && !self.holes.iter().any(|r| point_in_ring(point, r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    const INPUT: &str = "test area
+first_area
+     0.1E+01   0.1E+01
+     0.1E+01   0.9E+01
+     0.9E+01   0.9E+01
+     0.9E+01   0.1E+01
+END
+!hole
+     4 4
+     4 6
+     6 6
+     6 4
+     4 4
+END
+END
+";
+
+    fn loc(lat: f64, lon: f64) -> (Lat, Lon) {
+        (Lat::try_from(lat).unwrap(), Lon::try_from(lon).unwrap())
+    }
+
+    #[test]
+    fn parse() {
+        let poly: Poly = INPUT.parse().unwrap();
+        assert_eq!(poly.name, "test area");
+        assert_eq!(poly.outers.len(), 1);
+        assert_eq!(poly.outers[0].len(), 5);
+        assert_eq!(poly.holes.len(), 1);
+
+        assert!(poly.contains(loc(2., 2.)));
+        assert!(!poly.contains(loc(5., 5.)));
+        assert!(!poly.contains(loc(0., 0.)));
+        assert!(!poly.contains(loc(10., 5.)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let poly: Poly = INPUT.parse().unwrap();
+        let mut output = Vec::new();
+        poly.write(&mut output).unwrap();
+        let poly2 = Poly::from_reader(output.as_slice()).unwrap();
+        assert_eq!(poly, poly2);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            "name\n1\n1 2\n".parse::<Poly>(),
+            Err(ParsePolyError::UnexpectedEnd)
+        ));
+        assert!(matches!(
+            "name\n1\n1 x\nEND\nEND\n".parse::<Poly>(),
+            Err(ParsePolyError::InvalidLine(3, _))
+        ));
+    }
+}
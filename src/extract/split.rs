@@ -0,0 +1,330 @@
+//! Splitting a file into many parts (e.g. a grid of tiles) in one pass.
+//!
+//! Each part (“cell”) is a [`BBox`]. Nodes are written to every cell they are inside, ways to
+//! every cell which contains at least one of their nodes, and relations to every cell which has
+//! at least one of their members (only one level of relation members is followed). Like
+//! [`ExtractStrategy::Simple`](super::ExtractStrategy::Simple), ways can refer to nodes which
+//! aren't in the same cell.
+//!
+//! Writers are only created (by a function you supply) when the first object for that cell is
+//! found, so empty cells don't produce empty files.
+//!
+//! ```no_run
+//! use osmio::extract::split::Splitter;
+//! use osmio::extract::BBox;
+//! use osmio::node_locations::DenseNodeLocations;
+//! use osmio::xml::XMLWriter;
+//! use osmio::OSMWriter;
+//! use std::fs::File;
+//! use std::io::BufWriter;
+//!
+//! let world: BBox = "-180,-90,180,90".parse()?;
+//! let splitter = Splitter::grid(world, 10, 20);
+//! let mut reader = osmio::read_pbf("planet.osm.pbf")?;
+//! let writers = splitter.split(&mut reader, &mut DenseNodeLocations::new(), |idx, _bbox| {
+//!     Ok(XMLWriter::new(BufWriter::new(File::create(format!("tile-{}.osm", idx))?)))
+//! })?;
+//! for mut writer in writers.into_iter().flatten() {
+//!     writer.close()?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use idset::IdSet;
+use node_locations::NodeLocations;
+use std::io::Write;
+use BBox;
+use {Lat, Lon, Node, OSMObj, OSMObjBase, OSMObjectType, OSMReader, OSMWriter, Relation, Way};
+
+use anyhow::Result;
+
+/// A regular grid, which allows finding the cell for a location without checking every cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Grid {
+    bbox: BBox,
+    rows: usize,
+    cols: usize,
+}
+
+impl Grid {
+    /// Cell index along one axis. Locations on the outer edge go in the last cell.
+    fn axis_idx(value: i32, min: i32, max: i32, num: usize) -> usize {
+        let span = (max as i64 - min as i64).max(1);
+        let idx = (value as i64 - min as i64) * num as i64 / span;
+        (idx as usize).min(num - 1)
+    }
+
+    /// Boundary between cells along one axis
+    fn axis_boundary(i: usize, min: i32, max: i32, num: usize) -> i32 {
+        (min as i64 + (max as i64 - min as i64) * i as i64 / num as i64) as i32
+    }
+
+    fn cell(&self, (lat, lon): (Lat, Lon)) -> Option<usize> {
+        if !self.bbox.contains((lat, lon)) {
+            return None;
+        }
+        let row = Self::axis_idx(
+            lat.inner(),
+            self.bbox.min_lat.inner(),
+            self.bbox.max_lat.inner(),
+            self.rows,
+        );
+        let col = Self::axis_idx(
+            lon.inner(),
+            self.bbox.min_lon.inner(),
+            self.bbox.max_lon.inner(),
+            self.cols,
+        );
+        Some(row * self.cols + col)
+    }
+}
+
+/// Splits objects into cells. See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct Splitter {
+    cells: Vec<BBox>,
+    grid: Option<Grid>,
+}
+
+impl Splitter {
+    /// Split `bbox` into a grid of `rows` × `cols` equal sized cells. Cell `row * cols + col` is
+    /// the cell in that row (counting from the south) & column (counting from the west).
+    ///
+    /// Panics if `rows` or `cols` is 0, or `bbox` crosses the antimeridian.
+    pub fn grid(bbox: BBox, rows: usize, cols: usize) -> Self {
+        assert!(rows > 0 && cols > 0, "Grid needs at least 1 row & column");
+        assert!(
+            bbox.min_lon <= bbox.max_lon,
+            "Grid can't cross the antimeridian"
+        );
+        let (min_lat, max_lat) = (bbox.min_lat.inner(), bbox.max_lat.inner());
+        let (min_lon, max_lon) = (bbox.min_lon.inner(), bbox.max_lon.inner());
+        let mut cells = Vec::with_capacity(rows * cols);
+        for row in 0..rows {
+            for col in 0..cols {
+                cells.push(BBox::new(
+                    Lat::from_inner(Grid::axis_boundary(row, min_lat, max_lat, rows)),
+                    Lon::from_inner(Grid::axis_boundary(col, min_lon, max_lon, cols)),
+                    Lat::from_inner(Grid::axis_boundary(row + 1, min_lat, max_lat, rows)),
+                    Lon::from_inner(Grid::axis_boundary(col + 1, min_lon, max_lon, cols)),
+                ));
+            }
+        }
+        Splitter {
+            cells,
+            grid: Some(Grid { bbox, rows, cols }),
+        }
+    }
+
+    /// Split into these (possibly overlapping) bboxes. Cell `i` is `bboxes[i]`.
+    pub fn bboxes(bboxes: impl Into<Vec<BBox>>) -> Self {
+        Splitter {
+            cells: bboxes.into(),
+            grid: None,
+        }
+    }
+
+    /// The cells
+    pub fn cells(&self) -> &[BBox] {
+        &self.cells
+    }
+
+    /// Add the index of every cell containing this location to `out`
+    fn cells_containing(&self, loc: (Lat, Lon), out: &mut Vec<usize>) {
+        match &self.grid {
+            Some(grid) => out.extend(grid.cell(loc)),
+            None => out.extend(
+                self.cells
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, bbox)| bbox.contains(loc))
+                    .map(|(idx, _)| idx),
+            ),
+        }
+    }
+
+    /// Read every object from `reader`, and write it to the writer for each cell it's in.
+    ///
+    /// The file must be sorted (nodes, then ways, then relations). Node locations are stored in
+    /// `locations` so ways can be placed in cells. `make_writer` is called with the cell index &
+    /// bbox to create the writer for a cell, the first time an object is written to that cell.
+    ///
+    /// Returns the writer for each cell (`None` for cells with no objects). They haven't been
+    /// closed.
+    pub fn split<R, L, W, Wr, F>(
+        &self,
+        reader: &mut R,
+        locations: &mut L,
+        mut make_writer: F,
+    ) -> Result<Vec<Option<Wr>>>
+    where
+        R: OSMReader,
+        L: NodeLocations,
+        W: Write,
+        Wr: OSMWriter<W>,
+        F: FnMut(usize, &BBox) -> Result<Wr>,
+    {
+        let mut writers: Vec<Option<Wr>> = (0..self.cells.len()).map(|_| None).collect();
+        // The ways & relations written to each cell, to find which cells relations go in
+        let mut cell_ways: Vec<IdSet> = vec![IdSet::new(); self.cells.len()];
+        let mut cell_relations: Vec<IdSet> = vec![IdSet::new(); self.cells.len()];
+        let mut obj_cells: Vec<usize> = Vec::new();
+
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            obj_cells.clear();
+            if let Some(node) = obj.as_node() {
+                if let Some(loc) = node.lat_lon() {
+                    locations.set(node.id(), loc);
+                    self.cells_containing(loc, &mut obj_cells);
+                }
+            } else if let Some(way) = obj.as_way() {
+                for loc in way.nodes().iter().filter_map(|nid| locations.get(*nid)) {
+                    self.cells_containing(loc, &mut obj_cells);
+                }
+                obj_cells.sort_unstable();
+                obj_cells.dedup();
+                for idx in obj_cells.iter() {
+                    cell_ways[*idx].insert(OSMObjectType::Way, way.id());
+                }
+            } else if let Some(relation) = obj.as_relation() {
+                for (member_type, member_id, _role) in relation.members() {
+                    match member_type {
+                        OSMObjectType::Node => {
+                            if let Some(loc) = locations.get(member_id) {
+                                self.cells_containing(loc, &mut obj_cells);
+                            }
+                        }
+                        OSMObjectType::Way => obj_cells.extend(
+                            (0..self.cells.len())
+                                .filter(|idx| cell_ways[*idx].contains(member_type, member_id)),
+                        ),
+                        OSMObjectType::Relation => {
+                            obj_cells.extend((0..self.cells.len()).filter(|idx| {
+                                cell_relations[*idx].contains(member_type, member_id)
+                            }))
+                        }
+                    }
+                }
+                obj_cells.sort_unstable();
+                obj_cells.dedup();
+                for idx in obj_cells.iter() {
+                    cell_relations[*idx].insert(OSMObjectType::Relation, relation.id());
+                }
+            }
+
+            for idx in obj_cells.iter().copied() {
+                let writer = match &mut writers[idx] {
+                    Some(writer) => writer,
+                    empty => empty.insert(make_writer(idx, &self.cells[idx])?),
+                };
+                writer.write_obj(&obj)?;
+            }
+        }
+
+        Ok(writers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use std::fs::File;
+    use std::io::Cursor;
+    use xml::{XMLReader, XMLWriter};
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="3"/>
+<node id="3" version="1" lat="3" lon="3"/>
+<node id="4" version="1" lat="50" lon="50"/>
+<way id="10" version="1"><nd ref="1"/><nd ref="2"/></way>
+<way id="11" version="1"><nd ref="2"/><nd ref="3"/></way>
+<relation id="20" version="1"><member type="way" ref="10" role=""/></relation>
+<relation id="21" version="1"><member type="relation" ref="20" role=""/></relation>
+</osm>"#;
+
+    /// The ids written to each cell
+    fn split(splitter: &Splitter) -> Vec<Option<Vec<String>>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |idx: usize| dir.path().join(format!("{}.osm", idx));
+        let mut reader = XMLReader::new(Cursor::new(INPUT));
+        let writers = splitter
+            .split(
+                &mut reader,
+                &mut SparseNodeLocations::new(),
+                |idx, _bbox| Ok(XMLWriter::new(File::create(path(idx))?)),
+            )
+            .unwrap();
+        writers
+            .into_iter()
+            .enumerate()
+            .map(|(idx, writer)| {
+                writer.map(|writer| {
+                    drop(writer);
+                    XMLReader::new(File::open(path(idx)).unwrap())
+                        .objects()
+                        .map(|o| format!("{}{}", o.object_type().name_short(), o.id()))
+                        .collect()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn grid() {
+        // 2×2 grid of 2° cells
+        let splitter = Splitter::grid("0,0,4,4".parse().unwrap(), 2, 2);
+        assert_eq!(splitter.cells()[3], "2,2,4,4".parse().unwrap());
+        let cells = split(&splitter);
+        assert_eq!(
+            cells,
+            vec![
+                Some(vec!["n1".into(), "w10".into(), "r20".into(), "r21".into()]),
+                Some(vec![
+                    "n2".into(),
+                    "w10".into(),
+                    "w11".into(),
+                    "r20".into(),
+                    "r21".into()
+                ]),
+                None,
+                Some(vec!["n3".into(), "w11".into()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bboxes() {
+        let splitter = Splitter::bboxes(vec![
+            "0,0,10,10".parse().unwrap(),
+            "2,0,4,4".parse().unwrap(),
+        ]);
+        let cells = split(&splitter);
+        assert_eq!(cells[0].as_ref().unwrap().len(), 7);
+        assert_eq!(
+            cells[1],
+            Some(vec![
+                "n2".into(),
+                "n3".into(),
+                "w10".into(),
+                "w11".into(),
+                "r20".into(),
+                "r21".into()
+            ])
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        let input = r#"<osm version="0.6"><node id="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        let splitter = Splitter::grid("0,0,4,4".parse().unwrap(), 2, 2);
+        let result = splitter.split(
+            &mut XMLReader::new(Cursor::new(input)),
+            &mut SparseNodeLocations::new(),
+            |_idx, _bbox| Ok(XMLWriter::new(Vec::new())),
+        );
+        assert!(result.is_err());
+    }
+}
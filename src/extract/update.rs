@@ -0,0 +1,536 @@
+//! Keeping a regional extract up to date with replication diffs.
+//!
+//! [`update_extract`] reads the replication sequence & base URL from the header of a PBF extract,
+//! downloads the diffs after it (up to a point in time), keeps only the changes for the region,
+//! applies them, and rewrites the file with the new sequence number & timestamp in the header.
+//!
+//! ```no_run
+//! use osmio::extract::poly::Poly;
+//! use osmio::extract::update::update_extract;
+//! use osmio::TimestampFormat;
+//!
+//! let poly = Poly::from_filename("monaco.poly")?;
+//! let target: TimestampFormat = "2024-01-01T00:00:00Z".parse()?;
+//! let summary = update_extract("monaco.osm.pbf", &poly, target)?;
+//! println!("Now at sequence {}", summary.sequence);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The changes are clipped like [`ExtractStrategy::Simple`](super::ExtractStrategy::Simple):
+//! objects already in the extract are always updated, new nodes are added if they're inside
+//! the region, and new ways & relations if they refer to an included node (or way, or
+//! relation). Nodes outside the region which aren't in a diff can't be added, so new ways
+//! crossing the boundary may refer to missing nodes.
+use cancel::{self, CancellationToken};
+use diff::{ChangeCounts, JoinItem, TryMergeJoin};
+use download::{default_cache_dir, Curl, Downloader, Transport};
+use extract::Region;
+use header::FileInfo;
+use idset::IdSet;
+use obj_types::StringOSMObj;
+use open::read_file_info;
+use osc::squash::{DiffSquasher, SquashedChanges};
+use osc::{ChangeAction, OSCReader};
+use pbf::PBFWriter;
+use sort::ExternalSorter;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use {
+    read_pbf, Error, Node, OSMObj, OSMObjBase, OSMObjectType, OSMReader, OSMWriter, Relation,
+    TimestampFormat, Way,
+};
+
+use anyhow::{Context, Result};
+
+/// The contents of a replication `state.txt` file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicationState {
+    pub sequence: i64,
+    pub timestamp: TimestampFormat,
+}
+
+impl ReplicationState {
+    /// Parse a `state.txt` file, which is a Java properties file with (at least)
+    /// `sequenceNumber` & `timestamp`.
+    ///
+    /// ```rust
+    /// use osmio::extract::update::ReplicationState;
+    /// let state = ReplicationState::parse("#Mon Jan 01 00:00:02 UTC 2024\nsequenceNumber=123\ntimestamp=2024-01-01T00\\:00\\:00Z\n")?;
+    /// assert_eq!(state.sequence, 123);
+    /// assert_eq!(state.timestamp.to_iso_string(), "2024-01-01T00:00:00Z");
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut sequence = None;
+        let mut timestamp = None;
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("sequenceNumber", v)) => sequence = Some(v.trim().parse()?),
+                // Colons are escaped in properties files
+                Some(("timestamp", v)) => timestamp = Some(v.trim().replace("\\:", ":").parse()?),
+                _ => {}
+            }
+        }
+        Ok(ReplicationState {
+            sequence: sequence.context("No sequenceNumber in state file")?,
+            timestamp: timestamp.context("No timestamp in state file")?,
+        })
+    }
+}
+
+/// The path of a sequence number on a replication server, without the extension, e.g. `000/123/456`
+pub fn sequence_path(sequence: i64) -> String {
+    format!(
+        "{:03}/{:03}/{:03}",
+        sequence / 1_000_000,
+        (sequence / 1_000) % 1_000,
+        sequence % 1_000
+    )
+}
+
+/// Downloads state files & diffs from a replication server (e.g.
+/// `https://planet.openstreetmap.org/replication/minute/`)
+#[derive(Debug, Clone)]
+pub struct Replication<T> {
+    base_url: String,
+    downloader: Downloader<T>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl Replication<Curl> {
+    /// Download from this server into the [`default_cache_dir`]
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self::with_downloader(base_url, Downloader::new(default_cache_dir()))
+    }
+}
+
+impl<T: Transport> Replication<T> {
+    /// Download from this server with this downloader
+    pub fn with_downloader(base_url: impl Into<String>, downloader: Downloader<T>) -> Self {
+        let base_url = base_url.into().trim_end_matches('/').to_string();
+        Replication {
+            base_url,
+            downloader,
+            cancellation: None,
+        }
+    }
+
+    /// Stop when this token is cancelled. It's checked before each file is downloaded, and while
+    /// [`update_extract_with`] sorts the changes, which then return a
+    /// [`Cancelled`](crate::cancel::Cancelled) error. The extract is left unchanged.
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// The base URL, without a trailing `/`
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip(self)))]
+    fn fetch_state(&self, url: &str) -> Result<ReplicationState> {
+        cancel::check(&self.cancellation)?;
+        let path = self.downloader.fetch_path(url)?;
+        ReplicationState::parse(&fs::read_to_string(path)?)
+            .with_context(|| format!("Invalid state file {}", url))
+    }
+
+    /// The latest state of the server
+    pub fn latest_state(&self) -> Result<ReplicationState> {
+        self.fetch_state(&format!("{}/state.txt", self.base_url))
+    }
+
+    /// The state of this sequence number
+    pub fn state(&self, sequence: i64) -> Result<ReplicationState> {
+        self.fetch_state(&format!(
+            "{}/{}.state.txt",
+            self.base_url,
+            sequence_path(sequence)
+        ))
+    }
+
+    /// Download the diff (`.osc.gz`) for this sequence number, and return its path in the cache
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "info", skip(self)))]
+    pub fn diff_path(&self, sequence: i64) -> Result<PathBuf> {
+        cancel::check(&self.cancellation)?;
+        self.downloader.fetch_path(&format!(
+            "{}/{}.osc.gz",
+            self.base_url,
+            sequence_path(sequence)
+        ))
+    }
+
+    /// Open the diff for this sequence number
+    pub fn diff(&self, sequence: i64) -> Result<OSCReader<flate2::read::MultiGzDecoder<File>>> {
+        let file = File::open(self.diff_path(sequence)?)?;
+        Ok(OSCReader::new(flate2::read::MultiGzDecoder::new(file)))
+    }
+}
+
+/// What [`update_extract`] did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateSummary {
+    /// The sequence number the extract is now at
+    pub sequence: i64,
+    /// The replication timestamp the extract is now at
+    pub timestamp: Option<TimestampFormat>,
+    /// How many diffs were applied
+    pub diffs: u64,
+    /// How many changes (inside the region) were applied
+    pub counts: ChangeCounts,
+}
+
+/// Update the PBF extract at `pbf_path` with the replication diffs after its sequence number, up
+/// to (and including) the last one at or before `target_time`, downloading with `curl` into the
+/// [`default_cache_dir`]. See the [module documentation](self).
+pub fn update_extract(
+    pbf_path: impl AsRef<Path>,
+    boundary: &impl Region,
+    target_time: impl Into<TimestampFormat>,
+) -> Result<UpdateSummary> {
+    let pbf_path = pbf_path.as_ref();
+    let (_, _, file_info) = read_file_info(pbf_path)?;
+    let base_url = file_info.replication_base_url.with_context(|| {
+        format!(
+            "{} has no replication base URL in its header",
+            pbf_path.display()
+        )
+    })?;
+    update_extract_with(&Replication::new(base_url), pbf_path, boundary, target_time)
+}
+
+/// Like [`update_extract`], but download from this server, rather than the one in the header of
+/// the extract.
+///
+/// The extract is written to a temporary file next to it, which then replaces it, so it's
+/// unchanged if anything fails. If there are no new diffs, it isn't rewritten.
+pub fn update_extract_with<T: Transport>(
+    replication: &Replication<T>,
+    pbf_path: impl AsRef<Path>,
+    boundary: &impl Region,
+    target_time: impl Into<TimestampFormat>,
+) -> Result<UpdateSummary> {
+    let pbf_path = pbf_path.as_ref();
+    let (_, _, mut file_info) = read_file_info(pbf_path)?;
+    let start = file_info.replication_sequence.with_context(|| {
+        format!(
+            "{} has no replication sequence number in its header",
+            pbf_path.display()
+        )
+    })?;
+    let target = target_time.into().try_to_epoch_millis()?;
+
+    let latest = replication.latest_state()?;
+    let mut sorter = ExternalSorter::new();
+    if let Some(cancellation) = &replication.cancellation {
+        sorter = sorter.cancellation(cancellation.clone());
+    }
+    let mut squasher = DiffSquasher::with_sorter(sorter);
+    let mut summary = UpdateSummary {
+        sequence: start,
+        timestamp: file_info.replication_timestamp.clone(),
+        diffs: 0,
+        counts: ChangeCounts::default(),
+    };
+    for sequence in (start + 1)..=latest.sequence {
+        let state = replication.state(sequence)?;
+        if state.timestamp.try_to_epoch_millis()? > target {
+            break;
+        }
+        squasher.add_reader(&mut replication.diff(sequence)?)?;
+        summary.sequence = state.sequence;
+        summary.timestamp = Some(state.timestamp);
+        summary.diffs += 1;
+    }
+    if summary.diffs == 0 {
+        return Ok(summary);
+    }
+
+    let changes = clip_changes(
+        read_pbf(pbf_path)?.try_objects(),
+        squasher.finish()?,
+        boundary,
+        &mut summary.counts,
+    )?;
+
+    file_info.replication_sequence = Some(summary.sequence);
+    file_info.replication_timestamp = summary.timestamp.clone();
+    file_info.replication_base_url = Some(replication.base_url().to_string());
+    file_info.generator = None;
+
+    let mut part = pbf_path.as_os_str().to_os_string();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let result = write_updated(pbf_path, &part, file_info, changes);
+    if result.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    result?;
+    fs::rename(&part, pbf_path)?;
+
+    Ok(summary)
+}
+
+/// The changes which are for objects in the extract, or which are inside the region, sorted.
+/// Deleted objects are marked as deleted.
+fn clip_changes(
+    extract: impl Iterator<Item = Result<impl OSMObj, Error>>,
+    mut changes: SquashedChanges,
+    region: &impl Region,
+    counts: &mut ChangeCounts,
+) -> Result<Vec<StringOSMObj>> {
+    let mut included = IdSet::new();
+    for obj in extract {
+        included.insert_obj(&obj?);
+    }
+
+    let mut clipped = Vec::new();
+    while let Some((action, obj)) = changes.try_next()? {
+        let keep = included.contains_obj(&obj)
+            || (!obj.deleted() && refers_to_included(&obj, region, &included));
+        if !keep {
+            continue;
+        }
+        match action {
+            ChangeAction::Create => counts.created += 1,
+            ChangeAction::Modify => counts.modified += 1,
+            ChangeAction::Delete => counts.deleted += 1,
+        }
+        // Changes are sorted, so the members of a way or relation have been included by now
+        included.insert_obj(&obj);
+        clipped.push(obj);
+    }
+    Ok(clipped)
+}
+
+fn refers_to_included(obj: &StringOSMObj, region: &impl Region, included: &IdSet) -> bool {
+    if let Some(node) = obj.as_node() {
+        node.lat_lon().is_some_and(|loc| region.contains(loc))
+    } else if let Some(way) = obj.as_way() {
+        way.nodes()
+            .iter()
+            .any(|nid| included.contains(OSMObjectType::Node, *nid))
+    } else if let Some(relation) = obj.as_relation() {
+        relation
+            .members()
+            .any(|(member_type, member_id, _)| included.contains(member_type, member_id))
+    } else {
+        false
+    }
+}
+
+fn write_updated(
+    pbf_path: &Path,
+    output: &Path,
+    file_info: FileInfo,
+    changes: Vec<StringOSMObj>,
+) -> Result<()> {
+    let mut writer = PBFWriter::new(BufWriter::new(File::create(output)?)).file_info(file_info);
+    let mut reader = read_pbf(pbf_path)?;
+    let changes = changes.into_iter().map(Ok);
+    for item in TryMergeJoin::new(reader.try_objects(), changes) {
+        match item? {
+            JoinItem::OnlyLeft(old) => writer.write_obj(&old)?,
+            JoinItem::OnlyRight(new) | JoinItem::Both(_, new) => {
+                if !new.deleted() {
+                    writer.write_obj(&new)?;
+                }
+            }
+        }
+    }
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use download::{Fetched, Validators};
+    use std::collections::HashMap;
+    use xml::XMLReader;
+    use BBox;
+
+    /// Serves these files, by URL
+    struct Fake(HashMap<String, Vec<u8>>);
+
+    impl Transport for &Fake {
+        fn download(
+            &self,
+            url: &str,
+            _validators: Option<&Validators>,
+            dest: &Path,
+        ) -> Result<Fetched> {
+            let body = self.0.get(url).with_context(|| format!("404 {}", url))?;
+            fs::write(dest, body)?;
+            Ok(Fetched::Downloaded(Validators::default()))
+        }
+    }
+
+    fn gzip(s: &str) -> Vec<u8> {
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(s.as_bytes()).unwrap();
+        gz.finish().unwrap()
+    }
+
+    fn state(sequence: i64, time: &str) -> Vec<u8> {
+        format!(
+            "sequenceNumber={}\ntimestamp={}\n",
+            sequence,
+            time.replace(':', "\\:")
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(sequence_path(4_887_393), "004/887/393");
+        assert_eq!(sequence_path(12), "000/000/012");
+    }
+
+    #[test]
+    fn update() {
+        let base = "https://example.com/replication/minute";
+        let mut files = HashMap::new();
+        files.insert(
+            format!("{}/state.txt", base),
+            state(3, "2024-01-01T00:03:00Z"),
+        );
+        files.insert(
+            format!("{}/000/000/002.state.txt", base),
+            state(2, "2024-01-01T00:02:00Z"),
+        );
+        files.insert(
+            format!("{}/000/000/003.state.txt", base),
+            state(3, "2024-01-01T00:03:00Z"),
+        );
+        // Node 1 moves, node 2 is deleted, node 3 is created inside & node 4 outside, way 11 is
+        // new & uses node 3, and way 12 is outside
+        files.insert(
+            format!("{}/000/000/002.osc.gz", base),
+            gzip(r#"<osmChange version="0.6">
+<modify><node id="1" version="2" lat="1.5" lon="1.5"/></modify>
+<delete><node id="2" version="2"/></delete>
+<create><node id="3" version="1" lat="2" lon="2"/><node id="4" version="1" lat="9" lon="9"/>
+<way id="11" version="1"><nd ref="1"/><nd ref="3"/></way><way id="12" version="1"><nd ref="4"/><nd ref="4"/></way></create>
+</osmChange>"#),
+        );
+        // After the target time
+        files.insert(
+            format!("{}/000/000/003.osc.gz", base),
+            gzip(r#"<osmChange version="0.6"><delete><node id="1" version="3"/></delete></osmChange>"#),
+        );
+        let fake = Fake(files);
+
+        let dir = tempfile::tempdir().unwrap();
+        let pbf_path = dir.path().join("extract.osm.pbf");
+        let input = r#"<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="2"/>
+<way id="10" version="1"><nd ref="1"/><nd ref="2"/></way>
+</osm>"#;
+        let info = FileInfo {
+            replication_sequence: Some(1),
+            replication_base_url: Some(base.to_string()),
+            ..Default::default()
+        };
+        let mut writer = PBFWriter::new(File::create(&pbf_path).unwrap()).file_info(info);
+        for obj in XMLReader::new(input.as_bytes()).objects() {
+            writer.write_obj(&obj).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let replication = Replication::with_downloader(
+            base,
+            Downloader::new(dir.path().join("cache")).transport(&fake),
+        );
+        let bbox: BBox = "0,0,5,5".parse().unwrap();
+        let summary = update_extract_with(
+            &replication,
+            &pbf_path,
+            &bbox,
+            "2024-01-01T00:02:30Z".parse::<TimestampFormat>().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(summary.sequence, 2);
+        assert_eq!(summary.diffs, 1);
+        assert_eq!(
+            summary.counts,
+            ChangeCounts {
+                created: 2,
+                modified: 1,
+                deleted: 1
+            }
+        );
+
+        let (_, _, info) = read_file_info(&pbf_path).unwrap();
+        assert_eq!(info.replication_sequence, Some(2));
+        assert_eq!(
+            info.replication_timestamp,
+            Some("2024-01-01T00:02:00Z".parse().unwrap())
+        );
+        let objs: Vec<_> = read_pbf(&pbf_path)
+            .unwrap()
+            .objects()
+            .map(|o| {
+                (
+                    format!("{}{}", o.object_type().name_short(), o.id()),
+                    o.version(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            objs,
+            vec![
+                ("n1".to_string(), Some(2)),
+                ("n3".to_string(), Some(1)),
+                ("w10".to_string(), Some(1)),
+                ("w11".to_string(), Some(1)),
+            ]
+        );
+        assert!(!dir.path().join("extract.osm.pbf.part").exists());
+
+        // Nothing is downloaded or changed once it's cancelled
+        let token = CancellationToken::new();
+        token.cancel();
+        let replication = Replication::with_downloader(
+            base,
+            Downloader::new(dir.path().join("cache2")).transport(&fake),
+        )
+        .cancellation(token);
+        assert!(replication
+            .latest_state()
+            .unwrap_err()
+            .is::<cancel::Cancelled>());
+        assert!(replication.diff_path(3).is_err());
+        let before = fs::read(&pbf_path).unwrap();
+        let err = update_extract_with(
+            &replication,
+            &pbf_path,
+            &bbox,
+            "2024-01-01T00:05:00Z".parse::<TimestampFormat>().unwrap(),
+        )
+        .unwrap_err();
+        assert!(err.is::<cancel::Cancelled>());
+        assert_eq!(fs::read(&pbf_path).unwrap(), before);
+        assert!(!dir.path().join("cache2").exists());
+
+        // A truncated extract is an error, and is left as it was
+        let truncated = &before[..before.len() - 5];
+        fs::write(&pbf_path, truncated).unwrap();
+        let replication = Replication::with_downloader(
+            base,
+            Downloader::new(dir.path().join("cache3")).transport(&fake),
+        );
+        assert!(update_extract_with(
+            &replication,
+            &pbf_path,
+            &bbox,
+            "2024-01-01T00:05:00Z".parse::<TimestampFormat>().unwrap(),
+        )
+        .is_err());
+        assert_eq!(fs::read(&pbf_path).unwrap(), truncated);
+        assert!(!dir.path().join("extract.osm.pbf.part").exists());
+    }
+}
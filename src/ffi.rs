@@ -0,0 +1,320 @@
+//! A C API for reading OSM data, so osmio can be used from C, or other languages with a C FFI
+//! (e.g. Python with `cffi`). Only built with the `ffi` feature.
+//!
+//! Files are opened with [`AnyReader`], so PBF & (compressed) XML files are supported, and `-` is
+//! stdin.
+//!
+//! ```c
+//! OsmioReader *reader = osmio_reader_open("input.osm.pbf");
+//! if (reader == NULL) {
+//!     fprintf(stderr, "%s\n", osmio_last_error());
+//!     return 1;
+//! }
+//! const OsmioObject *obj;
+//! while ((obj = osmio_reader_next(reader)) != NULL) {
+//!     if (obj->object_type == OSMIO_NODE && obj->has_location) {
+//!         printf("node %lld at %f,%f\n", obj->id, obj->lat, obj->lon);
+//!     }
+//!     for (size_t i = 0; i < obj->num_tags; i++) {
+//!         printf("  %s=%s\n", obj->tag_keys[i], obj->tag_values[i]);
+//!     }
+//! }
+//! if (osmio_last_error() != NULL) { /* the file was invalid */ }
+//! osmio_reader_free(reader);
+//! ```
+//!
+//! The object returned by `osmio_reader_next` (and all its strings & arrays) is owned by the
+//! reader, and is only valid until the next call with that reader.
+//!
+//! The declarations are in `include/osmio.h`. The shared (or static) library is built with:
+//!
+//! ```sh
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+//!
+//! A panic inside osmio never unwinds into C: the function returns its error value (NULL) and
+//! [`osmio_last_error`] is set.
+use open::AnyReader;
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use {Node, OSMObj, OSMObjectType, OSMReader, ObjId, Way};
+
+/// `object_type` of a node
+pub const OSMIO_NODE: u8 = b'n';
+/// `object_type` of a way
+pub const OSMIO_WAY: u8 = b'w';
+/// `object_type` of a relation
+pub const OSMIO_RELATION: u8 = b'r';
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: impl ToString) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(to_cstring(&err.to_string())));
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+}
+
+/// Run `f`, returning `error` if it panics, since unwinding into C is undefined behaviour
+fn catch_panic<T>(error: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            set_last_error(format!("osmio panicked: {}", panic_message(&*payload)));
+            error
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown error"
+    }
+}
+
+/// C strings can't contain NUL, so those are removed
+fn to_cstring(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).unwrap()
+}
+
+/// An OSM object, as seen from C
+#[repr(C)]
+pub struct OsmioObject {
+    /// [`OSMIO_NODE`], [`OSMIO_WAY`] or [`OSMIO_RELATION`]
+    pub object_type: u8,
+    pub id: ObjId,
+    /// True iff `lat` & `lon` are set (only for nodes)
+    pub has_location: bool,
+    pub lat: f64,
+    pub lon: f64,
+    pub num_tags: usize,
+    /// `num_tags` NUL terminated strings
+    pub tag_keys: *const *const c_char,
+    /// `num_tags` NUL terminated strings, in the same order as `tag_keys`
+    pub tag_values: *const *const c_char,
+    /// Number of node ids in `nodes` (only for ways)
+    pub num_nodes: usize,
+    pub nodes: *const ObjId,
+}
+
+/// A reader, which owns the current object & its data
+pub struct OsmioReader {
+    reader: AnyReader,
+    object: OsmioObject,
+    keys: Vec<CString>,
+    values: Vec<CString>,
+    key_ptrs: Vec<*const c_char>,
+    value_ptrs: Vec<*const c_char>,
+    nodes: Vec<ObjId>,
+}
+
+impl OsmioReader {
+    fn new(reader: AnyReader) -> Self {
+        OsmioReader {
+            reader,
+            object: OsmioObject {
+                object_type: 0,
+                id: 0,
+                has_location: false,
+                lat: 0.,
+                lon: 0.,
+                num_tags: 0,
+                tag_keys: ptr::null(),
+                tag_values: ptr::null(),
+                num_nodes: 0,
+                nodes: ptr::null(),
+            },
+            keys: Vec::new(),
+            values: Vec::new(),
+            key_ptrs: Vec::new(),
+            value_ptrs: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Store this object, ready to be returned to C
+    fn set_object(&mut self, obj: &impl OSMObj) -> &OsmioObject {
+        self.keys.clear();
+        self.values.clear();
+        for (k, v) in obj.tags() {
+            self.keys.push(to_cstring(k));
+            self.values.push(to_cstring(v));
+        }
+        self.key_ptrs = self.keys.iter().map(|k| k.as_ptr()).collect();
+        self.value_ptrs = self.values.iter().map(|v| v.as_ptr()).collect();
+
+        self.nodes.clear();
+        if let Some(way) = obj.as_way() {
+            self.nodes.extend_from_slice(way.nodes());
+        }
+        let location = obj.as_node().and_then(|n| n.lat_lon_f64());
+
+        self.object = OsmioObject {
+            object_type: match obj.object_type() {
+                OSMObjectType::Node => OSMIO_NODE,
+                OSMObjectType::Way => OSMIO_WAY,
+                OSMObjectType::Relation => OSMIO_RELATION,
+            },
+            id: obj.id(),
+            has_location: location.is_some(),
+            lat: location.map_or(0., |l| l.0),
+            lon: location.map_or(0., |l| l.1),
+            num_tags: self.keys.len(),
+            tag_keys: self.key_ptrs.as_ptr(),
+            tag_values: self.value_ptrs.as_ptr(),
+            num_nodes: self.nodes.len(),
+            nodes: self.nodes.as_ptr(),
+        };
+        &self.object
+    }
+}
+
+/// Open a PBF or XML file (or stdin, if `path` is `-`). Returns NULL on error, see
+/// [`osmio_last_error`].
+///
+/// # Safety
+///
+/// `path` must be a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn osmio_reader_open(path: *const c_char) -> *mut OsmioReader {
+    clear_last_error();
+    catch_panic(ptr::null_mut(), || {
+        if path.is_null() {
+            set_last_error("path is NULL");
+            return ptr::null_mut();
+        }
+        let path = match CStr::from_ptr(path).to_str() {
+            Ok(path) => path,
+            Err(e) => {
+                set_last_error(e);
+                return ptr::null_mut();
+            }
+        };
+        match AnyReader::from_filename(path) {
+            Ok(reader) => Box::into_raw(Box::new(OsmioReader::new(reader))),
+            Err(e) => {
+                set_last_error(e);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// The next object, or NULL at the end of the file, or if there's an error (then
+/// [`osmio_last_error`] isn't NULL). The object is valid until the next call with this reader.
+///
+/// # Safety
+///
+/// `reader` must be from [`osmio_reader_open`], and not freed.
+#[no_mangle]
+pub unsafe extern "C" fn osmio_reader_next(reader: *mut OsmioReader) -> *const OsmioObject {
+    clear_last_error();
+    catch_panic(ptr::null(), || {
+        let reader = match reader.as_mut() {
+            Some(reader) => reader,
+            None => {
+                set_last_error("reader is NULL");
+                return ptr::null();
+            }
+        };
+        match reader.reader.try_next() {
+            Ok(Some(obj)) => reader.set_object(&obj),
+            Ok(None) => ptr::null(),
+            Err(e) => {
+                set_last_error(e);
+                ptr::null()
+            }
+        }
+    })
+}
+
+/// Close the file, and free the reader. Does nothing if `reader` is NULL.
+///
+/// # Safety
+///
+/// `reader` must be from [`osmio_reader_open`], and not already freed.
+#[no_mangle]
+pub unsafe extern "C" fn osmio_reader_free(reader: *mut OsmioReader) {
+    if !reader.is_null() {
+        catch_panic((), || drop(Box::from_raw(reader)));
+    }
+}
+
+/// The error from the last call (on this thread), or NULL if it succeeded. Valid until the next
+/// call.
+#[no_mangle]
+pub extern "C" fn osmio_last_error() -> *const c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+    })
+    .unwrap_or(ptr::null())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn read() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="1.5" lon="-2"><tag k="name" v="Pub"/></node>
+<way id="2"><nd ref="1"/><nd ref="3"/></way>
+</osm>"#
+        )
+        .unwrap();
+        let path = to_cstring(file.path().to_str().unwrap());
+
+        unsafe {
+            let reader = osmio_reader_open(path.as_ptr());
+            assert!(!reader.is_null());
+
+            let node = &*osmio_reader_next(reader);
+            assert_eq!(node.object_type, OSMIO_NODE);
+            assert_eq!(node.id, 1);
+            assert!(node.has_location);
+            assert_eq!((node.lat, node.lon), (1.5, -2.));
+            assert_eq!(node.num_tags, 1);
+            assert_eq!(CStr::from_ptr(*node.tag_keys).to_str(), Ok("name"));
+            assert_eq!(CStr::from_ptr(*node.tag_values).to_str(), Ok("Pub"));
+
+            let way = &*osmio_reader_next(reader);
+            assert_eq!(way.object_type, OSMIO_WAY);
+            assert_eq!(
+                std::slice::from_raw_parts(way.nodes, way.num_nodes),
+                &[1, 3]
+            );
+
+            assert!(osmio_reader_next(reader).is_null());
+            assert!(osmio_last_error().is_null());
+            osmio_reader_free(reader);
+
+            let missing = to_cstring("/does/not/exist.osm.pbf");
+            assert!(osmio_reader_open(missing.as_ptr()).is_null());
+            assert!(!osmio_last_error().is_null());
+        }
+    }
+
+    #[test]
+    fn panics() {
+        let result = catch_panic(ptr::null::<OsmioObject>(), || panic!("bad {}", "data"));
+        assert!(result.is_null());
+        let error = unsafe { CStr::from_ptr(osmio_last_error()) };
+        assert_eq!(error.to_str(), Ok("osmio panicked: bad data"));
+    }
+}
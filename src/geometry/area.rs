@@ -0,0 +1,254 @@
+//! Deciding whether a closed way is an area (polygon) or a closed line.
+//!
+//! A closed way is an area if it's tagged `area=yes`, or has a tag which implies an area, like
+//! `building=*` or `landuse=*`. Some values of those keys are lines though, e.g.
+//! `natural=coastline` or `man_made=embankment`. [`AreaRules`] is that table of keys & values.
+//! The [default rules](AreaRules::default) are similar to what iD & osm2pgsql use.
+//!
+//! ([`Way::is_area`] is simpler: it treats every closed way as an area unless it's `area=no`)
+//!
+//! ```rust
+//! use osmio::geometry::area::{is_area, AreaRules};
+//! use osmio::obj_types::StringWayBuilder;
+//!
+//! let way = StringWayBuilder::default()
+//!     ._id(1)
+//!     ._nodes(vec![1, 2, 3, 1])
+//!     ._tags(vec![("natural".to_string(), "coastline".to_string())])
+//!     .build()
+//!     .unwrap();
+//! assert!(!is_area(&way));
+//!
+//! let rules = AreaRules::default().key_values("natural", &["coastline"]);
+//! assert!(rules.is_area(&way));
+//! ```
+use std::sync::OnceLock;
+use Way;
+
+/// Which values of a key mean a closed way is an area
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AreaValues {
+    /// Every value (except `no`)
+    All,
+    /// Only these values
+    Only(Vec<String>),
+    /// Every value (except `no`), but not these
+    AllExcept(Vec<String>),
+}
+
+impl AreaValues {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            AreaValues::All => value != "no",
+            AreaValues::Only(values) => values.iter().any(|v| v == value),
+            AreaValues::AllExcept(values) => value != "no" && !values.iter().any(|v| v == value),
+        }
+    }
+}
+
+/// A table of which tags make a closed way an area.
+///
+/// `area=yes` & `area=no` always take precedence. Later rules for the same key replace earlier
+/// ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AreaRules {
+    rules: Vec<(String, AreaValues)>,
+}
+
+const DEFAULT_RULES: &[(&str, &[&str], bool)] = &[
+    // (key, values, true iff the values are the only ones which are areas, rather than exceptions)
+    ("aeroway", &["taxiway", "runway", "parking_position"], false),
+    ("amenity", &["bench"], false),
+    ("area:highway", &[], false),
+    ("building", &[], false),
+    ("building:part", &[], false),
+    ("craft", &[], false),
+    ("golf", &["hole"], false),
+    ("highway", &["services", "rest_area"], true),
+    ("historic", &[], false),
+    ("indoor", &[], false),
+    ("landuse", &[], false),
+    ("leisure", &["picnic_table", "slipway", "track"], false),
+    (
+        "man_made",
+        &[
+            "breakwater",
+            "cutline",
+            "dyke",
+            "embankment",
+            "groyne",
+            "pipeline",
+        ],
+        false,
+    ),
+    ("military", &[], false),
+    (
+        "natural",
+        &["arete", "cliff", "coastline", "ridge", "tree_row", "valley"],
+        false,
+    ),
+    ("office", &[], false),
+    ("place", &[], false),
+    (
+        "power",
+        &["generator", "plant", "substation", "transformer"],
+        true,
+    ),
+    ("public_transport", &["platform", "station"], true),
+    ("railway", &["platform", "station", "turntable"], true),
+    ("shop", &[], false),
+    ("tourism", &["artwork"], false),
+    ("water", &[], false),
+    ("waterway", &["boatyard", "dam", "dock", "riverbank"], true),
+];
+
+/// The standard rules: most tags which usually mean an area, like `building`, `landuse` or
+/// `amenity`, but not line features which are often closed, like `natural=coastline`,
+/// `barrier=*` or `highway=*`.
+impl Default for AreaRules {
+    fn default() -> Self {
+        let mut rules = AreaRules::new();
+        for (key, values, only) in DEFAULT_RULES {
+            rules = if *only {
+                rules.key_values(key, values)
+            } else if values.is_empty() {
+                rules.key(key)
+            } else {
+                rules.key_except(key, values)
+            };
+        }
+        rules
+    }
+}
+
+impl AreaRules {
+    /// No rules, so only `area=yes` ways are areas
+    pub fn new() -> Self {
+        AreaRules { rules: Vec::new() }
+    }
+
+    fn rule(mut self, key: &str, values: AreaValues) -> Self {
+        self.rules.retain(|(k, _)| k != key);
+        self.rules.push((key.to_string(), values));
+        self
+    }
+
+    /// Every value of `key` (except `no`) means an area
+    pub fn key(self, key: &str) -> Self {
+        self.rule(key, AreaValues::All)
+    }
+
+    /// Only these values of `key` mean an area
+    pub fn key_values(self, key: &str, values: &[&str]) -> Self {
+        self.rule(
+            key,
+            AreaValues::Only(values.iter().map(|v| v.to_string()).collect()),
+        )
+    }
+
+    /// Every value of `key` (except `no`), other than these, means an area
+    pub fn key_except(self, key: &str, values: &[&str]) -> Self {
+        self.rule(
+            key,
+            AreaValues::AllExcept(values.iter().map(|v| v.to_string()).collect()),
+        )
+    }
+
+    /// Remove the rule for this key, so it never means an area
+    pub fn remove_key(mut self, key: &str) -> Self {
+        self.rules.retain(|(k, _)| k != key);
+        self
+    }
+
+    /// The values of `key` which mean an area, if there's a rule for it
+    pub fn get(&self, key: &str) -> Option<&AreaValues> {
+        self.rules.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// True iff these tags mean a (closed) way is an area
+    pub fn tags_are_area<'a>(&self, tags: impl Iterator<Item = (&'a str, &'a str)>) -> bool {
+        let mut is_area = false;
+        for (k, v) in tags {
+            if k == "area" {
+                match v {
+                    "yes" => return true,
+                    "no" => return false,
+                    _ => {}
+                }
+            } else if !is_area {
+                is_area = self.get(k).is_some_and(|values| values.matches(v));
+            }
+        }
+        is_area
+    }
+
+    /// True iff this way is closed, and is an area according to these rules
+    pub fn is_area(&self, way: &impl Way) -> bool {
+        way.is_closed() && self.tags_are_area(way.tags())
+    }
+}
+
+/// True iff this way is closed, and is an area according to the
+/// [default rules](AreaRules::default)
+pub fn is_area(way: &impl Way) -> bool {
+    static RULES: OnceLock<AreaRules> = OnceLock::new();
+    RULES.get_or_init(AreaRules::default).is_area(way)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringWay, StringWayBuilder};
+
+    fn way(nodes: Vec<i64>, tags: &[(&str, &str)]) -> StringWay {
+        StringWayBuilder::default()
+            ._id(1)
+            ._nodes(nodes)
+            ._tags(
+                tags.iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn closed(tags: &[(&str, &str)]) -> StringWay {
+        way(vec![1, 2, 3, 1], tags)
+    }
+
+    #[test]
+    fn default_rules() {
+        assert!(is_area(&closed(&[("building", "yes")])));
+        assert!(is_area(&closed(&[("name", "x"), ("landuse", "grass")])));
+        assert!(is_area(&closed(&[("highway", "footway"), ("area", "yes")])));
+        assert!(is_area(&closed(&[("power", "substation")])));
+        assert!(!is_area(&way(vec![1, 2, 3], &[("building", "yes")])));
+        assert!(!is_area(&closed(&[("building", "yes"), ("area", "no")])));
+        assert!(!is_area(&closed(&[("building", "no")])));
+        assert!(!is_area(&closed(&[("highway", "residential")])));
+        assert!(!is_area(&closed(&[("natural", "coastline")])));
+        assert!(!is_area(&closed(&[("power", "line")])));
+        assert!(!is_area(&closed(&[("barrier", "fence")])));
+        assert!(!is_area(&closed(&[])));
+    }
+
+    #[test]
+    fn custom_rules() {
+        let rules = AreaRules::new()
+            .key("barrier")
+            .key_values("highway", &["pedestrian"]);
+        assert!(rules.is_area(&closed(&[("barrier", "wall")])));
+        assert!(rules.is_area(&closed(&[("highway", "pedestrian")])));
+        assert!(!rules.is_area(&closed(&[("building", "yes")])));
+        assert!(rules.is_area(&closed(&[("building", "yes"), ("area", "yes")])));
+
+        let rules = AreaRules::default()
+            .remove_key("building")
+            .key_except("barrier", &["fence"]);
+        assert!(!rules.is_area(&closed(&[("building", "yes")])));
+        assert!(rules.is_area(&closed(&[("barrier", "wall")])));
+        assert!(!rules.is_area(&closed(&[("barrier", "fence")])));
+        assert_eq!(rules.get("shop"), Some(&AreaValues::All));
+    }
+}
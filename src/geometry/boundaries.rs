@@ -0,0 +1,407 @@
+//! The hierarchy of administrative boundaries, e.g. for reverse geocoding.
+//!
+//! [`BoundaryHierarchy`] assembles the `boundary=administrative` relations into polygons (see
+//! [`multipolygon`](super::multipolygon)), and finds which areas contain which: the parent of an
+//! area is the containing area with the next lowest `admin_level`. Intermediate levels can be
+//! missing, e.g. a city (`admin_level=8`) in a country (`admin_level=2`) without any
+//! regions in between.
+//!
+//! ```no_run
+//! use osmio::geometry::boundaries::BoundaryHierarchy;
+//! use osmio::{Lat, Lon};
+//! use std::convert::TryFrom;
+//!
+//! let hierarchy = BoundaryHierarchy::read(|| osmio::read_pbf("input.osm.pbf"))?;
+//! let loc = (Lat::try_from(51.5)?, Lon::try_from(-0.12)?);
+//! for area in hierarchy.lookup(loc) {
+//!     println!("{}: {:?}", area.admin_level, area.name);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use super::multipolygon::{assemble_multipolygon, is_area_relation, Polygon};
+use super::point_in_ring;
+use node_locations::{NodeLocations, SparseNodeLocations};
+use std::collections::{HashMap, HashSet};
+use {Lat, Lon, OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, Relation, Way};
+
+use anyhow::Result;
+
+/// A ring of `(x, y)` points
+type Ring = Vec<(f64, f64)>;
+
+/// One administrative area
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminArea {
+    pub relation_id: ObjId,
+    pub admin_level: u8,
+    pub name: Option<String>,
+    pub polygons: Vec<Polygon>,
+    /// `(outer, inners)` rings, as `(x, y)`, for point in polygon tests
+    rings: Vec<(Ring, Vec<Ring>)>,
+    /// `(min_x, min_y, max_x, max_y)`
+    bbox: (f64, f64, f64, f64),
+}
+
+fn to_xy(ring: &[(Lat, Lon)]) -> Ring {
+    ring.iter()
+        .map(|(lat, lon)| (lon.degrees(), lat.degrees()))
+        .collect()
+}
+
+impl AdminArea {
+    /// Create an area from these polygons. Returns `None` if there are no polygons.
+    pub fn new(
+        relation_id: ObjId,
+        admin_level: u8,
+        name: Option<String>,
+        polygons: Vec<Polygon>,
+    ) -> Option<Self> {
+        if polygons.is_empty() {
+            return None;
+        }
+        let rings: Vec<_> = polygons
+            .iter()
+            .map(|p| (to_xy(&p.outer), p.inners.iter().map(|r| to_xy(r)).collect()))
+            .collect();
+        let mut bbox = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        for (x, y) in rings.iter().flat_map(|(outer, _)| outer.iter()) {
+            bbox = (
+                bbox.0.min(*x),
+                bbox.1.min(*y),
+                bbox.2.max(*x),
+                bbox.3.max(*y),
+            );
+        }
+        Some(AdminArea {
+            relation_id,
+            admin_level,
+            name,
+            polygons,
+            rings,
+            bbox,
+        })
+    }
+
+    fn contains_xy(&self, (x, y): (f64, f64)) -> bool {
+        x >= self.bbox.0
+            && y >= self.bbox.1
+            && x <= self.bbox.2
+            && y <= self.bbox.3
+            && self.rings.iter().any(|(outer, inners)| {
+                point_in_ring((x, y), outer) && !inners.iter().any(|r| point_in_ring((x, y), r))
+            })
+    }
+
+    /// True iff this location is inside this area
+    pub fn contains(&self, (lat, lon): (Lat, Lon)) -> bool {
+        self.contains_xy((lon.degrees(), lat.degrees()))
+    }
+
+    /// True iff `other` is inside this area. Boundaries are often shared (e.g. a region & its
+    /// country), so points of `other` which are also points of this area are ignored.
+    fn contains_area(&self, other: &AdminArea) -> bool {
+        if other.bbox.0 < self.bbox.0
+            || other.bbox.1 < self.bbox.1
+            || other.bbox.2 > self.bbox.2
+            || other.bbox.3 > self.bbox.3
+        {
+            return false;
+        }
+        let own_points: HashSet<(u64, u64)> = self
+            .rings
+            .iter()
+            .flat_map(|(outer, _)| outer.iter())
+            .map(|(x, y)| (x.to_bits(), y.to_bits()))
+            .collect();
+        let point = other
+            .rings
+            .iter()
+            .flat_map(|(outer, _)| outer.iter())
+            .find(|(x, y)| !own_points.contains(&(x.to_bits(), y.to_bits())));
+        match point {
+            Some(point) => self.contains_xy(*point),
+            // Every point is shared, so they're the same area
+            None => true,
+        }
+    }
+
+    /// The area of the bbox, to choose the smallest parent
+    fn bbox_size(&self) -> f64 {
+        (self.bbox.2 - self.bbox.0) * (self.bbox.3 - self.bbox.1)
+    }
+}
+
+/// True iff this is a `boundary=administrative` relation with a numeric `admin_level`
+pub fn is_admin_boundary(relation: &impl Relation) -> bool {
+    is_area_relation(relation)
+        && relation.tag("boundary") == Some("administrative")
+        && relation
+            .tag("admin_level")
+            .is_some_and(|l| l.parse::<u8>().is_ok())
+}
+
+/// Administrative areas, and which contain which. See the [module documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct BoundaryHierarchy {
+    areas: Vec<AdminArea>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    skipped: Vec<ObjId>,
+}
+
+impl BoundaryHierarchy {
+    /// Build the hierarchy of all the administrative boundaries in a file.
+    ///
+    /// The file is read 3 times, for the relations, then their ways, and then the locations of
+    /// the ways' nodes, so this is given a function which opens a new reader (e.g.
+    /// `|| osmio::read_pbf(&path)`).
+    pub fn read<F, R>(mut open: F) -> Result<Self>
+    where
+        F: FnMut() -> Result<R>,
+        R: OSMReader,
+    {
+        let mut relations = Vec::new();
+        let mut reader = open()?;
+        while let Some(obj) = reader.try_next()? {
+            if let Some(relation) = obj.as_relation() {
+                if is_admin_boundary(relation) {
+                    relations.push(relation.clone());
+                }
+            }
+        }
+
+        let needed_ways: HashSet<ObjId> = relations
+            .iter()
+            .flat_map(|r| r.members())
+            .filter(|(t, _, _)| *t == OSMObjectType::Way)
+            .map(|(_, id, _)| id)
+            .collect();
+        let mut way_nodes = HashMap::new();
+        let mut reader = open()?;
+        while let Some(obj) = reader.try_next()? {
+            if let Some(way) = obj.as_way() {
+                if needed_ways.contains(&way.id()) {
+                    way_nodes.insert(way.id(), way.nodes().to_vec());
+                }
+            }
+        }
+
+        let needed_nodes: HashSet<ObjId> = way_nodes.values().flatten().copied().collect();
+        let mut locations = SparseNodeLocations::new();
+        let mut reader = open()?;
+        while let Some(obj) = reader.try_next()? {
+            if let Some(node) = obj.as_node() {
+                if needed_nodes.contains(&node.id()) {
+                    locations.add_node(node);
+                }
+            }
+        }
+
+        Ok(Self::from_relations(
+            relations.iter(),
+            &way_nodes,
+            &locations,
+        ))
+    }
+
+    /// Build the hierarchy from these boundary relations (others are ignored), with the nodes of
+    /// their ways in `way_nodes`, and those nodes' locations in `locations`.
+    ///
+    /// Relations which can't be assembled into any polygons are left out (see
+    /// [`BoundaryHierarchy::skipped`]).
+    pub fn from_relations<'a, R: Relation + 'a>(
+        relations: impl IntoIterator<Item = &'a R>,
+        way_nodes: &HashMap<ObjId, Vec<ObjId>>,
+        locations: &impl NodeLocations,
+    ) -> Self {
+        let mut areas = Vec::new();
+        let mut skipped = Vec::new();
+        for relation in relations {
+            if !is_admin_boundary(relation) {
+                continue;
+            }
+            let admin_level = relation.tag("admin_level").unwrap().parse().unwrap();
+            let area = assemble_multipolygon(relation, way_nodes, locations);
+            let name = relation.tag("name").map(String::from);
+            match AdminArea::new(relation.id(), admin_level, name, area.polygons) {
+                Some(area) => areas.push(area),
+                None => skipped.push(relation.id()),
+            }
+        }
+        let mut hierarchy = Self::from_areas(areas);
+        hierarchy.skipped = skipped;
+        hierarchy
+    }
+
+    /// Build the hierarchy from these areas
+    pub fn from_areas(mut areas: Vec<AdminArea>) -> Self {
+        areas.sort_by_key(|a| (a.admin_level, a.relation_id));
+        let parents: Vec<Option<usize>> = (0..areas.len())
+            .map(|i| {
+                // Areas are sorted by level, so the possible parents are before this area
+                areas[..i]
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, p)| p.admin_level < areas[i].admin_level)
+                    .filter(|(_, p)| p.contains_area(&areas[i]))
+                    .max_by(|(_, a), (_, b)| {
+                        a.admin_level.cmp(&b.admin_level).then(
+                            b.bbox_size()
+                                .partial_cmp(&a.bbox_size())
+                                .unwrap_or(std::cmp::Ordering::Equal),
+                        )
+                    })
+                    .map(|(p, _)| p)
+            })
+            .collect();
+        let mut children = vec![Vec::new(); areas.len()];
+        for (i, parent) in parents.iter().enumerate() {
+            if let Some(parent) = parent {
+                children[*parent].push(i);
+            }
+        }
+        BoundaryHierarchy {
+            areas,
+            parents,
+            children,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// All the areas, sorted by `admin_level` (then relation id). The other methods use indexes
+    /// into this.
+    pub fn areas(&self) -> &[AdminArea] {
+        &self.areas
+    }
+
+    /// Relations which couldn't be assembled into any polygons
+    pub fn skipped(&self) -> &[ObjId] {
+        &self.skipped
+    }
+
+    /// The index of the area with this relation id
+    pub fn find(&self, relation_id: ObjId) -> Option<usize> {
+        self.areas.iter().position(|a| a.relation_id == relation_id)
+    }
+
+    /// The area which directly contains this area
+    pub fn parent(&self, idx: usize) -> Option<usize> {
+        self.parents[idx]
+    }
+
+    /// The areas which this area directly contains
+    pub fn children(&self, idx: usize) -> &[usize] {
+        &self.children[idx]
+    }
+
+    /// The areas which contain this area, from its parent up to the top
+    pub fn ancestors(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(self.parents[idx], move |i| self.parents[*i])
+    }
+
+    /// The areas which aren't inside any other area (e.g. countries)
+    pub fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.areas.len()).filter(move |i| self.parents[*i].is_none())
+    }
+
+    /// The areas which contain this location, from the top (lowest `admin_level`) down
+    pub fn lookup(&self, loc: (Lat, Lon)) -> Vec<&AdminArea> {
+        let mut result = Vec::new();
+        let mut candidates: Vec<usize> = self.roots().collect();
+        loop {
+            let idx = match candidates.iter().find(|i| self.areas[**i].contains(loc)) {
+                Some(idx) => *idx,
+                None => return result,
+            };
+            result.push(&self.areas[idx]);
+            candidates = self.children[idx].clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::StringRelationBuilder;
+
+    fn square(
+        id: ObjId,
+        level: &str,
+        (x0, y0, x1, y1): (i32, i32, i32, i32),
+        way_nodes: &mut HashMap<ObjId, Vec<ObjId>>,
+        locations: &mut SparseNodeLocations,
+    ) -> impl Relation {
+        let corners = [(x0, y0), (x1, y0), (x1, y1), (x0, y1)];
+        let mut nodes = Vec::new();
+        for (x, y) in corners.iter() {
+            // Shared corners get the same node id
+            let nid = i64::from(*x) * 1000 + i64::from(*y);
+            locations.set(
+                nid,
+                (
+                    Lat::from_inner(*y * 10_000_000),
+                    Lon::from_inner(*x * 10_000_000),
+                ),
+            );
+            nodes.push(nid);
+        }
+        nodes.push(nodes[0]);
+        way_nodes.insert(id, nodes);
+        StringRelationBuilder::default()
+            ._id(id)
+            ._tags(vec![
+                ("type".to_string(), "boundary".to_string()),
+                ("boundary".to_string(), "administrative".to_string()),
+                ("admin_level".to_string(), level.to_string()),
+                ("name".to_string(), format!("area {}", id)),
+            ])
+            ._members(vec![(OSMObjectType::Way, id, "outer".to_string())])
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn hierarchy() {
+        let mut way_nodes = HashMap::new();
+        let mut locations = SparseNodeLocations::new();
+        let mut square = |id, level, bbox| square(id, level, bbox, &mut way_nodes, &mut locations);
+        let relations = [
+            square(1, "2", (0, 0, 10, 10)),
+            square(2, "4", (0, 0, 5, 10)),
+            square(3, "4", (5, 0, 10, 10)),
+            square(4, "8", (0, 0, 2, 2)),
+            // No level 4 region between these
+            square(5, "8", (20, 20, 22, 22)),
+            square(6, "2", (20, 20, 30, 30)),
+        ];
+        let hierarchy = BoundaryHierarchy::from_relations(relations.iter(), &way_nodes, &locations);
+        let id = |idx: usize| hierarchy.areas()[idx].relation_id;
+        let idx = |id: ObjId| hierarchy.find(id).unwrap();
+
+        assert_eq!(hierarchy.areas().len(), 6);
+        assert_eq!(hierarchy.parent(idx(2)).map(id), Some(1));
+        assert_eq!(hierarchy.parent(idx(3)).map(id), Some(1));
+        assert_eq!(hierarchy.parent(idx(4)).map(id), Some(2));
+        assert_eq!(hierarchy.parent(idx(5)).map(id), Some(6));
+        assert_eq!(
+            hierarchy.ancestors(idx(4)).map(id).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert_eq!(hierarchy.roots().map(id).collect::<Vec<_>>(), vec![1, 6]);
+
+        let lookup = |lat: i32, lon: i32| {
+            hierarchy
+                .lookup((
+                    Lat::from_inner(lat * 10_000_000),
+                    Lon::from_inner(lon * 10_000_000),
+                ))
+                .iter()
+                .map(|a| a.name.clone().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lookup(1, 1), vec!["area 1", "area 2", "area 4"]);
+        assert_eq!(lookup(1, 7), vec!["area 1", "area 3"]);
+        assert_eq!(lookup(21, 21), vec!["area 6", "area 5"]);
+        assert!(lookup(15, 15).is_empty());
+    }
+}
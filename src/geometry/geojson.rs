@@ -0,0 +1,356 @@
+//! Writing geometries as GeoJSON.
+//!
+//! [`GeoJSONWriter`] writes either one `FeatureCollection`, or a GeoJSON text sequence: one
+//! feature per line, optionally prefixed with the ASCII record separator (RFC 8142). Sequences
+//! can be read while streaming, e.g. by tippecanoe, and neither form keeps features in memory.
+//! Coordinates are WGS84 unless [`GeoJSONWriter::transform`] is used.
+//!
+//! ```no_run
+//! use osmio::geometry::geojson::GeoJSONWriter;
+//! use osmio::geometry::GeometryReader;
+//!
+//! let mut reader = GeometryReader::new(|| osmio::read_pbf("input.osm.pbf")).untagged_nodes(false);
+//! let mut writer = GeoJSONWriter::seq(std::fs::File::create("output.geojsonseq")?);
+//! for geom in reader.geometries()? {
+//!     writer.write_geometry(&geom)?;
+//! }
+//! writer.finish()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use super::multipolygon::{Area, Ring};
+use super::projection::{CoordTransform, Wgs84};
+use super::Geometry;
+use serde_json::{json, Map, Value};
+use std::io::{self, Write};
+use {Lat, Lon, Node, Way};
+
+/// `[x, y]` (`[lon, lat]` for WGS84), as GeoJSON has it
+fn position(transform: &dyn CoordTransform, loc: &(Lat, Lon)) -> Value {
+    let (x, y) = transform.transform(*loc);
+    json!([x, y])
+}
+
+fn positions(transform: &dyn CoordTransform, locations: &[(Lat, Lon)]) -> Value {
+    Value::Array(locations.iter().map(|l| position(transform, l)).collect())
+}
+
+fn properties<'a>(tags: impl Iterator<Item = (&'a str, &'a str)>) -> Value {
+    Value::Object(
+        tags.map(|(k, v)| (k.to_string(), Value::String(v.to_string())))
+            .collect::<Map<_, _>>(),
+    )
+}
+
+/// A `Point` or `LineString` feature for this geometry. The id is `n`/`w` & the OSM id (e.g.
+/// `w123`), and the properties are the tags.
+pub fn geometry_feature<N: Node, W: Way>(geom: &Geometry<N, W>) -> Value {
+    geometry_feature_with(geom, &Wgs84)
+}
+
+/// Like [`geometry_feature`], with the coordinates transformed
+pub fn geometry_feature_with<N: Node, W: Way>(
+    geom: &Geometry<N, W>,
+    transform: &dyn CoordTransform,
+) -> Value {
+    let (id, geometry, tags) = match geom {
+        Geometry::Point { node, location } => (
+            format!("n{}", node.id()),
+            json!({"type": "Point", "coordinates": position(transform, location)}),
+            properties(node.tags()),
+        ),
+        Geometry::LineString { way, locations } => (
+            format!("w{}", way.id()),
+            json!({"type": "LineString", "coordinates": positions(transform, locations)}),
+            properties(way.tags()),
+        ),
+    };
+    json!({"type": "Feature", "id": id, "geometry": geometry, "properties": tags})
+}
+
+/// A `MultiPolygon` feature for this assembled area, with these properties (usually the tags of
+/// the relation). The id is `r` & the relation id.
+pub fn area_feature<'a>(area: &Area, tags: impl Iterator<Item = (&'a str, &'a str)>) -> Value {
+    area_feature_with(area, tags, &Wgs84)
+}
+
+/// Like [`area_feature`], with the coordinates transformed
+pub fn area_feature_with<'a>(
+    area: &Area,
+    tags: impl Iterator<Item = (&'a str, &'a str)>,
+    transform: &dyn CoordTransform,
+) -> Value {
+    let ring = |ring: &Ring| positions(transform, ring);
+    let polygons: Vec<Value> = area
+        .polygons
+        .iter()
+        .map(|polygon| {
+            Value::Array(
+                std::iter::once(ring(&polygon.outer))
+                    .chain(polygon.inners.iter().map(ring))
+                    .collect(),
+            )
+        })
+        .collect();
+    json!({
+        "type": "Feature",
+        "id": format!("r{}", area.relation_id),
+        "geometry": {"type": "MultiPolygon", "coordinates": polygons},
+        "properties": properties(tags),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    FeatureCollection,
+    Seq { record_separator: bool },
+}
+
+/// Writes GeoJSON features, one at a time. See the [module docs](self).
+///
+/// Call [`GeoJSONWriter::finish`] at the end, which ends the `FeatureCollection`. Dropping the
+/// writer does that too, but ignores errors.
+pub struct GeoJSONWriter<W: Write> {
+    /// `None` once finished
+    writer: Option<W>,
+    format: Format,
+    num_features: u64,
+    transform: Box<dyn CoordTransform>,
+}
+
+impl<W: Write> GeoJSONWriter<W> {
+    /// Write one `FeatureCollection`
+    pub fn new(writer: W) -> Self {
+        GeoJSONWriter {
+            writer: Some(writer),
+            format: Format::FeatureCollection,
+            num_features: 0,
+            transform: Box::new(Wgs84),
+        }
+    }
+
+    /// Write a GeoJSON text sequence, with one feature per line
+    pub fn seq(writer: W) -> Self {
+        GeoJSONWriter {
+            writer: Some(writer),
+            format: Format::Seq {
+                record_separator: false,
+            },
+            num_features: 0,
+            transform: Box::new(Wgs84),
+        }
+    }
+
+    /// Transform the coordinates of geometries & areas (default: [`Wgs84`], unchanged). Features
+    /// given to [`GeoJSONWriter::write_feature`] aren't changed.
+    pub fn transform(mut self, transform: impl CoordTransform + 'static) -> Self {
+        self.transform = Box::new(transform);
+        self
+    }
+
+    /// Start every line of a sequence with the record separator (`0x1E`), as RFC 8142 says.
+    /// Default: `false`, since most tools expect plain newline delimited JSON. Only for
+    /// sequences.
+    pub fn record_separator(mut self, record_separator: bool) -> Self {
+        if let Format::Seq { .. } = self.format {
+            self.format = Format::Seq { record_separator };
+        }
+        self
+    }
+
+    /// Number of features written so far
+    pub fn num_features(&self) -> u64 {
+        self.num_features
+    }
+
+    fn writer_mut(&mut self) -> io::Result<&mut W> {
+        self.writer
+            .as_mut()
+            .ok_or_else(|| io::Error::other("GeoJSONWriter is already finished"))
+    }
+
+    /// Write this feature (e.g. from [`geometry_feature`] or [`area_feature`])
+    pub fn write_feature(&mut self, feature: &Value) -> io::Result<()> {
+        let (format, first) = (self.format, self.num_features == 0);
+        let writer = self.writer_mut()?;
+        match format {
+            Format::FeatureCollection => {
+                if first {
+                    writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[\n")?;
+                } else {
+                    writer.write_all(b",\n")?;
+                }
+            }
+            Format::Seq { record_separator } => {
+                if record_separator {
+                    writer.write_all(b"\x1e")?;
+                }
+            }
+        }
+        serde_json::to_writer(&mut *writer, feature)?;
+        if let Format::Seq { .. } = format {
+            writer.write_all(b"\n")?;
+        }
+        self.num_features += 1;
+        Ok(())
+    }
+
+    /// Write this node or way
+    pub fn write_geometry<N: Node, Wy: Way>(&mut self, geom: &Geometry<N, Wy>) -> io::Result<()> {
+        let feature = geometry_feature_with(geom, &*self.transform);
+        self.write_feature(&feature)
+    }
+
+    /// Write this area, with these properties
+    pub fn write_area<'a>(
+        &mut self,
+        area: &Area,
+        tags: impl Iterator<Item = (&'a str, &'a str)>,
+    ) -> io::Result<()> {
+        let feature = area_feature_with(area, tags, &*self.transform);
+        self.write_feature(&feature)
+    }
+
+    /// Finish the file, and flush & return the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end()?;
+        Ok(self.writer.take().unwrap())
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        let (format, first) = (self.format, self.num_features == 0);
+        let writer = self.writer_mut()?;
+        if format == Format::FeatureCollection {
+            if first {
+                writer.write_all(b"{\"type\":\"FeatureCollection\",\"features\":[")?;
+            }
+            writer.write_all(b"]}\n")?;
+        }
+        writer.flush()
+    }
+}
+
+/// Ends the file if it hasn't been finished. Errors are ignored, so call
+/// [`GeoJSONWriter::finish`] to see them.
+impl<W: Write> Drop for GeoJSONWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.end();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geometry::multipolygon::Polygon;
+    use obj_types::{StringNode, StringNodeBuilder, StringWay, StringWayBuilder};
+
+    fn loc(lat: f64, lon: f64) -> (Lat, Lon) {
+        (
+            Lat::from_inner((lat * 1e7) as i32),
+            Lon::from_inner((lon * 1e7) as i32),
+        )
+    }
+
+    fn geometries() -> Vec<Geometry<StringNode, StringWay>> {
+        vec![
+            Geometry::Point {
+                node: StringNodeBuilder::default()
+                    ._id(1)
+                    ._tags(vec![("name".to_string(), "A \"quoted\"\nname".to_string())])
+                    .build()
+                    .unwrap(),
+                location: loc(51.5, -0.25),
+            },
+            Geometry::LineString {
+                way: StringWayBuilder::default()._id(2).build().unwrap(),
+                locations: vec![loc(1., 2.), loc(3., 4.)],
+            },
+        ]
+    }
+
+    #[test]
+    fn seq() {
+        let mut writer = GeoJSONWriter::seq(Vec::new());
+        for geom in geometries() {
+            writer.write_geometry(&geom).unwrap();
+        }
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            json!({"type": "Feature", "id": "n1",
+                "geometry": {"type": "Point", "coordinates": [-0.25, 51.5]},
+                "properties": {"name": "A \"quoted\"\nname"}})
+        );
+        assert_eq!(
+            lines[1]["geometry"],
+            json!({"type": "LineString", "coordinates": [[2., 1.], [4., 3.]]})
+        );
+
+        let mut writer = GeoJSONWriter::seq(Vec::new()).record_separator(true);
+        for geom in geometries() {
+            writer.write_geometry(&geom).unwrap();
+        }
+        let output = writer.finish().unwrap();
+        assert_eq!(output.iter().filter(|&&b| b == 0x1e).count(), 2);
+        assert_eq!(output[0], 0x1e);
+    }
+
+    #[test]
+    fn transform() {
+        let mut writer = GeoJSONWriter::seq(Vec::new())
+            .transform(|(lat, lon): (Lat, Lon)| (lon.degrees() * 10., lat.degrees() * 10.));
+        for geom in geometries() {
+            writer.write_geometry(&geom).unwrap();
+        }
+        let output = String::from_utf8(writer.finish().unwrap()).unwrap();
+        let line: Value = serde_json::from_str(output.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(
+            line["geometry"]["coordinates"],
+            json!([[20., 10.], [40., 30.]])
+        );
+    }
+
+    #[test]
+    fn feature_collection() {
+        let empty = GeoJSONWriter::new(Vec::new()).finish().unwrap();
+        let empty: Value = serde_json::from_slice(&empty).unwrap();
+        assert_eq!(empty, json!({"type": "FeatureCollection", "features": []}));
+
+        let mut output = Vec::new();
+        {
+            let mut writer = GeoJSONWriter::new(&mut output);
+            for geom in geometries() {
+                writer.write_geometry(&geom).unwrap();
+            }
+            let square = vec![loc(0., 0.), loc(0., 1.), loc(1., 1.), loc(0., 0.)];
+            let area = Area {
+                relation_id: 3,
+                polygons: vec![Polygon {
+                    outer: square,
+                    inners: vec![],
+                }],
+                problems: vec![],
+            };
+            writer
+                .write_area(&area, vec![("type", "multipolygon")].into_iter())
+                .unwrap();
+            assert_eq!(writer.num_features(), 3);
+            // Dropping ends the collection
+        }
+        let collection: Value = serde_json::from_slice(&output).unwrap();
+        let features = collection["features"].as_array().unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(features[2]["id"], "r3");
+        assert_eq!(features[2]["geometry"]["type"], "MultiPolygon");
+        assert_eq!(
+            features[2]["geometry"]["coordinates"][0][0][1],
+            json!([1., 0.])
+        );
+    }
+}
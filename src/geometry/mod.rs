@@ -0,0 +1,291 @@
+//! Reading OSM objects with their geometries.
+//!
+//! Ways only store the ids of their nodes, so getting the coordinates of a way requires first
+//! reading the file to store all node locations, and then reading it again for the ways.
+//! [`GeometryReader`] does both passes for you.
+//!
+//! ```no_run
+//! use osmio::geometry::{Geometry, GeometryReader};
+//! use osmio::prelude::*;
+//!
+//! let mut reader = GeometryReader::new(|| osmio::read_pbf("path/to/filename.osm.pbf"));
+//! for geom in reader.geometries()? {
+//!     match geom {
+//!         Geometry::Point { node, location } => { /* ... */ }
+//!         Geometry::LineString { way, locations } => { /* ... */ }
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use node_locations::{NodeLocations, SparseNodeLocations};
+use {Lat, Lon, Node, OSMObj, OSMObjBase, OSMReader, ObjId, Way};
+
+use anyhow::Result;
+
+pub mod area;
+pub mod boundaries;
+pub mod geojson;
+pub mod multipolygon;
+pub mod projection;
+pub mod route;
+pub mod split;
+
+/// A node or way, along with its coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Geometry<N, W> {
+    /// A node, and its location
+    Point { node: N, location: (Lat, Lon) },
+    /// A way, and the locations of its nodes (in order)
+    LineString { way: W, locations: Vec<(Lat, Lon)> },
+}
+
+impl<N: Node, W: Way> Geometry<N, W> {
+    /// Id of the node or way
+    pub fn id(&self) -> ObjId {
+        match self {
+            Geometry::Point { node, .. } => node.id(),
+            Geometry::LineString { way, .. } => way.id(),
+        }
+    }
+
+    /// The locations, as `(lat, lon)` `f64`s
+    pub fn coords_f64(&self) -> Vec<(f64, f64)> {
+        let to_f64 = |(lat, lon): &(Lat, Lon)| ((*lat).into(), (*lon).into());
+        match self {
+            Geometry::Point { location, .. } => vec![to_f64(location)],
+            Geometry::LineString { locations, .. } => locations.iter().map(to_f64).collect(),
+        }
+    }
+}
+
+/// The `Geometry` produced from the reader `R`
+pub type ReaderGeometry<R> =
+    Geometry<<<R as OSMReader>::Obj as OSMObj>::Node, <<R as OSMReader>::Obj as OSMObj>::Way>;
+
+/// Reads a file twice: first to store all node locations, then to return nodes & ways with their
+/// locations.
+///
+/// Since the file is read twice, this is created with a function which opens a new reader for the
+/// file (e.g. `|| osmio::read_pbf(&path)`). Node locations are stored in a
+/// [`SparseNodeLocations`] by default, use [`GeometryReader::with_node_locations`] for large
+/// files.
+///
+/// Relations are skipped.
+pub struct GeometryReader<F, L> {
+    open: F,
+    locations: L,
+    first_pass_done: bool,
+    untagged_nodes: bool,
+    incomplete_ways: bool,
+}
+
+impl<F, R> GeometryReader<F, SparseNodeLocations>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+{
+    /// Create a new reader, which opens the file with `open`
+    pub fn new(open: F) -> Self {
+        Self::with_node_locations(open, SparseNodeLocations::new())
+    }
+}
+
+impl<F, R, L> GeometryReader<F, L>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+    L: NodeLocations,
+{
+    /// Create a new reader which stores node locations in `locations`.
+    pub fn with_node_locations(open: F, locations: L) -> Self {
+        GeometryReader {
+            open,
+            locations,
+            first_pass_done: false,
+            untagged_nodes: true,
+            incomplete_ways: false,
+        }
+    }
+
+    /// Should nodes without any tags be returned as points? (default: `true`)
+    ///
+    /// Untagged nodes are usually only there as part of a way.
+    pub fn untagged_nodes(mut self, untagged_nodes: bool) -> Self {
+        self.untagged_nodes = untagged_nodes;
+        self
+    }
+
+    /// Should ways with some node locations missing be returned? (default: `false`)
+    ///
+    /// If `true`, the missing nodes are left out of the way's `locations`. This is common in
+    /// extracts, where ways which cross the boundary refer to nodes which aren't in the file.
+    pub fn incomplete_ways(mut self, incomplete_ways: bool) -> Self {
+        self.incomplete_ways = incomplete_ways;
+        self
+    }
+
+    /// The stored node locations. Only complete after [`GeometryReader::read_node_locations`]
+    /// has been called.
+    pub fn node_locations(&self) -> &L {
+        &self.locations
+    }
+
+    /// Convert into the stored node locations
+    pub fn into_node_locations(self) -> L {
+        self.locations
+    }
+
+    /// Do the first pass, reading all node locations. This is done automatically by
+    /// [`GeometryReader::geometries`] if needed.
+    pub fn read_node_locations(&mut self) -> Result<()> {
+        if self.first_pass_done {
+            return Ok(());
+        }
+        let mut reader = (self.open)()?;
+        let sorted = reader.get_sorted_assumption();
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            if let Some(node) = obj.as_node() {
+                self.locations.add_node(node);
+            } else if sorted {
+                break;
+            }
+        }
+        self.first_pass_done = true;
+        Ok(())
+    }
+
+    /// Iterate over all nodes & ways, with their geometries.
+    pub fn geometries(&mut self) -> Result<Geometries<'_, R, L>> {
+        self.read_node_locations()?;
+        let reader = (self.open)()?;
+        Ok(Geometries {
+            reader,
+            locations: &self.locations,
+            untagged_nodes: self.untagged_nodes,
+            incomplete_ways: self.incomplete_ways,
+        })
+    }
+}
+
+/// Iterator over the geometries of a file.
+///
+/// Created by [`GeometryReader::geometries`].
+pub struct Geometries<'a, R, L> {
+    reader: R,
+    locations: &'a L,
+    untagged_nodes: bool,
+    incomplete_ways: bool,
+}
+
+impl<'a, R, L> Iterator for Geometries<'a, R, L>
+where
+    R: OSMReader,
+    L: NodeLocations,
+{
+    type Item = ReaderGeometry<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let obj = self.reader.next()?;
+            if obj.is_node() {
+                let node = obj.into_node().unwrap();
+                if !self.untagged_nodes && node.untagged() {
+                    continue;
+                }
+                if let Some(location) = node.lat_lon() {
+                    return Some(Geometry::Point { node, location });
+                }
+            } else if obj.is_way() {
+                let way = obj.into_way().unwrap();
+                let locations: Vec<(Lat, Lon)> = way
+                    .nodes()
+                    .iter()
+                    .filter_map(|nid| self.locations.get(*nid))
+                    .collect();
+                if locations.len() == way.num_nodes() || self.incomplete_ways {
+                    return Some(Geometry::LineString { way, locations });
+                }
+            }
+        }
+    }
+}
+
+/// Is this point inside the closed ring? (using ray casting)
+///
+/// Works with either `(x, y)` or `(lat, lon)` pairs, as long as both arguments use the same.
+pub(crate) fn point_in_ring(point: (f64, f64), ring: &[(f64, f64)]) -> bool {
+    let (x, y) = point;
+    let mut inside = false;
+    for w in ring.windows(2) {
+        let ((x1, y1), (x2, y2)) = (w[0], w[1]);
+        if (y1 > y) != (y2 > y) && x < (x2 - x1) * (y - y1) / (y2 - y1) + x1 {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Where these 2 segments properly cross (not counting touching at the ends)
+pub(crate) fn segment_intersection(
+    p1: (f64, f64),
+    p2: (f64, f64),
+    p3: (f64, f64),
+    p4: (f64, f64),
+) -> Option<(f64, f64)> {
+    let d = (p2.0 - p1.0) * (p4.1 - p3.1) - (p2.1 - p1.1) * (p4.0 - p3.0);
+    if d == 0. {
+        return None;
+    }
+    let t = ((p3.0 - p1.0) * (p4.1 - p3.1) - (p3.1 - p1.1) * (p4.0 - p3.0)) / d;
+    let u = ((p3.0 - p1.0) * (p2.1 - p1.1) - (p3.1 - p1.1) * (p2.0 - p1.0)) / d;
+    if t > 0. && t < 1. && u > 0. && u < 1. {
+        Some((p1.0 + t * (p2.0 - p1.0), p1.1 + t * (p2.1 - p1.1)))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringNode, StringWay};
+    use std::io::Cursor;
+    use xml::XMLReader;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="1" lon="2"><tag k="name" v="A"/></node>
+<node id="2" lat="3" lon="4"/>
+<way id="10"><nd ref="1"/><nd ref="2"/></way>
+<way id="11"><nd ref="2"/><nd ref="3"/></way>
+<relation id="20"><member type="way" ref="10" role=""/></relation>
+</osm>"#;
+
+    fn open() -> Result<XMLReader<Cursor<&'static str>>> {
+        Ok(XMLReader::new(Cursor::new(INPUT)))
+    }
+
+    fn ids(geoms: &[Geometry<StringNode, StringWay>]) -> Vec<ObjId> {
+        geoms.iter().map(|g| g.id()).collect()
+    }
+
+    #[test]
+    fn two_pass() {
+        let mut reader = GeometryReader::new(open);
+        let geoms: Vec<_> = reader.geometries().unwrap().collect();
+        assert_eq!(ids(&geoms), vec![1, 2, 10]);
+        assert_eq!(geoms[2].coords_f64(), vec![(1., 2.), (3., 4.)]);
+        assert_eq!(reader.node_locations().len(), 2);
+    }
+
+    #[test]
+    fn options() {
+        let mut reader = GeometryReader::new(open)
+            .untagged_nodes(false)
+            .incomplete_ways(true);
+        let geoms: Vec<_> = reader.geometries().unwrap().collect();
+        assert_eq!(ids(&geoms), vec![1, 10, 11]);
+        assert_eq!(geoms[2].coords_f64(), vec![(3., 4.)]);
+    }
+}
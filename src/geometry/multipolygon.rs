@@ -0,0 +1,410 @@
+//! Assembling (multi)polygons from `type=multipolygon` & `type=boundary` relations.
+//!
+//! The member ways of a multipolygon relation are joined together into closed rings, which are
+//! then sorted into outer rings & inner rings (holes) based on how they are nested. The `inner` &
+//! `outer` roles are only checked, not trusted, since they are often wrong.
+use super::{point_in_ring, segment_intersection};
+use node_locations::NodeLocations;
+use std::collections::HashMap;
+use {Lat, Lon, OSMObjectType, ObjId, Relation};
+
+/// A closed ring of locations. The first and last location are the same.
+pub type Ring = Vec<(Lat, Lon)>;
+
+/// One polygon, with one outer ring, and zero or more holes.
+///
+/// Outer rings are counter-clockwise, and inner rings are clockwise (as in GeoJSON).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polygon {
+    pub outer: Ring,
+    pub inners: Vec<Ring>,
+}
+
+/// Something wrong with a multipolygon relation, found while assembling it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AreaProblem {
+    /// This member way isn't available
+    MissingWay(ObjId),
+    /// The location of this node isn't available
+    MissingNode(ObjId),
+    /// These ways could not be joined into a closed ring. The ring starts at `start_node` and ends
+    /// at `end_node`.
+    UnclosedRing {
+        ways: Vec<ObjId>,
+        start_node: ObjId,
+        end_node: ObjId,
+    },
+    /// 2 segments of the rings cross each other at this `(lat, lon)`
+    SelfIntersection { location: (f64, f64) },
+    /// This way has the role `given_role`, but is actually an `inner`/`outer` ring
+    WrongRole {
+        way_id: ObjId,
+        given_role: String,
+        actual_role: &'static str,
+    },
+    /// No closed rings could be made
+    NoRings,
+}
+
+impl AreaProblem {
+    /// True iff this problem means the resulting area may be wrong. Problems which were repaired
+    /// (like wrong roles) aren't fatal.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, AreaProblem::WrongRole { .. })
+    }
+}
+
+/// The result of assembling a multipolygon relation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Area {
+    /// Id of the relation
+    pub relation_id: ObjId,
+    /// The polygons which could be assembled
+    pub polygons: Vec<Polygon>,
+    /// Any problems found
+    pub problems: Vec<AreaProblem>,
+}
+
+impl Area {
+    /// True iff there were no fatal problems assembling this area.
+    pub fn is_valid(&self) -> bool {
+        !self.polygons.is_empty() && !self.problems.iter().any(|p| p.is_fatal())
+    }
+}
+
+/// True iff this relation should be assembled as an area (`type=multipolygon` or
+/// `type=boundary`)
+pub fn is_area_relation(relation: &impl Relation) -> bool {
+    matches!(
+        relation.tag("type"),
+        Some("multipolygon") | Some("boundary")
+    )
+}
+
+/// Assemble this multipolygon relation into polygons.
+///
+/// `way_nodes` has the node ids of the member ways, and `locations` the locations of those nodes.
+/// Any problems are reported in [`Area::problems`], and as much of the area as possible is
+/// assembled.
+pub fn assemble_multipolygon(
+    relation: &impl Relation,
+    way_nodes: &HashMap<ObjId, Vec<ObjId>>,
+    locations: &impl NodeLocations,
+) -> Area {
+    let mut problems = Vec::new();
+
+    let mut segments = Vec::new();
+    let mut roles = HashMap::new();
+    for (member_type, way_id, role) in relation.members() {
+        if member_type != OSMObjectType::Way {
+            continue;
+        }
+        match way_nodes.get(&way_id) {
+            None => problems.push(AreaProblem::MissingWay(way_id)),
+            Some(nodes) if nodes.len() < 2 => {}
+            Some(nodes) => {
+                roles.insert(way_id, role.to_string());
+                segments.push(Segment {
+                    ways: vec![way_id],
+                    nodes: nodes.clone(),
+                });
+            }
+        }
+    }
+
+    let mut rings = Vec::new();
+    for ring in join_rings(segments, &mut problems) {
+        let mut coords = Vec::with_capacity(ring.nodes.len());
+        for nid in ring.nodes.iter() {
+            match locations.get(*nid) {
+                Some(loc) => coords.push(loc),
+                None => problems.push(AreaProblem::MissingNode(*nid)),
+            }
+        }
+        if coords.len() == ring.nodes.len() {
+            rings.push((ring.ways, coords));
+        }
+    }
+    if rings.is_empty() {
+        problems.push(AreaProblem::NoRings);
+    }
+
+    let rings_f64: Vec<Vec<(f64, f64)>> = rings.iter().map(|(_, r)| to_xy(r)).collect();
+    for (i, ring) in rings_f64.iter().enumerate() {
+        find_intersections(ring, ring, true, &mut problems);
+        for other in rings_f64[i + 1..].iter() {
+            find_intersections(ring, other, false, &mut problems);
+        }
+    }
+
+    // A ring is an outer ring if it's inside an even number of other rings
+    let parents: Vec<Option<usize>> = (0..rings.len())
+        .map(|i| smallest_containing_ring(i, &rings_f64))
+        .collect();
+    let depth = |mut i: usize| {
+        let mut depth = 0;
+        while let Some(parent) = parents[i] {
+            depth += 1;
+            i = parent;
+        }
+        depth
+    };
+
+    let mut polygons = Vec::new();
+    let mut polygon_for_outer = HashMap::new();
+    for (i, (ways, ring)) in rings.iter().enumerate() {
+        let is_outer = depth(i) % 2 == 0;
+        let actual_role = if is_outer { "outer" } else { "inner" };
+        for way_id in ways {
+            let given_role = &roles[way_id];
+            if !given_role.is_empty() && given_role != actual_role {
+                problems.push(AreaProblem::WrongRole {
+                    way_id: *way_id,
+                    given_role: given_role.clone(),
+                    actual_role,
+                });
+            }
+        }
+        if is_outer {
+            polygon_for_outer.insert(i, polygons.len());
+            polygons.push(Polygon {
+                outer: oriented(ring.clone(), true),
+                inners: Vec::new(),
+            });
+        }
+    }
+    for (i, (_, ring)) in rings.iter().enumerate() {
+        if let Some(outer_idx) = parents[i].and_then(|p| polygon_for_outer.get(&p)) {
+            polygons[*outer_idx]
+                .inners
+                .push(oriented(ring.clone(), false));
+        }
+    }
+
+    Area {
+        relation_id: relation.id(),
+        polygons,
+        problems,
+    }
+}
+
+/// Some ways joined together
+struct Segment {
+    ways: Vec<ObjId>,
+    nodes: Vec<ObjId>,
+}
+
+impl Segment {
+    fn first(&self) -> ObjId {
+        self.nodes[0]
+    }
+    fn last(&self) -> ObjId {
+        self.nodes[self.nodes.len() - 1]
+    }
+    fn is_closed(&self) -> bool {
+        self.first() == self.last()
+    }
+}
+
+/// Join the segments into closed rings, by matching the end node ids.
+fn join_rings(segments: Vec<Segment>, problems: &mut Vec<AreaProblem>) -> Vec<Segment> {
+    let mut unused: Vec<Option<Segment>> = segments.into_iter().map(Some).collect();
+    let mut rings = Vec::new();
+
+    for i in 0..unused.len() {
+        let mut ring = match unused[i].take() {
+            None => continue,
+            Some(s) => s,
+        };
+        while !ring.is_closed() {
+            let next = unused.iter_mut().find(|s| match s {
+                Some(s) => {
+                    s.first() == ring.last()
+                        || s.last() == ring.last()
+                        || s.first() == ring.first()
+                        || s.last() == ring.first()
+                }
+                None => false,
+            });
+            let mut next = match next.and_then(|s| s.take()) {
+                None => break,
+                Some(next) => next,
+            };
+            if next.first() == ring.first() || next.last() == ring.first() {
+                // join to the start of the ring, by reversing
+                ring.nodes.reverse();
+                ring.ways.reverse();
+            }
+            if next.last() == ring.last() {
+                next.nodes.reverse();
+            }
+            ring.nodes.extend_from_slice(&next.nodes[1..]);
+            ring.ways.extend(next.ways);
+        }
+
+        if ring.is_closed() {
+            rings.push(ring);
+        } else {
+            problems.push(AreaProblem::UnclosedRing {
+                start_node: ring.first(),
+                end_node: ring.last(),
+                ways: ring.ways,
+            });
+        }
+    }
+
+    rings
+}
+
+/// Convert to `(x, y)` (i.e. `(lon, lat)`) `f64`s
+fn to_xy(ring: &[(Lat, Lon)]) -> Vec<(f64, f64)> {
+    ring.iter()
+        .map(|(lat, lon)| ((*lon).into(), (*lat).into()))
+        .collect()
+}
+
+/// Twice the signed area of this ring. Positive for counter-clockwise rings.
+fn signed_area(ring: &[(f64, f64)]) -> f64 {
+    ring.windows(2)
+        .map(|w| w[0].0 * w[1].1 - w[1].0 * w[0].1)
+        .sum()
+}
+
+/// Reverse the ring, if needed, so that it's counter-clockwise (`ccw`) or clockwise.
+fn oriented(mut ring: Ring, ccw: bool) -> Ring {
+    if (signed_area(&to_xy(&ring)) > 0.) != ccw {
+        ring.reverse();
+    }
+    ring
+}
+
+/// Index of the smallest ring which contains ring `idx`.
+fn smallest_containing_ring(idx: usize, rings: &[Vec<(f64, f64)>]) -> Option<usize> {
+    let ring = &rings[idx];
+    rings
+        .iter()
+        .enumerate()
+        .filter(|(i, other)| {
+            *i != idx
+                && ring
+                    .iter()
+                    .find(|p| !other.contains(p))
+                    .is_some_and(|p| point_in_ring(*p, other))
+        })
+        .min_by(|(_, a), (_, b)| {
+            signed_area(a)
+                .abs()
+                .partial_cmp(&signed_area(b).abs())
+                .unwrap()
+        })
+        .map(|(i, _)| i)
+}
+
+/// Report everywhere segments of `a` and `b` cross. If `same` then `a` and `b` are the same ring,
+/// and adjacent segments are ignored.
+fn find_intersections(
+    a: &[(f64, f64)],
+    b: &[(f64, f64)],
+    same: bool,
+    problems: &mut Vec<AreaProblem>,
+) {
+    let num_segs = a.len().saturating_sub(1);
+    for i in 0..num_segs {
+        let start_j = if same { i + 2 } else { 0 };
+        for j in start_j..b.len().saturating_sub(1) {
+            if same && i == 0 && j == num_segs - 1 {
+                continue;
+            }
+            if let Some((x, y)) = segment_intersection(a[i], a[i + 1], b[j], b[j + 1]) {
+                problems.push(AreaProblem::SelfIntersection { location: (y, x) });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use obj_types::StringRelation;
+    use obj_types::StringRelationBuilder;
+    use OSMObjectType::Way;
+
+    fn relation(members: &[(ObjId, &str)]) -> StringRelation {
+        StringRelationBuilder::default()
+            ._id(1)
+            ._members(
+                members
+                    .iter()
+                    .map(|(id, role)| (Way, *id, role.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+            ._tags(vec![("type".to_string(), "multipolygon".to_string())])
+            .build()
+            .unwrap()
+    }
+
+    /// Node `10*x+y` is at `(y, x)`, for `x` & `y` from 0 to 9
+    fn grid() -> SparseNodeLocations {
+        let mut locations = SparseNodeLocations::new();
+        for x in 0..10 {
+            for y in 0..10 {
+                locations.set(
+                    10 * x + y,
+                    (Lat::from_inner(y as i32), Lon::from_inner(x as i32)),
+                );
+            }
+        }
+        locations
+    }
+
+    #[test]
+    fn square_with_hole() {
+        let mut way_nodes = HashMap::new();
+        // outer in 2 halves, in opposite directions
+        way_nodes.insert(1, vec![0, 90, 99]);
+        way_nodes.insert(2, vec![0, 9, 99]);
+        way_nodes.insert(3, vec![33, 36, 66, 63, 33]);
+        let rel = relation(&[(1, "outer"), (2, "outer"), (3, "outer")]);
+        assert!(is_area_relation(&rel));
+
+        let area = assemble_multipolygon(&rel, &way_nodes, &grid());
+        assert_eq!(area.polygons.len(), 1);
+        let polygon = &area.polygons[0];
+        assert_eq!(polygon.outer.len(), 5);
+        assert_eq!(polygon.inners.len(), 1);
+        assert!(signed_area(&to_xy(&polygon.outer)) > 0.);
+        assert!(signed_area(&to_xy(&polygon.inners[0])) < 0.);
+        assert_eq!(
+            area.problems,
+            vec![AreaProblem::WrongRole {
+                way_id: 3,
+                given_role: "outer".to_string(),
+                actual_role: "inner"
+            }]
+        );
+        assert!(area.is_valid());
+    }
+
+    #[test]
+    fn problems() {
+        let mut way_nodes = HashMap::new();
+        way_nodes.insert(1, vec![0, 90, 99]);
+        // bowtie
+        way_nodes.insert(2, vec![33, 66, 36, 63, 33]);
+        let rel = relation(&[(1, ""), (2, ""), (3, "")]);
+
+        let area = assemble_multipolygon(&rel, &way_nodes, &grid());
+        assert!(!area.is_valid());
+        assert!(area.problems.contains(&AreaProblem::MissingWay(3)));
+        assert!(area.problems.contains(&AreaProblem::UnclosedRing {
+            ways: vec![1],
+            start_node: 0,
+            end_node: 99
+        }));
+        assert!(area
+            .problems
+            .iter()
+            .any(|p| matches!(p, AreaProblem::SelfIntersection { .. })));
+    }
+}
@@ -0,0 +1,87 @@
+//! Transforming coordinates when exporting, e.g. to Web Mercator.
+//!
+//! The exporters ([`GeoJSONWriter`](super::geojson::GeoJSONWriter) &
+//! [`ChangesetCsvWriter`](crate::changeset_export::ChangesetCsvWriter)) write WGS84 degrees by
+//! default. Give them a [`CoordTransform`] to write other coordinates. Any
+//! `Fn((Lat, Lon)) -> (f64, f64)` is a transform, so other projections (e.g. with the `proj`
+//! crate) can be plugged in.
+//!
+//! ```rust
+//! use osmio::geometry::projection::{CoordTransform, WebMercator};
+//! use osmio::{Lat, Lon};
+//!
+//! let (x, y) = WebMercator.transform((Lat::from_inner(0), Lon::from_inner(1_800_000_000)));
+//! assert_eq!((x.round(), y.round()), (20_037_508., 0.));
+//! ```
+use std::f64::consts::PI;
+use {Lat, Lon};
+
+/// Converts a location to the `(x, y)` coordinates which are written
+pub trait CoordTransform {
+    fn transform(&self, loc: (Lat, Lon)) -> (f64, f64);
+}
+
+impl<F> CoordTransform for F
+where
+    F: Fn((Lat, Lon)) -> (f64, f64),
+{
+    fn transform(&self, loc: (Lat, Lon)) -> (f64, f64) {
+        self(loc)
+    }
+}
+
+/// No transformation, `(lon, lat)` in degrees
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Wgs84;
+
+impl CoordTransform for Wgs84 {
+    fn transform(&self, (lat, lon): (Lat, Lon)) -> (f64, f64) {
+        (lon.degrees(), lat.degrees())
+    }
+}
+
+/// Web Mercator (EPSG:3857) metres. Latitudes beyond ±85.0511° are clamped to the edge of the
+/// map.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebMercator;
+
+/// Radius of the sphere used for Web Mercator, in metres
+pub const EARTH_RADIUS: f64 = 6_378_137.;
+
+/// The latitude of the top (& bottom) edge of the Web Mercator map
+pub const MAX_MERCATOR_LAT: f64 = 85.051_128_779_806_59;
+
+impl CoordTransform for WebMercator {
+    fn transform(&self, (lat, lon): (Lat, Lon)) -> (f64, f64) {
+        let lat = lat.degrees().clamp(-MAX_MERCATOR_LAT, MAX_MERCATOR_LAT);
+        let x = EARTH_RADIUS * lon.degrees().to_radians();
+        let y = EARTH_RADIUS * (PI / 4. + lat.to_radians() / 2.).tan().ln();
+        (x, y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(lat: f64, lon: f64) -> (Lat, Lon) {
+        (
+            Lat::from_inner((lat * 1e7) as i32),
+            Lon::from_inner((lon * 1e7) as i32),
+        )
+    }
+
+    #[test]
+    fn transforms() {
+        assert_eq!(Wgs84.transform(loc(1.5, -2.)), (-2., 1.5));
+
+        let (x, y) = WebMercator.transform(loc(51.5, -0.25));
+        assert!((x - -27_829.87).abs() < 0.01, "{}", x);
+        assert!((y - 6_710_219.08).abs() < 0.01, "{}", y);
+        let (_, top) = WebMercator.transform(loc(90., 0.));
+        assert!((top - 20_037_508.34).abs() < 0.01, "{}", top);
+
+        let double = |(lat, lon): (Lat, Lon)| (lon.degrees() * 2., lat.degrees() * 2.);
+        assert_eq!(double.transform(loc(1., 2.)), (4., 2.));
+    }
+}
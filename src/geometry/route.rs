@@ -0,0 +1,451 @@
+//! Assembling public transport routes from `type=route` & `type=route_master` relations
+//! ([PTv2](https://wiki.openstreetmap.org/wiki/Public_transport)).
+//!
+//! A PTv2 route relation lists its stops & platforms in order, and then the ways it travels
+//! along, in order. [`assemble_route`] joins the ways into continuous lines (reversing ways, and
+//! going part way round roundabouts as needed), and reports gaps & badly ordered members. A
+//! `route_master` groups the routes (e.g. each direction) of one line, see
+//! [`assemble_route_master`].
+//!
+//! ```no_run
+//! use osmio::geometry::route::{assemble_route, is_route_relation};
+//! use osmio::node_locations::{NodeLocations, SparseNodeLocations};
+//! use osmio::prelude::*;
+//! use osmio::OSMObjBase;
+//! use std::collections::HashMap;
+//!
+//! // Read the file once for the node locations & way nodes
+//! let mut locations = SparseNodeLocations::new();
+//! let mut way_nodes = HashMap::new();
+//! let mut reader = osmio::read_pbf("input.osm.pbf")?;
+//! for obj in reader.objects() {
+//!     if let Some(node) = obj.as_node() {
+//!         locations.add_node(node);
+//!     } else if let Some(way) = obj.as_way() {
+//!         way_nodes.insert(way.id(), way.nodes().to_vec());
+//!     } else if let Some(relation) = obj.as_relation() {
+//!         if is_route_relation(relation) {
+//!             let route = assemble_route(relation, &way_nodes, &locations);
+//!             println!("r{}: {} parts, {} stops", route.relation_id, route.segments.len(), route.stops.len());
+//!         }
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use node_locations::NodeLocations;
+use std::collections::HashMap;
+use {Lat, Lon, OSMObjectType, ObjId, Relation};
+
+/// Something wrong with a route relation, found while assembling it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteProblem {
+    /// This member way isn't available
+    MissingWay(ObjId),
+    /// The location of this node isn't available
+    MissingNode(ObjId),
+    /// These 2 consecutive ways aren't connected, so the route has a gap
+    Gap { from_way: ObjId, to_way: ObjId },
+    /// This stop or platform is after the first way, but PTv2 has all the stops & platforms first
+    StopAfterWays {
+        member_type: OSMObjectType,
+        member_id: ObjId,
+    },
+    /// This stop node isn't on any of the route's ways
+    StopNotOnRoute(ObjId),
+    /// This stop node is before the previous stop along the route
+    StopOutOfOrder(ObjId),
+    /// This member of a route master isn't a route relation
+    NotARoute {
+        member_type: OSMObjectType,
+        member_id: ObjId,
+    },
+    /// This route has a different `route` tag to its route master
+    RouteTypeMismatch(ObjId),
+}
+
+/// A stop or platform of a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stop {
+    pub member_type: OSMObjectType,
+    pub member_id: ObjId,
+    /// e.g. `stop`, `platform` or `stop_exit_only`
+    pub role: String,
+    /// The location, for nodes (if it's available)
+    pub location: Option<(Lat, Lon)>,
+}
+
+impl Stop {
+    /// True for `platform*` roles, false for `stop*` roles
+    pub fn is_platform(&self) -> bool {
+        self.role.starts_with("platform")
+    }
+}
+
+/// A continuous part of a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteSegment {
+    /// The ways, and whether each is travelled against its direction
+    pub ways: Vec<(ObjId, bool)>,
+    /// The nodes in the order they're travelled along
+    pub nodes: Vec<ObjId>,
+    /// The locations of the nodes (nodes whose locations aren't available are left out)
+    pub locations: Vec<(Lat, Lon)>,
+}
+
+/// The result of assembling a route relation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub relation_id: ObjId,
+    /// The `route` tag, e.g. `bus`
+    pub route_type: Option<String>,
+    /// The continuous parts of the route. There's more than one if there are gaps.
+    pub segments: Vec<RouteSegment>,
+    /// The stops & platforms, in order
+    pub stops: Vec<Stop>,
+    pub problems: Vec<RouteProblem>,
+}
+
+impl Route {
+    /// True iff the route was assembled into one continuous line, without any problems
+    pub fn is_valid(&self) -> bool {
+        self.segments.len() == 1 && self.problems.is_empty()
+    }
+}
+
+/// True iff this is a `type=route` relation
+pub fn is_route_relation(relation: &impl Relation) -> bool {
+    relation.tag("type") == Some("route")
+}
+
+/// True iff this is a `type=route_master` relation
+pub fn is_route_master(relation: &impl Relation) -> bool {
+    relation.tag("type") == Some("route_master")
+}
+
+fn is_stop_role(role: &str) -> bool {
+    role.starts_with("stop") || role.starts_with("platform")
+}
+
+/// The nodes of a closed way, going round from `start`, to the first node which is an end of
+/// `next` (or all the way round, if there's none)
+fn round_closed_way(nodes: &[ObjId], start: usize, next: Option<&[ObjId]>) -> Vec<ObjId> {
+    let ring = &nodes[..nodes.len() - 1];
+    let mut result = vec![ring[start]];
+    for i in 1..=ring.len() {
+        let nid = ring[(start + i) % ring.len()];
+        result.push(nid);
+        if next.is_some_and(|next| next[0] == nid || next[next.len() - 1] == nid) {
+            break;
+        }
+    }
+    result
+}
+
+/// Assemble this route relation.
+///
+/// `way_nodes` has the node ids of the member ways, and `locations` the locations of the nodes.
+/// Any problems are reported in [`Route::problems`], and as much of the route as possible is
+/// assembled.
+pub fn assemble_route(
+    relation: &impl Relation,
+    way_nodes: &HashMap<ObjId, Vec<ObjId>>,
+    locations: &impl NodeLocations,
+) -> Route {
+    let mut problems = Vec::new();
+    let mut stops = Vec::new();
+    let mut ways = Vec::new();
+    let mut seen_way = false;
+    for (member_type, member_id, role) in relation.members() {
+        if is_stop_role(role) {
+            if seen_way {
+                problems.push(RouteProblem::StopAfterWays {
+                    member_type,
+                    member_id,
+                });
+            }
+            stops.push(Stop {
+                member_type,
+                member_id,
+                role: role.to_string(),
+                location: match member_type {
+                    OSMObjectType::Node => locations.get(member_id),
+                    _ => None,
+                },
+            });
+        } else if member_type == OSMObjectType::Way {
+            seen_way = true;
+            match way_nodes.get(&member_id) {
+                None => problems.push(RouteProblem::MissingWay(member_id)),
+                Some(nodes) if nodes.len() < 2 => {}
+                Some(nodes) => ways.push((member_id, nodes.as_slice())),
+            }
+        }
+    }
+
+    let mut segments: Vec<RouteSegment> = Vec::new();
+    let mut current: Option<RouteSegment> = None;
+    for (i, &(way_id, nodes)) in ways.iter().enumerate() {
+        let next = ways.get(i + 1).map(|(_, nodes)| *nodes);
+        let is_closed = nodes[0] == nodes[nodes.len() - 1];
+        if let Some(segment) = current.as_mut() {
+            let end = segment.nodes[segment.nodes.len() - 1];
+            if segment.ways.len() == 1 && !nodes.contains(&end) && nodes.contains(&segment.nodes[0])
+            {
+                // The first way is travelled backwards
+                segment.nodes.reverse();
+                segment.ways[0].1 = !segment.ways[0].1;
+            }
+            let end = segment.nodes[segment.nodes.len() - 1];
+            if nodes[0] == end && !is_closed {
+                segment.nodes.extend_from_slice(&nodes[1..]);
+                segment.ways.push((way_id, false));
+                continue;
+            } else if nodes[nodes.len() - 1] == end && !is_closed {
+                segment.nodes.extend(nodes.iter().rev().skip(1));
+                segment.ways.push((way_id, true));
+                continue;
+            } else if let Some(start) = nodes.iter().position(|n| *n == end).filter(|_| is_closed) {
+                segment
+                    .nodes
+                    .extend(round_closed_way(nodes, start, next).into_iter().skip(1));
+                segment.ways.push((way_id, false));
+                continue;
+            }
+            problems.push(RouteProblem::Gap {
+                from_way: segment.ways[segment.ways.len() - 1].0,
+                to_way: way_id,
+            });
+        }
+        segments.extend(current.take());
+        current = Some(RouteSegment {
+            ways: vec![(way_id, false)],
+            nodes: nodes.to_vec(),
+            locations: Vec::new(),
+        });
+    }
+    segments.extend(current);
+
+    for segment in segments.iter_mut() {
+        for nid in segment.nodes.iter() {
+            match locations.get(*nid) {
+                Some(loc) => segment.locations.push(loc),
+                None => problems.push(RouteProblem::MissingNode(*nid)),
+            }
+        }
+    }
+    problems.dedup();
+
+    // Check the stop nodes are on the route, in order
+    let route_nodes: Vec<ObjId> = segments
+        .iter()
+        .flat_map(|s| s.nodes.iter().copied())
+        .collect();
+    let mut last_position = 0;
+    for stop in stops.iter() {
+        if stop.is_platform() || stop.member_type != OSMObjectType::Node {
+            continue;
+        }
+        match route_nodes[last_position..]
+            .iter()
+            .position(|n| *n == stop.member_id)
+        {
+            Some(position) => last_position += position,
+            None if route_nodes.contains(&stop.member_id) => {
+                problems.push(RouteProblem::StopOutOfOrder(stop.member_id))
+            }
+            None => problems.push(RouteProblem::StopNotOnRoute(stop.member_id)),
+        }
+    }
+
+    Route {
+        relation_id: relation.id(),
+        route_type: relation.tag("route").map(String::from),
+        segments,
+        stops,
+        problems,
+    }
+}
+
+/// The routes of a `route_master` relation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMaster {
+    pub relation_id: ObjId,
+    /// The `route_master` tag, e.g. `bus`
+    pub route_type: Option<String>,
+    /// The ids of the member routes, in order
+    pub routes: Vec<ObjId>,
+    pub problems: Vec<RouteProblem>,
+}
+
+/// Check the members of this route master relation, which should all be routes of the same type.
+/// `routes` has the assembled routes (at least the members).
+pub fn assemble_route_master(
+    relation: &impl Relation,
+    routes: &HashMap<ObjId, Route>,
+) -> RouteMaster {
+    let route_type = relation.tag("route_master").map(String::from);
+    let mut problems = Vec::new();
+    let mut members = Vec::new();
+    for (member_type, member_id, _role) in relation.members() {
+        let route = match member_type {
+            OSMObjectType::Relation => routes.get(&member_id),
+            _ => None,
+        };
+        match route {
+            None => problems.push(RouteProblem::NotARoute {
+                member_type,
+                member_id,
+            }),
+            Some(route) => {
+                if route.route_type != route_type {
+                    problems.push(RouteProblem::RouteTypeMismatch(member_id));
+                }
+                members.push(member_id);
+            }
+        }
+    }
+    RouteMaster {
+        relation_id: relation.id(),
+        route_type,
+        routes: members,
+        problems,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use obj_types::StringRelationBuilder;
+
+    fn relation(tags: &[(&str, &str)], members: &[(OSMObjectType, ObjId, &str)]) -> impl Relation {
+        StringRelationBuilder::default()
+            ._id(100)
+            ._tags(
+                tags.iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+            ._members(
+                members
+                    .iter()
+                    .map(|(t, id, role)| (*t, *id, role.to_string()))
+                    .collect::<Vec<_>>(),
+            )
+            .build()
+            .unwrap()
+    }
+
+    fn setup() -> (HashMap<ObjId, Vec<ObjId>>, SparseNodeLocations) {
+        let mut locations = SparseNodeLocations::new();
+        for nid in 1..=20 {
+            locations.set(nid, (Lat::from_inner(0), Lon::from_inner(nid as i32)));
+        }
+        let mut way_nodes = HashMap::new();
+        way_nodes.insert(1, vec![1, 2, 3]);
+        // Reversed
+        way_nodes.insert(2, vec![5, 4, 3]);
+        // A roundabout, entered at 6 and left at 8
+        way_nodes.insert(3, vec![6, 7, 8, 9, 6]);
+        way_nodes.insert(4, vec![5, 6]);
+        way_nodes.insert(5, vec![8, 10]);
+        way_nodes.insert(6, vec![12, 13]);
+        (way_nodes, locations)
+    }
+
+    #[test]
+    fn route() {
+        let (way_nodes, locations) = setup();
+        let r = relation(
+            &[("type", "route"), ("route", "bus")],
+            &[
+                (OSMObjectType::Node, 2, "stop"),
+                (OSMObjectType::Way, 20, "platform"),
+                (OSMObjectType::Node, 10, "stop_exit_only"),
+                (OSMObjectType::Way, 1, ""),
+                (OSMObjectType::Way, 2, ""),
+                (OSMObjectType::Way, 4, ""),
+                (OSMObjectType::Way, 3, ""),
+                (OSMObjectType::Way, 5, ""),
+            ],
+        );
+        let route = assemble_route(&r, &way_nodes, &locations);
+        assert_eq!(route.problems, vec![]);
+        assert!(route.is_valid());
+        assert_eq!(route.route_type.as_deref(), Some("bus"));
+        assert_eq!(route.segments[0].nodes, vec![1, 2, 3, 4, 5, 6, 7, 8, 10]);
+        assert_eq!(
+            route.segments[0].ways,
+            vec![(1, false), (2, true), (4, false), (3, false), (5, false)]
+        );
+        assert_eq!(route.segments[0].locations.len(), 9);
+        assert_eq!(route.stops.len(), 3);
+        assert!(route.stops[1].is_platform());
+        assert_eq!(route.stops[0].location, locations.get(2));
+
+        // Starting with a backwards way, with a gap, a stop out of order & a stop after the ways
+        let r = relation(
+            &[("type", "route"), ("route", "tram")],
+            &[
+                (OSMObjectType::Node, 5, "stop"),
+                (OSMObjectType::Node, 4, "stop"),
+                (OSMObjectType::Way, 2, ""),
+                (OSMObjectType::Way, 4, ""),
+                (OSMObjectType::Way, 6, ""),
+                (OSMObjectType::Node, 19, "stop"),
+            ],
+        );
+        let route = assemble_route(&r, &way_nodes, &locations);
+        assert_eq!(route.segments.len(), 2);
+        assert_eq!(route.segments[0].nodes, vec![3, 4, 5, 6]);
+        assert_eq!(route.segments[0].ways, vec![(2, true), (4, false)]);
+        assert_eq!(route.segments[1].nodes, vec![12, 13]);
+        assert_eq!(
+            route.problems,
+            vec![
+                RouteProblem::StopAfterWays {
+                    member_type: OSMObjectType::Node,
+                    member_id: 19
+                },
+                RouteProblem::Gap {
+                    from_way: 4,
+                    to_way: 6
+                },
+                RouteProblem::StopOutOfOrder(4),
+                RouteProblem::StopNotOnRoute(19),
+            ]
+        );
+    }
+
+    #[test]
+    fn route_master() {
+        let (way_nodes, locations) = setup();
+        let mut routes = HashMap::new();
+        for (id, route_type) in [(1, "bus"), (2, "tram")].iter() {
+            let r = relation(&[("type", "route"), ("route", *route_type)], &[]);
+            let mut route = assemble_route(&r, &way_nodes, &locations);
+            route.relation_id = *id;
+            routes.insert(*id, route);
+        }
+        let r = relation(
+            &[("type", "route_master"), ("route_master", "bus")],
+            &[
+                (OSMObjectType::Relation, 1, ""),
+                (OSMObjectType::Relation, 2, ""),
+                (OSMObjectType::Way, 1, ""),
+            ],
+        );
+        let master = assemble_route_master(&r, &routes);
+        assert_eq!(master.routes, vec![1, 2]);
+        assert_eq!(
+            master.problems,
+            vec![
+                RouteProblem::RouteTypeMismatch(2),
+                RouteProblem::NotARoute {
+                    member_type: OSMObjectType::Way,
+                    member_id: 1
+                },
+            ]
+        );
+    }
+}
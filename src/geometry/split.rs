@@ -0,0 +1,263 @@
+//! Splitting ways at junctions, and finding ways which cross themselves.
+//!
+//! Routing graphs (and many QA checks) need ways which only meet other ways at their ends.
+//! [`WaySplitter`] uses a [`NodeWayIndex`] to find the nodes which are shared with other ways, and
+//! splits ways there. The first part keeps the id of the original way, and the other parts get new
+//! (negative) ids. Use a [`Renumberer`](crate::renumber::Renumberer) to give them positive ids.
+//!
+//! ```rust,no_run
+//! use osmio::geometry::split::WaySplitter;
+//! use osmio::node_ways::NodeWayIndex;
+//! use osmio::OSMReader;
+//!
+//! let index = NodeWayIndex::from_reader(&mut osmio::read_pbf("input.osm.pbf")?)?;
+//! let mut splitter = WaySplitter::new(&index);
+//! for obj in osmio::read_pbf("input.osm.pbf")?.objects() {
+//!     for part in splitter.split_obj(obj)? {
+//!         // ...
+//!     }
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use super::segment_intersection;
+use node_locations::NodeLocations;
+use node_ways::NodeWayIndex;
+use std::collections::HashMap;
+use {OSMObj, ObjId, Way};
+
+use anyhow::Result;
+
+/// Split this list of nodes at these positions. Parts share the node they're split at.
+fn parts(nodes: &[ObjId], at: &[usize]) -> Vec<Vec<ObjId>> {
+    let mut parts = Vec::with_capacity(at.len() + 1);
+    let mut start = 0;
+    for &end in at
+        .iter()
+        .chain(std::iter::once(&(nodes.len().saturating_sub(1))))
+    {
+        if end > start {
+            parts.push(nodes[start..=end].to_vec());
+            start = end;
+        }
+    }
+    if parts.is_empty() {
+        parts.push(nodes.to_vec());
+    }
+    parts
+}
+
+/// Split `way` at these (sorted) node positions. The first part keeps the id of `way`, and the
+/// other parts get ids from `new_id`. Positions at the start or end of the way are ignored.
+pub fn split_way<W: Way>(way: &W, at: &[usize], mut new_id: impl FnMut() -> ObjId) -> Vec<W> {
+    parts(way.nodes(), at)
+        .into_iter()
+        .enumerate()
+        .map(|(i, nodes)| {
+            let mut part = way.clone();
+            if i > 0 {
+                part.set_id(new_id());
+            }
+            part.set_nodes(nodes);
+            part
+        })
+        .collect()
+}
+
+/// Splits ways at the nodes they share with other ways (or with themselves).
+pub struct WaySplitter<'a> {
+    index: &'a NodeWayIndex,
+    next_id: ObjId,
+}
+
+impl<'a> WaySplitter<'a> {
+    /// New ways get ids counting down from -1
+    pub fn new(index: &'a NodeWayIndex) -> Self {
+        WaySplitter { index, next_id: -1 }
+    }
+
+    fn new_id(&mut self) -> ObjId {
+        let id = self.next_id;
+        self.next_id -= 1;
+        id
+    }
+
+    /// The positions of the nodes in this way (other than the first & last) which are junctions:
+    /// they're in another way too, or are in this way more than once.
+    pub fn split_points(&self, way: &impl Way) -> Result<Vec<usize>> {
+        let nodes = way.nodes();
+        let mut counts: HashMap<ObjId, usize> = HashMap::new();
+        for nid in nodes {
+            *counts.entry(*nid).or_default() += 1;
+        }
+        let mut points = Vec::new();
+        for (i, nid) in nodes.iter().enumerate().skip(1) {
+            if i == nodes.len() - 1 {
+                break;
+            }
+            if counts[nid] > 1 || self.index.num_ways(*nid)? > 1 {
+                points.push(i);
+            }
+        }
+        Ok(points)
+    }
+
+    /// Split this way at its junctions. Ways without junctions are returned unchanged.
+    pub fn split<W: Way>(&mut self, way: &W) -> Result<Vec<W>> {
+        let points = self.split_points(way)?;
+        Ok(split_way(way, &points, || self.new_id()))
+    }
+
+    /// Split this object, if it's a way. Other objects are returned unchanged.
+    pub fn split_obj<O: OSMObj>(&mut self, obj: O) -> Result<Vec<O>> {
+        let (nodes, points) = match obj.as_way() {
+            Some(way) => (way.nodes().to_vec(), self.split_points(way)?),
+            None => return Ok(vec![obj]),
+        };
+        if points.is_empty() {
+            return Ok(vec![obj]);
+        }
+        let mut result = Vec::with_capacity(points.len() + 1);
+        for (i, nodes) in parts(&nodes, &points).into_iter().enumerate() {
+            let mut part = obj.clone();
+            if i > 0 {
+                part.set_id(self.new_id());
+            }
+            part.as_way_mut().unwrap().set_nodes(nodes);
+            result.push(part);
+        }
+        Ok(result)
+    }
+}
+
+/// Where a way crosses itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfIntersection {
+    pub way_id: ObjId,
+    /// The positions of the 2 crossing segments. Segment `i` goes from node `i` to node `i + 1`.
+    pub segments: (usize, usize),
+    /// `(lat, lon)` of the crossing
+    pub location: (f64, f64),
+}
+
+/// Everywhere this way crosses itself. Touching (i.e. using the same node twice) isn't
+/// crossing. Returns `None` if the location of any node is missing.
+pub fn self_intersections(
+    way: &impl Way,
+    locations: &impl NodeLocations,
+) -> Option<Vec<SelfIntersection>> {
+    let coords: Vec<(f64, f64)> = locations
+        .get_all(way.nodes())?
+        .into_iter()
+        .map(|(lat, lon)| (lon.degrees(), lat.degrees()))
+        .collect();
+    let num_segs = coords.len().saturating_sub(1);
+    let mut result = Vec::new();
+    for i in 0..num_segs {
+        for j in (i + 2)..num_segs {
+            // The first & last segments of a closed way are adjacent
+            if i == 0 && j == num_segs - 1 && way.is_closed() {
+                continue;
+            }
+            if let Some((x, y)) =
+                segment_intersection(coords[i], coords[i + 1], coords[j], coords[j + 1])
+            {
+                result.push(SelfIntersection {
+                    way_id: way.id(),
+                    segments: (i, j),
+                    location: (y, x),
+                });
+            }
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use node_ways::NodeWayIndexBuilder;
+    use obj_types::{StringNodeBuilder, StringOSMObj, StringWay, StringWayBuilder};
+    use {Lat, Lon, OSMObjBase, OSMObjectType};
+
+    fn way(id: ObjId, nodes: Vec<ObjId>) -> StringWay {
+        StringWayBuilder::default()
+            ._id(id)
+            ._nodes(nodes)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn split() {
+        let ways = [
+            way(1, vec![1, 2, 3, 4, 5]),
+            way(2, vec![10, 3, 11]),
+            way(3, vec![5, 12]),
+            way(4, vec![20, 21, 22, 21, 23]),
+        ];
+        let mut builder = NodeWayIndexBuilder::new();
+        for w in ways.iter() {
+            builder.add_way(w).unwrap();
+        }
+        let index = builder.finish().unwrap();
+        let mut splitter = WaySplitter::new(&index);
+
+        // Node 5 is shared, but it's the end
+        assert_eq!(splitter.split_points(&ways[0]).unwrap(), vec![2]);
+        let parts = splitter.split(&ways[0]).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!((parts[0].id(), parts[0].nodes()), (1, &[1, 2, 3][..]));
+        assert_eq!((parts[1].id(), parts[1].nodes()), (-1, &[3, 4, 5][..]));
+
+        let parts = splitter.split(&ways[2]).unwrap();
+        assert_eq!(parts, vec![ways[2].clone()]);
+
+        let parts = splitter.split(&ways[3]).unwrap();
+        let nodes: Vec<_> = parts.iter().map(|w| (w.id(), w.nodes().to_vec())).collect();
+        assert_eq!(
+            nodes,
+            vec![
+                (4, vec![20, 21]),
+                (-2, vec![21, 22, 21]),
+                (-3, vec![21, 23])
+            ]
+        );
+
+        let node: StringOSMObj = StringNodeBuilder::default()._id(3).build().unwrap().into();
+        assert_eq!(splitter.split_obj(node.clone()).unwrap(), vec![node]);
+        let parts = splitter
+            .split_obj(StringOSMObj::from(ways[1].clone()))
+            .unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[1].object_type(), OSMObjectType::Way);
+        assert_eq!(parts[1].id(), -4);
+        assert_eq!(parts[1].as_way().unwrap().nodes(), &[3, 11]);
+    }
+
+    #[test]
+    fn self_intersecting() {
+        let mut locations = SparseNodeLocations::new();
+        for (id, lat, lon) in [(1, 0, 0), (2, 0, 10), (3, 10, 0), (4, 10, 10), (5, 5, 20)] {
+            locations.set(id, (Lat::from_inner(lat), Lon::from_inner(lon)));
+        }
+
+        // A bowtie
+        let bowtie = way(1, vec![1, 2, 3, 4, 1]);
+        let crossings = self_intersections(&bowtie, &locations).unwrap();
+        assert_eq!(crossings.len(), 1);
+        assert_eq!(crossings[0].segments, (1, 3));
+        let (lat, lon) = crossings[0].location;
+        assert!((lat - 5e-7).abs() < 1e-12 && (lon - 5e-7).abs() < 1e-12);
+
+        // A square is fine
+        let square = way(2, vec![1, 2, 4, 3, 1]);
+        assert_eq!(self_intersections(&square, &locations).unwrap(), vec![]);
+
+        // Touching itself isn't crossing
+        let touching = way(3, vec![1, 2, 5, 4, 2, 3]);
+        assert_eq!(self_intersections(&touching, &locations).unwrap(), vec![]);
+
+        assert_eq!(self_intersections(&way(4, vec![1, 99]), &locations), None);
+    }
+}
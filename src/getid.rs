@@ -0,0 +1,204 @@
+//! Getting objects by id, along with the objects they refer to.
+//!
+//! Like `osmium getid --add-referenced`, this turns a set of “seed” ids into a self-consistent
+//! set: relations have all their members, and ways have all their nodes. Since relations can
+//! refer to relations which appear later in the file, this can take several passes over the file.
+//!
+//! ```no_run
+//! use osmio::getid::{add_referenced, write_ids};
+//! use osmio::idset::IdSet;
+//! use osmio::xml::XMLWriter;
+//! use osmio::OSMWriter;
+//!
+//! let mut ids: IdSet = "r62149\n".parse()?;
+//! add_referenced(|| osmio::read_pbf("planet.osm.pbf"), &mut ids)?;
+//! let mut writer = XMLWriter::new(std::fs::File::create("output.osm")?);
+//! write_ids(|| osmio::read_pbf("planet.osm.pbf"), &ids, &mut writer)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use idset::IdSet;
+use std::io::Write;
+use {OSMObj, OSMObjBase, OSMObjectType, OSMReader, OSMWriter, Relation, Way};
+
+use anyhow::Result;
+
+/// Add every object referred to by the objects in `ids` (recursively) to `ids`.
+///
+/// Relation members (including members of member relations) and way nodes are added. `open` is
+/// called to open a new reader for the file for each pass. The file must be sorted, so readers
+/// can skip the parts of the file which aren't needed.
+pub fn add_referenced<F, R>(mut open: F, ids: &mut IdSet) -> Result<()>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+{
+    // Relations, until no new relations are added
+    loop {
+        let num_relations = ids.len_of_type(OSMObjectType::Relation);
+        if num_relations == 0 {
+            break;
+        }
+        let mut reader = open()?;
+        reader.assume_sorted();
+        for obj in reader.try_objects() {
+            if let Some(relation) = obj?.as_relation() {
+                if ids.contains_obj(relation) {
+                    for (member_type, member_id, _role) in relation.members() {
+                        ids.insert(member_type, member_id);
+                    }
+                }
+            }
+        }
+        if ids.len_of_type(OSMObjectType::Relation) == num_relations {
+            break;
+        }
+    }
+
+    if ids.len_of_type(OSMObjectType::Way) > 0 {
+        let mut reader = open()?;
+        reader.assume_sorted();
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            if let Some(way) = obj.as_way() {
+                if ids.contains_obj(way) {
+                    ids.extend(way.nodes().iter().map(|nid| (OSMObjectType::Node, *nid)));
+                }
+            } else if obj.object_type() == OSMObjectType::Relation {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Add every object which refers to the objects in `ids` (recursively) to `ids`.
+///
+/// Ways which contain any of the nodes are added, as are relations which have any of the objects
+/// as members (and their parent relations). Call [`add_referenced`] afterwards to make the added
+/// ways & relations complete.
+pub fn add_referrers<F, R>(mut open: F, ids: &mut IdSet) -> Result<()>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+{
+    if ids.len_of_type(OSMObjectType::Node) > 0 {
+        let mut new_ways = Vec::new();
+        let mut reader = open()?;
+        reader.assume_sorted();
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            if let Some(way) = obj.as_way() {
+                if way
+                    .nodes()
+                    .iter()
+                    .any(|nid| ids.contains(OSMObjectType::Node, *nid))
+                {
+                    new_ways.push(way.id());
+                }
+            } else if obj.object_type() == OSMObjectType::Relation {
+                break;
+            }
+        }
+        ids.extend(new_ways.into_iter().map(|id| (OSMObjectType::Way, id)));
+    }
+
+    loop {
+        let num_relations = ids.len_of_type(OSMObjectType::Relation);
+        let mut reader = open()?;
+        reader.assume_sorted();
+        for obj in reader.try_objects() {
+            if let Some(relation) = obj?.as_relation() {
+                if !ids.contains_obj(relation)
+                    && relation
+                        .members()
+                        .any(|(member_type, member_id, _)| ids.contains(member_type, member_id))
+                {
+                    ids.insert_obj(relation);
+                }
+            }
+        }
+        if ids.len_of_type(OSMObjectType::Relation) == num_relations {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write every object in `ids` to `writer` (which isn't closed).
+pub fn write_ids<F, R, W>(mut open: F, ids: &IdSet, writer: &mut impl OSMWriter<W>) -> Result<()>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+    W: Write,
+{
+    let mut reader = open()?;
+    for obj in reader.try_objects() {
+        let obj = obj?;
+        if ids.contains_obj(&obj) {
+            writer.write_obj(&obj)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use xml::XMLReader;
+    use OSMObjectType::*;
+
+    /// Relation 20 has relation 21 (which appears later) as a member.
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="1" lon="1"/>
+<node id="2" lat="1" lon="5"/>
+<node id="3" lat="1" lon="9"/>
+<node id="4" lat="9" lon="9"/>
+<way id="10"><nd ref="1"/><nd ref="2"/></way>
+<way id="11"><nd ref="3"/><nd ref="4"/></way>
+<relation id="20"><member type="relation" ref="21" role=""/></relation>
+<relation id="21"><member type="way" ref="11" role=""/><member type="node" ref="1" role=""/></relation>
+<relation id="22"><member type="relation" ref="20" role=""/></relation>
+</osm>"#;
+
+    fn open() -> Result<XMLReader<Cursor<&'static str>>> {
+        Ok(XMLReader::new(Cursor::new(INPUT)))
+    }
+
+    fn ids_of(ids: &IdSet, object_type: OSMObjectType) -> Vec<i64> {
+        ids.ids(object_type).collect()
+    }
+
+    #[test]
+    fn referenced() {
+        let mut ids: IdSet = "r20\n".parse().unwrap();
+        add_referenced(open, &mut ids).unwrap();
+        assert_eq!(ids_of(&ids, Relation), vec![20, 21]);
+        assert_eq!(ids_of(&ids, Way), vec![11]);
+        assert_eq!(ids_of(&ids, Node), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn referrers() {
+        let mut ids: IdSet = "n4\n".parse().unwrap();
+        add_referrers(open, &mut ids).unwrap();
+        assert_eq!(ids_of(&ids, Way), vec![11]);
+        assert_eq!(ids_of(&ids, Relation), vec![20, 21, 22]);
+        assert_eq!(ids_of(&ids, Node), vec![4]);
+    }
+
+    #[test]
+    fn invalid() {
+        let open = || {
+            Ok(XMLReader::new(Cursor::new(
+                r#"<osm version="0.6"><way id="10"><nd ref="1"/></way><way id="x"/></osm>"#,
+            )))
+        };
+        let mut ids: IdSet = "w10\n".parse().unwrap();
+        assert!(add_referenced(open, &mut ids).is_err());
+        assert!(add_referrers(open, &mut "n1\n".parse().unwrap()).is_err());
+    }
+}
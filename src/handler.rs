@@ -0,0 +1,199 @@
+//! Doing several independent things in one pass over a file, like libosmium's handlers.
+//!
+//! Each [`Handler`] is called for every node, way & relation, in file order, and then once with
+//! [`Handler::flush`] at the end. [`apply`] reads the file once, and passes every object to all
+//! the handlers.
+//!
+//! ```no_run
+//! use osmio::handler::{apply, Handler};
+//! use osmio::prelude::*;
+//! use osmio::OSMObjBase;
+//!
+//! #[derive(Default)]
+//! struct CountShops(u64);
+//!
+//! impl<O: OSMObj> Handler<O> for CountShops {
+//!     fn node(&mut self, node: &O::Node) -> anyhow::Result<()> {
+//!         if node.has_tag("shop") {
+//!             self.0 += 1;
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! #[derive(Default)]
+//! struct LongestWay(usize);
+//!
+//! impl<O: OSMObj> Handler<O> for LongestWay {
+//!     fn way(&mut self, way: &O::Way) -> anyhow::Result<()> {
+//!         self.0 = self.0.max(way.num_nodes());
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut shops = CountShops::default();
+//! let mut longest = LongestWay::default();
+//! apply(&mut osmio::read_pbf("input.osm.pbf")?, &mut [&mut shops, &mut longest])?;
+//! println!("{} shops, longest way has {} nodes", shops.0, longest.0);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use {OSMObj, OSMReader};
+
+use anyhow::Result;
+
+/// Something which is given every object in a file. All methods do nothing by default, so only
+/// implement the ones needed.
+///
+/// Returning an error stops [`apply`].
+pub trait Handler<O: OSMObj> {
+    /// Called for every node
+    fn node(&mut self, _node: &O::Node) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every way
+    fn way(&mut self, _way: &O::Way) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for every relation
+    fn relation(&mut self, _relation: &O::Relation) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once after the last object
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<O: OSMObj, H: Handler<O> + ?Sized> Handler<O> for &mut H {
+    fn node(&mut self, node: &O::Node) -> Result<()> {
+        (**self).node(node)
+    }
+    fn way(&mut self, way: &O::Way) -> Result<()> {
+        (**self).way(way)
+    }
+    fn relation(&mut self, relation: &O::Relation) -> Result<()> {
+        (**self).relation(relation)
+    }
+    fn flush(&mut self) -> Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Pass this object to every handler, in order
+pub fn handle_obj<O: OSMObj>(obj: &O, handlers: &mut [&mut dyn Handler<O>]) -> Result<()> {
+    if let Some(node) = obj.as_node() {
+        for handler in handlers.iter_mut() {
+            handler.node(node)?;
+        }
+    } else if let Some(way) = obj.as_way() {
+        for handler in handlers.iter_mut() {
+            handler.way(way)?;
+        }
+    } else if let Some(relation) = obj.as_relation() {
+        for handler in handlers.iter_mut() {
+            handler.relation(relation)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read every object from `reader`, passing each one to all the `handlers` (in order), and then
+/// flush all the handlers.
+///
+/// Stops at the first error from a handler (which isn't flushed then).
+pub fn apply<R: OSMReader>(
+    reader: &mut R,
+    handlers: &mut [&mut dyn Handler<R::Obj>],
+) -> Result<()> {
+    while let Some(obj) = reader.try_next()? {
+        handle_obj(&obj, handlers)?;
+    }
+    for handler in handlers.iter_mut() {
+        handler.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xml::XMLReader;
+    use {OSMObjBase, Way};
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="1" lon="1"><tag k="shop" v="bakery"/></node>
+<node id="2" lat="1" lon="1"/>
+<way id="3"><nd ref="1"/><nd ref="2"/><nd ref="1"/></way>
+<relation id="4"><member type="way" ref="3" role=""/></relation>
+</osm>"#;
+
+    #[derive(Default)]
+    struct Counts {
+        ids: Vec<i64>,
+        flushed: bool,
+    }
+
+    impl<O: OSMObj> Handler<O> for Counts {
+        fn node(&mut self, node: &O::Node) -> Result<()> {
+            self.ids.push(node.id());
+            Ok(())
+        }
+        fn way(&mut self, way: &O::Way) -> Result<()> {
+            self.ids.push(way.id());
+            Ok(())
+        }
+        fn relation(&mut self, relation: &O::Relation) -> Result<()> {
+            self.ids.push(relation.id());
+            Ok(())
+        }
+        fn flush(&mut self) -> Result<()> {
+            self.flushed = true;
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct WayNodes(usize);
+
+    impl<O: OSMObj> Handler<O> for WayNodes {
+        fn way(&mut self, way: &O::Way) -> Result<()> {
+            self.0 += way.num_nodes();
+            Ok(())
+        }
+    }
+
+    struct Fails;
+
+    impl<O: OSMObj> Handler<O> for Fails {
+        fn way(&mut self, _way: &O::Way) -> Result<()> {
+            anyhow::bail!("no ways allowed")
+        }
+    }
+
+    #[test]
+    fn apply_handlers() {
+        let mut counts = Counts::default();
+        let mut way_nodes = WayNodes::default();
+        apply(
+            &mut XMLReader::new(INPUT.as_bytes()),
+            &mut [&mut counts, &mut way_nodes],
+        )
+        .unwrap();
+        assert_eq!(counts.ids, vec![1, 2, 3, 4]);
+        assert!(counts.flushed);
+        assert_eq!(way_nodes.0, 3);
+
+        let mut counts = Counts::default();
+        let res = apply(
+            &mut XMLReader::new(INPUT.as_bytes()),
+            &mut [&mut Fails, &mut counts],
+        );
+        assert!(res.is_err());
+        assert_eq!(counts.ids, vec![1, 2]);
+        assert!(!counts.flushed);
+    }
+}
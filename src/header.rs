@@ -0,0 +1,27 @@
+//! File level metadata, from the header of a file.
+//!
+//! PBF files have a `HeaderBlock` before the objects, and OSM XML files have attributes on the
+//! `<osm>` element and an optional `<bounds>`. [`FileInfo`] has the parts which both can have.
+//! Use [`open::read_file_info`](crate::open::read_file_info) to read it from any file.
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use {BBox, TimestampFormat};
+
+/// The metadata in the header of a file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileInfo {
+    /// The program which wrote the file
+    pub generator: Option<String>,
+    /// The area the file covers, according to the header. Not calculated from the nodes.
+    pub bbox: Option<BBox>,
+    /// The time of the replication diff the data is up to date with
+    pub replication_timestamp: Option<TimestampFormat>,
+    pub replication_sequence: Option<i64>,
+    pub replication_base_url: Option<String>,
+    /// PBF features a reader must support (e.g. `DenseNodes`)
+    pub required_features: Vec<String>,
+    /// PBF features a reader may use (e.g. `Sort.Type_then_ID`)
+    pub optional_features: Vec<String>,
+    /// Any other header values, e.g. XML attributes like `upload`
+    pub other: BTreeMap<String, String>,
+}
@@ -0,0 +1,475 @@
+//! Working with full history files (`.osh`), which have every version of every object.
+//!
+//! The objects must be sorted by type, id & version, as history files are.
+//!
+//! [`TimeSlice`] reconstructs the data as it was at a point in time:
+//!
+//! ```no_run
+//! use osmio::history::TimeSlice;
+//! use osmio::xml::XMLWriter;
+//! use osmio::{OSMReader, OSMWriter, TimestampFormat};
+//!
+//! let mut reader = osmio::read_pbf("history.osh.pbf")?;
+//! let timestamp: TimestampFormat = "2015-01-01T00:00:00Z".parse()?;
+//! let mut writer = XMLWriter::new(std::fs::File::create("2015.osm")?);
+//! for obj in TimeSlice::new(reader.objects(), timestamp) {
+//!     writer.write_obj(&obj)?;
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`LatestVersions`] is the same as a time slice at the end of the file, and can be used to
+//! convert a history file to a normal file.
+//!
+//! [`check_history`] finds anomalies which make historical analyses silently wrong: missing
+//! versions, timestamps which go backwards, and deleted versions which aren't consistent.
+use serde::{Deserialize, Serialize};
+use std::iter::Peekable;
+use {
+    Error, Node, OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, Relation, TimestampFormat,
+    Way,
+};
+
+/// Take all the versions of the next object from `objs`, and return the last one for which
+/// `keep` is true (or `Some(None)` if there's none). Returns `None` at the end of `objs`.
+fn next_version_where<I, O>(
+    objs: &mut Peekable<I>,
+    mut keep: impl FnMut(&O) -> bool,
+) -> Option<Option<O>>
+where
+    I: Iterator<Item = O>,
+    O: OSMObjBase,
+{
+    let first = objs.next()?;
+    let key = (first.object_type(), first.id());
+    let mut chosen = if keep(&first) { Some(first) } else { None };
+    while let Some(obj) = objs.next_if(|o| (o.object_type(), o.id()) == key) {
+        if keep(&obj) {
+            chosen = Some(obj);
+        }
+    }
+    Some(chosen)
+}
+
+/// The objects as they were at a point in time.
+///
+/// For each object, this returns the last version whose timestamp is not after the given
+/// timestamp, unless that version is deleted (or there's no such version, i.e. the object was
+/// created later). Objects without a timestamp are treated as always having existed.
+pub struct TimeSlice<I: Iterator> {
+    objs: Peekable<I>,
+    timestamp: TimestampFormat,
+}
+
+impl<I, O> TimeSlice<I>
+where
+    I: Iterator<Item = O>,
+    O: OSMObjBase,
+{
+    /// The objects from `objs` (e.g. `reader.objects()`) as they were at `timestamp`
+    pub fn new(objs: I, timestamp: TimestampFormat) -> Self {
+        TimeSlice {
+            objs: objs.peekable(),
+            timestamp,
+        }
+    }
+}
+
+impl<I, O> Iterator for TimeSlice<I>
+where
+    I: Iterator<Item = O>,
+    O: OSMObjBase,
+{
+    type Item = O;
+
+    fn next(&mut self) -> Option<O> {
+        let timestamp = &self.timestamp;
+        loop {
+            let version = next_version_where(&mut self.objs, |obj| {
+                obj.timestamp().as_ref().map_or(true, |t| t <= timestamp)
+            })?;
+            match version {
+                Some(obj) if !obj.deleted() => return Some(obj),
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// Only the newest version of each object.
+///
+/// Deleted objects are included, unless [`LatestVersions::drop_deleted`] is set, which gives the
+/// current data (like a normal `.osm` file).
+pub struct LatestVersions<I: Iterator> {
+    objs: Peekable<I>,
+    drop_deleted: bool,
+}
+
+impl<I, O> LatestVersions<I>
+where
+    I: Iterator<Item = O>,
+    O: OSMObjBase,
+{
+    /// The newest versions of the objects from `objs` (e.g. `reader.objects()`)
+    pub fn new(objs: I) -> Self {
+        LatestVersions {
+            objs: objs.peekable(),
+            drop_deleted: false,
+        }
+    }
+
+    /// Skip objects whose newest version is deleted (default: `false`).
+    pub fn drop_deleted(mut self, drop_deleted: bool) -> Self {
+        self.drop_deleted = drop_deleted;
+        self
+    }
+}
+
+impl<I, O> Iterator for LatestVersions<I>
+where
+    I: Iterator<Item = O>,
+    O: OSMObjBase,
+{
+    type Item = O;
+
+    fn next(&mut self) -> Option<O> {
+        loop {
+            let version = next_version_where(&mut self.objs, |_| true)?;
+            match version {
+                Some(obj) if !(self.drop_deleted && obj.deleted()) => return Some(obj),
+                _ => continue,
+            }
+        }
+    }
+}
+
+/// One anomaly in the history of an object, found by the [`HistoryChecker`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum HistoryProblem {
+    /// These versions (inclusive) aren't in the file
+    MissingVersions { first: u32, last: u32 },
+    /// This version is the same as, or lower than, the previous version
+    VersionNotIncreasing { previous: u32, version: u32 },
+    /// This object has no version number
+    NoVersion,
+    /// This version is older than the previous version
+    TimestampNotIncreasing {
+        version: Option<u32>,
+        previous_timestamp: TimestampFormat,
+        timestamp: TimestampFormat,
+    },
+    /// This version is deleted, but the previous version was already deleted
+    DeletedTwice { version: Option<u32> },
+    /// This version is deleted, but still has tags, a location, nodes or members
+    DeletedWithData { version: Option<u32> },
+    /// This node version is visible, but has no location
+    VisibleWithoutLocation { version: Option<u32> },
+}
+
+/// The anomalies in the history of one object
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectHistoryProblems {
+    pub object_type: OSMObjectType,
+    pub id: ObjId,
+    pub problems: Vec<HistoryProblem>,
+}
+
+/// The result of checking a history file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryReport {
+    /// Number of objects (not versions)
+    pub num_objects: u64,
+    pub num_versions: u64,
+    /// The objects with problems, sorted by type & id
+    pub objects: Vec<ObjectHistoryProblems>,
+}
+
+impl HistoryReport {
+    /// True iff no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.objects.is_empty()
+    }
+
+    /// The problems with this object
+    pub fn get(&self, object_type: OSMObjectType, id: ObjId) -> &[HistoryProblem] {
+        match self
+            .objects
+            .binary_search_by_key(&(object_type, id), |o| (o.object_type, o.id))
+        {
+            Ok(idx) => &self.objects[idx].problems,
+            Err(_) => &[],
+        }
+    }
+}
+
+/// The previous version of the object being checked
+#[derive(Debug)]
+struct PreviousVersion {
+    version: Option<u32>,
+    timestamp: Option<TimestampFormat>,
+    deleted: bool,
+}
+
+/// Checks versions one at a time. The objects must be sorted by type, id & version, as history
+/// files are. Only the previous version is kept in memory.
+///
+/// Versions before the first version in the file are only reported as missing if the first
+/// version is 1, or if `expect_first_version` is set, since extracts of history files can start
+/// part way through an object's history.
+#[derive(Debug, Default)]
+pub struct HistoryChecker {
+    current: Option<ObjectHistoryProblems>,
+    previous: Option<PreviousVersion>,
+    expect_first_version: bool,
+    report: HistoryReport,
+}
+
+impl HistoryChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report missing versions before the first version of each object (default: `false`).
+    pub fn expect_first_version(mut self, expect_first_version: bool) -> Self {
+        self.expect_first_version = expect_first_version;
+        self
+    }
+
+    /// Check the next version
+    pub fn check(&mut self, obj: &impl OSMObj) {
+        let key = (obj.object_type(), obj.id());
+        if self
+            .current
+            .as_ref()
+            .map_or(true, |c| (c.object_type, c.id) != key)
+        {
+            self.finish_object();
+            self.current = Some(ObjectHistoryProblems {
+                object_type: key.0,
+                id: key.1,
+                problems: Vec::new(),
+            });
+            self.report.num_objects += 1;
+        }
+        self.report.num_versions += 1;
+
+        let mut problems = Vec::new();
+        let version = obj.version();
+        match (self.previous.as_ref().and_then(|p| p.version), version) {
+            (_, None) => problems.push(HistoryProblem::NoVersion),
+            (None, Some(version)) => {
+                if self.previous.is_none() && self.expect_first_version && version > 1 {
+                    problems.push(HistoryProblem::MissingVersions {
+                        first: 1,
+                        last: version - 1,
+                    });
+                }
+            }
+            (Some(previous), Some(version)) => {
+                if version <= previous {
+                    problems.push(HistoryProblem::VersionNotIncreasing { previous, version });
+                } else if version > previous + 1 {
+                    problems.push(HistoryProblem::MissingVersions {
+                        first: previous + 1,
+                        last: version - 1,
+                    });
+                }
+            }
+        }
+
+        if let Some(previous) = &self.previous {
+            if let (Some(previous_timestamp), Some(timestamp)) =
+                (&previous.timestamp, obj.timestamp())
+            {
+                if timestamp < previous_timestamp {
+                    problems.push(HistoryProblem::TimestampNotIncreasing {
+                        version,
+                        previous_timestamp: previous_timestamp.clone(),
+                        timestamp: timestamp.clone(),
+                    });
+                }
+            }
+            if obj.deleted() && previous.deleted {
+                problems.push(HistoryProblem::DeletedTwice { version });
+            }
+        }
+
+        if obj.deleted() {
+            if has_data(obj) {
+                problems.push(HistoryProblem::DeletedWithData { version });
+            }
+        } else if obj.as_node().is_some_and(|n| n.lat_lon().is_none()) {
+            problems.push(HistoryProblem::VisibleWithoutLocation { version });
+        }
+
+        self.current.as_mut().unwrap().problems.extend(problems);
+        self.previous = Some(PreviousVersion {
+            version,
+            timestamp: obj.timestamp().clone(),
+            deleted: obj.deleted(),
+        });
+    }
+
+    fn finish_object(&mut self) {
+        self.previous = None;
+        if let Some(current) = self.current.take() {
+            if !current.problems.is_empty() {
+                self.report.objects.push(current);
+            }
+        }
+    }
+
+    /// Finish checking, and return the report
+    pub fn finish(mut self) -> HistoryReport {
+        self.finish_object();
+        self.report
+    }
+}
+
+/// True iff this object has tags, a location, nodes or members
+fn has_data(obj: &impl OSMObj) -> bool {
+    if !obj.untagged() {
+        return true;
+    }
+    if let Some(node) = obj.as_node() {
+        node.lat_lon().is_some()
+    } else if let Some(way) = obj.as_way() {
+        way.num_nodes() > 0
+    } else if let Some(relation) = obj.as_relation() {
+        relation.members().next().is_some()
+    } else {
+        false
+    }
+}
+
+/// Check every object in this history file. Returns an error if the file is invalid.
+pub fn check_history(reader: &mut impl OSMReader) -> Result<HistoryReport, Error> {
+    let mut checker = HistoryChecker::new();
+    for obj in reader.try_objects() {
+        checker.check(&obj?);
+    }
+    Ok(checker.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringNodeBuilder, StringOSMObj};
+    use xml::XMLReader;
+
+    fn node(id: i64, version: u32, timestamp: &str, deleted: bool) -> StringOSMObj {
+        StringNodeBuilder::default()
+            ._id(id)
+            ._version(version)
+            ._timestamp(timestamp.parse::<TimestampFormat>().unwrap())
+            ._deleted(deleted)
+            .build()
+            .unwrap()
+            .into()
+    }
+
+    fn history() -> Vec<StringOSMObj> {
+        vec![
+            node(1, 1, "2010-01-01T00:00:00Z", false),
+            node(1, 2, "2012-01-01T00:00:00Z", false),
+            node(1, 3, "2014-01-01T00:00:00Z", true),
+            node(2, 1, "2013-01-01T00:00:00Z", false),
+            node(3, 1, "2009-01-01T00:00:00Z", false),
+        ]
+    }
+
+    fn slice(timestamp: &str) -> Vec<(i64, Option<u32>)> {
+        TimeSlice::new(history().into_iter(), timestamp.parse().unwrap())
+            .map(|o| (o.id(), o.version()))
+            .collect()
+    }
+
+    #[test]
+    fn time_slice() {
+        assert_eq!(slice("2009-06-01T00:00:00Z"), vec![(3, Some(1))]);
+        assert_eq!(
+            slice("2012-01-01T00:00:00Z"),
+            vec![(1, Some(2)), (3, Some(1))]
+        );
+        assert_eq!(
+            slice("2013-06-01T00:00:00Z"),
+            vec![(1, Some(2)), (2, Some(1)), (3, Some(1))]
+        );
+        assert_eq!(
+            slice("2020-01-01T00:00:00Z"),
+            vec![(2, Some(1)), (3, Some(1))]
+        );
+    }
+
+    #[test]
+    fn latest_versions() {
+        let latest: Vec<_> = LatestVersions::new(history().into_iter())
+            .map(|o| (o.id(), o.version()))
+            .collect();
+        assert_eq!(latest, vec![(1, Some(3)), (2, Some(1)), (3, Some(1))]);
+
+        let latest: Vec<_> = LatestVersions::new(history().into_iter())
+            .drop_deleted(true)
+            .map(|o| o.id())
+            .collect();
+        assert_eq!(latest, vec![2, 3]);
+    }
+
+    #[test]
+    fn check() {
+        let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" timestamp="2010-01-01T00:00:00Z" lat="1" lon="1"/>
+<node id="1" version="2" timestamp="2011-01-01T00:00:00Z" visible="false"/>
+<node id="1" version="3" timestamp="2012-01-01T00:00:00Z" lat="1" lon="2"/>
+<node id="2" version="1" timestamp="2010-01-01T00:00:00Z" lat="1" lon="1"/>
+<node id="2" version="4" timestamp="2009-01-01T00:00:00Z" visible="false"/>
+<node id="2" version="5" timestamp="2013-01-01T00:00:00Z" visible="false"/>
+<node id="3" version="3" timestamp="2010-01-01T00:00:00Z"/>
+<way id="1" version="1" timestamp="2010-01-01T00:00:00Z"><nd ref="1"/><nd ref="2"/></way>
+<way id="1" version="2" timestamp="2011-01-01T00:00:00Z" visible="false"><tag k="highway" v="path"/></way>
+</osm>"#;
+        let report = check_history(&mut XMLReader::new(input.as_bytes())).unwrap();
+        assert_eq!(report.num_objects, 4);
+        assert_eq!(report.num_versions, 9);
+        assert!(report.get(OSMObjectType::Node, 1).is_empty());
+        assert_eq!(
+            report.get(OSMObjectType::Node, 2),
+            &[
+                HistoryProblem::MissingVersions { first: 2, last: 3 },
+                HistoryProblem::TimestampNotIncreasing {
+                    version: Some(4),
+                    previous_timestamp: "2010-01-01T00:00:00Z".parse().unwrap(),
+                    timestamp: "2009-01-01T00:00:00Z".parse().unwrap(),
+                },
+                HistoryProblem::DeletedTwice { version: Some(5) },
+            ]
+        );
+        assert_eq!(
+            report.get(OSMObjectType::Node, 3),
+            &[HistoryProblem::VisibleWithoutLocation { version: Some(3) }]
+        );
+        assert_eq!(
+            report.get(OSMObjectType::Way, 1),
+            &[HistoryProblem::DeletedWithData { version: Some(2) }]
+        );
+        assert_eq!(report.objects.len(), 3);
+
+        let mut checker = HistoryChecker::new().expect_first_version(true);
+        for obj in XMLReader::new(input.as_bytes()).objects() {
+            checker.check(&obj);
+        }
+        assert_eq!(
+            checker.finish().get(OSMObjectType::Node, 3),
+            &[
+                HistoryProblem::MissingVersions { first: 1, last: 2 },
+                HistoryProblem::VisibleWithoutLocation { version: Some(3) },
+            ]
+        );
+
+        let input =
+            r#"<osm version="0.6"><node id="1" version="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        assert!(check_history(&mut XMLReader::new(input.as_bytes())).is_err());
+    }
+}
@@ -0,0 +1,239 @@
+//! Memory efficient sets of object ids.
+//!
+//! Uses [roaring bitmaps](https://roaringbitmap.org/), which take far less memory than a
+//! `HashSet` for the large, dense sets of ids common in OSM (e.g. “all the highway ways”).
+//!
+//! ```rust
+//! use osmio::idset::IdSet;
+//! use osmio::OSMObjectType;
+//!
+//! let ids: IdSet = "n1\nw2 # a comment\nr3\n".parse()?;
+//! assert!(ids.contains(OSMObjectType::Node, 1));
+//! assert!(!ids.contains(OSMObjectType::Node, 2));
+//! assert_eq!(ids.len(), 3);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use roaring::RoaringTreemap;
+use std::collections::BTreeSet;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::iter::FromIterator;
+use std::path::Path;
+use std::str::FromStr;
+use {OSMObjBase, OSMObjectType, ObjId};
+
+use anyhow::{anyhow, Result};
+
+/// A set of object ids, for each object type.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IdSet {
+    nodes: RoaringTreemap,
+    ways: RoaringTreemap,
+    relations: RoaringTreemap,
+    /// Negative ids (e.g. new objects in JOSM files) can't be stored in the bitmaps.
+    negative: BTreeSet<(OSMObjectType, ObjId)>,
+}
+
+impl IdSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn bitmap(&self, object_type: OSMObjectType) -> &RoaringTreemap {
+        match object_type {
+            OSMObjectType::Node => &self.nodes,
+            OSMObjectType::Way => &self.ways,
+            OSMObjectType::Relation => &self.relations,
+        }
+    }
+
+    fn bitmap_mut(&mut self, object_type: OSMObjectType) -> &mut RoaringTreemap {
+        match object_type {
+            OSMObjectType::Node => &mut self.nodes,
+            OSMObjectType::Way => &mut self.ways,
+            OSMObjectType::Relation => &mut self.relations,
+        }
+    }
+
+    /// Add this id. Returns true iff it wasn't already present.
+    pub fn insert(&mut self, object_type: OSMObjectType, id: ObjId) -> bool {
+        if id < 0 {
+            self.negative.insert((object_type, id))
+        } else {
+            self.bitmap_mut(object_type).insert(id as u64)
+        }
+    }
+
+    /// Remove this id. Returns true iff it was present.
+    pub fn remove(&mut self, object_type: OSMObjectType, id: ObjId) -> bool {
+        if id < 0 {
+            self.negative.remove(&(object_type, id))
+        } else {
+            self.bitmap_mut(object_type).remove(id as u64)
+        }
+    }
+
+    /// True iff this id is in the set
+    pub fn contains(&self, object_type: OSMObjectType, id: ObjId) -> bool {
+        if id < 0 {
+            self.negative.contains(&(object_type, id))
+        } else {
+            self.bitmap(object_type).contains(id as u64)
+        }
+    }
+
+    /// Add this object's id
+    pub fn insert_obj(&mut self, obj: &impl OSMObjBase) -> bool {
+        self.insert(obj.object_type(), obj.id())
+    }
+
+    /// True iff this object's id is in the set
+    pub fn contains_obj(&self, obj: &impl OSMObjBase) -> bool {
+        self.contains(obj.object_type(), obj.id())
+    }
+
+    /// Total number of ids, of all types
+    pub fn len(&self) -> u64 {
+        self.nodes.len() + self.ways.len() + self.relations.len() + self.negative.len() as u64
+    }
+
+    /// Number of ids of this type
+    pub fn len_of_type(&self, object_type: OSMObjectType) -> u64 {
+        self.bitmap(object_type).len()
+            + self
+                .negative
+                .iter()
+                .filter(|(t, _)| *t == object_type)
+                .count() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The ids of this type, in increasing order
+    pub fn ids(&self, object_type: OSMObjectType) -> impl Iterator<Item = ObjId> + '_ {
+        self.negative
+            .iter()
+            .filter(move |(t, _)| *t == object_type)
+            .map(|(_, id)| *id)
+            .chain(self.bitmap(object_type).iter().map(|id| id as ObjId))
+    }
+
+    /// Add all the ids from `other` to this set
+    pub fn union_with(&mut self, other: &IdSet) {
+        self.nodes |= &other.nodes;
+        self.ways |= &other.ways;
+        self.relations |= &other.relations;
+        self.negative.extend(other.negative.iter().copied());
+    }
+
+    /// Read a list of ids, one per line.
+    ///
+    /// This is the format used by `osmium getid --id-file`: each line is the object type
+    /// (`n`/`w`/`r`) followed by the id (e.g. `w123`). Anything after a space or `#` is ignored,
+    /// as are empty lines. If there's no type letter, `default_type` is used, or it's an error
+    /// if that's `None`.
+    pub fn from_reader(reader: impl Read, default_type: Option<OSMObjectType>) -> Result<Self> {
+        let mut idset = IdSet::new();
+        for (line_no, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line
+                .split(|c: char| c == '#' || c.is_whitespace())
+                .next()
+                .unwrap_or("");
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || anyhow!("Invalid id on line {}: {:?}", line_no + 1, line);
+            let (object_type, id) = match line.chars().next() {
+                Some(c) if c.is_ascii_alphabetic() => (
+                    OSMObjectType::try_from(c.to_ascii_lowercase()).map_err(|_| invalid())?,
+                    &line[1..],
+                ),
+                _ => (default_type.ok_or_else(invalid)?, line),
+            };
+            let id: ObjId = id.parse().map_err(|_| invalid())?;
+            idset.insert(object_type, id);
+        }
+        Ok(idset)
+    }
+
+    /// Read a list of ids from this file, see [`IdSet::from_reader`]
+    pub fn from_filename(
+        filename: impl AsRef<Path>,
+        default_type: Option<OSMObjectType>,
+    ) -> Result<Self> {
+        Self::from_reader(File::open(filename)?, default_type)
+    }
+}
+
+/// Parse a list of ids, see [`IdSet::from_reader`]
+impl FromStr for IdSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::from_reader(s.as_bytes(), None)
+    }
+}
+
+impl Extend<(OSMObjectType, ObjId)> for IdSet {
+    fn extend<I: IntoIterator<Item = (OSMObjectType, ObjId)>>(&mut self, iter: I) {
+        for (object_type, id) in iter {
+            self.insert(object_type, id);
+        }
+    }
+}
+
+impl FromIterator<(OSMObjectType, ObjId)> for IdSet {
+    fn from_iter<I: IntoIterator<Item = (OSMObjectType, ObjId)>>(iter: I) -> Self {
+        let mut idset = IdSet::new();
+        idset.extend(iter);
+        idset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OSMObjectType::*;
+
+    #[test]
+    fn insert_contains() {
+        let mut ids = IdSet::new();
+        assert!(ids.is_empty());
+        assert!(ids.insert(Node, 1));
+        assert!(!ids.insert(Node, 1));
+        assert!(ids.insert(Way, 1));
+        assert!(ids.insert(Node, -5));
+        assert!(ids.insert(Relation, 10_000_000_000));
+        assert!(ids.contains(Node, 1));
+        assert!(ids.contains(Node, -5));
+        assert!(!ids.contains(Way, -5));
+        assert!(!ids.contains(Relation, 1));
+        assert_eq!(ids.len(), 4);
+        assert_eq!(ids.len_of_type(Node), 2);
+        assert_eq!(ids.ids(Node).collect::<Vec<_>>(), vec![-5, 1]);
+
+        assert!(ids.remove(Node, 1));
+        assert!(!ids.contains(Node, 1));
+    }
+
+    #[test]
+    fn parse() {
+        let ids: IdSet = "n1\n\nW2 # comment\nr3 extra\n#comment\n".parse().unwrap();
+        let expected: IdSet = vec![(Node, 1), (Way, 2), (Relation, 3)]
+            .into_iter()
+            .collect();
+        assert_eq!(ids, expected);
+
+        assert!("1\n".parse::<IdSet>().is_err());
+        assert!("x1\n".parse::<IdSet>().is_err());
+        assert!("nfoo\n".parse::<IdSet>().is_err());
+
+        let ids = IdSet::from_reader("1\n2\nn3\n".as_bytes(), Some(Way)).unwrap();
+        assert_eq!(ids.ids(Way).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(ids.contains(Node, 3));
+    }
+}
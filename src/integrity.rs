@@ -0,0 +1,267 @@
+//! Checking the referential integrity of a file.
+//!
+//! Finds ways which refer to nodes which aren't in the file, relations which refer to members
+//! which aren't in the file, duplicate objects, and ways with fewer than 2 nodes. The report can
+//! be serialised (e.g. to JSON) for use in scripts.
+//!
+//! ```no_run
+//! use osmio::integrity::check_integrity;
+//!
+//! let mut reader = osmio::read_pbf("extract.osm.pbf")?;
+//! let report = check_integrity(&mut reader)?;
+//! if !report.is_ok() {
+//!     println!("{}", serde_json::to_string_pretty(&report)?);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use idset::IdSet;
+use serde::{Deserialize, Serialize};
+use {Error, OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, Relation, Way};
+
+/// One problem found by the [`IntegrityChecker`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum IntegrityProblem {
+    /// This way refers to a node which isn't in the file
+    MissingNode { way_id: ObjId, node_id: ObjId },
+    /// This relation has a member which isn't in the file
+    MissingMember {
+        relation_id: ObjId,
+        member_type: OSMObjectType,
+        member_id: ObjId,
+    },
+    /// There's more than one object with this type, id & version
+    Duplicate {
+        object_type: OSMObjectType,
+        id: ObjId,
+        version: Option<u32>,
+    },
+    /// This way has fewer than 2 nodes
+    TooFewNodes { way_id: ObjId, num_nodes: usize },
+    /// This object is out of order, so the file isn't sorted. Other problems may be wrong, since
+    /// the checker relies on the file being sorted. Only the first is reported.
+    NotSorted {
+        object_type: OSMObjectType,
+        id: ObjId,
+    },
+}
+
+/// The result of checking a file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IntegrityReport {
+    pub num_nodes: u64,
+    pub num_ways: u64,
+    pub num_relations: u64,
+    pub problems: Vec<IntegrityProblem>,
+    /// True iff the file isn't sorted (there's a [`IntegrityProblem::NotSorted`])
+    #[serde(default)]
+    pub unsorted: bool,
+}
+
+impl IntegrityReport {
+    /// True iff no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks objects one at a time. The objects must be sorted (nodes, then ways, then relations,
+/// each by id & version).
+///
+/// Only the ids of the objects are kept in memory. Since relations can have later relations as
+/// members, those references are only checked in [`IntegrityChecker::finish`].
+#[derive(Debug, Default)]
+pub struct IntegrityChecker {
+    seen: IdSet,
+    last_key: Option<(OSMObjectType, ObjId, Option<u32>)>,
+    /// `(relation_id, member_id)` for relation members which hadn't been seen yet
+    pending_relation_members: Vec<(ObjId, ObjId)>,
+    report: IntegrityReport,
+}
+
+impl IntegrityChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the next object
+    pub fn check(&mut self, obj: &impl OSMObj) {
+        let key = (obj.object_type(), obj.id(), obj.version());
+        if let Some(last_key) = self.last_key {
+            if key == last_key {
+                self.report.problems.push(IntegrityProblem::Duplicate {
+                    object_type: key.0,
+                    id: key.1,
+                    version: key.2,
+                });
+            } else if key < last_key && !self.report.unsorted {
+                self.report.unsorted = true;
+                self.report.problems.push(IntegrityProblem::NotSorted {
+                    object_type: key.0,
+                    id: key.1,
+                });
+            }
+        }
+        self.last_key = Some(key);
+        self.seen.insert_obj(obj);
+
+        if obj.is_node() {
+            self.report.num_nodes += 1;
+        } else if let Some(way) = obj.as_way() {
+            self.report.num_ways += 1;
+            if way.num_nodes() < 2 {
+                self.report.problems.push(IntegrityProblem::TooFewNodes {
+                    way_id: way.id(),
+                    num_nodes: way.num_nodes(),
+                });
+            }
+            for nid in way.nodes() {
+                if !self.seen.contains(OSMObjectType::Node, *nid) {
+                    self.report.problems.push(IntegrityProblem::MissingNode {
+                        way_id: way.id(),
+                        node_id: *nid,
+                    });
+                }
+            }
+        } else if let Some(relation) = obj.as_relation() {
+            self.report.num_relations += 1;
+            for (member_type, member_id, _role) in relation.members() {
+                if self.seen.contains(member_type, member_id) {
+                    continue;
+                }
+                if member_type == OSMObjectType::Relation {
+                    self.pending_relation_members
+                        .push((relation.id(), member_id));
+                } else {
+                    self.report.problems.push(IntegrityProblem::MissingMember {
+                        relation_id: relation.id(),
+                        member_type,
+                        member_id,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Finish checking, and return the report
+    pub fn finish(mut self) -> IntegrityReport {
+        for (relation_id, member_id) in self.pending_relation_members {
+            if !self.seen.contains(OSMObjectType::Relation, member_id) {
+                self.report.problems.push(IntegrityProblem::MissingMember {
+                    relation_id,
+                    member_type: OSMObjectType::Relation,
+                    member_id,
+                });
+            }
+        }
+        self.report
+    }
+}
+
+/// Check every object in this reader. Returns an error if the file is invalid.
+pub fn check_integrity(reader: &mut impl OSMReader) -> Result<IntegrityReport, Error> {
+    let mut checker = IntegrityChecker::new();
+    for obj in reader.try_objects() {
+        checker.check(&obj?);
+    }
+    Ok(checker.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use xml::XMLReader;
+    use OSMObjectType::*;
+
+    fn check(input: &str) -> IntegrityReport {
+        check_integrity(&mut XMLReader::new(Cursor::new(input))).unwrap()
+    }
+
+    #[test]
+    fn ok() {
+        let report = check(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="5"/>
+<way id="10"><nd ref="1"/><nd ref="2"/></way>
+<relation id="20"><member type="relation" ref="21" role=""/></relation>
+<relation id="21"><member type="way" ref="10" role=""/></relation>
+</osm>"#,
+        );
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.num_nodes, 2);
+        assert_eq!(report.num_relations, 2);
+        assert!(!report.unsorted);
+    }
+
+    #[test]
+    fn problems() {
+        let report = check(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="1" version="1" lat="1" lon="1"/>
+<way id="10"><nd ref="1"/><nd ref="2"/></way>
+<way id="11"><nd ref="1"/></way>
+<relation id="20"><member type="relation" ref="22" role=""/><member type="node" ref="3" role=""/></relation>
+</osm>"#,
+        );
+        assert_eq!(
+            report.problems,
+            vec![
+                IntegrityProblem::Duplicate {
+                    object_type: Node,
+                    id: 1,
+                    version: Some(1)
+                },
+                IntegrityProblem::MissingNode {
+                    way_id: 10,
+                    node_id: 2
+                },
+                IntegrityProblem::TooFewNodes {
+                    way_id: 11,
+                    num_nodes: 1
+                },
+                IntegrityProblem::MissingMember {
+                    relation_id: 20,
+                    member_type: Node,
+                    member_id: 3
+                },
+                IntegrityProblem::MissingMember {
+                    relation_id: 20,
+                    member_type: Relation,
+                    member_id: 22
+                },
+            ]
+        );
+
+        let json = serde_json::to_value(&report.problems[1]).unwrap();
+        assert_eq!(json["problem"], "missing_node");
+        assert_eq!(json["node_id"], 2);
+    }
+
+    #[test]
+    fn unsorted() {
+        let report = check(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<way id="10"><nd ref="1"/><nd ref="2"/></way>
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="1"/>
+</osm>"#,
+        );
+        assert!(report.unsorted);
+        assert!(report.problems.contains(&IntegrityProblem::NotSorted {
+            object_type: Node,
+            id: 1
+        }));
+    }
+
+    #[test]
+    fn invalid() {
+        let input = r#"<osm version="0.6"><node id="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        assert!(check_integrity(&mut XMLReader::new(Cursor::new(input))).is_err());
+    }
+}
@@ -12,18 +12,44 @@
 //! # Ok::<(), anyhow::Error>(())
 //! ```
 extern crate byteorder;
+#[cfg(feature = "cloud")]
+extern crate bytes;
+#[cfg(feature = "chrono")]
 extern crate chrono;
 extern crate flate2;
 extern crate protobuf;
 extern crate quick_protobuf;
 extern crate quick_xml;
-extern crate xml as xml_rs;
+#[cfg(feature = "cloud")]
+extern crate object_store;
+#[cfg(feature = "petgraph")]
+extern crate petgraph;
+#[cfg(feature = "proptest")]
+extern crate proptest;
+#[cfg(feature = "quickcheck")]
+extern crate quickcheck;
 #[macro_use]
 extern crate derive_builder;
 extern crate anyhow;
+extern crate bincode;
+#[cfg(feature = "bzip2")]
 extern crate bzip2;
+extern crate lru;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+extern crate roaring;
+#[cfg(feature = "spatial")]
+extern crate rstar;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 extern crate serde;
 extern crate serde_json;
+extern crate sha2;
+extern crate tempfile;
+#[cfg(feature = "cloud")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -36,7 +62,7 @@ use std::io::{BufReader, Read, Write};
 use std::iter::{ExactSizeIterator, Iterator};
 use std::path::Path;
 use std::str::FromStr;
-use utils::{epoch_to_iso, iso_to_epoch};
+use utils::{epoch_millis_to_iso, epoch_to_iso, parse_iso8601, parse_iso8601_millis};
 
 use anyhow::Result;
 
@@ -55,7 +81,60 @@ pub mod obj_types;
 #[cfg(test)]
 mod tests;
 
+pub mod changeset_export;
+pub mod changeset_stats;
 pub mod changesets;
+pub mod classify;
+#[cfg(feature = "cloud")]
+pub mod cloud;
+pub mod completeness;
+
+pub mod address;
+pub mod anonymise;
+#[cfg(feature = "api")]
+pub mod api;
+#[cfg(any(feature = "quickcheck", feature = "proptest"))]
+pub mod arbitrary;
+mod bbox;
+pub use bbox::{BBox, ParseBBoxError};
+pub mod cancel;
+mod error;
+pub use error::Error;
+pub mod dedup;
+pub mod diff;
+pub mod download;
+pub mod extract;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod geometry;
+pub mod getid;
+pub mod handler;
+pub mod header;
+pub mod history;
+pub mod idset;
+pub mod integrity;
+pub mod memory;
+pub mod merge;
+pub mod node_locations;
+pub mod node_ways;
+pub mod notes;
+pub mod open;
+pub mod parse_mode;
+pub mod pipeline;
+pub mod renumber;
+pub mod routing;
+pub mod sort;
+#[cfg(feature = "spatial")]
+pub mod spatial;
+pub mod stats;
+pub mod store;
+pub mod tag_stats;
+pub mod tag_values;
+pub mod tagfilter;
+pub mod tee;
+pub mod transform;
+pub mod turn_restrictions;
+pub mod user_extract;
 
 /// Type that stores the OSM Id
 pub type ObjId = i64;
@@ -239,28 +318,110 @@ impl From<std::num::ParseFloatError> for ParseLatLonError {
     }
 }
 
-/// Timestamps can be stored as an ISO formatted string, or number of seconds since unix epoch
+/// Timestamps can be stored as an ISO formatted string, or number of seconds (or milliseconds)
+/// since unix epoch
 ///
 /// In XML files, timestamps are represented as ISO strings, and in PBF files, as integer seconds
-/// since the epoch
+/// since the epoch. PBF files with a `date_granularity` finer than 1 second have their timestamps
+/// stored as milliseconds since the epoch.
 #[derive(Debug, Clone, Eq, Serialize, Deserialize)]
 pub enum TimestampFormat {
     ISOString(String),
     EpochNunber(i64),
+    EpochMillis(i64),
 }
 
 impl TimestampFormat {
     pub fn to_iso_string(&self) -> String {
         match self {
             TimestampFormat::ISOString(s) => s.clone(),
-            TimestampFormat::EpochNunber(t) => epoch_to_iso(*t as i32),
+            TimestampFormat::EpochNunber(t) => epoch_to_iso(*t),
+            TimestampFormat::EpochMillis(t) => epoch_millis_to_iso(*t),
         }
     }
 
+    /// Number of seconds since the unix epoch. Invalid ISO strings are returned as 0, use
+    /// [`TimestampFormat::try_to_epoch_number`] to detect that.
     pub fn to_epoch_number(&self) -> i64 {
+        self.try_to_epoch_number().unwrap_or(0)
+    }
+
+    /// Number of seconds since the unix epoch, or an error if this is an invalid ISO string.
+    /// Any milliseconds are truncated.
+    pub fn try_to_epoch_number(&self) -> Result<i64, ParseTimestampError> {
+        match self {
+            TimestampFormat::ISOString(s) => parse_iso8601(s),
+            &TimestampFormat::EpochNunber(t) => Ok(t),
+            &TimestampFormat::EpochMillis(t) => Ok(t.div_euclid(1000)),
+        }
+    }
+
+    /// Number of milliseconds since the unix epoch. Invalid ISO strings are returned as 0.
+    pub fn to_epoch_millis(&self) -> i64 {
+        self.try_to_epoch_millis().unwrap_or(0)
+    }
+
+    /// Number of milliseconds since the unix epoch, or an error if this is an invalid ISO string.
+    ///
+    /// ```rust
+    /// use osmio::TimestampFormat;
+    /// let ts = TimestampFormat::ISOString("1970-01-01T00:00:01.5Z".to_string());
+    /// assert_eq!(ts.try_to_epoch_millis(), Ok(1500));
+    /// assert_eq!(ts.try_to_epoch_number(), Ok(1));
+    /// ```
+    pub fn try_to_epoch_millis(&self) -> Result<i64, ParseTimestampError> {
         match self {
-            TimestampFormat::ISOString(s) => iso_to_epoch(s) as i64,
-            &TimestampFormat::EpochNunber(t) => t,
+            TimestampFormat::ISOString(s) => parse_iso8601_millis(s),
+            &TimestampFormat::EpochNunber(t) => Ok(t.saturating_mul(1000)),
+            &TimestampFormat::EpochMillis(t) => Ok(t),
+        }
+    }
+
+    /// Build a timestamp from a PBF value, which is in units of `date_granularity` milliseconds.
+    /// If the granularity is whole seconds, it's stored as seconds, otherwise milliseconds. An
+    /// error if that's too large for an `i64` of milliseconds.
+    pub(crate) fn from_date_granularity(
+        units: i64,
+        date_granularity: i32,
+    ) -> Result<Self, ParseTimestampError> {
+        let out_of_range = ParseTimestampError::OutOfRange {
+            field: "timestamp",
+            value: units,
+        };
+        let millis = units
+            .checked_mul(date_granularity as i64)
+            .ok_or(out_of_range)?;
+        if date_granularity % 1000 == 0 {
+            Ok(TimestampFormat::EpochNunber(millis / 1000))
+        } else {
+            Ok(TimestampFormat::EpochMillis(millis))
+        }
+    }
+
+    /// Convert to a chrono `DateTime` in UTC.
+    ///
+    /// ```rust
+    /// use osmio::TimestampFormat;
+    /// let ts = TimestampFormat::ISOString("2020-01-01T02:00:00+02:00".to_string());
+    /// assert_eq!(ts.to_datetime().unwrap().to_rfc3339(), "2020-01-01T00:00:00+00:00");
+    /// ```
+    #[cfg(feature = "chrono")]
+    pub fn to_datetime(&self) -> Result<chrono::DateTime<chrono::Utc>, ParseTimestampError> {
+        let millis = self.try_to_epoch_millis()?;
+        chrono::DateTime::from_timestamp_millis(millis).ok_or(ParseTimestampError::OutOfRange {
+            field: "timestamp",
+            value: millis,
+        })
+    }
+
+    /// Create a timestamp from a chrono `DateTime` (in any timezone). Sub-second precision is
+    /// kept to the millisecond.
+    #[cfg(feature = "chrono")]
+    pub fn from_datetime<Tz: chrono::TimeZone>(datetime: &chrono::DateTime<Tz>) -> Self {
+        if datetime.timestamp_subsec_millis() == 0 {
+            TimestampFormat::EpochNunber(datetime.timestamp())
+        } else {
+            TimestampFormat::EpochMillis(datetime.timestamp_millis())
         }
     }
 }
@@ -275,13 +436,33 @@ where
 }
 
 impl std::str::FromStr for TimestampFormat {
-    type Err = String;
+    type Err = ParseTimestampError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let date: i64 = chrono::DateTime::parse_from_rfc3339(s)
-            .map_err(|_| "invalid date")?
-            .timestamp();
-        Ok(TimestampFormat::EpochNunber(date))
+        Ok(TimestampFormat::EpochNunber(parse_iso8601(s)?))
+    }
+}
+
+/// An error while trying to parse an ISO 8601 string into a timestamp
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseTimestampError {
+    /// The string was not in the `YYYY-MM-DDTHH:MM:SSZ` form. Contains the original string.
+    InvalidFormat(String),
+
+    /// One of the fields was outside the allowed range, e.g. a month of `13`.
+    OutOfRange { field: &'static str, value: i64 },
+}
+
+impl std::error::Error for ParseTimestampError {}
+
+impl Display for ParseTimestampError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidFormat(s) => write!(f, "{:?} is not a valid ISO 8601 timestamp", s),
+            Self::OutOfRange { field, value } => {
+                write!(f, "{} of {} is out of range for a timestamp", field, value)
+            }
+        }
     }
 }
 
@@ -291,22 +472,37 @@ impl fmt::Display for TimestampFormat {
     }
 }
 
+impl TimestampFormat {
+    /// What we compare on. ISO strings & epoch numbers are all converted to epoch milliseconds, so
+    /// that they compare equal regardless of which reader produced them. ISO strings which are
+    /// invalid sort after all valid timestamps, and then by the string.
+    fn cmp_key(&self) -> std::result::Result<i64, &str> {
+        match self {
+            TimestampFormat::ISOString(s) => parse_iso8601_millis(s).map_err(|_| s.as_str()),
+            &TimestampFormat::EpochNunber(t) => Ok(t.saturating_mul(1000)),
+            &TimestampFormat::EpochMillis(t) => Ok(t),
+        }
+    }
+}
+
+impl std::cmp::Ord for TimestampFormat {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.cmp_key().cmp(&other.cmp_key())
+    }
+}
 impl std::cmp::PartialOrd for TimestampFormat {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        match (self, other) {
-            (TimestampFormat::ISOString(a), TimestampFormat::ISOString(b)) => a.partial_cmp(b),
-            (TimestampFormat::EpochNunber(a), TimestampFormat::EpochNunber(b)) => a.partial_cmp(b),
-            (a, b) => a.to_epoch_number().partial_cmp(&b.to_epoch_number()),
-        }
+        Some(self.cmp(other))
     }
 }
 impl std::cmp::PartialEq for TimestampFormat {
     fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (TimestampFormat::ISOString(a), TimestampFormat::ISOString(b)) => a.eq(b),
-            (TimestampFormat::EpochNunber(a), TimestampFormat::EpochNunber(b)) => a.eq(b),
-            (a, b) => a.to_epoch_number().eq(&b.to_epoch_number()),
-        }
+        self.cmp_key() == other.cmp_key()
+    }
+}
+impl std::hash::Hash for TimestampFormat {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.cmp_key().hash(state);
     }
 }
 
@@ -335,6 +531,48 @@ pub trait OSMObjBase: PartialEq + Debug + Clone {
     fn num_tags(&self) -> usize {
         self.tags().count()
     }
+    /// The value of this tag as a boolean (see [`tag_values::parse_bool`])
+    fn tag_bool(&self, key: impl AsRef<str>) -> Option<bool> {
+        self.tag(key).and_then(tag_values::parse_bool)
+    }
+    /// The value of this tag as a number with an optional unit (see
+    /// [`tag_values::parse_quantity`])
+    fn tag_quantity(&self, key: impl AsRef<str>) -> Option<tag_values::Quantity<'_>> {
+        self.tag(key).and_then(tag_values::parse_quantity)
+    }
+    /// The semicolon separated values of this tag (see [`tag_values::split_values`]). Empty if
+    /// the tag isn't set.
+    fn tag_values<'a>(&'a self, key: impl AsRef<str>) -> Box<dyn Iterator<Item = &'a str> + 'a> {
+        Box::new(self.tag(key).into_iter().flat_map(tag_values::split_values))
+    }
+    /// The name in the first of these languages it has (`name:<lang>`, or `name:zh` for
+    /// `zh-Hant`), otherwise the `name` tag
+    fn name(&self, preferred_langs: &[&str]) -> Option<&str> {
+        for lang in preferred_langs {
+            if let Some(name) = self.tag(format!("name:{}", lang)) {
+                return Some(name);
+            }
+            if let Some((base, _)) = lang.split_once('-') {
+                if let Some(name) = self.tag(format!("name:{}", base)) {
+                    return Some(name);
+                }
+            }
+        }
+        self.tag("name")
+    }
+    /// All the name tags (key & value): `name`, `name:<lang>`, and other `*_name` keys like
+    /// `alt_name` or `official_name:en`
+    fn names<'a>(&'a self) -> Box<dyn Iterator<Item = (&'a str, &'a str)> + 'a> {
+        Box::new(self.tags().filter(|(k, _)| {
+            let base = k.split(':').next().unwrap_or_default();
+            base == "name" || base.ends_with("_name")
+        }))
+    }
+    /// True iff this object has an `opening_hours` tag (see [`tag_values::is_opening_hours`])
+    fn has_opening_hours(&self) -> bool {
+        self.tag("opening_hours")
+            .is_some_and(tag_values::is_opening_hours)
+    }
     // The tags of this object, as a JSON string
     fn tags_json_string(&self) -> String {
         if self.untagged() {
@@ -460,6 +698,10 @@ pub trait Way: OSMObjBase {
 
     /// When `is_area` is true, the Way should be interpreted as a 2-D shape rather than a 1-D
     /// linestring. Uses OSM convention to detect “areas”.
+    ///
+    /// This treats every closed way as an area unless it's tagged `area=no`. For decisions based
+    /// on the other tags (e.g. a closed `highway=residential` is a line), see
+    /// [`geometry::area`].
     fn is_area(&self) -> bool {
         // Generally any closed way represents an area the `area=yes` tag should also be present,
         // but sometimes it's `area=highway` or other things. In the interest of accepting all
@@ -504,6 +746,15 @@ impl OSMObjectType {
             OSMObjectType::Relation => "relation",
         }
     }
+
+    /// 0, 1 or 2 (in the standard order), to index arrays with one entry per type
+    pub(crate) fn index(&self) -> usize {
+        match self {
+            OSMObjectType::Node => 0,
+            OSMObjectType::Way => 1,
+            OSMObjectType::Relation => 2,
+        }
+    }
 }
 
 impl std::fmt::Debug for OSMObjectType {
@@ -599,15 +850,52 @@ pub trait OSMReader {
         self.set_sorted_assumption(false);
     }
 
+    /// What to do with invalid data (default: [`ParseMode::Lenient`](parse_mode::ParseMode)).
+    /// Readers which can't detect invalid data ignore this.
+    #[allow(unused_variables)]
+    fn set_parse_mode(&mut self, parse_mode: parse_mode::ParseMode) {}
+
+    /// Decode strings which aren't valid UTF-8 lossily, replacing invalid bytes with `U+FFFD`
+    /// (default: `false`, they're skipped). They're still an
+    /// [`AnomalyKind::InvalidUtf8`](parse_mode::AnomalyKind::InvalidUtf8), so this only makes a
+    /// difference when the parse mode isn't strict.
+    #[allow(unused_variables)]
+    fn set_lossy_utf8(&mut self, lossy_utf8: bool) {}
+
+    /// What to do with objects which have more than one tag with the same key (default:
+    /// [`DuplicateTags::KeepAll`](parse_mode::DuplicateTags::KeepAll))
+    #[allow(unused_variables)]
+    fn set_duplicate_tags(&mut self, duplicate_tags: parse_mode::DuplicateTags) {}
+
+    /// What to do with negative or zero ids (default:
+    /// [`NonPositiveIds::Allow`](parse_mode::NonPositiveIds::Allow))
+    #[allow(unused_variables)]
+    fn set_non_positive_ids(&mut self, non_positive_ids: parse_mode::NonPositiveIds) {}
+
     /// Convert to the underlying reader
     fn into_inner(self) -> Self::R;
 
     /// Reference to the inner
     fn inner(&self) -> &Self::R;
 
+    /// The metadata from the header of the file. The header is read with the first object, so
+    /// this is `None` until then (and for formats without a header).
+    fn file_info(&self) -> Option<&header::FileInfo> {
+        None
+    }
+
     /// Returns the next OSM Object in this reader
     fn next(&mut self) -> Option<Self::Obj>;
 
+    /// Returns the next OSM Object in this reader, or an error if the file is invalid.
+    ///
+    /// Readers which can detect invalid files override this (and their `next` panics on those
+    /// errors). By default, this calls `next`. The [`Error`] can be matched on, to tell e.g. IO
+    /// errors from invalid files.
+    fn try_next(&mut self) -> Result<Option<Self::Obj>, Error> {
+        Ok(self.next())
+    }
+
     /// Returns an iterator over the objects in this reader.
     fn objects(&mut self) -> OSMObjectIterator<'_, Self>
     where
@@ -616,20 +904,73 @@ pub trait OSMReader {
         OSMObjectIterator { inner: self }
     }
 
+    /// Returns an iterator over the objects in this reader, which returns an error (and then
+    /// stops) if the file is invalid, rather than panicking.
+    ///
+    /// ```rust,no_run
+    /// use osmio::OSMReader;
+    /// let mut reader = osmio::read_pbf("untrusted.osm.pbf")?;
+    /// for obj in reader.try_objects() {
+    ///     let obj = obj?;
+    ///     // ...
+    /// }
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    fn try_objects(&mut self) -> OSMTryObjectIterator<'_, Self>
+    where
+        Self: Sized,
+    {
+        OSMTryObjectIterator {
+            inner: self,
+            done: false,
+        }
+    }
+
+    /// Returns an iterator over the objects in this reader, with their uid, user & changeset
+    /// id removed, as [`Anonymous`](anonymise::Anonymous) objects which have no accessors for
+    /// them.
+    fn anonymous_objects(&mut self) -> anonymise::AnonymousObjects<OSMObjectIterator<'_, Self>>
+    where
+        Self: Sized,
+    {
+        anonymise::AnonymousObjects::new(self.objects())
+    }
+
+    /// Returns an iterator over just the nodes in this reader.
+    ///
+    /// If the reader assumes the file is sorted (see [`OSMReader::assume_sorted`]), it stops at
+    /// the first way or relation, rather than reading the rest of the file.
     fn nodes(&mut self) -> Box<dyn Iterator<Item = <<Self as OSMReader>::Obj as OSMObj>::Node> + '_>
     where
         Self: Sized,
     {
-        Box::new(self.objects().filter_map(|o| o.into_node()))
+        if self.get_sorted_assumption() {
+            Box::new(self.objects().map_while(|o| o.into_node()))
+        } else {
+            Box::new(self.objects().filter_map(|o| o.into_node()))
+        }
     }
 
+    /// Returns an iterator over just the ways in this reader.
+    ///
+    /// If the reader assumes the file is sorted (see [`OSMReader::assume_sorted`]), it stops at
+    /// the first relation, rather than reading the rest of the file.
     fn ways(&mut self) -> Box<dyn Iterator<Item = <<Self as OSMReader>::Obj as OSMObj>::Way> + '_>
     where
         Self: Sized,
     {
-        Box::new(self.objects().filter_map(|o| o.into_way()))
+        if self.get_sorted_assumption() {
+            Box::new(
+                self.objects()
+                    .take_while(|o| o.object_type() != OSMObjectType::Relation)
+                    .filter_map(|o| o.into_way()),
+            )
+        } else {
+            Box::new(self.objects().filter_map(|o| o.into_way()))
+        }
     }
 
+    /// Returns an iterator over just the relations in this reader.
     fn relations(
         &mut self,
     ) -> Box<dyn Iterator<Item = <<Self as OSMReader>::Obj as OSMObj>::Relation> + '_>
@@ -639,21 +980,115 @@ pub trait OSMReader {
         Box::new(self.objects().filter_map(|o| o.into_relation()))
     }
 
-    //fn nodes_locations<'a>(&'a mut self) -> Box<Iterator<Item=(ObjId, Lat, Lon)>+'a> where Self:Sized {
-    //    Box::new(self.nodes().filter_map(|n| if n.deleted || n.lat.is_none() { None } else { Some((n.id, n.lat.unwrap(), n.lon.unwrap())) } ))
-    //}
+    /// Returns an iterator over the objects whose ids are in `ids`.
+    fn filter_ids<'a>(
+        &'a mut self,
+        ids: &'a idset::IdSet,
+    ) -> Box<dyn Iterator<Item = Self::Obj> + 'a>
+    where
+        Self: Sized,
+    {
+        Box::new(self.objects().filter(move |o| ids.contains_obj(o)))
+    }
+
+    /// Returns an iterator over the objects which match this tag filter.
+    fn filter_tags<'a>(
+        &'a mut self,
+        filter: &'a tagfilter::TagFilter,
+    ) -> Box<dyn Iterator<Item = Self::Obj> + 'a>
+    where
+        Self: Sized,
+    {
+        Box::new(self.objects().filter(move |o| filter.matches(o)))
+    }
+
+    /// Returns an iterator over the objects whose timestamp is at or after `timestamp`.
+    ///
+    /// Timestamps are compared as points in time, so ISO strings & epoch numbers can be mixed.
+    /// Objects without a timestamp, or with an invalid one, are skipped. If `timestamp` itself is
+    /// invalid, nothing is returned.
+    fn modified_since(
+        &mut self,
+        timestamp: impl Into<TimestampFormat>,
+    ) -> Box<dyn Iterator<Item = Self::Obj> + '_>
+    where
+        Self: Sized,
+    {
+        let since = match timestamp.into().try_to_epoch_millis() {
+            Ok(since) => since,
+            Err(_) => return Box::new(std::iter::empty()),
+        };
+        Box::new(self.objects().filter(move |o| {
+            o.timestamp()
+                .as_ref()
+                .and_then(|t| t.try_to_epoch_millis().ok())
+                .is_some_and(|t| t >= since)
+        }))
+    }
+
+    /// Returns an iterator over every `n`th object (the first, then the `n + 1`th, …), for a
+    /// representative sample of a large file. `n` of 0 is treated as 1.
+    fn sample_every(&mut self, n: usize) -> Box<dyn Iterator<Item = Self::Obj> + '_>
+    where
+        Self: Sized,
+    {
+        Box::new(self.objects().step_by(n.max(1)))
+    }
+
+    /// Returns an iterator over the first `n` objects. Nothing after them is read.
+    fn take_objects(&mut self, n: usize) -> Box<dyn Iterator<Item = Self::Obj> + '_>
+    where
+        Self: Sized,
+    {
+        Box::new(self.objects().take(n))
+    }
+
+    /// Returns an iterator over the results of `f` for every object, skipping `None`s.
+    fn filter_map_objs<'a, T, F>(&'a mut self, f: F) -> Box<dyn Iterator<Item = T> + 'a>
+    where
+        Self: Sized,
+        F: FnMut(Self::Obj) -> Option<T> + 'a,
+    {
+        Box::new(self.objects().filter_map(f))
+    }
+}
+
+/// Something that produces OSMObjects, or the error which stopped reading
+///
+/// Created by `OSMReader::try_objects`
+pub struct OSMTryObjectIterator<'a, R>
+where
+    R: OSMReader + 'a,
+{
+    inner: &'a mut R,
+    done: bool,
+}
+
+impl<'a, R> OSMTryObjectIterator<'a, R>
+where
+    R: OSMReader,
+{
+    pub fn inner(&self) -> &R {
+        self.inner
+    }
+}
 
-    //fn ways<'a>(&'a mut self) -> Box<Iterator<Item=Way>+'a> where Self:Sized {
-    //    if self.get_sorted_assumption() {
-    //        Box::new(self.objects().take_while(|o| (o.is_node() || o.is_way())).filter_map(|o| o.into_way()))
-    //    } else {
-    //        Box::new(self.objects().filter_map(|o| o.into_way()))
-    //    }
-    //}
+impl<'a, R> Iterator for OSMTryObjectIterator<'a, R>
+where
+    R: OSMReader,
+{
+    type Item = Result<R::Obj, Error>;
 
-    //fn relations<'a>(&'a mut self) -> Box<Iterator<Item=Relation>+'a> where Self:Sized {
-    //    Box::new(self.objects().filter_map(|o| o.into_relation()))
-    //}
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.inner.try_next().transpose();
+        if !matches!(next, Some(Ok(_))) {
+            self.done = true;
+        }
+        next
+    }
 }
 
 /// Something that produces OSMObjects
@@ -695,6 +1130,7 @@ pub enum OSMWriteError {
     OPLWrite(::std::io::Error),
     XMLWriteXMLError(quick_xml::Error),
     XMLWriteIOError(::std::io::Error),
+    PBFWrite(::std::io::Error),
 }
 impl std::fmt::Display for OSMWriteError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -713,7 +1149,7 @@ pub trait OSMWriter<W: Write> {
     /// any more OSM objects. e.g. an XML file format will require that you close your root XML
     /// tag.
     /// After calling this method, you cannot add any more OSM objects to this writer, and
-    /// `is_open` will return `false`.
+    /// `is_open` will return `false`. Closing an already closed writer does nothing.
     fn close(&mut self) -> Result<(), OSMWriteError>;
 
     /// Return true iff this writer is not closed.
@@ -724,13 +1160,36 @@ pub trait OSMWriter<W: Write> {
     /// Write an OSM object to this.
     fn write_obj(&mut self, obj: &impl OSMObj) -> Result<(), OSMWriteError>;
 
-    /// Convert back to the underlying writer object
+    /// Convert back to the underlying writer object. This doesn't close the writer, so the file
+    /// may be incomplete, use [`OSMWriter::finish`] to close it first.
     fn into_inner(self) -> W;
 
+    /// Close this writer (if it's still open), flushing everything, and return the underlying
+    /// writer.
+    ///
+    /// Writers also close themselves when dropped, but errors are ignored then, so call this (or
+    /// [`OSMWriter::close`]) to know the file was written correctly.
+    fn finish(mut self) -> Result<W, OSMWriteError>
+    where
+        Self: Sized,
+    {
+        if self.is_open() {
+            self.close()?;
+        }
+        Ok(self.into_inner())
+    }
+
     fn set_header(&mut self, _key_value: (&str, &str)) -> Result<(), OSMWriteError> {
         todo!("set_header not done yet")
     }
 
+    /// Use this metadata (e.g. from [`OSMReader::file_info`]) for the header of the file. Like
+    /// `set_header`, this must be called before any objects are written. Parts which the format
+    /// can't store are ignored.
+    fn set_file_info(&mut self, _file_info: &header::FileInfo) -> Result<(), OSMWriteError> {
+        Err(OSMWriteError::FormatDoesntSupportHeaders)
+    }
+
     /// Create a new OSMWriter, consume all the objects from an OSMObj iterator source, and then
     /// close this source. Returns this OSMWriter.
     fn from_iter<I: Iterator<Item = impl OSMObj>>(writer: W, iter: I) -> Self
@@ -761,7 +1220,14 @@ pub fn read_pbf(filename: impl AsRef<Path>) -> Result<pbf::PBFReader<BufReader<F
     pbf::PBFReader::from_filename(filename)
 }
 
+/// Opens a PBF or OSM XML file (optionally compressed), or stdin if the filename is `-`. The
+/// format is detected from the contents, see [`open::AnyReader`].
+pub fn read_any(filename: impl AsRef<Path>) -> Result<open::AnyReader> {
+    open::AnyReader::from_filename(filename)
+}
+
 /// Opens a bzip2 filename
+#[cfg(feature = "bzip2")]
 pub fn read_xml(
     filename: impl AsRef<Path>,
 ) -> Result<xml::XMLReader<bzip2::read::MultiBzDecoder<std::fs::File>>> {
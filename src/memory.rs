@@ -0,0 +1,186 @@
+//! Limiting how much memory a pipeline uses in total.
+//!
+//! A [`MemoryBudget`] is shared (it's cheap to clone) between the components which buffer data.
+//! Each one reserves memory from the budget before using it, and when the budget is used up, it
+//! spills to disk, evicts cached data or waits instead of growing. These components can use a
+//! budget:
+//!
+//! * [`ExternalSorter`](crate::sort::ExternalSorter) &
+//!   [`NodeWayIndexBuilder`](crate::node_ways::NodeWayIndexBuilder) write runs to disk
+//! * [`HybridNodeLocations`](crate::node_locations::HybridNodeLocations) drops cached pages
+//! * [`PBFReader::par_for_each_block`](crate::pbf::PBFReader::par_for_each_block) stops reading
+//!   until queued blocks have been decoded
+//! * [`PBFWriter`](crate::pbf::PBFWriter) writes the current block early
+//!
+//! ```no_run
+//! use osmio::memory::MemoryBudget;
+//! use osmio::node_locations::HybridNodeLocations;
+//! use osmio::sort::ExternalSorter;
+//!
+//! // Sorting & the node cache together use at most about 2 GiB
+//! let budget = MemoryBudget::new(2 << 30);
+//! let mut sorter = ExternalSorter::new().memory_budget(budget.clone());
+//! let locations = HybridNodeLocations::create("nodes.bin", 0)?.memory_budget(budget.clone());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Sizes are estimates of the heap memory used, not exact, so leave some headroom.
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct Inner {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+/// A limit on the total number of bytes, shared between several components. Clones share the
+/// same budget.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget(Arc<Inner>);
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget(Arc::new(Inner {
+            limit,
+            used: AtomicUsize::new(0),
+        }))
+    }
+
+    /// A budget which never runs out, but still tracks how much is used
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    /// The limit, in bytes
+    pub fn limit(&self) -> usize {
+        self.0.limit
+    }
+
+    /// Number of bytes currently reserved
+    pub fn used(&self) -> usize {
+        self.0.used.load(Ordering::Relaxed)
+    }
+
+    /// Number of bytes which can still be reserved
+    pub fn available(&self) -> usize {
+        self.limit().saturating_sub(self.used())
+    }
+
+    /// An empty reservation, which can be grown later
+    pub fn reservation(&self) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    /// Reserve `bytes`, if that doesn't go over the limit
+    pub fn try_reserve(&self, bytes: usize) -> Option<Reservation> {
+        let mut reservation = self.reservation();
+        if reservation.try_grow(bytes) {
+            Some(reservation)
+        } else {
+            None
+        }
+    }
+
+    fn try_add(&self, bytes: usize) -> bool {
+        self.0
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|&new| new <= self.0.limit)
+            })
+            .is_ok()
+    }
+
+    fn force_add(&self, bytes: usize) {
+        self.0.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub(&self, bytes: usize) {
+        self.0.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {} bytes used", self.used(), self.limit())
+    }
+}
+
+/// Some bytes reserved from a [`MemoryBudget`], which are given back when this is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+}
+
+impl Reservation {
+    /// Number of bytes reserved
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Reserve `bytes` more, returning `false` (and reserving nothing) if that would go over the
+    /// limit
+    pub fn try_grow(&mut self, bytes: usize) -> bool {
+        if self.budget.try_add(bytes) {
+            self.bytes += bytes;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Reserve `bytes` more, even if that goes over the limit. For memory which is needed to make
+    /// progress at all (e.g. one object).
+    pub fn grow(&mut self, bytes: usize) {
+        self.budget.force_add(bytes);
+        self.bytes += bytes;
+    }
+
+    /// Give back `bytes` (or everything, if that's more than is reserved)
+    pub fn shrink(&mut self, bytes: usize) {
+        let bytes = bytes.min(self.bytes);
+        self.budget.sub(bytes);
+        self.bytes -= bytes;
+    }
+
+    /// Give back everything
+    pub fn clear(&mut self) {
+        self.shrink(self.bytes);
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget() {
+        let budget = MemoryBudget::new(100);
+        let mut a = budget.try_reserve(60).unwrap();
+        assert!(budget.try_reserve(41).is_none());
+        let b = budget.try_reserve(40).unwrap();
+        assert_eq!(budget.available(), 0);
+
+        assert!(!a.try_grow(1));
+        a.grow(10);
+        assert_eq!(budget.used(), 110);
+        a.shrink(30);
+        assert_eq!(a.bytes(), 40);
+        drop(b);
+        assert_eq!(budget.used(), 40);
+        drop(a);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,203 @@
+//! Merging several sorted files into one sorted file, like `osmium merge`.
+//!
+//! All the inputs must be sorted (nodes, then ways, then relations, each by id). Only one object
+//! from each input is kept in memory at a time. When the same object (type & id) is in more than
+//! one input, the [`DuplicatePolicy`] decides what's written.
+//!
+//! ```no_run
+//! use osmio::merge::{merge, DuplicatePolicy};
+//! use osmio::xml::XMLWriter;
+//! use osmio::OSMWriter;
+//!
+//! let readers = vec![osmio::read_pbf("ireland.osm.pbf")?, osmio::read_pbf("uk.osm.pbf")?];
+//! let mut writer = XMLWriter::new(std::fs::File::create("merged.osm")?);
+//! merge(readers, DuplicatePolicy::KeepNewest, &mut writer)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io::Write;
+use {OSMObjBase, OSMObjectType, OSMReader, OSMWriter, ObjId};
+
+use anyhow::{bail, Result};
+
+/// What to do when an object is in more than one input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Keep the object with the highest version. If the versions are the same, the object from
+    /// the earliest input is kept.
+    #[default]
+    KeepNewest,
+    /// Keep the object from the earliest input
+    KeepFirst,
+    /// Return an error
+    Error,
+}
+
+/// Merge the objects from all these readers, writing them in sorted order to `writer` (which
+/// isn't closed). Returns the number of objects written.
+pub fn merge<R, W>(
+    readers: impl IntoIterator<Item = R>,
+    policy: DuplicatePolicy,
+    writer: &mut impl OSMWriter<W>,
+) -> Result<u64>
+where
+    R: OSMReader,
+    W: Write,
+{
+    let mut readers: Vec<R> = readers.into_iter().collect();
+    let mut heads: Vec<Option<R::Obj>> = Vec::with_capacity(readers.len());
+    // Smallest (type, id, input index) first
+    let mut heap: BinaryHeap<Reverse<(OSMObjectType, ObjId, usize)>> = BinaryHeap::new();
+    for (idx, reader) in readers.iter_mut().enumerate() {
+        let head = reader.next();
+        if let Some(obj) = &head {
+            heap.push(Reverse((obj.object_type(), obj.id(), idx)));
+        }
+        heads.push(head);
+    }
+
+    let mut num_written = 0;
+    while let Some(Reverse((object_type, id, _))) = heap.peek().copied() {
+        // All the copies of this object, from all inputs
+        let mut copies: Vec<(usize, R::Obj)> = Vec::new();
+        while let Some(Reverse((other_type, other_id, idx))) = heap.peek().copied() {
+            if (other_type, other_id) != (object_type, id) {
+                break;
+            }
+            heap.pop();
+            let obj = heads[idx].take().unwrap();
+            let next = readers[idx].next();
+            if let Some(next) = &next {
+                let next_key = (next.object_type(), next.id());
+                if next_key < (object_type, id) {
+                    bail!(
+                        "Input {} isn't sorted: {}{} is after {}{}",
+                        idx,
+                        next_key.0.name_short(),
+                        next_key.1,
+                        object_type.name_short(),
+                        id
+                    );
+                }
+                heap.push(Reverse((next_key.0, next_key.1, idx)));
+            }
+            heads[idx] = next;
+            copies.push((idx, obj));
+        }
+
+        let first_idx = copies[0].0;
+        let is_duplicate = copies.iter().any(|(idx, _)| *idx != first_idx);
+        if !is_duplicate {
+            // Only in one input (maybe several versions, if it's a history file)
+            for (_, obj) in copies {
+                writer.write_obj(&obj)?;
+                num_written += 1;
+            }
+            continue;
+        }
+
+        match policy {
+            DuplicatePolicy::Error => {
+                let inputs: Vec<String> = copies.iter().map(|(idx, _)| idx.to_string()).collect();
+                bail!(
+                    "{}{} is in more than one input ({})",
+                    object_type.name_short(),
+                    id,
+                    inputs.join(", ")
+                );
+            }
+            DuplicatePolicy::KeepFirst => {
+                let first_idx = copies.iter().map(|(idx, _)| *idx).min().unwrap();
+                for (_, obj) in copies.into_iter().filter(|(idx, _)| *idx == first_idx) {
+                    writer.write_obj(&obj)?;
+                    num_written += 1;
+                }
+            }
+            DuplicatePolicy::KeepNewest => {
+                // Highest version, then earliest input
+                let newest = copies
+                    .iter()
+                    .max_by_key(|(idx, obj)| (obj.version(), Reverse(*idx)))
+                    .map(|(_, obj)| obj)
+                    .unwrap();
+                writer.write_obj(newest)?;
+                num_written += 1;
+            }
+        }
+    }
+
+    Ok(num_written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use xml::{XMLReader, XMLWriter};
+
+    fn reader(objs: &str) -> XMLReader<Cursor<String>> {
+        XMLReader::new(Cursor::new(format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<osm version=\"0.6\">\n{}\n</osm>",
+            objs
+        )))
+    }
+
+    fn inputs() -> Vec<XMLReader<Cursor<String>>> {
+        vec![
+            reader(
+                r#"<node id="1" version="1" lat="1" lon="1"/>
+<node id="3" version="2" lat="1" lon="1"/>
+<way id="10" version="1"><nd ref="1"/><nd ref="3"/></way>"#,
+            ),
+            reader(
+                r#"<node id="2" version="1" lat="1" lon="1"/>
+<node id="3" version="5" lat="2" lon="2"/>
+<relation id="20" version="1"><member type="node" ref="2" role=""/></relation>"#,
+            ),
+        ]
+    }
+
+    fn run(policy: DuplicatePolicy) -> Result<Vec<(OSMObjectType, ObjId, Option<u32>)>> {
+        let mut output = Vec::new();
+        {
+            let mut writer = XMLWriter::new(&mut output);
+            merge(inputs(), policy, &mut writer)?;
+        }
+        let mut reader = XMLReader::new(Cursor::new(output));
+        Ok(reader
+            .objects()
+            .map(|o| (o.object_type(), o.id(), o.version()))
+            .collect())
+    }
+
+    #[test]
+    fn policies() {
+        use OSMObjectType::*;
+        assert_eq!(
+            run(DuplicatePolicy::KeepNewest).unwrap(),
+            vec![
+                (Node, 1, Some(1)),
+                (Node, 2, Some(1)),
+                (Node, 3, Some(5)),
+                (Way, 10, Some(1)),
+                (Relation, 20, Some(1))
+            ]
+        );
+        assert_eq!(
+            run(DuplicatePolicy::KeepFirst).unwrap()[2],
+            (Node, 3, Some(2))
+        );
+        assert!(run(DuplicatePolicy::Error).is_err());
+    }
+
+    #[test]
+    fn unsorted() {
+        let readers = vec![reader(
+            r#"<node id="2" version="1" lat="1" lon="1"/>
+<node id="1" version="1" lat="1" lon="1"/>"#,
+        )];
+        let mut writer = XMLWriter::new(Vec::new());
+        assert!(merge(readers, DuplicatePolicy::KeepFirst, &mut writer).is_err());
+    }
+}
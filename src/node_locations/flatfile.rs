@@ -0,0 +1,321 @@
+//! Node locations stored in a flat file on disk
+use super::{NodeLocations, EMPTY};
+use byteorder::{BigEndian, ByteOrder};
+use lru::LruCache;
+use memory::{MemoryBudget, Reservation};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use {Lat, Lon, ObjId};
+
+/// Number of bytes used for each node
+const BYTES_PER_NODE: u64 = 8;
+
+/// Node locations stored in a file, at offset `node_id * 8`, as 2 big endian `i32`s (latitude &
+/// longitude). This takes up 8 bytes for every node id from 0 to the largest node id, about 90 GB
+/// for the planet.
+///
+/// Every lookup reads from the file, so wrap it in a [`HybridNodeLocations`] if lookups have
+/// spatial locality.
+///
+/// Panics on I/O errors when writing. I/O errors when reading are returned as “not found”.
+#[derive(Debug)]
+pub struct FlatFileNodeLocations {
+    file: RefCell<fs::File>,
+    /// Number of node ids the file currently has space for
+    len: u64,
+    negative: HashMap<ObjId, (Lat, Lon)>,
+}
+
+impl FlatFileNodeLocations {
+    /// Create a new, empty, store at this path. Any existing file is truncated.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self::from_file(file, 0))
+    }
+
+    /// Open a store previously created with [`FlatFileNodeLocations::create`].
+    ///
+    /// Negative node ids are not stored on disk, and so are not present after reopening.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() / BYTES_PER_NODE;
+        Ok(Self::from_file(file, len))
+    }
+
+    fn from_file(file: fs::File, len: u64) -> Self {
+        FlatFileNodeLocations {
+            file: RefCell::new(file),
+            len,
+            negative: HashMap::new(),
+        }
+    }
+
+    /// Fill `buf` with the raw `(lat, lon)`s for node ids starting at `first_node_id`. Node ids
+    /// past the end of the file are filled with `EMPTY`.
+    fn read_raw(&self, first_node_id: u64, buf: &mut [(i32, i32)]) -> io::Result<()> {
+        buf.fill((EMPTY, EMPTY));
+        if first_node_id >= self.len {
+            return Ok(());
+        }
+        let num_to_read = (self.len - first_node_id).min(buf.len() as u64) as usize;
+        let mut bytes = vec![0; num_to_read * BYTES_PER_NODE as usize];
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(first_node_id * BYTES_PER_NODE))?;
+        file.read_exact(&mut bytes)?;
+        for (loc, chunk) in buf
+            .iter_mut()
+            .zip(bytes.chunks_exact(BYTES_PER_NODE as usize))
+        {
+            *loc = (
+                BigEndian::read_i32(&chunk[0..4]),
+                BigEndian::read_i32(&chunk[4..8]),
+            );
+        }
+        Ok(())
+    }
+
+    fn write_raw(&mut self, node_id: u64, lat: i32, lon: i32) -> io::Result<()> {
+        let file = self.file.get_mut();
+        if node_id > self.len {
+            // Fill the gap with empty values
+            let empty_node = [0x7F, 0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0xFF, 0xFF];
+            let empty_chunk: Vec<u8> = empty_node.iter().copied().cycle().take(64 * 1024).collect();
+            file.seek(SeekFrom::Start(self.len * BYTES_PER_NODE))?;
+            let mut remaining = ((node_id - self.len) * BYTES_PER_NODE) as usize;
+            while remaining > 0 {
+                let this_chunk = remaining.min(empty_chunk.len());
+                file.write_all(&empty_chunk[..this_chunk])?;
+                remaining -= this_chunk;
+            }
+        }
+        let mut bytes = [0; BYTES_PER_NODE as usize];
+        BigEndian::write_i32(&mut bytes[0..4], lat);
+        BigEndian::write_i32(&mut bytes[4..8], lon);
+        file.seek(SeekFrom::Start(node_id * BYTES_PER_NODE))?;
+        file.write_all(&bytes)?;
+        self.len = self.len.max(node_id + 1);
+        Ok(())
+    }
+}
+
+impl NodeLocations for FlatFileNodeLocations {
+    fn get(&self, node_id: ObjId) -> Option<(Lat, Lon)> {
+        if node_id < 0 {
+            return self.negative.get(&node_id).copied();
+        }
+        let mut loc = [(EMPTY, EMPTY)];
+        self.read_raw(node_id as u64, &mut loc).ok()?;
+        match loc[0] {
+            (EMPTY, _) => None,
+            (lat, lon) => Some((Lat::from_inner(lat), Lon::from_inner(lon))),
+        }
+    }
+
+    fn set(&mut self, node_id: ObjId, loc: (Lat, Lon)) {
+        if node_id < 0 {
+            self.negative.insert(node_id, loc);
+            return;
+        }
+        self.write_raw(node_id as u64, loc.0.inner(), loc.1.inner())
+            .expect("Unable to write node location to file");
+    }
+}
+
+/// Number of consecutive node ids which are read from disk & cached together
+const PAGE_SIZE: usize = 4096;
+
+/// Raw locations for `PAGE_SIZE` consecutive node ids
+type Page = Box<[(i32, i32)]>;
+
+/// A [`FlatFileNodeLocations`] with an in-memory least-recently-used cache.
+///
+/// Node locations are read from disk in “pages” of consecutive node ids. Since nodes which are
+/// near each other usually have similar ids, a sorted file of ways will mostly need nodes which
+/// are already in the cache, giving (nearly) in-memory speed, without needing to store all nodes
+/// in memory.
+#[derive(Debug)]
+pub struct HybridNodeLocations {
+    flatfile: FlatFileNodeLocations,
+    cache: RefCell<LruCache<u64, Page>>,
+    /// Memory used by the cache, if it's limited by a budget
+    memory: RefCell<Option<Reservation>>,
+}
+
+impl HybridNodeLocations {
+    /// Create a new, empty, store at this path, which will cache about `max_cached_nodes` node
+    /// locations in memory (8 bytes each).
+    pub fn create(path: impl AsRef<Path>, max_cached_nodes: usize) -> io::Result<Self> {
+        Ok(Self::from_flatfile(
+            FlatFileNodeLocations::create(path)?,
+            max_cached_nodes,
+        ))
+    }
+
+    /// Open an existing flat file store, see [`FlatFileNodeLocations::open`].
+    pub fn open(path: impl AsRef<Path>, max_cached_nodes: usize) -> io::Result<Self> {
+        Ok(Self::from_flatfile(
+            FlatFileNodeLocations::open(path)?,
+            max_cached_nodes,
+        ))
+    }
+
+    pub fn from_flatfile(flatfile: FlatFileNodeLocations, max_cached_nodes: usize) -> Self {
+        let num_pages =
+            NonZeroUsize::new(max_cached_nodes / PAGE_SIZE).unwrap_or(NonZeroUsize::MIN);
+        HybridNodeLocations {
+            flatfile,
+            cache: RefCell::new(LruCache::new(num_pages)),
+            memory: RefCell::new(None),
+        }
+    }
+
+    /// Size the cache by this budget, instead of `max_cached_nodes`: pages are cached while
+    /// there's memory available, and the least recently used pages are dropped when there isn't.
+    pub fn memory_budget(self, budget: MemoryBudget) -> Self {
+        HybridNodeLocations {
+            flatfile: self.flatfile,
+            cache: RefCell::new(LruCache::unbounded()),
+            memory: RefCell::new(Some(budget.reservation())),
+        }
+    }
+
+    /// Reserve memory for one more page, dropping old pages if the budget is used up
+    fn reserve_page(&self, cache: &mut LruCache<u64, Page>) {
+        let page_bytes = PAGE_SIZE * std::mem::size_of::<(i32, i32)>();
+        if let Some(memory) = self.memory.borrow_mut().as_mut() {
+            while !memory.try_grow(page_bytes) {
+                if cache.pop_lru().is_some() {
+                    memory.shrink(page_bytes);
+                } else {
+                    // At least one page is needed
+                    memory.grow(page_bytes);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Convert back to the underlying flat file store (dropping the cache)
+    pub fn into_inner(self) -> FlatFileNodeLocations {
+        self.flatfile
+    }
+}
+
+impl NodeLocations for HybridNodeLocations {
+    fn get(&self, node_id: ObjId) -> Option<(Lat, Lon)> {
+        if node_id < 0 {
+            return self.flatfile.get(node_id);
+        }
+        let node_id = node_id as u64;
+        let page_no = node_id / PAGE_SIZE as u64;
+        let offset = (node_id % PAGE_SIZE as u64) as usize;
+
+        let mut cache = self.cache.borrow_mut();
+        let loc = match cache.get(&page_no) {
+            Some(page) => page[offset],
+            None => {
+                let mut page = vec![(EMPTY, EMPTY); PAGE_SIZE].into_boxed_slice();
+                self.flatfile
+                    .read_raw(page_no * PAGE_SIZE as u64, &mut page)
+                    .ok()?;
+                let loc = page[offset];
+                self.reserve_page(&mut cache);
+                cache.put(page_no, page);
+                loc
+            }
+        };
+        match loc {
+            (EMPTY, _) => None,
+            (lat, lon) => Some((Lat::from_inner(lat), Lon::from_inner(lon))),
+        }
+    }
+
+    fn set(&mut self, node_id: ObjId, loc: (Lat, Lon)) {
+        self.flatfile.set(node_id, loc);
+        if node_id >= 0 {
+            let page_no = node_id as u64 / PAGE_SIZE as u64;
+            let offset = node_id as usize % PAGE_SIZE;
+            if let Some(page) = self.cache.get_mut().peek_mut(&page_no) {
+                page[offset] = (loc.0.inner(), loc.1.inner());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(lat: i32, lon: i32) -> (Lat, Lon) {
+        (Lat::from_inner(lat), Lon::from_inner(lon))
+    }
+
+    #[test]
+    fn flatfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flatfile");
+        let mut store = FlatFileNodeLocations::create(&path).unwrap();
+        store.set(10, loc(1, 2));
+        store.set(3, loc(0, 0));
+        store.set(-1, loc(5, 5));
+        assert_eq!(store.get(10), Some(loc(1, 2)));
+        assert_eq!(store.get(3), Some(loc(0, 0)));
+        assert_eq!(store.get(-1), Some(loc(5, 5)));
+        assert_eq!(store.get(4), None);
+        assert_eq!(store.get(11), None);
+        drop(store);
+
+        let store = FlatFileNodeLocations::open(&path).unwrap();
+        assert_eq!(store.get(10), Some(loc(1, 2)));
+        assert_eq!(store.get(9), None);
+    }
+
+    #[test]
+    fn hybrid() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hybrid");
+        let mut store = HybridNodeLocations::create(&path, PAGE_SIZE).unwrap();
+        for i in 0..(3 * PAGE_SIZE as i64) {
+            store.set(i * 2, loc(i as i32, -(i as i32)));
+        }
+        // reads pages in & out of the 1 page cache
+        for i in (0..(3 * PAGE_SIZE as i64)).rev() {
+            assert_eq!(store.get(i * 2), Some(loc(i as i32, -(i as i32))));
+            assert_eq!(store.get(i * 2 + 1), None);
+        }
+        // writes update the cached page
+        assert_eq!(store.get(0), Some(loc(0, 0)));
+        store.set(1, loc(9, 9));
+        assert_eq!(store.get(1), Some(loc(9, 9)));
+    }
+
+    #[test]
+    fn hybrid_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("hybrid_budget");
+        let page_bytes = PAGE_SIZE * 8;
+        let budget = MemoryBudget::new(2 * page_bytes);
+        let mut store = HybridNodeLocations::create(&path, 0)
+            .unwrap()
+            .memory_budget(budget.clone());
+        for i in 0..(4 * PAGE_SIZE as i64) {
+            store.set(i, loc(i as i32, 0));
+        }
+        for i in 0..(4 * PAGE_SIZE as i64) {
+            assert_eq!(store.get(i), Some(loc(i as i32, 0)));
+        }
+        assert_eq!(store.cache.borrow().len(), 2);
+        assert_eq!(budget.used(), 2 * page_bytes);
+        drop(store);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,185 @@
+//! Storing the locations of nodes, so they can be looked up later by node id.
+//!
+//! Ways only store the ids of their nodes, so anything which needs the geometry of a way (or
+//! relation) needs somewhere to look up where those nodes are.
+//!
+//! ```rust
+//! use osmio::node_locations::{NodeLocations, SparseNodeLocations};
+//! use osmio::{Lat, Lon};
+//!
+//! let mut locations = SparseNodeLocations::new();
+//! locations.set(1, (Lat::from_inner(10), Lon::from_inner(20)));
+//! assert_eq!(locations.get(1), Some((Lat::from_inner(10), Lon::from_inner(20))));
+//! assert_eq!(locations.get(2), None);
+//! ```
+use std::collections::HashMap;
+use {Lat, Lon, Node, ObjId};
+
+mod flatfile;
+pub use self::flatfile::{FlatFileNodeLocations, HybridNodeLocations};
+
+/// Something which can store & look up the location of a node.
+pub trait NodeLocations {
+    /// The location of this node, if known
+    fn get(&self, node_id: ObjId) -> Option<(Lat, Lon)>;
+
+    /// Store the location of this node, replacing any previous location
+    fn set(&mut self, node_id: ObjId, loc: (Lat, Lon));
+
+    /// True iff the location of this node is known
+    fn contains(&self, node_id: ObjId) -> bool {
+        self.get(node_id).is_some()
+    }
+
+    /// Store the location of this node. Nodes without a location (e.g. deleted nodes) are ignored.
+    fn add_node(&mut self, node: &impl Node) {
+        if let Some(loc) = node.lat_lon() {
+            self.set(node.id(), loc);
+        }
+    }
+
+    /// Look up the location of every node id in `node_ids`. Returns `None` if any are missing.
+    fn get_all(&self, node_ids: &[ObjId]) -> Option<Vec<(Lat, Lon)>> {
+        node_ids.iter().map(|nid| self.get(*nid)).collect()
+    }
+}
+
+/// Locations stored in a `Vec`, indexed by node id.
+///
+/// Uses 8 bytes for every node id from 0 to the largest node id, whether it's present or not. This
+/// is the most efficient in-memory store for the whole planet (where nearly all node ids are
+/// used), but is wasteful for small extracts; use [`SparseNodeLocations`] for them. If the
+/// locations don't fit in memory, use [`HybridNodeLocations`].
+///
+/// Negative node ids (e.g. from JOSM files) are stored separately in a `HashMap`.
+#[derive(Debug, Default, Clone)]
+pub struct DenseNodeLocations {
+    locations: Vec<(i32, i32)>,
+    negative: HashMap<ObjId, (Lat, Lon)>,
+}
+
+/// Used in [`DenseNodeLocations`] for node ids which have no location. It's not a valid latitude.
+const EMPTY: i32 = i32::MAX;
+
+impl DenseNodeLocations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a store with space for node ids up to (but excluding) `max_node_id`, to avoid
+    /// reallocations.
+    pub fn with_capacity(max_node_id: usize) -> Self {
+        DenseNodeLocations {
+            locations: Vec::with_capacity(max_node_id),
+            negative: HashMap::new(),
+        }
+    }
+}
+
+impl NodeLocations for DenseNodeLocations {
+    fn get(&self, node_id: ObjId) -> Option<(Lat, Lon)> {
+        if node_id < 0 {
+            return self.negative.get(&node_id).copied();
+        }
+        match self.locations.get(node_id as usize) {
+            None | Some((EMPTY, _)) => None,
+            Some(&(lat, lon)) => Some((Lat::from_inner(lat), Lon::from_inner(lon))),
+        }
+    }
+
+    fn set(&mut self, node_id: ObjId, loc: (Lat, Lon)) {
+        if node_id < 0 {
+            self.negative.insert(node_id, loc);
+            return;
+        }
+        let idx = node_id as usize;
+        if idx >= self.locations.len() {
+            self.locations.resize(idx + 1, (EMPTY, EMPTY));
+        }
+        self.locations[idx] = (loc.0.inner(), loc.1.inner());
+    }
+}
+
+/// Locations stored in a `HashMap`. Best for extracts, where only a small fraction of all node ids
+/// are used.
+#[derive(Debug, Default, Clone)]
+pub struct SparseNodeLocations {
+    locations: HashMap<ObjId, (Lat, Lon)>,
+}
+
+impl SparseNodeLocations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of node locations stored
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+}
+
+impl NodeLocations for SparseNodeLocations {
+    fn get(&self, node_id: ObjId) -> Option<(Lat, Lon)> {
+        self.locations.get(&node_id).copied()
+    }
+
+    fn set(&mut self, node_id: ObjId, loc: (Lat, Lon)) {
+        self.locations.insert(node_id, loc);
+    }
+}
+
+impl NodeLocations for HashMap<ObjId, (Lat, Lon)> {
+    fn get(&self, node_id: ObjId) -> Option<(Lat, Lon)> {
+        HashMap::get(self, &node_id).copied()
+    }
+
+    fn set(&mut self, node_id: ObjId, loc: (Lat, Lon)) {
+        self.insert(node_id, loc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loc(lat: i32, lon: i32) -> (Lat, Lon) {
+        (Lat::from_inner(lat), Lon::from_inner(lon))
+    }
+
+    fn check_store(mut store: impl NodeLocations) {
+        assert_eq!(store.get(1), None);
+        store.set(1, loc(1, 2));
+        store.set(100, loc(-3, -4));
+        store.set(-5, loc(5, 6));
+        assert_eq!(store.get(1), Some(loc(1, 2)));
+        assert_eq!(store.get(100), Some(loc(-3, -4)));
+        assert_eq!(store.get(-5), Some(loc(5, 6)));
+        assert_eq!(store.get(50), None);
+        assert!(!store.contains(2));
+
+        store.set(1, loc(7, 8));
+        assert_eq!(store.get(1), Some(loc(7, 8)));
+
+        assert_eq!(store.get_all(&[1, 100]), Some(vec![loc(7, 8), loc(-3, -4)]));
+        assert_eq!(store.get_all(&[1, 2]), None);
+    }
+
+    #[test]
+    fn dense() {
+        check_store(DenseNodeLocations::new());
+    }
+
+    #[test]
+    fn sparse() {
+        check_store(SparseNodeLocations::new());
+    }
+
+    #[test]
+    fn hashmap() {
+        check_store(HashMap::new());
+    }
+}
@@ -0,0 +1,431 @@
+//! Index from node id to the ways which use that node (the node's “parent” ways).
+//!
+//! Ways store their nodes, but there's no way to go from a node to its ways without reading all
+//! the ways. This is needed to find intersections, process turn restrictions, or answer “which
+//! streets touch this node”.
+//!
+//! The index is a sorted list of `(node id, way id)` pairs. Small indexes are kept in memory. If
+//! there are more pairs than [`NodeWayIndexBuilder::max_pairs_in_memory`], they're sorted on disk
+//! with an [`ExternalSorter`], and the index is a temporary file, which is searched without
+//! loading it into memory.
+//!
+//! ```rust,no_run
+//! use osmio::node_ways::NodeWayIndex;
+//!
+//! let mut reader = osmio::read_pbf("input.osm.pbf")?;
+//! let index = NodeWayIndex::from_reader(&mut reader)?;
+//! println!("Node 1 is in ways {:?}", index.ways(1)?);
+//! for node_id in index.shared_nodes() {
+//!     println!("Node {} is in more than 1 way", node_id?);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use byteorder::{BigEndian, ByteOrder};
+use memory::{MemoryBudget, Reservation};
+use sort::{ExternalSorter, SortItem};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use {OSMObj, OSMReader, ObjId, Way};
+
+use anyhow::Result;
+
+/// Number of bytes for each `(node id, way id)` pair on disk
+const BYTES_PER_PAIR: usize = 16;
+
+/// Number of pairs read from disk at once when iterating
+const READ_CHUNK: usize = 4096;
+
+fn encode_pair((node_id, way_id): (ObjId, ObjId)) -> [u8; BYTES_PER_PAIR] {
+    let mut bytes = [0; BYTES_PER_PAIR];
+    BigEndian::write_i64(&mut bytes[0..8], node_id);
+    BigEndian::write_i64(&mut bytes[8..16], way_id);
+    bytes
+}
+
+fn decode_pair(bytes: &[u8]) -> (ObjId, ObjId) {
+    (
+        BigEndian::read_i64(&bytes[0..8]),
+        BigEndian::read_i64(&bytes[8..16]),
+    )
+}
+
+/// Pairs are sorted by node id, then way id
+impl SortItem for (ObjId, ObjId) {
+    type Key = (ObjId, ObjId);
+
+    fn sort_key(&self) -> Self::Key {
+        *self
+    }
+}
+
+/// Builds a [`NodeWayIndex`] from ways.
+pub struct NodeWayIndexBuilder {
+    sorter: ExternalSorter<(ObjId, ObjId)>,
+    temp_dir: PathBuf,
+    budget: Option<MemoryBudget>,
+}
+
+impl Default for NodeWayIndexBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NodeWayIndexBuilder {
+    /// A new builder, which keeps up to 10,000,000 pairs in memory, and uses the system temporary
+    /// directory.
+    pub fn new() -> Self {
+        NodeWayIndexBuilder {
+            sorter: ExternalSorter::default().max_objects_in_memory(10_000_000),
+            temp_dir: std::env::temp_dir(),
+            budget: None,
+        }
+    }
+
+    /// Keep at most this many pairs in memory before writing them to disk.
+    pub fn max_pairs_in_memory(mut self, max_pairs_in_memory: usize) -> Self {
+        self.sorter = self.sorter.max_objects_in_memory(max_pairs_in_memory);
+        self
+    }
+
+    /// Read at most this many sorted runs at once. See [`ExternalSorter::max_merge_runs`].
+    pub fn max_merge_runs(mut self, max_merge_runs: usize) -> Self {
+        self.sorter = self.sorter.max_merge_runs(max_merge_runs);
+        self
+    }
+
+    /// Store temporary files in this directory.
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = temp_dir.into();
+        self.sorter = self.sorter.temp_dir(self.temp_dir.clone());
+        self
+    }
+
+    /// Also write the pairs to disk when they would use more than the available memory in this
+    /// budget. If the index is kept in memory, the memory is reserved until it's dropped.
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.sorter = self.sorter.memory_budget(budget.clone());
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Add the nodes of this way. Each way should only be added once.
+    pub fn add_way(&mut self, way: &impl Way) -> Result<()> {
+        let mut node_ids = way.nodes().to_vec();
+        // Closed ways have the first node twice
+        node_ids.sort_unstable();
+        node_ids.dedup();
+        for node_id in node_ids {
+            self.sorter.push((node_id, way.id()))?;
+        }
+        Ok(())
+    }
+
+    /// Add all the ways from this iterator (e.g. `reader.objects()`). Other objects are ignored.
+    pub fn extend(&mut self, objs: impl IntoIterator<Item = impl OSMObj>) -> Result<()> {
+        for obj in objs {
+            if let Some(way) = obj.as_way() {
+                self.add_way(way)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish adding ways, and return the index.
+    pub fn finish(self) -> Result<NodeWayIndex> {
+        let on_disk = self.sorter.num_runs() > 0;
+        let mut sorted = self.sorter.finish()?;
+        if !on_disk {
+            let pairs: Vec<_> = sorted.by_ref().collect();
+            let memory = self.budget.map(|budget| {
+                let mut memory = budget.reservation();
+                memory.grow(std::mem::size_of_val(&pairs[..]));
+                memory
+            });
+            return Ok(NodeWayIndex {
+                storage: Storage::Memory(pairs),
+                _memory: memory,
+            });
+        }
+
+        let mut output = BufWriter::new(tempfile::tempfile_in(&self.temp_dir)?);
+        let mut len = 0;
+        while let Some(pair) = sorted.try_next()? {
+            output.write_all(&encode_pair(pair))?;
+            len += 1;
+        }
+        Ok(NodeWayIndex {
+            storage: Storage::Disk {
+                file: RefCell::new(output.into_inner()?),
+                len,
+            },
+            _memory: None,
+        })
+    }
+}
+
+enum Storage {
+    Memory(Vec<(ObjId, ObjId)>),
+    /// Sorted pairs in a file. `len` is the number of pairs.
+    Disk {
+        file: RefCell<File>,
+        len: u64,
+    },
+}
+
+/// Index from node id to the ids of the ways which contain that node. See the
+/// [module documentation](self).
+pub struct NodeWayIndex {
+    storage: Storage,
+    /// The memory used by in-memory pairs, given back when the index is dropped
+    _memory: Option<Reservation>,
+}
+
+impl NodeWayIndex {
+    /// Build an (in-memory if small enough) index of all the ways in this reader. Returns an
+    /// error if the file is invalid.
+    pub fn from_reader(reader: &mut impl OSMReader) -> Result<Self> {
+        let mut builder = NodeWayIndexBuilder::new();
+        for obj in reader.try_objects() {
+            if let Some(way) = obj?.as_way() {
+                builder.add_way(way)?;
+            }
+        }
+        builder.finish()
+    }
+
+    /// Number of `(node id, way id)` pairs
+    pub fn len(&self) -> u64 {
+        match &self.storage {
+            Storage::Memory(pairs) => pairs.len() as u64,
+            Storage::Disk { len, .. } => *len,
+        }
+    }
+
+    /// True iff there are no pairs
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// True iff the index is stored on disk
+    pub fn is_on_disk(&self) -> bool {
+        matches!(self.storage, Storage::Disk { .. })
+    }
+
+    /// Read `num` pairs (or fewer at the end) starting at pair `idx` into `buf`
+    fn read_pairs(&self, idx: u64, buf: &mut Vec<(ObjId, ObjId)>, num: usize) -> Result<()> {
+        buf.clear();
+        match &self.storage {
+            Storage::Memory(pairs) => {
+                let start = (idx as usize).min(pairs.len());
+                let end = start.saturating_add(num).min(pairs.len());
+                buf.extend_from_slice(&pairs[start..end]);
+            }
+            Storage::Disk { file, len } => {
+                let num = (len.saturating_sub(idx)).min(num as u64) as usize;
+                let mut bytes = vec![0; num * BYTES_PER_PAIR];
+                let mut file = file.borrow_mut();
+                file.seek(SeekFrom::Start(idx * BYTES_PER_PAIR as u64))?;
+                file.read_exact(&mut bytes)?;
+                buf.extend(bytes.chunks_exact(BYTES_PER_PAIR).map(decode_pair));
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the first pair with a node id `>= node_id`
+    fn lower_bound(&self, node_id: ObjId) -> Result<u64> {
+        match &self.storage {
+            Storage::Memory(pairs) => Ok(pairs.partition_point(|(n, _)| *n < node_id) as u64),
+            Storage::Disk { len, .. } => {
+                let (mut lo, mut hi) = (0, *len);
+                let mut buf = Vec::with_capacity(1);
+                while lo < hi {
+                    let mid = lo + (hi - lo) / 2;
+                    self.read_pairs(mid, &mut buf, 1)?;
+                    if buf[0].0 < node_id {
+                        lo = mid + 1;
+                    } else {
+                        hi = mid;
+                    }
+                }
+                Ok(lo)
+            }
+        }
+    }
+
+    /// The ids of the ways containing this node, sorted
+    pub fn ways(&self, node_id: ObjId) -> Result<Vec<ObjId>> {
+        let mut way_ids = Vec::new();
+        let mut idx = self.lower_bound(node_id)?;
+        let mut buf = Vec::new();
+        loop {
+            self.read_pairs(idx, &mut buf, 16)?;
+            if buf.is_empty() {
+                break;
+            }
+            let before = way_ids.len();
+            way_ids.extend(
+                buf.iter()
+                    .take_while(|(n, _)| *n == node_id)
+                    .map(|(_, w)| *w),
+            );
+            if way_ids.len() - before < buf.len() {
+                break;
+            }
+            idx += buf.len() as u64;
+        }
+        Ok(way_ids)
+    }
+
+    /// Number of ways containing this node
+    pub fn num_ways(&self, node_id: ObjId) -> Result<usize> {
+        Ok(self.ways(node_id)?.len())
+    }
+
+    /// All the `(node id, way id)` pairs, sorted by node id, then way id
+    pub fn pairs(&self) -> impl Iterator<Item = Result<(ObjId, ObjId)>> + '_ {
+        let mut buf = Vec::new();
+        let mut buf_idx = 0;
+        let mut next_idx = 0;
+        std::iter::from_fn(move || {
+            if buf_idx >= buf.len() {
+                if let Err(e) = self.read_pairs(next_idx, &mut buf, READ_CHUNK) {
+                    // Stop after the error
+                    next_idx = self.len();
+                    buf.clear();
+                    return Some(Err(e));
+                }
+                buf_idx = 0;
+                next_idx += buf.len() as u64;
+            }
+            let pair = buf.get(buf_idx).copied()?;
+            buf_idx += 1;
+            Some(Ok(pair))
+        })
+    }
+
+    /// Every node id, with the ids of the ways which contain it, sorted by node id
+    pub fn iter(&self) -> impl Iterator<Item = Result<(ObjId, Vec<ObjId>)>> + '_ {
+        let mut pairs = self.pairs().peekable();
+        std::iter::from_fn(move || {
+            let (node_id, way_id) = match pairs.next()? {
+                Ok(pair) => pair,
+                Err(e) => return Some(Err(e)),
+            };
+            let mut way_ids = vec![way_id];
+            while let Some(Ok((_, way_id))) =
+                pairs.next_if(|p| matches!(p, Ok((n, _)) if *n == node_id))
+            {
+                way_ids.push(way_id);
+            }
+            Some(Ok((node_id, way_ids)))
+        })
+    }
+
+    /// The ids of all nodes which are in more than one way (e.g. intersections), sorted
+    pub fn shared_nodes(&self) -> impl Iterator<Item = Result<ObjId>> + '_ {
+        self.iter().filter_map(|res| match res {
+            Ok((node_id, way_ids)) if way_ids.len() > 1 => Some(Ok(node_id)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringOSMObj, StringWayBuilder};
+    use xml::XMLReader;
+
+    fn ways() -> Vec<StringOSMObj> {
+        [
+            (10, vec![1, 2, 3]),
+            (11, vec![3, 4, 5, 3]),
+            (12, vec![5, 6]),
+            (13, vec![-1, 3]),
+        ]
+        .iter()
+        .map(|(id, nodes)| {
+            StringWayBuilder::default()
+                ._id(*id)
+                ._nodes(nodes.clone())
+                .build()
+                .unwrap()
+                .into()
+        })
+        .collect()
+    }
+
+    fn check(index: NodeWayIndex) {
+        assert_eq!(index.len(), 10);
+        assert_eq!(index.ways(3).unwrap(), vec![10, 11, 13]);
+        assert_eq!(index.ways(1).unwrap(), vec![10]);
+        assert_eq!(index.ways(-1).unwrap(), vec![13]);
+        assert_eq!(index.ways(7).unwrap(), Vec::<ObjId>::new());
+        assert_eq!(index.ways(-5).unwrap(), Vec::<ObjId>::new());
+        assert_eq!(index.num_ways(5).unwrap(), 2);
+
+        let shared: Vec<_> = index.shared_nodes().map(|n| n.unwrap()).collect();
+        assert_eq!(shared, vec![3, 5]);
+        let all: Vec<_> = index.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(all.len(), 7);
+        assert_eq!(all[0], (-1, vec![13]));
+    }
+
+    #[test]
+    fn in_memory() {
+        let mut builder = NodeWayIndexBuilder::new();
+        builder.extend(ways()).unwrap();
+        let index = builder.finish().unwrap();
+        assert!(!index.is_on_disk());
+        check(index);
+    }
+
+    #[test]
+    fn from_reader() {
+        let input = r#"<osm version="0.6"><way id="10"><nd ref="1"/><nd ref="2"/></way></osm>"#;
+        let index = NodeWayIndex::from_reader(&mut XMLReader::new(input.as_bytes())).unwrap();
+        assert_eq!(index.ways(2).unwrap(), vec![10]);
+
+        let input = r#"<osm version="0.6"><way id="10"><nd ref="1"/></way><way id="x"/></osm>"#;
+        assert!(NodeWayIndex::from_reader(&mut XMLReader::new(input.as_bytes())).is_err());
+    }
+
+    #[test]
+    fn on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut builder = NodeWayIndexBuilder::new()
+            .max_pairs_in_memory(3)
+            .max_merge_runs(2)
+            .temp_dir(dir.path());
+        builder.extend(ways()).unwrap();
+        let index = builder.finish().unwrap();
+        assert!(index.is_on_disk());
+        check(index);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let budget = MemoryBudget::new(100 * BYTES_PER_PAIR);
+        let mut builder = NodeWayIndexBuilder::new().memory_budget(budget.clone());
+        builder.extend(ways()).unwrap();
+        let index = builder.finish().unwrap();
+        assert!(!index.is_on_disk());
+        assert_eq!(budget.used(), 10 * BYTES_PER_PAIR);
+        check(index);
+        assert_eq!(budget.used(), 0);
+
+        let budget = MemoryBudget::new(4 * BYTES_PER_PAIR);
+        let mut builder = NodeWayIndexBuilder::new().memory_budget(budget.clone());
+        builder.extend(ways()).unwrap();
+        assert!(budget.used() <= budget.limit());
+        let index = builder.finish().unwrap();
+        assert!(index.is_on_disk());
+        assert_eq!(budget.used(), 0);
+        check(index);
+    }
+}
@@ -0,0 +1,460 @@
+//! Map notes, from the notes dump or the OSM API
+//!
+//! Parses the `planet-notes-latest.osn.bz2` notes dump from
+//! [https://planet.openstreetmap.org/notes/](https://planet.openstreetmap.org/notes/), and the
+//! XML returned by the notes API (e.g. `/api/0.6/notes?bbox=…`, or `/api/0.6/notes/1`). Both are
+//! read into the same [`Note`] struct.
+//!
+//! ```rust,no_run
+//! use osmio::notes::{NoteReader, NoteStatus};
+//! # fn main() -> anyhow::Result<()> {
+//! let reader = NoteReader::from_filename("planet-notes-latest.osn.bz2")?;
+//! for note in reader {
+//!     let note = note?;
+//!     if note.status == NoteStatus::Open {
+//!         println!("{} has {} comments", note.id, note.comments.len());
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use super::*;
+use anyhow::{bail, Context};
+#[cfg(feature = "bzip2")]
+use bzip2::read::MultiBzDecoder;
+use quick_xml::events::Event;
+use std::io::BufRead;
+
+/// Whether a note is open, closed (resolved) or hidden (by a moderator)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NoteStatus {
+    Open,
+    Closed,
+    Hidden,
+}
+
+impl NoteStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoteStatus::Open => "open",
+            NoteStatus::Closed => "closed",
+            NoteStatus::Hidden => "hidden",
+        }
+    }
+}
+
+impl FromStr for NoteStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(NoteStatus::Open),
+            "closed" => Ok(NoteStatus::Closed),
+            "hidden" => Ok(NoteStatus::Hidden),
+            _ => bail!("Unknown note status {:?}", s),
+        }
+    }
+}
+
+/// What a comment did to the note
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NoteAction {
+    Opened,
+    Commented,
+    Closed,
+    Reopened,
+    Hidden,
+}
+
+impl NoteAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NoteAction::Opened => "opened",
+            NoteAction::Commented => "commented",
+            NoteAction::Closed => "closed",
+            NoteAction::Reopened => "reopened",
+            NoteAction::Hidden => "hidden",
+        }
+    }
+}
+
+impl FromStr for NoteAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opened" => Ok(NoteAction::Opened),
+            "commented" => Ok(NoteAction::Commented),
+            "closed" => Ok(NoteAction::Closed),
+            "reopened" => Ok(NoteAction::Reopened),
+            "hidden" => Ok(NoteAction::Hidden),
+            _ => bail!("Unknown note action {:?}", s),
+        }
+    }
+}
+
+/// One comment on a note. Opening, closing etc. are comments too, possibly with empty text.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NoteComment {
+    pub action: NoteAction,
+    pub timestamp: TimestampFormat,
+    /// `None` for anonymous comments
+    pub uid: Option<u32>,
+    pub user: Option<String>,
+    pub text: String,
+}
+
+/// A single map note
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Note {
+    pub id: u64,
+    pub lat: Lat,
+    pub lon: Lon,
+    pub created: TimestampFormat,
+    /// When it was (last) closed, if it's closed
+    pub closed: Option<TimestampFormat>,
+    pub status: NoteStatus,
+    /// All comments, oldest first
+    pub comments: Vec<NoteComment>,
+}
+
+impl Note {
+    /// The text of the opening comment, i.e. the description of the problem
+    pub fn text(&self) -> Option<&str> {
+        self.comments
+            .first()
+            .filter(|c| c.action == NoteAction::Opened)
+            .map(|c| c.text.as_str())
+    }
+}
+
+/// The API uses `2019-06-15 08:26:04 UTC`, the dump uses ISO 8601
+fn api_timestamp(s: &str) -> TimestampFormat {
+    match s.strip_suffix(" UTC") {
+        Some(t) => TimestampFormat::ISOString(format!("{}Z", t.replacen(' ', "T", 1))),
+        None => TimestampFormat::ISOString(s.to_string()),
+    }
+}
+
+/// Read the text content of the element that was just started, up to its end
+fn read_text<R: BufRead>(reader: &mut quick_xml::Reader<R>, buf: &mut Vec<u8>) -> Result<String> {
+    let mut text = String::new();
+    let mut depth = 0;
+    loop {
+        buf.clear();
+        match reader.read_event_into(buf)? {
+            Event::Text(t) => text.push_str(&t.unescape()?),
+            Event::CData(t) => text.push_str(std::str::from_utf8(&t)?),
+            Event::Start(_) => depth += 1,
+            Event::End(_) if depth == 0 => return Ok(text),
+            Event::End(_) => depth -= 1,
+            Event::Eof => bail!("File ends in the middle of an element"),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the notes dump, or notes API XML, and produces [`Note`]s
+pub struct NoteReader<R: Read> {
+    reader: quick_xml::Reader<BufReader<R>>,
+    buf: Vec<u8>,
+    text_buf: Vec<u8>,
+}
+
+impl<R: Read> NoteReader<R> {
+    pub fn new(reader: R) -> NoteReader<R> {
+        NoteReader {
+            reader: quick_xml::Reader::from_reader(BufReader::new(reader)),
+            buf: Vec::new(),
+            text_buf: Vec::new(),
+        }
+    }
+
+    /// Get a refernce to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        self.reader.get_ref().get_ref()
+    }
+    /// Consumes Reader returning the underlying reader
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner().into_inner()
+    }
+
+    pub fn next_note(&mut self) -> Result<Option<Note>> {
+        let (mut id, mut lat, mut lon, mut created, mut closed) = (None, None, None, None, None);
+        // move forward until we are at a note tag (happens at the start)
+        loop {
+            self.buf.clear();
+            match self.reader.read_event_into(&mut self.buf)? {
+                Event::Eof => return Ok(None),
+                Event::Start(ref e) if e.name().local_name().as_ref() == b"note" => {
+                    for attr in e.attributes() {
+                        let attr = attr?;
+                        let value = attr.decode_and_unescape_value(&self.reader)?;
+                        match attr.key.local_name().as_ref() {
+                            b"id" => id = Some(value.parse()?),
+                            b"lat" => lat = Some(value.parse()?),
+                            b"lon" => lon = Some(value.parse()?),
+                            b"created_at" => {
+                                created = Some(TimestampFormat::ISOString(value.to_string()))
+                            }
+                            b"closed_at" => {
+                                closed = Some(TimestampFormat::ISOString(value.to_string()))
+                            }
+                            _ => {}
+                        }
+                    }
+                    break;
+                }
+                _ => continue,
+            }
+        }
+
+        let mut status = None;
+        let mut comments = Vec::new();
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match self.reader.read_event_into(&mut buf)? {
+                Event::Eof => bail!("File ends in the middle of a note"),
+                Event::End(ref e) if e.name().local_name().as_ref() == b"note" => break,
+                Event::Start(ref e) => match e.name().local_name().as_ref() {
+                    // API child elements
+                    b"id" => id = Some(read_text(&mut self.reader, &mut self.text_buf)?.parse()?),
+                    b"date_created" => {
+                        created = Some(api_timestamp(&read_text(
+                            &mut self.reader,
+                            &mut self.text_buf,
+                        )?))
+                    }
+                    b"date_closed" => {
+                        closed = Some(api_timestamp(&read_text(
+                            &mut self.reader,
+                            &mut self.text_buf,
+                        )?))
+                    }
+                    b"status" => {
+                        status = Some(read_text(&mut self.reader, &mut self.text_buf)?.parse()?)
+                    }
+                    b"comment" if e.attributes().next().is_none() => {
+                        comments.push(self.api_comment()?);
+                    }
+                    // dump comment
+                    b"comment" => {
+                        let (action, timestamp, uid, user) = self.dump_comment_attrs(e)?;
+                        let text = read_text(&mut self.reader, &mut self.text_buf)?;
+                        comments.push(NoteComment {
+                            action,
+                            timestamp,
+                            uid,
+                            user,
+                            text,
+                        });
+                    }
+                    _ => {}
+                },
+                Event::Empty(ref e) if e.name().local_name().as_ref() == b"comment" => {
+                    let (action, timestamp, uid, user) = self.dump_comment_attrs(e)?;
+                    comments.push(NoteComment {
+                        action,
+                        timestamp,
+                        uid,
+                        user,
+                        text: String::new(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        let status = match status {
+            Some(status) => status,
+            None if comments.last().map(|c| c.action) == Some(NoteAction::Hidden) => {
+                NoteStatus::Hidden
+            }
+            None if closed.is_some() => NoteStatus::Closed,
+            None => NoteStatus::Open,
+        };
+        Ok(Some(Note {
+            id: id.context("Note has no id")?,
+            lat: lat.context("Note has no lat")?,
+            lon: lon.context("Note has no lon")?,
+            created: created.context("Note has no creation date")?,
+            closed,
+            status,
+            comments,
+        }))
+    }
+
+    fn dump_comment_attrs(
+        &self,
+        e: &quick_xml::events::BytesStart,
+    ) -> Result<(NoteAction, TimestampFormat, Option<u32>, Option<String>)> {
+        let (mut action, mut timestamp, mut uid, mut user) = (None, None, None, None);
+        for attr in e.attributes() {
+            let attr = attr?;
+            let value = attr.decode_and_unescape_value(&self.reader)?;
+            match attr.key.local_name().as_ref() {
+                b"action" => action = Some(value.parse()?),
+                b"timestamp" => timestamp = Some(TimestampFormat::ISOString(value.to_string())),
+                b"uid" => uid = Some(value.parse()?),
+                b"user" => user = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Ok((
+            action.context("Note comment has no action")?,
+            timestamp.context("Note comment has no timestamp")?,
+            uid,
+            user,
+        ))
+    }
+
+    /// Read an API `<comment>`, whose fields are child elements
+    fn api_comment(&mut self) -> Result<NoteComment> {
+        let (mut action, mut timestamp, mut uid, mut user, mut text) =
+            (None, None, None, None, String::new());
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match self.reader.read_event_into(&mut buf)? {
+                Event::Eof => bail!("File ends in the middle of a note comment"),
+                Event::End(_) => break,
+                Event::Start(ref e) => {
+                    let value = read_text(&mut self.reader, &mut self.text_buf)?;
+                    match e.name().local_name().as_ref() {
+                        b"action" => action = Some(value.parse()?),
+                        b"date" => timestamp = Some(api_timestamp(&value)),
+                        b"uid" => uid = Some(value.parse()?),
+                        b"user" => user = Some(value),
+                        b"text" => text = value,
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(NoteComment {
+            action: action.context("Note comment has no action")?,
+            timestamp: timestamp.context("Note comment has no date")?,
+            uid,
+            user,
+            text,
+        })
+    }
+}
+
+impl<R: Read> Iterator for NoteReader<R> {
+    type Item = Result<Note>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_note().transpose()
+    }
+}
+
+#[cfg(feature = "bzip2")]
+impl NoteReader<MultiBzDecoder<File>> {
+    /// Read a bzip2 compressed notes dump, e.g. `planet-notes-latest.osn.bz2`
+    pub fn from_filename(filename: &str) -> Result<Self> {
+        let f = File::open(filename)?;
+        Ok(NoteReader::new(MultiBzDecoder::new(f)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm-notes>
+<note id="1" lat="51.5" lon="-0.1" created_at="2013-04-24T08:07:02Z" closed_at="2013-04-25T10:00:00Z">
+<comment action="opened" timestamp="2013-04-24T08:07:02Z" uid="1" user="alice">Missing &amp; wrong
+road</comment>
+<comment action="closed" timestamp="2013-04-25T10:00:00Z" uid="2" user="bob"/>
+</note>
+<note id="2" lat="48.8" lon="2.3" created_at="2014-01-01T00:00:00Z">
+<comment action="opened" timestamp="2014-01-01T00:00:00Z">anon</comment>
+</note>
+<note id="3" lat="0" lon="0" created_at="2015-01-01T00:00:00Z">
+<comment action="opened" timestamp="2015-01-01T00:00:00Z">spam</comment>
+<comment action="hidden" timestamp="2015-01-02T00:00:00Z" uid="3" user="mod"></comment>
+</note>
+</osm-notes>"#;
+        let notes: Vec<Note> = NoteReader::new(xml.as_bytes())
+            .map(|n| n.unwrap())
+            .collect();
+        assert_eq!(notes.len(), 3);
+
+        assert_eq!(notes[0].id, 1);
+        assert_eq!(notes[0].lat, Lat::try_from(51.5).unwrap());
+        assert_eq!(notes[0].status, NoteStatus::Closed);
+        assert_eq!(
+            notes[0].closed,
+            Some(TimestampFormat::ISOString("2013-04-25T10:00:00Z".into()))
+        );
+        assert_eq!(notes[0].text(), Some("Missing & wrong\nroad"));
+        assert_eq!(notes[0].comments[1].action, NoteAction::Closed);
+        assert_eq!(notes[0].comments[1].user.as_deref(), Some("bob"));
+        assert_eq!(notes[0].comments[1].text, "");
+
+        assert_eq!(notes[1].status, NoteStatus::Open);
+        assert_eq!(notes[1].comments[0].uid, None);
+        assert_eq!(notes[1].comments[0].user, None);
+
+        assert_eq!(notes[2].status, NoteStatus::Hidden);
+    }
+
+    #[test]
+    fn api() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="OpenStreetMap server">
+  <note lon="-0.1" lat="51.5">
+    <id>42</id>
+    <url>https://api.openstreetmap.org/api/0.6/notes/42</url>
+    <reopen_url>https://api.openstreetmap.org/api/0.6/notes/42/reopen</reopen_url>
+    <date_created>2019-06-15 08:26:04 UTC</date_created>
+    <status>closed</status>
+    <date_closed>2019-06-16 09:00:00 UTC</date_closed>
+    <comments>
+      <comment>
+        <date>2019-06-15 08:26:04 UTC</date>
+        <uid>1</uid>
+        <user>alice</user>
+        <user_url>https://api.openstreetmap.org/user/alice</user_url>
+        <action>opened</action>
+        <text>Shop &lt;closed&gt;</text>
+        <html>&lt;p&gt;Shop &amp;lt;closed&amp;gt;&lt;/p&gt;</html>
+      </comment>
+      <comment>
+        <date>2019-06-16 09:00:00 UTC</date>
+        <action>closed</action>
+        <text></text>
+        <html>&lt;p&gt;&lt;/p&gt;</html>
+      </comment>
+    </comments>
+  </note>
+</osm>"#;
+        let notes: Vec<Note> = NoteReader::new(xml.as_bytes())
+            .map(|n| n.unwrap())
+            .collect();
+        assert_eq!(notes.len(), 1);
+        let note = &notes[0];
+        assert_eq!(note.id, 42);
+        assert_eq!(note.lon, Lon::try_from(-0.1).unwrap());
+        assert_eq!(
+            note.created,
+            TimestampFormat::ISOString("2019-06-15T08:26:04Z".into())
+        );
+        assert_eq!(
+            note.closed,
+            Some(TimestampFormat::ISOString("2019-06-16T09:00:00Z".into()))
+        );
+        assert_eq!(note.status, NoteStatus::Closed);
+        assert_eq!(note.comments.len(), 2);
+        assert_eq!(note.text(), Some("Shop <closed>"));
+        assert_eq!(note.comments[0].uid, Some(1));
+        assert_eq!(note.comments[1].action, NoteAction::Closed);
+        assert_eq!(note.comments[1].user, None);
+    }
+}
@@ -1,3 +1,5 @@
+use parse_mode::AnomalyKind;
+use std::borrow::Cow;
 use std::sync::Arc;
 use *;
 
@@ -21,7 +23,130 @@ macro_rules! func_call_inner_set {
     };
 }
 
-#[derive(PartialEq, Debug, Clone)]
+/// The strings of one PBF block, in one buffer. All the objects decoded from a block share it
+/// (with one `Arc` each), and refer to their strings by index.
+#[derive(Debug, Default)]
+pub(crate) struct StringTable {
+    data: String,
+    /// Where each string is in `data`, or `None` if it isn't valid UTF-8
+    ranges: Vec<Option<(u32, u32)>>,
+    /// Indexes of the strings which weren't valid UTF-8, and were decoded lossily
+    repaired: Vec<u32>,
+}
+
+impl StringTable {
+    /// Strings which aren't valid UTF-8 are decoded lossily (with `U+FFFD` replacement
+    /// characters) if `lossy`, otherwise they're left out.
+    pub(crate) fn new<S: AsRef<[u8]>>(strings: &[S], lossy: bool) -> Self {
+        let mut table = StringTable::default();
+        table.refill(strings, lossy);
+        table
+    }
+
+    /// Replace the strings with these, reusing the buffers
+    pub(crate) fn refill<S: AsRef<[u8]>>(&mut self, strings: &[S], lossy: bool) {
+        self.data.clear();
+        self.data
+            .reserve(strings.iter().map(|s| s.as_ref().len()).sum());
+        self.ranges.clear();
+        self.ranges.reserve(strings.len());
+        self.repaired.clear();
+        for (idx, s) in strings.iter().enumerate() {
+            let s = match std::str::from_utf8(s.as_ref()) {
+                Ok(s) => Some(Cow::Borrowed(s)),
+                Err(_) if lossy => {
+                    self.repaired.push(idx as u32);
+                    Some(String::from_utf8_lossy(s.as_ref()))
+                }
+                Err(_) => None,
+            };
+            let range = s.map(|s| {
+                let start = self.data.len() as u32;
+                self.data.push_str(&s);
+                (start, self.data.len() as u32)
+            });
+            self.ranges.push(range);
+        }
+    }
+
+    /// True iff the string at this index wasn't valid UTF-8, and was decoded lossily
+    pub(crate) fn is_repaired(&self, idx: u32) -> bool {
+        self.repaired.binary_search(&idx).is_ok()
+    }
+
+    /// Number of strings
+    pub(crate) fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// The string at this index, or `None` if there isn't one, or it isn't valid UTF-8
+    pub(crate) fn get(&self, idx: u32) -> Option<&str> {
+        let (start, end) = (*self.ranges.get(idx as usize)?)?;
+        Some(&self.data[start as usize..end as usize])
+    }
+
+    /// Look up an index from a PBF message. Returns the index if there's a valid string there,
+    /// and the anomaly (with its detail) if the index is out of range, or the string isn't valid
+    /// UTF-8 (or was decoded lossily).
+    pub(crate) fn check(&self, idx: i64) -> (Option<u32>, Option<(AnomalyKind, String)>) {
+        let idx = match u32::try_from(idx) {
+            Ok(idx) if (idx as usize) < self.len() => idx,
+            _ => {
+                let detail = format!("index {}, but there are {} strings", idx, self.len());
+                return (None, Some((AnomalyKind::StringIndexOutOfRange, detail)));
+            }
+        };
+        if self.get(idx).is_none() {
+            let detail = format!("string {}", idx);
+            (None, Some((AnomalyKind::InvalidUtf8, detail)))
+        } else if self.is_repaired(idx) {
+            let detail = format!("string {} (decoded lossily)", idx);
+            (Some(idx), Some((AnomalyKind::InvalidUtf8, detail)))
+        } else {
+            (Some(idx), None)
+        }
+    }
+}
+
+/// A string of an object: an index into the object's [`StringTable`], or an owned string (if it
+/// was changed after decoding).
+#[derive(Debug, Clone)]
+pub(crate) enum StrRef {
+    Table(u32),
+    Owned(Arc<str>),
+}
+
+impl StrRef {
+    /// A reference to the string at `idx`, if it's a valid string
+    pub(crate) fn table(strings: &StringTable, idx: u32) -> Option<StrRef> {
+        strings.get(idx).map(|_| StrRef::Table(idx))
+    }
+
+    fn owned(s: &str) -> StrRef {
+        StrRef::Owned(Arc::from(s))
+    }
+
+    pub(crate) fn resolve<'a>(&'a self, strings: &'a StringTable) -> &'a str {
+        match self {
+            StrRef::Table(idx) => strings.get(*idx).unwrap_or(""),
+            StrRef::Owned(s) => s,
+        }
+    }
+}
+
+/// Equal if all the metadata & tags are equal (regardless of where the strings are stored)
+fn base_eq(a: &impl OSMObjBase, b: &impl OSMObjBase) -> bool {
+    a.id() == b.id()
+        && a.version() == b.version()
+        && a.deleted() == b.deleted()
+        && a.changeset_id() == b.changeset_id()
+        && a.timestamp() == b.timestamp()
+        && a.uid() == b.uid()
+        && a.user() == b.user()
+        && a.tags().eq(b.tags())
+}
+
+#[derive(Debug, Clone)]
 pub struct ArcNode {
     pub(crate) _id: ObjId,
     pub(crate) _version: Option<u32>,
@@ -29,13 +154,14 @@ pub struct ArcNode {
     pub(crate) _changeset_id: Option<u32>,
     pub(crate) _timestamp: Option<TimestampFormat>,
     pub(crate) _uid: Option<u32>,
-    pub(crate) _user: Option<Arc<str>>,
-    pub(crate) _tags: Option<Vec<(Arc<str>, Arc<str>)>>,
+    pub(crate) _strings: Arc<StringTable>,
+    pub(crate) _user: Option<StrRef>,
+    pub(crate) _tags: Option<Vec<(StrRef, StrRef)>>,
 
     pub(crate) _lat_lon: Option<(Lat, Lon)>,
 }
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct ArcWay {
     pub(crate) _id: ObjId,
     pub(crate) _version: Option<u32>,
@@ -43,13 +169,14 @@ pub struct ArcWay {
     pub(crate) _changeset_id: Option<u32>,
     pub(crate) _timestamp: Option<TimestampFormat>,
     pub(crate) _uid: Option<u32>,
-    pub(crate) _user: Option<Arc<str>>,
-    pub(crate) _tags: Vec<(Arc<str>, Arc<str>)>,
+    pub(crate) _strings: Arc<StringTable>,
+    pub(crate) _user: Option<StrRef>,
+    pub(crate) _tags: Vec<(StrRef, StrRef)>,
 
     pub(crate) _nodes: Vec<ObjId>,
 }
 
-#[derive(PartialEq, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct ArcRelation {
     pub(crate) _id: ObjId,
     pub(crate) _version: Option<u32>,
@@ -57,10 +184,29 @@ pub struct ArcRelation {
     pub(crate) _changeset_id: Option<u32>,
     pub(crate) _timestamp: Option<TimestampFormat>,
     pub(crate) _uid: Option<u32>,
-    pub(crate) _user: Option<Arc<str>>,
-    pub(crate) _tags: Vec<(Arc<str>, Arc<str>)>,
+    pub(crate) _strings: Arc<StringTable>,
+    pub(crate) _user: Option<StrRef>,
+    pub(crate) _tags: Vec<(StrRef, StrRef)>,
+
+    pub(crate) _members: Vec<(OSMObjectType, ObjId, StrRef)>,
+}
 
-    pub(crate) _members: Vec<(OSMObjectType, ObjId, Arc<str>)>,
+impl PartialEq for ArcNode {
+    fn eq(&self, other: &Self) -> bool {
+        base_eq(self, other) && self._lat_lon == other._lat_lon
+    }
+}
+
+impl PartialEq for ArcWay {
+    fn eq(&self, other: &Self) -> bool {
+        base_eq(self, other) && self._nodes == other._nodes
+    }
+}
+
+impl PartialEq for ArcRelation {
+    fn eq(&self, other: &Self) -> bool {
+        base_eq(self, other) && self.members().eq(other.members())
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -269,7 +415,7 @@ impl OSMObjBase for ArcNode {
         self._uid
     }
     fn user(&self) -> Option<&str> {
-        self._user.as_ref().map(|x| x as _)
+        self._user.as_ref().map(|u| u.resolve(&self._strings))
     }
 
     fn set_id(&mut self, val: impl Into<ObjId>) {
@@ -291,13 +437,16 @@ impl OSMObjBase for ArcNode {
         self._uid = val.into();
     }
     fn set_user<'a>(&mut self, val: impl Into<Option<&'a str>>) {
-        self._user = val.into().map(Arc::from);
+        self._user = val.into().map(StrRef::owned);
     }
 
     fn tags<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = (&'a str, &'a str)> + 'a> {
         match self._tags {
             None => Box::new(std::iter::empty()),
-            Some(ref t) => Box::new(t.iter().map(|(k, v)| (k.as_ref(), v.as_ref()))),
+            Some(ref t) => Box::new(
+                t.iter()
+                    .map(move |(k, v)| (k.resolve(&self._strings), v.resolve(&self._strings))),
+            ),
         }
     }
 
@@ -306,8 +455,8 @@ impl OSMObjBase for ArcNode {
         self._tags.as_ref().and_then(|tags| {
             tags.iter()
                 .filter_map(|(k, v)| {
-                    if k.as_ref() == key {
-                        Some(v.as_ref())
+                    if k.resolve(&self._strings) == key {
+                        Some(v.resolve(&self._strings))
                     } else {
                         None
                     }
@@ -317,34 +466,28 @@ impl OSMObjBase for ArcNode {
     }
 
     fn set_tag(&mut self, key: impl AsRef<str>, value: impl Into<String>) {
+        let strings = &self._strings;
         let key = key.as_ref();
         let value = value.into();
         match self._tags {
             None => {
-                self._tags = Some(vec![(Arc::from(key), Arc::from(value.as_str()))]);
+                self._tags = Some(vec![(StrRef::owned(key), StrRef::owned(&value))]);
             }
             Some(ref mut tags) => {
-                let idx = tags
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(i, (k, _))| if k.as_ref() == key { Some(i) } else { None })
-                    .next();
+                let idx = tags.iter().position(|(k, _)| k.resolve(strings) == key);
                 match idx {
-                    None => tags.push((Arc::from(key), Arc::from(value.as_str()))),
-                    Some(i) => tags[i] = (key.into(), Arc::from(value.as_str())),
+                    None => tags.push((StrRef::owned(key), StrRef::owned(&value))),
+                    Some(i) => tags[i].1 = StrRef::owned(&value),
                 }
             }
         }
     }
 
     fn unset_tag(&mut self, key: impl AsRef<str>) {
+        let strings = &self._strings;
         if let Some(ref mut tags) = self._tags {
             let key = key.as_ref();
-            let idx = tags
-                .iter()
-                .enumerate()
-                .filter_map(|(i, (k, _))| if k.as_ref() == key { Some(i) } else { None })
-                .next();
+            let idx = tags.iter().position(|(k, _)| k.resolve(strings) == key);
             if let Some(i) = idx {
                 tags.remove(i);
             }
@@ -386,7 +529,7 @@ impl OSMObjBase for ArcWay {
         self._uid
     }
     fn user(&self) -> Option<&str> {
-        self._user.as_ref().map(|x| x as _)
+        self._user.as_ref().map(|u| u.resolve(&self._strings))
     }
 
     fn set_id(&mut self, val: impl Into<ObjId>) {
@@ -408,11 +551,15 @@ impl OSMObjBase for ArcWay {
         self._uid = val.into();
     }
     fn set_user<'a>(&mut self, val: impl Into<Option<&'a str>>) {
-        self._user = val.into().map(Arc::from);
+        self._user = val.into().map(StrRef::owned);
     }
 
     fn tags<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = (&'a str, &'a str)> + 'a> {
-        Box::new(self._tags.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+        Box::new(
+            self._tags
+                .iter()
+                .map(move |(k, v)| (k.resolve(&self._strings), v.resolve(&self._strings))),
+        )
     }
 
     fn tag(&self, key: impl AsRef<str>) -> Option<&str> {
@@ -420,8 +567,8 @@ impl OSMObjBase for ArcWay {
         self._tags
             .iter()
             .filter_map(|(k, v)| {
-                if k.as_ref() == key {
-                    Some(v.as_ref())
+                if k.resolve(&self._strings) == key {
+                    Some(v.resolve(&self._strings))
                 } else {
                     None
                 }
@@ -430,28 +577,26 @@ impl OSMObjBase for ArcWay {
     }
 
     fn set_tag(&mut self, key: impl AsRef<str>, value: impl Into<String>) {
+        let strings = &self._strings;
         let key = key.as_ref();
         let value = value.into();
         let idx = self
             ._tags
             .iter()
-            .enumerate()
-            .filter_map(|(i, (k, _))| if k.as_ref() == key { Some(i) } else { None })
-            .next();
+            .position(|(k, _)| k.resolve(strings) == key);
         match idx {
-            None => self._tags.push((Arc::from(key), Arc::from(value.as_str()))),
-            Some(i) => self._tags[i] = (key.into(), Arc::from(value.as_str())),
+            None => self._tags.push((StrRef::owned(key), StrRef::owned(&value))),
+            Some(i) => self._tags[i].1 = StrRef::owned(&value),
         }
     }
 
     fn unset_tag(&mut self, key: impl AsRef<str>) {
+        let strings = &self._strings;
         let key = key.as_ref();
         let idx = self
             ._tags
             .iter()
-            .enumerate()
-            .filter_map(|(i, (k, _))| if k.as_ref() == key { Some(i) } else { None })
-            .next();
+            .position(|(k, _)| k.resolve(strings) == key);
         if let Some(i) = idx {
             self._tags.remove(i);
         }
@@ -500,7 +645,7 @@ impl OSMObjBase for ArcRelation {
         self._uid
     }
     fn user(&self) -> Option<&str> {
-        self._user.as_ref().map(|x| x as _)
+        self._user.as_ref().map(|u| u.resolve(&self._strings))
     }
 
     fn set_id(&mut self, val: impl Into<ObjId>) {
@@ -522,11 +667,15 @@ impl OSMObjBase for ArcRelation {
         self._uid = val.into();
     }
     fn set_user<'a>(&mut self, val: impl Into<Option<&'a str>>) {
-        self._user = val.into().map(Arc::from);
+        self._user = val.into().map(StrRef::owned);
     }
 
     fn tags<'a>(&'a self) -> Box<dyn ExactSizeIterator<Item = (&'a str, &'a str)> + 'a> {
-        Box::new(self._tags.iter().map(|(k, v)| (k.as_ref(), v.as_ref())))
+        Box::new(
+            self._tags
+                .iter()
+                .map(move |(k, v)| (k.resolve(&self._strings), v.resolve(&self._strings))),
+        )
     }
 
     fn tag(&self, key: impl AsRef<str>) -> Option<&str> {
@@ -534,8 +683,8 @@ impl OSMObjBase for ArcRelation {
         self._tags
             .iter()
             .filter_map(|(k, v)| {
-                if k.as_ref() == key {
-                    Some(v.as_ref())
+                if k.resolve(&self._strings) == key {
+                    Some(v.resolve(&self._strings))
                 } else {
                     None
                 }
@@ -544,28 +693,26 @@ impl OSMObjBase for ArcRelation {
     }
 
     fn set_tag(&mut self, key: impl AsRef<str>, value: impl Into<String>) {
+        let strings = &self._strings;
         let key = key.as_ref();
         let value = value.into();
         let idx = self
             ._tags
             .iter()
-            .enumerate()
-            .filter_map(|(i, (k, _))| if k.as_ref() == key { Some(i) } else { None })
-            .next();
+            .position(|(k, _)| k.resolve(strings) == key);
         match idx {
-            None => self._tags.push((Arc::from(key), Arc::from(value.as_str()))),
-            Some(i) => self._tags[i] = (key.into(), Arc::from(value.as_str())),
+            None => self._tags.push((StrRef::owned(key), StrRef::owned(&value))),
+            Some(i) => self._tags[i].1 = StrRef::owned(&value),
         }
     }
 
     fn unset_tag(&mut self, key: impl AsRef<str>) {
+        let strings = &self._strings;
         let key = key.as_ref();
         let idx = self
             ._tags
             .iter()
-            .enumerate()
-            .filter_map(|(i, (k, _))| if k.as_ref() == key { Some(i) } else { None })
-            .next();
+            .position(|(k, _)| k.resolve(strings) == key);
         if let Some(i) = idx {
             self._tags.remove(i);
         }
@@ -580,7 +727,11 @@ impl Relation for ArcRelation {
     fn members<'a>(
         &'a self,
     ) -> Box<dyn ExactSizeIterator<Item = (OSMObjectType, ObjId, &'a str)> + 'a> {
-        Box::new(self._members.iter().map(|(t, o, r)| (*t, *o, r.as_ref())))
+        Box::new(
+            self._members
+                .iter()
+                .map(move |(t, o, r)| (*t, *o, r.resolve(&self._strings))),
+        )
     }
 
     fn set_members(
@@ -592,7 +743,76 @@ impl Relation for ArcRelation {
         self._members.extend(
             members
                 .into_iter()
-                .map(|(t, i, r)| (t, i, Arc::from(r.into()))),
+                .map(|(t, i, r)| (t, i, StrRef::owned(&r.into()))),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn way(strings: &[&[u8]]) -> ArcWay {
+        let strings = Arc::new(StringTable::new(strings, false));
+        ArcWay {
+            _id: 1,
+            _version: None,
+            _deleted: false,
+            _changeset_id: None,
+            _timestamp: None,
+            _uid: None,
+            _user: StrRef::table(&strings, 0),
+            _tags: vec![(
+                StrRef::table(&strings, 1).unwrap(),
+                StrRef::table(&strings, 2).unwrap(),
+            )],
+            _nodes: vec![1, 2],
+            _strings: strings,
+        }
+    }
+
+    #[test]
+    fn string_table() {
+        let strings = [&b"highway"[..], b"\xff", b""];
+        let table = StringTable::new(&strings, false);
+        assert_eq!(table.get(0), Some("highway"));
+        assert_eq!(table.get(1), None);
+        assert_eq!(table.get(2), Some(""));
+        assert_eq!(table.get(3), None);
+
+        let table = StringTable::new(&strings, true);
+        assert_eq!(table.get(1), Some("\u{FFFD}"));
+        assert!(table.is_repaired(1));
+        assert!(!table.is_repaired(0));
+
+        assert_eq!(table.check(0), (Some(0), None));
+        assert_eq!(table.check(1).0, Some(1));
+        assert_eq!(table.check(1).1.unwrap().0, AnomalyKind::InvalidUtf8);
+        assert_eq!(table.check(-1).0, None);
+        assert_eq!(
+            table.check(3).1.unwrap().0,
+            AnomalyKind::StringIndexOutOfRange
+        );
+        let table = StringTable::new(&strings, false);
+        assert_eq!(table.check(1).0, None);
+    }
+
+    #[test]
+    fn shared_strings() {
+        let mut w = way(&[b"alice", b"highway", b"primary"]);
+        assert_eq!(w.user(), Some("alice"));
+        assert_eq!(w.tag("highway"), Some("primary"));
+
+        // Equal, even though the strings are stored differently
+        let other = way(&[b"alice", b"highway", b"primary", b"unused"]);
+        assert_eq!(w, other);
+
+        w.set_tag("highway", "secondary");
+        w.set_tag("name", "Main Street");
+        assert_eq!(
+            w.tags().collect::<Vec<_>>(),
+            vec![("highway", "secondary"), ("name", "Main Street")]
         );
+        assert_ne!(w, other);
     }
 }
@@ -1,11 +1,13 @@
 //! Base OpenStreetMap object types
 mod arc_types;
+mod packed;
 mod rc_types;
 mod string_types;
 
 use {Node, OSMObjBase, Relation, Way};
 
 pub use self::arc_types::*;
+pub use self::packed::{PackedNodes, PackedNodesIter};
 pub use self::rc_types::*;
 pub use self::string_types::*;
 
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::iter::FromIterator;
+use ObjId;
+
+/// The node ids of a way, stored compactly: each id is delta coded from the previous one, then
+/// zigzag & varint encoded, like PBF files do. The ids are decoded when iterating.
+///
+/// Consecutive nodes of a way usually have close ids, so most take 1–3 bytes instead of 8. This
+/// is for keeping many ways in memory, e.g. when assembling relations.
+///
+/// ```
+/// use osmio::obj_types::PackedNodes;
+/// let nodes: PackedNodes = vec![1_000_000, 1_000_001, 999_998, 1_000_000].into();
+/// assert_eq!(nodes.len(), 4);
+/// assert_eq!(nodes.iter().collect::<Vec<_>>(), [1_000_000, 1_000_001, 999_998, 1_000_000]);
+/// assert!(nodes.is_closed());
+/// assert!(nodes.heap_size() < 4 * 8);
+/// ```
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct PackedNodes {
+    bytes: Box<[u8]>,
+    len: u32,
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+impl PackedNodes {
+    /// No nodes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the node ids, decoding them
+    pub fn iter(&self) -> PackedNodesIter<'_> {
+        PackedNodesIter {
+            bytes: &self.bytes,
+            last: 0,
+            remaining: self.len as usize,
+        }
+    }
+
+    /// The first node id
+    pub fn first(&self) -> Option<ObjId> {
+        self.iter().next()
+    }
+
+    /// The last node id. This decodes all the ids.
+    pub fn last(&self) -> Option<ObjId> {
+        self.iter().last()
+    }
+
+    /// True iff this begins & ends with the same node, like
+    /// [`Way::is_closed`](crate::Way::is_closed)
+    pub fn is_closed(&self) -> bool {
+        match (self.first(), self.last()) {
+            (Some(a), Some(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Decode all the node ids
+    pub fn to_vec(&self) -> Vec<ObjId> {
+        let mut nodes = Vec::with_capacity(self.len());
+        nodes.extend(self.iter());
+        nodes
+    }
+
+    /// Bytes of heap memory used
+    pub fn heap_size(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl FromIterator<ObjId> for PackedNodes {
+    fn from_iter<I: IntoIterator<Item = ObjId>>(iter: I) -> Self {
+        let mut bytes = Vec::new();
+        let mut last = 0i64;
+        let mut len = 0u32;
+        for id in iter {
+            let mut v = zigzag(id.wrapping_sub(last));
+            last = id;
+            len += 1;
+            while v >= 0x80 {
+                bytes.push((v as u8) | 0x80);
+                v >>= 7;
+            }
+            bytes.push(v as u8);
+        }
+        PackedNodes {
+            bytes: bytes.into_boxed_slice(),
+            len,
+        }
+    }
+}
+
+impl<'a> From<&'a [ObjId]> for PackedNodes {
+    fn from(nodes: &'a [ObjId]) -> Self {
+        nodes.iter().copied().collect()
+    }
+}
+
+impl From<Vec<ObjId>> for PackedNodes {
+    fn from(nodes: Vec<ObjId>) -> Self {
+        nodes.into_iter().collect()
+    }
+}
+
+impl<'a> IntoIterator for &'a PackedNodes {
+    type Item = ObjId;
+    type IntoIter = PackedNodesIter<'a>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl fmt::Debug for PackedNodes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Iterator over the node ids of [`PackedNodes`]
+#[derive(Clone)]
+pub struct PackedNodesIter<'a> {
+    bytes: &'a [u8],
+    last: i64,
+    remaining: usize,
+}
+
+impl<'a> Iterator for PackedNodesIter<'a> {
+    type Item = ObjId;
+
+    fn next(&mut self) -> Option<ObjId> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.bytes[0];
+            self.bytes = &self.bytes[1..];
+            v |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        self.remaining -= 1;
+        self.last = self.last.wrapping_add(unzigzag(v));
+        Some(self.last)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for PackedNodesIter<'a> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let nodes = vec![
+            0,
+            1,
+            -5,
+            i64::MAX,
+            i64::MIN,
+            12_345_678_901,
+            12_345_678_900,
+            0,
+        ];
+        let packed = PackedNodes::from(nodes.clone());
+        assert_eq!(packed.len(), nodes.len());
+        assert_eq!(packed.to_vec(), nodes);
+        assert_eq!(packed.first(), Some(0));
+        assert_eq!(packed.last(), Some(0));
+        assert!(packed.is_closed());
+        assert_eq!(format!("{:?}", packed), format!("{:?}", nodes));
+
+        let empty = PackedNodes::new();
+        assert!(empty.is_empty());
+        assert_eq!(empty.iter().next(), None);
+        assert!(!empty.is_closed());
+    }
+
+    #[test]
+    fn smaller() {
+        let nodes: Vec<ObjId> = (0..1000).map(|i| 8_000_000_000 + i * 3).collect();
+        let packed: PackedNodes = nodes.iter().copied().collect();
+        // 5 bytes for the first, then 1 each
+        assert_eq!(packed.heap_size(), 5 + 999);
+        assert!(packed.iter().eq(nodes.iter().copied()));
+    }
+}
@@ -21,7 +21,7 @@ macro_rules! func_call_inner_set {
 }
 
 #[derive(PartialEq, Debug, Builder, Clone, Serialize, Deserialize)]
-#[builder(setter(strip_option))]
+#[builder(setter(strip_option), build_fn(error = "::Error"))]
 pub struct StringNode {
     pub(crate) _id: ObjId,
 
@@ -47,7 +47,7 @@ pub struct StringNode {
 }
 
 #[derive(PartialEq, Debug, Builder, Clone, Serialize, Deserialize)]
-#[builder(setter(strip_option))]
+#[builder(setter(strip_option), build_fn(error = "::Error"))]
 pub struct StringWay {
     pub(crate) _id: ObjId,
     #[builder(default = "None")]
@@ -71,7 +71,7 @@ pub struct StringWay {
 }
 
 #[derive(PartialEq, Debug, Builder, Clone, Serialize, Deserialize)]
-#[builder(setter(strip_option))]
+#[builder(setter(strip_option), build_fn(error = "::Error"))]
 pub struct StringRelation {
     pub(crate) _id: ObjId,
     #[builder(default = "None")]
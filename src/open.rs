@@ -0,0 +1,636 @@
+//! Opening files (or stdin/stdout) without knowing their format in advance.
+//!
+//! The filename `-` means stdin (when reading) or stdout (when writing), like most command line
+//! tools, so programs using these can be used in shell pipelines.
+//!
+//! [`AnyReader`] detects the file format (PBF or OSM XML) & compression (bzip2 or gzip) from the
+//! first bytes of the file, not from the filename, so it works for stdin too.
+//!
+//! ```no_run
+//! use osmio::open::{create_output, AnyReader};
+//! use osmio::xml::XMLWriter;
+//! use osmio::{OSMReader, OSMWriter};
+//!
+//! // osmio-cat < input.osm.pbf > output.osm
+//! let mut reader = AnyReader::from_filename("-")?;
+//! let mut writer = XMLWriter::new(create_output("-")?);
+//! for obj in reader.objects() {
+//!     writer.write_obj(&obj)?;
+//! }
+//! writer.close()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! [`AnyWriter`] goes the other way, choosing the format & compression from the output filename
+//! (e.g. `.osm.bz2`), and [`copy`] writes everything from a reader to a writer:
+//!
+//! ```no_run
+//! use osmio::open::{copy, AnyReader, AnyWriter};
+//!
+//! let mut reader = AnyReader::from_filename("input.osm.pbf")?;
+//! let mut writer = AnyWriter::from_filename("output.osm.gz", None)?;
+//! copy(&mut reader, &mut writer)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use arcpbf;
+#[cfg(feature = "cloud")]
+use cloud;
+use header::FileInfo;
+use obj_types::StringOSMObj;
+use parse_mode::{DuplicateTags, NonPositiveIds, ParseMode};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::Path;
+use stringpbf;
+use xml::{self, XMLReader, XMLWriter};
+use {Error, OSMObj, OSMReader, OSMWriteError, OSMWriter};
+
+use anyhow::Result;
+
+/// The filename which means stdin or stdout
+pub const STDIO_FILENAME: &str = "-";
+
+/// Number of bytes looked at to detect the format
+const PREFIX_LEN: usize = 256;
+
+/// The format of an OSM data file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FileFormat {
+    Pbf,
+    Xml,
+    /// An osmChange file (which [`AnyReader`] can't read)
+    Osc,
+}
+
+/// How a file is compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    None,
+    Bzip2,
+    Gzip,
+}
+
+fn is_stdio(filename: &Path) -> bool {
+    filename == Path::new(STDIO_FILENAME)
+}
+
+/// Open this file for reading, or stdin if it's `-`
+///
+/// With the `cloud` feature, `s3://` & `gs://` URLs are read with
+/// [`ObjectReader`](crate::cloud::ObjectReader).
+pub fn open_input(filename: impl AsRef<Path>) -> Result<Box<dyn Read + Send>> {
+    let filename = filename.as_ref();
+    #[cfg(feature = "cloud")]
+    {
+        if let Some(url) = filename.to_str().filter(|f| cloud::is_url(f)) {
+            return Ok(Box::new(cloud::ObjectReader::from_url(url)?));
+        }
+    }
+    if is_stdio(filename) {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(File::open(filename)?))
+    }
+}
+
+/// Create this file (buffered) for writing, or use stdout if it's `-`
+pub fn create_output(filename: impl AsRef<Path>) -> Result<Box<dyn Write + Send>> {
+    let filename = filename.as_ref();
+    if is_stdio(filename) {
+        Ok(Box::new(BufWriter::new(io::stdout())))
+    } else {
+        Ok(Box::new(BufWriter::new(File::create(filename)?)))
+    }
+}
+
+/// Create this file (or use stdout if it's `-`) for writing, compressing everything written
+///
+/// The compressed stream is finished when the writer is dropped.
+pub fn create_compressed_output(
+    filename: impl AsRef<Path>,
+    compression: Compression,
+) -> Result<Box<dyn Write + Send>> {
+    let output = create_output(filename)?;
+    Ok(match compression {
+        Compression::None => output,
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Box::new(bzip2::write::BzEncoder::new(
+            output,
+            bzip2::Compression::default(),
+        )),
+        #[cfg(not(feature = "bzip2"))]
+        Compression::Bzip2 => anyhow::bail!("bzip2 support isn't enabled (feature `bzip2`)"),
+        Compression::Gzip => Box::new(flate2::write::GzEncoder::new(
+            output,
+            flate2::Compression::default(),
+        )),
+    })
+}
+
+/// The format & compression of a file, from the extensions of its filename (e.g. `.osm.pbf`,
+/// `.osc.gz`), or `None` if they aren't known
+pub fn format_from_filename(filename: impl AsRef<Path>) -> Option<(FileFormat, Compression)> {
+    let filename = filename
+        .as_ref()
+        .file_name()?
+        .to_str()?
+        .to_ascii_lowercase();
+    let (filename, compression) = if let Some(f) = filename.strip_suffix(".bz2") {
+        (f, Compression::Bzip2)
+    } else if let Some(f) = filename.strip_suffix(".gz") {
+        (f, Compression::Gzip)
+    } else {
+        (filename.as_str(), Compression::None)
+    };
+    let format = if filename.ends_with(".pbf") {
+        FileFormat::Pbf
+    } else if filename.ends_with(".osm") || filename.ends_with(".xml") {
+        FileFormat::Xml
+    } else if filename.ends_with(".osc") {
+        FileFormat::Osc
+    } else {
+        return None;
+    };
+    Some((format, compression))
+}
+
+/// Read the first (up to) `PREFIX_LEN` bytes, and return them, and a reader which still returns
+/// everything (incl. those bytes).
+fn peek(mut reader: Box<dyn Read + Send>) -> io::Result<(Vec<u8>, Box<dyn Read + Send>)> {
+    let mut prefix = Vec::with_capacity(PREFIX_LEN);
+    (&mut reader)
+        .take(PREFIX_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    let reader = Box::new(Cursor::new(prefix.clone()).chain(reader));
+    Ok((prefix, reader))
+}
+
+/// Detect the compression of this reader, and return it, the first bytes of the uncompressed
+/// data, and a reader of all the uncompressed data
+fn decompress(
+    reader: Box<dyn Read + Send>,
+) -> Result<(Compression, Vec<u8>, Box<dyn Read + Send>)> {
+    let (prefix, reader) = peek(reader)?;
+    let compression = detect_compression(&prefix);
+    let reader: Box<dyn Read + Send> = match compression {
+        Compression::None => reader,
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+        #[cfg(not(feature = "bzip2"))]
+        Compression::Bzip2 => anyhow::bail!("bzip2 support isn't enabled (feature `bzip2`)"),
+        Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+    };
+    let (prefix, reader) = peek(reader)?;
+    Ok((compression, prefix, reader))
+}
+
+/// The format, compression & [`FileInfo`] (header) of this file, or stdin if it's `-`. Only the
+/// start of the file is read.
+pub fn read_file_info(filename: impl AsRef<Path>) -> Result<(FileFormat, Compression, FileInfo)> {
+    let (compression, prefix, reader) = decompress(open_input(filename)?)?;
+    let format = detect_format(&prefix)
+        .ok_or_else(|| anyhow::anyhow!("Unknown file format, expected PBF or OSM XML"))?;
+    let info = match format {
+        FileFormat::Pbf => arcpbf::read_file_info(reader)?,
+        FileFormat::Xml | FileFormat::Osc => xml::read_file_info(io::BufReader::new(reader))?,
+    };
+    Ok((format, compression, info))
+}
+
+/// The compression of a file starting with these bytes
+pub fn detect_compression(prefix: &[u8]) -> Compression {
+    if prefix.starts_with(b"BZh") {
+        Compression::Bzip2
+    } else if prefix.starts_with(&[0x1f, 0x8b]) {
+        Compression::Gzip
+    } else {
+        Compression::None
+    }
+}
+
+/// The format of an (uncompressed) file starting with these bytes, if it's known
+pub fn detect_format(prefix: &[u8]) -> Option<FileFormat> {
+    // PBF: a 4 byte (big endian) header size, and then the header, whose first field is the type
+    if prefix.len() > 4 && prefix[..2] == [0, 0] && prefix[4] == 0x0a {
+        return Some(FileFormat::Pbf);
+    }
+    let text = String::from_utf8_lossy(prefix);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    if !text.starts_with('<') {
+        None
+    } else if text.contains("<osmChange") {
+        Some(FileFormat::Osc)
+    } else {
+        Some(FileFormat::Xml)
+    }
+}
+
+/// Reads PBF or OSM XML (optionally compressed with bzip2 or gzip), detecting which from the
+/// contents. See the [module documentation](self).
+pub enum AnyReader {
+    Pbf(Box<stringpbf::PBFReader<Box<dyn Read + Send>>>),
+    Xml(Box<XMLReader<Box<dyn Read + Send>>>),
+}
+
+impl AnyReader {
+    /// Open this file, or stdin if it's `-`
+    pub fn from_filename(filename: impl AsRef<Path>) -> Result<Self> {
+        Self::from_reader(open_input(filename)?)
+    }
+
+    /// Read from this reader, returning an error if the format isn't known
+    pub fn from_reader(reader: Box<dyn Read + Send>) -> Result<Self> {
+        let (_, prefix, reader) = decompress(reader)?;
+        match detect_format(&prefix) {
+            Some(FileFormat::Pbf) => {
+                Ok(AnyReader::Pbf(Box::new(stringpbf::PBFReader::new(reader))))
+            }
+            Some(FileFormat::Xml) => Ok(AnyReader::Xml(Box::new(XMLReader::new(reader)))),
+            Some(FileFormat::Osc) => {
+                anyhow::bail!("osmChange files aren't supported, use osc::OSCReader")
+            }
+            None => anyhow::bail!("Unknown file format, expected PBF or OSM XML"),
+        }
+    }
+
+    /// The format being read
+    pub fn format(&self) -> FileFormat {
+        match self {
+            AnyReader::Pbf(_) => FileFormat::Pbf,
+            AnyReader::Xml(_) => FileFormat::Xml,
+        }
+    }
+}
+
+impl OSMReader for AnyReader {
+    type R = Box<dyn Read + Send>;
+    type Obj = StringOSMObj;
+
+    /// Panics if the format isn't known, use [`AnyReader::from_reader`] to get an error instead.
+    fn new(reader: Self::R) -> Self {
+        AnyReader::from_reader(reader).unwrap()
+    }
+
+    fn set_sorted_assumption(&mut self, sorted_assumption: bool) {
+        match self {
+            AnyReader::Pbf(r) => r.set_sorted_assumption(sorted_assumption),
+            AnyReader::Xml(r) => r.set_sorted_assumption(sorted_assumption),
+        }
+    }
+
+    fn get_sorted_assumption(&mut self) -> bool {
+        match self {
+            AnyReader::Pbf(r) => r.get_sorted_assumption(),
+            AnyReader::Xml(r) => r.get_sorted_assumption(),
+        }
+    }
+
+    fn set_parse_mode(&mut self, parse_mode: ParseMode) {
+        match self {
+            AnyReader::Pbf(r) => r.set_parse_mode(parse_mode),
+            AnyReader::Xml(r) => r.set_parse_mode(parse_mode),
+        }
+    }
+
+    fn set_lossy_utf8(&mut self, lossy_utf8: bool) {
+        match self {
+            AnyReader::Pbf(r) => r.set_lossy_utf8(lossy_utf8),
+            AnyReader::Xml(r) => r.set_lossy_utf8(lossy_utf8),
+        }
+    }
+
+    fn set_duplicate_tags(&mut self, duplicate_tags: DuplicateTags) {
+        match self {
+            AnyReader::Pbf(r) => r.set_duplicate_tags(duplicate_tags),
+            AnyReader::Xml(r) => r.set_duplicate_tags(duplicate_tags),
+        }
+    }
+
+    fn set_non_positive_ids(&mut self, non_positive_ids: NonPositiveIds) {
+        match self {
+            AnyReader::Pbf(r) => r.set_non_positive_ids(non_positive_ids),
+            AnyReader::Xml(r) => r.set_non_positive_ids(non_positive_ids),
+        }
+    }
+
+    fn inner(&self) -> &Self::R {
+        match self {
+            AnyReader::Pbf(r) => r.inner(),
+            AnyReader::Xml(r) => r.inner(),
+        }
+    }
+
+    fn into_inner(self) -> Self::R {
+        match self {
+            AnyReader::Pbf(r) => r.into_inner(),
+            AnyReader::Xml(r) => r.into_inner(),
+        }
+    }
+
+    fn file_info(&self) -> Option<&FileInfo> {
+        match self {
+            AnyReader::Pbf(r) => r.file_info(),
+            AnyReader::Xml(r) => r.file_info(),
+        }
+    }
+
+    fn next(&mut self) -> Option<Self::Obj> {
+        match self {
+            AnyReader::Pbf(r) => r.next(),
+            AnyReader::Xml(r) => r.next(),
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<Self::Obj>, Error> {
+        match self {
+            AnyReader::Pbf(r) => r.try_next(),
+            AnyReader::Xml(r) => r.try_next(),
+        }
+    }
+}
+
+/// Writes any format osmio can write, chosen from the filename. See the
+/// [module documentation](self).
+///
+/// OSM XML & PBF can be written, other formats are an error.
+pub enum AnyWriter {
+    Xml(XMLWriter<Box<dyn Write + Send>>),
+    Pbf(Box<arcpbf::PBFWriter<Box<dyn Write + Send>>>),
+}
+
+impl AnyWriter {
+    /// Create this file, or write (uncompressed OSM XML) to stdout if it's `-`.
+    ///
+    /// The format & compression are detected from the filename, `compression` overrides the
+    /// compression.
+    pub fn from_filename(
+        filename: impl AsRef<Path>,
+        compression: Option<Compression>,
+    ) -> Result<Self> {
+        let filename = filename.as_ref();
+        let (format, detected_compression) = if is_stdio(filename) {
+            (FileFormat::Xml, Compression::None)
+        } else {
+            format_from_filename(filename).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Unknown file format for {}, expected .osm, .osm.pbf or .osc",
+                    filename.display()
+                )
+            })?
+        };
+        let output =
+            create_compressed_output(filename, compression.unwrap_or(detected_compression))?;
+        Self::from_writer(output, format)
+    }
+
+    /// Write this format to this writer
+    pub fn from_writer(writer: Box<dyn Write + Send>, format: FileFormat) -> Result<Self> {
+        match format {
+            FileFormat::Xml => Ok(AnyWriter::Xml(XMLWriter::new(writer))),
+            FileFormat::Pbf => Ok(AnyWriter::Pbf(Box::new(arcpbf::PBFWriter::new(writer)))),
+            FileFormat::Osc => {
+                anyhow::bail!("osmChange files aren't supported, use osc::OSCWriter")
+            }
+        }
+    }
+
+    /// The format being written
+    pub fn format(&self) -> FileFormat {
+        match self {
+            AnyWriter::Xml(_) => FileFormat::Xml,
+            AnyWriter::Pbf(_) => FileFormat::Pbf,
+        }
+    }
+}
+
+impl OSMWriter<Box<dyn Write + Send>> for AnyWriter {
+    /// Writes OSM XML
+    fn new(writer: Box<dyn Write + Send>) -> Self {
+        AnyWriter::Xml(XMLWriter::new(writer))
+    }
+
+    fn close(&mut self) -> Result<(), OSMWriteError> {
+        match self {
+            AnyWriter::Xml(w) => w.close(),
+            AnyWriter::Pbf(w) => w.close(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            AnyWriter::Xml(w) => w.is_open(),
+            AnyWriter::Pbf(w) => w.is_open(),
+        }
+    }
+
+    fn write_obj(&mut self, obj: &impl OSMObj) -> Result<(), OSMWriteError> {
+        match self {
+            AnyWriter::Xml(w) => w.write_obj(obj),
+            AnyWriter::Pbf(w) => w.write_obj(obj),
+        }
+    }
+
+    fn into_inner(self) -> Box<dyn Write + Send> {
+        match self {
+            AnyWriter::Xml(w) => w.into_inner(),
+            AnyWriter::Pbf(w) => w.into_inner(),
+        }
+    }
+
+    fn set_header(&mut self, key_value: (&str, &str)) -> Result<(), OSMWriteError> {
+        match self {
+            AnyWriter::Xml(w) => w.set_header(key_value),
+            AnyWriter::Pbf(w) => w.set_header(key_value),
+        }
+    }
+
+    fn set_file_info(&mut self, file_info: &FileInfo) -> Result<(), OSMWriteError> {
+        match self {
+            AnyWriter::Xml(w) => w.set_file_info(file_info),
+            AnyWriter::Pbf(w) => w.set_file_info(file_info),
+        }
+    }
+}
+
+/// Write every object from `reader` to `writer`, and close the writer. Returns how many objects
+/// were copied.
+///
+/// The header of the input ([`OSMReader::file_info`]), e.g. the bbox & replication timestamp, is
+/// used for the header of the output, if the writer's format has one. Stops at the first read or
+/// write error.
+pub fn copy<R, W, Wr>(reader: &mut R, writer: &mut Wr) -> Result<u64>
+where
+    R: OSMReader,
+    W: Write,
+    Wr: OSMWriter<W>,
+{
+    let mut num = 0;
+    // The header is read with the first object, and has to be written before it
+    let first = reader.try_next()?;
+    if let Some(file_info) = reader.file_info() {
+        match writer.set_file_info(file_info) {
+            Ok(()) | Err(OSMWriteError::FormatDoesntSupportHeaders) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if let Some(obj) = first {
+        writer.write_obj(&obj)?;
+        num += 1;
+        for obj in reader.try_objects() {
+            writer.write_obj(&obj?)?;
+            num += 1;
+        }
+    }
+    writer.close()?;
+    Ok(num)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OSMObjBase;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="1" lon="1"/>
+<node id="2" lat="1" lon="1"/>
+</osm>"#;
+
+    fn ids(reader: Box<dyn Read + Send>) -> Vec<i64> {
+        AnyReader::from_reader(reader)
+            .unwrap()
+            .objects()
+            .map(|o| o.id())
+            .collect()
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(detect_format(INPUT.as_bytes()), Some(FileFormat::Xml));
+        assert_eq!(
+            detect_format(b"\n <osmChange version=\"0.6\">"),
+            Some(FileFormat::Osc)
+        );
+        assert_eq!(
+            detect_format(&[0, 0, 0, 14, 0x0a, 9, b'O', b'S', b'M']),
+            Some(FileFormat::Pbf)
+        );
+        assert_eq!(detect_format(b"n1 v1"), None);
+        assert_eq!(detect_compression(b"BZh91AY"), Compression::Bzip2);
+        assert_eq!(detect_compression(INPUT.as_bytes()), Compression::None);
+    }
+
+    #[test]
+    fn any_reader() {
+        assert_eq!(ids(Box::new(INPUT.as_bytes())), vec![1, 2]);
+
+        #[cfg(feature = "bzip2")]
+        {
+            let mut bz2 = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+            bz2.write_all(INPUT.as_bytes()).unwrap();
+            assert_eq!(
+                ids(Box::new(Cursor::new(bz2.finish().unwrap()))),
+                vec![1, 2]
+            );
+        }
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(INPUT.as_bytes()).unwrap();
+        assert_eq!(ids(Box::new(Cursor::new(gz.finish().unwrap()))), vec![1, 2]);
+
+        assert!(AnyReader::from_reader(Box::new(&b"n1 v1"[..])).is_err());
+        assert!(AnyReader::from_reader(Box::new(&b"<osmChange>"[..])).is_err());
+    }
+
+    #[test]
+    fn filename_formats() {
+        assert_eq!(
+            format_from_filename("dir/planet.osm.pbf"),
+            Some((FileFormat::Pbf, Compression::None))
+        );
+        assert_eq!(
+            format_from_filename("a.OSM.BZ2"),
+            Some((FileFormat::Xml, Compression::Bzip2))
+        );
+        assert_eq!(
+            format_from_filename("a.osc.gz"),
+            Some((FileFormat::Osc, Compression::Gzip))
+        );
+        assert_eq!(format_from_filename("a.txt"), None);
+        assert_eq!(format_from_filename("osm"), None);
+    }
+
+    #[test]
+    fn copy_to_any_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.osm.gz");
+        let mut reader = AnyReader::from_reader(Box::new(INPUT.as_bytes())).unwrap();
+        let mut writer = AnyWriter::from_filename(&output, None).unwrap();
+        assert_eq!(writer.format(), FileFormat::Xml);
+        assert_eq!(copy(&mut reader, &mut writer).unwrap(), 2);
+        drop(writer);
+
+        let written = std::fs::read(&output).unwrap();
+        assert_eq!(detect_compression(&written), Compression::Gzip);
+        assert_eq!(ids(Box::new(Cursor::new(written))), vec![1, 2]);
+
+        let output = dir.path().join("output.osm.pbf");
+        let mut reader = AnyReader::from_reader(Box::new(INPUT.as_bytes())).unwrap();
+        let mut writer = AnyWriter::from_filename(&output, None).unwrap();
+        assert_eq!(writer.format(), FileFormat::Pbf);
+        assert_eq!(copy(&mut reader, &mut writer).unwrap(), 2);
+        drop(writer);
+        let written = std::fs::read(&output).unwrap();
+        assert_eq!(detect_format(&written), Some(FileFormat::Pbf));
+        assert_eq!(ids(Box::new(Cursor::new(written))), vec![1, 2]);
+
+        assert!(AnyWriter::from_filename(dir.path().join("output.txt"), None).is_err());
+    }
+
+    #[test]
+    fn copy_keeps_header() {
+        let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6" generator="upstream 1.0" osmosis_replication_timestamp="2023-04-05T06:07:08Z" osmosis_replication_sequence_number="5678">
+<bounds minlat="51.5" minlon="-0.2" maxlat="51.6" maxlon="0.1"/>
+<node id="1" lat="51.55" lon="0"/>
+</osm>"#;
+        let dir = tempfile::tempdir().unwrap();
+        let pbf = dir.path().join("output.osm.pbf");
+        let mut reader = AnyReader::from_reader(Box::new(input.as_bytes())).unwrap();
+        let mut writer = AnyWriter::from_filename(&pbf, None).unwrap();
+        copy(&mut reader, &mut writer).unwrap();
+        drop(writer);
+        let expected = read_file_info(&pbf).unwrap().2;
+        assert_eq!(expected.generator.as_deref(), Some("upstream 1.0"));
+        assert_eq!(expected.replication_sequence, Some(5678));
+        assert_eq!(
+            expected
+                .replication_timestamp
+                .as_ref()
+                .map(|t| t.to_epoch_number()),
+            Some(1_680_674_828)
+        );
+        assert!(expected.bbox.is_some());
+        assert_eq!(
+            expected.bbox,
+            xml::read_file_info(input.as_bytes()).unwrap().bbox
+        );
+
+        // …and back to XML
+        let osm = dir.path().join("output.osm");
+        let mut reader = AnyReader::from_filename(&pbf).unwrap();
+        let mut writer = AnyWriter::from_filename(&osm, None).unwrap();
+        copy(&mut reader, &mut writer).unwrap();
+        drop(writer);
+        let info = read_file_info(&osm).unwrap().2;
+        assert_eq!(info.generator, expected.generator);
+        assert_eq!(info.bbox, expected.bbox);
+        assert_eq!(info.replication_sequence, expected.replication_sequence);
+        assert_eq!(
+            info.replication_timestamp.map(|t| t.to_epoch_number()),
+            Some(1_680_674_828)
+        );
+        assert_eq!(ids(Box::new(File::open(&osm).unwrap())), vec![1]);
+    }
+}
@@ -0,0 +1,459 @@
+//! Calculating which map tiles an osmChange file changes (tile expiry), like osm2pgsql's
+//! `--expire-tiles`.
+//!
+//! [`TileExpiry`] collects the Web Mercator `z/x/y` tiles touched by the changes: the old & new
+//! location of every changed node, and tiles along every changed way. Closed ways which are areas
+//! have every tile inside them expired, unless they are larger than
+//! [`max_bbox_size`](TileExpiry::max_bbox_size), when only the outline is. Tiles within
+//! [`buffer`](TileExpiry::buffer) of a change are included too, since labels & symbols spread
+//! over tile edges.
+//!
+//! Tiles are collected at the maximum zoom. [`TileExpiry::write_tiles`] writes them, and their
+//! parents down to the minimum zoom, one `z/x/y` per line, which is what tile servers (e.g.
+//! `render_expired`) read.
+//!
+//! ```no_run
+//! use osmio::node_locations::FlatFileNodeLocations;
+//! use osmio::osc::expire::TileExpiry;
+//! use osmio::osc::OSCReader;
+//! use osmio::OSMReader;
+//!
+//! let mut locations = FlatFileNodeLocations::open("nodes.bin")?;
+//! let mut expiry = TileExpiry::new(18).min_zoom(10);
+//! expiry.add_reader(
+//!     &mut OSCReader::new(std::fs::File::open("001.osc")?),
+//!     &mut locations,
+//! )?;
+//! expiry.write_tiles(std::fs::File::create("expired.list")?)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! The node location store must have the locations from before the change, and is updated with
+//! the new ones, so it can be used for the next diff. Objects which aren't in the diff aren't
+//! looked up: when a node moves, only the tiles around its old & new location are expired, not
+//! the tiles along the (unchanged) ways it's in; and relations only expire their member nodes.
+//! Deleted ways have no nodes in a diff, so they expire nothing.
+use geometry::area::is_area;
+use geometry::projection::{CoordTransform, WebMercator, EARTH_RADIUS};
+use node_locations::NodeLocations;
+use obj_types::StringOSMObj;
+use osc::{ChangeAction, OSCReader};
+use std::collections::{BTreeSet, HashSet};
+use std::f64::consts::PI;
+use std::io::{Read, Write};
+use {Lat, Lon, Node, OSMObjectType, Relation, Way};
+
+use anyhow::Result;
+
+/// The largest zoom which is supported, so tile numbers fit in a `u32`
+pub const MAX_ZOOM: u8 = 31;
+
+/// A Web Mercator map tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Tile {
+    pub z: u8,
+    /// Column, counting from the west
+    pub x: u32,
+    /// Row, counting from the north
+    pub y: u32,
+}
+
+impl Tile {
+    /// The tile at this zoom which contains this location
+    pub fn containing(loc: (Lat, Lon), zoom: u8) -> Self {
+        let (x, y) = tile_coords(loc, zoom);
+        let max = (1u64 << zoom) as f64 - 1.;
+        Tile {
+            z: zoom,
+            x: x.floor().clamp(0., max) as u32,
+            y: y.floor().clamp(0., max) as u32,
+        }
+    }
+
+    /// The tile at the zoom below which contains this tile, `None` at zoom 0
+    pub fn parent(&self) -> Option<Tile> {
+        if self.z == 0 {
+            None
+        } else {
+            Some(Tile {
+                z: self.z - 1,
+                x: self.x / 2,
+                y: self.y / 2,
+            })
+        }
+    }
+}
+
+/// `z/x/y`
+impl std::fmt::Display for Tile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.z, self.x, self.y)
+    }
+}
+
+/// The position of this location in tile units at this zoom, e.g. `(3.5, 0.25)` is in the middle
+/// of the top edge of tile `3/0`
+fn tile_coords(loc: (Lat, Lon), zoom: u8) -> (f64, f64) {
+    let (x, y) = WebMercator.transform(loc);
+    let half_width = EARTH_RADIUS * PI;
+    let num_tiles = (1u64 << zoom) as f64;
+    (
+        (x + half_width) / (2. * half_width) * num_tiles,
+        (half_width - y) / (2. * half_width) * num_tiles,
+    )
+}
+
+/// The set of tiles changed by some diffs. See the [module documentation](self).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileExpiry {
+    max_zoom: u8,
+    min_zoom: u8,
+    buffer: f64,
+    max_bbox_size: f64,
+    /// Tiles at `max_zoom`, as `(x, y)`
+    tiles: HashSet<(u32, u32)>,
+    missing_locations: u64,
+}
+
+impl TileExpiry {
+    /// Collect tiles at this zoom. Only tiles at this zoom are written, until
+    /// [`min_zoom`](Self::min_zoom) is set.
+    ///
+    /// Panics if `max_zoom` is more than [`MAX_ZOOM`].
+    pub fn new(max_zoom: u8) -> Self {
+        assert!(max_zoom <= MAX_ZOOM, "Zoom {} is too large", max_zoom);
+        TileExpiry {
+            max_zoom,
+            min_zoom: max_zoom,
+            buffer: 0.1,
+            max_bbox_size: 20_000.,
+            tiles: HashSet::new(),
+            missing_locations: 0,
+        }
+    }
+
+    /// Also write the parents of the expired tiles, down to this zoom.
+    ///
+    /// Panics if this is more than the maximum zoom.
+    pub fn min_zoom(mut self, min_zoom: u8) -> Self {
+        assert!(
+            min_zoom <= self.max_zoom,
+            "Minimum zoom is more than the maximum zoom"
+        );
+        self.min_zoom = min_zoom;
+        self
+    }
+
+    /// Also expire tiles this close to a change, as a fraction of the tile size. The default is
+    /// 0.1, like osm2pgsql.
+    pub fn buffer(mut self, buffer: f64) -> Self {
+        self.buffer = buffer.max(0.);
+        self
+    }
+
+    /// Areas whose bbox is wider or taller than this (in Web Mercator metres) only have their
+    /// outline expired. The default is 20 km, like osm2pgsql.
+    pub fn max_bbox_size(mut self, metres: f64) -> Self {
+        self.max_bbox_size = metres;
+        self
+    }
+
+    /// Number of expired tiles at the maximum zoom
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Number of times a way or relation referred to a node whose location wasn't known
+    pub fn missing_locations(&self) -> u64 {
+        self.missing_locations
+    }
+
+    /// The expired tiles (& their parents, down to the minimum zoom), sorted by zoom, then `x`,
+    /// then `y`
+    pub fn tiles(&self) -> Vec<Tile> {
+        let mut tiles = BTreeSet::new();
+        for (x, y) in self.tiles.iter().copied() {
+            let mut tile = Tile {
+                z: self.max_zoom,
+                x,
+                y,
+            };
+            // Stop once the parent is already there, since its parents must be too
+            while tiles.insert(tile) && tile.z > self.min_zoom {
+                tile = tile.parent().unwrap();
+            }
+        }
+        tiles.into_iter().collect()
+    }
+
+    /// Write the expired tiles, one `z/x/y` per line
+    pub fn write_tiles(&self, mut output: impl Write) -> std::io::Result<()> {
+        for tile in self.tiles() {
+            writeln!(output, "{}", tile)?;
+        }
+        output.flush()
+    }
+
+    /// Expire the tiles touching the line from `a` to `b` (in tile units), with the buffer
+    fn expire_segment(&mut self, a: (f64, f64), b: (f64, f64)) {
+        let ((x0, y0), (x1, y1)) = if a.0 <= b.0 { (a, b) } else { (b, a) };
+        let buffer = self.buffer;
+        let max = (1u64 << self.max_zoom) as f64 - 1.;
+        let idx = |v: f64| v.floor().clamp(0., max) as u32;
+        for col in idx(x0 - buffer)..=idx(x1 + buffer) {
+            // The part of the line in this column (& the buffer either side of it)
+            let (min_y, max_y) = if x1 > x0 {
+                let y_at = |x: f64| y0 + (y1 - y0) * (x - x0) / (x1 - x0);
+                let start = y_at(x0.max(col as f64 - buffer));
+                let end = y_at(x1.min(col as f64 + 1. + buffer));
+                (start.min(end), start.max(end))
+            } else {
+                (y0.min(y1), y0.max(y1))
+            };
+            for row in idx(min_y - buffer)..=idx(max_y + buffer) {
+                self.tiles.insert((col, row));
+            }
+        }
+    }
+
+    /// Expire the tiles around this location
+    pub fn expire_location(&mut self, loc: (Lat, Lon)) {
+        let point = tile_coords(loc, self.max_zoom);
+        self.expire_segment(point, point);
+    }
+
+    /// Expire the tiles along this line
+    pub fn expire_line(&mut self, locs: &[(Lat, Lon)]) {
+        let points: Vec<_> = locs
+            .iter()
+            .map(|loc| tile_coords(*loc, self.max_zoom))
+            .collect();
+        match points.as_slice() {
+            [] => {}
+            [point] => self.expire_segment(*point, *point),
+            points => {
+                for segment in points.windows(2) {
+                    self.expire_segment(segment[0], segment[1]);
+                }
+            }
+        }
+    }
+
+    /// Expire the tiles inside this ring, or only along it if its bbox is larger than
+    /// [`max_bbox_size`](Self::max_bbox_size). The tiles inside are approximated by the bbox.
+    pub fn expire_polygon(&mut self, ring: &[(Lat, Lon)]) {
+        if ring.is_empty() {
+            return;
+        }
+        self.expire_line(ring);
+        let points = ring.iter().map(|loc| tile_coords(*loc, self.max_zoom));
+        let (min_x, min_y, max_x, max_y) = points.fold(
+            (
+                f64::INFINITY,
+                f64::INFINITY,
+                f64::NEG_INFINITY,
+                f64::NEG_INFINITY,
+            ),
+            |(min_x, min_y, max_x, max_y), (x, y)| {
+                (min_x.min(x), min_y.min(y), max_x.max(x), max_y.max(y))
+            },
+        );
+        let tile_size = 2. * EARTH_RADIUS * PI / (1u64 << self.max_zoom) as f64;
+        if (max_x - min_x).max(max_y - min_y) * tile_size > self.max_bbox_size {
+            return;
+        }
+        let max = (1u64 << self.max_zoom) as f64 - 1.;
+        let idx = |v: f64| v.floor().clamp(0., max) as u32;
+        for col in idx(min_x - self.buffer)..=idx(max_x + self.buffer) {
+            for row in idx(min_y - self.buffer)..=idx(max_y + self.buffer) {
+                self.tiles.insert((col, row));
+            }
+        }
+    }
+
+    /// Expire the old (from `locations`) & new location of this node, and store the new location
+    pub fn add_node(
+        &mut self,
+        action: ChangeAction,
+        node: &impl Node,
+        locations: &mut impl NodeLocations,
+    ) {
+        if let Some(old) = locations.get(node.id()) {
+            self.expire_location(old);
+        }
+        if action == ChangeAction::Delete || node.deleted() {
+            return;
+        }
+        if let Some(new) = node.lat_lon() {
+            self.expire_location(new);
+            locations.set(node.id(), new);
+        }
+    }
+
+    /// Expire the tiles along (or inside) this way, with its nodes' current locations
+    pub fn add_way(&mut self, way: &impl Way, locations: &impl NodeLocations) {
+        if way.deleted() {
+            return;
+        }
+        let mut locs = Vec::with_capacity(way.num_nodes());
+        for nid in way.nodes() {
+            match locations.get(*nid) {
+                Some(loc) => locs.push(loc),
+                None => self.missing_locations += 1,
+            }
+        }
+        if is_area(way) {
+            self.expire_polygon(&locs);
+        } else {
+            self.expire_line(&locs);
+        }
+    }
+
+    /// Expire the locations of this relation's member nodes
+    pub fn add_relation(&mut self, relation: &impl Relation, locations: &impl NodeLocations) {
+        if relation.deleted() {
+            return;
+        }
+        for (member_type, member_id, _role) in relation.members() {
+            if member_type != OSMObjectType::Node {
+                continue;
+            }
+            match locations.get(member_id) {
+                Some(loc) => self.expire_location(loc),
+                None => self.missing_locations += 1,
+            }
+        }
+    }
+
+    /// Expire the tiles changed by every change in this file, and update `locations`.
+    ///
+    /// Nodes are done first, so ways & relations use the new locations, whatever order the
+    /// blocks are in.
+    pub fn add_reader<R: Read>(
+        &mut self,
+        reader: &mut OSCReader<R>,
+        locations: &mut impl NodeLocations,
+    ) -> Result<()> {
+        let mut ways = Vec::new();
+        let mut relations = Vec::new();
+        while let Some((action, obj)) = reader.next_change()? {
+            match obj {
+                StringOSMObj::Node(node) => self.add_node(action, &node, locations),
+                StringOSMObj::Way(way) => ways.push(way),
+                StringOSMObj::Relation(relation) => relations.push(relation),
+            }
+        }
+        for way in ways.iter() {
+            self.add_way(way, locations);
+        }
+        for relation in relations.iter() {
+            self.add_relation(relation, locations);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+    use OSMReader;
+
+    fn ll(lat: f64, lon: f64) -> (Lat, Lon) {
+        (Lat::try_from(lat).unwrap(), Lon::try_from(lon).unwrap())
+    }
+
+    #[test]
+    fn tiles() {
+        assert_eq!(Tile::containing(ll(0., 0.), 0).to_string(), "0/0/0");
+        assert_eq!(Tile::containing(ll(0., 0.), 1).to_string(), "1/1/1");
+        assert_eq!(
+            Tile::containing(ll(51.5, -0.12), 10).to_string(),
+            "10/511/340"
+        );
+        assert_eq!(Tile::containing(ll(89.9, 180.), 2).to_string(), "2/3/0");
+        assert_eq!(
+            Tile::containing(ll(51.5, -0.12), 10).parent(),
+            Some(Tile {
+                z: 9,
+                x: 255,
+                y: 170
+            })
+        );
+        assert_eq!(Tile::containing(ll(0., 0.), 0).parent(), None);
+    }
+
+    #[test]
+    fn buffer() {
+        // Just west of the edge between columns 1 & 2, at zoom 2
+        let mut expiry = TileExpiry::new(2);
+        expiry.expire_location(ll(10., -4.5));
+        assert_eq!(
+            expiry
+                .tiles()
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>(),
+            vec!["2/1/1", "2/2/1"]
+        );
+        let mut expiry = TileExpiry::new(2).buffer(0.);
+        expiry.expire_location(ll(10., -4.5));
+        assert_eq!(expiry.num_tiles(), 1);
+    }
+
+    #[test]
+    fn polygons() {
+        // Corners in the middle of the corner tiles of the zoom 2 map
+        let ring = [
+            ll(79., -135.),
+            ll(79., 135.),
+            ll(-79., 135.),
+            ll(-79., -135.),
+            ll(79., -135.),
+        ];
+        let mut expiry = TileExpiry::new(2).buffer(0.);
+        expiry.expire_polygon(&ring);
+        assert_eq!(expiry.num_tiles(), 12);
+        let mut expiry = TileExpiry::new(2).buffer(0.).max_bbox_size(f64::INFINITY);
+        expiry.expire_polygon(&ring);
+        assert_eq!(expiry.num_tiles(), 16);
+    }
+
+    #[test]
+    fn diff() {
+        let mut locations = SparseNodeLocations::new();
+        locations.set(1, ll(10., -100.));
+        locations.set(3, ll(10., -10.));
+        locations.set(4, ll(-60., -170.));
+        let diff = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osmChange version="0.6">
+<create>
+  <way id="1" version="1"><nd ref="1"/><nd ref="3"/></way>
+  <way id="2" version="1"><nd ref="3"/><nd ref="99"/></way>
+  <node id="2" version="1" lat="-10" lon="100"/>
+</create>
+<modify>
+  <node id="1" version="2" lat="10" lon="100"/>
+</modify>
+<delete>
+  <node id="4" version="2"/>
+</delete>
+</osmChange>"#;
+        let mut expiry = TileExpiry::new(2).min_zoom(1).buffer(0.);
+        expiry
+            .add_reader(&mut OSCReader::new(Cursor::new(diff)), &mut locations)
+            .unwrap();
+        assert_eq!(locations.get(1), Some(ll(10., 100.)));
+        assert_eq!(locations.get(2), Some(ll(-10., 100.)));
+        assert_eq!(expiry.missing_locations(), 1);
+
+        let mut output = Vec::new();
+        expiry.write_tiles(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1/0/0\n1/0/1\n1/1/0\n1/1/1\n2/0/1\n2/0/2\n2/1/1\n2/2/1\n2/3/1\n2/3/2\n"
+        );
+    }
+}
@@ -4,15 +4,18 @@ use super::version;
 use super::{Node, OSMObj, OSMObjectType, Relation, Way};
 use super::{OSMReader, OSMWriteError, OSMWriter};
 use obj_types::StringOSMObj;
+use parse_mode::{DuplicateTags, NonPositiveIds, ParseMode};
 use std::io::{BufReader, Read, Write};
-use std::iter::Iterator;
 
-use xml::{write_xml_escaped, xml_elements_to_osm_obj};
+use xml::{write_xml_escaped, ObjParser};
+use {Error, OSMObjBase};
 
-use xml_rs::reader::{EventReader, Events, XmlEvent};
+pub mod expire;
+pub mod squash;
 
+/// Reads the objects in osmChange files (ignoring which block they're in)
 pub struct OSCReader<R: Read> {
-    parser: Events<BufReader<R>>,
+    parser: ObjParser<BufReader<R>>,
 }
 
 #[derive(PartialEq)]
@@ -22,10 +25,32 @@ enum State {
     Closed,
 }
 
+/// Which block of an osmChange file an object is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeAction {
+    Create,
+    Modify,
+    Delete,
+}
+
+impl ChangeAction {
+    /// The XML element name for this block
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeAction::Create => "create",
+            ChangeAction::Modify => "modify",
+            ChangeAction::Delete => "delete",
+        }
+    }
+}
+
 pub struct OSCWriter<W: Write> {
-    writer: W,
+    /// `None` once [`OSMWriter::into_inner`] has been called
+    writer: Option<W>,
     //headers: HashMap<String, String>,
     _state: State,
+    /// The block currently being written, if any
+    action: Option<ChangeAction>,
 }
 
 impl<R: Read> OSMReader for OSCReader<R> {
@@ -34,92 +59,125 @@ impl<R: Read> OSMReader for OSCReader<R> {
 
     fn new(reader: R) -> Self {
         OSCReader {
-            parser: EventReader::new(BufReader::new(reader)).into_iter(),
+            parser: ObjParser::new(BufReader::new(reader)),
         }
     }
 
+    fn set_parse_mode(&mut self, parse_mode: ParseMode) {
+        self.parser.set_parse_mode(parse_mode);
+    }
+
+    fn set_lossy_utf8(&mut self, lossy_utf8: bool) {
+        self.parser.set_lossy_utf8(lossy_utf8);
+    }
+
+    fn set_duplicate_tags(&mut self, duplicate_tags: DuplicateTags) {
+        self.parser.set_duplicate_tags(duplicate_tags);
+    }
+
+    fn set_non_positive_ids(&mut self, non_positive_ids: NonPositiveIds) {
+        self.parser.set_non_positive_ids(non_positive_ids);
+    }
+
     fn into_inner(self) -> R {
-        self.parser.into_inner().into_inner().into_inner()
+        self.parser.into_inner().into_inner()
     }
 
     fn inner(&self) -> &R {
-        todo!("{} {} OSCReader inner()", file!(), line!());
+        self.parser.get_ref().get_ref()
     }
 
+    /// Panics if the XML is invalid, use `try_next` to get an error instead
     fn next(&mut self) -> Option<StringOSMObj> {
-        let mut elements = Vec::new();
-
-        // Pull xml/sax elements from the xml parser into a vector so we know what to work with.
-        let mut should_push = false;
-        loop {
-            let el = match self.parser.next() {
-                None => {
-                    break;
-                }
-                Some(e) => e,
-            };
-
-            let el = el.unwrap();
-
-            let mut should_break = false;
-            match el {
-                XmlEvent::StartElement { ref name, .. } => match name.local_name.as_str() {
-                    "node" | "way" | "relation" => {
-                        should_push = true;
-                    }
-                    _ => {}
-                },
-                XmlEvent::EndElement { ref name, .. } => match name.local_name.as_str() {
-                    "node" | "way" | "relation" => {
-                        should_break = true;
-                    }
-                    _ => {}
-                },
-                _ => {}
-            }
+        self.try_next().unwrap()
+    }
 
-            if should_push {
-                elements.push(el);
-            }
-            if should_break {
-                break;
-            }
-        }
+    fn try_next(&mut self) -> Result<Option<StringOSMObj>, Error> {
+        self.parser.next_obj()
+    }
+}
 
-        xml_elements_to_osm_obj(&mut elements)
+impl<R: Read> OSCReader<R> {
+    /// The next object, and the block it's in. Objects in `delete` blocks are marked as deleted.
+    pub fn next_change(&mut self) -> Result<Option<(ChangeAction, StringOSMObj)>, Error> {
+        let mut obj = match self.parser.next_obj()? {
+            Some(obj) => obj,
+            None => return Ok(None),
+        };
+        // Objects outside any block are treated as modifications
+        let action = self.parser.action().unwrap_or(ChangeAction::Modify);
+        if action == ChangeAction::Delete {
+            obj.set_deleted(true);
+        }
+        Ok(Some((action, obj)))
     }
 }
 
 impl<W: Write> OSCWriter<W> {
+    fn writer_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+
     fn ensure_header(&mut self) -> Result<(), OSMWriteError> {
         if self._state == State::Initial {
-            writeln!(self.writer, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
+            writeln!(
+                self.writer_mut(),
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>"
+            )?;
             write!(
-                self.writer,
+                self.writer_mut(),
                 "<osmChange version=\"0.6\" generator=\"osmio/{}\"",
                 version()
             )?;
 
             //for (k, v) in self.headers.iter() {
-            //    write!(self.writer, " {}=\"", k)?;
-            //    write_xml_escaped(&mut self.writer, v)?;
-            //    write!(self.writer, "\"")?;
+            //    write!(self.writer_mut(), " {}=\"", k)?;
+            //    write_xml_escaped(self.writer_mut(), v)?;
+            //    write!(self.writer_mut(), "\"")?;
             //}
-            write!(self.writer, ">")?;
-            write!(self.writer, "\n<modify>")?;
+            write!(self.writer_mut(), ">")?;
 
             self._state = State::WritingObjects;
         }
         Ok(())
     }
+
+    /// Close the current block (if any), and open a block for `action`, unless it's already open.
+    fn ensure_action(&mut self, action: ChangeAction) -> Result<(), OSMWriteError> {
+        if self.action != Some(action) {
+            if let Some(current) = self.action {
+                write!(self.writer_mut(), "\n</{}>", current.name())?;
+            }
+            write!(self.writer_mut(), "\n<{}>", action.name())?;
+            self.action = Some(action);
+        }
+        Ok(())
+    }
+
+    /// Write this object in a `create`, `modify` or `delete` block. A new block is started
+    /// whenever the action changes, so group objects by action to keep the file small.
+    pub fn write_change(
+        &mut self,
+        action: ChangeAction,
+        obj: &impl OSMObj,
+    ) -> Result<(), OSMWriteError> {
+        match self._state {
+            State::Initial => self.ensure_header()?, // This will update self._state
+            State::WritingObjects => {}
+            State::Closed => return Err(OSMWriteError::AlreadyClosed),
+        }
+        self.ensure_action(action)?;
+        self.write_obj_xml(obj)
+    }
 }
 
 impl<W: Write> OSMWriter<W> for OSCWriter<W> {
     fn new(writer: W) -> Self {
         OSCWriter {
-            writer,
+            writer: Some(writer),
             //headers: HashMap::new(),
             _state: State::Initial,
+            action: None,
         }
     }
 
@@ -131,8 +189,11 @@ impl<W: Write> OSMWriter<W> for OSCWriter<W> {
         self.ensure_header()?;
 
         if self._state != State::Closed {
-            write!(self.writer, "\n</modify>")?;
-            write!(self.writer, "\n</osmChange>")?;
+            if let Some(action) = self.action.take() {
+                write!(self.writer_mut(), "\n</{}>", action.name())?;
+            }
+            write!(self.writer_mut(), "\n</osmChange>")?;
+            self.writer_mut().flush()?;
 
             self._state = State::Closed;
         }
@@ -140,15 +201,21 @@ impl<W: Write> OSMWriter<W> for OSCWriter<W> {
         Ok(())
     }
 
+    /// Objects are written in a `modify` block, use [`OSCWriter::write_change`] for the others.
     fn write_obj(&mut self, obj: &impl OSMObj) -> Result<(), OSMWriteError> {
-        match self._state {
-            State::Initial => self.ensure_header()?, // This will update self._state
-            State::WritingObjects => {}
-            State::Closed => return Err(OSMWriteError::AlreadyClosed),
-        }
+        self.write_change(ChangeAction::Modify, obj)
+    }
 
+    /// The underlying writer. This doesn't close the file, use [`OSMWriter::finish`] for that.
+    fn into_inner(mut self) -> W {
+        self.writer.take().unwrap()
+    }
+}
+
+impl<W: Write> OSCWriter<W> {
+    fn write_obj_xml(&mut self, obj: &impl OSMObj) -> Result<(), OSMWriteError> {
         write!(
-            self.writer,
+            self.writer_mut(),
             "{}",
             match obj.object_type() {
                 OSMObjectType::Node => "\n\t<node",
@@ -156,71 +223,72 @@ impl<W: Write> OSMWriter<W> for OSCWriter<W> {
                 OSMObjectType::Relation => "\n\t<relation",
             }
         )?;
-        write!(self.writer, " id=\"{}\"", obj.id())?;
+        write!(self.writer_mut(), " id=\"{}\"", obj.id())?;
         write!(
-            self.writer,
+            self.writer_mut(),
             " visible=\"{}\"",
             if obj.deleted() { "false" } else { "true" }
         )?;
-        write!(self.writer, " version=\"{}\"", obj.version().unwrap())?;
+        write!(self.writer_mut(), " version=\"{}\"", obj.version().unwrap())?;
         if let Some(user) = obj.user() {
-            write!(self.writer, " user=\"")?;
-            write_xml_escaped(&mut self.writer, user)?;
-            write!(self.writer, "\"")?;
+            write!(self.writer_mut(), " user=\"")?;
+            write_xml_escaped(self.writer_mut(), user)?;
+            write!(self.writer_mut(), "\"")?;
         }
         if let Some(uid) = obj.uid() {
-            write!(self.writer, " uid=\"{}\"", uid)?;
+            write!(self.writer_mut(), " uid=\"{}\"", uid)?;
         }
         if let Some(changeset_id) = obj.changeset_id() {
-            write!(self.writer, " changeset=\"{}\"", changeset_id)?;
+            write!(self.writer_mut(), " changeset=\"{}\"", changeset_id)?;
         }
         if let Some(timestamp) = obj.timestamp() {
-            write!(self.writer, " timestamp=\"{}\"", timestamp)?;
+            write!(self.writer_mut(), " timestamp=\"{}\"", timestamp)?;
         }
 
         if let Some(node) = obj.as_node() {
             if let Some((lat, lon)) = node.lat_lon() {
-                write!(self.writer, " lat=\"{}\"", lat)?;
-                write!(self.writer, " lon=\"{}\"", lon)?;
+                write!(self.writer_mut(), " lat=\"{}\"", lat)?;
+                write!(self.writer_mut(), " lon=\"{}\"", lon)?;
             }
         }
 
         if obj.is_node() && obj.untagged() {
-            write!(self.writer, " />")?;
+            write!(self.writer_mut(), " />")?;
             return Ok(());
         }
-        write!(self.writer, ">")?;
+        write!(self.writer_mut(), ">")?;
 
         if let Some(way) = obj.as_way() {
             for nid in way.nodes() {
-                write!(self.writer, "\n\t\t<nd ref=\"{}\" />", nid)?;
+                write!(self.writer_mut(), "\n\t\t<nd ref=\"{}\" />", nid)?;
             }
         }
 
         if let Some(relation) = obj.as_relation() {
             for member in relation.members() {
                 write!(
-                    self.writer,
+                    self.writer_mut(),
                     "\n\t\t<member type=\"{}\" ref=\"{}\" role=\"",
-                    member.0, member.1
+                    member.0,
+                    member.1
                 )?;
                 if !member.2.is_empty() {
-                    write_xml_escaped(&mut self.writer, member.2)?;
+                    write_xml_escaped(self.writer_mut(), member.2)?;
                 }
-                write!(self.writer, "\"/>")?;
+                write!(self.writer_mut(), "\"/>")?;
             }
         }
 
         for (k, v) in obj.tags() {
-            write!(self.writer, "\n\t\t<tag k=\"")?;
-            write_xml_escaped(&mut self.writer, k)?;
-            write!(self.writer, "\" v=\"")?;
-            write_xml_escaped(&mut self.writer, v)?;
-            write!(self.writer, "\" />")?;
+            write!(self.writer_mut(), "\n\t\t<tag k=\"")?;
+            write_xml_escaped(self.writer_mut(), k)?;
+            write!(self.writer_mut(), "\" v=\"")?;
+            write_xml_escaped(self.writer_mut(), v)?;
+            write!(self.writer_mut(), "\" />")?;
         }
 
         write!(
-            self.writer,
+            self.writer_mut(),
             "{}",
             match obj.object_type() {
                 OSMObjectType::Node => "\n\t</node>",
@@ -231,15 +299,15 @@ impl<W: Write> OSMWriter<W> for OSCWriter<W> {
 
         Ok(())
     }
-
-    fn into_inner(self) -> W {
-        todo!("{} {}  OSCWriter into_inner", file!(), line!());
-        //self.writer.into_inner()
-    }
 }
 
+/// Closes the file if it's still open, and flushes it. Errors are ignored, so call
+/// [`OSMWriter::close`] or [`OSMWriter::finish`] to see them.
 impl<W: Write> Drop for OSCWriter<W> {
     fn drop(&mut self) {
-        self.close().unwrap();
+        if self.writer.is_some() {
+            let _ = self.close();
+            let _ = self.writer_mut().flush();
+        }
     }
 }
@@ -0,0 +1,231 @@
+//! Merging several osmChange files into one, with only the net change of each object.
+//!
+//! Applying a day of minutely diffs one after the other changes many objects several times.
+//! [`DiffSquasher`] keeps only the last version of each object, so the result can be applied
+//! once, like `osmium merge-changes --simplify`. Objects are sorted with an
+//! [`ExternalSorter`], so they don't all have to fit in memory.
+//!
+//! ```no_run
+//! use osmio::osc::squash::DiffSquasher;
+//! use osmio::osc::{OSCReader, OSCWriter};
+//! use osmio::OSMReader;
+//! use osmio::OSMWriter;
+//!
+//! let mut squasher = DiffSquasher::new();
+//! for filename in ["001.osc", "002.osc", "003.osc"] {
+//!     squasher.add_reader(&mut OSCReader::new(std::fs::File::open(filename)?))?;
+//! }
+//! let mut writer = OSCWriter::new(std::fs::File::create("day.osc")?);
+//! let counts = squasher.write(&mut writer)?;
+//! writer.close()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use cancel::Cancelled;
+use diff::ChangeCounts;
+use obj_types::StringOSMObj;
+use osc::{ChangeAction, OSCReader, OSCWriter};
+use sort::{ExternalSorter, SortedObjects};
+use std::io::{Read, Write};
+use {OSMObjBase, OSMObjectType, ObjId};
+
+use anyhow::Result;
+
+/// Collects the changes from several diffs, and returns the net change of each object.
+///
+/// The versions of the objects decide which change is the latest (not the order they were
+/// added), so the diffs can be added in any order, and may overlap. Objects need versions, as
+/// they have in replication diffs: an object whose oldest change is version 1 was created
+/// within these diffs.
+pub struct DiffSquasher {
+    sorter: ExternalSorter,
+}
+
+impl Default for DiffSquasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiffSquasher {
+    /// Uses an [`ExternalSorter`] with the default settings
+    pub fn new() -> Self {
+        Self::with_sorter(ExternalSorter::new())
+    }
+
+    /// Sort the changes with this sorter, e.g. to set the temporary directory, or a memory budget
+    pub fn with_sorter(sorter: ExternalSorter) -> Self {
+        DiffSquasher { sorter }
+    }
+
+    /// Add one change. Deleted objects must be in the `Delete` action.
+    pub fn add_change(&mut self, action: ChangeAction, mut obj: StringOSMObj) -> Result<()> {
+        // The action is stored in the object: deletions are deleted, and creations are version 1
+        if action == ChangeAction::Delete {
+            obj.set_deleted(true);
+        }
+        self.sorter.push(obj)
+    }
+
+    /// Add all the changes in this file
+    pub fn add_reader<R: Read>(&mut self, reader: &mut OSCReader<R>) -> Result<()> {
+        while let Some((action, obj)) = reader.next_change()? {
+            self.add_change(action, obj)?;
+        }
+        Ok(())
+    }
+
+    /// The net change of each object, sorted by type & id.
+    ///
+    /// An object which was created & then deleted in these diffs isn't included. One which was
+    /// created & then modified is a creation, of the last version.
+    pub fn finish(self) -> Result<SquashedChanges> {
+        Ok(SquashedChanges {
+            objs: self.sorter.finish()?,
+            peeked: None,
+        })
+    }
+
+    /// Write the net changes to `writer` (which isn't closed), and return how many of each there
+    /// are
+    pub fn write<W: Write>(self, writer: &mut OSCWriter<W>) -> Result<ChangeCounts> {
+        let mut counts = ChangeCounts::default();
+        let mut changes = self.finish()?;
+        while let Some((action, obj)) = changes.try_next()? {
+            match action {
+                ChangeAction::Create => counts.created += 1,
+                ChangeAction::Modify => counts.modified += 1,
+                ChangeAction::Delete => counts.deleted += 1,
+            }
+            writer.write_change(action, &obj)?;
+        }
+        Ok(counts)
+    }
+}
+
+/// The net changes, from [`DiffSquasher::finish`]. Iterating panics if the sorted changes can't
+/// be read back from disk, use [`SquashedChanges::try_next`] to get the error instead. Iterating
+/// stops if the sorter is cancelled.
+pub struct SquashedChanges {
+    objs: SortedObjects,
+    /// The object after the last change, which has been read already
+    peeked: Option<StringOSMObj>,
+}
+
+impl SquashedChanges {
+    fn next_obj(&mut self) -> Result<Option<StringOSMObj>> {
+        match self.peeked.take() {
+            Some(obj) => Ok(Some(obj)),
+            None => self.objs.try_next(),
+        }
+    }
+
+    /// The next change, `None` at the end, or an error if the sorted changes can't be read
+    pub fn try_next(&mut self) -> Result<Option<(ChangeAction, StringOSMObj)>> {
+        let key = |o: &StringOSMObj| -> (OSMObjectType, ObjId) { (o.object_type(), o.id()) };
+        loop {
+            let first = match self.next_obj()? {
+                Some(first) => first,
+                None => return Ok(None),
+            };
+            let created = first.version() == Some(1) && !first.deleted();
+            let mut last = first;
+            while let Some(obj) = self.next_obj()? {
+                if key(&obj) != key(&last) {
+                    self.peeked = Some(obj);
+                    break;
+                }
+                last = obj;
+            }
+            let action = match (created, last.deleted()) {
+                (true, true) => continue,
+                (true, false) => ChangeAction::Create,
+                (false, true) => ChangeAction::Delete,
+                (false, false) => ChangeAction::Modify,
+            };
+            return Ok(Some((action, last)));
+        }
+    }
+}
+
+impl Iterator for SquashedChanges {
+    type Item = (ChangeAction, StringOSMObj);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.try_next() {
+            Err(e) if e.is::<Cancelled>() => None,
+            res => res.expect("Error reading sorted changes"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {OSMReader, OSMWriter};
+
+    fn osc(changes: &str) -> OSCReader<&[u8]> {
+        OSCReader::new(changes.as_bytes())
+    }
+
+    #[test]
+    fn squash() {
+        let first = r#"<osmChange version="0.6">
+<create><node id="1" version="1" lat="1" lon="1"/><node id="2" version="1" lat="1" lon="1"/></create>
+<modify><node id="3" version="4" lat="1" lon="1"/><way id="10" version="2"><nd ref="3"/><nd ref="4"/></way></modify>
+</osmChange>"#;
+        let second = r#"<osmChange version="0.6">
+<modify><node id="1" version="2" lat="2" lon="2"/><node id="3" version="5" lat="3" lon="3"/></modify>
+<delete><node id="2" version="2"/><node id="4" version="7"/></delete>
+<modify><way id="10" version="3"><nd ref="3"/><nd ref="1"/></way></modify>
+</osmChange>"#;
+
+        let mut squasher =
+            DiffSquasher::with_sorter(ExternalSorter::new().max_objects_in_memory(2));
+        // The order they're added doesn't matter
+        squasher.add_reader(&mut osc(second)).unwrap();
+        squasher.add_reader(&mut osc(first)).unwrap();
+        let changes: Vec<_> = squasher
+            .finish()
+            .unwrap()
+            .map(|(action, obj)| (action, obj.object_type(), obj.id(), obj.version()))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (ChangeAction::Create, OSMObjectType::Node, 1, Some(2)),
+                (ChangeAction::Modify, OSMObjectType::Node, 3, Some(5)),
+                (ChangeAction::Delete, OSMObjectType::Node, 4, Some(7)),
+                (ChangeAction::Modify, OSMObjectType::Way, 10, Some(3)),
+            ]
+        );
+
+        let mut squasher = DiffSquasher::new();
+        squasher.add_reader(&mut osc(first)).unwrap();
+        squasher.add_reader(&mut osc(second)).unwrap();
+        let mut writer = OSCWriter::new(Vec::new());
+        let counts = squasher.write(&mut writer).unwrap();
+        assert_eq!(
+            counts,
+            ChangeCounts {
+                created: 1,
+                modified: 2,
+                deleted: 1
+            }
+        );
+        let output = writer.finish().unwrap();
+        let mut reader = OSCReader::new(&output[..]);
+        let mut actions = Vec::new();
+        while let Some((action, obj)) = reader.next_change().unwrap() {
+            actions.push((action, obj.id(), obj.deleted()));
+        }
+        assert_eq!(
+            actions,
+            vec![
+                (ChangeAction::Create, 1, false),
+                (ChangeAction::Modify, 3, false),
+                (ChangeAction::Delete, 4, true),
+                (ChangeAction::Modify, 10, false),
+            ]
+        );
+    }
+}
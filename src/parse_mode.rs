@@ -0,0 +1,326 @@
+//! What readers do with invalid data.
+//!
+//! Real files contain small problems, like a tag which isn't valid UTF-8, a PBF object without
+//! metadata, or a timestamp which can't be parsed. These are [`Anomaly`]s. With the default
+//! [`ParseMode::Lenient`], readers repair or skip them (e.g. the tag is left out, or the
+//! timestamp is `None`). With [`ParseMode::Strict`], they're an [`Error::Anomaly`].
+//!
+//! ```no_run
+//! use osmio::parse_mode::ParseMode;
+//! use osmio::pbf::PBFReader;
+//! use osmio::OSMReader;
+//!
+//! let mut reader = PBFReader::builder()
+//!     .parse_mode(ParseMode::warn(|anomaly| eprintln!("Warning: {}", anomaly)))
+//!     .open("input.osm.pbf")?;
+//! let num_objects = reader.objects().count();
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::Arc;
+use {Error, OSMObjectType, ObjId};
+
+/// A kind of invalid data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AnomalyKind {
+    /// A string (key, value, role or user) isn't valid UTF-8
+    InvalidUtf8,
+    /// A PBF object has no metadata (version, timestamp, user etc.)
+    MissingInfo,
+    /// A PBF string table index is past the end of the table
+    StringIndexOutOfRange,
+    /// A timestamp can't be parsed
+    InvalidTimestamp,
+    /// A number (e.g. a version, a coordinate or a node id) can't be parsed
+    InvalidNumber,
+    /// An object has more than one tag with the same key
+    DuplicateTag,
+    /// An object id, or a reference to one, is negative or zero
+    NonPositiveId,
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            AnomalyKind::InvalidUtf8 => "invalid UTF-8",
+            AnomalyKind::MissingInfo => "missing metadata",
+            AnomalyKind::StringIndexOutOfRange => "string table index out of range",
+            AnomalyKind::InvalidTimestamp => "invalid timestamp",
+            AnomalyKind::InvalidNumber => "invalid number",
+            AnomalyKind::DuplicateTag => "duplicate tag",
+            AnomalyKind::NonPositiveId => "non-positive id",
+        })
+    }
+}
+
+/// Invalid data found in an object
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    /// Byte offset of the PBF blob, or of the XML element
+    pub offset: Option<u64>,
+    pub object_type: OSMObjectType,
+    pub id: ObjId,
+    /// What exactly is wrong
+    pub detail: String,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} in {} {}", self.kind, self.object_type, self.id)?;
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        write!(f, ": {}", self.detail)
+    }
+}
+
+/// Whether invalid data is an error. See the [module documentation](self).
+#[derive(Clone, Default)]
+pub enum ParseMode {
+    /// Every anomaly is an [`Error::Anomaly`]
+    Strict,
+    /// Anomalies are repaired or skipped
+    #[default]
+    Lenient,
+    /// Like `Lenient`, but each anomaly is passed to this function first
+    Warn(Arc<dyn Fn(&Anomaly) + Send + Sync>),
+}
+
+impl ParseMode {
+    /// Lenient, calling `warning` for each anomaly
+    pub fn warn(warning: impl Fn(&Anomaly) + Send + Sync + 'static) -> Self {
+        ParseMode::Warn(Arc::new(warning))
+    }
+
+    /// True iff anomalies are errors
+    pub fn is_strict(&self) -> bool {
+        matches!(self, ParseMode::Strict)
+    }
+
+    /// An error if strict, otherwise `Ok` (after warning), and the caller repairs or skips the
+    /// invalid data
+    pub(crate) fn report(&self, anomaly: Anomaly) -> Result<(), Error> {
+        match self {
+            ParseMode::Strict => Err(Error::Anomaly(anomaly)),
+            ParseMode::Lenient => Ok(()),
+            ParseMode::Warn(warning) => {
+                warning(&anomaly);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What readers do with objects which have more than one tag with the same key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DuplicateTags {
+    /// Keep all the tags, as they are in the file. Duplicates aren't looked for, so they aren't
+    /// an anomaly.
+    #[default]
+    KeepAll,
+    /// Keep the first tag with each key. The duplicates are an anomaly.
+    KeepFirst,
+    /// Keep the last tag with each key. The duplicates are an anomaly.
+    KeepLast,
+    /// Duplicates are an [`Error::Anomaly`], whatever the [`ParseMode`]
+    Error,
+}
+
+impl DuplicateTags {
+    /// The duplicates among these keys, or `None` if there aren't any (or they're all kept)
+    pub(crate) fn find<'a>(self, keys: impl Iterator<Item = &'a str>) -> Option<Duplicates> {
+        if self == DuplicateTags::KeepAll {
+            return None;
+        }
+        let mut keys: Vec<&str> = keys.collect();
+        if keys.len() < 2 {
+            return None;
+        }
+        if self == DuplicateTags::KeepLast {
+            keys.reverse();
+        }
+        let mut seen = HashSet::with_capacity(keys.len());
+        let mut key = None;
+        let mut keep: Vec<bool> = keys
+            .iter()
+            .map(|k| {
+                let first = seen.insert(k);
+                if !first {
+                    key.get_or_insert(*k);
+                }
+                first
+            })
+            .collect();
+        if self == DuplicateTags::KeepLast {
+            keep.reverse();
+        }
+        key.map(|key| Duplicates {
+            keep,
+            key: key.to_string(),
+        })
+    }
+
+    /// Report duplicates: an error with [`DuplicateTags::Error`], otherwise the parse mode
+    /// decides
+    pub(crate) fn report(self, anomaly: Anomaly, parse_mode: &ParseMode) -> Result<(), Error> {
+        if self == DuplicateTags::Error {
+            return Err(Error::Anomaly(anomaly));
+        }
+        parse_mode.report(anomaly)
+    }
+}
+
+/// Duplicate tags found by [`DuplicateTags::find`]
+pub(crate) struct Duplicates {
+    /// Whether to keep each tag
+    keep: Vec<bool>,
+    /// The first duplicated key
+    pub(crate) key: String,
+}
+
+impl Duplicates {
+    /// Remove the tags which aren't kept
+    pub(crate) fn remove<T>(self, tags: &mut Vec<T>) {
+        let mut keep = self.keep.into_iter();
+        tags.retain(|_| keep.next().unwrap_or(true));
+    }
+}
+
+/// What readers do with negative or zero ids.
+///
+/// Ids are signed ([`ObjId`] is an `i64`), and new objects in JOSM files have negative ids. In
+/// files from the OSM database, all ids are positive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum NonPositiveIds {
+    /// Read them like any other id
+    #[default]
+    Allow,
+    /// An object with a non-positive id, or which refers to one (a way node or relation
+    /// member), is an [`Error::Anomaly`], whatever the [`ParseMode`]
+    Error,
+}
+
+impl NonPositiveIds {
+    /// Check the id of this object, and the ids it refers to
+    pub(crate) fn check(
+        self,
+        object_type: OSMObjectType,
+        id: ObjId,
+        mut refs: impl Iterator<Item = ObjId>,
+        offset: Option<u64>,
+    ) -> Result<(), Error> {
+        if self == NonPositiveIds::Allow {
+            return Ok(());
+        }
+        let detail = if id <= 0 {
+            format!("id {}", id)
+        } else if let Some(r) = refs.find(|&r| r <= 0) {
+            format!("reference to {}", r)
+        } else {
+            return Ok(());
+        };
+        Err(Error::Anomaly(Anomaly {
+            kind: AnomalyKind::NonPositiveId,
+            offset,
+            object_type,
+            id,
+            detail,
+        }))
+    }
+}
+
+impl fmt::Debug for ParseMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseMode::Strict => f.write_str("Strict"),
+            ParseMode::Lenient => f.write_str("Lenient"),
+            ParseMode::Warn(_) => f.write_str("Warn(..)"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn anomaly() -> Anomaly {
+        Anomaly {
+            kind: AnomalyKind::InvalidTimestamp,
+            offset: Some(10),
+            object_type: OSMObjectType::Way,
+            id: 5,
+            detail: "\"yesterday\"".to_string(),
+        }
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(
+            anomaly().to_string(),
+            "invalid timestamp in way 5 at byte 10: \"yesterday\""
+        );
+        assert!(matches!(
+            ParseMode::Strict.report(anomaly()),
+            Err(Error::Anomaly(a)) if a == anomaly()
+        ));
+        assert!(ParseMode::Lenient.report(anomaly()).is_ok());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = Arc::clone(&seen);
+        let mode = ParseMode::warn(move |a| seen2.lock().unwrap().push(a.kind));
+        assert!(mode.report(anomaly()).is_ok());
+        assert_eq!(*seen.lock().unwrap(), vec![AnomalyKind::InvalidTimestamp]);
+    }
+
+    #[test]
+    fn duplicate_tags() {
+        let tags = vec![("a", 1), ("b", 2), ("a", 3), ("c", 4), ("b", 5)];
+        let apply = |policy: DuplicateTags| {
+            let mut tags = tags.clone();
+            if let Some(duplicates) = policy.find(tags.iter().map(|t| t.0)) {
+                let anomaly = Anomaly {
+                    kind: AnomalyKind::DuplicateTag,
+                    detail: duplicates.key.clone(),
+                    ..anomaly()
+                };
+                policy.report(anomaly, &ParseMode::Lenient)?;
+                duplicates.remove(&mut tags);
+            }
+            Ok::<_, Error>(tags)
+        };
+        assert_eq!(apply(DuplicateTags::KeepAll).unwrap(), tags);
+        assert_eq!(
+            apply(DuplicateTags::KeepFirst).unwrap(),
+            vec![("a", 1), ("b", 2), ("c", 4)]
+        );
+        assert_eq!(
+            apply(DuplicateTags::KeepLast).unwrap(),
+            vec![("a", 3), ("c", 4), ("b", 5)]
+        );
+        assert!(matches!(
+            apply(DuplicateTags::Error),
+            Err(Error::Anomaly(Anomaly { detail, .. })) if detail == "a"
+        ));
+    }
+
+    #[test]
+    fn non_positive_ids() {
+        let check = |policy: NonPositiveIds, id, refs: &[ObjId]| {
+            policy.check(OSMObjectType::Way, id, refs.iter().copied(), None)
+        };
+        assert!(check(NonPositiveIds::Allow, -1, &[-2, 0]).is_ok());
+        assert!(check(NonPositiveIds::Error, 1, &[2, 3]).is_ok());
+        assert!(matches!(
+            check(NonPositiveIds::Error, -1, &[]),
+            Err(Error::Anomaly(Anomaly { detail, .. })) if detail == "id -1"
+        ));
+        assert!(matches!(
+            check(NonPositiveIds::Error, 1, &[2, 0]),
+            Err(Error::Anomaly(Anomaly { detail, .. })) if detail == "reference to 0"
+        ));
+    }
+}
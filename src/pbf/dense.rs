@@ -0,0 +1,371 @@
+//! Decoding dense nodes, for all the PBF readers.
+//!
+//! Dense nodes are stored as parallel, delta coded arrays. [`DenseNodes`] checks the arrays all
+//! have one entry per node, and decodes the nodes one at a time. Strings (tags & user names) are
+//! left as string table indexes, since each reader has its own string table.
+use utils::pbf_lat_lon;
+use {Error, Lat, Lon, ObjId, ParseTimestampError, TimestampFormat};
+
+/// The arrays of one `DenseNodes` message
+pub(crate) struct DenseArrays<'a> {
+    pub(crate) ids: &'a [i64],
+    pub(crate) lats: &'a [i64],
+    pub(crate) lons: &'a [i64],
+    pub(crate) keys_vals: &'a [i32],
+    /// `None` if there's no `DenseInfo`
+    pub(crate) info: Option<DenseInfoArrays<'a>>,
+}
+
+/// The arrays of one `DenseInfo` message
+pub(crate) struct DenseInfoArrays<'a> {
+    pub(crate) versions: &'a [i32],
+    pub(crate) timestamps: &'a [i64],
+    pub(crate) changesets: &'a [i64],
+    pub(crate) uids: &'a [i32],
+    pub(crate) user_sids: &'a [i32],
+    /// Empty unless the file has historical information
+    pub(crate) visible: &'a [bool],
+}
+
+impl DenseInfoArrays<'_> {
+    fn lengths(&self) -> [(&'static str, usize); 5] {
+        [
+            ("versions", self.versions.len()),
+            ("timestamps", self.timestamps.len()),
+            ("changesets", self.changesets.len()),
+            ("uids", self.uids.len()),
+            ("user names", self.user_sids.len()),
+        ]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.visible.is_empty() && self.lengths().iter().all(|&(_, len)| len == 0)
+    }
+}
+
+/// One decoded dense node
+pub(crate) struct DenseNode<'a> {
+    pub(crate) id: ObjId,
+    pub(crate) lat_lon: (Lat, Lon),
+    pub(crate) deleted: bool,
+    /// `None` if the block has no dense info
+    pub(crate) info: Option<DenseNodeInfo>,
+    /// Key & value string indexes. `None` if no node in the group has tags.
+    keys_vals: Option<&'a [i32]>,
+}
+
+impl<'a> DenseNode<'a> {
+    /// The key & value string indexes of each tag, or `None` if no node in the group has tags
+    pub(crate) fn tags(&self) -> Option<impl Iterator<Item = (i64, i64)> + 'a> {
+        self.keys_vals.map(|keys_vals| {
+            keys_vals
+                .chunks_exact(2)
+                .map(|kv| (kv[0].into(), kv[1].into()))
+        })
+    }
+}
+
+/// The metadata of one dense node
+pub(crate) struct DenseNodeInfo {
+    pub(crate) version: u32,
+    /// An error if it's out of range, which the reader reports as an anomaly
+    pub(crate) timestamp: Result<TimestampFormat, ParseTimestampError>,
+    pub(crate) changeset_id: u32,
+    pub(crate) uid: u32,
+    /// String index of the user name
+    pub(crate) user_sid: i64,
+}
+
+/// Decodes the nodes of one `DenseNodes` message, in order. See the [module docs](self).
+pub(crate) struct DenseNodes<'a> {
+    arrays: DenseArrays<'a>,
+    granularity: i32,
+    lat_lon_offsets: (i64, i64),
+    date_granularity: i32,
+    /// Offset of the blob, for errors
+    offset: u64,
+    index: usize,
+    keys_vals_index: usize,
+    // Everything is delta coded, so these are the values of the previous node
+    id: i64,
+    raw_lat: i64,
+    raw_lon: i64,
+    timestamp: i64,
+    changeset: i64,
+    uid: i32,
+    user_sid: i32,
+}
+
+impl<'a> DenseNodes<'a> {
+    /// Decode these arrays, from a block with this granularity & these offsets. An error if the
+    /// arrays don't all have one entry per node (an empty `DenseInfo` is the same as none).
+    pub(crate) fn new(
+        mut arrays: DenseArrays<'a>,
+        granularity: i32,
+        lat_lon_offsets: (i64, i64),
+        date_granularity: i32,
+        offset: u64,
+    ) -> Result<Self, Error> {
+        let num_nodes = arrays.ids.len();
+        let mismatch = |reason: String| Error::Format {
+            offset: Some(offset),
+            reason: format!("{} dense node ids, but {}", num_nodes, reason),
+        };
+        if arrays.lats.len() != num_nodes || arrays.lons.len() != num_nodes {
+            return Err(mismatch(format!(
+                "{} latitudes and {} longitudes",
+                arrays.lats.len(),
+                arrays.lons.len()
+            )));
+        }
+        if arrays.info.as_ref().is_some_and(|info| info.is_empty()) {
+            arrays.info = None;
+        }
+        if let Some(info) = &arrays.info {
+            let visible = ("visible flags", info.visible.len());
+            let lengths = info.lengths();
+            let wrong = lengths
+                .iter()
+                .chain(Some(&visible).filter(|(_, len)| *len != 0))
+                .find(|&&(_, len)| len != num_nodes);
+            if let Some((name, len)) = wrong {
+                return Err(mismatch(format!("{} {}", len, name)));
+            }
+        }
+        Ok(DenseNodes {
+            arrays,
+            granularity,
+            lat_lon_offsets,
+            date_granularity,
+            offset,
+            index: 0,
+            keys_vals_index: 0,
+            id: 0,
+            raw_lat: 0,
+            raw_lon: 0,
+            timestamp: 0,
+            changeset: 0,
+            uid: 0,
+            user_sid: 0,
+        })
+    }
+
+    /// Number of nodes
+    pub(crate) fn len(&self) -> usize {
+        self.arrays.ids.len()
+    }
+
+    /// True iff the nodes have metadata
+    pub(crate) fn has_info(&self) -> bool {
+        self.arrays.info.is_some()
+    }
+
+    /// Id of the first node
+    pub(crate) fn first_id(&self) -> Option<ObjId> {
+        self.arrays.ids.first().copied()
+    }
+
+    /// The key & value string indexes of the current node. Each node's tags are key, value
+    /// pairs, ending with a 0. If the array ends first, the block is invalid.
+    fn keys_vals(&mut self) -> Result<Option<&'a [i32]>, Error> {
+        let keys_vals = self.arrays.keys_vals;
+        if keys_vals.is_empty() {
+            return Ok(None);
+        }
+        let rest = &keys_vals[self.keys_vals_index.min(keys_vals.len())..];
+        let mut pairs = rest.chunks_exact(2);
+        let mut len = 0;
+        loop {
+            match pairs.next() {
+                Some([0, _]) => break,
+                Some(_) => len += 2,
+                None if pairs.remainder() == [0] => break,
+                None => {
+                    return Err(Error::Format {
+                        offset: Some(self.offset),
+                        reason: format!("The dense tags of node {} are truncated", self.id),
+                    })
+                }
+            }
+        }
+        self.keys_vals_index += len + 1;
+        Ok(Some(&rest[..len]))
+    }
+
+    fn decode(&mut self) -> Result<DenseNode<'a>, Error> {
+        let index = self.index;
+        // Invalid deltas can overflow
+        self.id = self.id.wrapping_add(self.arrays.ids[index]);
+        self.raw_lat = self.raw_lat.wrapping_add(self.arrays.lats[index]);
+        self.raw_lon = self.raw_lon.wrapping_add(self.arrays.lons[index]);
+        let lat_lon = pbf_lat_lon(
+            (self.raw_lat, self.raw_lon),
+            self.granularity,
+            self.lat_lon_offsets,
+            self.id,
+        )?;
+        let keys_vals = self.keys_vals()?;
+
+        let mut deleted = false;
+        let info = match &self.arrays.info {
+            None => None,
+            Some(info) => {
+                self.timestamp = self.timestamp.wrapping_add(info.timestamps[index]);
+                self.changeset = self.changeset.wrapping_add(info.changesets[index]);
+                self.uid = self.uid.wrapping_add(info.uids[index]);
+                self.user_sid = self.user_sid.wrapping_add(info.user_sids[index]);
+                deleted = !info.visible.get(index).unwrap_or(&true);
+                Some(DenseNodeInfo {
+                    version: info.versions[index] as u32,
+                    timestamp: TimestampFormat::from_date_granularity(
+                        self.timestamp,
+                        self.date_granularity,
+                    ),
+                    changeset_id: self.changeset as u32,
+                    uid: self.uid as u32,
+                    user_sid: self.user_sid.into(),
+                })
+            }
+        };
+        Ok(DenseNode {
+            id: self.id,
+            lat_lon,
+            deleted,
+            info,
+            keys_vals,
+        })
+    }
+}
+
+impl<'a> Iterator for DenseNodes<'a> {
+    type Item = Result<DenseNode<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.len() {
+            return None;
+        }
+        let node = self.decode();
+        // Stop after an error
+        self.index = if node.is_ok() {
+            self.index + 1
+        } else {
+            self.len()
+        };
+        Some(node)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arrays<'a>(keys_vals: &'a [i32], info: Option<DenseInfoArrays<'a>>) -> DenseArrays<'a> {
+        DenseArrays {
+            ids: &[10, 1, 5],
+            lats: &[515_000_000, -100, 200],
+            lons: &[-1_000_000, 50, -50],
+            keys_vals,
+            info,
+        }
+    }
+
+    fn decode(arrays: DenseArrays) -> Result<Vec<DenseNode>, Error> {
+        DenseNodes::new(arrays, 100, (0, 0), 1000, 0)?.collect()
+    }
+
+    #[test]
+    fn decode_deltas() {
+        let info = DenseInfoArrays {
+            versions: &[1, 2, 3],
+            timestamps: &[1_600_000_000, 10, -5],
+            changesets: &[100, 1, 1],
+            uids: &[5, 0, -5],
+            user_sids: &[1, 0, 1],
+            visible: &[],
+        };
+        let nodes = decode(arrays(&[1, 2, 0, 0, 3, 4, 1, 2, 0], Some(info))).unwrap();
+        let ids: Vec<_> = nodes.iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![10, 11, 16]);
+        assert_eq!(nodes[1].lat_lon, (Lat(514_999_900), Lon(-999_950)));
+        let tags = |n: &DenseNode| n.tags().unwrap().collect::<Vec<_>>();
+        assert_eq!(tags(&nodes[0]), vec![(1, 2)]);
+        assert_eq!(tags(&nodes[1]), vec![]);
+        assert_eq!(tags(&nodes[2]), vec![(3, 4), (1, 2)]);
+        let info = nodes[2].info.as_ref().unwrap();
+        assert_eq!(
+            (info.version, info.changeset_id, info.uid, info.user_sid),
+            (3, 102, 0, 2)
+        );
+        assert_eq!(
+            info.timestamp,
+            Ok(TimestampFormat::EpochNunber(1_600_000_005))
+        );
+        assert!(!nodes[2].deleted);
+
+        // Without tags or info
+        let nodes = decode(arrays(&[], None)).unwrap();
+        assert!(nodes.iter().all(|n| n.tags().is_none() && n.info.is_none()));
+        // An empty `DenseInfo` is the same as none
+        let info = DenseInfoArrays {
+            versions: &[],
+            timestamps: &[],
+            changesets: &[],
+            uids: &[],
+            user_sids: &[],
+            visible: &[],
+        };
+        assert!(
+            !DenseNodes::new(arrays(&[], Some(info)), 100, (0, 0), 1000, 0)
+                .unwrap()
+                .has_info()
+        );
+    }
+
+    #[test]
+    fn length_mismatches() {
+        let reason = |arrays| match decode(arrays) {
+            Err(Error::Format { reason, .. }) => reason,
+            other => panic!("{:?}", other.map(|nodes| nodes.len())),
+        };
+        let mut short = arrays(&[], None);
+        short.lons = &[1, 2];
+        assert_eq!(
+            reason(short),
+            "3 dense node ids, but 3 latitudes and 2 longitudes"
+        );
+
+        let info = DenseInfoArrays {
+            versions: &[1, 1, 1],
+            timestamps: &[0, 0, 0],
+            changesets: &[0, 0],
+            uids: &[0, 0, 0],
+            user_sids: &[0, 0, 0],
+            visible: &[true, false, true],
+        };
+        assert_eq!(
+            reason(arrays(&[], Some(info))),
+            "3 dense node ids, but 2 changesets"
+        );
+        let info = DenseInfoArrays {
+            versions: &[1, 1, 1],
+            timestamps: &[0, 0, 0],
+            changesets: &[0, 0, 0],
+            uids: &[0, 0, 0],
+            user_sids: &[0, 0, 0],
+            visible: &[true],
+        };
+        assert_eq!(
+            reason(arrays(&[], Some(info))),
+            "3 dense node ids, but 1 visible flags"
+        );
+
+        assert_eq!(
+            reason(arrays(&[0, 1, 2], None)),
+            "The dense tags of node 11 are truncated"
+        );
+        assert_eq!(
+            reason(arrays(&[0, 0], None)),
+            "The dense tags of node 16 are truncated"
+        );
+    }
+}
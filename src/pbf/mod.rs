@@ -1,2 +1,4 @@
 //! Read PBF file (currently alias for arcpbf::)
 pub use arcpbf::*;
+
+pub(crate) mod dense;
@@ -0,0 +1,362 @@
+//! Chaining filters & transforms between a reader and a writer.
+//!
+//! A [`Pipeline`] is a list of steps, applied in order to each object: filters drop objects (by
+//! tag, bounding box, id, or timestamp), and transforms change them (rewriting tags, anonymising,
+//! renumbering). [`Pipeline::run`] reads the input once, and writes the objects which are left to
+//! the output. [`Pipeline::iter`] applies the steps to an iterator instead.
+//!
+//! ```rust,no_run
+//! use osmio::pipeline::Pipeline;
+//! use osmio::transform::TagTransform;
+//! use osmio::anonymise::Anonymiser;
+//! use osmio::open::{AnyReader, AnyWriter};
+//! use osmio::TimestampFormat;
+//!
+//! let mut reader = AnyReader::from_filename("input.osm.pbf")?;
+//! let mut writer = AnyWriter::from_filename("output.osm.xml", None)?;
+//! let stats = Pipeline::new()
+//!     .tag_filter("n/amenity".parse()?)
+//!     .modified_since(TimestampFormat::ISOString("2024-01-01T00:00:00Z".to_string()))
+//!     .tag_transform(TagTransform::new().drop_keys("fixme"))
+//!     .anonymise(Anonymiser::strip())
+//!     .progress(1_000_000, |stats| eprintln!("{} objects read", stats.read))
+//!     .run(&mut reader, &mut writer)?;
+//! println!("Wrote {} of {} objects", stats.written, stats.read);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Every step works in a single pass. The bounding box filter relies on the input being sorted
+//! (nodes, then ways, then relations), like [`extract`](crate::extract) does for its simple
+//! strategy: a way is kept if it has a node which was kept, and a relation is kept if it has a
+//! member which was kept.
+use anonymise::Anonymiser;
+use cancel::CancellationToken;
+use classify::Classifier;
+use idset::IdSet;
+use renumber::Renumberer;
+use std::io::Write;
+use tagfilter::TagFilter;
+use transform::TagTransform;
+use TimestampFormat;
+use {BBox, Node, OSMObj, OSMObjectType, OSMReader, OSMWriter, Relation, Way};
+
+use anyhow::Result;
+
+/// Closure which decides whether to keep an object, see [`Pipeline::filter`]
+type FilterFn<O> = Box<dyn FnMut(&O) -> bool>;
+/// Closure which changes an object, see [`Pipeline::map`]
+type MapFn<O> = Box<dyn FnMut(&mut O)>;
+/// Closure which is called regularly, see [`Pipeline::progress`]
+type ProgressFn = Box<dyn FnMut(&PipelineStats)>;
+
+enum Step<O> {
+    Filter(FilterFn<O>),
+    Map(MapFn<O>),
+}
+
+/// How many objects went through a [`Pipeline`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    /// Objects read from the input
+    pub read: u64,
+    /// Objects which got through every filter (and were written, for [`Pipeline::run`])
+    pub written: u64,
+}
+
+impl PipelineStats {
+    /// Objects dropped by a filter
+    pub fn filtered(&self) -> u64 {
+        self.read - self.written
+    }
+}
+
+/// A list of filters & transforms, applied in order to each object. See the
+/// [module docs](self).
+pub struct Pipeline<O: OSMObj> {
+    steps: Vec<Step<O>>,
+    progress: Option<(u64, ProgressFn)>,
+    cancellation: Option<CancellationToken>,
+}
+
+impl<O: OSMObj> std::fmt::Debug for Pipeline<O> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("num_steps", &self.steps.len())
+            .finish()
+    }
+}
+
+impl<O: OSMObj> Default for Pipeline<O> {
+    fn default() -> Self {
+        Pipeline {
+            steps: Vec::new(),
+            progress: None,
+            cancellation: None,
+        }
+    }
+}
+
+impl<O: OSMObj + 'static> Pipeline<O> {
+    /// A pipeline which keeps every object unchanged
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep objects for which `f` returns true
+    pub fn filter(mut self, f: impl FnMut(&O) -> bool + 'static) -> Self {
+        self.steps.push(Step::Filter(Box::new(f)));
+        self
+    }
+
+    /// Change every object with `f`
+    pub fn map(mut self, f: impl FnMut(&mut O) + 'static) -> Self {
+        self.steps.push(Step::Map(Box::new(f)));
+        self
+    }
+
+    /// Only keep objects which match this tag filter
+    pub fn tag_filter(self, tag_filter: TagFilter) -> Self {
+        self.filter(move |obj| tag_filter.matches(obj))
+    }
+
+    /// Only keep nodes inside this bounding box, and the ways & relations which refer to them.
+    /// The input must be sorted (see the [module docs](self)).
+    pub fn bbox(self, bbox: BBox) -> Self {
+        let mut kept = IdSet::new();
+        self.filter(move |obj| {
+            let keep = if let Some(node) = obj.as_node() {
+                node.lat_lon().is_some_and(|ll| bbox.contains(ll))
+            } else if let Some(way) = obj.as_way() {
+                way.nodes()
+                    .iter()
+                    .any(|nid| kept.contains(OSMObjectType::Node, *nid))
+            } else if let Some(relation) = obj.as_relation() {
+                relation.members().any(|(t, id, _)| kept.contains(t, id))
+            } else {
+                false
+            };
+            if keep {
+                kept.insert_obj(obj);
+            }
+            keep
+        })
+    }
+
+    /// Only keep objects in this set of ids
+    pub fn ids(self, ids: IdSet) -> Self {
+        self.filter(move |obj| ids.contains_obj(obj))
+    }
+
+    /// Drop objects in this set of ids
+    pub fn exclude_ids(self, ids: IdSet) -> Self {
+        self.filter(move |obj| !ids.contains_obj(obj))
+    }
+
+    /// Only keep objects last changed at or after this time. Objects without a timestamp are
+    /// dropped.
+    pub fn modified_since(self, since: TimestampFormat) -> Self {
+        self.filter(move |obj| obj.timestamp().as_ref().is_some_and(|t| *t >= since))
+    }
+
+    /// Change the tags of every object
+    pub fn tag_transform(self, mut transform: TagTransform) -> Self {
+        self.map(move |obj| transform.apply(obj))
+    }
+
+    /// Set the tag `key` of every object to its category (see [`Classifier::annotate`])
+    pub fn classify(self, classifier: Classifier, key: impl Into<String>) -> Self {
+        let key = key.into();
+        self.map(move |obj| classifier.annotate(obj, &key))
+    }
+
+    /// Remove (or pseudonymise) the user metadata of every object
+    pub fn anonymise(self, anonymiser: Anonymiser) -> Self {
+        self.map(move |obj| anonymiser.apply(obj))
+    }
+
+    /// Give every object a new id. The mapping from old to new ids isn't kept; to save it, call
+    /// [`Renumberer::renumber`] from a [`map`](Self::map) step instead.
+    pub fn renumber(self, mut renumberer: Renumberer) -> Self {
+        self.map(move |obj| renumberer.renumber(obj))
+    }
+
+    /// Call `f` with the counts so far, after every `every` objects are read, and once at the
+    /// end.
+    pub fn progress(mut self, every: u64, f: impl FnMut(&PipelineStats) + 'static) -> Self {
+        self.progress = Some((every.max(1), Box::new(f)));
+        self
+    }
+
+    /// Stop with an [`Error::Cancelled`](crate::Error::Cancelled) once this token is cancelled
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Apply every step to this object, in order. Returns false if a filter dropped it.
+    pub fn apply(&mut self, obj: &mut O) -> bool {
+        for step in self.steps.iter_mut() {
+            match step {
+                Step::Filter(f) => {
+                    if !f(obj) {
+                        return false;
+                    }
+                }
+                Step::Map(f) => f(obj),
+            }
+        }
+        true
+    }
+
+    /// Apply the steps to every object from this iterator, leaving out the ones which are
+    /// filtered out. Progress & cancellation are only used by [`run`](Self::run).
+    pub fn iter<'a>(
+        &'a mut self,
+        objs: impl Iterator<Item = O> + 'a,
+    ) -> impl Iterator<Item = O> + 'a {
+        objs.filter_map(move |mut obj| {
+            if self.apply(&mut obj) {
+                Some(obj)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Read every object from `reader`, apply the steps, write the ones which are left to
+    /// `writer`, and close it.
+    ///
+    /// Stops at the first read or write error, or when cancelled.
+    pub fn run<R, W, Wr>(&mut self, reader: &mut R, writer: &mut Wr) -> Result<PipelineStats>
+    where
+        R: OSMReader<Obj = O>,
+        W: Write,
+        Wr: OSMWriter<W>,
+    {
+        let mut stats = PipelineStats::default();
+        for obj in reader.try_objects() {
+            if let Some(cancellation) = &self.cancellation {
+                cancellation.check().map_err(::Error::from)?;
+            }
+            let mut obj = obj?;
+            stats.read += 1;
+            if self.apply(&mut obj) {
+                writer.write_obj(&obj)?;
+                stats.written += 1;
+            }
+            if let Some((every, f)) = &mut self.progress {
+                if stats.read % *every == 0 {
+                    f(&stats);
+                }
+            }
+        }
+        writer.close()?;
+        if let Some((_, f)) = &mut self.progress {
+            f(&stats);
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::StringOSMObj;
+    use std::cell::Cell;
+    use std::convert::TryFrom;
+    use std::rc::Rc;
+    use xml::{XMLReader, XMLWriter};
+    use {Lat, Lon, OSMObjBase};
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" timestamp="2020-01-01T00:00:00Z" user="alice" uid="1" lat="1" lon="1"><tag k="amenity" v="bench"/></node>
+<node id="2" version="1" timestamp="2024-01-01T00:00:00Z" user="alice" uid="1" lat="1" lon="1"/>
+<node id="3" version="1" timestamp="2024-01-01T00:00:00Z" user="alice" uid="1" lat="5" lon="5"/>
+<way id="1" version="1" timestamp="2024-01-01T00:00:00Z"><nd ref="1"/><nd ref="2"/><tag k="highway" v="path"/><tag k="fixme" v="yes"/></way>
+<way id="2" version="1" timestamp="2024-01-01T00:00:00Z"><nd ref="3"/></way>
+<relation id="1" version="1" timestamp="2024-01-01T00:00:00Z"><member type="way" ref="1" role=""/></relation>
+</osm>"#;
+
+    #[test]
+    fn run() {
+        let progress = Rc::new(Cell::new(0));
+        let calls = progress.clone();
+        let bbox = BBox::new(
+            Lat::try_from(0.).unwrap(),
+            Lon::try_from(0.).unwrap(),
+            Lat::try_from(2.).unwrap(),
+            Lon::try_from(2.).unwrap(),
+        );
+        let mut writer = XMLWriter::new(Vec::new());
+        let stats = Pipeline::new()
+            .bbox(bbox)
+            .modified_since(TimestampFormat::ISOString(
+                "2023-01-01T00:00:00Z".to_string(),
+            ))
+            .tag_transform(TagTransform::new().drop_keys("fixme"))
+            .anonymise(Anonymiser::strip())
+            .progress(2, move |_| calls.set(calls.get() + 1))
+            .run(&mut XMLReader::new(INPUT.as_bytes()), &mut writer)
+            .unwrap();
+        assert_eq!(
+            stats,
+            PipelineStats {
+                read: 6,
+                written: 3
+            }
+        );
+        assert_eq!(stats.filtered(), 3);
+        assert_eq!(progress.get(), 4);
+
+        let output = writer.into_inner();
+        let objs: Vec<_> = XMLReader::new(&output[..]).objects().collect();
+        let ids: Vec<_> = objs.iter().map(|o| (o.object_type(), o.id())).collect();
+        assert_eq!(
+            ids,
+            vec![
+                (OSMObjectType::Node, 2),
+                (OSMObjectType::Way, 1),
+                (OSMObjectType::Relation, 1)
+            ]
+        );
+        assert_eq!(objs[0].user(), None);
+        assert_eq!(objs[1].tag("highway"), Some("path"));
+        assert!(!objs[1].has_tag("fixme"));
+    }
+
+    #[test]
+    fn iter_and_cancel() {
+        let mut ids = IdSet::new();
+        ids.insert(OSMObjectType::Node, 1);
+        ids.insert(OSMObjectType::Way, 1);
+        let mut pipeline = Pipeline::new()
+            .exclude_ids(ids)
+            .tag_filter("highway".parse().unwrap())
+            .renumber(Renumberer::new());
+        let objs: Vec<_> = pipeline
+            .iter(XMLReader::new(INPUT.as_bytes()).objects())
+            .collect();
+        assert_eq!(objs.len(), 0);
+
+        let mut pipeline = Pipeline::new()
+            .renumber(Renumberer::with_start_ids(10, 20, 30))
+            .filter(|o: &StringOSMObj| o.object_type() == OSMObjectType::Way);
+        let objs: Vec<_> = pipeline
+            .iter(XMLReader::new(INPUT.as_bytes()).objects())
+            .collect();
+        assert_eq!(objs[1].id(), 21);
+        assert_eq!(objs[1].as_way().unwrap().nodes(), &[12]);
+
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let err = Pipeline::new()
+            .cancellation(cancellation)
+            .run(
+                &mut XMLReader::new(INPUT.as_bytes()),
+                &mut XMLWriter::new(Vec::new()),
+            )
+            .unwrap_err();
+        assert!(err.downcast_ref::<::Error>().unwrap().is_cancelled());
+    }
+}
@@ -0,0 +1,264 @@
+//! Renumbering object ids.
+//!
+//! Gives every node, way & relation a new id, counting up from 1 (for each object type), and
+//! updates the references in ways & relations to match. Objects are given new ids in the order
+//! they are first seen, either as an object or as a reference, so it works on a single stream of
+//! objects. The mapping from old to new ids can be saved & loaded, to renumber other files (e.g.
+//! change files) consistently.
+//!
+//! ```rust
+//! use osmio::renumber::Renumberer;
+//! use osmio::obj_types::StringWayBuilder;
+//! use osmio::{OSMObjBase, OSMObjectType, Way};
+//!
+//! let mut renumberer = Renumberer::new();
+//! let mut way = StringWayBuilder::default()._id(1000)._nodes(vec![50, 60, 50]).build().unwrap();
+//! renumberer.renumber_way(&mut way);
+//! assert_eq!(way.id(), 1);
+//! assert_eq!(way.nodes(), &[1, 2, 1]);
+//! assert_eq!(renumberer.get(OSMObjectType::Node, 60), Some(2));
+//! ```
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use {Error, Node, OSMObj, OSMObjectType, ObjId, Relation, Way};
+
+use anyhow::{anyhow, Result};
+
+/// Assigns new ids, and remembers the mapping from old ids.
+#[derive(Debug, Clone)]
+pub struct Renumberer {
+    /// One map per object type, in the order of `OSMObjectType::index`
+    mappings: [HashMap<ObjId, ObjId>; 3],
+    next_ids: [ObjId; 3],
+}
+
+impl Default for Renumberer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Renumberer {
+    /// New renumberer, where all new ids start at 1
+    pub fn new() -> Self {
+        Self::with_start_ids(1, 1, 1)
+    }
+
+    /// New renumberer, where new ids start at these values
+    pub fn with_start_ids(node_start: ObjId, way_start: ObjId, relation_start: ObjId) -> Self {
+        Renumberer {
+            mappings: Default::default(),
+            next_ids: [node_start, way_start, relation_start],
+        }
+    }
+
+    /// The new id for this object, if it has been given one
+    pub fn get(&self, object_type: OSMObjectType, old_id: ObjId) -> Option<ObjId> {
+        self.mappings[object_type.index()].get(&old_id).copied()
+    }
+
+    /// The new id for this object, giving it a new id if it hasn't got one yet
+    pub fn map_id(&mut self, object_type: OSMObjectType, old_id: ObjId) -> ObjId {
+        let idx = object_type.index();
+        let next_id = &mut self.next_ids[idx];
+        *self.mappings[idx].entry(old_id).or_insert_with(|| {
+            let new_id = *next_id;
+            *next_id += 1;
+            new_id
+        })
+    }
+
+    /// Number of ids which have been mapped, for this object type
+    pub fn len(&self, object_type: OSMObjectType) -> usize {
+        self.mappings[object_type.index()].len()
+    }
+
+    /// True iff no ids have been mapped
+    pub fn is_empty(&self) -> bool {
+        self.mappings.iter().all(|m| m.is_empty())
+    }
+
+    /// Change the id of this node
+    pub fn renumber_node(&mut self, node: &mut impl Node) {
+        let new_id = self.map_id(OSMObjectType::Node, node.id());
+        node.set_id(new_id);
+    }
+
+    /// Change the id of this way, and the ids of its nodes
+    pub fn renumber_way(&mut self, way: &mut impl Way) {
+        let new_id = self.map_id(OSMObjectType::Way, way.id());
+        way.set_id(new_id);
+        let nodes: Vec<ObjId> = way
+            .nodes()
+            .iter()
+            .map(|nid| self.map_id(OSMObjectType::Node, *nid))
+            .collect();
+        way.set_nodes(nodes);
+    }
+
+    /// Change the id of this relation, and the ids of its members
+    pub fn renumber_relation(&mut self, relation: &mut impl Relation) {
+        let new_id = self.map_id(OSMObjectType::Relation, relation.id());
+        relation.set_id(new_id);
+        let members: Vec<(OSMObjectType, ObjId, String)> = relation
+            .members()
+            .map(|(t, id, role)| (t, id, role.to_string()))
+            .collect();
+        relation.set_members(
+            members
+                .into_iter()
+                .map(|(t, id, role)| (t, self.map_id(t, id), role)),
+        );
+    }
+
+    /// Change the id (and references) of this object
+    pub fn renumber<O: OSMObj>(&mut self, obj: &mut O) {
+        if let Some(node) = obj.as_node_mut() {
+            self.renumber_node(node);
+        } else if let Some(way) = obj.as_way_mut() {
+            self.renumber_way(way);
+        } else if let Some(relation) = obj.as_relation_mut() {
+            self.renumber_relation(relation);
+        }
+    }
+
+    /// Renumber all the objects from this iterator (e.g. `reader.objects()`)
+    pub fn renumber_iter<'a, O: OSMObj + 'a>(
+        &'a mut self,
+        objs: impl Iterator<Item = O> + 'a,
+    ) -> impl Iterator<Item = O> + 'a {
+        objs.map(move |mut obj| {
+            self.renumber(&mut obj);
+            obj
+        })
+    }
+
+    /// Write the id mapping, one per line, as `TYPE,OLD_ID,NEW_ID` (e.g. `n,123,1`).
+    pub fn write_mapping(&self, writer: impl Write) -> Result<()> {
+        let mut writer = std::io::BufWriter::new(writer);
+        for object_type in [
+            OSMObjectType::Node,
+            OSMObjectType::Way,
+            OSMObjectType::Relation,
+        ] {
+            let mut mapping: Vec<_> = self.mappings[object_type.index()].iter().collect();
+            mapping.sort_by_key(|(_old, new)| **new);
+            for (old, new) in mapping {
+                writeln!(writer, "{},{},{}", object_type.name_short(), old, new)?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a mapping written by [`Renumberer::write_mapping`]. New ids will continue after the
+    /// largest ids in the mapping.
+    pub fn read_mapping(reader: impl Read) -> Result<Self> {
+        let mut renumberer = Self::new();
+        for (line_no, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || anyhow!("Invalid id mapping on line {}: {:?}", line_no + 1, line);
+            let mut parts = line.split(',');
+            let (object_type, old, new) = match (parts.next(), parts.next(), parts.next()) {
+                (Some(t), Some(old), Some(new)) => (
+                    t.parse::<OSMObjectType>().map_err(|_| invalid())?,
+                    old.parse::<ObjId>().map_err(|_| invalid())?,
+                    new.parse::<ObjId>().map_err(|_| invalid())?,
+                ),
+                _ => return Err(invalid()),
+            };
+            let next_id = new.checked_add(1).ok_or_else(|| Error::Format {
+                offset: None,
+                reason: format!(
+                    "New id {} on line {} of the id mapping is too large to continue after",
+                    new,
+                    line_no + 1
+                ),
+            })?;
+            let idx = object_type.index();
+            renumberer.mappings[idx].insert(old, new);
+            renumberer.next_ids[idx] = renumberer.next_ids[idx].max(next_id);
+        }
+        Ok(renumberer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringNodeBuilder, StringOSMObj, StringRelationBuilder, StringWayBuilder};
+    use OSMObjBase;
+
+    fn objects() -> Vec<StringOSMObj> {
+        vec![
+            StringNodeBuilder::default()
+                ._id(100)
+                .build()
+                .unwrap()
+                .into(),
+            StringNodeBuilder::default()._id(50).build().unwrap().into(),
+            StringWayBuilder::default()
+                ._id(7)
+                ._nodes(vec![50, 100, 200])
+                .build()
+                .unwrap()
+                .into(),
+            StringRelationBuilder::default()
+                ._id(9)
+                ._members(vec![
+                    (OSMObjectType::Relation, 10, "sub".to_string()),
+                    (OSMObjectType::Way, 7, "".to_string()),
+                    (OSMObjectType::Node, 200, "".to_string()),
+                ])
+                .build()
+                .unwrap()
+                .into(),
+            StringRelationBuilder::default()
+                ._id(10)
+                .build()
+                .unwrap()
+                .into(),
+        ]
+    }
+
+    #[test]
+    fn renumber() {
+        let mut renumberer = Renumberer::new();
+        let objs: Vec<_> = renumberer.renumber_iter(objects().into_iter()).collect();
+        let ids: Vec<_> = objs.iter().map(|o| o.id()).collect();
+        assert_eq!(ids, vec![1, 2, 1, 1, 2]);
+        assert_eq!(objs[2].as_way().unwrap().nodes(), &[2, 1, 3]);
+        let members: Vec<_> = objs[3].as_relation().unwrap().members().collect();
+        assert_eq!(
+            members,
+            vec![
+                (OSMObjectType::Relation, 2, "sub"),
+                (OSMObjectType::Way, 1, ""),
+                (OSMObjectType::Node, 3, "")
+            ]
+        );
+        assert_eq!(renumberer.len(OSMObjectType::Node), 3);
+    }
+
+    #[test]
+    fn mapping_round_trip() {
+        let mut renumberer = Renumberer::with_start_ids(1, 1, 1000);
+        for mut obj in objects() {
+            renumberer.renumber(&mut obj);
+        }
+        let mut output = Vec::new();
+        renumberer.write_mapping(&mut output).unwrap();
+        assert!(String::from_utf8_lossy(&output).starts_with("n,100,1\nn,50,2\nn,200,3\nw,7,1\n"));
+
+        let mut renumberer2 = Renumberer::read_mapping(output.as_slice()).unwrap();
+        assert_eq!(renumberer2.get(OSMObjectType::Relation, 10), Some(1001));
+        assert_eq!(renumberer2.map_id(OSMObjectType::Node, 1), 4);
+
+        assert!(Renumberer::read_mapping("x,1,2\n".as_bytes()).is_err());
+        let err = Renumberer::read_mapping("n,1,9223372036854775807\n".as_bytes()).unwrap_err();
+        assert!(matches!(err.downcast_ref(), Some(Error::Format { .. })));
+    }
+}
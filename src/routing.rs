@@ -0,0 +1,354 @@
+//! Turning highways into a graph, e.g. for routing or network analysis.
+//!
+//! The vertices of a [`RoutingGraph`] are the junctions: nodes which are the end of a way, or
+//! which are used by more than one way (or more than once by the same way). Each edge is the part
+//! of a way between 2 junctions, with its length in metres and the tags of its way.
+//!
+//! ```no_run
+//! use osmio::routing::RoutingGraph;
+//!
+//! let graph = RoutingGraph::read(|| osmio::read_pbf("input.osm.pbf"))?;
+//! for edge in graph.edges() {
+//!     println!("way {}: {:.0} m", edge.way_id, edge.length);
+//! }
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use node_locations::{NodeLocations, SparseNodeLocations};
+use std::collections::{HashMap, HashSet};
+use {Lat, Lon, OSMObj, OSMObjBase, OSMReader, ObjId, Way};
+
+use anyhow::Result;
+
+/// Mean radius of the earth, in metres
+const EARTH_RADIUS: f64 = 6_371_008.8;
+
+/// The great circle (haversine) distance between 2 `(lat, lon)` locations, in metres
+pub fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lon1) = (a.0.to_radians(), a.1.to_radians());
+    let (lat2, lon2) = (b.0.to_radians(), b.1.to_radians());
+    let h = ((lat2 - lat1) / 2.).sin().powi(2)
+        + lat1.cos() * lat2.cos() * ((lon2 - lon1) / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// True iff this way has a `highway` tag
+pub fn is_highway(way: &impl Way) -> bool {
+    way.has_tag("highway")
+}
+
+/// A junction in a [`RoutingGraph`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vertex {
+    pub node_id: ObjId,
+    pub location: (Lat, Lon),
+}
+
+/// Part of a way, between 2 junctions. `from` & `to` are indexes into
+/// [`RoutingGraph::vertices`], in the direction of the way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub way_id: ObjId,
+    /// All the node ids, including the junctions at both ends
+    pub nodes: Vec<ObjId>,
+    /// Length in metres
+    pub length: f64,
+    pub tags: Vec<(String, String)>,
+}
+
+/// A graph of junctions & the way segments between them. See the [module documentation](self).
+#[derive(Debug, Clone, Default)]
+pub struct RoutingGraph {
+    vertices: Vec<Vertex>,
+    edges: Vec<Edge>,
+    /// Edge indexes which start or end at each vertex
+    adjacent: Vec<Vec<usize>>,
+    vertex_index: HashMap<ObjId, usize>,
+    skipped_ways: Vec<ObjId>,
+}
+
+impl RoutingGraph {
+    /// Build a graph from all the highways in a file.
+    ///
+    /// The file is read twice, first for the ways, and then for the locations of their nodes, so
+    /// this is given a function which opens a new reader (e.g. `|| osmio::read_pbf(&path)`).
+    pub fn read<F, R>(open: F) -> Result<Self>
+    where
+        F: FnMut() -> Result<R>,
+        R: OSMReader,
+    {
+        Self::read_filtered(open, is_highway)
+    }
+
+    /// Build a graph from the ways in a file for which `include` returns true.
+    pub fn read_filtered<F, R, P>(mut open: F, mut include: P) -> Result<Self>
+    where
+        F: FnMut() -> Result<R>,
+        R: OSMReader,
+        P: FnMut(&<R::Obj as OSMObj>::Way) -> bool,
+    {
+        let mut ways = Vec::new();
+        let mut reader = open()?;
+        while let Some(obj) = reader.try_next()? {
+            if let Some(way) = obj.as_way() {
+                if include(way) {
+                    ways.push(SimpleWay::from_way(way));
+                }
+            }
+        }
+
+        let needed: HashSet<ObjId> = ways.iter().flat_map(|w| w.nodes.iter().copied()).collect();
+        let mut locations = SparseNodeLocations::new();
+        let mut reader = open()?;
+        while let Some(obj) = reader.try_next()? {
+            if let Some(node) = obj.as_node() {
+                if needed.contains(&node.id()) {
+                    locations.add_node(node);
+                }
+            }
+        }
+
+        Ok(Self::build(ways.iter(), &locations))
+    }
+
+    /// Build a graph from these ways, with node locations from `locations`.
+    ///
+    /// Ways with fewer than 2 nodes, or with a node whose location is unknown, are left out (see
+    /// [`RoutingGraph::skipped_ways`]).
+    pub fn from_ways<'a, W: Way + 'a>(
+        ways: impl IntoIterator<Item = &'a W>,
+        locations: &impl NodeLocations,
+    ) -> Self {
+        let ways: Vec<SimpleWay> = ways.into_iter().map(SimpleWay::from_way).collect();
+        Self::build(ways.iter(), locations)
+    }
+
+    fn build<'a>(
+        ways: impl Iterator<Item = &'a SimpleWay> + Clone,
+        locations: &impl NodeLocations,
+    ) -> Self {
+        let mut uses: HashMap<ObjId, u32> = HashMap::new();
+        for way in ways.clone() {
+            for (i, nid) in way.nodes.iter().enumerate() {
+                // Ends count twice, so they're always junctions
+                let count = if i == 0 || i == way.nodes.len() - 1 {
+                    2
+                } else {
+                    1
+                };
+                *uses.entry(*nid).or_insert(0) += count;
+            }
+        }
+
+        let mut graph = RoutingGraph::default();
+        for way in ways {
+            let locs = match locations.get_all(&way.nodes) {
+                Some(locs) if way.nodes.len() >= 2 => locs,
+                _ => {
+                    graph.skipped_ways.push(way.id);
+                    continue;
+                }
+            };
+            let mut start = 0;
+            let mut length = 0.;
+            for i in 1..way.nodes.len() {
+                length += distance(to_f64(locs[i - 1]), to_f64(locs[i]));
+                if uses[&way.nodes[i]] < 2 {
+                    continue;
+                }
+                let from = graph.vertex(way.nodes[start], locs[start]);
+                let to = graph.vertex(way.nodes[i], locs[i]);
+                graph.add_edge(Edge {
+                    from,
+                    to,
+                    way_id: way.id,
+                    nodes: way.nodes[start..=i].to_vec(),
+                    length,
+                    tags: way.tags.clone(),
+                });
+                start = i;
+                length = 0.;
+            }
+        }
+        graph
+    }
+
+    /// The vertex index for this node, adding it if needed
+    fn vertex(&mut self, node_id: ObjId, location: (Lat, Lon)) -> usize {
+        if let Some(&idx) = self.vertex_index.get(&node_id) {
+            return idx;
+        }
+        let idx = self.vertices.len();
+        self.vertices.push(Vertex { node_id, location });
+        self.adjacent.push(Vec::new());
+        self.vertex_index.insert(node_id, idx);
+        idx
+    }
+
+    fn add_edge(&mut self, edge: Edge) {
+        let idx = self.edges.len();
+        self.adjacent[edge.from].push(idx);
+        if edge.to != edge.from {
+            self.adjacent[edge.to].push(idx);
+        }
+        self.edges.push(edge);
+    }
+
+    /// All the junctions
+    pub fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+
+    /// All the way segments
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    /// The index of the vertex for this node, if it's a junction
+    pub fn vertex_for_node(&self, node_id: ObjId) -> Option<usize> {
+        self.vertex_index.get(&node_id).copied()
+    }
+
+    /// The edges which start or end at this vertex (ignoring the way's direction)
+    pub fn edges_at(&self, vertex: usize) -> impl Iterator<Item = &Edge> + '_ {
+        self.adjacent[vertex].iter().map(move |&e| &self.edges[e])
+    }
+
+    /// Ids of the ways which were left out, because they had too few nodes, or missing node
+    /// locations
+    pub fn skipped_ways(&self) -> &[ObjId] {
+        &self.skipped_ways
+    }
+
+    /// Copy this into a [`petgraph`] graph, for its algorithms (`petgraph` feature).
+    ///
+    /// Each vertex & edge has the same index as in [`RoutingGraph::vertices`] &
+    /// [`RoutingGraph::edges`]. The graph is undirected, since oneway tags aren't interpreted.
+    ///
+    /// ```no_run
+    /// use osmio::routing::RoutingGraph;
+    ///
+    /// let graph = RoutingGraph::read(|| osmio::read_pbf("input.osm.pbf"))?;
+    /// let start = graph.vertex_for_node(1234).unwrap();
+    /// let start = petgraph::graph::NodeIndex::new(start);
+    /// let distances =
+    ///     petgraph::algo::dijkstra(&graph.to_petgraph(), start, None, |e| e.weight().length);
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    #[cfg(feature = "petgraph")]
+    pub fn to_petgraph(&self) -> petgraph::graph::UnGraph<Vertex, Edge> {
+        let mut graph =
+            petgraph::graph::UnGraph::with_capacity(self.vertices.len(), self.edges.len());
+        for vertex in self.vertices.iter() {
+            graph.add_node(vertex.clone());
+        }
+        for edge in self.edges.iter() {
+            graph.add_edge(
+                petgraph::graph::NodeIndex::new(edge.from),
+                petgraph::graph::NodeIndex::new(edge.to),
+                edge.clone(),
+            );
+        }
+        graph
+    }
+}
+
+fn to_f64((lat, lon): (Lat, Lon)) -> (f64, f64) {
+    (lat.into(), lon.into())
+}
+
+/// The parts of a way which the graph needs, so ways from any reader can be stored
+struct SimpleWay {
+    id: ObjId,
+    nodes: Vec<ObjId>,
+    tags: Vec<(String, String)>,
+}
+
+impl SimpleWay {
+    fn from_way(way: &impl Way) -> Self {
+        SimpleWay {
+            id: way.id(),
+            nodes: way.nodes().to_vec(),
+            tags: way
+                .tags()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use xml::XMLReader;
+
+    // 2 --- 1 --- 3 --- 4, and 3 --- 5. Way 13 isn't a highway, and way 12 has a missing node
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="0" lon="0"/>
+<node id="2" lat="0" lon="-0.01"/>
+<node id="3" lat="0" lon="0.01"/>
+<node id="4" lat="0" lon="0.02"/>
+<node id="5" lat="0.01" lon="0.01"/>
+<way id="10"><nd ref="2"/><nd ref="1"/><nd ref="3"/><nd ref="4"/><tag k="highway" v="primary"/></way>
+<way id="11"><nd ref="3"/><nd ref="5"/><tag k="highway" v="service"/></way>
+<way id="12"><nd ref="5"/><nd ref="6"/><tag k="highway" v="track"/></way>
+<way id="13"><nd ref="1"/><nd ref="5"/><tag k="waterway" v="river"/></way>
+</osm>"#;
+
+    fn open() -> Result<XMLReader<Cursor<&'static str>>> {
+        Ok(XMLReader::new(Cursor::new(INPUT)))
+    }
+
+    #[test]
+    fn graph() {
+        let graph = RoutingGraph::read(open).unwrap();
+        let node_ids: Vec<ObjId> = graph.vertices().iter().map(|v| v.node_id).collect();
+        assert_eq!(node_ids, vec![2, 3, 4, 5]);
+        assert_eq!(graph.skipped_ways(), &[12]);
+
+        let edges: Vec<_> = graph
+            .edges()
+            .iter()
+            .map(|e| (e.way_id, e.nodes.clone()))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![(10, vec![2, 1, 3]), (10, vec![3, 4]), (11, vec![3, 5])]
+        );
+        // 0.01° of longitude at the equator is about 1112 m
+        assert!((graph.edges()[0].length - 2224.0).abs() < 1.);
+        assert_eq!(graph.edges()[2].tags[0].1, "service");
+
+        let junction = graph.vertex_for_node(3).unwrap();
+        assert_eq!(graph.edges_at(junction).count(), 3);
+        assert_eq!(graph.vertex_for_node(1), None);
+    }
+
+    #[test]
+    #[cfg(feature = "petgraph")]
+    fn petgraph() {
+        use petgraph::graph::NodeIndex;
+
+        let graph = RoutingGraph::read(open).unwrap();
+        let petgraph = graph.to_petgraph();
+        assert_eq!(petgraph.node_count(), 4);
+        assert_eq!(petgraph.edge_count(), 3);
+        let from = NodeIndex::new(graph.vertex_for_node(2).unwrap());
+        let to = NodeIndex::new(graph.vertex_for_node(5).unwrap());
+        assert_eq!(petgraph[to].node_id, 5);
+        let distances = petgraph::algo::dijkstra(&petgraph, from, Some(to), |e| e.weight().length);
+        let expected = graph.edges()[0].length + graph.edges()[2].length;
+        assert!((distances[&to] - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn haversine() {
+        assert_eq!(distance((1., 2.), (1., 2.)), 0.);
+        // London to Paris
+        let d = distance((51.5074, -0.1278), (48.8566, 2.3522));
+        assert!((d - 343_500.).abs() < 1000., "{}", d);
+    }
+}
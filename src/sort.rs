@@ -0,0 +1,521 @@
+//! Sorting OSM objects which don't fit in memory.
+//!
+//! Objects can be added in any order. When the in-memory buffer is full, it's sorted and written
+//! to a temporary file (a “run”). At the end, all the runs are merged together, so objects are
+//! returned in the standard order: nodes, then ways, then relations, each sorted by id, then
+//! version. This is the same order as `osmium sort`. If there are more than
+//! [`ExternalSorter::max_merge_runs`] runs, groups of them are merged into longer runs first, so
+//! only that many files are read at once.
+//!
+//! Other things can be sorted too, by implementing [`SortItem`] for them.
+//!
+//! ```rust
+//! use osmio::sort::ExternalSorter;
+//! use osmio::obj_types::StringNodeBuilder;
+//! use osmio::OSMObjBase;
+//!
+//! let mut sorter = ExternalSorter::new();
+//! for id in [3, 1, 2] {
+//!     sorter.push(StringNodeBuilder::default()._id(id).build().unwrap())?;
+//! }
+//! let ids: Vec<_> = sorter.finish()?.map(|o| o.id()).collect();
+//! assert_eq!(ids, vec![1, 2, 3]);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use cancel::{self, CancellationToken, Cancelled};
+use memory::{MemoryBudget, Reservation};
+use obj_types::StringOSMObj;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use {OSMObj, OSMObjBase, OSMObjectType, ObjId, Relation, Way};
+
+use anyhow::Result;
+
+/// The key which objects are sorted by
+fn sort_key(obj: &StringOSMObj) -> (OSMObjectType, ObjId, Option<u32>) {
+    (obj.object_type(), obj.id(), obj.version())
+}
+
+/// Approximately how many bytes this object uses in memory
+fn estimated_size(obj: &StringOSMObj) -> usize {
+    let mut size = std::mem::size_of::<StringOSMObj>() + obj.user().map_or(0, |u| u.len());
+    size += obj
+        .tags()
+        .map(|(k, v)| k.len() + v.len() + std::mem::size_of::<(String, String)>())
+        .sum::<usize>();
+    if let Some(way) = obj.as_way() {
+        size += std::mem::size_of_val(way.nodes());
+    }
+    if let Some(relation) = obj.as_relation() {
+        size += relation
+            .members()
+            .map(|(_, _, role)| role.len() + std::mem::size_of::<(OSMObjectType, ObjId, String)>())
+            .sum::<usize>();
+    }
+    size
+}
+
+/// Something which an [`ExternalSorter`] can sort. It's written to runs with `bincode`.
+pub trait SortItem: Serialize + DeserializeOwned {
+    /// What items are sorted by
+    type Key: Ord;
+
+    /// The key of this item
+    fn sort_key(&self) -> Self::Key;
+
+    /// Approximately how many bytes this item uses in memory
+    fn estimated_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+    }
+}
+
+impl SortItem for StringOSMObj {
+    type Key = (OSMObjectType, ObjId, Option<u32>);
+
+    fn sort_key(&self) -> Self::Key {
+        sort_key(self)
+    }
+
+    fn estimated_size(&self) -> usize {
+        estimated_size(self)
+    }
+}
+
+/// Sorts objects (or other [`SortItem`]s), using temporary files when there are too many to keep
+/// in memory.
+pub struct ExternalSorter<T = StringOSMObj> {
+    max_objects_in_memory: usize,
+    max_merge_runs: usize,
+    temp_dir: PathBuf,
+    buffer: Vec<T>,
+    runs: Vec<File>,
+    cancellation: Option<CancellationToken>,
+    /// The memory used by `buffer`, if there's a budget
+    memory: Option<Reservation>,
+}
+
+/// A new sorter, which keeps up to 1,000,000 items in memory, merges up to 64 runs at once, and
+/// stores runs in the system temporary directory.
+impl<T: SortItem> Default for ExternalSorter<T> {
+    fn default() -> Self {
+        ExternalSorter {
+            max_objects_in_memory: 1_000_000,
+            max_merge_runs: 64,
+            temp_dir: std::env::temp_dir(),
+            buffer: Vec::new(),
+            runs: Vec::new(),
+            cancellation: None,
+            memory: None,
+        }
+    }
+}
+
+impl ExternalSorter {
+    /// A new sorter for objects, which keeps up to 1,000,000 objects in memory, merges up to 64
+    /// runs at once, and stores runs in the system temporary directory. Other items are sorted
+    /// with `ExternalSorter::default()`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<T: SortItem> ExternalSorter<T> {
+    /// Keep at most this many objects in memory before writing a run to disk.
+    pub fn max_objects_in_memory(mut self, max_objects_in_memory: usize) -> Self {
+        self.max_objects_in_memory = max_objects_in_memory.max(1);
+        self
+    }
+
+    /// Read at most this many runs at once (minimum 2). When there are more, groups of runs are
+    /// merged into one first.
+    pub fn max_merge_runs(mut self, max_merge_runs: usize) -> Self {
+        self.max_merge_runs = max_merge_runs.max(2);
+        self
+    }
+
+    /// Store runs in this directory.
+    pub fn temp_dir(mut self, temp_dir: impl Into<PathBuf>) -> Self {
+        self.temp_dir = temp_dir.into();
+        self
+    }
+
+    /// Stop when this token is cancelled, adding objects & finishing will return a
+    /// [`Cancelled`](crate::cancel::Cancelled) error.
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Also write a run to disk when the objects in memory would use more than the available
+    /// memory in this budget. The memory is reserved until the sorted objects are dropped.
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory = Some(budget.reservation());
+        self
+    }
+
+    /// Number of runs written to disk so far
+    pub fn num_runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Add an object
+    pub fn push(&mut self, obj: impl Into<T>) -> Result<()> {
+        cancel::check(&self.cancellation)?;
+        let obj = obj.into();
+        if self.memory.is_some() {
+            let size = obj.estimated_size();
+            let fits = self.memory.as_mut().unwrap().try_grow(size);
+            if !fits && !self.buffer.is_empty() {
+                self.write_run()?;
+            }
+            let memory = self.memory.as_mut().unwrap();
+            if !fits && !memory.try_grow(size) {
+                // Each object has to be kept in memory, even if it's over the budget
+                memory.grow(size);
+            }
+        }
+        self.buffer.push(obj);
+        if self.buffer.len() >= self.max_objects_in_memory {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    /// Add all objects from this iterator (e.g. `reader.objects()`)
+    pub fn extend<I>(&mut self, objs: I) -> Result<()>
+    where
+        I: IntoIterator,
+        I::Item: Into<T>,
+    {
+        for obj in objs {
+            self.push(obj)?;
+        }
+        Ok(())
+    }
+
+    /// Sort the buffer & write it to a new temporary file
+    fn write_run(&mut self) -> Result<()> {
+        self.buffer.sort_by_key(T::sort_key);
+        let mut file = BufWriter::new(tempfile::tempfile_in(&self.temp_dir)?);
+        for obj in self.buffer.drain(..) {
+            bincode::serialize_into(&mut file, &obj)?;
+        }
+        if let Some(memory) = &mut self.memory {
+            memory.clear();
+        }
+        self.runs.push(finish_run(file)?);
+        Ok(())
+    }
+
+    /// Merge the first `max_merge_runs` runs into one new run, at the end
+    fn merge_runs(&mut self) -> Result<()> {
+        let runs = self.runs.drain(..self.max_merge_runs).collect();
+        let mut merged = SortedObjects::<T>::new(Vec::new(), runs, self.cancellation.clone())?;
+        let mut file = BufWriter::new(tempfile::tempfile_in(&self.temp_dir)?);
+        while let Some(obj) = merged.try_next()? {
+            bincode::serialize_into(&mut file, &obj)?;
+        }
+        self.runs.push(finish_run(file)?);
+        Ok(())
+    }
+
+    /// Finish adding objects, and return all the objects in sorted order.
+    ///
+    /// If everything fit in memory, nothing is written to disk.
+    pub fn finish(mut self) -> Result<SortedObjects<T>> {
+        cancel::check(&self.cancellation)?;
+        while self.runs.len() > self.max_merge_runs {
+            self.merge_runs()?;
+        }
+        self.buffer.sort_by_key(T::sort_key);
+        let mut sorted = SortedObjects::new(
+            std::mem::take(&mut self.buffer),
+            self.runs,
+            self.cancellation,
+        )?;
+        sorted._memory = self.memory;
+        Ok(sorted)
+    }
+}
+
+/// Flush a run which has been written, and go back to the start of it to read it
+fn finish_run(mut file: BufWriter<File>) -> Result<File> {
+    file.flush()?;
+    let mut file = file.into_inner()?;
+    file.seek(SeekFrom::Start(0))?;
+    Ok(file)
+}
+
+/// Read the next object from a run, or `None` at the end
+fn read_obj<T: DeserializeOwned>(run: &mut BufReader<File>) -> Result<Option<T>> {
+    match bincode::deserialize_from(run) {
+        Ok(obj) => Ok(Some(obj)),
+        Err(e) => match *e {
+            bincode::ErrorKind::Io(ref io_err)
+                if io_err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                Ok(None)
+            }
+            _ => Err(e.into()),
+        },
+    }
+}
+
+/// The next object from one run
+struct HeapEntry<T> {
+    obj: T,
+    idx: usize,
+}
+
+impl<T: SortItem> PartialEq for HeapEntry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl<T: SortItem> Eq for HeapEntry<T> {}
+impl<T: SortItem> PartialOrd for HeapEntry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl<T: SortItem> Ord for HeapEntry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.obj
+            .sort_key()
+            .cmp(&other.obj.sort_key())
+            .then(self.idx.cmp(&other.idx))
+    }
+}
+
+/// All the objects, in sorted order.
+///
+/// Created by [`ExternalSorter::finish`]. Iterating panics if there's an error reading the
+/// temporary files, use [`SortedObjects::try_next`] to get the error instead. If the sorter's
+/// cancellation token is cancelled, iterating stops, and `try_next` returns a [`Cancelled`]
+/// error.
+pub struct SortedObjects<T = StringOSMObj> {
+    in_memory: std::iter::Peekable<std::vec::IntoIter<T>>,
+    runs: Vec<BufReader<File>>,
+    heap: BinaryHeap<Reverse<HeapEntry<T>>>,
+    cancellation: Option<CancellationToken>,
+    _memory: Option<Reservation>,
+}
+
+impl<T: SortItem> SortedObjects<T> {
+    /// Merge these sorted objects & runs
+    fn new(
+        in_memory: Vec<T>,
+        runs: Vec<File>,
+        cancellation: Option<CancellationToken>,
+    ) -> Result<Self> {
+        let mut runs: Vec<BufReader<File>> = runs.into_iter().map(BufReader::new).collect();
+        let mut heap = BinaryHeap::with_capacity(runs.len());
+        for (idx, run) in runs.iter_mut().enumerate() {
+            if let Some(obj) = read_obj(run)? {
+                heap.push(Reverse(HeapEntry { obj, idx }));
+            }
+        }
+        Ok(SortedObjects {
+            in_memory: in_memory.into_iter().peekable(),
+            runs,
+            heap,
+            cancellation,
+            _memory: None,
+        })
+    }
+
+    /// The next object, `None` at the end, or an error if a temporary file can't be read, or it's
+    /// cancelled
+    pub fn try_next(&mut self) -> Result<Option<T>> {
+        cancel::check(&self.cancellation)?;
+        let use_memory = match (self.in_memory.peek(), self.heap.peek()) {
+            (None, None) => return Ok(None),
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (Some(obj), Some(Reverse(entry))) => obj.sort_key() <= entry.obj.sort_key(),
+        };
+        if use_memory {
+            return Ok(self.in_memory.next());
+        }
+
+        // Read the run's next object first, so nothing is lost if that fails
+        let idx = self.heap.peek().unwrap().0.idx;
+        let next = read_obj(&mut self.runs[idx])?;
+        let Reverse(HeapEntry { obj, .. }) = self.heap.pop().unwrap();
+        if let Some(next) = next {
+            self.heap.push(Reverse(HeapEntry { obj: next, idx }));
+        }
+        Ok(Some(obj))
+    }
+}
+
+impl<T: SortItem> Iterator for SortedObjects<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.try_next() {
+            Err(e) if e.is::<Cancelled>() => None,
+            res => res.expect("Error reading sorted run"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use obj_types::{StringNodeBuilder, StringRelationBuilder, StringWayBuilder};
+    use serde::Deserialize;
+
+    fn objects() -> Vec<StringOSMObj> {
+        let mut objs: Vec<StringOSMObj> = Vec::new();
+        for i in 0..50 {
+            // scramble the order
+            let id = (i * 37) % 50;
+            objs.push(
+                StringRelationBuilder::default()
+                    ._id(id)
+                    .build()
+                    .unwrap()
+                    .into(),
+            );
+            objs.push(
+                StringNodeBuilder::default()
+                    ._id(id)
+                    ._version(2)
+                    .build()
+                    .unwrap()
+                    .into(),
+            );
+            objs.push(StringWayBuilder::default()._id(id).build().unwrap().into());
+            objs.push(
+                StringNodeBuilder::default()
+                    ._id(id)
+                    ._version(1)
+                    .build()
+                    .unwrap()
+                    .into(),
+            );
+        }
+        objs
+    }
+
+    fn check_sorted(sorted: Vec<StringOSMObj>) {
+        assert_eq!(sorted.len(), 200);
+        let keys: Vec<_> = sorted.iter().map(sort_key).collect();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(keys, expected);
+        assert_eq!(sort_key(&sorted[0]), (OSMObjectType::Node, 0, Some(1)));
+        assert_eq!(sort_key(&sorted[199]), (OSMObjectType::Relation, 49, None));
+    }
+
+    #[test]
+    fn in_memory() {
+        let mut sorter = ExternalSorter::new();
+        sorter.extend(objects()).unwrap();
+        assert_eq!(sorter.num_runs(), 0);
+        check_sorted(sorter.finish().unwrap().collect());
+    }
+
+    #[test]
+    fn with_runs() {
+        let mut sorter = ExternalSorter::new().max_objects_in_memory(30);
+        sorter.extend(objects()).unwrap();
+        assert_eq!(sorter.num_runs(), 6);
+        check_sorted(sorter.finish().unwrap().collect());
+    }
+
+    #[test]
+    fn merge_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sorter = ExternalSorter::new()
+            .max_objects_in_memory(10)
+            .max_merge_runs(3)
+            .temp_dir(dir.path());
+        sorter.extend(objects()).unwrap();
+        assert_eq!(sorter.num_runs(), 20);
+        check_sorted(sorter.finish().unwrap().collect());
+
+        // Any item can be sorted
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Id(ObjId);
+        impl SortItem for Id {
+            type Key = ObjId;
+            fn sort_key(&self) -> ObjId {
+                self.0
+            }
+        }
+        let mut sorter = ExternalSorter::default()
+            .max_objects_in_memory(4)
+            .max_merge_runs(2);
+        sorter.extend((0..50).map(|i| Id((i * 37) % 50))).unwrap();
+        let sorted: Vec<Id> = sorter.finish().unwrap().collect();
+        assert_eq!(sorted, (0..50).map(Id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn corrupt_run() {
+        let mut sorter = ExternalSorter::new().max_objects_in_memory(30);
+        sorter.extend(objects()).unwrap();
+        // Overwrite the second half of the first run
+        let run = &mut sorter.runs[0];
+        let len = run.metadata().unwrap().len();
+        run.seek(SeekFrom::Start(len / 2)).unwrap();
+        run.write_all(&vec![0xff; (len - len / 2) as usize])
+            .unwrap();
+        run.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut sorted = sorter.finish().unwrap();
+        let mut count = 0;
+        let err = loop {
+            match sorted.try_next() {
+                Ok(Some(_)) => count += 1,
+                Ok(None) => panic!("no error after {} objects", count),
+                Err(e) => break e,
+            }
+        };
+        assert!(err.is::<bincode::Error>());
+        assert!(count < 200);
+    }
+
+    #[test]
+    fn memory_budget() {
+        let budget = MemoryBudget::new(50 * std::mem::size_of::<StringOSMObj>());
+        let mut sorter = ExternalSorter::new().memory_budget(budget.clone());
+        sorter.extend(objects()).unwrap();
+        assert_eq!(sorter.num_runs(), 3);
+        assert!(budget.used() > 0 && budget.used() <= budget.limit());
+        let sorted = sorter.finish().unwrap();
+        assert!(budget.used() > 0);
+        check_sorted(sorted.collect());
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn cancelled() {
+        let token = CancellationToken::new();
+        let mut sorter = ExternalSorter::new().cancellation(token.clone());
+        sorter.push(objects().remove(0)).unwrap();
+        token.cancel();
+        let err = sorter.extend(objects()).unwrap_err();
+        assert!(err.is::<cancel::Cancelled>());
+        assert!(sorter.finish().is_err());
+
+        // While merging
+        let token = CancellationToken::new();
+        let mut sorter = ExternalSorter::new()
+            .max_objects_in_memory(30)
+            .cancellation(token.clone());
+        sorter.extend(objects()).unwrap();
+        let mut sorted = sorter.finish().unwrap();
+        assert!(sorted.try_next().unwrap().is_some());
+        token.cancel();
+        assert!(sorted.try_next().unwrap_err().is::<cancel::Cancelled>());
+        assert!(sorted.next().is_none());
+    }
+}
@@ -0,0 +1,329 @@
+//! Spatial indexes of nodes & ways, to find the objects near a location (`spatial` feature).
+//!
+//! [`NodeIndex`] & [`WayIndex`] bulk load node locations or way bboxes into an
+//! [`rstar::RTree`], and find the objects inside a [`BBox`], or within a distance of a location.
+//! Coordinates in the trees are `[lon, lat]` in degrees, and the tree is available (with
+//! [`NodeIndex::tree`] & [`WayIndex::tree`]) for other queries.
+//!
+//! ```no_run
+//! use osmio::spatial::NodeIndex;
+//! use osmio::{Lat, Lon};
+//! use std::convert::TryFrom;
+//!
+//! let index = NodeIndex::from_reader(&mut osmio::read_pbf("input.osm.pbf")?)?;
+//! let centre = (Lat::try_from(53.35)?, Lon::try_from(-6.26)?);
+//! println!("{} nodes within 500m", index.within_radius(centre, 500.).count());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use node_locations::NodeLocations;
+use routing::distance;
+use rstar::primitives::{GeomWithData, Rectangle};
+use rstar::{Envelope, RTree, RTreeObject, AABB};
+use {BBox, Lat, Lon, Node, OSMObj, OSMObjBase, OSMReader, ObjId, Way};
+
+use anyhow::Result;
+
+/// A node in a [`NodeIndex`]: its `[lon, lat]`, and its id
+pub type IndexedNode = GeomWithData<[f64; 2], ObjId>;
+
+/// A way in a [`WayIndex`]: its bbox (as `[lon, lat]` corners), and its id
+pub type IndexedWay = GeomWithData<Rectangle<[f64; 2]>, ObjId>;
+
+/// Metres in a degree of latitude (& longitude at the equator)
+const METRES_PER_DEGREE: f64 = 111_195.;
+
+fn point((lat, lon): (Lat, Lon)) -> [f64; 2] {
+    [lon.degrees(), lat.degrees()]
+}
+
+/// The envelopes to search for this bbox, 2 if it crosses the antimeridian
+fn bbox_envelopes(bbox: &BBox) -> Vec<AABB<[f64; 2]>> {
+    let (min_lat, max_lat) = (bbox.min_lat.degrees(), bbox.max_lat.degrees());
+    let (min_lon, max_lon) = (bbox.min_lon.degrees(), bbox.max_lon.degrees());
+    if bbox.crosses_antimeridian() {
+        vec![
+            AABB::from_corners([min_lon, min_lat], [180., max_lat]),
+            AABB::from_corners([-180., min_lat], [max_lon, max_lat]),
+        ]
+    } else {
+        vec![AABB::from_corners([min_lon, min_lat], [max_lon, max_lat])]
+    }
+}
+
+/// The envelopes which contain everything within `radius` metres of `centre`
+fn radius_envelopes(centre: [f64; 2], radius: f64) -> Vec<AABB<[f64; 2]>> {
+    let [lon, lat] = centre;
+    let dlat = radius / METRES_PER_DEGREE;
+    let (min_lat, max_lat) = ((lat - dlat).max(-90.), (lat + dlat).min(90.));
+    // Longitude degrees are shortest at the latitude furthest from the equator
+    let widest = min_lat.abs().max(max_lat.abs()).to_radians().cos();
+    let dlon = if widest <= 0. || max_lat >= 90. || min_lat <= -90. {
+        180.
+    } else {
+        (dlat / widest).min(180.)
+    };
+    let (min_lon, max_lon) = (lon - dlon, lon + dlon);
+    let mut envelopes = vec![AABB::from_corners(
+        [min_lon.max(-180.), min_lat],
+        [max_lon.min(180.), max_lat],
+    )];
+    if min_lon < -180. {
+        envelopes.push(AABB::from_corners(
+            [min_lon + 360., min_lat],
+            [180., max_lat],
+        ));
+    }
+    if max_lon > 180. {
+        envelopes.push(AABB::from_corners(
+            [-180., min_lat],
+            [max_lon - 360., max_lat],
+        ));
+    }
+    envelopes
+}
+
+/// Distance in metres between 2 `[lon, lat]` points
+fn distance_lon_lat(a: [f64; 2], b: [f64; 2]) -> f64 {
+    distance((a[1], a[0]), (b[1], b[0]))
+}
+
+/// The objects in the tree which intersect any of these envelopes, each only once
+fn locate<'a, T>(
+    tree: &'a RTree<T>,
+    envelopes: Vec<AABB<[f64; 2]>>,
+) -> impl Iterator<Item = &'a T> + 'a
+where
+    T: RTreeObject<Envelope = AABB<[f64; 2]>>,
+{
+    (0..envelopes.len()).flat_map(move |idx| {
+        let earlier = envelopes[..idx].to_vec();
+        tree.locate_in_envelope_intersecting(&envelopes[idx])
+            .filter(move |obj| !earlier.iter().any(|e| e.intersects(&obj.envelope())))
+    })
+}
+
+/// An R-tree of node locations. See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct NodeIndex {
+    tree: RTree<IndexedNode>,
+}
+
+impl NodeIndex {
+    /// Bulk load these node locations
+    pub fn new(nodes: impl IntoIterator<Item = (ObjId, (Lat, Lon))>) -> Self {
+        NodeIndex {
+            tree: RTree::bulk_load(
+                nodes
+                    .into_iter()
+                    .map(|(id, loc)| IndexedNode::new(point(loc), id))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Bulk load the nodes (with locations) from this reader
+    pub fn from_reader(reader: &mut impl OSMReader) -> Result<Self> {
+        let mut nodes = Vec::new();
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            if let Some(node) = obj.as_node() {
+                if let Some(loc) = node.lat_lon() {
+                    nodes.push(IndexedNode::new(point(loc), node.id()));
+                }
+            }
+        }
+        Ok(NodeIndex {
+            tree: RTree::bulk_load(nodes),
+        })
+    }
+
+    /// The underlying tree
+    pub fn tree(&self) -> &RTree<IndexedNode> {
+        &self.tree
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.tree.size()
+    }
+
+    /// True iff there are no nodes
+    pub fn is_empty(&self) -> bool {
+        self.tree.size() == 0
+    }
+
+    /// The ids of the nodes inside this bbox (including on its edge)
+    pub fn within_bbox<'a>(&'a self, bbox: &BBox) -> impl Iterator<Item = ObjId> + 'a {
+        locate(&self.tree, bbox_envelopes(bbox)).map(|node| node.data)
+    }
+
+    /// The ids of the nodes within `radius` metres (great circle distance) of `centre`
+    pub fn within_radius<'a>(
+        &'a self,
+        centre: (Lat, Lon),
+        radius: f64,
+    ) -> impl Iterator<Item = ObjId> + 'a {
+        let centre = point(centre);
+        locate(&self.tree, radius_envelopes(centre, radius))
+            .filter(move |node| distance_lon_lat(centre, *node.geom()) <= radius)
+            .map(|node| node.data)
+    }
+}
+
+/// An R-tree of way bboxes. See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct WayIndex {
+    tree: RTree<IndexedWay>,
+}
+
+impl WayIndex {
+    /// Bulk load these way bboxes. Bboxes which cross the antimeridian aren't supported: they're
+    /// treated as covering every longitude between their edges.
+    pub fn new(ways: impl IntoIterator<Item = (ObjId, BBox)>) -> Self {
+        WayIndex {
+            tree: RTree::bulk_load(
+                ways.into_iter()
+                    .map(|(id, bbox)| {
+                        let corners = Rectangle::from_corners(
+                            [bbox.min_lon.degrees(), bbox.min_lat.degrees()],
+                            [bbox.max_lon.degrees(), bbox.max_lat.degrees()],
+                        );
+                        IndexedWay::new(corners, id)
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Bulk load the bboxes of these ways, with the node locations in `locations`. Ways with no
+    /// known node locations are skipped.
+    pub fn from_ways<'a, W: Way + 'a>(
+        ways: impl IntoIterator<Item = &'a W>,
+        locations: &impl NodeLocations,
+    ) -> Self {
+        Self::new(
+            ways.into_iter()
+                .filter_map(|way| Some((way.id(), BBox::of_way(way, locations)?))),
+        )
+    }
+
+    /// The underlying tree
+    pub fn tree(&self) -> &RTree<IndexedWay> {
+        &self.tree
+    }
+
+    /// Number of ways
+    pub fn len(&self) -> usize {
+        self.tree.size()
+    }
+
+    /// True iff there are no ways
+    pub fn is_empty(&self) -> bool {
+        self.tree.size() == 0
+    }
+
+    /// The ids of the ways whose bbox intersects this bbox
+    pub fn within_bbox<'a>(&'a self, bbox: &BBox) -> impl Iterator<Item = ObjId> + 'a {
+        locate(&self.tree, bbox_envelopes(bbox)).map(|way| way.data)
+    }
+
+    /// The ids of the ways whose bbox comes within `radius` metres of `centre`. The way itself
+    /// may be further away.
+    pub fn within_radius<'a>(
+        &'a self,
+        centre: (Lat, Lon),
+        radius: f64,
+    ) -> impl Iterator<Item = ObjId> + 'a {
+        let centre = point(centre);
+        locate(&self.tree, radius_envelopes(centre, radius))
+            .filter(move |way| {
+                let envelope = way.envelope();
+                let closest = [
+                    centre[0].clamp(envelope.lower()[0], envelope.upper()[0]),
+                    centre[1].clamp(envelope.lower()[1], envelope.upper()[1]),
+                ];
+                distance_lon_lat(centre, closest) <= radius
+            })
+            .map(|way| way.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use node_locations::SparseNodeLocations;
+    use obj_types::StringWayBuilder;
+    use std::convert::TryFrom;
+    use xml::XMLReader;
+
+    fn ll(lat: f64, lon: f64) -> (Lat, Lon) {
+        (Lat::try_from(lat).unwrap(), Lon::try_from(lon).unwrap())
+    }
+
+    fn sorted(ids: impl Iterator<Item = ObjId>) -> Vec<ObjId> {
+        let mut ids: Vec<_> = ids.collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn nodes() {
+        let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="0" lon="0"/>
+<node id="2" version="1" lat="0" lon="0.001"/>
+<node id="3" version="1" lat="0" lon="0.01"/>
+<node id="4" version="1" lat="10" lon="179.9999"/>
+<node id="5" version="1" lat="10" lon="-179.9999"/>
+<way id="1" version="1"><nd ref="1"/><nd ref="2"/></way>
+</osm>"#;
+        let index = NodeIndex::from_reader(&mut XMLReader::new(input.as_bytes())).unwrap();
+        assert_eq!(index.len(), 5);
+
+        // 0.001° of longitude at the equator is 111m
+        assert_eq!(sorted(index.within_radius(ll(0., 0.), 150.)), vec![1, 2]);
+        assert_eq!(sorted(index.within_radius(ll(0., 0.), 100.)), vec![1]);
+        assert_eq!(
+            sorted(index.within_radius(ll(0., 0.), 2_000.)),
+            vec![1, 2, 3]
+        );
+        // Across the antimeridian
+        assert_eq!(sorted(index.within_radius(ll(10., 180.), 100.)), vec![4, 5]);
+
+        let bbox: BBox = "-0.0001,-1,0.005,1".parse().unwrap();
+        assert_eq!(sorted(index.within_bbox(&bbox)), vec![1, 2]);
+        let bbox: BBox = "179,9,-179,11".parse().unwrap();
+        assert_eq!(sorted(index.within_bbox(&bbox)), vec![4, 5]);
+    }
+
+    #[test]
+    fn ways() {
+        let mut locations = SparseNodeLocations::new();
+        locations.set(1, ll(0., 0.));
+        locations.set(2, ll(0., 1.));
+        locations.set(3, ll(5., 5.));
+        locations.set(4, ll(6., 6.));
+        let way = |id, nodes| {
+            StringWayBuilder::default()
+                ._id(id)
+                ._nodes(nodes)
+                .build()
+                .unwrap()
+        };
+        let ways = [way(1, vec![1, 2]), way(2, vec![3, 4]), way(3, vec![99])];
+        let index = WayIndex::from_ways(ways.iter(), &locations);
+        assert_eq!(index.len(), 2);
+
+        assert_eq!(
+            sorted(index.within_bbox(&"0.5,-1,5.5,5.5".parse().unwrap())),
+            vec![1, 2]
+        );
+        assert_eq!(
+            sorted(index.within_bbox(&"2,2,3,3".parse().unwrap())),
+            Vec::<ObjId>::new()
+        );
+        // The bbox of way 1 is 111m south of this
+        assert_eq!(sorted(index.within_radius(ll(0.001, 0.5), 150.)), vec![1]);
+        assert!(index.within_radius(ll(0.001, 0.5), 100.).next().is_none());
+    }
+}
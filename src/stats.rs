@@ -0,0 +1,269 @@
+//! Summary statistics about a file, like `osmium fileinfo --extended`.
+//!
+//! Everything is calculated in one pass. The report can be serialised (e.g. to JSON).
+//!
+//! ```no_run
+//! use osmio::stats::file_stats;
+//!
+//! let mut reader = osmio::read_pbf("extract.osm.pbf")?;
+//! let stats = file_stats(&mut reader)?;
+//! println!("{} nodes, sorted: {}", stats.nodes.count, stats.sorted);
+//! println!("{}", serde_json::to_string_pretty(&stats)?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use {BBox, Error, Node, OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, TimestampFormat};
+
+/// Statistics about one object type
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeStats {
+    /// Number of objects (including deleted objects & all versions)
+    pub count: u64,
+    /// Number of deleted objects
+    pub deleted: u64,
+    pub min_id: Option<ObjId>,
+    pub max_id: Option<ObjId>,
+    /// Total number of tags on all the objects
+    pub num_tags: u64,
+    /// Number of objects with more than one tag with the same key. Readers keep duplicate tags
+    /// by default, see [`OSMReader::set_duplicate_tags`].
+    #[serde(default)]
+    pub duplicate_tags: u64,
+}
+
+impl TypeStats {
+    pub(crate) fn add(&mut self, obj: &impl OSMObjBase) {
+        self.count += 1;
+        if obj.deleted() {
+            self.deleted += 1;
+        }
+        let id = obj.id();
+        self.min_id = Some(self.min_id.map_or(id, |m| m.min(id)));
+        self.max_id = Some(self.max_id.map_or(id, |m| m.max(id)));
+        let num_tags = obj.num_tags();
+        self.num_tags += num_tags as u64;
+        if num_tags > 1 {
+            // Objects have few tags, so sorting is cheaper than hashing
+            let mut keys: Vec<&str> = obj.tags().map(|(k, _)| k).collect();
+            keys.sort_unstable();
+            if keys.windows(2).any(|w| w[0] == w[1]) {
+                self.duplicate_tags += 1;
+            }
+        }
+    }
+}
+
+/// Statistics about a file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStats {
+    pub nodes: TypeStats,
+    pub ways: TypeStats,
+    pub relations: TypeStats,
+    pub min_timestamp: Option<TimestampFormat>,
+    pub max_timestamp: Option<TimestampFormat>,
+    /// Bounding box of all the nodes with a location
+    pub bbox: Option<BBox>,
+    /// Number of different user ids
+    pub num_users: u64,
+    /// Number of different changesets
+    pub num_changesets: u64,
+    /// True iff the objects are in the standard order (nodes, then ways, then relations, each
+    /// sorted by id, then version)
+    pub sorted: bool,
+}
+
+impl FileStats {
+    /// Total number of objects
+    pub fn count(&self) -> u64 {
+        self.nodes.count + self.ways.count + self.relations.count
+    }
+
+    /// Statistics for this object type
+    pub fn of_type(&self, object_type: OSMObjectType) -> &TypeStats {
+        match object_type {
+            OSMObjectType::Node => &self.nodes,
+            OSMObjectType::Way => &self.ways,
+            OSMObjectType::Relation => &self.relations,
+        }
+    }
+}
+
+/// Calculates [`FileStats`], one object at a time
+#[derive(Debug)]
+pub struct StatsCollector {
+    stats: FileStats,
+    users: HashSet<u32>,
+    changesets: HashSet<u32>,
+    last_key: Option<(OSMObjectType, ObjId, Option<u32>)>,
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StatsCollector {
+    pub fn new() -> Self {
+        StatsCollector {
+            stats: FileStats {
+                sorted: true,
+                ..Default::default()
+            },
+            users: HashSet::new(),
+            changesets: HashSet::new(),
+            last_key: None,
+        }
+    }
+
+    /// Include this object in the statistics
+    pub fn add(&mut self, obj: &impl OSMObj) {
+        let key = (obj.object_type(), obj.id(), obj.version());
+        if self.last_key.is_some_and(|last_key| key < last_key) {
+            self.stats.sorted = false;
+        }
+        self.last_key = Some(key);
+
+        if let Some(timestamp) = obj.timestamp() {
+            if self
+                .stats
+                .min_timestamp
+                .as_ref()
+                .map_or(true, |min| timestamp < min)
+            {
+                self.stats.min_timestamp = Some(timestamp.clone());
+            }
+            if self
+                .stats
+                .max_timestamp
+                .as_ref()
+                .map_or(true, |max| timestamp > max)
+            {
+                self.stats.max_timestamp = Some(timestamp.clone());
+            }
+        }
+        if let Some(uid) = obj.uid() {
+            self.users.insert(uid);
+        }
+        if let Some(changeset_id) = obj.changeset_id() {
+            self.changesets.insert(changeset_id);
+        }
+
+        match obj.object_type() {
+            OSMObjectType::Node => self.stats.nodes.add(obj),
+            OSMObjectType::Way => self.stats.ways.add(obj),
+            OSMObjectType::Relation => self.stats.relations.add(obj),
+        }
+
+        if let Some(loc) = obj.as_node().and_then(|n| n.lat_lon()) {
+            match &mut self.stats.bbox {
+                None => self.stats.bbox = Some(BBox::from_point(loc)),
+                Some(bbox) => bbox.expand(loc),
+            }
+        }
+    }
+
+    /// Finish, and return the statistics
+    pub fn finish(mut self) -> FileStats {
+        self.stats.num_users = self.users.len() as u64;
+        self.stats.num_changesets = self.changesets.len() as u64;
+        self.stats
+    }
+}
+
+/// Calculate the statistics for every object in this reader. Returns an error if the file is
+/// invalid.
+pub fn file_stats(reader: &mut impl OSMReader) -> Result<FileStats, Error> {
+    let mut collector = StatsCollector::new();
+    for obj in reader.try_objects() {
+        collector.add(&obj?);
+    }
+    Ok(collector.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+    use xml::XMLReader;
+    use {Lat, Lon};
+
+    fn stats(input: &str) -> FileStats {
+        file_stats(&mut XMLReader::new(Cursor::new(input))).unwrap()
+    }
+
+    #[test]
+    fn simple() {
+        let stats = stats(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" uid="5" changeset="10" timestamp="2020-01-01T00:00:00Z" lat="1" lon="3"/>
+<node id="7" version="2" uid="6" changeset="11" timestamp="2019-01-01T00:00:00Z" lat="-2" lon="4"><tag k="a" v="b"/></node>
+<way id="3" version="1" uid="5" changeset="10" timestamp="2021-06-01T00:00:00Z"><nd ref="1"/><nd ref="7"/><tag k="highway" v="primary"/><tag k="name" v="x"/></way>
+</osm>"#,
+        );
+        assert_eq!(stats.count(), 3);
+        assert_eq!(stats.nodes.count, 2);
+        assert_eq!(stats.nodes.min_id, Some(1));
+        assert_eq!(stats.nodes.max_id, Some(7));
+        assert_eq!(stats.nodes.num_tags, 1);
+        assert_eq!(stats.ways.num_tags, 2);
+        assert_eq!(stats.relations, TypeStats::default());
+        assert_eq!(stats.num_users, 2);
+        assert_eq!(stats.num_changesets, 2);
+        assert_eq!(
+            stats.min_timestamp.unwrap().to_iso_string(),
+            "2019-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            stats.max_timestamp.unwrap().to_iso_string(),
+            "2021-06-01T00:00:00Z"
+        );
+        assert_eq!(
+            stats.bbox,
+            Some(BBox::new(
+                Lat::try_from(-2.).unwrap(),
+                Lon::try_from(3.).unwrap(),
+                Lat::try_from(1.).unwrap(),
+                Lon::try_from(4.).unwrap()
+            ))
+        );
+        assert!(stats.sorted);
+    }
+
+    #[test]
+    fn unsorted() {
+        let stats = stats(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<way id="3"><nd ref="1"/><nd ref="7"/></way>
+<node id="1" lat="1" lon="3"/>
+</osm>"#,
+        );
+        assert!(!stats.sorted);
+        assert_eq!(stats.min_timestamp, None);
+        assert_eq!(stats.num_users, 0);
+    }
+
+    #[test]
+    fn duplicate_tags() {
+        let stats = stats(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="1" lon="3"><tag k="name" v="a"/><tag k="amenity" v="pub"/></node>
+<way id="3"><nd ref="1"/><tag k="name" v="a"/><tag k="highway" v="primary"/><tag k="name" v="b"/></way>
+</osm>"#,
+        );
+        assert_eq!(stats.nodes.duplicate_tags, 0);
+        assert_eq!(stats.ways.duplicate_tags, 1);
+        assert_eq!(stats.ways.num_tags, 3);
+    }
+
+    #[test]
+    fn invalid() {
+        let input = r#"<osm version="0.6"><node id="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        assert!(file_stats(&mut XMLReader::new(Cursor::new(input))).is_err());
+    }
+}
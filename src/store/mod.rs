@@ -0,0 +1,389 @@
+//! Storing whole objects, so they can be looked up by id.
+//!
+//! Many tasks (e.g. following relation members, or building the geometry of a way) need random
+//! access to objects. For a medium sized extract (a city, or a small country) it's simplest to
+//! load everything into a [`MemoryStore`] first.
+//!
+//! ```rust
+//! use osmio::store::{MemoryStore, ObjectStore};
+//! use osmio::xml::XMLReader;
+//! use osmio::{OSMObjBase, OSMReader, Way};
+//!
+//! let input = r#"<osm version="0.6"><node id="1" lat="1" lon="2"><tag k="name" v="A"/></node><way id="2"><nd ref="1"/></way></osm>"#;
+//! let mut store = MemoryStore::new();
+//! store.add_reader(&mut XMLReader::new(input.as_bytes()))?;
+//! assert_eq!(store.get_node(1)?.unwrap().tag("name"), Some("A"));
+//! assert_eq!(store.get_way(2)?.unwrap().nodes(), &[1]);
+//! assert!(store.get_relation(3)?.is_none());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! For larger files, [`SqliteStore`] (with the `sqlite` feature) keeps them in a database on
+//! disk.
+use obj_types::{PackedNodes, StringNode, StringOSMObj, StringRelation, StringWay};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use {
+    Lat, Lon, Node, OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, Relation, TimestampFormat,
+    Way,
+};
+
+use anyhow::Result;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+
+/// Something which stores objects, and can look them up by type & id.
+///
+/// Only one version of each object is stored: adding an object replaces any object with the same
+/// type & id.
+///
+/// Every method returns an error if the storage can't be read or written (which never happens
+/// for a [`MemoryStore`]).
+pub trait ObjectStore {
+    /// Store this object, replacing any previous one
+    fn insert(&mut self, obj: &impl OSMObj) -> Result<()>;
+
+    fn get_node(&self, id: ObjId) -> Result<Option<StringNode>>;
+    fn get_way(&self, id: ObjId) -> Result<Option<StringWay>>;
+    fn get_relation(&self, id: ObjId) -> Result<Option<StringRelation>>;
+
+    /// All the objects, in the usual order: nodes, then ways, then relations, each by id
+    fn objects(&self) -> Box<dyn Iterator<Item = Result<StringOSMObj>> + '_>;
+
+    /// Number of objects stored
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// The object of this type & id
+    fn get(&self, object_type: OSMObjectType, id: ObjId) -> Result<Option<StringOSMObj>> {
+        Ok(match object_type {
+            OSMObjectType::Node => self.get_node(id)?.map(StringOSMObj::Node),
+            OSMObjectType::Way => self.get_way(id)?.map(StringOSMObj::Way),
+            OSMObjectType::Relation => self.get_relation(id)?.map(StringOSMObj::Relation),
+        })
+    }
+
+    /// True iff there is an object of this type & id
+    fn contains(&self, object_type: OSMObjectType, id: ObjId) -> Result<bool> {
+        Ok(self.get(object_type, id)?.is_some())
+    }
+
+    /// Store all the objects from this reader. Returns an error if the file is invalid, or the
+    /// objects can't be stored.
+    fn add_reader(&mut self, reader: &mut impl OSMReader) -> Result<()>
+    where
+        Self: Sized,
+    {
+        for obj in reader.try_objects() {
+            self.insert(&obj?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every distinct string once, referred to by index
+#[derive(Debug, Default, Clone)]
+struct Interner {
+    strings: Vec<Arc<str>>,
+    index: HashMap<Arc<str>, u32>,
+}
+
+impl Interner {
+    fn intern(&mut self, s: &str) -> u32 {
+        if let Some(&idx) = self.index.get(s) {
+            return idx;
+        }
+        let idx = self.strings.len() as u32;
+        let s: Arc<str> = Arc::from(s);
+        self.strings.push(s.clone());
+        self.index.insert(s, idx);
+        idx
+    }
+
+    fn get(&self, idx: u32) -> &str {
+        &self.strings[idx as usize]
+    }
+}
+
+/// Used for users which aren't set
+const NONE: u32 = u32::MAX;
+
+/// The metadata & tags of a stored object
+#[derive(Debug, Clone)]
+struct Base {
+    version: Option<u32>,
+    deleted: bool,
+    changeset_id: Option<u32>,
+    /// Milliseconds since the epoch
+    timestamp: Option<i64>,
+    uid: Option<u32>,
+    user: u32,
+    tags: Box<[(u32, u32)]>,
+}
+
+#[derive(Debug, Clone)]
+struct StoredRelation {
+    base: Base,
+    members: Box<[(OSMObjectType, ObjId, u32)]>,
+}
+
+/// Stores objects in memory.
+///
+/// To save memory, every string (tag keys & values, users & roles) is stored once, and way node
+/// ids are delta encoded (see [`PackedNodes`]). Objects are converted back to
+/// [`StringOSMObj`]s when they're looked up. Timestamps are stored as epoch milliseconds, so
+/// invalid ISO timestamps are dropped.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    strings: Interner,
+    nodes: BTreeMap<ObjId, (Base, Option<(Lat, Lon)>)>,
+    ways: BTreeMap<ObjId, (Base, PackedNodes)>,
+    relations: BTreeMap<ObjId, StoredRelation>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of distinct strings stored
+    pub fn num_strings(&self) -> usize {
+        self.strings.strings.len()
+    }
+
+    fn base(&mut self, obj: &impl OSMObjBase) -> Base {
+        let strings = &mut self.strings;
+        Base {
+            version: obj.version(),
+            deleted: obj.deleted(),
+            changeset_id: obj.changeset_id(),
+            timestamp: obj
+                .timestamp()
+                .as_ref()
+                .and_then(|t| t.try_to_epoch_millis().ok()),
+            uid: obj.uid(),
+            user: obj.user().map_or(NONE, |u| strings.intern(u)),
+            tags: obj
+                .tags()
+                .map(|(k, v)| (strings.intern(k), strings.intern(v)))
+                .collect(),
+        }
+    }
+
+    fn timestamp(millis: Option<i64>) -> Option<TimestampFormat> {
+        millis.map(|t| {
+            if t % 1000 == 0 {
+                TimestampFormat::EpochNunber(t / 1000)
+            } else {
+                TimestampFormat::EpochMillis(t)
+            }
+        })
+    }
+
+    fn user(&self, base: &Base) -> Option<String> {
+        if base.user == NONE {
+            None
+        } else {
+            Some(self.strings.get(base.user).to_string())
+        }
+    }
+
+    fn tags(&self, base: &Base) -> Vec<(String, String)> {
+        base.tags
+            .iter()
+            .map(|&(k, v)| {
+                (
+                    self.strings.get(k).to_string(),
+                    self.strings.get(v).to_string(),
+                )
+            })
+            .collect()
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn insert(&mut self, obj: &impl OSMObj) -> Result<()> {
+        let base = self.base(obj);
+        if let Some(node) = obj.as_node() {
+            self.nodes.insert(node.id(), (base, node.lat_lon()));
+        } else if let Some(way) = obj.as_way() {
+            self.ways
+                .insert(way.id(), (base, PackedNodes::from(way.nodes())));
+        } else if let Some(relation) = obj.as_relation() {
+            let strings = &mut self.strings;
+            let members = relation
+                .members()
+                .map(|(t, id, role)| (t, id, strings.intern(role)))
+                .collect();
+            self.relations
+                .insert(relation.id(), StoredRelation { base, members });
+        }
+        Ok(())
+    }
+
+    fn get_node(&self, id: ObjId) -> Result<Option<StringNode>> {
+        let (base, lat_lon) = match self.nodes.get(&id) {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        let tags = self.tags(base);
+        Ok(Some(StringNode {
+            _id: id,
+            _version: base.version,
+            _deleted: base.deleted,
+            _changeset_id: base.changeset_id,
+            _timestamp: Self::timestamp(base.timestamp),
+            _uid: base.uid,
+            _user: self.user(base),
+            _tags: if tags.is_empty() { None } else { Some(tags) },
+            _lat_lon: *lat_lon,
+        }))
+    }
+
+    fn get_way(&self, id: ObjId) -> Result<Option<StringWay>> {
+        let (base, nodes) = match self.ways.get(&id) {
+            Some(way) => way,
+            None => return Ok(None),
+        };
+        Ok(Some(StringWay {
+            _id: id,
+            _version: base.version,
+            _deleted: base.deleted,
+            _changeset_id: base.changeset_id,
+            _timestamp: Self::timestamp(base.timestamp),
+            _uid: base.uid,
+            _user: self.user(base),
+            _tags: self.tags(base),
+            _nodes: nodes.to_vec(),
+        }))
+    }
+
+    fn get_relation(&self, id: ObjId) -> Result<Option<StringRelation>> {
+        let relation = match self.relations.get(&id) {
+            Some(relation) => relation,
+            None => return Ok(None),
+        };
+        let base = &relation.base;
+        Ok(Some(StringRelation {
+            _id: id,
+            _version: base.version,
+            _deleted: base.deleted,
+            _changeset_id: base.changeset_id,
+            _timestamp: Self::timestamp(base.timestamp),
+            _uid: base.uid,
+            _user: self.user(base),
+            _tags: self.tags(base),
+            _members: relation
+                .members
+                .iter()
+                .map(|&(t, id, role)| (t, id, self.strings.get(role).to_string()))
+                .collect(),
+        }))
+    }
+
+    fn objects(&self) -> Box<dyn Iterator<Item = Result<StringOSMObj>> + '_> {
+        let nodes = self
+            .nodes
+            .keys()
+            .filter_map(move |&id| self.get_node(id).transpose());
+        let ways = self
+            .ways
+            .keys()
+            .filter_map(move |&id| self.get_way(id).transpose());
+        let relations = self
+            .relations
+            .keys()
+            .filter_map(move |&id| self.get_relation(id).transpose());
+        Box::new(
+            nodes
+                .map(|n| n.map(StringOSMObj::Node))
+                .chain(ways.map(|w| w.map(StringOSMObj::Way)))
+                .chain(relations.map(|r| r.map(StringOSMObj::Relation))),
+        )
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok((self.nodes.len() + self.ways.len() + self.relations.len()) as u64)
+    }
+
+    fn contains(&self, object_type: OSMObjectType, id: ObjId) -> Result<bool> {
+        Ok(match object_type {
+            OSMObjectType::Node => self.nodes.contains_key(&id),
+            OSMObjectType::Way => self.ways.contains_key(&id),
+            OSMObjectType::Relation => self.relations.contains_key(&id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xml::XMLReader;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<relation id="20" version="1"><member type="way" ref="10" role="outer"/><tag k="type" v="multipolygon"/></relation>
+<way id="10" version="3" user="alice" uid="7" changeset="5" timestamp="2020-01-01T00:00:00Z"><nd ref="2"/><nd ref="1"/><nd ref="2"/><tag k="name" v="alice"/></way>
+<node id="2" version="1" lat="1" lon="2" user="alice" uid="7"/>
+<node id="1" version="1" lat="-1.5" lon="2.25" timestamp="2020-01-01T00:00:00.5Z"><tag k="name" v="alice"/></node>
+</osm>"#;
+
+    #[test]
+    fn store() {
+        let mut store = MemoryStore::new();
+        store
+            .add_reader(&mut XMLReader::new(INPUT.as_bytes()))
+            .unwrap();
+        assert_eq!(store.len().unwrap(), 4);
+        // "alice", "name", "outer", "type" & "multipolygon"
+        assert_eq!(store.num_strings(), 5);
+
+        let node = store.get_node(1).unwrap().unwrap();
+        assert_eq!(node.lat_lon_f64(), Some((-1.5, 2.25)));
+        assert_eq!(node.tag("name"), Some("alice"));
+        assert_eq!(
+            node.timestamp().as_ref().unwrap().to_epoch_millis(),
+            1_577_836_800_500
+        );
+        assert_eq!(store.get_node(2).unwrap().unwrap().user(), Some("alice"));
+        assert!(store.get_node(2).unwrap().unwrap().untagged());
+
+        let way = store.get_way(10).unwrap().unwrap();
+        assert_eq!(way.nodes(), &[2, 1, 2]);
+        assert_eq!(way.version(), Some(3));
+        assert_eq!(way.changeset_id(), Some(5));
+        assert_eq!(way.uid(), Some(7));
+        assert_eq!(
+            way.timestamp(),
+            &Some("2020-01-01T00:00:00Z".parse().unwrap())
+        );
+
+        let relation = store.get_relation(20).unwrap().unwrap();
+        assert_eq!(
+            relation.members().collect::<Vec<_>>(),
+            vec![(OSMObjectType::Way, 10, "outer")]
+        );
+        assert!(store.contains(OSMObjectType::Relation, 20).unwrap());
+        assert!(store.get(OSMObjectType::Way, 20).unwrap().is_none());
+
+        let order: Vec<_> = store
+            .objects()
+            .map(|o| o.unwrap())
+            .map(|o| format!("{}{}", o.object_type().name_short(), o.id()))
+            .collect();
+        assert_eq!(order, vec!["n1", "n2", "w10", "r20"]);
+
+        // Replaces the old version
+        let mut node = store.get_node(2).unwrap().unwrap();
+        node.set_tag("name", "bob");
+        store.insert(&StringOSMObj::Node(node)).unwrap();
+        assert_eq!(store.len().unwrap(), 4);
+        assert_eq!(store.get_node(2).unwrap().unwrap().tag("name"), Some("bob"));
+    }
+}
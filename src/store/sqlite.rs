@@ -0,0 +1,397 @@
+//! An [`ObjectStore`] in an SQLite database, for data which doesn't fit in memory.
+use super::ObjectStore;
+use idset::IdSet;
+use obj_types::{StringNode, StringOSMObj, StringRelation, StringWay};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::VecDeque;
+use std::path::Path;
+use {read_pbf, Node, OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, Relation, Way};
+
+use anyhow::Result;
+
+/// How many objects [`SqliteStore::objects`] reads at a time
+const PAGE_SIZE: u32 = 1_000;
+
+fn type_code(object_type: OSMObjectType) -> i64 {
+    match object_type {
+        OSMObjectType::Node => 0,
+        OSMObjectType::Way => 1,
+        OSMObjectType::Relation => 2,
+    }
+}
+
+fn object_type(code: i64) -> Result<OSMObjectType> {
+    match code {
+        0 => Ok(OSMObjectType::Node),
+        1 => Ok(OSMObjectType::Way),
+        2 => Ok(OSMObjectType::Relation),
+        _ => anyhow::bail!("Unknown object type {} in database", code),
+    }
+}
+
+/// Stores objects in an SQLite database, so it can be used as a simple queryable OSM database.
+///
+/// Each object is stored (with bincode) in one row, keyed by type & id. Optionally, there's an
+/// index from tag key to the objects with that key, see [`SqliteStore::ids_with_key`].
+///
+/// ```no_run
+/// use osmio::store::{ObjectStore, SqliteStore};
+///
+/// let store = SqliteStore::from_pbf("planet.osm.pbf", "planet.sqlite", true)?;
+/// let bus_stop = store.get_node(123);
+/// let highways = store.ids_with_key("highway")?;
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+///
+/// Use [`SqliteStore::load`] to add many objects, which is much faster than
+/// [`ObjectStore::insert`]ing them one at a time.
+///
+/// SQLite (through `rusqlite`, already used for the `sqlite` feature) is used rather than an
+/// embedded key-value store like sled or LMDB: the database is one file which other tools (e.g.
+/// the `sqlite3` shell) can query, loading in one transaction is fast & atomic, and the tag index
+/// is just another table, kept consistent with the objects in the same transaction.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Connection,
+    tag_index: bool,
+}
+
+impl SqliteStore {
+    /// Open this database, creating it (and the tables) if needed. If `tag_index`, there's an
+    /// index of the tag keys. An existing database keeps the index it has.
+    pub fn create(path: impl AsRef<Path>, tag_index: bool) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?, tag_index)
+    }
+
+    /// Open an existing database
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        anyhow::ensure!(path.exists(), "{} doesn't exist", path.display());
+        Self::from_connection(Connection::open(path)?, false)
+    }
+
+    /// A store in memory, e.g. for tests
+    pub fn in_memory(tag_index: bool) -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?, tag_index)
+    }
+
+    fn from_connection(conn: Connection, tag_index: bool) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS objects (
+                type INTEGER NOT NULL,
+                id INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (type, id)
+            ) WITHOUT ROWID;",
+        )?;
+        if tag_index {
+            conn.execute_batch(
+                "CREATE TABLE IF NOT EXISTS tag_keys (
+                    key TEXT NOT NULL,
+                    type INTEGER NOT NULL,
+                    id INTEGER NOT NULL,
+                    PRIMARY KEY (key, type, id)
+                ) WITHOUT ROWID;
+                CREATE INDEX IF NOT EXISTS tag_keys_object ON tag_keys (type, id);",
+            )?;
+        }
+        let tag_index = conn
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'tag_keys'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        Ok(SqliteStore { conn, tag_index })
+    }
+
+    /// Create (or add to) the database at `db_path`, with all the objects from the PBF file at
+    /// `pbf_path`
+    pub fn from_pbf(
+        pbf_path: impl AsRef<Path>,
+        db_path: impl AsRef<Path>,
+        tag_index: bool,
+    ) -> Result<Self> {
+        let mut store = Self::create(db_path, tag_index)?;
+        store.load(&mut read_pbf(pbf_path)?)?;
+        Ok(store)
+    }
+
+    /// True iff there is an index of the tag keys
+    pub fn has_tag_index(&self) -> bool {
+        self.tag_index
+    }
+
+    /// Store all the objects from this reader, in one transaction. Returns how many there were.
+    pub fn load(&mut self, reader: &mut impl OSMReader) -> Result<u64> {
+        let tag_index = self.tag_index;
+        let txn = self.conn.transaction()?;
+        let mut count = 0;
+        for obj in reader.try_objects() {
+            insert(&txn, tag_index, &obj?)?;
+            count += 1;
+        }
+        txn.commit()?;
+        Ok(count)
+    }
+
+    /// The objects with a tag with this key. Only if there's a tag index, otherwise it's an
+    /// error.
+    pub fn ids_with_key(&self, key: &str) -> Result<IdSet> {
+        anyhow::ensure!(self.tag_index, "This store has no tag index");
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT type, id FROM tag_keys WHERE key = ?1")?;
+        let mut ids = IdSet::new();
+        for row in stmt.query_map(params![key], |row| Ok((row.get(0)?, row.get(1)?)))? {
+            let (code, id): (i64, ObjId) = row?;
+            ids.insert(object_type(code)?, id);
+        }
+        Ok(ids)
+    }
+
+    /// The next `PAGE_SIZE` objects after this type & id
+    fn page_after(&self, after: (i64, ObjId)) -> Result<Vec<StringOSMObj>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT data FROM objects WHERE type > ?1 OR (type = ?1 AND id > ?2) ORDER BY type, id LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(params![after.0, after.1, PAGE_SIZE], |row| {
+            row.get::<_, Vec<u8>>(0)
+        })?;
+        let mut objs = Vec::new();
+        for data in rows {
+            objs.push(bincode::deserialize(&data?)?);
+        }
+        Ok(objs)
+    }
+}
+
+/// A copy of this object
+fn to_string_obj(obj: &impl OSMObj) -> StringOSMObj {
+    let tags: Vec<(String, String)> = obj
+        .tags()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let user = obj.user().map(String::from);
+    if let Some(node) = obj.as_node() {
+        StringOSMObj::Node(StringNode {
+            _id: obj.id(),
+            _version: obj.version(),
+            _deleted: obj.deleted(),
+            _changeset_id: obj.changeset_id(),
+            _timestamp: obj.timestamp().clone(),
+            _uid: obj.uid(),
+            _user: user,
+            _tags: if tags.is_empty() { None } else { Some(tags) },
+            _lat_lon: node.lat_lon(),
+        })
+    } else if let Some(way) = obj.as_way() {
+        StringOSMObj::Way(StringWay {
+            _id: obj.id(),
+            _version: obj.version(),
+            _deleted: obj.deleted(),
+            _changeset_id: obj.changeset_id(),
+            _timestamp: obj.timestamp().clone(),
+            _uid: obj.uid(),
+            _user: user,
+            _tags: tags,
+            _nodes: way.nodes().to_vec(),
+        })
+    } else {
+        let members = obj
+            .as_relation()
+            .map(|r| {
+                r.members()
+                    .map(|(t, id, role)| (t, id, role.to_string()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        StringOSMObj::Relation(StringRelation {
+            _id: obj.id(),
+            _version: obj.version(),
+            _deleted: obj.deleted(),
+            _changeset_id: obj.changeset_id(),
+            _timestamp: obj.timestamp().clone(),
+            _uid: obj.uid(),
+            _user: user,
+            _tags: tags,
+            _members: members,
+        })
+    }
+}
+
+fn insert(conn: &Connection, tag_index: bool, obj: &impl OSMObj) -> Result<()> {
+    let (code, id) = (type_code(obj.object_type()), obj.id());
+    let data = bincode::serialize(&to_string_obj(obj))?;
+    conn.prepare_cached("INSERT OR REPLACE INTO objects (type, id, data) VALUES (?1, ?2, ?3)")?
+        .execute(params![code, id, data])?;
+    if tag_index {
+        conn.prepare_cached("DELETE FROM tag_keys WHERE type = ?1 AND id = ?2")?
+            .execute(params![code, id])?;
+        let mut stmt = conn
+            .prepare_cached("INSERT OR IGNORE INTO tag_keys (key, type, id) VALUES (?1, ?2, ?3)")?;
+        for (key, _) in obj.tags() {
+            stmt.execute(params![key, code, id])?;
+        }
+    }
+    Ok(())
+}
+
+impl ObjectStore for SqliteStore {
+    fn insert(&mut self, obj: &impl OSMObj) -> Result<()> {
+        insert(&self.conn, self.tag_index, obj)
+    }
+
+    fn get_node(&self, id: ObjId) -> Result<Option<StringNode>> {
+        Ok(self
+            .get(OSMObjectType::Node, id)?
+            .and_then(StringOSMObj::into_node))
+    }
+
+    fn get_way(&self, id: ObjId) -> Result<Option<StringWay>> {
+        Ok(self
+            .get(OSMObjectType::Way, id)?
+            .and_then(StringOSMObj::into_way))
+    }
+
+    fn get_relation(&self, id: ObjId) -> Result<Option<StringRelation>> {
+        Ok(self
+            .get(OSMObjectType::Relation, id)?
+            .and_then(StringOSMObj::into_relation))
+    }
+
+    fn get(&self, object_type: OSMObjectType, id: ObjId) -> Result<Option<StringOSMObj>> {
+        let data: Option<Vec<u8>> = self
+            .conn
+            .prepare_cached("SELECT data FROM objects WHERE type = ?1 AND id = ?2")?
+            .query_row(params![type_code(object_type), id], |row| row.get(0))
+            .optional()?;
+        Ok(match data {
+            Some(data) => Some(bincode::deserialize(&data)?),
+            None => None,
+        })
+    }
+
+    /// Objects are read a page at a time, so they don't all have to fit in memory. Iterating
+    /// stops after an error.
+    fn objects(&self) -> Box<dyn Iterator<Item = Result<StringOSMObj>> + '_> {
+        let mut after = (-1, 0);
+        let mut page = VecDeque::new();
+        let mut failed = false;
+        Box::new(std::iter::from_fn(move || {
+            if page.is_empty() && !failed {
+                match self.page_after(after) {
+                    Ok(objs) => page.extend(objs),
+                    Err(e) => {
+                        failed = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            let obj = page.pop_front()?;
+            after = (type_code(obj.object_type()), obj.id());
+            Some(Ok(obj))
+        }))
+    }
+
+    fn len(&self) -> Result<u64> {
+        let len = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM objects", [], |row| {
+                row.get::<_, i64>(0)
+            })?;
+        Ok(len as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xml::XMLReader;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="2"><tag k="highway" v="bus_stop"/></node>
+<node id="2" version="1" lat="1" lon="3"/>
+<way id="10" version="1"><nd ref="1"/><nd ref="2"/><tag k="highway" v="road"/></way>
+<relation id="20" version="1"><member type="way" ref="10" role=""/><tag k="type" v="route"/></relation>
+</osm>"#;
+
+    #[test]
+    fn store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("objects.sqlite");
+        {
+            let mut store = SqliteStore::create(&path, true).unwrap();
+            assert_eq!(
+                store.load(&mut XMLReader::new(INPUT.as_bytes())).unwrap(),
+                4
+            );
+        }
+
+        let mut store = SqliteStore::open(&path).unwrap();
+        assert!(store.has_tag_index());
+        assert_eq!(store.len().unwrap(), 4);
+        assert_eq!(
+            store.get_node(1).unwrap().unwrap().tag("highway"),
+            Some("bus_stop")
+        );
+        assert_eq!(store.get_way(10).unwrap().unwrap().nodes(), &[1, 2]);
+        assert!(store.get_way(1).unwrap().is_none());
+        let highways = store.ids_with_key("highway").unwrap();
+        assert!(highways.contains(OSMObjectType::Node, 1));
+        assert!(highways.contains(OSMObjectType::Way, 10));
+        assert_eq!(highways.len(), 2);
+
+        // Replacing an object updates the index
+        let mut node = store.get_node(1).unwrap().unwrap();
+        node.unset_tag("highway");
+        store.insert(&StringOSMObj::Node(node)).unwrap();
+        assert!(store.get_node(1).unwrap().unwrap().untagged());
+        assert_eq!(store.ids_with_key("highway").unwrap().len(), 1);
+
+        let order: Vec<_> = store
+            .objects()
+            .map(|o| o.unwrap())
+            .map(|o| format!("{}{}", o.object_type().name_short(), o.id()))
+            .collect();
+        assert_eq!(order, vec!["n1", "n2", "w10", "r20"]);
+
+        assert!(SqliteStore::in_memory(false)
+            .unwrap()
+            .ids_with_key("highway")
+            .is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let mut store = SqliteStore::in_memory(true).unwrap();
+        store
+            .add_reader(&mut XMLReader::new(INPUT.as_bytes()))
+            .unwrap();
+        store
+            .conn
+            .execute_batch(
+                "UPDATE objects SET data = x'ff' WHERE type = 1;
+                INSERT INTO tag_keys (key, type, id) VALUES ('highway', 7, 1);",
+            )
+            .unwrap();
+        assert!(store.get_way(10).is_err());
+        assert!(store.get_node(1).unwrap().is_some());
+        let err = store.ids_with_key("highway").unwrap_err();
+        assert!(err.to_string().contains("Unknown object type 7"), "{}", err);
+        // The first page has the broken way
+        let objs: Vec<_> = store.objects().collect();
+        assert_eq!(objs.len(), 1);
+        assert!(objs[0].is_err());
+
+        // An invalid file is an error, not a panic
+        let mut store = SqliteStore::in_memory(false).unwrap();
+        let input = r#"<osm version="0.6"><node id="1" lat="1" lon="2"/><node id="x"/></osm>"#;
+        assert!(store
+            .add_reader(&mut XMLReader::new(input.as_bytes()))
+            .is_err());
+        assert!(store.load(&mut XMLReader::new(input.as_bytes())).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Checking the structure of a `PrimitiveBlock` before quick-protobuf decodes it.
+//!
+//! quick-protobuf trusts the length of nested messages: a field which runs past the end of the
+//! message it's in can make it overflow (and panic). This checks that every field of every
+//! nested message ends inside that message, without decoding (or allocating) anything.
+use std::convert::TryFrom;
+
+/// How to check a length delimited field
+#[derive(Clone, Copy)]
+enum Field {
+    /// A message, with its own fields
+    Message(Schema),
+    /// Packed varints
+    Packed,
+    /// Bytes, a string, or an unknown field
+    Opaque,
+}
+
+/// The kind of each length delimited field (by number) of a message
+type Schema = fn(u64) -> Field;
+
+fn primitive_block(field: u64) -> Field {
+    match field {
+        1 => Field::Message(string_table),
+        2 => Field::Message(primitive_group),
+        _ => Field::Opaque,
+    }
+}
+
+fn string_table(_field: u64) -> Field {
+    Field::Opaque
+}
+
+fn primitive_group(field: u64) -> Field {
+    match field {
+        1 => Field::Message(node),
+        2 => Field::Message(dense_nodes),
+        3 | 4 => Field::Message(way_or_relation),
+        5 => Field::Message(info),
+        _ => Field::Opaque,
+    }
+}
+
+fn node(field: u64) -> Field {
+    match field {
+        2 | 3 => Field::Packed,
+        4 => Field::Message(info),
+        _ => Field::Opaque,
+    }
+}
+
+/// `Info` (and `ChangeSet`) only have scalar fields
+fn info(_field: u64) -> Field {
+    Field::Opaque
+}
+
+fn dense_nodes(field: u64) -> Field {
+    match field {
+        1 | 8 | 9 | 10 => Field::Packed,
+        5 => Field::Message(dense_info),
+        _ => Field::Opaque,
+    }
+}
+
+fn dense_info(field: u64) -> Field {
+    match field {
+        1..=6 => Field::Packed,
+        _ => Field::Opaque,
+    }
+}
+
+fn way_or_relation(field: u64) -> Field {
+    match field {
+        2 | 3 | 8 | 9 | 10 => Field::Packed,
+        4 => Field::Message(info),
+        _ => Field::Opaque,
+    }
+}
+
+/// Read a varint from `bytes` at `*pos`, or `None` if it runs past the end
+fn varint(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let b = *bytes.get(*pos)?;
+        *pos += 1;
+        value |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// True iff every field of this message (and of the messages in it) ends inside it
+fn check(bytes: &[u8], schema: Schema) -> bool {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let tag = match varint(bytes, &mut pos) {
+            Some(tag) => tag,
+            None => return false,
+        };
+        let len = match tag & 0x7 {
+            0 => match varint(bytes, &mut pos) {
+                Some(_) => continue,
+                None => return false,
+            },
+            1 => 8,
+            5 => 4,
+            2 => match varint(bytes, &mut pos) {
+                Some(len) => len,
+                None => return false,
+            },
+            // quick-protobuf returns an error for these
+            _ => return false,
+        };
+        let end = match usize::try_from(len)
+            .ok()
+            .and_then(|len| pos.checked_add(len))
+        {
+            Some(end) if end <= bytes.len() => end,
+            _ => return false,
+        };
+        if tag & 0x7 == 2 {
+            let field = &bytes[pos..end];
+            let valid = match schema(tag >> 3) {
+                Field::Message(schema) => check(field, schema),
+                Field::Packed => {
+                    let mut pos = 0;
+                    while pos < field.len() {
+                        if varint(field, &mut pos).is_none() {
+                            return false;
+                        }
+                    }
+                    true
+                }
+                Field::Opaque => true,
+            };
+            if !valid {
+                return false;
+            }
+        }
+        pos = end;
+    }
+    true
+}
+
+/// True iff quick-protobuf can safely decode this `PrimitiveBlock`, i.e. no field runs past the
+/// end of its message
+pub(super) fn primitive_block_is_valid(bytes: &[u8]) -> bool {
+    check(bytes, primitive_block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_lengths() {
+        // primitivegroup { ways { id: 1, refs: [1, 2] } }
+        let block = [0x12, 0x08, 0x1a, 0x06, 0x08, 0x01, 0x42, 0x02, 0x02, 0x04];
+        assert!(primitive_block_is_valid(&block));
+        // the refs are 3 bytes long, past the end of the way
+        let mut invalid = block;
+        invalid[7] = 0x03;
+        assert!(!primitive_block_is_valid(&invalid));
+        // the last ref doesn't end
+        let mut invalid = block;
+        invalid[9] = 0x84;
+        assert!(!primitive_block_is_valid(&invalid));
+        assert!(!primitive_block_is_valid(&block[..9]));
+    }
+}
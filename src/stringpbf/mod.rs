@@ -4,216 +4,441 @@
 use super::OSMReader;
 use super::ObjId;
 use super::TimestampFormat;
-use byteorder;
-use byteorder::ReadBytesExt;
 use quick_protobuf::{BytesReader, MessageRead};
 use std::collections::VecDeque;
 use std::io::{Cursor, Read};
 use std::iter::Iterator;
 
 use super::*;
-use crate::COORD_PRECISION_NANOS;
+use arcpbf::{check_blob_size, read_blob_header_size, MAX_BLOB_HEADER_SIZE, MAX_BLOB_SIZE};
+use utils::pbf_lat_lon;
 
 use flate2::read::ZlibDecoder;
 
-use obj_types::{StringNode, StringOSMObj, StringRelation, StringWay};
+use cancel::{self, CancellationToken};
+use obj_types::{StringNode, StringOSMObj, StringRelation, StringTable, StringWay};
+use parse_mode::{Anomaly, AnomalyKind, DuplicateTags, NonPositiveIds, ParseMode};
+use pbf::dense::{DenseArrays, DenseInfoArrays, DenseNodes};
 
 mod OSMPBF;
+mod check;
 mod fileformat;
 mod node_id_pos;
 pub use self::node_id_pos::PBFNodePositionReader;
 
 type ObjectFilter = (bool, bool, bool);
 
-fn blob_raw_data(blob: &mut fileformat::Blob, buf: &mut Vec<u8>) {
-    // TODO Shame this can't return a Option<&[u8]>, then I don't need blob to be mut. However I
-    // get lifetime errors with bytes not living long enough.
+/// Decompress `blob`, which is at `offset`, into `buf`
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", name = "decompress_blob", skip(blob, buf))
+)]
+fn blob_raw_data(blob: &fileformat::Blob, offset: u64, buf: &mut Vec<u8>) -> Result<(), Error> {
     buf.truncate(0);
-    let fileformat::Blob {
-        raw,
-        raw_size: _,
-        zlib_data,
-        lzma_data: _,
-    } = blob;
-    if let Some(raw) = raw {
-        buf.reserve(raw.len());
-        buf.copy_from_slice(raw);
-    } else if let Some(zlib_data) = zlib_data {
+    if let Some(raw) = &blob.raw {
+        buf.extend_from_slice(raw);
+    } else if let Some(zlib_data) = &blob.zlib_data {
         let cursor = Cursor::new(zlib_data);
-        ZlibDecoder::new(cursor).read_to_end(buf).unwrap();
+        ZlibDecoder::new(cursor)
+            .take(MAX_BLOB_SIZE + 1)
+            .read_to_end(buf)
+            .map_err(|source| Error::Decompression { offset, source })?;
+        check_blob_size(buf.len() as i64, MAX_BLOB_SIZE, offset, "uncompressed blob")?;
     }
+    Ok(())
 }
 
+/// Decode the `PrimitiveBlock` in the blob at `offset`
+fn primitive_block(bytes: &[u8], offset: u64) -> Result<OSMPBF::PrimitiveBlock<'_>, Error> {
+    if !check::primitive_block_is_valid(bytes) {
+        let err = quick_protobuf::Error::UnexpectedEndOfBuffer;
+        return Err(Error::protobuf(offset, err));
+    }
+    // The block isn't length prefixed, so it's read with `from_reader` (not
+    // `deserialize_from_slice`)
+    let mut reader = BytesReader::from_bytes(bytes);
+    OSMPBF::PrimitiveBlock::from_reader(&mut reader, bytes).map_err(|e| Error::protobuf(offset, e))
+}
+
+/// Read the next `OSMData` blob from `reader`, which is at `*offset` (and is advanced past the
+/// blob), and decompress it into `blob_raw_bytes`. Returns the offset of the blob, or `None` at
+/// the end of the file. If `header` is given, and is still `None`, an `OSMHeader` blob on the way
+/// is decoded into it.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", name = "read_blob", skip_all, fields(offset = *offset))
+)]
+fn read_osmdata_blob(
+    reader: &mut impl Read,
+    offset: &mut u64,
+    blob_bytes: &mut Vec<u8>,
+    blob_raw_bytes: &mut Vec<u8>,
+    header: Option<&mut Option<header::FileInfo>>,
+) -> Result<Option<u64>, Error> {
+    let mut header = header;
+    loop {
+        let blob_offset = *offset;
+        let size = match read_blob_header_size(reader, blob_offset)? {
+            Some(size) => size,
+            None => return Ok(None),
+        };
+        let size = check_blob_size(
+            size.into(),
+            MAX_BLOB_HEADER_SIZE,
+            blob_offset,
+            "blob header",
+        )?;
+        let mut header_bytes_vec = vec![0; size];
+        reader
+            .read_exact(header_bytes_vec.as_mut_slice())
+            .map_err(|e| Error::reading(e, blob_offset, "blob header"))?;
+
+        let mut bytes_reader = BytesReader::from_bytes(&header_bytes_vec);
+        let blob_header = fileformat::BlobHeader::from_reader(&mut bytes_reader, &header_bytes_vec)
+            .map_err(|e| Error::protobuf(blob_offset, e))?;
+
+        let datasize = blob_header.datasize.into();
+        blob_bytes.resize(
+            check_blob_size(datasize, MAX_BLOB_SIZE, blob_offset, "blob")?,
+            0,
+        );
+        reader
+            .read_exact(blob_bytes.as_mut_slice())
+            .map_err(|e| Error::reading(e, blob_offset, "blob"))?;
+        *offset += 4 + header_bytes_vec.len() as u64 + blob_bytes.len() as u64;
+
+        if blob_header.type_pb == "OSMHeader" {
+            if let Some(header) = header.as_mut().filter(|h| h.is_none()) {
+                **header = Some(arcpbf::parse_header_blob(blob_bytes, blob_offset)?);
+            }
+        }
+        if blob_header.type_pb != "OSMData" {
+            // keep going to the next blob
+            continue;
+        }
+
+        let mut bytes_reader = BytesReader::from_bytes(blob_bytes);
+        let blob = fileformat::Blob::from_reader(&mut bytes_reader, blob_bytes)
+            .map_err(|e| Error::protobuf(blob_offset, e))?;
+        blob_raw_data(&blob, blob_offset, blob_raw_bytes)?;
+        return Ok(Some(blob_offset));
+    }
+}
+
+/// Reports the anomalies in one block
+struct Anomalies<'a> {
+    mode: &'a ParseMode,
+    /// Offset of the blob
+    offset: u64,
+    duplicate_tags: DuplicateTags,
+    non_positive_ids: NonPositiveIds,
+}
+
+impl Anomalies<'_> {
+    /// Check the ids of this object, and the objects it refers to, are allowed
+    fn ids(
+        &self,
+        object_type: OSMObjectType,
+        id: ObjId,
+        refs: impl Iterator<Item = ObjId>,
+    ) -> Result<(), Error> {
+        self.non_positive_ids
+            .check(object_type, id, refs, Some(self.offset))
+    }
+
+    fn report(
+        &self,
+        kind: AnomalyKind,
+        object_type: OSMObjectType,
+        id: ObjId,
+        detail: String,
+    ) -> Result<(), Error> {
+        self.mode.report(Anomaly {
+            kind,
+            offset: Some(self.offset),
+            object_type,
+            id,
+            detail,
+        })
+    }
+
+    /// This timestamp, or `None` (after reporting it) if it's out of range
+    fn timestamp(
+        &self,
+        timestamp: Result<TimestampFormat, ParseTimestampError>,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Option<TimestampFormat>, Error> {
+        match timestamp {
+            Ok(timestamp) => Ok(Some(timestamp)),
+            Err(e) => {
+                self.report(
+                    AnomalyKind::InvalidTimestamp,
+                    object_type,
+                    id,
+                    e.to_string(),
+                )?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// The string at `idx`, or `None` (after reporting it) if there isn't a valid one
+    fn string(
+        &self,
+        strings: &StringTable,
+        idx: i64,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Option<String>, Error> {
+        let (idx, anomaly) = strings.check(idx);
+        if let Some((kind, detail)) = anomaly {
+            self.report(kind, object_type, id, detail)?;
+        }
+        Ok(idx.and_then(|idx| strings.get(idx)).map(str::to_string))
+    }
+
+    /// The tags with these key & value indexes. Invalid ones are left out, and the duplicate
+    /// tags policy is applied.
+    fn tags(
+        &self,
+        strings: &StringTable,
+        keys_vals: impl Iterator<Item = (i64, i64)>,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let mut tags = Vec::new();
+        for (k, v) in keys_vals {
+            let k = self.string(strings, k, object_type, id)?;
+            let v = self.string(strings, v, object_type, id)?;
+            if let (Some(k), Some(v)) = (k, v) {
+                tags.push((k, v));
+            }
+        }
+        let keys = tags.iter().map(|(k, _)| k.as_str());
+        if let Some(duplicates) = self.duplicate_tags.find(keys) {
+            let anomaly = Anomaly {
+                kind: AnomalyKind::DuplicateTag,
+                offset: Some(self.offset),
+                object_type,
+                id,
+                detail: format!("key {:?}", duplicates.key),
+            };
+            self.duplicate_tags.report(anomaly, self.mode)?;
+            duplicates.remove(&mut tags);
+        }
+        Ok(tags)
+    }
+
+    /// The metadata from this `Info`, or none (after reporting it) if there isn't one
+    fn metadata(
+        &self,
+        info: Option<&OSMPBF::Info>,
+        date_granularity: i32,
+        strings: &StringTable,
+        object_type: OSMObjectType,
+        id: ObjId,
+    ) -> Result<Metadata, Error> {
+        let info = match info {
+            Some(info) => info,
+            None => {
+                self.report(
+                    AnomalyKind::MissingInfo,
+                    object_type,
+                    id,
+                    "no Info".to_string(),
+                )?;
+                return Ok(Metadata::default());
+            }
+        };
+        let user = match info.user_sid {
+            Some(user_sid) => self.string(strings, user_sid.into(), object_type, id)?,
+            None => None,
+        };
+        Ok(Metadata {
+            deleted: !info.visible.unwrap_or(true),
+            changeset_id: info.changeset.map(|c| c as u32),
+            uid: info.uid.map(|u| u as u32),
+            user,
+            version: Some(info.version as u32),
+            timestamp: match info.timestamp {
+                Some(t) => self.timestamp(
+                    TimestampFormat::from_date_granularity(t, date_granularity),
+                    object_type,
+                    id,
+                )?,
+                None => None,
+            },
+        })
+    }
+}
+
+/// The metadata of a way or relation
+#[derive(Default)]
+struct Metadata {
+    deleted: bool,
+    changeset_id: Option<u32>,
+    uid: Option<u32>,
+    user: Option<String>,
+    version: Option<u32>,
+    timestamp: Option<TimestampFormat>,
+}
+
+#[allow(clippy::too_many_arguments)]
 fn decode_nodes(
-    _primitive_group: OSMPBF::PrimitiveGroup,
-    _granularity: i32,
-    _lat_offset: i64,
-    _lon_offset: i64,
-    _date_granularity: i32,
-    _stringtable: &[Option<String>],
-    _sink: &mut VecDeque<StringOSMObj>,
-) -> usize {
-    unimplemented!("Dense node");
+    primitive_group: OSMPBF::PrimitiveGroup,
+    granularity: i32,
+    lat_offset: i64,
+    lon_offset: i64,
+    date_granularity: i32,
+    stringtable: &StringTable,
+    anomalies: &Anomalies,
+    results: &mut VecDeque<StringOSMObj>,
+) -> Result<usize, Error> {
+    let mut num_objects_written = 0;
+    let nodes = primitive_group.nodes;
+    results.reserve(nodes.len());
+    for node in nodes {
+        let id = node.id as ObjId;
+        anomalies.ids(OSMObjectType::Node, id, std::iter::empty())?;
+        let keys_vals = node
+            .keys
+            .iter()
+            .zip(&node.vals)
+            .map(|(&k, &v)| (k.into(), v.into()));
+        let tags = anomalies.tags(stringtable, keys_vals, OSMObjectType::Node, id)?;
+
+        let raw = (node.lat, node.lon);
+        let lat_lon = pbf_lat_lon(raw, granularity, (lat_offset, lon_offset), id)?;
+
+        let metadata = anomalies.metadata(
+            node.info.as_ref(),
+            date_granularity,
+            stringtable,
+            OSMObjectType::Node,
+            id,
+        )?;
+
+        results.push_back(StringOSMObj::Node(StringNode {
+            _id: id,
+            _tags: Some(tags),
+            _lat_lon: Some(lat_lon),
+            _deleted: metadata.deleted,
+            _changeset_id: metadata.changeset_id,
+            _uid: metadata.uid,
+            _user: metadata.user,
+            _version: metadata.version,
+            _timestamp: metadata.timestamp,
+        }));
+        num_objects_written += 1;
+    }
+    Ok(num_objects_written)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decode_dense_nodes(
     primitive_group: OSMPBF::PrimitiveGroup,
     granularity: i32,
     lat_offset: i64,
     lon_offset: i64,
     date_granularity: i32,
-    stringtable: &[Option<String>],
+    stringtable: &StringTable,
+    anomalies: &Anomalies,
     results: &mut VecDeque<StringOSMObj>,
-) -> usize {
+) -> Result<usize, Error> {
     let mut num_objects_written = 0;
     let dense = primitive_group.dense.unwrap();
-    let ids = dense.id;
-    let lats = dense.lat;
-    let lons = dense.lon;
-    let denseinfo = dense.denseinfo.unwrap();
-
-    let uids = denseinfo.uid;
-    let changesets = denseinfo.changeset;
-    let user_sids = denseinfo.user_sid;
-    let timestamps = denseinfo.timestamp;
-
-    let num_nodes = ids.len();
-    results.reserve(num_nodes);
-    // TODO assert that the id, denseinfo, lat, lon and optionally keys_vals has the same
-    // length
-
-    let keys_vals = dense.keys_vals;
-    let has_tags = !keys_vals.is_empty();
-
-    let mut keys_vals_index = 0;
-
-    // NB it's important that these start at zero, makes the code easier later
-    let mut last_id: i64 = 0;
-    let mut last_raw_lat: i32 = 0;
-    let mut last_raw_lon: i32 = 0;
-    let mut last_timestamp = 0;
-    let mut last_changset = 0;
-    let mut last_uid = 0;
-    let mut last_user_sid = 0;
-
-    for index in 0..num_nodes {
-        // last_* start off 0
-        let id = ids[index] + last_id;
-        last_id = id;
-
-        let raw_lat = i32::try_from(lats[index] + last_raw_lat as i64)
-            .expect("raw_lat was larger than the OSM precision allows");
-        last_raw_lat = raw_lat;
-
-        let raw_lon = i32::try_from(lons[index] + last_raw_lon as i64)
-            .expect("raw_lon was larger than OSM precision allows");
-        last_raw_lon = raw_lon;
-
-        // granularity is in nanodegrees
-        let scale_factor = granularity / COORD_PRECISION_NANOS;
-        let mut internal_lat = raw_lat * scale_factor;
-        let mut internal_lon = raw_lon * scale_factor;
-
-        // Offsets from pbf are in nanodegrees
-        let internal_lat_offset = lat_offset / COORD_PRECISION_NANOS as i64;
-        let internal_lon_offset = lon_offset / COORD_PRECISION_NANOS as i64;
-        internal_lat += internal_lat_offset as i32;
-        internal_lon += internal_lon_offset as i32;
-
-        let tags = if !has_tags {
-            None
-        } else {
-            let mut tags = Vec::new();
-            loop {
-                //assert!(keys_vals_index <= keys_vals.len());
-                let next = keys_vals[keys_vals_index];
-                keys_vals_index += 1;
-                if next == 0 {
-                    break;
-                } else {
-                    let key = next;
-                    let val = keys_vals[keys_vals_index];
-                    keys_vals_index += 1;
-                    tags.push((key, val));
-                }
-                // FIXME infinite loop detection maybe?
-            }
+    let nodes = DenseNodes::new(
+        dense_arrays(&dense),
+        granularity,
+        (lat_offset, lon_offset),
+        date_granularity,
+        anomalies.offset,
+    )?;
+    results.reserve(nodes.len());
+    if let Some(id) = nodes.first_id().filter(|_| !nodes.has_info()) {
+        let detail = format!("no metadata for {} nodes", nodes.len());
+        anomalies.report(AnomalyKind::MissingInfo, OSMObjectType::Node, id, detail)?;
+    }
 
-            Some(
-                tags.iter()
-                    .map(|&(kidx, vidx)| {
-                        (
-                            stringtable[kidx as usize].clone(),
-                            stringtable[vidx as usize].clone(),
-                        )
-                    })
-                    .filter_map(|(k, v)| match (k, v) {
-                        (Some(k), Some(v)) => Some((k, v)),
-                        _ => None,
-                    })
-                    .collect(),
-            )
+    for node in nodes {
+        let node = node?;
+        let id = node.id;
+        anomalies.ids(OSMObjectType::Node, id, std::iter::empty())?;
+        let tags = match node.tags() {
+            Some(tags) => Some(anomalies.tags(stringtable, tags, OSMObjectType::Node, id)?),
+            None => None,
         };
 
-        let changeset_id = changesets[index] + last_changset;
-        last_changset = changeset_id;
-        let uid_id = uids[index] + last_uid;
-        last_uid = uid_id;
-        let user_sid = user_sids[index] + last_user_sid;
-        last_user_sid = user_sid;
-        let timestamp = timestamps[index] as i32 + last_timestamp;
-        let timestamp = timestamp * date_granularity;
-        last_timestamp = timestamp;
-        let timestamp = TimestampFormat::EpochNunber(timestamp as i64);
-        assert!(uid_id < std::i32::MAX);
+        let mut metadata = Metadata::default();
+        if let Some(info) = node.info {
+            metadata = Metadata {
+                deleted: node.deleted,
+                changeset_id: Some(info.changeset_id),
+                uid: Some(info.uid),
+                user: anomalies.string(stringtable, info.user_sid, OSMObjectType::Node, id)?,
+                version: Some(info.version),
+                timestamp: anomalies.timestamp(info.timestamp, OSMObjectType::Node, id)?,
+            };
+        }
 
         results.push_back(StringOSMObj::Node(StringNode {
-            _id: id as ObjId,
+            _id: id,
             _tags: tags,
-            _lat_lon: Some((Lat(internal_lat), Lon(internal_lon))),
-            _deleted: !denseinfo.visible.get(index).unwrap_or(&true),
-            _changeset_id: Some(changeset_id as u32),
-            _uid: Some(uid_id as u32),
-            _user: Some(stringtable[user_sid as usize].clone().unwrap()),
-            _version: Some(denseinfo.version[index] as u32),
-            _timestamp: Some(timestamp),
+            _lat_lon: Some(node.lat_lon),
+            _deleted: metadata.deleted,
+            _changeset_id: metadata.changeset_id,
+            _uid: metadata.uid,
+            _user: metadata.user,
+            _version: metadata.version,
+            _timestamp: metadata.timestamp,
         }));
         num_objects_written += 1
     }
 
-    num_objects_written
+    Ok(num_objects_written)
+}
+
+/// The arrays of this `DenseNodes` message, for [`DenseNodes`]
+fn dense_arrays(dense: &OSMPBF::DenseNodes) -> DenseArrays<'_> {
+    DenseArrays {
+        ids: &dense.id,
+        lats: &dense.lat,
+        lons: &dense.lon,
+        keys_vals: &dense.keys_vals,
+        info: dense.denseinfo.as_ref().map(|info| DenseInfoArrays {
+            versions: &info.version,
+            timestamps: &info.timestamp,
+            changesets: &info.changeset,
+            uids: &info.uid,
+            user_sids: &info.user_sid,
+            visible: &info.visible,
+        }),
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decode_ways(
     primitive_group: OSMPBF::PrimitiveGroup,
     _granularity: i32,
     _lat_offset: i64,
     _lon_offset: i64,
-    _date_granularity: i32,
-    stringtable: &[Option<String>],
+    date_granularity: i32,
+    stringtable: &StringTable,
+    anomalies: &Anomalies,
     results: &mut VecDeque<StringOSMObj>,
-) -> usize {
+) -> Result<usize, Error> {
     let mut num_objects_written = 0;
     let ways = primitive_group.ways;
     results.reserve(ways.len());
     for way in ways {
         let id = way.id as ObjId;
-        // TODO check for +itive keys/vals
-        let keys = way
+        let keys_vals = way
             .keys
             .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let vals = way
-            .vals
-            .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let tags = keys.zip(vals);
-        let tags: Vec<_> = tags
-            .filter_map(|(k, v)| match (k, v) {
-                (Some(k), Some(v)) => Some((k, v)),
-                _ => None,
-            })
-            .collect();
+            .zip(&way.vals)
+            .map(|(&k, &v)| (k.into(), v.into()));
+        let tags = anomalies.tags(stringtable, keys_vals, OSMObjectType::Way, id)?;
 
         let refs = way.refs;
         let mut nodes = Vec::with_capacity(refs.len());
@@ -222,78 +447,63 @@ fn decode_ways(
             let mut last_id = refs[0];
             nodes.push(last_id as ObjId);
             for nid in &refs[1..] {
-                last_id += nid;
+                last_id = last_id.wrapping_add(*nid);
                 nodes.push(last_id as ObjId);
             }
         }
+        anomalies.ids(OSMObjectType::Way, id, nodes.iter().copied())?;
 
-        // TODO assert all node ids are positive
-
-        // TODO could there be *no* info? What should be done there
-
-        //println!("from pbf {} last_timestamp {}", way.get_info().get_timestamp(), last_timestamp);
-        //let timestamp = way.get_info().get_timestamp() as i32 + last_timestamp;
-        //let timestamp = timestamp * date_granularity;
-        //last_timestamp = timestamp;
-        //let timestamp = epoch_to_iso(timestamp);
-        let timestamp = TimestampFormat::EpochNunber(way.info.as_ref().unwrap().timestamp.unwrap());
-        let info = way.info.as_ref().unwrap();
+        let metadata = anomalies.metadata(
+            way.info.as_ref(),
+            date_granularity,
+            stringtable,
+            OSMObjectType::Way,
+            id,
+        )?;
 
         results.push_back(StringOSMObj::Way(StringWay {
             _id: id,
             _tags: tags,
             _nodes: nodes,
-            _deleted: !info.visible.unwrap_or(true),
-            _changeset_id: Some(info.changeset.unwrap() as u32),
-            _uid: Some(info.uid.unwrap() as u32),
-            _user: Some(
-                stringtable[info.user_sid.unwrap() as usize]
-                    .clone()
-                    .unwrap()
-                    .clone(),
-            ),
-            _version: Some(info.version as u32),
-            _timestamp: Some(timestamp),
+            _deleted: metadata.deleted,
+            _changeset_id: metadata.changeset_id,
+            _uid: metadata.uid,
+            _user: metadata.user,
+            _version: metadata.version,
+            _timestamp: metadata.timestamp,
         }));
         num_objects_written += 1;
     }
-    num_objects_written
+    Ok(num_objects_written)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn decode_relations(
     primitive_group: OSMPBF::PrimitiveGroup,
     _granularity: i32,
     _lat_offset: i64,
     _lon_offset: i64,
-    _date_granularity: i32,
-    stringtable: &[Option<String>],
+    date_granularity: i32,
+    stringtable: &StringTable,
+    anomalies: &Anomalies,
     sink: &mut VecDeque<StringOSMObj>,
-) -> usize {
+) -> Result<usize, Error> {
     let _last_timestamp = 0;
     let mut num_objects_written = 0;
     for relation in primitive_group.relations.into_iter() {
         let id = relation.id as ObjId;
-        // TODO check for +itive keys/vals
-        let keys = relation
+        let keys_vals = relation
             .keys
             .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let vals = relation
-            .vals
-            .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
-        let tags = keys.zip(vals);
-        let tags: Vec<_> = tags
-            .filter_map(|(k, v)| match (k, v) {
-                (Some(k), Some(v)) => Some((k, v)),
-                _ => None,
-            })
-            .collect();
+            .zip(&relation.vals)
+            .map(|(&k, &v)| (k.into(), v.into()));
+        let tags = anomalies.tags(stringtable, keys_vals, OSMObjectType::Relation, id)?;
 
         let roles = relation
             .roles_sid
             .iter()
-            .map(|&idx| stringtable[idx as usize].clone());
+            .map(|&idx| anomalies.string(stringtable, idx.into(), OSMObjectType::Relation, id))
+            .collect::<Result<Vec<_>, _>>()?;
 
         let refs = relation.memids;
         let mut member_ids = Vec::with_capacity(refs.len());
@@ -302,10 +512,11 @@ fn decode_relations(
             let mut last_id = refs[0];
             member_ids.push(last_id as ObjId);
             for nid in &refs[1..] {
-                last_id += nid;
+                last_id = last_id.wrapping_add(*nid);
                 member_ids.push(last_id as ObjId);
             }
         }
+        anomalies.ids(OSMObjectType::Relation, id, member_ids.iter().copied())?;
         let _num_members = member_ids.len();
         let member_ids = member_ids.iter();
 
@@ -321,32 +532,28 @@ fn decode_relations(
             .filter_map(|((t, &id), r_opt)| r_opt.map(|r| (t, id, r)))
             .collect();
 
-        // TODO could there be *no* info? What should be done there
-        //let timestamp = relation.get_info().get_timestamp() as i32 + last_timestamp;
-        //let timestamp = timestamp * date_granularity;
-        //last_timestamp = timestamp;
-        //let timestamp = epoch_to_iso(timestamp);
-        let info = relation.info.as_ref().unwrap();
-        let timestamp = TimestampFormat::EpochNunber(info.timestamp.unwrap());
+        let metadata = anomalies.metadata(
+            relation.info.as_ref(),
+            date_granularity,
+            stringtable,
+            OSMObjectType::Relation,
+            id,
+        )?;
 
         sink.push_back(StringOSMObj::Relation(StringRelation {
             _id: id,
             _tags: tags,
             _members: members,
-            _deleted: !info.visible.unwrap(),
-            _changeset_id: Some(info.changeset.unwrap() as u32),
-            _uid: Some(info.uid.unwrap() as u32),
-            _user: Some(
-                stringtable[info.user_sid.unwrap() as usize]
-                    .clone()
-                    .unwrap(),
-            ),
-            _version: Some(info.version as u32),
-            _timestamp: Some(timestamp),
+            _deleted: metadata.deleted,
+            _changeset_id: metadata.changeset_id,
+            _uid: metadata.uid,
+            _user: metadata.user,
+            _version: metadata.version,
+            _timestamp: metadata.timestamp,
         }));
         num_objects_written += 1;
     }
-    num_objects_written
+    Ok(num_objects_written)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -356,11 +563,11 @@ fn decode_primitive_group_to_objs(
     lat_offset: i64,
     lon_offset: i64,
     date_granularity: i32,
-    stringtable: &[Option<String>],
+    stringtable: &StringTable,
     object_filter: &ObjectFilter,
+    anomalies: &Anomalies,
     sink: &mut VecDeque<StringOSMObj>,
-) -> usize {
-    let date_granularity = date_granularity / 1000;
+) -> Result<usize, Error> {
     let mut num_objects_written = 0;
     if !primitive_group.nodes.is_empty() && object_filter.0 {
         num_objects_written += decode_nodes(
@@ -370,8 +577,9 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            anomalies,
             sink,
-        );
+        )?;
     } else if primitive_group.dense.is_some() && object_filter.0 {
         num_objects_written += decode_dense_nodes(
             primitive_group,
@@ -380,8 +588,9 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            anomalies,
             sink,
-        );
+        )?;
     } else if !primitive_group.ways.is_empty() && object_filter.1 {
         num_objects_written += decode_ways(
             primitive_group,
@@ -390,8 +599,9 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            anomalies,
             sink,
-        );
+        )?;
     } else if !primitive_group.relations.is_empty() && object_filter.2 {
         num_objects_written += decode_relations(
             primitive_group,
@@ -400,26 +610,28 @@ fn decode_primitive_group_to_objs(
             lon_offset,
             date_granularity,
             stringtable,
+            anomalies,
             sink,
-        );
+        )?;
     } else {
         // can happen if there is an object filter in operation
     }
 
-    num_objects_written
+    Ok(num_objects_written)
 }
 
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", name = "decode_block", skip_all, fields(offset = anomalies.offset))
+)]
 fn decode_block_to_objs(
     block: OSMPBF::PrimitiveBlock,
     object_filter: &ObjectFilter,
+    lossy_utf8: bool,
+    anomalies: &Anomalies,
     sink: &mut VecDeque<StringOSMObj>,
-) -> usize {
-    let stringtable: Vec<Option<String>> = block
-        .stringtable
-        .s
-        .iter()
-        .map(|chars| std::str::from_utf8(chars).ok().map(String::from))
-        .collect();
+) -> Result<usize, Error> {
+    let stringtable = StringTable::new(&block.stringtable.s, lossy_utf8);
 
     let granularity = block.granularity;
     let lat_offset = block.lat_offset;
@@ -437,19 +649,29 @@ fn decode_block_to_objs(
             date_granularity,
             &stringtable,
             object_filter,
+            anomalies,
             sink,
-        );
+        )?;
     }
 
-    results
+    Ok(results)
 }
 
 /// A thing that read PBF files
 pub struct PBFReader<R: Read> {
     reader: R,
+    /// Number of bytes read so far, i.e. the offset of the next blob
+    offset: u64,
     buffer: VecDeque<StringOSMObj>,
     _sorted_assumption: bool,
     object_filter: ObjectFilter,
+    parse_mode: ParseMode,
+    lossy_utf8: bool,
+    duplicate_tags: DuplicateTags,
+    non_positive_ids: NonPositiveIds,
+    /// The `OSMHeader` blob, once it's been read
+    header: Option<header::FileInfo>,
+    cancellation: Option<CancellationToken>,
 }
 
 impl<R: Read> PBFReader<R> {
@@ -470,6 +692,12 @@ impl<R: Read> PBFReader<R> {
         self.object_filter = (false, false, true);
         self.objects().filter_map(|o| o.into_relation())
     }
+
+    /// Stop reading when this token is cancelled. It's checked before every block is read, and
+    /// then `try_next` returns an [`Error::Cancelled`] (and `next` returns `None`).
+    pub fn set_cancellation(&mut self, cancellation: impl Into<Option<CancellationToken>>) {
+        self.cancellation = cancellation.into();
+    }
 }
 
 impl PBFReader<BufReader<File>> {
@@ -487,12 +715,24 @@ impl<R: Read> OSMReader for PBFReader<R> {
     fn new(reader: R) -> PBFReader<R> {
         PBFReader {
             reader,
+            offset: 0,
             buffer: VecDeque::new(),
             _sorted_assumption: false,
             object_filter: (true, true, true),
+            parse_mode: ParseMode::default(),
+            lossy_utf8: false,
+            duplicate_tags: DuplicateTags::default(),
+            non_positive_ids: NonPositiveIds::default(),
+            header: None,
+            cancellation: None,
         }
     }
 
+    /// The `OSMHeader` blob, which is read with the first block
+    fn file_info(&self) -> Option<&header::FileInfo> {
+        self.header.as_ref()
+    }
+
     fn set_sorted_assumption(&mut self, sorted_assumption: bool) {
         self._sorted_assumption = sorted_assumption;
     }
@@ -500,6 +740,22 @@ impl<R: Read> OSMReader for PBFReader<R> {
         self._sorted_assumption
     }
 
+    fn set_parse_mode(&mut self, parse_mode: ParseMode) {
+        self.parse_mode = parse_mode;
+    }
+
+    fn set_lossy_utf8(&mut self, lossy_utf8: bool) {
+        self.lossy_utf8 = lossy_utf8;
+    }
+
+    fn set_duplicate_tags(&mut self, duplicate_tags: DuplicateTags) {
+        self.duplicate_tags = duplicate_tags;
+    }
+
+    fn set_non_positive_ids(&mut self, non_positive_ids: NonPositiveIds) {
+        self.non_positive_ids = non_positive_ids;
+    }
+
     fn inner(&self) -> &R {
         &self.reader
     }
@@ -508,59 +764,54 @@ impl<R: Read> OSMReader for PBFReader<R> {
         self.reader
     }
 
+    /// Panics if the file is invalid, use `try_next` to get an error instead. Returns `None` if
+    /// cancelled.
     fn next(&mut self) -> Option<StringOSMObj> {
+        match self.try_next() {
+            Err(Error::Cancelled(_)) => None,
+            res => res.unwrap(),
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<StringOSMObj>, Error> {
         let mut blob_bytes = Vec::new();
         let mut blob_raw_bytes = Vec::new();
-        let mut blob;
         while self.buffer.is_empty() {
             // get the next file block and fill up our buffer
             // FIXME make this parallel
-
-            // get the next block
-            // FIXME is there a way we can ask self.reader if it's at EOF? Rather than waiting for
-            // the failure and catching that?
-
-            // read the next blob
-            loop {
-                let size = self.reader.read_u32::<byteorder::BigEndian>().ok()?;
-                let mut header_bytes_vec = vec![0; size as usize];
-
-                self.reader
-                    .read_exact(header_bytes_vec.as_mut_slice())
-                    .unwrap();
-
-                let mut reader = BytesReader::from_bytes(&header_bytes_vec);
-
-                let blob_header =
-                    fileformat::BlobHeader::from_reader(&mut reader, &header_bytes_vec).unwrap();
-
-                blob_bytes.resize(blob_header.datasize as usize, 0);
-                self.reader.read_exact(blob_bytes.as_mut_slice()).unwrap();
-
-                if blob_header.type_pb != "OSMData" {
-                    // keep going to the next blob
-                    continue;
-                }
-
-                let mut reader = BytesReader::from_bytes(&blob_bytes);
-
-                blob = fileformat::Blob::from_reader(&mut reader, &blob_bytes).unwrap();
-                break;
-            }
-
-            blob_raw_bytes.truncate(0);
-            blob_raw_data(&mut blob, &mut blob_raw_bytes);
+            cancel::check(&self.cancellation)?;
+            let offset = match read_osmdata_blob(
+                &mut self.reader,
+                &mut self.offset,
+                &mut blob_bytes,
+                &mut blob_raw_bytes,
+                Some(&mut self.header),
+            )? {
+                None => return Ok(None),
+                Some(offset) => offset,
+            };
             if blob_raw_bytes.is_empty() {
                 // maybe the filter meant nothing was read
                 continue;
             }
-            let block: OSMPBF::PrimitiveBlock =
-                quick_protobuf::reader::deserialize_from_slice(&blob_raw_bytes).unwrap();
+            let block = primitive_block(&blob_raw_bytes, offset)?;
 
             // Turn a block into OSM objects
-            decode_block_to_objs(block, &self.object_filter, &mut self.buffer);
+            let anomalies = Anomalies {
+                mode: &self.parse_mode,
+                offset,
+                duplicate_tags: self.duplicate_tags,
+                non_positive_ids: self.non_positive_ids,
+            };
+            decode_block_to_objs(
+                block,
+                &self.object_filter,
+                self.lossy_utf8,
+                &anomalies,
+                &mut self.buffer,
+            )?;
         }
 
-        self.buffer.pop_front()
+        Ok(self.buffer.pop_front())
     }
 }
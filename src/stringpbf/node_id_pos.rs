@@ -1,11 +1,11 @@
 use crate::{Lat, Lon, ObjId};
 use std::io::Read;
 use std::iter::Iterator;
+use utils::pbf_lat_lon;
 
 use std::collections::VecDeque;
 
 use super::*;
-use crate::COORD_PRECISION_NANOS;
 
 /// (node id, (latitude, longitude))
 type NodeIdPos = (ObjId, (Lat, Lon));
@@ -13,13 +13,16 @@ type NodeIdPos = (ObjId, (Lat, Lon));
 /// Reads a PBF file and returns just (nodeid, pos). This is a little faster than reading the whole
 /// file
 ///
-/// ```
+/// ```no_run
 /// let mut reader =
 /// osmio::stringpbf::PBFNodePositionReader::from_filename("region-latest.osm.pbf")?;
 /// let (nid, (lat, lon)) = reader.next().unwrap();
+/// # Ok::<(), anyhow::Error>(())
 /// ```
 pub struct PBFNodePositionReader<R: Read> {
     reader: R,
+    /// Number of bytes read so far, i.e. the offset of the next blob
+    offset: u64,
     buffer: VecDeque<NodeIdPos>,
 }
 
@@ -28,6 +31,7 @@ impl<R: Read> PBFNodePositionReader<R> {
     fn new(reader: R) -> Self {
         Self {
             reader,
+            offset: 0,
             buffer: VecDeque::new(),
         }
     }
@@ -36,131 +40,87 @@ impl<R: Read> PBFNodePositionReader<R> {
     pub fn from_reader(reader: R) -> Self {
         Self::new(reader)
     }
-}
 
-impl PBFNodePositionReader<BufReader<File>> {
-    /// Create a new PBFNodePositionReader for this path
-    pub fn from_filename(filename: impl AsRef<Path>) -> Result<Self> {
-        let filename: &Path = filename.as_ref();
-        Ok(Self::new(BufReader::new(File::open(filename)?)))
-    }
-}
-
-impl<R: Read> Iterator for PBFNodePositionReader<R> {
-    type Item = NodeIdPos;
-
-    fn next(&mut self) -> Option<Self::Item> {
+    /// The next node, `None` at the end of the file, or an error if the file is invalid
+    pub fn try_next(&mut self) -> Result<Option<NodeIdPos>, Error> {
         let mut blob_bytes = Vec::new();
         let mut blob_raw_bytes = Vec::new();
-        let mut blob;
 
         while self.buffer.is_empty() {
             // get the next file block and fill up our buffer
             // FIXME make this parallel
-
-            // get the next block
-            // FIXME is there a way we can ask self.reader if it's at EOF? Rather than waiting for
-            // the failure and catching that?
-
-            // read the next blob
-            loop {
-                let size = self.reader.read_u32::<byteorder::BigEndian>().ok()?;
-                let mut header_bytes_vec = vec![0; size as usize];
-
-                self.reader
-                    .read_exact(header_bytes_vec.as_mut_slice())
-                    .unwrap();
-
-                let mut reader = BytesReader::from_bytes(&header_bytes_vec);
-
-                let blob_header =
-                    fileformat::BlobHeader::from_reader(&mut reader, &header_bytes_vec).unwrap();
-
-                blob_bytes.resize(blob_header.datasize as usize, 0);
-                self.reader.read_exact(blob_bytes.as_mut_slice()).unwrap();
-
-                if blob_header.type_pb != "OSMData" {
-                    // keep going to the next blob
-                    continue;
-                }
-
-                let mut reader = BytesReader::from_bytes(&blob_bytes);
-
-                blob = fileformat::Blob::from_reader(&mut reader, &blob_bytes).unwrap();
-                break;
-            }
-
-            blob_raw_bytes.truncate(0);
-            blob_raw_data(&mut blob, &mut blob_raw_bytes);
+            let offset = match read_osmdata_blob(
+                &mut self.reader,
+                &mut self.offset,
+                &mut blob_bytes,
+                &mut blob_raw_bytes,
+                None,
+            )? {
+                None => return Ok(None),
+                Some(offset) => offset,
+            };
             if blob_raw_bytes.is_empty() {
                 // maybe the filter meant nothing was read
                 continue;
             }
-            let mut reader = BytesReader::from_bytes(&blob_raw_bytes);
-            let block = OSMPBF::PrimitiveBlock::from_reader(&mut reader, &blob_raw_bytes).unwrap();
+            let block = primitive_block(&blob_raw_bytes, offset)?;
 
             // Turn a block into OSM objects
-            decode_block_to_objs(block, &mut self.buffer);
+            decode_block_to_objs(block, offset, &mut self.buffer)?;
         }
 
-        self.buffer.pop_front()
+        Ok(self.buffer.pop_front())
+    }
+}
+
+impl PBFNodePositionReader<BufReader<File>> {
+    /// Create a new PBFNodePositionReader for this path
+    pub fn from_filename(filename: impl AsRef<Path>) -> Result<Self> {
+        let filename: &Path = filename.as_ref();
+        Ok(Self::new(BufReader::new(File::open(filename)?)))
+    }
+}
+
+impl<R: Read> Iterator for PBFNodePositionReader<R> {
+    type Item = NodeIdPos;
+
+    /// Panics if the file is invalid, use `try_next` to get an error instead.
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().unwrap()
     }
 }
 
-fn decode_block_to_objs(block: OSMPBF::PrimitiveBlock, sink: &mut VecDeque<NodeIdPos>) -> usize {
+fn decode_block_to_objs(
+    block: OSMPBF::PrimitiveBlock,
+    offset: u64,
+    sink: &mut VecDeque<NodeIdPos>,
+) -> Result<usize, Error> {
     let granularity = block.granularity;
-    let lat_offset = block.lat_offset;
-    let lon_offset = block.lon_offset;
+    let offsets = (block.lat_offset, block.lon_offset);
+    let date_granularity = block.date_granularity;
     let mut num_objects = 0;
 
     for primitive_group in block.primitivegroup.into_iter() {
-        if !primitive_group.nodes.is_empty() {
-            unimplemented!()
-        } else if !primitive_group.ways.is_empty() || !primitive_group.relations.is_empty() {
-            continue;
-        } else if let Some(dense) = primitive_group.dense {
-            let ids = dense.id;
-            let lats = dense.lat;
-            let lons = dense.lon;
-
-            let num_nodes = ids.len();
-
-            // NB it's important that these start at zero, makes the code easier later
-            let mut last_id: i64 = 0;
-            let mut last_raw_lat: i32 = 0;
-            let mut last_raw_lon: i32 = 0;
-
-            for index in 0..num_nodes {
-                // last_* start off 0
-                let id = ids[index] + last_id;
-                last_id = id;
-
-                let raw_lat = i32::try_from(lats[index] + last_raw_lat as i64)
-                    .expect("raw_lat was larger than the OSM precision allows");
-                last_raw_lat = raw_lat;
-
-                let raw_lon = i32::try_from(lons[index] + last_raw_lon as i64)
-                    .expect("raw_lon was larger than OSM precision allows");
-                last_raw_lon = raw_lon;
-
-                // granularity is in nanodegrees
-                let scale_factor = granularity / COORD_PRECISION_NANOS;
-                let mut internal_lat = raw_lat * scale_factor;
-                let mut internal_lon = raw_lon * scale_factor;
-
-                // Offsets from pbf are in nanodegrees
-                let internal_lat_offset = lat_offset / COORD_PRECISION_NANOS as i64;
-                let internal_lon_offset = lon_offset / COORD_PRECISION_NANOS as i64;
-                internal_lat += internal_lat_offset as i32;
-                internal_lon += internal_lon_offset as i32;
-
-                sink.push_back((id as ObjId, (Lat(internal_lat), Lon(internal_lon))));
+        for node in &primitive_group.nodes {
+            let lat_lon = pbf_lat_lon((node.lat, node.lon), granularity, offsets, node.id)?;
+            sink.push_back((node.id, lat_lon));
+            num_objects += 1;
+        }
+        if let Some(dense) = primitive_group.dense {
+            let nodes = DenseNodes::new(
+                dense_arrays(&dense),
+                granularity,
+                offsets,
+                date_granularity,
+                offset,
+            )?;
+            for node in nodes {
+                let node = node?;
+                sink.push_back((node.id, node.lat_lon));
                 num_objects += 1;
             }
-        } else {
-            unreachable!();
         }
     }
 
-    num_objects
+    Ok(num_objects)
 }
@@ -0,0 +1,375 @@
+//! Counting how often each key & tag (key=value) is used, like [taginfo](https://taginfo.openstreetmap.org/).
+//!
+//! By default every key & tag is counted exactly, which needs memory for every distinct tag (a
+//! lot for the planet, since values like `name` are nearly all different). With
+//! [`TagFrequencyCounter::max_entries`], only that many keys & tags are kept, using the
+//! “space saving” algorithm, so the most common ones (which are usually what's wanted) are still
+//! found, with an upper bound on the error of each count.
+//!
+//! ```no_run
+//! use osmio::tag_stats::TagFrequencyCounter;
+//!
+//! let mut reader = osmio::read_pbf("planet.osm.pbf")?;
+//! let report = TagFrequencyCounter::new()
+//!     .max_entries(1_000_000)
+//!     .count(&mut reader)?
+//!     .top(100);
+//! println!("{}", serde_json::to_string_pretty(&report)?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use serde::{Deserialize, Serialize};
+use std::borrow::Borrow;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use {Error, OSMObjBase, OSMObjectType, OSMReader};
+
+/// How often one key or tag is used
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagCount {
+    pub key: String,
+    /// `None` for key counts
+    pub value: Option<String>,
+    /// Number of objects with this key/tag
+    pub count: u64,
+    /// Number of nodes, ways & relations (only if counting per type)
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub by_type: Option<TypeCounts>,
+    /// The most `count` could be too high by, when memory is bounded (otherwise 0)
+    pub max_error: u64,
+}
+
+/// Counts per object type
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeCounts {
+    pub nodes: u64,
+    pub ways: u64,
+    pub relations: u64,
+}
+
+impl TypeCounts {
+    /// Total of all types
+    pub fn total(&self) -> u64 {
+        self.nodes + self.ways + self.relations
+    }
+
+    pub(crate) fn add(&mut self, object_type: OSMObjectType) {
+        match object_type {
+            OSMObjectType::Node => self.nodes += 1,
+            OSMObjectType::Way => self.ways += 1,
+            OSMObjectType::Relation => self.relations += 1,
+        }
+    }
+}
+
+/// The result of counting tags. Keys & tags are sorted by count, most common first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TagFrequencyReport {
+    /// Number of objects looked at
+    pub num_objects: u64,
+    pub keys: Vec<TagCount>,
+    pub tags: Vec<TagCount>,
+}
+
+impl TagFrequencyReport {
+    /// Keep only the `n` most common keys & tags
+    pub fn top(mut self, n: usize) -> Self {
+        self.keys.truncate(n);
+        self.tags.truncate(n);
+        self
+    }
+
+    /// The count of this key, if it was counted
+    pub fn key(&self, key: &str) -> Option<&TagCount> {
+        self.keys.iter().find(|c| c.key == key)
+    }
+
+    /// The count of this tag, if it was counted
+    pub fn tag(&self, key: &str, value: &str) -> Option<&TagCount> {
+        self.tags
+            .iter()
+            .find(|c| c.key == key && c.value.as_deref() == Some(value))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    count: u64,
+    by_type: TypeCounts,
+    max_error: u64,
+}
+
+/// A tag, so a `(String, String)` can be looked up with a `(&str, &str)`
+trait TagKey {
+    fn pair(&self) -> (&str, &str);
+}
+
+impl TagKey for (String, String) {
+    fn pair(&self) -> (&str, &str) {
+        (&self.0, &self.1)
+    }
+}
+
+impl TagKey for (&str, &str) {
+    fn pair(&self) -> (&str, &str) {
+        *self
+    }
+}
+
+impl<'a> Borrow<dyn TagKey + 'a> for Arc<(String, String)> {
+    fn borrow(&self) -> &(dyn TagKey + 'a) {
+        &**self
+    }
+}
+
+/// The same hash as the `(String, String)`
+impl Hash for dyn TagKey + '_ {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pair().hash(state)
+    }
+}
+
+impl PartialEq for dyn TagKey + '_ {
+    fn eq(&self, other: &Self) -> bool {
+        self.pair() == other.pair()
+    }
+}
+
+impl Eq for dyn TagKey + '_ {}
+
+/// Counts things, either exactly, or keeping only the `max_entries` most common. Keys are
+/// `Arc`s, so they can be in both collections without being copied.
+#[derive(Debug, Clone)]
+struct FrequencyTable<K: Hash + Eq + Ord + Clone> {
+    entries: HashMap<K, Entry>,
+    /// (count, key) for every entry, to find the least common quickly. Only used if bounded.
+    by_count: BTreeSet<(u64, K)>,
+    max_entries: Option<usize>,
+}
+
+impl<K: Hash + Eq + Ord + Clone> FrequencyTable<K> {
+    fn new(max_entries: Option<usize>) -> Self {
+        FrequencyTable {
+            entries: HashMap::new(),
+            by_count: BTreeSet::new(),
+            max_entries,
+        }
+    }
+
+    /// Count `key`, which is only copied (with `to_owned`) the first time it's seen
+    fn add<Q>(&mut self, key: &Q, object_type: OSMObjectType, to_owned: impl FnOnce(&Q) -> K)
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Hash + Eq,
+    {
+        let max_entries = match self.max_entries {
+            None => {
+                if !self.entries.contains_key(key) {
+                    self.entries.insert(to_owned(key), Entry::default());
+                }
+                let entry = self.entries.get_mut(key).unwrap();
+                entry.count += 1;
+                entry.by_type.add(object_type);
+                return;
+            }
+            Some(max_entries) => max_entries,
+        };
+
+        if let Some(key) = self.entries.get_key_value(key).map(|(k, _)| k.clone()) {
+            let entry = self.entries.get_mut::<K>(&key).unwrap();
+            self.by_count.remove(&(entry.count, key.clone()));
+            entry.count += 1;
+            entry.by_type.add(object_type);
+            self.by_count.insert((entry.count, key));
+            return;
+        }
+        let key = to_owned(key);
+
+        let mut entry = Entry::default();
+        if self.entries.len() >= max_entries {
+            // Replace the least common entry. The new key could have been seen up to that many
+            // times before.
+            let (min_count, min_key) = self.by_count.pop_first().unwrap();
+            self.entries.remove::<K>(&min_key);
+            entry.count = min_count;
+            entry.max_error = min_count;
+        }
+        entry.count += 1;
+        entry.by_type.add(object_type);
+        self.by_count.insert((entry.count, key.clone()));
+        self.entries.insert(key, entry);
+    }
+
+    /// All the entries, most common first
+    fn into_sorted(self) -> Vec<(K, Entry)> {
+        let mut entries: Vec<(K, Entry)> = self.entries.into_iter().collect();
+        entries.sort_by(|a, b| b.1.count.cmp(&a.1.count).then_with(|| a.0.cmp(&b.0)));
+        entries
+    }
+}
+
+/// Counts keys & tags. See the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct TagFrequencyCounter {
+    per_type: bool,
+    count_values: bool,
+    num_objects: u64,
+    keys: FrequencyTable<Arc<str>>,
+    tags: FrequencyTable<Arc<(String, String)>>,
+}
+
+impl Default for TagFrequencyCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TagFrequencyCounter {
+    /// Count all keys & tags exactly, not per type
+    pub fn new() -> Self {
+        TagFrequencyCounter {
+            per_type: false,
+            count_values: true,
+            num_objects: 0,
+            keys: FrequencyTable::new(None),
+            tags: FrequencyTable::new(None),
+        }
+    }
+
+    /// Also count how many nodes, ways & relations have each key & tag (default: `false`).
+    pub fn per_type(mut self, per_type: bool) -> Self {
+        self.per_type = per_type;
+        self
+    }
+
+    /// Count tags (key=value), not just keys (default: `true`).
+    pub fn count_values(mut self, count_values: bool) -> Self {
+        self.count_values = count_values;
+        self
+    }
+
+    /// Keep at most this many keys, and this many tags, in memory. Counts can then be too high,
+    /// by up to [`TagCount::max_error`]. Must be set before adding objects.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        let max_entries = Some(max_entries.max(1));
+        self.keys = FrequencyTable::new(max_entries);
+        self.tags = FrequencyTable::new(max_entries);
+        self
+    }
+
+    /// Count the tags of this object
+    pub fn add(&mut self, obj: &impl OSMObjBase) {
+        self.num_objects += 1;
+        let object_type = obj.object_type();
+        for (k, v) in obj.tags() {
+            self.keys.add(k, object_type, |k| Arc::from(k));
+            if self.count_values {
+                let tag: &dyn TagKey = &(k, v);
+                self.tags.add(tag, object_type, |tag| {
+                    let (k, v) = tag.pair();
+                    Arc::new((k.to_string(), v.to_string()))
+                });
+            }
+        }
+    }
+
+    /// Count the tags of every object in this reader, and return the report. Returns an error if
+    /// the file is invalid.
+    pub fn count(mut self, reader: &mut impl OSMReader) -> Result<TagFrequencyReport, Error> {
+        for obj in reader.try_objects() {
+            self.add(&obj?);
+        }
+        Ok(self.finish())
+    }
+
+    /// Return the report
+    pub fn finish(self) -> TagFrequencyReport {
+        let per_type = self.per_type;
+        let tag_count = |key: String, value: Option<String>, entry: Entry| TagCount {
+            key,
+            value,
+            count: entry.count,
+            by_type: if per_type { Some(entry.by_type) } else { None },
+            max_error: entry.max_error,
+        };
+        TagFrequencyReport {
+            num_objects: self.num_objects,
+            keys: self
+                .keys
+                .into_sorted()
+                .into_iter()
+                .map(|(k, e)| tag_count(k.to_string(), None, e))
+                .collect(),
+            tags: self
+                .tags
+                .into_sorted()
+                .into_iter()
+                .map(|(tag, e)| {
+                    let (k, v) = Arc::try_unwrap(tag).unwrap_or_else(|tag| (*tag).clone());
+                    tag_count(k, Some(v), e)
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xml::XMLReader;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="1" lon="1"><tag k="amenity" v="pub"/><tag k="name" v="A"/></node>
+<node id="2" lat="1" lon="1"><tag k="amenity" v="pub"/><tag k="name" v="B"/></node>
+<node id="3" lat="1" lon="1"><tag k="amenity" v="cafe"/></node>
+<way id="4"><nd ref="1"/><nd ref="2"/><tag k="amenity" v="pub"/><tag k="building" v="yes"/></way>
+</osm>"#;
+
+    #[test]
+    fn exact() {
+        let report = TagFrequencyCounter::new()
+            .per_type(true)
+            .count(&mut XMLReader::new(INPUT.as_bytes()))
+            .unwrap();
+        assert_eq!(report.num_objects, 4);
+        assert_eq!(report.keys[0].key, "amenity");
+        assert_eq!(report.keys[0].count, 4);
+        assert_eq!(
+            report.keys[0].by_type,
+            Some(TypeCounts {
+                nodes: 3,
+                ways: 1,
+                relations: 0
+            })
+        );
+        assert_eq!(report.key("name").unwrap().count, 2);
+        assert_eq!(report.tag("amenity", "pub").unwrap().count, 3);
+        assert_eq!(report.tags[0].value.as_deref(), Some("pub"));
+        assert_eq!(report.tags.len(), 5);
+        assert!(report.tags.iter().all(|t| t.max_error == 0));
+    }
+
+    #[test]
+    fn bounded() {
+        let report = TagFrequencyCounter::new()
+            .max_entries(2)
+            .count(&mut XMLReader::new(INPUT.as_bytes()))
+            .unwrap();
+        assert_eq!(report.keys.len(), 2);
+        assert_eq!(report.tags.len(), 2);
+        // The most common is still found, & counts are never too low
+        let pub_count = report.tag("amenity", "pub").unwrap();
+        assert!(pub_count.count >= 3 && pub_count.count - pub_count.max_error <= 3);
+        assert_eq!(report.key("amenity").unwrap().count, 4);
+        assert_eq!(report.top(1).keys.len(), 1);
+    }
+
+    #[test]
+    fn invalid() {
+        let input = r#"<osm version="0.6"><node id="1" lat="1" lon="1"/><node id="x"/></osm>"#;
+        assert!(TagFrequencyCounter::new()
+            .count(&mut XMLReader::new(input.as_bytes()))
+            .is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Parsing common tag value syntaxes.
+//!
+//! OSM tag values are free text, but many keys have conventional formats: numbers with optional
+//! units (`maxspeed=50 mph`, `maxheight=3.5`, `maxheight=12'6"`), booleans (`oneway=yes`), and
+//! multiple values separated by semicolons (`ref=A1;A2`). These are also available on objects with
+//! [`OSMObjBase::tag_bool`](crate::OSMObjBase::tag_bool),
+//! [`OSMObjBase::tag_quantity`](crate::OSMObjBase::tag_quantity) &
+//! [`OSMObjBase::tag_values`](crate::OSMObjBase::tag_values).
+//!
+//! ```rust
+//! use osmio::tag_values::{parse_bool, parse_quantity, split_values};
+//!
+//! assert_eq!(parse_bool("yes"), Some(true));
+//! assert_eq!(parse_quantity("50 mph").unwrap().to_kmh().unwrap().round(), 80.);
+//! assert_eq!(split_values("A1; A2").collect::<Vec<_>>(), vec!["A1", "A2"]);
+//! ```
+
+/// Parse a boolean value: `yes`, `true` & `1` are true, and `no`, `false` & `0` are false
+/// (ignoring case & surrounding whitespace). Anything else (e.g. `oneway=-1`) is `None`.
+pub fn parse_bool(value: &str) -> Option<bool> {
+    let value = value.trim();
+    if ["yes", "true", "1"]
+        .iter()
+        .any(|v| value.eq_ignore_ascii_case(v))
+    {
+        Some(true)
+    } else if ["no", "false", "0"]
+        .iter()
+        .any(|v| value.eq_ignore_ascii_case(v))
+    {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// The values of a semicolon separated list, with surrounding whitespace and empty values removed
+pub fn split_values(value: &str) -> impl Iterator<Item = &str> {
+    value.split(';').map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// True iff this is an `opening_hours` value, rather than empty or a placeholder like `unknown`.
+/// The syntax isn't checked.
+pub fn is_opening_hours(value: &str) -> bool {
+    let value = value.trim();
+    !(value.is_empty()
+        || value.eq_ignore_ascii_case("unknown")
+        || value.eq_ignore_ascii_case("fixme"))
+}
+
+/// A number with an optional unit, e.g. `50 mph`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quantity<'a> {
+    pub value: f64,
+    /// The unit, as written. `None` if there's no unit, which means the default unit of the key
+    /// (e.g. km/h for `maxspeed`, metres for `maxheight`, tonnes for `maxweight`). Feet & inches
+    /// (`12'6"`) are converted to feet, with the unit `ft`.
+    pub unit: Option<&'a str>,
+}
+
+impl<'a> Quantity<'a> {
+    /// The value in km/h, if the unit is a speed (or there's no unit)
+    pub fn to_kmh(&self) -> Option<f64> {
+        let factor = match self.unit {
+            None | Some("km/h") | Some("kmh") | Some("kph") => 1.,
+            Some("mph") => 1.609_344,
+            Some("knots") => 1.852,
+            _ => return None,
+        };
+        Some(self.value * factor)
+    }
+
+    /// The value in metres, if the unit is a length (or there's no unit)
+    pub fn to_metres(&self) -> Option<f64> {
+        let factor = match self.unit {
+            None | Some("m") => 1.,
+            Some("km") => 1000.,
+            Some("cm") => 0.01,
+            Some("mi") => 1609.344,
+            Some("nmi") => 1852.,
+            Some("ft") => 0.3048,
+            _ => return None,
+        };
+        Some(self.value * factor)
+    }
+
+    /// The value in tonnes, if the unit is a weight (or there's no unit)
+    pub fn to_tonnes(&self) -> Option<f64> {
+        let factor = match self.unit {
+            None | Some("t") => 1.,
+            Some("kg") => 0.001,
+            Some("st") => 0.907_184_74,
+            Some("lbs") => 0.000_453_592_37,
+            _ => return None,
+        };
+        Some(self.value * factor)
+    }
+}
+
+/// Parse a number with an optional unit, e.g. `50`, `50 mph`, `3.5m` or `12'6"`. Returns `None`
+/// for values which aren't numbers, like `maxspeed=walk` or `maxspeed=DE:urban`.
+pub fn parse_quantity(value: &str) -> Option<Quantity<'_>> {
+    let value = value.trim();
+    let number_len = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(value.len());
+    let number: f64 = value[..number_len].parse().ok()?;
+    if !number.is_finite() {
+        return None;
+    }
+    let rest = value[number_len..].trim_start();
+    if rest.is_empty() {
+        return Some(Quantity {
+            value: number,
+            unit: None,
+        });
+    }
+    if let Some(inches) = rest.strip_prefix('\'') {
+        let inches = inches.trim();
+        let inches: f64 = match inches.strip_suffix('"') {
+            Some(inches) => inches.trim().parse().ok()?,
+            None if inches.is_empty() => 0.,
+            None => return None,
+        };
+        return Some(Quantity {
+            value: number + inches / 12.,
+            unit: Some("ft"),
+        });
+    }
+    if let Some(inches) = rest.strip_suffix('"') {
+        if inches.is_empty() {
+            return Some(Quantity {
+                value: number / 12.,
+                unit: Some("ft"),
+            });
+        }
+    }
+    if rest.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Quantity {
+        value: number,
+        unit: Some(rest),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bools() {
+        assert_eq!(parse_bool("yes"), Some(true));
+        assert_eq!(parse_bool(" True"), Some(true));
+        assert_eq!(parse_bool("1"), Some(true));
+        assert_eq!(parse_bool("no"), Some(false));
+        assert_eq!(parse_bool("0"), Some(false));
+        assert_eq!(parse_bool("-1"), None);
+        assert_eq!(parse_bool("designated"), None);
+    }
+
+    #[test]
+    fn multi_values() {
+        assert_eq!(
+            split_values("A1;A2 ; ;B3").collect::<Vec<_>>(),
+            vec!["A1", "A2", "B3"]
+        );
+        assert_eq!(split_values("").count(), 0);
+        assert!(is_opening_hours("Mo-Fr 09:00-17:00"));
+        assert!(!is_opening_hours(" "));
+        assert!(!is_opening_hours("unknown"));
+    }
+
+    #[test]
+    fn quantities() {
+        let q = |v: f64, unit: Option<&'static str>| Some(Quantity { value: v, unit });
+        assert_eq!(parse_quantity("50"), q(50., None));
+        assert_eq!(parse_quantity("50 mph"), q(50., Some("mph")));
+        assert_eq!(parse_quantity("3.5m"), q(3.5, Some("m")));
+        assert_eq!(parse_quantity("12'6\""), q(12.5, Some("ft")));
+        assert_eq!(parse_quantity("7'"), q(7., Some("ft")));
+        assert_eq!(parse_quantity("walk"), None);
+        assert_eq!(parse_quantity("DE:urban"), None);
+        assert_eq!(parse_quantity("50 or 60"), None);
+
+        let approx = |a: Option<f64>, b: f64| assert!((a.unwrap() - b).abs() < 1e-9, "{:?}", a);
+        approx(parse_quantity("20 knots").unwrap().to_kmh(), 37.04);
+        approx(parse_quantity("1.5 km").unwrap().to_metres(), 1500.);
+        approx(parse_quantity("6'").unwrap().to_metres(), 1.8288);
+        approx(parse_quantity("7500 kg").unwrap().to_tonnes(), 7.5);
+        assert_eq!(parse_quantity("50 mph").unwrap().to_metres(), None);
+    }
+}
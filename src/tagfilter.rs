@@ -0,0 +1,462 @@
+//! Filtering objects by their tags, with `osmium tags-filter` style expressions.
+//!
+//! Each expression is `[TYPES/]CONDITION[,CONDITION...]`:
+//!
+//! * `TYPES` is any of `n`, `w` & `r` (e.g. `nw/`). Without it, all types match.
+//! * `key` matches if the object has that tag, `!key` if it doesn't.
+//! * `key=value` matches if the object has that tag with that value, and `key!=value` if it
+//!   doesn't. Several values can be separated by `|` (e.g. `highway=primary|secondary`).
+//! * A key or value ending in `*` matches anything starting with that (e.g. `tiger:*`), and `*`
+//!   on its own matches anything.
+//! * Spaces & the characters above can be part of a key or value by quoting them with `"` (e.g.
+//!   `name="Main Street"`) or escaping them with `\` (e.g. `name=Main\ Street`). A quoted `*`
+//!   is just a `*`. A `/` after the `=` is part of the value, so `opening_hours=24/7` works
+//!   without quotes.
+//!
+//! All the conditions in an expression must match, and an object matches a [`TagFilter`] if any
+//! of its expressions match.
+//!
+//! ```rust
+//! use osmio::tagfilter::TagFilter;
+//! use osmio::OSMObjectType;
+//!
+//! let filter: TagFilter = "w/highway n/amenity=bench r/type=route,route=bicycle".parse()?;
+//! assert!(filter.matches_tags(OSMObjectType::Way, vec![("highway", "primary")].into_iter()));
+//! assert!(!filter.matches_tags(OSMObjectType::Node, vec![("highway", "primary")].into_iter()));
+//! assert!(!filter.matches_tags(
+//!     OSMObjectType::Relation,
+//!     vec![("type", "route"), ("route", "bus")].into_iter()
+//! ));
+//! # Ok::<(), osmio::tagfilter::ParseTagFilterError>(())
+//! ```
+//!
+//! Filters can be used with any reader with [`OSMReader::filter_tags`](crate::OSMReader::filter_tags).
+//! The PBF reader can also apply them while decoding (with
+//! [`PBFReader::set_tag_filter`](crate::pbf::PBFReader::set_tag_filter)), which skips building
+//! the objects which don't match.
+//!
+//! Objects which are referred to by a matching object aren't included, use
+//! [`getid::add_referenced`](crate::getid::add_referenced) for that.
+use std::convert::TryFrom;
+use std::str::FromStr;
+use {OSMObjBase, OSMObjectType};
+
+/// A string to match a key or value against
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Pattern {
+    Any,
+    Exact(String),
+    Prefix(String),
+}
+
+/// One character of an expression, after removing quotes & escapes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    /// Part of a key or value
+    Char(char),
+    /// An unquoted, unescaped character with a meaning in the syntax, one of [`SYNTAX`]
+    Syntax(char),
+}
+
+/// Characters which are [`Token::Syntax`] unless they're quoted or escaped
+const SYNTAX: &str = "/,=!|*";
+
+impl Token {
+    fn char(self) -> char {
+        match self {
+            Token::Char(c) | Token::Syntax(c) => c,
+        }
+    }
+}
+
+/// The text of these tokens
+fn text(tokens: &[Token]) -> String {
+    tokens.iter().map(|t| t.char()).collect()
+}
+
+/// Remove the quotes & escapes from `s`, and split it into expressions. With `one_expression`,
+/// all of `s` is one expression. Otherwise expressions are separated by whitespace, and a `#`
+/// starts a comment which goes to the end of the line.
+fn tokenize(s: &str, one_expression: bool) -> Result<Vec<Vec<Token>>, ParseTagFilterError> {
+    let mut expressions = Vec::new();
+    let mut expression = Vec::new();
+    // True once anything (even just `""`) has been read of the current expression
+    let mut started = one_expression;
+    let mut quoted = false;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(c) => expression.push(Token::Char(c)),
+                None => return Err(ParseTagFilterError::TrailingBackslash),
+            },
+            '"' => quoted = !quoted,
+            _ if quoted => expression.push(Token::Char(c)),
+            '#' if !one_expression => {
+                chars.by_ref().find(|&c| c == '\n');
+            }
+            _ if c.is_whitespace() && !one_expression => {}
+            _ if SYNTAX.contains(c) => expression.push(Token::Syntax(c)),
+            _ => expression.push(Token::Char(c)),
+        }
+        if c == '"' || c == '\\' || !expression.is_empty() {
+            started = true;
+        }
+        let ended = !quoted && (c == '#' || c.is_whitespace()) && !one_expression;
+        if ended && started {
+            expressions.push(std::mem::take(&mut expression));
+            started = false;
+        }
+    }
+    if quoted {
+        return Err(ParseTagFilterError::UnterminatedQuote);
+    }
+    if started {
+        expressions.push(expression);
+    }
+    Ok(expressions)
+}
+
+impl Pattern {
+    pub(crate) fn parse(s: &str) -> Self {
+        if s == "*" {
+            Pattern::Any
+        } else if let Some(prefix) = s.strip_suffix('*') {
+            Pattern::Prefix(prefix.to_string())
+        } else {
+            Pattern::Exact(s.to_string())
+        }
+    }
+
+    /// The pattern for these tokens. Only an unquoted `*` is a wildcard.
+    fn from_tokens(tokens: &[Token]) -> Self {
+        match tokens {
+            [Token::Syntax('*')] => Pattern::Any,
+            [prefix @ .., Token::Syntax('*')] => Pattern::Prefix(text(prefix)),
+            _ => Pattern::Exact(text(tokens)),
+        }
+    }
+
+    pub(crate) fn matches(&self, s: &str) -> bool {
+        match self {
+            Pattern::Any => true,
+            Pattern::Exact(e) => s == e,
+            Pattern::Prefix(p) => s.starts_with(p.as_str()),
+        }
+    }
+}
+
+/// One condition, e.g. `highway=primary|secondary`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Condition {
+    key: Pattern,
+    /// `None` means any value
+    values: Option<Vec<Pattern>>,
+    negated: bool,
+}
+
+impl Condition {
+    fn parse(tokens: &[Token]) -> Result<Self, ParseTagFilterError> {
+        let invalid = || ParseTagFilterError::InvalidCondition(text(tokens));
+        let not_equal = [Token::Syntax('!'), Token::Syntax('=')];
+        let (key, values, negated) = if let Some(i) = tokens.windows(2).position(|w| w == not_equal)
+        {
+            (&tokens[..i], Some(&tokens[i + 2..]), true)
+        } else if let Some(i) = tokens.iter().position(|&t| t == Token::Syntax('=')) {
+            (&tokens[..i], Some(&tokens[i + 1..]), false)
+        } else if let Some((Token::Syntax('!'), key)) = tokens.split_first() {
+            (key, None, true)
+        } else {
+            (tokens, None, false)
+        };
+        if key.is_empty() || values.is_some_and(|v| v.is_empty()) {
+            return Err(invalid());
+        }
+        Ok(Condition {
+            key: Pattern::from_tokens(key),
+            values: values.map(|v| {
+                v.split(|&t| t == Token::Syntax('|'))
+                    .map(Pattern::from_tokens)
+                    .collect()
+            }),
+            negated,
+        })
+    }
+
+    fn matches<'a>(&self, tags: impl Iterator<Item = (&'a str, &'a str)>) -> bool {
+        let mut tags = tags.filter(|(k, _)| self.key.matches(k));
+        let found = match &self.values {
+            None => tags.next().is_some(),
+            Some(values) => tags.any(|(_, v)| values.iter().any(|p| p.matches(v))),
+        };
+        found != self.negated
+    }
+}
+
+/// One expression, e.g. `w/highway,name`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expression {
+    /// Whether this matches nodes, ways & relations
+    types: [bool; 3],
+    conditions: Vec<Condition>,
+}
+
+impl Expression {
+    fn parse(tokens: &[Token]) -> Result<Self, ParseTagFilterError> {
+        // The object types end at the first `/`, unless a condition has already started
+        let end = tokens
+            .iter()
+            .position(|t| matches!(t, Token::Syntax('/' | '=' | ',')));
+        let (types, conditions) = match end {
+            Some(end) if end > 0 && tokens[end] == Token::Syntax('/') => {
+                let mut type_mask = [false; 3];
+                for c in tokens[..end].iter().map(|t| t.char()) {
+                    let object_type = OSMObjectType::try_from(c)
+                        .map_err(|_| ParseTagFilterError::InvalidObjectType(c))?;
+                    type_mask[object_type.index()] = true;
+                }
+                (type_mask, &tokens[end + 1..])
+            }
+            _ => ([true; 3], tokens),
+        };
+        let conditions = conditions
+            .split(|&t| t == Token::Syntax(','))
+            .map(Condition::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Expression { types, conditions })
+    }
+}
+
+/// Parse one expression. Quotes & escapes are removed, but whitespace is part of the keys &
+/// values.
+impl FromStr for Expression {
+    type Err = ParseTagFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s, true)?;
+        Expression::parse(&tokens[0])
+    }
+}
+
+/// A compiled set of tag filter expressions. See the [module documentation](self) for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TagFilter {
+    expressions: Vec<Expression>,
+}
+
+impl TagFilter {
+    /// Compile these expressions. An object matches if it matches any of them.
+    pub fn new(
+        expressions: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Self, ParseTagFilterError> {
+        let mut filter = TagFilter::default();
+        for expression in expressions {
+            filter.add_expression(expression.as_ref())?;
+        }
+        Ok(filter)
+    }
+
+    /// Add another expression
+    pub fn add_expression(&mut self, expression: &str) -> Result<(), ParseTagFilterError> {
+        self.expressions.push(expression.parse()?);
+        Ok(())
+    }
+
+    /// True iff there are no expressions, so nothing matches
+    pub fn is_empty(&self) -> bool {
+        self.expressions.is_empty()
+    }
+
+    /// True iff some objects of this type could match
+    pub fn matches_type(&self, object_type: OSMObjectType) -> bool {
+        let idx = object_type.index();
+        self.expressions.iter().any(|e| e.types[idx])
+    }
+
+    /// True iff an object of this type, with these tags, matches.
+    pub fn matches_tags<'a>(
+        &self,
+        object_type: OSMObjectType,
+        tags: impl Iterator<Item = (&'a str, &'a str)> + Clone,
+    ) -> bool {
+        let idx = object_type.index();
+        self.expressions
+            .iter()
+            .any(|e| e.types[idx] && e.conditions.iter().all(|c| c.matches(tags.clone())))
+    }
+
+    /// True iff this object matches
+    pub fn matches(&self, obj: &impl OSMObjBase) -> bool {
+        let idx = obj.object_type().index();
+        self.expressions
+            .iter()
+            .any(|e| e.types[idx] && e.conditions.iter().all(|c| c.matches(obj.tags())))
+    }
+}
+
+/// Parse whitespace separated expressions (whitespace in a key or value must be quoted or
+/// escaped). Anything after an unquoted `#` on a line is ignored, so this can read the
+/// expression files used by `osmium tags-filter -e`.
+impl FromStr for TagFilter {
+    type Err = ParseTagFilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expressions = tokenize(s, false)?
+            .iter()
+            .map(|tokens| Expression::parse(tokens))
+            .collect::<Result<_, _>>()?;
+        Ok(TagFilter { expressions })
+    }
+}
+
+/// An error while trying to parse a tag filter expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseTagFilterError {
+    /// This isn't one of `n`, `w` or `r`
+    InvalidObjectType(char),
+    /// This condition isn't valid (e.g. it has no key)
+    InvalidCondition(String),
+    /// A `"` is never closed
+    UnterminatedQuote,
+    /// The expression ends with a `\`, so there's nothing to escape
+    TrailingBackslash,
+}
+
+impl std::fmt::Display for ParseTagFilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseTagFilterError::InvalidObjectType(c) => {
+                write!(f, "Invalid object type {:?}, expected n, w or r", c)
+            }
+            ParseTagFilterError::InvalidCondition(s) => {
+                write!(f, "Invalid tag filter condition {:?}", s)
+            }
+            ParseTagFilterError::UnterminatedQuote => write!(f, "Unterminated quote"),
+            ParseTagFilterError::TrailingBackslash => {
+                write!(f, "Tag filter ends with a backslash")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseTagFilterError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use OSMObjectType::*;
+
+    fn matches(filter: &str, object_type: OSMObjectType, tags: &[(&str, &str)]) -> bool {
+        let filter: TagFilter = filter.parse().unwrap();
+        filter.matches_tags(object_type, tags.iter().copied())
+    }
+
+    #[test]
+    fn conditions() {
+        assert!(matches("highway", Way, &[("highway", "primary")]));
+        assert!(matches("highway", Node, &[("highway", "crossing")]));
+        assert!(!matches("highway", Way, &[("building", "yes")]));
+        assert!(matches("!highway", Way, &[("building", "yes")]));
+        assert!(matches(
+            "highway=primary|secondary",
+            Way,
+            &[("highway", "secondary")]
+        ));
+        assert!(!matches("highway!=primary", Way, &[("highway", "primary")]));
+        assert!(matches("highway!=primary", Way, &[]));
+        assert!(matches("tiger:*", Way, &[("tiger:cfcc", "A41")]));
+        assert!(matches("*=yes", Way, &[("building", "yes")]));
+        assert!(matches(
+            "name:*=Dublin*",
+            Node,
+            &[("name:en", "Dublin City")]
+        ));
+    }
+
+    #[test]
+    fn expressions() {
+        let filter = "w/highway n/amenity=bench r/type=route,route=bicycle # comment\n";
+        assert!(matches(filter, Way, &[("highway", "primary")]));
+        assert!(!matches(filter, Node, &[("highway", "primary")]));
+        assert!(matches(filter, Node, &[("amenity", "bench")]));
+        assert!(matches(
+            filter,
+            Relation,
+            &[("type", "route"), ("route", "bicycle")]
+        ));
+        assert!(!matches(filter, Relation, &[("type", "route")]));
+        assert!(!matches("", Node, &[("amenity", "bench")]));
+
+        let filter: TagFilter = filter.parse().unwrap();
+        assert!(filter.matches_type(Node));
+        assert!(!TagFilter::new(vec!["nw/highway"])
+            .unwrap()
+            .matches_type(Relation));
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            "x/highway".parse::<TagFilter>(),
+            Err(ParseTagFilterError::InvalidObjectType('x'))
+        );
+        assert_eq!(
+            "w/=primary".parse::<TagFilter>(),
+            Err(ParseTagFilterError::InvalidCondition(
+                "=primary".to_string()
+            ))
+        );
+        assert!("w/highway=".parse::<TagFilter>().is_err());
+        assert!("w/highway,,name".parse::<TagFilter>().is_err());
+        assert!(r#"w/highway """#.parse::<TagFilter>().is_err());
+        assert_eq!(
+            r#"name="Main Street"#.parse::<TagFilter>(),
+            Err(ParseTagFilterError::UnterminatedQuote)
+        );
+        assert_eq!(
+            r#"name=Main\"#.parse::<TagFilter>(),
+            Err(ParseTagFilterError::TrailingBackslash)
+        );
+    }
+
+    #[test]
+    fn quoting() {
+        let main_street = &[("name", "Main Street")];
+        assert!(matches(r#"name="Main Street""#, Way, main_street));
+        assert!(matches(r#"name=Main" "Street"#, Way, main_street));
+        assert!(matches(r#"w/name=Main\ Street"#, Way, main_street));
+        assert!(matches(r#"highway name="Main Street""#, Way, main_street));
+        assert!(!matches(r#"name="Main Street""#, Way, &[("name", "Main")]));
+        // One expression, so the whitespace doesn't need quoting
+        assert!(TagFilter::new(vec!["w/name=Main Street"])
+            .unwrap()
+            .matches_tags(Way, main_street.iter().copied()));
+
+        // Quoted syntax is part of the key or value
+        assert!(matches(r#"name="a,b|c!=d""#, Node, &[("name", "a,b|c!=d")]));
+        assert!(matches(r#""a=b""#, Node, &[("a=b", "c")]));
+        assert!(matches(r#"name="Bar*""#, Node, &[("name", "Bar*")]));
+        assert!(!matches(r#"name="Bar*""#, Node, &[("name", "Bar none")]));
+        assert!(matches(r#"name=Bar\*"#, Node, &[("name", "Bar*")]));
+        assert!(matches(
+            r##"ref="#5" # comment "quoted"##,
+            Node,
+            &[("ref", "#5")]
+        ));
+    }
+
+    #[test]
+    fn slashes() {
+        let tags = &[("opening_hours", "24/7")];
+        assert!(matches("opening_hours=24/7", Node, tags));
+        assert!(matches("nw/opening_hours=24/7", Way, tags));
+        assert!(!matches("w/opening_hours=24/7", Node, tags));
+        assert!(matches("opening_hours=24/7|Mo-Fr", Node, tags));
+        assert!(matches(
+            "maxspeed,opening_hours=24/7",
+            Node,
+            &[("maxspeed", "50"), ("opening_hours", "24/7")]
+        ));
+        assert!(matches(r#"n/"a/b""#, Node, &[("a/b", "yes")]));
+    }
+}
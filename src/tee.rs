@@ -0,0 +1,118 @@
+//! Writing objects to a file while they're being read.
+//!
+//! A [`TeeReader`] passes every object from a reader through unchanged, and also writes it to a
+//! writer, like the `tee` command. This can save a copy of exactly what a long pipeline read.
+//!
+//! ```no_run
+//! use osmio::tee::TeeReader;
+//! use osmio::xml::XMLWriter;
+//! use osmio::{OSMObjBase, OSMWriter};
+//!
+//! let reader = osmio::read_pbf("input.osm.pbf")?;
+//! let writer = XMLWriter::new(std::fs::File::create("snapshot.osm")?);
+//! let mut tee = TeeReader::new(reader, writer);
+//! let num_tagged = tee.by_ref().filter(|o| o.tagged()).count();
+//! let (_reader, mut writer) = tee.finish()?;
+//! writer.close()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use std::io::Write;
+use std::marker::PhantomData;
+use {OSMReader, OSMWriteError, OSMWriter};
+
+/// Iterator over the objects of a reader, which also writes every object to a writer.
+///
+/// If writing fails, iteration stops, and the error is returned by [`TeeReader::finish`].
+pub struct TeeReader<Rd: OSMReader, W: Write, Wr: OSMWriter<W>> {
+    reader: Rd,
+    writer: Wr,
+    error: Option<OSMWriteError>,
+    _writer: PhantomData<W>,
+}
+
+impl<Rd: OSMReader, W: Write, Wr: OSMWriter<W>> TeeReader<Rd, W, Wr> {
+    /// Read from `reader`, and write every object to `writer`
+    pub fn new(reader: Rd, writer: Wr) -> Self {
+        TeeReader {
+            reader,
+            writer,
+            error: None,
+            _writer: PhantomData,
+        }
+    }
+
+    /// The reader
+    pub fn reader(&self) -> &Rd {
+        &self.reader
+    }
+
+    /// The writer
+    pub fn writer(&self) -> &Wr {
+        &self.writer
+    }
+
+    /// The error from writing, if there was one
+    pub fn error(&self) -> Option<&OSMWriteError> {
+        self.error.as_ref()
+    }
+
+    /// Return the reader & writer (which isn't closed), or the error from writing.
+    pub fn finish(self) -> Result<(Rd, Wr), OSMWriteError> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok((self.reader, self.writer)),
+        }
+    }
+}
+
+impl<Rd: OSMReader, W: Write, Wr: OSMWriter<W>> Iterator for TeeReader<Rd, W, Wr> {
+    type Item = Rd::Obj;
+
+    fn next(&mut self) -> Option<Rd::Obj> {
+        if self.error.is_some() {
+            return None;
+        }
+        let obj = self.reader.next()?;
+        if let Err(e) = self.writer.write_obj(&obj) {
+            self.error = Some(e);
+            return None;
+        }
+        Some(obj)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use xml::{XMLReader, XMLWriter};
+    use OSMObjBase;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1"/>
+<node id="2" version="1" lat="1" lon="1"/>
+<node id="3" version="1" lat="1" lon="1"/>
+</osm>"#;
+
+    #[test]
+    fn tee() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("copy.osm");
+        let mut tee = TeeReader::new(
+            XMLReader::new(INPUT.as_bytes()),
+            XMLWriter::new(File::create(&path).unwrap()),
+        );
+        let ids: Vec<_> = tee.by_ref().take(2).map(|o| o.id()).collect();
+        assert_eq!(ids, vec![1, 2]);
+        let (_reader, mut writer) = tee.finish().unwrap();
+        writer.close().unwrap();
+
+        // Only what was read is written
+        let copied: Vec<_> = XMLReader::new(File::open(&path).unwrap())
+            .objects()
+            .map(|o| o.id())
+            .collect();
+        assert_eq!(copied, vec![1, 2]);
+    }
+}
@@ -85,6 +85,157 @@ mod timestamp_format {
         TimestampFormat::EpochNunber(1577836800),
         Equal
     );
+
+    assert_cmp!(
+        iso_offsets,
+        "2020-01-01T00:00:00Z".parse().unwrap(),
+        TimestampFormat::ISOString("2020-01-01T01:00:00+01:00".to_string()),
+        Equal
+    );
+    assert_cmp!(
+        iso_offsets2,
+        TimestampFormat::ISOString("2020-01-01T00:00:00Z".to_string()),
+        TimestampFormat::ISOString("2020-01-01T00:30:00+01:00".to_string()),
+        Greater
+    );
+    assert_cmp!(
+        invalid_after_valid,
+        TimestampFormat::ISOString("not a date".to_string()),
+        TimestampFormat::EpochNunber(i64::MAX),
+        Greater
+    );
+
+    #[test]
+    fn total_order() {
+        use std::collections::HashSet;
+        let mut timestamps = [
+            TimestampFormat::ISOString("2020-01-01T00:00:02Z".to_string()),
+            TimestampFormat::EpochNunber(1577836801),
+            TimestampFormat::ISOString("bad".to_string()),
+            TimestampFormat::ISOString("2020-01-01T00:00:00Z".to_string()),
+        ];
+        timestamps.sort();
+        let epochs: Vec<i64> = timestamps.iter().map(|t| t.to_epoch_number()).collect();
+        assert_eq!(epochs, vec![1577836800, 1577836801, 1577836802, 0]);
+
+        let set: HashSet<TimestampFormat> = vec![
+            TimestampFormat::EpochNunber(1577836800),
+            TimestampFormat::ISOString("2020-01-01T00:00:00Z".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(set.len(), 1);
+
+        assert_eq!(
+            TimestampFormat::EpochNunber(1).max(TimestampFormat::ISOString(
+                "1970-01-01T00:00:02Z".to_string()
+            )),
+            TimestampFormat::EpochNunber(2)
+        );
+    }
+
+    #[test]
+    fn iso_round_trip() {
+        for epoch in [0, 1, 951782400, 1577836800, -86_401, 4_102_444_800] {
+            let iso = TimestampFormat::EpochNunber(epoch).to_iso_string();
+            assert_eq!(
+                TimestampFormat::ISOString(iso).try_to_epoch_number(),
+                Ok(epoch)
+            );
+        }
+        assert_eq!(
+            TimestampFormat::EpochNunber(951782400).to_iso_string(),
+            "2000-02-29T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn iso_parse_errors() {
+        assert!("".parse::<TimestampFormat>().is_err());
+        assert!("2020-01-01".parse::<TimestampFormat>().is_err());
+        assert!("2020-01-01T00:00:00".parse::<TimestampFormat>().is_err());
+        assert!("2020-01-01T00:00:00.Z".parse::<TimestampFormat>().is_err());
+        assert_eq!(
+            "2019-02-29T00:00:00Z".parse::<TimestampFormat>(),
+            Err(ParseTimestampError::OutOfRange {
+                field: "day",
+                value: 29
+            })
+        );
+        assert_eq!(
+            TimestampFormat::ISOString("2020-01-01T25:00:00Z".to_string()).try_to_epoch_number(),
+            Err(ParseTimestampError::OutOfRange {
+                field: "hour",
+                value: 25
+            })
+        );
+        assert_eq!(
+            "2020-01-01T00:00:00.123Z".parse::<TimestampFormat>(),
+            Ok(TimestampFormat::EpochNunber(1577836800))
+        );
+        assert_eq!(
+            "2020-01-01T01:30:00+0130".parse::<TimestampFormat>(),
+            Ok(TimestampFormat::EpochNunber(1577836800))
+        );
+    }
+
+    #[test]
+    fn millis() {
+        let ts = TimestampFormat::EpochMillis(1577836800250);
+        assert_eq!(ts.to_iso_string(), "2020-01-01T00:00:00.250Z");
+        assert_eq!(ts.to_epoch_number(), 1577836800);
+        assert_eq!(
+            TimestampFormat::ISOString(ts.to_iso_string()).to_epoch_millis(),
+            1577836800250
+        );
+        assert_eq!(
+            TimestampFormat::EpochMillis(1577836800000).to_iso_string(),
+            "2020-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            TimestampFormat::EpochMillis(-1).to_iso_string(),
+            "1969-12-31T23:59:59.999Z"
+        );
+
+        // sub-second differences are kept when comparing
+        assert!(ts > TimestampFormat::EpochNunber(1577836800));
+        assert!(ts < TimestampFormat::EpochNunber(1577836801));
+        assert_eq!(
+            TimestampFormat::EpochMillis(1577836801000),
+            TimestampFormat::EpochNunber(1577836801)
+        );
+    }
+
+    #[test]
+    fn from_date_granularity() {
+        assert_eq!(
+            TimestampFormat::from_date_granularity(1577836800, 1000),
+            Ok(TimestampFormat::EpochNunber(1577836800))
+        );
+        assert!(matches!(
+            TimestampFormat::from_date_granularity(157783680025, 10),
+            Ok(TimestampFormat::EpochMillis(1577836800250))
+        ));
+        assert_eq!(
+            TimestampFormat::from_date_granularity(i64::MAX / 10, 1000),
+            Err(ParseTimestampError::OutOfRange {
+                field: "timestamp",
+                value: i64::MAX / 10
+            })
+        );
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_datetime() {
+        let ts: TimestampFormat = "2020-01-01T00:00:00Z".parse().unwrap();
+        let dt = ts.to_datetime().unwrap();
+        assert_eq!(dt.timestamp(), 1577836800);
+        assert_eq!(TimestampFormat::from_datetime(&dt), ts);
+        assert!(TimestampFormat::ISOString("garbage".to_string())
+            .to_datetime()
+            .is_err());
+    }
 }
 
 #[test]
@@ -167,3 +318,291 @@ fn area_ways() {
         </osm>"#;
     assert_closed_area!(closed_explicit_area_input, true, true);
 }
+
+#[test]
+fn reader_adaptors() {
+    use crate::xml::XMLReader;
+
+    let mut input =
+        String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<osm version=\"0.6\">\n");
+    for id in 1..=10 {
+        input.push_str(&format!("<node id=\"{}\" lat=\"0\" lon=\"0\"/>\n", id));
+    }
+    input.push_str("</osm>");
+
+    let ids: Vec<ObjId> = XMLReader::new(input.as_bytes())
+        .sample_every(4)
+        .map(|o| o.id())
+        .collect();
+    assert_eq!(ids, vec![1, 5, 9]);
+
+    let ids: Vec<ObjId> = XMLReader::new(input.as_bytes())
+        .take_objects(2)
+        .map(|o| o.id())
+        .collect();
+    assert_eq!(ids, vec![1, 2]);
+
+    let ids: Vec<ObjId> = XMLReader::new(input.as_bytes())
+        .filter_map_objs(|o| if o.id() % 5 == 0 { Some(o.id()) } else { None })
+        .collect();
+    assert_eq!(ids, vec![5, 10]);
+}
+
+#[test]
+fn modified_since() {
+    use crate::xml::XMLReader;
+
+    let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="0" lon="0" timestamp="2020-01-01T00:00:00Z"/>
+<node id="2" lat="0" lon="0" timestamp="2021-01-01T00:00:00Z"/>
+<node id="3" lat="0" lon="0" timestamp="2021-06-01T00:00:00.500Z"/>
+<node id="4" lat="0" lon="0"/>
+</osm>"#;
+
+    // 2021-01-01T00:00:00Z, as an epoch number
+    let ids: Vec<ObjId> = XMLReader::new(input.as_bytes())
+        .modified_since(1609459200)
+        .map(|o| o.id())
+        .collect();
+    assert_eq!(ids, vec![2, 3]);
+
+    let since: TimestampFormat = "2021-06-01T00:00:00.500Z".parse().unwrap();
+    let ids: Vec<ObjId> = XMLReader::new(input.as_bytes())
+        .modified_since(since)
+        .map(|o| o.id())
+        .collect();
+    assert_eq!(ids, vec![3]);
+}
+
+#[test]
+fn try_objects() {
+    use crate::xml::XMLReader;
+
+    let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="0" lon="0"/>
+<node id="2" lat="0" lon="0">
+</osm>"#;
+    let results: Vec<_> = XMLReader::new(input.as_bytes()).try_objects().collect();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].as_ref().unwrap().id(), 1);
+    assert!(results[1].is_err());
+
+    // Truncated PBF files are errors, empty ones are not
+    let truncated: &[u8] = &[0, 0, 0, 10, 1, 2, 3];
+    let mut reader = arcpbf::PBFReader::new(truncated);
+    assert!(matches!(
+        reader.try_next(),
+        Err(crate::Error::Format {
+            offset: Some(0),
+            ..
+        })
+    ));
+    let mut reader = stringpbf::PBFReader::new(truncated);
+    assert!(matches!(
+        reader.try_next(),
+        Err(crate::Error::Format {
+            offset: Some(0),
+            ..
+        })
+    ));
+    // A valid blob header, but the blob isn't valid protobuf
+    let header = [
+        0x0a, 0x07, b'O', b'S', b'M', b'D', b'a', b't', b'a', 0x18, 0x02,
+    ];
+    let mut bad_blob = vec![0, 0, 0, header.len() as u8];
+    bad_blob.extend_from_slice(&header);
+    bad_blob.extend_from_slice(&[0xff, 0xff]);
+    let err = arcpbf::PBFReader::new(&bad_blob[..])
+        .try_next()
+        .unwrap_err();
+    assert!(matches!(err, crate::Error::Protobuf { offset: 0, .. }));
+    assert!(err
+        .to_string()
+        .starts_with("Invalid protobuf message at byte 0"));
+    let mut reader = arcpbf::PBFReader::new(&[][..]);
+    assert!(reader.try_next().unwrap().is_none());
+}
+
+#[test]
+fn typed_iterators() {
+    use crate::xml::XMLReader;
+
+    // Not actually sorted, to show where reading stops
+    let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="0" lon="0"/>
+<way id="10"><nd ref="1"/></way>
+<node id="2" lat="0" lon="0"/>
+<relation id="20"><member type="node" ref="1" role=""/></relation>
+<way id="11"><nd ref="1"/></way>
+</osm>"#;
+
+    let mut reader = XMLReader::new(input.as_bytes());
+    assert_eq!(
+        reader.nodes().map(|n| n.id()).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+    let mut reader = XMLReader::new(input.as_bytes());
+    assert_eq!(
+        reader.ways().map(|w| w.id()).collect::<Vec<_>>(),
+        vec![10, 11]
+    );
+    let mut reader = XMLReader::new(input.as_bytes());
+    assert_eq!(
+        reader.relations().map(|r| r.id()).collect::<Vec<_>>(),
+        vec![20]
+    );
+
+    let mut reader = XMLReader::new(input.as_bytes());
+    reader.assume_sorted();
+    assert_eq!(reader.nodes().map(|n| n.id()).collect::<Vec<_>>(), vec![1]);
+    let mut reader = XMLReader::new(input.as_bytes());
+    reader.assume_sorted();
+    assert_eq!(reader.ways().map(|w| w.id()).collect::<Vec<_>>(), vec![10]);
+}
+
+#[test]
+fn reader_builders() {
+    use crate::pbf::PBFReader;
+    use crate::xml::XMLReader;
+
+    let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="2" user="alice" uid="5" lat="0" lon="0"/>
+<way id="10"><nd ref="1"/></way>
+<node id="2" version="x" lat="0" lon="0"/>
+</osm>"#;
+    let mut reader = XMLReader::builder()
+        .sorted_assumption(true)
+        .buffer_size(16)
+        .build(input.as_bytes());
+    assert!(reader.get_sorted_assumption());
+    assert_eq!(reader.nodes().map(|n| n.id()).collect::<Vec<_>>(), vec![1]);
+
+    // The invalid version isn't an error either
+    let objs = XMLReader::builder()
+        .parse_mode(crate::parse_mode::ParseMode::Strict)
+        .skip_metadata(true)
+        .build(input.as_bytes())
+        .try_objects()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(objs.len(), 3);
+    assert!(objs
+        .iter()
+        .all(|o| o.version().is_none() && o.user().is_none() && o.uid().is_none()));
+
+    let mut reader = PBFReader::builder()
+        .sorted_assumption(true)
+        .tag_filter("n/amenity".parse().unwrap())
+        .build(std::io::empty());
+    assert!(reader.get_sorted_assumption());
+    assert!(reader.tag_filter().is_some());
+
+    // Each object in its own block
+    let mut writer = crate::pbf::PBFWriter::new(Vec::new()).max_block_objects(1);
+    for obj in XMLReader::new(input.as_bytes()).objects() {
+        writer.write_obj(&obj).unwrap();
+    }
+    let pbf = writer.finish().unwrap();
+    let mut reader = PBFReader::builder()
+        .buffer_size(64)
+        .threads(3)
+        .skip_metadata(true)
+        .build(&pbf[..]);
+    let mut ids = Vec::new();
+    reader
+        .for_each_block(|objs| {
+            assert!(objs
+                .iter()
+                .all(|o| o.version().is_none() && o.user().is_none()));
+            ids.extend(objs.iter().map(|o| o.id()));
+        })
+        .unwrap();
+    assert_eq!(ids, vec![1, 10, 2]);
+    let node = PBFReader::builder().build(&pbf[..]).next().unwrap();
+    assert_eq!((node.version(), node.user()), (Some(2), Some("alice")));
+
+    let mut reader = PBFReader::builder().max_blob_size(8).build(&pbf[..]);
+    let res = reader.try_next();
+    assert!(matches!(res, Err(Error::Format { .. })), "{:?}", res);
+}
+
+#[test]
+fn cancellation() {
+    use crate::cancel::CancellationToken;
+    use crate::xml::{XMLReader, XMLReaderBuilder};
+
+    let input = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" lat="0" lon="0"/>
+<node id="2" lat="0" lon="0"/>
+</osm>"#;
+    let token = CancellationToken::new();
+    let mut reader = XMLReaderBuilder::new()
+        .cancellation(token.clone())
+        .build(input.as_bytes());
+    assert_eq!(reader.try_next().unwrap().unwrap().id(), 1);
+    token.cancel();
+    assert!(reader.try_next().unwrap_err().is_cancelled());
+    assert!(reader.next().is_none());
+
+    // Each node in its own block
+    let mut writer = crate::pbf::PBFWriter::new(Vec::new()).max_block_objects(1);
+    for obj in XMLReader::new(input.as_bytes()).objects() {
+        writer.write_obj(&obj).unwrap();
+    }
+    let pbf = writer.finish().unwrap();
+    let token = CancellationToken::new();
+    let mut reader = stringpbf::PBFReader::new(&pbf[..]);
+    reader.set_cancellation(token.clone());
+    assert_eq!(reader.try_next().unwrap().unwrap().id(), 1);
+    token.cancel();
+    assert!(reader.try_next().unwrap_err().is_cancelled());
+    assert!(reader.next().is_none());
+}
+
+#[test]
+fn builder_error() {
+    use crate::obj_types::StringWayBuilder;
+
+    match StringWayBuilder::default()._nodes(vec![1, 2]).build() {
+        Err(Error::Builder(reason)) => assert_eq!(reason, "_id is required"),
+        res => panic!("{:?}", res),
+    }
+}
+
+#[test]
+fn names() {
+    use crate::obj_types::StringNodeBuilder;
+
+    let tags = [
+        ("name", "Brussel - Bruxelles"),
+        ("name:fr", "Bruxelles"),
+        ("name:nl", "Brussel"),
+        ("alt_name", "Brussels"),
+        ("official_name:de", "Brüssel"),
+        ("population", "1000000"),
+        ("surname", "x"),
+    ];
+    let node = StringNodeBuilder::default()
+        ._id(1)
+        ._tags(
+            tags.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>(),
+        )
+        .build()
+        .unwrap();
+    assert_eq!(node.name(&["nl", "fr"]), Some("Brussel"));
+    assert_eq!(node.name(&["de", "fr-BE"]), Some("Bruxelles"));
+    assert_eq!(node.name(&["de"]), Some("Brussel - Bruxelles"));
+    assert_eq!(node.name(&[]), Some("Brussel - Bruxelles"));
+    let names: Vec<_> = node.names().map(|(k, _)| k).collect();
+    assert_eq!(
+        names,
+        vec!["name", "name:fr", "name:nl", "alt_name", "official_name:de"]
+    );
+}
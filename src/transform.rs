@@ -0,0 +1,230 @@
+//! Changing the tags of objects.
+//!
+//! A [`TagTransform`] is a list of steps (rename a key, drop keys, add a tag, rewrite values),
+//! which are applied in order to each object. Keys can be patterns: a key ending in `*` matches
+//! every key starting with that, and `*` on its own matches every key.
+//!
+//! ```rust
+//! use osmio::transform::TagTransform;
+//! use osmio::obj_types::StringNodeBuilder;
+//! use osmio::OSMObjBase;
+//!
+//! let mut transform = TagTransform::new()
+//!     .drop_keys("tiger:*")
+//!     .rename_key("addr:street_name", "addr:street")
+//!     .rewrite_values("name", |_key, value| Some(value.trim().to_string()))
+//!     .add_tag("source", "survey");
+//!
+//! let mut node = StringNodeBuilder::default()
+//!     ._id(1)
+//!     ._tags(vec![
+//!         ("tiger:cfcc".to_string(), "A41".to_string()),
+//!         ("name".to_string(), " Main Street ".to_string()),
+//!     ])
+//!     .build()
+//!     .unwrap();
+//! transform.apply(&mut node);
+//! assert_eq!(node.tag("name"), Some("Main Street"));
+//! assert_eq!(node.tag("source"), Some("survey"));
+//! assert!(!node.has_tag("tiger:cfcc"));
+//! ```
+use tagfilter::Pattern;
+use OSMObjBase;
+
+/// Closure which rewrites the value of a tag, see [`TagTransform::rewrite_values`]
+type RewriteFn = Box<dyn FnMut(&str, &str) -> Option<String>>;
+
+enum Step {
+    Rename { from: String, to: String },
+    Drop(Pattern),
+    Add { key: String, value: String },
+    Rewrite { key: Pattern, rewrite: RewriteFn },
+}
+
+/// A list of changes to make to the tags of objects.
+#[derive(Default)]
+pub struct TagTransform {
+    steps: Vec<Step>,
+}
+
+impl std::fmt::Debug for TagTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TagTransform")
+            .field("num_steps", &self.steps.len())
+            .finish()
+    }
+}
+
+impl TagTransform {
+    /// A transform which changes nothing
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rename the key `from` to `to`. If there's already a `to` tag, it's replaced.
+    pub fn rename_key(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.steps.push(Step::Rename {
+            from: from.into(),
+            to: to.into(),
+        });
+        self
+    }
+
+    /// Remove all tags whose key matches this pattern
+    pub fn drop_keys(mut self, key: &str) -> Self {
+        self.steps.push(Step::Drop(Pattern::parse(key)));
+        self
+    }
+
+    /// Set this tag, replacing any existing value
+    pub fn add_tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.steps.push(Step::Add {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// For every tag whose key matches this pattern, call `rewrite` with the key & value. The tag
+    /// is given the returned value, or removed if it returns `None`.
+    pub fn rewrite_values(
+        mut self,
+        key: &str,
+        rewrite: impl FnMut(&str, &str) -> Option<String> + 'static,
+    ) -> Self {
+        self.steps.push(Step::Rewrite {
+            key: Pattern::parse(key),
+            rewrite: Box::new(rewrite),
+        });
+        self
+    }
+
+    /// True iff there are no steps
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Apply all the steps to these tags
+    pub fn apply_to_tags(&mut self, tags: &mut Vec<(String, String)>) {
+        for step in self.steps.iter_mut() {
+            match step {
+                Step::Rename { from, to } => {
+                    if from != to && tags.iter().any(|(k, _)| k == from) {
+                        tags.retain(|(k, _)| k != to);
+                        for tag in tags.iter_mut().filter(|(k, _)| k == from) {
+                            tag.0 = to.clone();
+                        }
+                    }
+                }
+                Step::Drop(key) => tags.retain(|(k, _)| !key.matches(k)),
+                Step::Add { key, value } => match tags.iter_mut().find(|(k, _)| k == key) {
+                    Some(tag) => tag.1 = value.clone(),
+                    None => tags.push((key.clone(), value.clone())),
+                },
+                Step::Rewrite { key, rewrite } => {
+                    tags.retain_mut(|(k, v)| {
+                        if !key.matches(k) {
+                            return true;
+                        }
+                        match rewrite(k, v) {
+                            Some(new_value) => {
+                                *v = new_value;
+                                true
+                            }
+                            None => false,
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Apply all the steps to the tags of this object
+    pub fn apply(&mut self, obj: &mut impl OSMObjBase) {
+        if self.steps.is_empty() {
+            return;
+        }
+        let mut tags: Vec<(String, String)> = obj
+            .tags()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        for (k, _) in tags.iter() {
+            obj.unset_tag(k);
+        }
+        self.apply_to_tags(&mut tags);
+        for (k, v) in tags {
+            obj.set_tag(k, v);
+        }
+    }
+
+    /// Transform all the objects from this iterator (e.g. `reader.objects()`), so they can be
+    /// passed on to a writer.
+    pub fn transform_iter<'a, O: OSMObjBase + 'a>(
+        &'a mut self,
+        objs: impl Iterator<Item = O> + 'a,
+    ) -> impl Iterator<Item = O> + 'a {
+        objs.map(move |mut obj| {
+            self.apply(&mut obj);
+            obj
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn steps() {
+        let mut transform = TagTransform::new()
+            .rename_key("old", "new")
+            .drop_keys("tiger:*")
+            .add_tag("source", "survey")
+            .rewrite_values("name*", |_k, v| {
+                if v.is_empty() {
+                    None
+                } else {
+                    Some(v.to_uppercase())
+                }
+            });
+
+        let mut t = tags(&[
+            ("old", "1"),
+            ("new", "2"),
+            ("tiger:cfcc", "A41"),
+            ("tiger:county", "X"),
+            ("name", "main st"),
+            ("name:en", ""),
+            ("source", "bing"),
+        ]);
+        transform.apply_to_tags(&mut t);
+        assert_eq!(
+            t,
+            tags(&[("new", "1"), ("name", "MAIN ST"), ("source", "survey")])
+        );
+
+        let mut t = tags(&[("highway", "primary")]);
+        transform.apply_to_tags(&mut t);
+        assert_eq!(t, tags(&[("highway", "primary"), ("source", "survey")]));
+    }
+
+    #[test]
+    fn objects() {
+        use obj_types::StringWayBuilder;
+
+        let mut transform = TagTransform::new().drop_keys("*");
+        let way = StringWayBuilder::default()
+            ._id(1)
+            ._tags(tags(&[("highway", "primary"), ("name", "x")]))
+            .build()
+            .unwrap();
+        let ways: Vec<_> = transform.transform_iter(vec![way].into_iter()).collect();
+        assert!(ways[0].untagged());
+    }
+}
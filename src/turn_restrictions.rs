@@ -0,0 +1,436 @@
+//! Turn restrictions (`type=restriction` relations), for routing.
+//!
+//! A restriction relation has a `from` way, a `via` node (or one or more `via` ways) and a `to`
+//! way, and a `restriction` tag like `no_left_turn` or `only_straight_on`. Restrictions for some
+//! vehicles use a `restriction:<mode>` tag (e.g. `restriction:hgv`), and can have `except`ions.
+//! [`TurnRestriction::from_relation`] checks the members & tags, and
+//! [`TurnRestriction::check_connected`] checks that the ways actually meet at the via node or ways.
+//!
+//! ```no_run
+//! use osmio::turn_restrictions::read_restrictions;
+//!
+//! let report = read_restrictions(|| osmio::read_pbf("input.osm.pbf"))?;
+//! for restriction in report.restrictions.iter() {
+//!     println!("{:?}", restriction);
+//! }
+//! println!("{} invalid restrictions", report.invalid.len());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use tag_values::split_values;
+use {OSMObj, OSMObjBase, OSMObjectType, OSMReader, ObjId, Relation, Way};
+
+/// The kind of turn restriction, from the `restriction` tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RestrictionKind {
+    NoLeftTurn,
+    NoRightTurn,
+    NoStraightOn,
+    NoUTurn,
+    /// Can't enter the `to` way from any of the (possibly several) `from` ways
+    NoEntry,
+    /// Can't leave the `from` way to any of the (possibly several) `to` ways
+    NoExit,
+    OnlyLeftTurn,
+    OnlyRightTurn,
+    OnlyStraightOn,
+    OnlyUTurn,
+}
+
+impl RestrictionKind {
+    /// True for `only_*` restrictions, which forbid every other turn
+    pub fn is_mandatory(&self) -> bool {
+        matches!(
+            self,
+            RestrictionKind::OnlyLeftTurn
+                | RestrictionKind::OnlyRightTurn
+                | RestrictionKind::OnlyStraightOn
+                | RestrictionKind::OnlyUTurn
+        )
+    }
+
+    /// The `restriction` tag value
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestrictionKind::NoLeftTurn => "no_left_turn",
+            RestrictionKind::NoRightTurn => "no_right_turn",
+            RestrictionKind::NoStraightOn => "no_straight_on",
+            RestrictionKind::NoUTurn => "no_u_turn",
+            RestrictionKind::NoEntry => "no_entry",
+            RestrictionKind::NoExit => "no_exit",
+            RestrictionKind::OnlyLeftTurn => "only_left_turn",
+            RestrictionKind::OnlyRightTurn => "only_right_turn",
+            RestrictionKind::OnlyStraightOn => "only_straight_on",
+            RestrictionKind::OnlyUTurn => "only_u_turn",
+        }
+    }
+}
+
+impl FromStr for RestrictionKind {
+    type Err = RestrictionProblem;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "no_left_turn" => RestrictionKind::NoLeftTurn,
+            "no_right_turn" => RestrictionKind::NoRightTurn,
+            "no_straight_on" => RestrictionKind::NoStraightOn,
+            "no_u_turn" => RestrictionKind::NoUTurn,
+            "no_entry" => RestrictionKind::NoEntry,
+            "no_exit" => RestrictionKind::NoExit,
+            "only_left_turn" => RestrictionKind::OnlyLeftTurn,
+            "only_right_turn" => RestrictionKind::OnlyRightTurn,
+            "only_straight_on" => RestrictionKind::OnlyStraightOn,
+            "only_u_turn" => RestrictionKind::OnlyUTurn,
+            _ => return Err(RestrictionProblem::UnknownRestriction(s.to_string())),
+        })
+    }
+}
+
+/// Where the `from` & `to` ways meet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Via {
+    Node(ObjId),
+    /// The via ways, in order from the `from` way to the `to` way
+    Ways(Vec<ObjId>),
+}
+
+/// A valid turn restriction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurnRestriction {
+    pub relation_id: ObjId,
+    pub kind: RestrictionKind,
+    /// The `from` ways. There's exactly one, except for `no_entry`.
+    pub from: Vec<ObjId>,
+    pub via: Via,
+    /// The `to` ways. There's exactly one, except for `no_exit`.
+    pub to: Vec<ObjId>,
+    /// The transport mode (e.g. `hgv` for `restriction:hgv`), or `None` if it applies to all
+    pub mode: Option<String>,
+    /// The transport modes it doesn't apply to, from the `except` tag
+    pub except: Vec<String>,
+}
+
+/// Something wrong with a restriction relation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestrictionProblem {
+    /// There's no `restriction` (or `restriction:<mode>`) tag
+    NoRestrictionTag,
+    /// This isn't a known `restriction` value
+    UnknownRestriction(String),
+    /// There are this many `from` members (it must be 1, or at least 1 for `no_entry`)
+    WrongNumberOfFrom(usize),
+    /// There are this many `to` members (it must be 1, or at least 1 for `no_exit`)
+    WrongNumberOfTo(usize),
+    /// There's no `via` member, more than one via node, or both via nodes & ways
+    InvalidVia,
+    /// This member has a role which isn't `from`, `via`, `to` or `location_hint`, or the wrong
+    /// type for its role (e.g. a `from` node)
+    InvalidMember {
+        member_type: OSMObjectType,
+        member_id: ObjId,
+        role: String,
+    },
+    /// This member way isn't available
+    MissingWay(ObjId),
+    /// These 2 member ways don't meet at an end of one of them (or at the via node)
+    NotConnected { from: ObjId, to: ObjId },
+}
+
+/// True iff this is a `type=restriction` relation (or `type=restriction:<mode>`)
+pub fn is_restriction(relation: &impl Relation) -> bool {
+    relation
+        .tag("type")
+        .is_some_and(|t| t == "restriction" || t.starts_with("restriction:"))
+}
+
+impl TurnRestriction {
+    /// Read a restriction from the tags & members of this relation. All the problems are
+    /// returned if it's not valid.
+    pub fn from_relation(relation: &impl Relation) -> Result<Self, Vec<RestrictionProblem>> {
+        let mut problems = Vec::new();
+        let tag = relation.tags().find(|(k, _)| {
+            *k == "restriction" || (k.starts_with("restriction:") && !k.ends_with(":conditional"))
+        });
+        let kind = match tag {
+            None => {
+                problems.push(RestrictionProblem::NoRestrictionTag);
+                None
+            }
+            Some((_, value)) => match value.parse::<RestrictionKind>() {
+                Ok(kind) => Some(kind),
+                Err(problem) => {
+                    problems.push(problem);
+                    None
+                }
+            },
+        };
+        let mode = tag.and_then(|(k, _)| k.strip_prefix("restriction:").map(String::from));
+
+        let (mut from, mut via_nodes, mut via_ways, mut to) = (vec![], vec![], vec![], vec![]);
+        for (member_type, member_id, role) in relation.members() {
+            let list = match (role, member_type) {
+                ("from", OSMObjectType::Way) => &mut from,
+                ("to", OSMObjectType::Way) => &mut to,
+                ("via", OSMObjectType::Node) => &mut via_nodes,
+                ("via", OSMObjectType::Way) => &mut via_ways,
+                ("location_hint", OSMObjectType::Node) => continue,
+                _ => {
+                    problems.push(RestrictionProblem::InvalidMember {
+                        member_type,
+                        member_id,
+                        role: role.to_string(),
+                    });
+                    continue;
+                }
+            };
+            list.push(member_id);
+        }
+
+        if from.is_empty() || (from.len() > 1 && kind != Some(RestrictionKind::NoEntry)) {
+            problems.push(RestrictionProblem::WrongNumberOfFrom(from.len()));
+        }
+        if to.is_empty() || (to.len() > 1 && kind != Some(RestrictionKind::NoExit)) {
+            problems.push(RestrictionProblem::WrongNumberOfTo(to.len()));
+        }
+        let via = match (via_nodes.as_slice(), via_ways.is_empty()) {
+            ([node], true) => Some(Via::Node(*node)),
+            ([], false) => Some(Via::Ways(via_ways)),
+            _ => {
+                problems.push(RestrictionProblem::InvalidVia);
+                None
+            }
+        };
+
+        match (kind, via) {
+            (Some(kind), Some(via)) if problems.is_empty() => Ok(TurnRestriction {
+                relation_id: relation.id(),
+                kind,
+                from,
+                via,
+                to,
+                mode,
+                except: relation
+                    .tag("except")
+                    .map_or_else(Vec::new, |e| split_values(e).map(String::from).collect()),
+            }),
+            _ => Err(problems),
+        }
+    }
+
+    /// All the member ways
+    pub fn way_ids(&self) -> impl Iterator<Item = ObjId> + '_ {
+        let via_ways = match &self.via {
+            Via::Node(_) => &[][..],
+            Via::Ways(ways) => ways.as_slice(),
+        };
+        self.from
+            .iter()
+            .chain(via_ways.iter())
+            .chain(self.to.iter())
+            .copied()
+    }
+
+    /// Check that the `from` ways start or end at the via node (or first via way), the via ways
+    /// are connected end to end, and the `to` ways start or end at the via node (or last via
+    /// way). `way_nodes` has the nodes of (at least) the member ways.
+    pub fn check_connected(
+        &self,
+        way_nodes: &HashMap<ObjId, Vec<ObjId>>,
+    ) -> Vec<RestrictionProblem> {
+        let mut problems = Vec::new();
+        let ends = |way_id: ObjId, problems: &mut Vec<RestrictionProblem>| match way_nodes
+            .get(&way_id)
+            .filter(|nodes| !nodes.is_empty())
+        {
+            Some(nodes) => Some([nodes[0], nodes[nodes.len() - 1]]),
+            None => {
+                problems.push(RestrictionProblem::MissingWay(way_id));
+                None
+            }
+        };
+
+        match &self.via {
+            Via::Node(via) => {
+                for way_id in self.from.iter().chain(self.to.iter()) {
+                    if let Some(ends) = ends(*way_id, &mut problems) {
+                        if !ends.contains(via) {
+                            let (from, to) = if self.from.contains(way_id) {
+                                (*way_id, *via)
+                            } else {
+                                (*via, *way_id)
+                            };
+                            problems.push(RestrictionProblem::NotConnected { from, to });
+                        }
+                    }
+                }
+            }
+            Via::Ways(via_ways) => {
+                // Each pair of consecutive ways must share an end node
+                let check_pair = |a: ObjId, b: ObjId, problems: &mut Vec<RestrictionProblem>| {
+                    if let (Some(a_ends), Some(b_ends)) = (ends(a, problems), ends(b, problems)) {
+                        if !a_ends.iter().any(|n| b_ends.contains(n)) {
+                            problems.push(RestrictionProblem::NotConnected { from: a, to: b });
+                        }
+                    }
+                };
+                for from in self.from.iter() {
+                    check_pair(*from, via_ways[0], &mut problems);
+                }
+                for pair in via_ways.windows(2) {
+                    check_pair(pair[0], pair[1], &mut problems);
+                }
+                for to in self.to.iter() {
+                    check_pair(via_ways[via_ways.len() - 1], *to, &mut problems);
+                }
+            }
+        }
+        problems.dedup();
+        problems
+    }
+}
+
+/// A restriction relation which isn't valid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidRestriction {
+    pub relation_id: ObjId,
+    pub problems: Vec<RestrictionProblem>,
+}
+
+/// All the turn restrictions in a file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestrictionReport {
+    pub restrictions: Vec<TurnRestriction>,
+    pub invalid: Vec<InvalidRestriction>,
+}
+
+/// Find all the restriction relations in a file, and check them, including that their ways are
+/// connected.
+///
+/// The file is read twice, first for the relations, and then for the nodes of their ways, so this
+/// is given a function which opens a new reader (e.g. `|| osmio::read_pbf(&path)`).
+pub fn read_restrictions<F, R>(mut open: F) -> Result<RestrictionReport>
+where
+    F: FnMut() -> Result<R>,
+    R: OSMReader,
+{
+    let mut report = RestrictionReport::default();
+    let mut reader = open()?;
+    while let Some(obj) = reader.try_next()? {
+        if let Some(relation) = obj.as_relation() {
+            if !is_restriction(relation) {
+                continue;
+            }
+            match TurnRestriction::from_relation(relation) {
+                Ok(restriction) => report.restrictions.push(restriction),
+                Err(problems) => report.invalid.push(InvalidRestriction {
+                    relation_id: relation.id(),
+                    problems,
+                }),
+            }
+        }
+    }
+
+    let needed: HashSet<ObjId> = report
+        .restrictions
+        .iter()
+        .flat_map(|r| r.way_ids())
+        .collect();
+    let mut way_nodes = HashMap::new();
+    let mut reader = open()?;
+    while let Some(obj) = reader.try_next()? {
+        if let Some(way) = obj.as_way() {
+            if needed.contains(&way.id()) {
+                way_nodes.insert(way.id(), way.nodes().to_vec());
+            }
+        }
+    }
+
+    let (restrictions, invalid): (Vec<_>, Vec<_>) = report
+        .restrictions
+        .into_iter()
+        .map(|r| {
+            let problems = r.check_connected(&way_nodes);
+            (r, problems)
+        })
+        .partition(|(_, problems)| problems.is_empty());
+    report.restrictions = restrictions.into_iter().map(|(r, _)| r).collect();
+    report
+        .invalid
+        .extend(invalid.into_iter().map(|(r, problems)| InvalidRestriction {
+            relation_id: r.relation_id,
+            problems,
+        }));
+    report.invalid.sort_by_key(|i| i.relation_id);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use xml::XMLReader;
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<way id="1"><nd ref="1"/><nd ref="2"/></way>
+<way id="2"><nd ref="2"/><nd ref="3"/></way>
+<way id="3"><nd ref="3"/><nd ref="4"/></way>
+<way id="4"><nd ref="5"/><nd ref="6"/></way>
+<way id="5"><nd ref="7"/><nd ref="2"/></way>
+<relation id="10"><member type="way" ref="1" role="from"/><member type="node" ref="2" role="via"/><member type="way" ref="2" role="to"/><tag k="type" v="restriction"/><tag k="restriction" v="no_left_turn"/><tag k="except" v="bicycle;psv"/></relation>
+<relation id="11"><member type="way" ref="1" role="from"/><member type="way" ref="2" role="via"/><member type="way" ref="3" role="to"/><tag k="type" v="restriction"/><tag k="restriction:hgv" v="only_straight_on"/></relation>
+<relation id="12"><member type="way" ref="1" role="from"/><member type="node" ref="2" role="via"/><member type="way" ref="4" role="to"/><tag k="type" v="restriction"/><tag k="restriction" v="no_right_turn"/></relation>
+<relation id="13"><member type="node" ref="1" role="from"/><member type="way" ref="2" role="to"/><tag k="type" v="restriction"/><tag k="restriction" v="no_turning"/></relation>
+<relation id="14"><member type="way" ref="1" role="from"/><member type="way" ref="5" role="from"/><member type="node" ref="2" role="via"/><member type="way" ref="2" role="to"/><tag k="type" v="restriction"/><tag k="restriction" v="no_entry"/></relation>
+<relation id="15"><tag k="type" v="multipolygon"/></relation>
+</osm>"#;
+
+    #[test]
+    fn restrictions() {
+        let report =
+            read_restrictions(|| Ok(XMLReader::new(Cursor::new(INPUT.as_bytes())))).unwrap();
+        assert_eq!(
+            report.restrictions[0],
+            TurnRestriction {
+                relation_id: 10,
+                kind: RestrictionKind::NoLeftTurn,
+                from: vec![1],
+                via: Via::Node(2),
+                to: vec![2],
+                mode: None,
+                except: vec!["bicycle".to_string(), "psv".to_string()],
+            }
+        );
+        assert_eq!(report.restrictions[1].via, Via::Ways(vec![2]));
+        assert_eq!(report.restrictions[1].mode.as_deref(), Some("hgv"));
+        assert!(report.restrictions[1].kind.is_mandatory());
+        assert_eq!(report.restrictions[2].relation_id, 14);
+        assert_eq!(report.restrictions[2].from, vec![1, 5]);
+        assert_eq!(report.restrictions.len(), 3);
+
+        assert_eq!(
+            report.invalid,
+            vec![
+                InvalidRestriction {
+                    relation_id: 12,
+                    problems: vec![RestrictionProblem::NotConnected { from: 2, to: 4 }],
+                },
+                InvalidRestriction {
+                    relation_id: 13,
+                    problems: vec![
+                        RestrictionProblem::UnknownRestriction("no_turning".to_string()),
+                        RestrictionProblem::InvalidMember {
+                            member_type: OSMObjectType::Node,
+                            member_id: 1,
+                            role: "from".to_string(),
+                        },
+                        RestrictionProblem::WrongNumberOfFrom(0),
+                        RestrictionProblem::InvalidVia,
+                    ],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Extracting everything last edited by some users, e.g. to respond to vandalism.
+//!
+//! Objects are matched by user id or user name. The matching objects are written to a writer, and
+//! a report of what each user edited is returned, which can be serialised (e.g. to JSON). The
+//! users' changesets can be taken from a changeset file with [`UserExtract::changesets`].
+//!
+//! ```no_run
+//! use osmio::user_extract::UserExtract;
+//! use osmio::xml::XMLWriter;
+//! use osmio::OSMWriter;
+//!
+//! let mut reader = osmio::read_pbf("input.osm.pbf")?;
+//! let mut writer = XMLWriter::new(std::fs::File::create("vandal.osm")?);
+//! let report = UserExtract::new()
+//!     .uid(1234)
+//!     .user("vandal")
+//!     .extract(&mut reader, &mut writer)?;
+//! println!("{}", serde_json::to_string_pretty(&report)?);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+use changesets::Changeset;
+use serde::{Deserialize, Serialize};
+use stats::TypeStats;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryFrom;
+use std::io::Write;
+use {OSMObjBase, OSMObjectType, OSMReader, OSMWriter, TimestampFormat};
+
+use anyhow::Result;
+
+/// What one user edited
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserStats {
+    pub uid: Option<u32>,
+    /// The user name (from the last object found)
+    pub user: Option<String>,
+    pub nodes: TypeStats,
+    pub ways: TypeStats,
+    pub relations: TypeStats,
+    /// The ids of the changesets the objects are from
+    pub changesets: BTreeSet<u32>,
+    pub min_timestamp: Option<TimestampFormat>,
+    pub max_timestamp: Option<TimestampFormat>,
+}
+
+impl UserStats {
+    /// Total number of objects
+    pub fn count(&self) -> u64 {
+        self.nodes.count + self.ways.count + self.relations.count
+    }
+
+    fn add(&mut self, obj: &impl OSMObjBase) {
+        self.uid = self.uid.or(obj.uid());
+        if let Some(user) = obj.user() {
+            self.user = Some(user.to_string());
+        }
+        match obj.object_type() {
+            OSMObjectType::Node => self.nodes.add(obj),
+            OSMObjectType::Way => self.ways.add(obj),
+            OSMObjectType::Relation => self.relations.add(obj),
+        }
+        self.changesets.extend(obj.changeset_id());
+        if let Some(ts) = obj.timestamp() {
+            if self.min_timestamp.as_ref().map_or(true, |m| ts < m) {
+                self.min_timestamp = Some(ts.clone());
+            }
+            if self.max_timestamp.as_ref().map_or(true, |m| ts > m) {
+                self.max_timestamp = Some(ts.clone());
+            }
+        }
+    }
+}
+
+/// The result of [`UserExtract::extract`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserReport {
+    /// Every user who had at least one object, sorted by uid (users only matched by name, without
+    /// a uid, are last)
+    pub users: Vec<UserStats>,
+}
+
+impl UserReport {
+    /// Total number of objects
+    pub fn count(&self) -> u64 {
+        self.users.iter().map(|u| u.count()).sum()
+    }
+
+    /// The ids of all the changesets of all the objects
+    pub fn changeset_ids(&self) -> BTreeSet<u32> {
+        self.users
+            .iter()
+            .flat_map(|u| u.changesets.iter().copied())
+            .collect()
+    }
+}
+
+/// Finds the objects last edited by some users. See the [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserExtract {
+    uids: HashSet<u32>,
+    users: HashSet<String>,
+}
+
+impl UserExtract {
+    /// No users (nothing matches)
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also match objects with this user id
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uids.insert(uid);
+        self
+    }
+
+    /// Also match objects with this user name
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.users.insert(user.into());
+        self
+    }
+
+    /// True iff there are no users to match
+    pub fn is_empty(&self) -> bool {
+        self.uids.is_empty() && self.users.is_empty()
+    }
+
+    /// True iff this object was last edited by one of the users
+    pub fn matches(&self, obj: &impl OSMObjBase) -> bool {
+        obj.uid().is_some_and(|uid| self.uids.contains(&uid))
+            || obj.user().is_some_and(|user| self.users.contains(user))
+    }
+
+    /// Write every matching object from `reader` to `writer` (which isn't closed), and return
+    /// what each user edited.
+    ///
+    /// For a history file, every version edited by the users is included.
+    pub fn extract<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut impl OSMWriter<W>,
+    ) -> Result<UserReport>
+    where
+        R: OSMReader,
+        W: Write,
+    {
+        // Users with a uid are keyed by it, other users by their name
+        let mut by_uid: BTreeMap<u32, UserStats> = BTreeMap::new();
+        let mut by_name: BTreeMap<String, UserStats> = BTreeMap::new();
+        for obj in reader.try_objects() {
+            let obj = obj?;
+            if !self.matches(&obj) {
+                continue;
+            }
+            writer.write_obj(&obj)?;
+            let stats = match (obj.uid(), obj.user()) {
+                (Some(uid), _) => by_uid.entry(uid).or_default(),
+                (None, user) => by_name
+                    .entry(user.unwrap_or_default().to_string())
+                    .or_default(),
+            };
+            stats.add(&obj);
+        }
+        Ok(UserReport {
+            users: by_uid.into_values().chain(by_name.into_values()).collect(),
+        })
+    }
+
+    /// Only the changesets (e.g. from a
+    /// [`ChangesetReader`](crate::changesets::ChangesetReader)) opened by one of the users
+    pub fn changesets<'a>(
+        &'a self,
+        changesets: impl Iterator<Item = Result<Changeset>> + 'a,
+    ) -> impl Iterator<Item = Result<Changeset>> + 'a {
+        changesets.filter(move |res| match res {
+            Ok(changeset) => {
+                changeset
+                    .uid
+                    .is_some_and(|uid| u32::try_from(uid).is_ok_and(|uid| self.uids.contains(&uid)))
+                    || changeset
+                        .user
+                        .as_ref()
+                        .is_some_and(|user| self.users.contains(user))
+            }
+            Err(_) => true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use xml::{XMLReader, XMLWriter};
+
+    const INPUT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<osm version="0.6">
+<node id="1" version="1" lat="1" lon="1" uid="1" user="alice" changeset="10" timestamp="2020-01-01T00:00:00Z"/>
+<node id="2" version="1" lat="1" lon="1" uid="2" user="bob" changeset="11" timestamp="2020-01-01T00:00:00Z"/>
+<node id="3" version="1" lat="1" lon="1" uid="1" user="alice" changeset="12" timestamp="2021-01-01T00:00:00Z"/>
+<way id="4" version="1" uid="3" user="carol" changeset="13"><nd ref="1"/><nd ref="2"/></way>
+<way id="5" version="1" uid="4" user="dave" changeset="14"><nd ref="1"/><nd ref="2"/></way>
+</osm>"#;
+
+    #[test]
+    fn extract() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.osm");
+        let extract = UserExtract::new().uid(1).user("carol");
+        let report = {
+            let mut writer = XMLWriter::new(File::create(&path).unwrap());
+            extract
+                .extract(&mut XMLReader::new(INPUT.as_bytes()), &mut writer)
+                .unwrap()
+        };
+
+        assert_eq!(report.count(), 3);
+        assert_eq!(report.users.len(), 2);
+        let alice = &report.users[0];
+        assert_eq!(alice.user.as_deref(), Some("alice"));
+        assert_eq!(alice.nodes.count, 2);
+        assert_eq!(alice.changesets, vec![10, 12].into_iter().collect());
+        assert_eq!(
+            alice.max_timestamp,
+            Some("2021-01-01T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(report.users[1].ways.count, 1);
+        assert_eq!(
+            report.changeset_ids(),
+            vec![10, 12, 13].into_iter().collect()
+        );
+
+        let ids: Vec<_> = XMLReader::new(File::open(&path).unwrap())
+            .objects()
+            .map(|o| o.id())
+            .collect();
+        assert_eq!(ids, vec![1, 3, 4]);
+
+        let input = r#"<osm version="0.6"><node id="1" uid="1"/><node id="x"/></osm>"#;
+        let mut writer = XMLWriter::new(Vec::new());
+        assert!(extract
+            .extract(&mut XMLReader::new(input.as_bytes()), &mut writer)
+            .is_err());
+    }
+}
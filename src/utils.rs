@@ -1,17 +1,221 @@
 //! Misc local utilities
-use chrono::{offset::Utc, DateTime, NaiveDateTime};
+use std::convert::TryFrom;
+use {Error, Lat, Lon, OSMObjectType, ObjId, ParseTimestampError, COORD_PRECISION_NANOS};
 
-pub fn epoch_to_iso(epoch: i32) -> String {
-    // Chrono is a little silly and can't easily convert from epoch to utc timezone
-    let d: DateTime<Utc> = DateTime::from_naive_utc_and_offset(
-        NaiveDateTime::from_timestamp_opt(epoch as i64, 0).expect("epoch timestamp too high"),
-        Utc,
-    );
-    d.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+/// Convert seconds since the unix epoch to an ISO 8601 string (e.g. `2020-01-01T00:00:00Z`)
+pub fn epoch_to_iso(epoch: impl Into<i64>) -> String {
+    let epoch: i64 = epoch.into();
+    let days = epoch.div_euclid(86_400);
+    let secs_of_day = epoch.rem_euclid(86_400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60
+    )
 }
 
+/// Convert milliseconds since the unix epoch to an ISO 8601 string. The milliseconds are only
+/// included if they are non-zero (e.g. `2020-01-01T00:00:00.250Z`).
+pub fn epoch_millis_to_iso(epoch_millis: i64) -> String {
+    let millis = epoch_millis.rem_euclid(1000);
+    let iso = epoch_to_iso(epoch_millis.div_euclid(1000));
+    if millis == 0 {
+        iso
+    } else {
+        format!("{}.{:03}Z", iso.trim_end_matches('Z'), millis)
+    }
+}
+
+/// Convert an ISO 8601 string to seconds since the unix epoch.
+///
+/// Returns 0 if the string cannot be parsed, see [`parse_iso8601`] for a version which reports
+/// errors.
 pub fn iso_to_epoch(iso: &str) -> u32 {
-    DateTime::parse_from_rfc3339(iso)
-        .map(|x| x.timestamp() as u32)
-        .unwrap_or(0)
+    parse_iso8601(iso).map(|x| x as u32).unwrap_or(0)
+}
+
+/// Parse an ISO 8601/RFC 3339 timestamp (e.g. `2020-01-01T00:00:00Z` or
+/// `2020-01-01T02:00:00+02:00`) into the number of seconds since the unix epoch.
+///
+/// Fractional seconds are accepted, and truncated.
+///
+/// ```rust
+/// use osmio::utils::parse_iso8601;
+/// assert_eq!(parse_iso8601("1970-01-01T00:00:01Z"), Ok(1));
+/// assert_eq!(parse_iso8601("1970-01-01T01:00:01+01:00"), Ok(1));
+/// assert!(parse_iso8601("1970-13-01T00:00:01Z").is_err());
+/// assert!(parse_iso8601("yesterday").is_err());
+/// ```
+pub fn parse_iso8601(iso: &str) -> Result<i64, ParseTimestampError> {
+    parse_iso8601_millis(iso).map(|millis| millis.div_euclid(1000))
+}
+
+/// Parse an ISO 8601/RFC 3339 timestamp into the number of milliseconds since the unix epoch.
+///
+/// Fractional seconds beyond milliseconds are truncated.
+///
+/// ```rust
+/// use osmio::utils::parse_iso8601_millis;
+/// assert_eq!(parse_iso8601_millis("1970-01-01T00:00:01Z"), Ok(1000));
+/// assert_eq!(parse_iso8601_millis("1970-01-01T00:00:01.25Z"), Ok(1250));
+/// assert_eq!(parse_iso8601_millis("1970-01-01T00:00:01.123456Z"), Ok(1123));
+/// ```
+pub fn parse_iso8601_millis(iso: &str) -> Result<i64, ParseTimestampError> {
+    let invalid = || ParseTimestampError::InvalidFormat(iso.to_string());
+    let bytes = iso.as_bytes();
+    // Shortest valid form is `YYYY-MM-DDTHH:MM:SSZ`
+    if bytes.len() < 20 {
+        return Err(invalid());
+    }
+    let (datetime, rest) = bytes.split_at(19);
+    for (idx, allowed) in [(4, "-"), (7, "-"), (10, "Tt "), (13, ":"), (16, ":")] {
+        if !allowed.as_bytes().contains(&datetime[idx]) {
+            return Err(invalid());
+        }
+    }
+    let num =
+        |start: usize, len: usize| parse_digits(&datetime[start..start + len]).ok_or_else(invalid);
+    let year = num(0, 4)?;
+    let month = num(5, 2)?;
+    let day = num(8, 2)?;
+    let hour = num(11, 2)?;
+    let minute = num(14, 2)?;
+    let second = num(17, 2)?;
+
+    // fractional seconds, if any
+    let mut rest = rest;
+    let mut millis = 0;
+    if rest.first() == Some(&b'.') {
+        let num_frac_digits = rest[1..].iter().take_while(|b| b.is_ascii_digit()).count();
+        if num_frac_digits == 0 {
+            return Err(invalid());
+        }
+        millis = rest[1..=num_frac_digits]
+            .iter()
+            .chain(b"00".iter())
+            .take(3)
+            .fold(0, |acc, d| acc * 10 + (d - b'0') as i64);
+        rest = &rest[1 + num_frac_digits..];
+    }
+
+    let offset_secs = match rest {
+        b"Z" | b"z" => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] | [sign @ (b'+' | b'-'), h1, h2, m1, m2] => {
+            let offset_hours = parse_digits(&[*h1, *h2]).ok_or_else(invalid)?;
+            let offset_mins = parse_digits(&[*m1, *m2]).ok_or_else(invalid)?;
+            if offset_hours > 23 || offset_mins > 59 {
+                return Err(invalid());
+            }
+            let offset = offset_hours * 3600 + offset_mins * 60;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return Err(invalid()),
+    };
+
+    check_range("month", month, 1, 12)?;
+    check_range("day", day, 1, days_in_month(year, month))?;
+    check_range("hour", hour, 0, 23)?;
+    check_range("minute", minute, 0, 59)?;
+    // 60 is allowed for leap seconds
+    check_range("second", second, 0, 60)?;
+
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86_400 + hour * 3600 + minute * 60 + second - offset_secs;
+    Ok(secs * 1000 + millis)
+}
+
+/// Parse a non-empty string of only ascii digits
+fn parse_digits(digits: &[u8]) -> Option<i64> {
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(digits.iter().fold(0, |acc, d| acc * 10 + (d - b'0') as i64))
+}
+
+fn check_range(
+    field: &'static str,
+    value: i64,
+    min: i64,
+    max: i64,
+) -> Result<(), ParseTimestampError> {
+    if value < min || value > max {
+        Err(ParseTimestampError::OutOfRange { field, value })
+    } else {
+        Ok(())
+    }
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Number of days since 1970-01-01 for this (proleptic Gregorian) date.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html>
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// (year, month, day) for this number of days since 1970-01-01. Inverse of `days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400;
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
+
+/// The location of PBF node `id`, from its raw coordinates (in units of `granularity`
+/// nanodegrees) and the block's offsets (in nanodegrees). An error if it's out of range, which
+/// only happens with invalid data.
+pub(crate) fn pbf_lat_lon(
+    (raw_lat, raw_lon): (i64, i64),
+    granularity: i32,
+    (lat_offset, lon_offset): (i64, i64),
+    id: ObjId,
+) -> Result<(Lat, Lon), Error> {
+    // granularity is in nanodegrees
+    let scale_factor = i64::from(granularity / COORD_PRECISION_NANOS);
+    let internal = |raw: i64, offset: i64| {
+        raw.checked_mul(scale_factor)?
+            .checked_add(offset / i64::from(COORD_PRECISION_NANOS))
+            .and_then(|c| i32::try_from(c).ok())
+    };
+    match (internal(raw_lat, lat_offset), internal(raw_lon, lon_offset)) {
+        (Some(lat), Some(lon)) => Ok((Lat(lat), Lon(lon))),
+        _ => Err(Error::InvalidObject {
+            object_type: OSMObjectType::Node,
+            id,
+            reason: format!("location ({}, {}) is out of range", raw_lat, raw_lon),
+        }),
+    }
 }
@@ -1,26 +1,33 @@
 //! XML file format
 
 use super::version;
-use super::ObjId;
-use super::TimestampFormat;
-use super::{Node, OSMObj, OSMObjectType, Relation, Way};
+use super::{Node, OSMObj, Relation, Way};
 use super::{OSMReader, OSMWriteError, OSMWriter};
+#[cfg(feature = "bzip2")]
 use bzip2::read::MultiBzDecoder;
-use obj_types::{StringNode, StringOSMObj, StringRelation, StringWay};
-use std::collections::HashMap;
+use cancel::{self, CancellationToken};
+use header::FileInfo;
+use obj_types::StringOSMObj;
+use parse_mode::{DuplicateTags, NonPositiveIds, ParseMode};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufReader, Read, Write};
-use std::iter::Iterator;
 use std::path::Path;
+use {BBox, Error};
 
 use anyhow::Result;
-use xml_rs::attribute::OwnedAttribute;
-use xml_rs::reader::{EventReader, Events, XmlEvent};
 
+mod parser;
+pub(crate) use self::parser::{read_file_info, ObjParser};
+
+/// Reads OSM XML files
 pub struct XMLReader<R: Read> {
-    parser: Events<BufReader<R>>,
+    parser: ObjParser<BufReader<R>>,
+    sorted_assumption: bool,
+    cancellation: Option<CancellationToken>,
 }
 
+#[cfg(feature = "bzip2")]
 pub fn from_filename_bz2(
     filename: impl AsRef<Path>,
 ) -> Result<XMLReader<bzip2::read::MultiBzDecoder<std::fs::File>>> {
@@ -54,311 +61,230 @@ pub fn from_filename(filename: impl AsRef<Path>) -> Result<XMLReader<dyn Read>>>
 }
 */
 
+/// Write `s` escaped for an XML attribute value (or text).
+///
+/// Tabs & newlines are written as character references, so that they aren't normalised to spaces
+/// when read. Other control characters can't be in XML 1.0 at all, not even as references, so
+/// they're replaced with U+FFFD, like the other characters which aren't allowed.
 pub(crate) fn write_xml_escaped(writer: &mut impl Write, s: &str) -> std::io::Result<()> {
-    for c in s.chars() {
+    let mut rest = s;
+    while let Some((i, c)) = rest.char_indices().find(|&(_, c)| needs_escape(c)) {
+        writer.write_all(&rest.as_bytes()[..i])?;
         match c {
-            '&' => write!(writer, "&amp;")?,
-            '"' => write!(writer, "&quot;")?,
-            '\'' => write!(writer, "&apos;")?,
-            '<' => write!(writer, "&lt;")?,
-            '>' => write!(writer, "&gt;")?,
-            c => write!(writer, "{}", c)?,
+            '&' => writer.write_all(b"&amp;")?,
+            '"' => writer.write_all(b"&quot;")?,
+            '\'' => writer.write_all(b"&apos;")?,
+            '<' => writer.write_all(b"&lt;")?,
+            '>' => writer.write_all(b"&gt;")?,
+            '\t' => writer.write_all(b"&#9;")?,
+            '\n' => writer.write_all(b"&#10;")?,
+            '\r' => writer.write_all(b"&#13;")?,
+            _ => writer.write_all(char::REPLACEMENT_CHARACTER.to_string().as_bytes())?,
         }
+        rest = &rest[i + c.len_utf8()..];
     }
-    Ok(())
+    writer.write_all(rest.as_bytes())
 }
 
-impl<R: Read> OSMReader for XMLReader<R> {
-    type R = R;
-    type Obj = StringOSMObj;
+fn needs_escape(c: char) -> bool {
+    matches!(
+        c,
+        '&' | '"' | '\'' | '<' | '>' | '\u{0}'..='\u{1F}' | '\u{FFFE}' | '\u{FFFF}'
+    )
+}
 
-    fn new(reader: R) -> XMLReader<R> {
-        XMLReader {
-            parser: EventReader::new(BufReader::new(reader)).into_iter(),
-        }
+impl XMLReader<File> {
+    /// A [`XMLReaderBuilder`] to configure a reader
+    pub fn builder() -> XMLReaderBuilder {
+        XMLReaderBuilder::new()
     }
+}
 
-    fn into_inner(self) -> R {
-        self.parser.into_inner().into_inner().into_inner()
+/// Configures a [`XMLReader`], instead of calling setters after creating it.
+///
+/// ```no_run
+/// use osmio::xml::XMLReader;
+/// use osmio::OSMReader;
+///
+/// let mut reader = XMLReader::builder()
+///     .sorted_assumption(true)
+///     .buffer_size(1 << 20)
+///     .open("input.osm")?;
+/// let num_ways = reader.ways().count();
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct XMLReaderBuilder {
+    sorted_assumption: bool,
+    buffer_size: Option<usize>,
+    cancellation: Option<CancellationToken>,
+    parse_mode: ParseMode,
+    lossy_utf8: bool,
+    duplicate_tags: DuplicateTags,
+    non_positive_ids: NonPositiveIds,
+    skip_metadata: bool,
+}
+
+impl XMLReaderBuilder {
+    /// The same settings as [`XMLReader::new`]
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    fn inner(&self) -> &R {
-        todo!("{} {} XMLReader inner()", file!(), line!());
+    /// Assume the file is sorted (default: `false`). See [`OSMReader::set_sorted_assumption`].
+    pub fn sorted_assumption(mut self, sorted_assumption: bool) -> Self {
+        self.sorted_assumption = sorted_assumption;
+        self
     }
 
-    fn next(&mut self) -> Option<StringOSMObj> {
-        let mut elements = Vec::new();
-
-        // Pull xml/sax elements from the xml parser into a vector so we know what to work with.
-        let mut should_push = false;
-        loop {
-            let el = match self.parser.next() {
-                None => {
-                    break;
-                }
-                Some(e) => e,
-            };
-
-            let el = el.unwrap();
-
-            let mut should_break = false;
-            match el {
-                XmlEvent::StartElement { ref name, .. } => match name.local_name.as_str() {
-                    "node" | "way" | "relation" => {
-                        should_push = true;
-                    }
-                    _ => {}
-                },
-                XmlEvent::EndElement { ref name, .. } => match name.local_name.as_str() {
-                    "node" | "way" | "relation" => {
-                        should_break = true;
-                    }
-                    _ => {}
-                },
-                _ => {}
-            }
+    /// Stop reading when this token is cancelled. It's checked before every object is read, and
+    /// then `try_next` returns an [`Error::Cancelled`] (and `next`
+    /// returns `None`).
+    pub fn cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
 
-            if should_push {
-                elements.push(el);
-            }
-            if should_break {
-                break;
-            }
-        }
+    /// What to do with invalid data (default: lenient). See [`OSMReader::set_parse_mode`].
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.parse_mode = parse_mode;
+        self
+    }
 
-        xml_elements_to_osm_obj(&mut elements)
+    /// Decode strings which aren't valid UTF-8 lossily (default: `false`). See
+    /// [`OSMReader::set_lossy_utf8`].
+    pub fn lossy_utf8(mut self, lossy_utf8: bool) -> Self {
+        self.lossy_utf8 = lossy_utf8;
+        self
     }
-}
 
-// FIXME can I put this enum inside XMLWriter?
-#[derive(PartialEq)]
-enum State {
-    Initial,
-    WritingObjects,
-    Closed,
-}
+    /// What to do with duplicate tags (default: keep them all). See
+    /// [`OSMReader::set_duplicate_tags`].
+    pub fn duplicate_tags(mut self, duplicate_tags: DuplicateTags) -> Self {
+        self.duplicate_tags = duplicate_tags;
+        self
+    }
 
-/// Write as OSM XML file format
-pub struct XMLWriter<W: Write> {
-    writer: W,
-    headers: HashMap<String, String>,
-    _state: State,
-}
+    /// What to do with negative or zero ids (default: allow them). See
+    /// [`OSMReader::set_non_positive_ids`].
+    pub fn non_positive_ids(mut self, non_positive_ids: NonPositiveIds) -> Self {
+        self.non_positive_ids = non_positive_ids;
+        self
+    }
 
-#[allow(unused_variables)]
-pub(crate) fn extract_attrs(x: &mut XmlEvent) -> Option<&mut Vec<OwnedAttribute>> {
-    match *x {
-        XmlEvent::StartElement {
-            ref name,
-            ref mut attributes,
-            ref namespace,
-        } => Some(attributes),
-        _ => None,
+    /// Don't parse the version, timestamp, changeset, uid & user of objects, which are then
+    /// `None` (default: `false`). Invalid values of them aren't an anomaly then.
+    pub fn skip_metadata(mut self, skip_metadata: bool) -> Self {
+        self.skip_metadata = skip_metadata;
+        self
     }
-}
 
-pub(crate) fn get_xml_attribute(attrs: &mut Vec<OwnedAttribute>, key: &str) -> Option<String> {
-    attrs
-        .iter()
-        .position(|attr| attr.name.local_name == key)
-        .map(|idx| {
-            let attr = attrs.swap_remove(idx);
-            let OwnedAttribute { name: _, value } = attr;
-            value
-        })
-}
+    /// Size of the read buffer (default: the `BufReader` default)
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = Some(buffer_size);
+        self
+    }
 
-fn get_tags(els: &mut [XmlEvent]) -> Option<Vec<(String, String)>> {
-    let mut result: Option<Vec<(String, String)>> = None;
-    for el in els.iter_mut() {
-        if let &mut XmlEvent::StartElement {
-            ref name,
-            ref mut attributes,
-            ..
-        } = el
-        {
-            if name.local_name == "tag" {
-                let ko = get_xml_attribute(attributes, "k");
-                let vo = get_xml_attribute(attributes, "v");
-                if let (Some(k), Some(v)) = (ko, vo) {
-                    match result {
-                        None => {
-                            result = Some(vec![(k, v)]);
-                        }
-                        Some(ref mut res) => {
-                            res.push((k, v));
-                        }
-                    }
-                }
-            }
+    /// Create a reader which reads from `reader`
+    pub fn build<R: Read>(self, reader: R) -> XMLReader<R> {
+        let reader = match self.buffer_size {
+            Some(buffer_size) => BufReader::with_capacity(buffer_size, reader),
+            None => BufReader::new(reader),
+        };
+        let mut parser = ObjParser::new(reader);
+        parser.set_parse_mode(self.parse_mode);
+        parser.set_lossy_utf8(self.lossy_utf8);
+        parser.set_duplicate_tags(self.duplicate_tags);
+        parser.set_non_positive_ids(self.non_positive_ids);
+        parser.set_skip_metadata(self.skip_metadata);
+        XMLReader {
+            parser,
+            sorted_assumption: self.sorted_assumption,
+            cancellation: self.cancellation,
         }
     }
 
-    result
+    /// Open this (uncompressed) file
+    pub fn open(self, filename: impl AsRef<Path>) -> Result<XMLReader<File>> {
+        Ok(self.build(File::open(filename.as_ref())?))
+    }
 }
 
-fn get_nodes(els: &mut [XmlEvent]) -> Vec<ObjId> {
-    let mut result = Vec::new();
+impl<R: Read> OSMReader for XMLReader<R> {
+    type R = R;
+    type Obj = StringOSMObj;
 
-    for el in els.iter_mut() {
-        if let &mut XmlEvent::StartElement {
-            ref name,
-            ref mut attributes,
-            ..
-        } = el
-        {
-            if name.local_name == "nd" {
-                let ref_id_o: Option<ObjId> =
-                    get_xml_attribute(attributes, "ref").and_then(|e| e.parse().ok());
-                if let Some(ref_id) = ref_id_o {
-                    result.push(ref_id);
-                }
-            }
-        }
+    fn new(reader: R) -> XMLReader<R> {
+        XMLReaderBuilder::new().build(reader)
     }
 
-    result
-}
+    fn set_sorted_assumption(&mut self, sorted_assumption: bool) {
+        self.sorted_assumption = sorted_assumption;
+    }
+    fn get_sorted_assumption(&mut self) -> bool {
+        self.sorted_assumption
+    }
 
-fn get_members(els: &mut [XmlEvent]) -> Vec<(OSMObjectType, ObjId, String)> {
-    let mut result = Vec::new();
+    fn set_parse_mode(&mut self, parse_mode: ParseMode) {
+        self.parser.set_parse_mode(parse_mode);
+    }
 
-    for el in els.iter_mut() {
-        if let &mut XmlEvent::StartElement {
-            ref name,
-            ref mut attributes,
-            ..
-        } = el
-        {
-            if name.local_name == "member" {
-                let ref_id_o: Option<ObjId> =
-                    get_xml_attribute(attributes, "ref").and_then(|e| e.parse().ok());
-                let member_type_o: Option<OSMObjectType> =
-                    get_xml_attribute(attributes, "type").and_then(|t| t.parse().ok());
-                let role = get_xml_attribute(attributes, "role").unwrap_or_default();
-                if let (Some(ref_id), Some(member_type)) = (ref_id_o, member_type_o) {
-                    result.push((member_type, ref_id, role));
-                }
-            }
-        }
+    fn set_lossy_utf8(&mut self, lossy_utf8: bool) {
+        self.parser.set_lossy_utf8(lossy_utf8);
     }
 
-    result
-}
+    fn set_duplicate_tags(&mut self, duplicate_tags: DuplicateTags) {
+        self.parser.set_duplicate_tags(duplicate_tags);
+    }
 
-pub(crate) fn xml_elements_to_osm_obj(els: &mut [XmlEvent]) -> Option<StringOSMObj> {
-    match els.first() {
-        Some(XmlEvent::StartElement { name, .. }) => match name.local_name.as_str() {
-            "node" => node_xml_elements_to_osm_obj(els),
-            "way" => way_xml_elements_to_osm_obj(els),
-            "relation" => relation_xml_elements_to_osm_obj(els),
-            _ => None,
-        },
-        _ => None,
+    fn set_non_positive_ids(&mut self, non_positive_ids: NonPositiveIds) {
+        self.parser.set_non_positive_ids(non_positive_ids);
+    }
+
+    fn into_inner(self) -> R {
+        self.parser.into_inner().into_inner()
     }
-}
 
-fn node_xml_elements_to_osm_obj(els: &mut [XmlEvent]) -> Option<StringOSMObj> {
-    let attrs = extract_attrs(els.first_mut()?)?;
-    let id: ObjId = get_xml_attribute(attrs, "id").and_then(|x| x.parse().ok())?;
-    let version = get_xml_attribute(attrs, "version").and_then(|x| x.parse().ok());
-    let changeset_id = get_xml_attribute(attrs, "changeset").and_then(|x| x.parse().ok());
-    let timestamp =
-        get_xml_attribute(attrs, "timestamp").map(|x| TimestampFormat::ISOString(x.to_owned()));
-    let uid = get_xml_attribute(attrs, "uid").and_then(|x| x.parse().ok());
-    let user = get_xml_attribute(attrs, "user");
-    let lat = get_xml_attribute(attrs, "lat")?.parse();
-    let lon = get_xml_attribute(attrs, "lon")?.parse();
-
-    let lat_lon = match (lat, lon) {
-        (Ok(lat), Ok(lon)) => Some((lat, lon)),
-        _errs => None,
-    };
-    let deleted = get_xml_attribute(attrs, "visible")
-        .and_then(|val| match val.as_str() {
-            "true" => Some(false),
-            "false" => Some(true),
-            _ => None,
-        })
-        .unwrap_or(false);
-
-    let tags = get_tags(els);
-
-    Some(StringOSMObj::Node(StringNode {
-        _id: id,
-        _version: version,
-        _deleted: deleted,
-        _changeset_id: changeset_id,
-        _timestamp: timestamp,
-        _uid: uid,
-        _user: user,
-        _lat_lon: lat_lon,
-        _tags: tags,
-    }))
+    fn inner(&self) -> &R {
+        self.parser.get_ref().get_ref()
+    }
+
+    /// The attributes of the `<osm>` element, and the `<bounds>`
+    fn file_info(&self) -> Option<&FileInfo> {
+        self.parser.file_info()
+    }
+
+    /// Panics if the XML is invalid, use `try_next` to get an error instead. Returns `None` if
+    /// cancelled.
+    fn next(&mut self) -> Option<StringOSMObj> {
+        match self.try_next() {
+            Err(Error::Cancelled(_)) => None,
+            res => res.unwrap(),
+        }
+    }
+
+    fn try_next(&mut self) -> Result<Option<StringOSMObj>, Error> {
+        cancel::check(&self.cancellation)?;
+        self.parser.next_obj()
+    }
 }
 
-fn way_xml_elements_to_osm_obj(els: &mut [XmlEvent]) -> Option<StringOSMObj> {
-    let attrs = extract_attrs(els.first_mut()?)?;
-    let id: ObjId = get_xml_attribute(attrs, "id").and_then(|x| x.parse().ok())?;
-    let version = get_xml_attribute(attrs, "version").and_then(|x| x.parse().ok());
-    let changeset_id = get_xml_attribute(attrs, "changeset").and_then(|x| x.parse().ok());
-    let timestamp =
-        get_xml_attribute(attrs, "timestamp").map(|x| TimestampFormat::ISOString(x.to_owned()));
-    let uid = get_xml_attribute(attrs, "uid").and_then(|x| x.parse().ok());
-    let user = get_xml_attribute(attrs, "user");
-    let deleted = get_xml_attribute(attrs, "visible")
-        .and_then(|val| match val.as_str() {
-            "true" => Some(false),
-            "false" => Some(true),
-            _ => None,
-        })
-        .unwrap_or(false);
-
-    let tags = get_tags(els);
-    let nodes = get_nodes(els);
-    Some(StringOSMObj::Way(StringWay {
-        _id: id,
-        _version: version,
-        _deleted: deleted,
-        _changeset_id: changeset_id,
-        _timestamp: timestamp,
-        _uid: uid,
-        _user: user,
-        _tags: tags.unwrap_or_default(),
-        _nodes: nodes,
-    }))
+// FIXME can I put this enum inside XMLWriter?
+#[derive(PartialEq)]
+enum State {
+    Initial,
+    WritingObjects,
+    Closed,
 }
 
-fn relation_xml_elements_to_osm_obj(els: &mut [XmlEvent]) -> Option<StringOSMObj> {
-    let attrs = extract_attrs(els.first_mut()?)?;
-    let id: ObjId = get_xml_attribute(attrs, "id").and_then(|x| x.parse().ok())?;
-    let version = get_xml_attribute(attrs, "version").and_then(|x| x.parse().ok());
-    let changeset_id = get_xml_attribute(attrs, "changeset").and_then(|x| x.parse().ok());
-    let timestamp =
-        get_xml_attribute(attrs, "timestamp").map(|x| TimestampFormat::ISOString(x.to_owned()));
-    let uid = get_xml_attribute(attrs, "uid").and_then(|x| x.parse().ok());
-    let user = get_xml_attribute(attrs, "user");
-    let deleted = get_xml_attribute(attrs, "visible")
-        .and_then(|val| match val.as_str() {
-            "true" => Some(false),
-            "false" => Some(true),
-            _ => None,
-        })
-        .unwrap_or(false);
-
-    let tags = get_tags(els);
-    let members = get_members(els);
-    Some(StringOSMObj::Relation(StringRelation {
-        _id: id,
-        _version: version,
-        _deleted: deleted,
-        _changeset_id: changeset_id,
-        _timestamp: timestamp,
-        _uid: uid,
-        _user: user,
-        _tags: tags.unwrap_or_default(),
-        _members: members,
-    }))
+/// Write as OSM XML file format
+pub struct XMLWriter<W: Write> {
+    /// `None` once [`OSMWriter::into_inner`] has been called
+    writer: Option<W>,
+    headers: BTreeMap<String, String>,
+    bbox: Option<BBox>,
+    indent: String,
+    _state: State,
 }
 
 impl From<quick_xml::Error> for OSMWriteError {
@@ -374,21 +300,58 @@ impl From<std::io::Error> for OSMWriteError {
 }
 
 impl<W: Write> XMLWriter<W> {
+    /// The string to indent with, once for objects & twice for their tags, nodes & members.
+    /// Default: a tab. Every element is on its own line, even with an empty indent.
+    pub fn indent(mut self, indent: impl Into<String>) -> Self {
+        self.indent = indent.into();
+        self
+    }
+
+    fn writer_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+
+    /// Start a new line, indented `level` times
+    fn newline(&mut self, level: usize) -> std::io::Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(b"\n")?;
+        for _ in 0..level {
+            writer.write_all(self.indent.as_bytes())?;
+        }
+        Ok(())
+    }
+
     fn ensure_header(&mut self) -> Result<(), OSMWriteError> {
         if self._state == State::Initial {
-            writeln!(self.writer, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
-            write!(
-                self.writer,
-                "<osm version=\"0.6\" generator=\"osmio/{}\"",
-                version()
+            writeln!(
+                self.writer_mut(),
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>"
             )?;
+            let writer = self.writer.as_mut().unwrap();
+            write!(writer, "<osm version=\"0.6\" generator=\"")?;
+            match self.headers.get("generator") {
+                Some(generator) => write_xml_escaped(writer, generator)?,
+                None => write!(writer, "osmio/{}", version())?,
+            }
+            write!(writer, "\"")?;
+            for (k, v) in self.headers.iter().filter(|(k, _)| *k != "generator") {
+                write!(writer, " {}=\"", k)?;
+                write_xml_escaped(writer, v)?;
+                write!(writer, "\"")?;
+            }
+            write!(self.writer_mut(), ">")?;
 
-            for (k, v) in self.headers.iter() {
-                write!(self.writer, " {}=\"", k)?;
-                write_xml_escaped(&mut self.writer, v)?;
-                write!(self.writer, "\"")?;
+            if let Some(bbox) = self.bbox {
+                self.newline(1)?;
+                write!(
+                    self.writer_mut(),
+                    "<bounds minlat=\"{}\" minlon=\"{}\" maxlat=\"{}\" maxlon=\"{}\" />",
+                    bbox.min_lat,
+                    bbox.min_lon,
+                    bbox.max_lat,
+                    bbox.max_lon
+                )?;
             }
-            write!(self.writer, ">")?;
 
             self._state = State::WritingObjects;
         }
@@ -398,10 +361,11 @@ impl<W: Write> XMLWriter<W> {
 
 impl<W: Write> OSMWriter<W> for XMLWriter<W> {
     fn new(writer: W) -> Self {
-        // TODO have a config that does indentation and stuff
         XMLWriter {
-            writer,
-            headers: HashMap::new(),
+            writer: Some(writer),
+            headers: BTreeMap::new(),
+            bbox: None,
+            indent: "\t".to_string(),
             _state: State::Initial,
         }
     }
@@ -417,14 +381,51 @@ impl<W: Write> OSMWriter<W> for XMLWriter<W> {
         }
     }
 
+    /// Replaces all the header values. The generator, replication fields & other values are
+    /// attributes of the `<osm>` element, and the bbox is a `<bounds>` element. PBF features are
+    /// ignored.
+    fn set_file_info(&mut self, file_info: &FileInfo) -> Result<(), OSMWriteError> {
+        match self._state {
+            State::Initial => {}
+            State::Closed => return Err(OSMWriteError::AlreadyClosed),
+            _ => return Err(OSMWriteError::AlreadyStarted),
+        }
+        let mut headers = file_info.other.clone();
+        if let Some(generator) = &file_info.generator {
+            headers.insert("generator".into(), generator.clone());
+        }
+        if let Some(timestamp) = &file_info.replication_timestamp {
+            headers.insert(
+                "osmosis_replication_timestamp".into(),
+                timestamp.to_string(),
+            );
+        }
+        if let Some(sequence) = file_info.replication_sequence {
+            headers.insert(
+                "osmosis_replication_sequence_number".into(),
+                sequence.to_string(),
+            );
+        }
+        if let Some(url) = &file_info.replication_base_url {
+            headers.insert("osmosis_replication_base_url".into(), url.clone());
+        }
+        self.headers = headers;
+        self.bbox = file_info.bbox;
+        Ok(())
+    }
+
     fn is_open(&self) -> bool {
         self._state != State::Closed
     }
 
     fn close(&mut self) -> Result<(), OSMWriteError> {
+        if self._state == State::Closed {
+            return Ok(());
+        }
         self.ensure_header()?;
 
-        write!(self.writer, "\n</osm>")?;
+        write!(self.writer_mut(), "\n</osm>")?;
+        self.writer_mut().flush()?;
 
         self._state = State::Closed;
 
@@ -438,108 +439,105 @@ impl<W: Write> OSMWriter<W> for XMLWriter<W> {
             State::Closed => return Err(OSMWriteError::AlreadyClosed),
         }
 
+        self.newline(1)?;
+        write!(self.writer_mut(), "<{}", obj.object_type())?;
+        write!(self.writer_mut(), " id=\"{}\"", obj.id())?;
         write!(
-            self.writer,
-            "{}",
-            match obj.object_type() {
-                OSMObjectType::Node => "\n\t<node",
-                OSMObjectType::Way => "\n\t<way",
-                OSMObjectType::Relation => "\n\t<relation",
-            }
-        )?;
-        write!(self.writer, " id=\"{}\"", obj.id())?;
-        write!(
-            self.writer,
+            self.writer_mut(),
             " visible=\"{}\"",
             if obj.deleted() { "false" } else { "true" }
         )?;
-        write!(self.writer, " version=\"{}\"", obj.version().unwrap())?;
+        if let Some(version) = obj.version() {
+            write!(self.writer_mut(), " version=\"{}\"", version)?;
+        }
         if let Some(user) = obj.user() {
-            write!(self.writer, " user=\"")?;
-            write_xml_escaped(&mut self.writer, user)?;
-            write!(self.writer, "\"")?;
+            write!(self.writer_mut(), " user=\"")?;
+            write_xml_escaped(self.writer_mut(), user)?;
+            write!(self.writer_mut(), "\"")?;
         }
         if let Some(uid) = obj.uid() {
-            write!(self.writer, " uid=\"{}\"", uid)?;
+            write!(self.writer_mut(), " uid=\"{}\"", uid)?;
         }
         if let Some(changeset_id) = obj.changeset_id() {
-            write!(self.writer, " changeset=\"{}\"", changeset_id)?;
+            write!(self.writer_mut(), " changeset=\"{}\"", changeset_id)?;
         }
         if let Some(timestamp) = obj.timestamp() {
-            write!(self.writer, " timestamp=\"{}\"", timestamp)?;
+            write!(self.writer_mut(), " timestamp=\"{}\"", timestamp)?;
         }
 
         if let Some(node) = obj.as_node() {
             if let Some((lat, lon)) = node.lat_lon() {
-                write!(self.writer, " lat=\"{}\"", lat)?;
-                write!(self.writer, " lon=\"{}\"", lon)?;
+                write!(self.writer_mut(), " lat=\"{}\"", lat)?;
+                write!(self.writer_mut(), " lon=\"{}\"", lon)?;
             }
         }
 
         if obj.is_node() && obj.untagged() {
-            write!(self.writer, " />")?;
+            write!(self.writer_mut(), " />")?;
             return Ok(());
         }
-        write!(self.writer, ">")?;
+        write!(self.writer_mut(), ">")?;
 
         if let Some(way) = obj.as_way() {
             for nid in way.nodes() {
-                write!(self.writer, "\n\t\t<nd ref=\"{}\" />", nid)?;
+                self.newline(2)?;
+                write!(self.writer_mut(), "<nd ref=\"{}\" />", nid)?;
             }
         }
 
         if let Some(relation) = obj.as_relation() {
             for member in relation.members() {
+                self.newline(2)?;
                 write!(
-                    self.writer,
-                    "\n\t\t<member type=\"{}\" ref=\"{}\" role=\"",
-                    member.0, member.1
+                    self.writer_mut(),
+                    "<member type=\"{}\" ref=\"{}\" role=\"",
+                    member.0,
+                    member.1
                 )?;
                 if !member.2.is_empty() {
-                    write_xml_escaped(&mut self.writer, member.2)?;
+                    write_xml_escaped(self.writer_mut(), member.2)?;
                 }
-                write!(self.writer, "\"/>")?;
+                write!(self.writer_mut(), "\"/>")?;
             }
         }
 
         for (k, v) in obj.tags() {
-            write!(self.writer, "\n\t\t<tag k=\"")?;
-            write_xml_escaped(&mut self.writer, k)?;
-            write!(self.writer, "\" v=\"")?;
-            write_xml_escaped(&mut self.writer, v)?;
-            write!(self.writer, "\" />")?;
+            self.newline(2)?;
+            write!(self.writer_mut(), "<tag k=\"")?;
+            write_xml_escaped(self.writer_mut(), k)?;
+            write!(self.writer_mut(), "\" v=\"")?;
+            write_xml_escaped(self.writer_mut(), v)?;
+            write!(self.writer_mut(), "\" />")?;
         }
 
-        write!(
-            self.writer,
-            "{}",
-            match obj.object_type() {
-                OSMObjectType::Node => "\n\t</node>",
-                OSMObjectType::Way => "\n\t</way>",
-                OSMObjectType::Relation => "\n\t</relation>",
-            }
-        )?;
+        self.newline(1)?;
+        write!(self.writer_mut(), "</{}>", obj.object_type())?;
 
         Ok(())
     }
 
-    fn into_inner(self) -> W {
-        todo!("converting an XMLWriter into_inner");
-        //self.writer.into_inner()
+    /// The underlying writer. This doesn't close the file, use [`OSMWriter::finish`] for that.
+    fn into_inner(mut self) -> W {
+        self.writer.take().unwrap()
     }
 }
 
+/// Closes the file if it's still open, and flushes it. Errors are ignored, so call
+/// [`OSMWriter::close`] or [`OSMWriter::finish`] to see them.
 impl<W: Write> Drop for XMLWriter<W> {
     fn drop(&mut self) {
-        self.close().unwrap();
+        if self.writer.is_some() {
+            let _ = self.close();
+            let _ = self.writer_mut().flush();
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Lat, Lon};
-    use obj_types::StringNodeBuilder;
+    use crate::{Lat, Lon, OSMObjectType};
+    use obj_types::{StringNodeBuilder, StringRelationBuilder, StringWayBuilder};
 
     macro_rules! assert_escape {
         ( $name:ident, $input:expr, $output:expr ) => {
@@ -557,7 +555,7 @@ mod tests {
     assert_escape!(escape1, "foo", "foo");
     assert_escape!(escape2, "&foo", "&amp;foo");
     assert_escape!(escape3, "foo bar", "foo bar");
-    assert_escape!(escape4, "foo\nbar", "foo\nbar");
+    assert_escape!(escape4, "foo\nbar", "foo&#10;bar");
     assert_escape!(escape5, "&&foo", "&amp;&amp;foo");
     assert_escape!(escape6, "&ergio", "&amp;ergio");
     assert_escape!(escape7, "foo & bar", "foo &amp; bar");
@@ -567,6 +565,14 @@ mod tests {
         "foo &amp; bar &quot; &apos; &lt;whoop&gt;"
     );
 
+    assert_escape!(escape9, "a\tb\r\nc", "a&#9;b&#13;&#10;c");
+    assert_escape!(
+        escape10,
+        "a\u{0}b\u{1b}c\u{FFFF}",
+        "a\u{FFFD}b\u{FFFD}c\u{FFFD}"
+    );
+    assert_escape!(escape11, "ünïcödé ✓", "ünïcödé ✓");
+
     macro_rules! assert_write_obj {
         ( $name:ident, $input:expr, $output:expr ) => {
             #[test]
@@ -594,6 +600,109 @@ mod tests {
 			._lat_lon((Lat(0), Lon(0)))
 			.build()
 			.unwrap(),
-	    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<osm version=\"0.6\" generator=\"osmio/{}\">\n\t<node id=\"1\" visible=\"true\" version=\"2\" user=\"&amp;foo\" uid=\"1\" changeset=\"1\" timestamp=\"1970-01-01T00:11:40Z\" lat=\"0\" lon=\"0\" />\n</osm>\n</osm>", crate::version())
+	    format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<osm version=\"0.6\" generator=\"osmio/{}\">\n\t<node id=\"1\" visible=\"true\" version=\"2\" user=\"&amp;foo\" uid=\"1\" changeset=\"1\" timestamp=\"1970-01-01T00:11:40Z\" lat=\"0\" lon=\"0\" />\n</osm>", crate::version())
 	);
+
+    #[test]
+    fn finish() {
+        let mut xmlwr = XMLWriter::new(Vec::new());
+        xmlwr.set_header(("foo", "bar")).unwrap();
+        let output = String::from_utf8(xmlwr.finish().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<osm version=\"0.6\" generator=\"osmio/{}\" foo=\"bar\">\n</osm>",
+                crate::version()
+            )
+        );
+
+        // Closing twice, or closing & dropping, only ends the file once
+        let mut res = Vec::new();
+        {
+            let mut xmlwr = XMLWriter::new(&mut res);
+            xmlwr.close().unwrap();
+            xmlwr.close().unwrap();
+            assert!(!xmlwr.is_open());
+        }
+        assert_eq!(
+            std::str::from_utf8(&res).unwrap().matches("</osm>").count(),
+            1
+        );
+    }
+
+    #[test]
+    fn indent() {
+        let way: StringOSMObj = StringWayBuilder::default()
+            ._id(2)
+            ._nodes(vec![1, 3])
+            ._tags(vec![("highway".to_string(), "path".to_string())])
+            .build()
+            .unwrap()
+            .into();
+        let mut xmlwr = XMLWriter::new(Vec::new()).indent("  ");
+        xmlwr.write_obj(&way).unwrap();
+        let output = String::from_utf8(xmlwr.finish().unwrap()).unwrap();
+        assert!(output.ends_with(
+            "\n  <way id=\"2\" visible=\"true\">\n    <nd ref=\"1\" />\n    <nd ref=\"3\" />\n    <tag k=\"highway\" v=\"path\" />\n  </way>\n</osm>"
+        ));
+    }
+
+    #[test]
+    fn round_trip() {
+        let exotic = [
+            "\"quoted\" & 'apos' <tag>",
+            "multi\nline\r\nvalue\twith tab",
+            "  leading & trailing spaces  ",
+            "ünïcödé ✓ 𝄞",
+            "]]> &amp; &#10;",
+        ];
+        let tags = |i: usize| {
+            vec![
+                (format!("k{}", i), exotic[i].to_string()),
+                (exotic[i].to_string(), "v".to_string()),
+            ]
+        };
+        let objs: Vec<StringOSMObj> = vec![
+            StringNodeBuilder::default()
+                ._id(1)
+                ._user(exotic[0].to_string())
+                ._lat_lon((Lat(515_000_000), Lon(-1_000_000)))
+                ._tags(tags(0))
+                .build()
+                .unwrap()
+                .into(),
+            StringNodeBuilder::default()
+                ._id(2)
+                ._tags(tags(1))
+                .build()
+                .unwrap()
+                .into(),
+            StringWayBuilder::default()
+                ._id(3)
+                ._nodes(vec![1, 2])
+                ._tags(tags(2))
+                .build()
+                .unwrap()
+                .into(),
+            StringRelationBuilder::default()
+                ._id(4)
+                ._members(vec![
+                    (OSMObjectType::Way, 3, exotic[3].to_string()),
+                    (OSMObjectType::Node, 1, exotic[4].to_string()),
+                ])
+                ._tags(tags(4))
+                .build()
+                .unwrap()
+                .into(),
+        ];
+        for indent in ["\t", "", "    "].iter() {
+            let mut xmlwr = XMLWriter::new(Vec::new()).indent(*indent);
+            for obj in objs.iter() {
+                xmlwr.write_obj(obj).unwrap();
+            }
+            let output = xmlwr.finish().unwrap();
+            let read: Vec<StringOSMObj> = XMLReader::new(&output[..]).objects().collect();
+            assert_eq!(read, objs);
+        }
+    }
 }
@@ -0,0 +1,718 @@
+//! Parsing OSM objects from XML (OSM or osmChange files) with quick-xml.
+//!
+//! Events are read into one reusable buffer, and attribute values are borrowed from it, so
+//! strings are only allocated for values which are kept (tags, roles, users & timestamps).
+use header::FileInfo;
+use obj_types::{StringNode, StringOSMObj, StringRelation, StringWay};
+use osc::ChangeAction;
+use parse_mode::{Anomaly, AnomalyKind, DuplicateTags, NonPositiveIds, ParseMode};
+use quick_xml::events::{BytesStart, Event};
+use std::borrow::Cow;
+use std::io::BufRead;
+use std::str::FromStr;
+use utils::parse_iso8601_millis;
+use {BBox, Error, Lat, Lon, OSMObjBase, OSMObjectType, ObjId, TimestampFormat};
+
+/// An invalid attribute, which is reported once the object's id is known
+type Problem = (AnomalyKind, String);
+
+pub(crate) struct ObjParser<R: BufRead> {
+    reader: quick_xml::Reader<R>,
+    buf: Vec<u8>,
+    parse_mode: ParseMode,
+    lossy_utf8: bool,
+    duplicate_tags: DuplicateTags,
+    non_positive_ids: NonPositiveIds,
+    /// Don't parse the version, timestamp, changeset, uid & user
+    skip_metadata: bool,
+    /// From the `<osm>` element & `<bounds>`, complete once `header_done`
+    file_info: FileInfo,
+    /// Whether the first object (or the end of the file) has been read
+    header_done: bool,
+    /// The osmChange block (`<create>` etc.) which the parser is in
+    action: Option<ChangeAction>,
+}
+
+impl<R: BufRead> ObjParser<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        ObjParser {
+            reader: quick_xml::Reader::from_reader(reader),
+            buf: Vec::new(),
+            parse_mode: ParseMode::default(),
+            lossy_utf8: false,
+            duplicate_tags: DuplicateTags::default(),
+            non_positive_ids: NonPositiveIds::default(),
+            skip_metadata: false,
+            file_info: FileInfo::default(),
+            header_done: false,
+            action: None,
+        }
+    }
+
+    /// The osmChange block which the last object was in, `None` for OSM XML files
+    pub(crate) fn action(&self) -> Option<ChangeAction> {
+        self.action
+    }
+
+    /// The header of the file, once the first object has been read
+    pub(crate) fn file_info(&self) -> Option<&FileInfo> {
+        if self.header_done {
+            Some(&self.file_info)
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn set_parse_mode(&mut self, parse_mode: ParseMode) {
+        self.parse_mode = parse_mode;
+    }
+
+    pub(crate) fn set_lossy_utf8(&mut self, lossy_utf8: bool) {
+        self.lossy_utf8 = lossy_utf8;
+    }
+
+    pub(crate) fn set_duplicate_tags(&mut self, duplicate_tags: DuplicateTags) {
+        self.duplicate_tags = duplicate_tags;
+    }
+
+    pub(crate) fn set_non_positive_ids(&mut self, non_positive_ids: NonPositiveIds) {
+        self.non_positive_ids = non_positive_ids;
+    }
+
+    pub(crate) fn set_skip_metadata(&mut self, skip_metadata: bool) {
+        self.skip_metadata = skip_metadata;
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        self.reader.get_ref()
+    }
+
+    pub(crate) fn into_inner(self) -> R {
+        self.reader.into_inner()
+    }
+
+    /// The next node, way or relation, skipping all other elements
+    pub(crate) fn next_obj(&mut self) -> Result<Option<StringOSMObj>, Error> {
+        loop {
+            self.buf.clear();
+            let offset = self.reader.buffer_position() as u64;
+            let (e, has_children) = match self.reader.read_event_into(&mut self.buf)? {
+                Event::Eof => {
+                    self.header_done = true;
+                    return Ok(None);
+                }
+                Event::Start(e) => (e, true),
+                Event::Empty(e) => (e, false),
+                _ => continue,
+            };
+            if !self.header_done && read_header_element(&mut self.file_info, &e)? {
+                continue;
+            }
+            let action = match e.local_name().as_ref() {
+                b"create" => Some(ChangeAction::Create),
+                b"modify" => Some(ChangeAction::Modify),
+                b"delete" => Some(ChangeAction::Delete),
+                _ => None,
+            };
+            if action.is_some() {
+                self.action = action;
+                continue;
+            }
+            let obj = start_obj(
+                &e,
+                offset,
+                &self.parse_mode,
+                self.lossy_utf8,
+                self.skip_metadata,
+            )?;
+            let mut obj = match obj {
+                Some(obj) => obj,
+                None => continue,
+            };
+            self.header_done = true;
+            if has_children {
+                self.read_children(&mut obj)?;
+                self.remove_duplicate_tags(&mut obj, offset)?;
+            }
+            let refs: Box<dyn Iterator<Item = ObjId>> = match &obj {
+                StringOSMObj::Node(_) => Box::new(std::iter::empty()),
+                StringOSMObj::Way(w) => Box::new(w._nodes.iter().copied()),
+                StringOSMObj::Relation(r) => Box::new(r._members.iter().map(|m| m.1)),
+            };
+            self.non_positive_ids
+                .check(obj.object_type(), obj.id(), refs, Some(offset))?;
+            return Ok(Some(obj));
+        }
+    }
+
+    /// Apply the duplicate tags policy to `obj`, which is at `offset`
+    fn remove_duplicate_tags(&self, obj: &mut StringOSMObj, offset: u64) -> Result<(), Error> {
+        let (object_type, id) = (obj.object_type(), obj.id());
+        let tags = match obj {
+            StringOSMObj::Node(n) => match &mut n._tags {
+                Some(tags) => tags,
+                None => return Ok(()),
+            },
+            StringOSMObj::Way(w) => &mut w._tags,
+            StringOSMObj::Relation(r) => &mut r._tags,
+        };
+        let keys = tags.iter().map(|(k, _)| k.as_str());
+        if let Some(duplicates) = self.duplicate_tags.find(keys) {
+            let anomaly = Anomaly {
+                kind: AnomalyKind::DuplicateTag,
+                offset: Some(offset),
+                object_type,
+                id,
+                detail: format!("key {:?}", duplicates.key),
+            };
+            self.duplicate_tags.report(anomaly, &self.parse_mode)?;
+            duplicates.remove(tags);
+        }
+        Ok(())
+    }
+
+    /// Add the tags, nodes & members to `obj`, up to its end tag
+    fn read_children(&mut self, obj: &mut StringOSMObj) -> Result<(), Error> {
+        let mut depth = 0;
+        loop {
+            self.buf.clear();
+            let offset = self.reader.buffer_position() as u64;
+            match self.reader.read_event_into(&mut self.buf)? {
+                Event::Eof => {
+                    return Err(Error::Format {
+                        offset: Some(self.reader.buffer_position() as u64),
+                        reason: format!(
+                            "File ends in the middle of {} {}",
+                            obj.object_type(),
+                            obj.id()
+                        ),
+                    })
+                }
+                // Only direct children are tags, nodes or members
+                Event::Start(e) => {
+                    if depth == 0 {
+                        add_child(obj, &e, offset, &self.parse_mode, self.lossy_utf8)?;
+                    }
+                    depth += 1;
+                }
+                Event::Empty(e) if depth == 0 => {
+                    add_child(obj, &e, offset, &self.parse_mode, self.lossy_utf8)?
+                }
+                Event::End(_) if depth == 0 => return Ok(()),
+                Event::End(_) => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// An attribute value, or an error if it isn't valid UTF-8 (with the lossily decoded value, if
+/// that's wanted)
+type Value<'a> = Result<Cow<'a, str>, Option<Cow<'a, str>>>;
+
+/// The attributes of this element, with unescaped values (which are only allocated if they had
+/// to be unescaped)
+fn attributes<'a>(
+    e: &'a BytesStart,
+    lossy_utf8: bool,
+) -> impl Iterator<Item = Result<(&'a [u8], Value<'a>), Error>> + 'a {
+    e.attributes().map(move |attr| {
+        let attr = attr.map_err(quick_xml::Error::from)?;
+        let value = match attr.unescape_value() {
+            Ok(value) => Ok(value),
+            Err(quick_xml::Error::NonDecodable(_)) if lossy_utf8 => {
+                let value = String::from_utf8_lossy(&attr.value);
+                let value =
+                    quick_xml::escape::unescape(&value).map_err(quick_xml::Error::EscapeError)?;
+                Err(Some(Cow::Owned(value.into_owned())))
+            }
+            Err(quick_xml::Error::NonDecodable(_)) => Err(None),
+            Err(e) => return Err(e.into()),
+        };
+        let key = attr.key.local_name().into_inner();
+        Ok((key, value))
+    })
+}
+
+/// The value of this attribute, or the lossily decoded value (or `None`) and a problem if it
+/// isn't valid UTF-8
+fn text<'a>(key: &[u8], value: Value<'a>, problems: &mut Vec<Problem>) -> Option<Cow<'a, str>> {
+    match value {
+        Ok(value) => Some(value),
+        Err(repaired) => {
+            let mut detail = format!("{} attribute", String::from_utf8_lossy(key));
+            if repaired.is_some() {
+                detail.push_str(" (decoded lossily)");
+            }
+            problems.push((AnomalyKind::InvalidUtf8, detail));
+            repaired
+        }
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+/// This attribute as a number, or `None` (and a problem) if it isn't one
+fn number<T: FromStr>(key: &[u8], value: &str, problems: &mut Vec<Problem>) -> Option<T> {
+    let number = parse(value);
+    if number.is_none() {
+        let detail = format!("{}={:?}", String::from_utf8_lossy(key), value);
+        problems.push((AnomalyKind::InvalidNumber, detail));
+    }
+    number
+}
+
+/// Report these problems with `obj`, which is at `offset`
+fn report(
+    parse_mode: &ParseMode,
+    obj: &StringOSMObj,
+    offset: u64,
+    problems: Vec<Problem>,
+) -> Result<(), Error> {
+    for (kind, detail) in problems {
+        parse_mode.report(Anomaly {
+            kind,
+            offset: Some(offset),
+            object_type: obj.object_type(),
+            id: obj.id(),
+            detail,
+        })?;
+    }
+    Ok(())
+}
+
+/// The object this element starts (without tags etc.), or `None` if it's not a node, way or
+/// relation
+fn start_obj(
+    e: &BytesStart,
+    offset: u64,
+    parse_mode: &ParseMode,
+    lossy_utf8: bool,
+    skip_metadata: bool,
+) -> Result<Option<StringOSMObj>, Error> {
+    let object_type = match e.local_name().as_ref() {
+        b"node" => OSMObjectType::Node,
+        b"way" => OSMObjectType::Way,
+        b"relation" => OSMObjectType::Relation,
+        _ => return Ok(None),
+    };
+
+    let mut id = None;
+    let (mut version, mut changeset_id, mut uid) = (None, None, None);
+    let (mut timestamp, mut user) = (None, None);
+    let (mut lat, mut lon) = (None, None);
+    let mut deleted = false;
+    let mut problems = Vec::new();
+    for attr in attributes(e, lossy_utf8) {
+        let (key, value) = attr?;
+        let value = match text(key, value, &mut problems) {
+            Some(value) => value,
+            None => continue,
+        };
+        match key {
+            b"id" => id = parse::<ObjId>(&value),
+            b"version" | b"changeset" | b"uid" | b"timestamp" | b"user" if skip_metadata => {}
+            b"version" => version = number(key, &value, &mut problems),
+            b"changeset" => changeset_id = number(key, &value, &mut problems),
+            b"uid" => uid = number(key, &value, &mut problems),
+            b"timestamp" => {
+                if parse_iso8601_millis(&value).is_ok() {
+                    timestamp = Some(TimestampFormat::ISOString(value.into_owned()));
+                } else {
+                    problems.push((AnomalyKind::InvalidTimestamp, format!("{:?}", value)));
+                }
+            }
+            b"user" => user = Some(value.into_owned()),
+            b"lat" => lat = number(key, &value, &mut problems),
+            b"lon" => lon = number(key, &value, &mut problems),
+            b"visible" => deleted = &*value == "false",
+            _ => {}
+        }
+    }
+    let id = id.ok_or_else(|| Error::Format {
+        offset: Some(offset),
+        reason: format!("{} without a valid id", object_type),
+    })?;
+
+    let obj = match object_type {
+        OSMObjectType::Node => StringOSMObj::Node(StringNode {
+            _id: id,
+            _version: version,
+            _deleted: deleted,
+            _changeset_id: changeset_id,
+            _timestamp: timestamp,
+            _uid: uid,
+            _user: user,
+            _lat_lon: lat.zip(lon),
+            _tags: None,
+        }),
+        OSMObjectType::Way => StringOSMObj::Way(StringWay {
+            _id: id,
+            _version: version,
+            _deleted: deleted,
+            _changeset_id: changeset_id,
+            _timestamp: timestamp,
+            _uid: uid,
+            _user: user,
+            _tags: Vec::new(),
+            _nodes: Vec::new(),
+        }),
+        OSMObjectType::Relation => StringOSMObj::Relation(StringRelation {
+            _id: id,
+            _version: version,
+            _deleted: deleted,
+            _changeset_id: changeset_id,
+            _timestamp: timestamp,
+            _uid: uid,
+            _user: user,
+            _tags: Vec::new(),
+            _members: Vec::new(),
+        }),
+    };
+    report(parse_mode, &obj, offset, problems)?;
+    Ok(Some(obj))
+}
+
+/// Add this child element (a `tag`, `nd` or `member`) to `obj`. Others, and ones with missing
+/// or invalid attributes, are ignored.
+fn add_child(
+    obj: &mut StringOSMObj,
+    e: &BytesStart,
+    offset: u64,
+    parse_mode: &ParseMode,
+    lossy_utf8: bool,
+) -> Result<(), Error> {
+    let mut problems = Vec::new();
+    match e.local_name().as_ref() {
+        b"tag" => {
+            let (mut k, mut v) = (None, None);
+            for attr in attributes(e, lossy_utf8) {
+                let (key, value) = attr?;
+                match key {
+                    b"k" => k = text(key, value, &mut problems),
+                    b"v" => v = text(key, value, &mut problems),
+                    _ => {}
+                }
+            }
+            if let (Some(k), Some(v)) = (k, v) {
+                let tag = (k.into_owned(), v.into_owned());
+                match obj {
+                    StringOSMObj::Node(n) => n._tags.get_or_insert_with(Vec::new).push(tag),
+                    StringOSMObj::Way(w) => w._tags.push(tag),
+                    StringOSMObj::Relation(r) => r._tags.push(tag),
+                }
+            }
+        }
+        b"nd" => {
+            if let StringOSMObj::Way(w) = obj {
+                for attr in attributes(e, lossy_utf8) {
+                    if let (b"ref", value) = attr? {
+                        let value = text(b"ref", value, &mut problems);
+                        if let Some(node_id) = value.and_then(|v| number(b"ref", &v, &mut problems))
+                        {
+                            w._nodes.push(node_id);
+                        }
+                    }
+                }
+            }
+        }
+        b"member" => {
+            if let StringOSMObj::Relation(r) = obj {
+                let (mut member_type, mut member_id, mut role) = (None, None, None);
+                for attr in attributes(e, lossy_utf8) {
+                    let (key, value) = attr?;
+                    let value = match text(key, value, &mut problems) {
+                        Some(value) => value,
+                        None => continue,
+                    };
+                    match key {
+                        b"type" => member_type = parse::<OSMObjectType>(&value),
+                        b"ref" => member_id = number::<ObjId>(key, &value, &mut problems),
+                        b"role" => role = Some(value.into_owned()),
+                        _ => {}
+                    }
+                }
+                if let (Some(member_type), Some(member_id)) = (member_type, member_id) {
+                    r._members
+                        .push((member_type, member_id, role.unwrap_or_default()));
+                }
+            }
+        }
+        _ => {}
+    }
+    report(parse_mode, obj, offset, problems)
+}
+
+/// The [`FileInfo`] from the attributes of the `<osm>` (or `<osmChange>`) element, and the
+/// `<bounds>`, at the start of a file. Stops at the first object.
+pub(crate) fn read_file_info(reader: impl BufRead) -> Result<FileInfo, Error> {
+    let mut reader = quick_xml::Reader::from_reader(reader);
+    let mut buf = Vec::new();
+    let mut info = FileInfo::default();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(ref e) | Event::Empty(ref e) if !read_header_element(&mut info, e)? => {
+                break
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buf.clear();
+    }
+    Ok(info)
+}
+
+/// Add this element to `info` if it's part of the header (`<osm>`, `<osmChange>` or
+/// `<bounds>`). Returns false for other elements.
+fn read_header_element(info: &mut FileInfo, e: &BytesStart) -> Result<bool, Error> {
+    match e.local_name().as_ref() {
+        b"osm" | b"osmChange" => {
+            for attr in attributes(e, true) {
+                let (key, value) = match attr? {
+                    (key, Ok(value)) | (key, Err(Some(value))) => (key, value.into_owned()),
+                    (_, Err(None)) => continue,
+                };
+                match key {
+                    b"version" => {}
+                    b"generator" => info.generator = Some(value),
+                    b"timestamp" | b"osmosis_replication_timestamp" => {
+                        info.replication_timestamp = Some(TimestampFormat::ISOString(value))
+                    }
+                    b"osmosis_replication_sequence_number" => {
+                        info.replication_sequence = value.parse().ok()
+                    }
+                    b"osmosis_replication_base_url" => info.replication_base_url = Some(value),
+                    _ => {
+                        info.other
+                            .insert(String::from_utf8_lossy(key).into_owned(), value);
+                    }
+                }
+            }
+        }
+        b"bounds" => {
+            let (mut min_lat, mut min_lon, mut max_lat, mut max_lon) = (None, None, None, None);
+            for attr in attributes(e, true) {
+                let (key, value) = match attr? {
+                    (key, Ok(value)) => (key, value),
+                    _ => continue,
+                };
+                match key {
+                    b"minlat" => min_lat = Lat::from_str(&value).ok(),
+                    b"minlon" => min_lon = Lon::from_str(&value).ok(),
+                    b"maxlat" => max_lat = Lat::from_str(&value).ok(),
+                    b"maxlon" => max_lon = Lon::from_str(&value).ok(),
+                    _ => {}
+                }
+            }
+            if let (Some(min_lat), Some(min_lon), Some(max_lat), Some(max_lon)) =
+                (min_lat, min_lon, max_lat, max_lon)
+            {
+                info.bbox = Some(BBox::new(min_lat, min_lon, max_lat, max_lon));
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Node, OSMObj, Relation, Way};
+
+    fn parse_all(input: &str) -> Result<Vec<StringOSMObj>, Error> {
+        parse_with(input.as_bytes(), ParseMode::default())
+    }
+
+    fn parse_with(input: &[u8], parse_mode: ParseMode) -> Result<Vec<StringOSMObj>, Error> {
+        parse_lossy(input, parse_mode, false)
+    }
+
+    fn parse_lossy(
+        input: &[u8],
+        parse_mode: ParseMode,
+        lossy_utf8: bool,
+    ) -> Result<Vec<StringOSMObj>, Error> {
+        let mut parser = ObjParser::new(input);
+        parser.set_parse_mode(parse_mode);
+        parser.set_lossy_utf8(lossy_utf8);
+        let mut objs = Vec::new();
+        while let Some(obj) = parser.next_obj()? {
+            objs.push(obj);
+        }
+        Ok(objs)
+    }
+
+    #[test]
+    fn objects() {
+        let objs = parse_all(
+            r#"<osm version="0.6"><bounds minlat="0" minlon="0" maxlat="1" maxlon="1"/>
+<node id="1" version="2" visible="false" user="A &amp; B"/>
+<way id="2"><nd ref="1"/><extra><tag k="ignored" v="no"/></extra><nd ref="3"/><tag k="name" v="&lt;x&gt;"/></way>
+<relation id="3"><member type="way" ref="2"/><member type="node" ref="1" role="stop"/></relation>
+</osm>"#,
+        )
+        .unwrap();
+        assert_eq!(objs.len(), 3);
+
+        let node = objs[0].as_node().unwrap();
+        assert_eq!(node.lat_lon(), None);
+        assert!(node.deleted());
+        assert_eq!(node.user(), Some("A & B"));
+
+        let way = objs[1].as_way().unwrap();
+        assert_eq!(way.nodes(), &[1, 3]);
+        assert_eq!(way.tags().collect::<Vec<_>>(), vec![("name", "<x>")]);
+
+        let relation = objs[2].as_relation().unwrap();
+        assert_eq!(
+            relation.members().collect::<Vec<_>>(),
+            vec![
+                (OSMObjectType::Way, 2, ""),
+                (OSMObjectType::Node, 1, "stop")
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(
+            parse_all(r#"<osm><way id="1"><nd ref="1"/>"#),
+            Err(Error::Format { .. })
+        ));
+        assert!(matches!(
+            parse_all(r#"<osm><node lat="1" lon="1"/></osm>"#),
+            Err(Error::Format { .. })
+        ));
+        assert!(matches!(
+            parse_all(r#"<osm><node id="1"></way></osm>"#),
+            Err(Error::Xml(_))
+        ));
+    }
+
+    #[test]
+    fn parse_modes() {
+        let mut input =
+            br#"<osm><way id="1" version="x" timestamp="yesterday"><nd ref="2"/><nd ref="y"/>"#
+                .to_vec();
+        input.extend(b"<tag k=\"name\" v=\"\xff\"/><tag k=\"a\" v=\"b\"/></way></osm>");
+
+        let objs = parse_with(&input, ParseMode::Lenient).unwrap();
+        let way = objs[0].as_way().unwrap();
+        assert_eq!(way.version(), None);
+        assert_eq!(way.timestamp(), &None);
+        assert_eq!(way.nodes(), &[2]);
+        assert_eq!(way.tags().collect::<Vec<_>>(), vec![("a", "b")]);
+
+        let kinds = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let kinds2 = kinds.clone();
+        parse_with(
+            &input,
+            ParseMode::warn(move |a| kinds2.lock().unwrap().push(a.kind)),
+        )
+        .unwrap();
+        assert_eq!(
+            *kinds.lock().unwrap(),
+            vec![
+                AnomalyKind::InvalidNumber,
+                AnomalyKind::InvalidTimestamp,
+                AnomalyKind::InvalidNumber,
+                AnomalyKind::InvalidUtf8
+            ]
+        );
+
+        match parse_with(&input, ParseMode::Strict) {
+            Err(Error::Anomaly(anomaly)) => {
+                assert_eq!(anomaly.kind, AnomalyKind::InvalidNumber);
+                assert_eq!(anomaly.detail, "version=\"x\"");
+                assert_eq!((anomaly.object_type, anomaly.id), (OSMObjectType::Way, 1));
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn lossy_utf8() {
+        let input = b"<osm><node id=\"1\" user=\"a\xffb\"><tag k=\"name\" v=\"x\xfe &amp; y\"/></node></osm>";
+        let objs = parse_with(input, ParseMode::Lenient).unwrap();
+        assert_eq!(objs[0].user(), None);
+        assert_eq!(objs[0].tags().count(), 0);
+
+        let objs = parse_lossy(input, ParseMode::Lenient, true).unwrap();
+        assert_eq!(objs[0].user(), Some("a\u{FFFD}b"));
+        assert_eq!(objs[0].tag("name"), Some("x\u{FFFD} & y"));
+
+        assert!(matches!(
+            parse_lossy(input, ParseMode::Strict, true),
+            Err(Error::Anomaly(Anomaly {
+                kind: AnomalyKind::InvalidUtf8,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn duplicate_tags() {
+        let input = br#"<osm><node id="1"><tag k="a" v="1"/><tag k="a" v="2"/></node></osm>"#;
+        let mut parser = ObjParser::new(&input[..]);
+        parser.set_duplicate_tags(DuplicateTags::KeepLast);
+        let node = parser.next_obj().unwrap().unwrap();
+        assert_eq!(node.tags().collect::<Vec<_>>(), vec![("a", "2")]);
+
+        let mut parser = ObjParser::new(&input[..]);
+        parser.set_duplicate_tags(DuplicateTags::Error);
+        assert!(matches!(
+            parser.next_obj(),
+            Err(Error::Anomaly(Anomaly {
+                kind: AnomalyKind::DuplicateTag,
+                id: 1,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn non_positive_ids() {
+        // New objects in a JOSM file
+        let input = br#"<osm><node id="-1" lat="1" lon="2"/><way id="-2"><nd ref="-1"/><nd ref="5"/></way></osm>"#;
+        let objs = parse_with(&input[..], ParseMode::Strict).unwrap();
+        assert_eq!(objs[0].id(), -1);
+        assert_eq!(objs[1].as_way().unwrap().nodes(), &[-1, 5]);
+
+        let mut parser = ObjParser::new(&input[..]);
+        parser.set_non_positive_ids(NonPositiveIds::Error);
+        assert!(matches!(
+            parser.next_obj(),
+            Err(Error::Anomaly(Anomaly {
+                kind: AnomalyKind::NonPositiveId,
+                id: -1,
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn file_info() {
+        let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<osm version="0.6" generator="JOSM" upload="never" timestamp="2024-03-01T00:00:00Z">
+  <bounds minlat="51.5" minlon="-0.2" maxlat="51.6" maxlon="0.1"/>
+  <node id="1" lat="51.55" lon="0"/>
+</osm>"#;
+        let info = read_file_info(xml.as_bytes()).unwrap();
+        assert_eq!(info.generator.as_deref(), Some("JOSM"));
+        assert_eq!(info.bbox, Some("-0.2,51.5,0.1,51.6".parse().unwrap()));
+        assert_eq!(
+            info.replication_timestamp,
+            Some("2024-03-01T00:00:00Z".parse().unwrap())
+        );
+        assert_eq!(info.other.get("upload").map(|s| s.as_str()), Some("never"));
+        assert_eq!(info.other.len(), 1);
+
+        assert_eq!(
+            read_file_info(&b"<osm><node id='1'/></osm>"[..]).unwrap(),
+            FileInfo::default()
+        );
+    }
+}